@@ -0,0 +1,95 @@
+use anyhow::{Context, anyhow};
+use snow::Builder;
+use std::io::Write;
+use std::path::Path;
+
+/// Noise static key of the listener, kept in a file so the gateway can pin it
+pub struct StaticKey {
+    pub private: [u8; 32],
+    pub public: [u8; 32],
+}
+
+/// Static key stored at `path`, the private and the public key hex encoded on
+/// a line each. On first start one is generated and stored, its public key
+/// logged for provisioning the gateway.
+pub fn load(path: &Path) -> Result<StaticKey, anyhow::Error> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => {
+            let key = parse(&content)?;
+            tracing::info!("Listener public key {}", hex(&key.public));
+            Ok(key)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let keypair = Builder::new(crate::sender::PARAMS.parse()?).generate_keypair()?;
+            let key = StaticKey {
+                private: to_key(&keypair.private)?,
+                public: to_key(&keypair.public)?,
+            };
+            write(path, &key)?;
+            tracing::warn!(
+                "Generated a new static key, pin it in the gateway's [[noise.listeners]] as \
+                public_key = \"{}\"",
+                hex(&key.public)
+            );
+            Ok(key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write(path: &Path, key: &StaticKey) -> Result<(), anyhow::Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    writeln!(file, "{}\n{}", hex(&key.private), hex(&key.public))?;
+    Ok(())
+}
+
+fn parse(content: &str) -> Result<StaticKey, anyhow::Error> {
+    let mut lines = content.lines();
+    let mut next = || {
+        let line = lines.next().ok_or_else(|| anyhow!("Key file too short"))?;
+        let mut key = [0u8; 32];
+        if line.len() != 64 {
+            return Err(anyhow!("Key isn't 64 hex characters"));
+        }
+        for (byte, pair) in key.iter_mut().zip(line.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair)?;
+            *byte = u8::from_str_radix(pair, 16).context("Key isn't hex")?;
+        }
+        Ok(key)
+    };
+    Ok(StaticKey {
+        private: next()?,
+        public: next()?,
+    })
+}
+
+fn to_key(bytes: &[u8]) -> Result<[u8; 32], anyhow::Error> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("Expected a 32 byte key, got {}", bytes.len()))
+}
+
+/// Hex encodes bytes, the format the gateway's config takes public keys in
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+
+    #[test]
+    fn keeps_the_generated_key() {
+        let path = std::env::temp_dir().join(format!("ruuvi-identity-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let generated = load(&path).unwrap();
+        let loaded = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(generated.private, loaded.private);
+        assert_eq!(generated.public, loaded.public);
+    }
+}
@@ -0,0 +1,7 @@
+//! Listener for hosts with Bluetooth, like a laptop or a Raspberry Pi. It scans
+//! Ruuvi advertisements with btleplug and speaks the Noise protocol of the
+//! ESP32 firmware to ruuvi-gateway, so the gateway can't tell the two apart.
+
+pub mod identity;
+pub mod scanner;
+pub mod sender;
@@ -0,0 +1,63 @@
+use anyhow::{Context, anyhow};
+use clap::Parser;
+use ruuvi_desktop_listener::sender::{self, GatewayConfig};
+use ruuvi_desktop_listener::{identity, scanner};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+/// Readings waiting for the gateway, the newest are dropped when full
+const QUEUE_DEPTH: usize = 256;
+
+/// Scans RuuviTags with the host's Bluetooth adapter and forwards them to
+/// ruuvi-gateway, like an ESP32 listener
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Gateway's listener address, `host:port`
+    #[arg(long, env = "GATEWAY")]
+    gateway: String,
+    /// Noise pre-shared key of the gateway, exactly 32 bytes
+    #[arg(long, env = "AUTH_KEY", hide_env_values = true)]
+    psk: String,
+    /// Name of the key in the gateway's `[[noise.psks]]`, empty for its default key
+    #[arg(long, env = "PSK_NAME", default_value = "")]
+    psk_name: String,
+    /// File keeping the listener's static key, created on first start
+    #[arg(long, default_value = "ruuvi-listener.key")]
+    identity: PathBuf,
+    /// Bluetooth adapter, by its position in the system's list
+    #[arg(long, default_value_t = 0)]
+    adapter: usize,
+    /// Log filter, like `info` or `ruuvi_desktop_listener=debug`
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    log_level: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(cli.log_level.as_str())
+        .compact()
+        .init();
+
+    let psk: [u8; 32] = cli
+        .psk
+        .as_bytes()
+        .try_into()
+        .map_err(|_| anyhow!("The pre-shared key must be exactly 32 bytes"))?;
+    let key = identity::load(&cli.identity)
+        .with_context(|| format!("Failed to load the static key {}", cli.identity.display()))?;
+    let config = GatewayConfig {
+        address: cli.gateway,
+        psk,
+        psk_name: cli.psk_name,
+    };
+
+    let (readings, receiver) = mpsc::channel(QUEUE_DEPTH);
+    tokio::try_join!(
+        scanner::scan(cli.adapter, readings),
+        sender::run(config, key, receiver)
+    )?;
+    Ok(())
+}
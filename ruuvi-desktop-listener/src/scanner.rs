@@ -0,0 +1,93 @@
+use anyhow::anyhow;
+use btleplug::api::{Central, CentralEvent, Manager as _, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Manager, PeripheralId};
+use futures_util::StreamExt;
+use ruuvi_schema::RuuviRaw;
+use ruuvi_schema::ack::Ack;
+use std::collections::HashMap;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::Instant;
+
+/// Company identifier of Ruuvi Innovations in manufacturer specific data
+pub const RUUVI_MANUFACTURER_ID: u16 = 0x0499;
+/// TX power of an advertisement that doesn't carry one, as controllers report it
+const TX_POWER_UNAVAILABLE: i8 = 127;
+
+/// Forward every new Ruuvi measurement heard by adapter `index`, with the
+/// moment it was heard. Readings are dropped while `sender` is full, and
+/// scanning stops once its receiver is gone or the adapter goes away.
+pub async fn scan(
+    index: usize,
+    sender: mpsc::Sender<(RuuviRaw, Instant)>,
+) -> Result<(), anyhow::Error> {
+    let manager = Manager::new().await?;
+    let adapter = manager
+        .adapters()
+        .await?
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| anyhow!("No Bluetooth adapter {index}"))?;
+    let mut events = adapter.events().await?;
+    adapter.start_scan(ScanFilter::default()).await?;
+    tracing::info!("Scanning with {}", adapter.adapter_info().await?);
+
+    // BlueZ reports changed properties, a measurement comes by again with a new RSSI
+    let mut latest = HashMap::<[u8; 6], Ack>::new();
+    while let Some(event) = events.next().await {
+        let CentralEvent::ManufacturerDataAdvertisement {
+            id,
+            manufacturer_data,
+        } = event
+        else {
+            continue;
+        };
+        let Some(data) = manufacturer_data.get(&RUUVI_MANUFACTURER_ID) else {
+            continue;
+        };
+        let Some(advertiser) = advertiser(&adapter, &id).await else {
+            continue;
+        };
+        let raw = match RuuviRaw::parse(data, advertiser.mac, advertiser.rssi, advertiser.tx_power)
+        {
+            Ok(raw) => raw,
+            Err(e) => {
+                tracing::debug!("Unreadable advertisement of {id}: {e}");
+                continue;
+            }
+        };
+        if latest.insert(raw.mac(), Ack::of(&raw)) == Some(Ack::of(&raw)) {
+            continue;
+        }
+
+        tracing::debug!("Heard {raw:?}");
+        match sender.try_send((raw, Instant::now())) {
+            Ok(()) => (),
+            Err(TrySendError::Full((raw, _))) => {
+                tracing::warn!("Queue full, dropping a reading of {:02X?}", raw.mac());
+            }
+            Err(TrySendError::Closed(_)) => return Ok(()),
+        }
+    }
+    Err(anyhow!("The Bluetooth adapter stopped scanning"))
+}
+
+struct Advertiser {
+    mac: [u8; 6],
+    rssi: i8,
+    tx_power: i8,
+}
+
+/// Address and signal of a peripheral. CoreBluetooth hides the address, which
+/// only format 3 lacks in its payload.
+async fn advertiser(adapter: &Adapter, id: &PeripheralId) -> Option<Advertiser> {
+    let peripheral = adapter.peripheral(id).await.ok()?;
+    let properties = peripheral.properties().await.ok()??;
+    let clamp = |v: i16| v.clamp(i16::from(i8::MIN), i16::from(i8::MAX)) as i8;
+    Some(Advertiser {
+        mac: properties.address.into_inner(),
+        rssi: properties.rssi.map_or(0, clamp),
+        tx_power: properties
+            .tx_power_level
+            .map_or(TX_POWER_UNAVAILABLE, clamp),
+    })
+}
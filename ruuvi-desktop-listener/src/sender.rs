@@ -0,0 +1,458 @@
+//! Session with the gateway, the ESP32 firmware's `transport-noise`: a Noise
+//! handshake carrying the [`Hello`], then postcard encoded [`Message`]s. See
+//! [`protocol`](ruuvi_schema::protocol).
+
+use crate::identity::StaticKey;
+use anyhow::{Context, anyhow, bail};
+use ruuvi_schema::RuuviRaw;
+use ruuvi_schema::ack::Ack;
+use ruuvi_schema::ota::version;
+use ruuvi_schema::protocol::{Hello, MAX_BATCH, Message, PROTOCOL_VERSION, RekeyPolicy};
+use ruuvi_schema::time::ClockSync;
+use snow::{Builder, TransportState};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, MissedTickBehavior};
+
+pub const PARAMS: &str = "Noise_XXpsk3_25519_ChaChaPoly_SHA256";
+// Gateway's id for the ChaChaPoly suite, sent before the handshake and used as the prologue
+const SUITE_ID: u8 = 1;
+const BASE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Connecting, the handshake and the first time sync together
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// The clock is re-synced with the gateway this often while connected
+const TIME_SYNC_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// A heartbeat goes out this often, so a dead connection fails a write
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// A connection without acknowledgements for this long is considered dead. Keep it
+/// above the gateway's dedup window.
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Sent readings kept until the gateway acknowledges storing them, the oldest is
+/// dropped when full. Resent after a reconnect.
+const RETRY_BUFFER_DEPTH: usize = 1024;
+/// Longest Noise message
+const MAX_MESSAGE_LEN: usize = 65_535;
+/// Authentication tag of every encrypted message
+const TAG_LEN: usize = 16;
+
+pub struct GatewayConfig {
+    /// `host:port` of the gateway's listener
+    pub address: String,
+    pub psk: [u8; 32],
+    /// Name of `psk` in the gateway's `[[noise.psks]]`, empty for its default key
+    pub psk_name: String,
+}
+
+/// Send the readings to the gateway, reconnecting with a backoff whenever the
+/// connection fails. Returns once `readings` is closed.
+pub async fn run(
+    config: GatewayConfig,
+    key: StaticKey,
+    mut readings: mpsc::Receiver<(RuuviRaw, Instant)>,
+) -> Result<(), anyhow::Error> {
+    // Kept across reconnects so the drift estimate carries over
+    let mut clock = Clock {
+        started: Instant::now(),
+        sync: ClockSync::default(),
+    };
+    let mut retry = RetryBuffer::default();
+    let mut backoff = BASE_BACKOFF;
+    loop {
+        tracing::info!("Trying to connect to {}", config.address);
+        let connected = tokio::time::timeout(CONNECT_TIMEOUT, connect(&config, &key, &mut clock))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Timed out")));
+        match connected {
+            Ok((connection, frames)) => {
+                tracing::info!("Session established with the gateway");
+                backoff = BASE_BACKOFF;
+                match connection
+                    .run(frames, &mut readings, &mut clock, &mut retry)
+                    .await
+                {
+                    Ok(()) => return Ok(()),
+                    Err(e) => tracing::error!("Gateway connection failed: {e}"),
+                }
+            }
+            Err(e) => tracing::warn!("Failed to connect to {}: {e}", config.address),
+        }
+
+        tracing::info!("Reconnecting after backoff {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Maps local time to gateway time
+struct Clock {
+    started: Instant,
+    sync: ClockSync,
+}
+
+impl Clock {
+    fn local_ms(&self, at: Instant) -> u64 {
+        at.duration_since(self.started).as_millis() as u64
+    }
+
+    /// Record a time response to the request sent at `requested`, assuming
+    /// the reply took half the round trip
+    fn synced(&mut self, requested: Instant, unix_ms: u64) -> Duration {
+        let delay = requested.elapsed() / 2;
+        self.sync.update(
+            self.local_ms(requested + delay),
+            unix_ms + delay.as_millis() as u64,
+        );
+        delay
+    }
+
+    fn gateway_time(&self, at: Instant) -> Option<u64> {
+        self.sync.gateway_time(self.local_ms(at))
+    }
+}
+
+async fn connect(
+    config: &GatewayConfig,
+    key: &StaticKey,
+    clock: &mut Clock,
+) -> Result<(Connection, FrameReader), anyhow::Error> {
+    let mut stream = TcpStream::connect(&config.address).await?;
+    stream.set_nodelay(true)?;
+    let mut noise = Builder::new(PARAMS.parse()?)
+        .local_private_key(&key.private)?
+        .psk(3, &config.psk)?
+        .prologue(&[SUITE_ID])?
+        .build_initiator()?;
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+
+    // Select the cipher suite
+    stream.write_all(&[SUITE_ID]).await?;
+
+    // https://noiseprotocol.org/noise.html
+    // -> e, naming our PSK for the gateway
+    let len = noise.write_message(config.psk_name.as_bytes(), &mut buf)?;
+    send(&mut stream, &buf[..len]).await?;
+
+    // <- e, ee, s, es
+    let message = recv(&mut stream).await?;
+    noise
+        .read_message(&message, &mut buf)
+        .context("Failed to read e, ee, s, es messages")?;
+
+    // -> s, se, with our versions as payload
+    let hello = postcard::to_stdvec(&Hello {
+        protocol: PROTOCOL_VERSION,
+        firmware: firmware_version(),
+    })?;
+    let len = noise.write_message(&hello, &mut buf)?;
+    send(&mut stream, &buf[..len]).await?;
+
+    let (mut reader, writer) = stream.into_split();
+    let mut connection = Connection {
+        writer,
+        transport: noise.into_transport_mode()?,
+    };
+
+    // Opens the session, also where an incompatible gateway refuses us
+    let requested = Instant::now();
+    connection.send(&Message::TimeSyncRequest).await?;
+    let response = connection.decrypt(&recv(&mut reader).await?)?;
+    match parse(&response)? {
+        Message::TimeSyncResponse { unix_ms } => {
+            let delay = clock.synced(requested, unix_ms);
+            tracing::info!("Time synced, network delay {} ms", delay.as_millis());
+        }
+        Message::Incompatible { min, max } => {
+            bail!("Gateway supports protocols {min}-{max}, this listener speaks {PROTOCOL_VERSION}")
+        }
+        message => bail!("Expected a time response from the gateway, got {message:?}"),
+    }
+    Ok((connection, read_frames(reader)))
+}
+
+struct Connection {
+    writer: OwnedWriteHalf,
+    transport: TransportState,
+}
+
+/// Frames read by their own task, so waiting for one can be cancelled
+struct FrameReader {
+    frames: mpsc::Receiver<Result<Vec<u8>, anyhow::Error>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for FrameReader {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn read_frames(mut reader: impl AsyncRead + Unpin + Send + 'static) -> FrameReader {
+    let (sender, frames) = mpsc::channel(16);
+    let task = tokio::spawn(async move {
+        loop {
+            let frame = recv(&mut reader).await;
+            let failed = frame.is_err();
+            if sender.send(frame).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    FrameReader { frames, task }
+}
+
+impl Connection {
+    /// Sends readings, the ones the previous connection didn't get acknowledged
+    /// first, answers the gateway and keeps the session alive. Returns an error
+    /// once the connection fails, `Ok` once `readings` is closed.
+    async fn run(
+        mut self,
+        mut reader: FrameReader,
+        readings: &mut mpsc::Receiver<(RuuviRaw, Instant)>,
+        clock: &mut Clock,
+        retry: &mut RetryBuffer,
+    ) -> Result<(), anyhow::Error> {
+        let unacknowledged = retry.unacknowledged();
+        if !unacknowledged.is_empty() {
+            tracing::info!("Resending {} unacknowledged readings", unacknowledged.len());
+        }
+        for batch in unacknowledged.chunks(MAX_BATCH) {
+            self.send_readings(batch, retry).await?;
+        }
+
+        let mut rekey = RekeyPolicy::new(
+            self.transport.sending_nonce(),
+            clock.local_ms(Instant::now()),
+        );
+        let mut next_sync = Instant::now() + TIME_SYNC_INTERVAL;
+        let mut requested = None;
+        let mut heartbeat =
+            tokio::time::interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut watchdog = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                reading = readings.recv() => {
+                    let Some(reading) = reading else {
+                        return Ok(());
+                    };
+                    // Compute the timestamp from the latest sync, corrected for drift
+                    let timestamped = |(mut raw, heard): (RuuviRaw, Instant)| {
+                        raw.set_timestamp(clock.gateway_time(heard));
+                        raw
+                    };
+                    // Readings queued meanwhile share the message
+                    let mut batch = vec![timestamped(reading)];
+                    while batch.len() < MAX_BATCH
+                        && let Ok(reading) = readings.try_recv()
+                    {
+                        batch.push(timestamped(reading));
+                    }
+                    self.send_readings(&batch, retry).await?;
+                }
+                _ = heartbeat.tick() => {
+                    if Instant::now() >= next_sync {
+                        next_sync = Instant::now() + TIME_SYNC_INTERVAL;
+                        self.send(&Message::TimeSyncRequest).await?;
+                        requested = Some(Instant::now());
+                    } else {
+                        self.send(&Message::Heartbeat).await?;
+                    }
+                }
+                frame = reader.frames.recv() => {
+                    let frame = frame.context("Gateway reader stopped")??;
+                    self.handle(&frame, clock, &mut requested, retry)?;
+                }
+                _ = watchdog.tick() => {
+                    if retry.oldest().is_some_and(|sent| sent.elapsed() > ACK_TIMEOUT) {
+                        bail!("No acknowledgement in {}s", ACK_TIMEOUT.as_secs());
+                    }
+                }
+            }
+
+            let nonce = self.transport.sending_nonce();
+            if rekey.due(nonce, clock.local_ms(Instant::now())) {
+                // Messages encrypted from here on use the new key, the gateway
+                // follows once it reads this one
+                self.send(&Message::Rekey).await?;
+                self.transport.rekey_outgoing();
+                rekey.rekeyed(
+                    self.transport.sending_nonce(),
+                    clock.local_ms(Instant::now()),
+                );
+                tracing::info!("Rekeyed the session at message {nonce}");
+            }
+        }
+    }
+
+    /// Applies acknowledgements, rekeys and time responses of the gateway
+    fn handle(
+        &mut self,
+        frame: &[u8],
+        clock: &mut Clock,
+        requested: &mut Option<Instant>,
+        retry: &mut RetryBuffer,
+    ) -> Result<(), anyhow::Error> {
+        let plaintext = self.decrypt(frame)?;
+        match parse(&plaintext)? {
+            Message::Ack(acks) => acks.into_iter().for_each(|ack| retry.acknowledge(ack)),
+            Message::Rekey => self.transport.rekey_incoming(),
+            Message::TimeSyncResponse { unix_ms } => {
+                let Some(requested) = requested.take() else {
+                    tracing::warn!("Ignoring an unrequested time response");
+                    return Ok(());
+                };
+                let delay = clock.synced(requested, unix_ms);
+                tracing::info!(
+                    "Time re-synced, network delay {} ms, drift {:.1} ppm",
+                    delay.as_millis(),
+                    clock.sync.drift_ppm()
+                );
+            }
+            // Updated with the host's package manager instead
+            Message::OtaDownlink(_) => tracing::debug!("Ignoring a firmware offer"),
+            // btleplug picks the scan parameters itself
+            Message::ScanSettings(_) => tracing::debug!("Ignoring scan settings"),
+            // Meant for the firmware, the host has its own service manager and logging
+            Message::Command(command) => tracing::info!("Ignoring {command:?}"),
+            message => tracing::warn!("Ignoring an unexpected gateway message {message:?}"),
+        }
+        Ok(())
+    }
+
+    /// Decrypts a frame, the message borrows from the plaintext
+    fn decrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut plaintext = vec![0u8; frame.len()];
+        let len = self
+            .transport
+            .read_message(frame, &mut plaintext)
+            .context("Failed to decrypt a gateway message")?;
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+
+    /// Sends up to [`MAX_BATCH`] readings in one message and tracks them until acknowledged
+    async fn send_readings(
+        &mut self,
+        readings: &[RuuviRaw],
+        retry: &mut RetryBuffer,
+    ) -> Result<(), anyhow::Error> {
+        let message = match readings {
+            [raw] => Message::Measurement(raw.clone()),
+            // Callers never pass more than a batch
+            _ => Message::Batch(heapless::Vec::from_slice(readings).unwrap()),
+        };
+        readings.iter().for_each(|raw| retry.sent(raw));
+        self.send(&message).await
+    }
+
+    async fn send(&mut self, message: &Message<'_>) -> Result<(), anyhow::Error> {
+        let payload = postcard::to_stdvec(message)?;
+        let mut frame = vec![0u8; payload.len() + TAG_LEN];
+        let len = self.transport.write_message(&payload, &mut frame)?;
+        send(&mut self.writer, &frame[..len]).await
+    }
+}
+
+/// Readings sent but not yet acknowledged by the gateway, with the time they were last sent
+#[derive(Default)]
+struct RetryBuffer {
+    entries: VecDeque<(RuuviRaw, Instant)>,
+}
+
+impl RetryBuffer {
+    /// Track a sent reading, a resent one only gets its send time refreshed
+    fn sent(&mut self, raw: &RuuviRaw) {
+        let now = Instant::now();
+        if let Some(entry) = self.entries.iter_mut().find(|(r, _)| r == raw) {
+            entry.1 = now;
+            return;
+        }
+        if self.entries.len() == RETRY_BUFFER_DEPTH
+            && let Some((dropped, _)) = self.entries.pop_front()
+        {
+            tracing::warn!(
+                "Retry buffer full, dropping an unacknowledged reading of {:02X?}",
+                dropped.mac()
+            );
+        }
+        self.entries.push_back((raw.clone(), now));
+    }
+
+    fn acknowledge(&mut self, ack: Ack) {
+        self.entries.retain(|(raw, _)| Ack::of(raw) != ack);
+    }
+
+    /// When the longest waiting reading was sent
+    fn oldest(&self) -> Option<Instant> {
+        self.entries.iter().map(|(_, sent)| *sent).min()
+    }
+
+    fn unacknowledged(&self) -> Vec<RuuviRaw> {
+        self.entries.iter().map(|(raw, _)| raw.clone()).collect()
+    }
+}
+
+fn parse(plaintext: &[u8]) -> Result<Message<'_>, anyhow::Error> {
+    postcard::from_bytes(plaintext).context("Failed to parse a gateway message")
+}
+
+/// Version of this listener in the [`Hello`], see [`version`]
+fn firmware_version() -> u32 {
+    let part = |v: &str| v.parse().unwrap_or_default();
+    version(
+        part(env!("CARGO_PKG_VERSION_MAJOR")),
+        part(env!("CARGO_PKG_VERSION_MINOR")),
+        part(env!("CARGO_PKG_VERSION_PATCH")),
+    )
+}
+
+async fn recv(socket: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>, anyhow::Error> {
+    let len = socket
+        .read_u16()
+        .await
+        .context("Failed to read the length")?;
+    let mut message = vec![0u8; usize::from(len)];
+    socket
+        .read_exact(&mut message)
+        .await
+        .with_context(|| format!("Failed to read {len} bytes"))?;
+    Ok(message)
+}
+
+async fn send(socket: &mut (impl AsyncWrite + Unpin), message: &[u8]) -> Result<(), anyhow::Error> {
+    let len = u16::try_from(message.len())?;
+    socket.write_all(&len.to_be_bytes()).await?;
+    socket.write_all(message).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryBuffer;
+    use ruuvi_schema::RuuviRaw;
+    use ruuvi_schema::ack::Ack;
+
+    #[test]
+    fn keeps_readings_until_acknowledged() {
+        // Format 3 test vector
+        let payload = [
+            0x03, 0x29, 0x1A, 0x1E, 0xCE, 0x1E, 0xFC, 0x18, 0xF9, 0x42, 0x02, 0xCA, 0x0B, 0x53,
+        ];
+        let reading = |mac| RuuviRaw::parse(&payload, mac, -60, 0).unwrap();
+        let (kitchen, sauna) = (reading([1; 6]), reading([2; 6]));
+        let mut retry = RetryBuffer::default();
+        retry.sent(&kitchen);
+        retry.sent(&sauna);
+        retry.sent(&kitchen);
+        assert_eq!(retry.unacknowledged(), [kitchen.clone(), sauna.clone()]);
+
+        retry.acknowledge(Ack::of(&kitchen));
+        assert_eq!(retry.unacknowledged(), [sauna]);
+    }
+}
@@ -0,0 +1,93 @@
+-- Tables of the gateway. IF NOT EXISTS keeps databases created by hand before
+-- the migrations intact.
+
+CREATE TABLE IF NOT EXISTS tag_readings (
+    id serial PRIMARY KEY,
+    recorded_at timestamptz NOT NULL DEFAULT now(),
+    mac_address macaddr NOT NULL,
+    temperature real,
+    relative_humidity real,
+    pressure integer,
+    acceleration_x smallint,
+    acceleration_y smallint,
+    acceleration_z smallint,
+    battery_voltage real,
+    tx_power smallint,
+    movement_counter smallint,
+    measurement_sequence integer,
+    absolute_humidity real,
+    dew_point_temperature real,
+    rssi smallint
+);
+CREATE INDEX IF NOT EXISTS tag_readings_mac_address_recorded_at_idx
+    ON tag_readings (mac_address, recorded_at);
+
+CREATE TABLE IF NOT EXISTS air_readings (
+    id serial PRIMARY KEY,
+    recorded_at timestamptz NOT NULL DEFAULT now(),
+    mac_address macaddr NOT NULL,
+    temperature real,
+    dew_point_temperature double precision,
+    relative_humidity real,
+    absolute_humidity double precision,
+    pressure integer,
+    pm1_0 real,
+    pm2_5 real,
+    pm4_0 real,
+    pm10_0 real,
+    co2 smallint,
+    voc_index smallint,
+    nox_index smallint,
+    luminosity real,
+    measurement_sequence integer,
+    flags smallint,
+    tx_power smallint,
+    rssi smallint
+);
+CREATE INDEX IF NOT EXISTS air_readings_mac_address_recorded_at_idx
+    ON air_readings (mac_address, recorded_at);
+
+CREATE TABLE IF NOT EXISTS door_events (
+    id serial PRIMARY KEY,
+    recorded_at timestamptz NOT NULL DEFAULT now(),
+    mac_address macaddr NOT NULL,
+    state text NOT NULL,
+    previous_state text,
+    acceleration smallint
+);
+CREATE INDEX IF NOT EXISTS door_events_mac_address_recorded_at_idx
+    ON door_events (mac_address, recorded_at);
+
+CREATE TABLE IF NOT EXISTS tag_models (
+    mac_address macaddr PRIMARY KEY,
+    model text NOT NULL,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS tag_quality (
+    id serial PRIMARY KEY,
+    recorded_at timestamptz NOT NULL DEFAULT now(),
+    mac_address macaddr NOT NULL,
+    score real NOT NULL,
+    sensor real NOT NULL,
+    delivery real NOT NULL,
+    readings bigint NOT NULL,
+    lost bigint NOT NULL,
+    sentinels bigint NOT NULL,
+    clamped bigint NOT NULL,
+    missing_timestamps bigint NOT NULL
+);
+CREATE INDEX IF NOT EXISTS tag_quality_mac_address_recorded_at_idx
+    ON tag_quality (mac_address, recorded_at);
+
+CREATE TABLE IF NOT EXISTS receptions (
+    id serial PRIMARY KEY,
+    recorded_at timestamptz NOT NULL DEFAULT now(),
+    mac_address macaddr NOT NULL,
+    measurement_sequence integer NOT NULL,
+    listener text NOT NULL,
+    rssi smallint,
+    is_primary boolean NOT NULL DEFAULT false
+);
+CREATE INDEX IF NOT EXISTS receptions_mac_address_recorded_at_idx
+    ON receptions (mac_address, recorded_at);
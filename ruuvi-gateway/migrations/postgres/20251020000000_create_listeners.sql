@@ -0,0 +1,16 @@
+-- Listeners that have connected, and which of them heard each stored reading.
+-- listener_id has no foreign key, compressed hypertables can't take one.
+
+CREATE TABLE IF NOT EXISTS listeners (
+    id serial PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    identified boolean NOT NULL,
+    address text NOT NULL,
+    psk text NOT NULL,
+    firmware text,
+    first_seen timestamptz NOT NULL DEFAULT now(),
+    last_seen timestamptz NOT NULL DEFAULT now()
+);
+
+ALTER TABLE tag_readings ADD COLUMN IF NOT EXISTS listener_id integer;
+ALTER TABLE air_readings ADD COLUMN IF NOT EXISTS listener_id integer;
@@ -0,0 +1,10 @@
+-- Registered tags. formats is a comma separated list of the advertisement
+-- formats a tag is expected to send, empty for any.
+
+CREATE TABLE IF NOT EXISTS tags (
+    mac_address macaddr PRIMARY KEY,
+    name text NOT NULL UNIQUE,
+    location text,
+    formats text NOT NULL DEFAULT '',
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,13 @@
+-- Alert rules managed through the API, next to the ones in the config file.
+-- Exactly one of above and below is set.
+
+CREATE TABLE IF NOT EXISTS alert_rules (
+    name text PRIMARY KEY,
+    tag text,
+    metric text NOT NULL,
+    above double precision,
+    below double precision,
+    hysteresis double precision NOT NULL DEFAULT 0,
+    for_secs bigint NOT NULL DEFAULT 0,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,55 @@
+-- Minimum, average and maximum of the readings per tag in 5 minute and hourly
+-- buckets, written by the rollup job. bucket is the start of the bucket.
+
+CREATE TABLE IF NOT EXISTS tag_readings_agg (
+    bucket timestamptz NOT NULL,
+    resolution_secs integer NOT NULL,
+    mac_address macaddr NOT NULL,
+    samples bigint NOT NULL,
+    temperature_min double precision,
+    temperature_avg double precision,
+    temperature_max double precision,
+    relative_humidity_min double precision,
+    relative_humidity_avg double precision,
+    relative_humidity_max double precision,
+    pressure_min double precision,
+    pressure_avg double precision,
+    pressure_max double precision,
+    battery_voltage_min double precision,
+    battery_voltage_avg double precision,
+    battery_voltage_max double precision,
+    PRIMARY KEY (mac_address, resolution_secs, bucket)
+);
+CREATE INDEX IF NOT EXISTS tag_readings_agg_resolution_secs_bucket_idx
+    ON tag_readings_agg (resolution_secs, bucket);
+
+CREATE TABLE IF NOT EXISTS air_readings_agg (
+    bucket timestamptz NOT NULL,
+    resolution_secs integer NOT NULL,
+    mac_address macaddr NOT NULL,
+    samples bigint NOT NULL,
+    temperature_min double precision,
+    temperature_avg double precision,
+    temperature_max double precision,
+    relative_humidity_min double precision,
+    relative_humidity_avg double precision,
+    relative_humidity_max double precision,
+    pressure_min double precision,
+    pressure_avg double precision,
+    pressure_max double precision,
+    pm2_5_min double precision,
+    pm2_5_avg double precision,
+    pm2_5_max double precision,
+    co2_min double precision,
+    co2_avg double precision,
+    co2_max double precision,
+    voc_index_min double precision,
+    voc_index_avg double precision,
+    voc_index_max double precision,
+    nox_index_min double precision,
+    nox_index_avg double precision,
+    nox_index_max double precision,
+    PRIMARY KEY (mac_address, resolution_secs, bucket)
+);
+CREATE INDEX IF NOT EXISTS air_readings_agg_resolution_secs_bucket_idx
+    ON air_readings_agg (resolution_secs, bucket);
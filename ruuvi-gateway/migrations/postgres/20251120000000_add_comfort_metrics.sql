@@ -0,0 +1,8 @@
+-- Comfort metrics derived from temperature and humidity, and the European CAQI
+-- of the particulate matter. NULL for readings stored before.
+
+ALTER TABLE tag_readings ADD COLUMN IF NOT EXISTS heat_index real;
+ALTER TABLE tag_readings ADD COLUMN IF NOT EXISTS humidex real;
+ALTER TABLE tag_readings ADD COLUMN IF NOT EXISTS wet_bulb_temperature real;
+ALTER TABLE tag_readings ADD COLUMN IF NOT EXISTS vapour_pressure_deficit real;
+ALTER TABLE air_readings ADD COLUMN IF NOT EXISTS air_quality_index real;
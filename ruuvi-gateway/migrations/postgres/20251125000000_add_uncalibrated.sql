@@ -0,0 +1,8 @@
+-- Values a calibrated tag reported before its [[calibration]] offsets were
+-- applied. NULL for tags without a calibration.
+
+ALTER TABLE tag_readings ADD COLUMN IF NOT EXISTS uncalibrated_temperature real;
+ALTER TABLE tag_readings ADD COLUMN IF NOT EXISTS uncalibrated_relative_humidity real;
+ALTER TABLE air_readings ADD COLUMN IF NOT EXISTS uncalibrated_temperature real;
+ALTER TABLE air_readings ADD COLUMN IF NOT EXISTS uncalibrated_relative_humidity real;
+ALTER TABLE air_readings ADD COLUMN IF NOT EXISTS uncalibrated_co2 smallint;
@@ -0,0 +1,16 @@
+-- Latest health report of each listener, replaced by every new one. Counters
+-- run since the listener booted.
+
+CREATE TABLE IF NOT EXISTS listener_status (
+    listener_id integer PRIMARY KEY REFERENCES listeners (id) ON DELETE CASCADE,
+    reported_at timestamptz NOT NULL,
+    uptime_secs bigint NOT NULL,
+    heap_free bigint NOT NULL,
+    wifi_rssi smallint,
+    adverts bigint NOT NULL,
+    parse_failures bigint NOT NULL,
+    dropped bigint NOT NULL,
+    queue_overflows bigint NOT NULL,
+    frames_sent bigint NOT NULL,
+    reconnects bigint NOT NULL
+);
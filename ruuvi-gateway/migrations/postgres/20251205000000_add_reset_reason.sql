@@ -0,0 +1,4 @@
+-- Why the listener last started, like power_on or sender_stalled. NULL for
+-- listeners that don't report it.
+
+ALTER TABLE listener_status ADD COLUMN IF NOT EXISTS reset_reason text;
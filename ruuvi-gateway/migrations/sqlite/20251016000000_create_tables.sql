@@ -0,0 +1,91 @@
+-- Tables of the gateway, the Postgres ones with SQLite types. Timestamps are
+-- RFC 3339 text and MAC addresses lowercase aa:bb:cc:dd:ee:ff text.
+
+CREATE TABLE tag_readings (
+    id INTEGER PRIMARY KEY,
+    recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    mac_address TEXT NOT NULL,
+    temperature REAL,
+    relative_humidity REAL,
+    pressure INTEGER,
+    acceleration_x INTEGER,
+    acceleration_y INTEGER,
+    acceleration_z INTEGER,
+    battery_voltage REAL,
+    tx_power INTEGER,
+    movement_counter INTEGER,
+    measurement_sequence INTEGER,
+    absolute_humidity REAL,
+    dew_point_temperature REAL,
+    rssi INTEGER
+);
+CREATE INDEX tag_readings_mac_address_recorded_at_idx
+    ON tag_readings (mac_address, recorded_at);
+CREATE INDEX tag_readings_recorded_at_idx ON tag_readings (recorded_at);
+
+CREATE TABLE air_readings (
+    id INTEGER PRIMARY KEY,
+    recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    mac_address TEXT NOT NULL,
+    temperature REAL,
+    dew_point_temperature REAL,
+    relative_humidity REAL,
+    absolute_humidity REAL,
+    pressure INTEGER,
+    pm1_0 REAL,
+    pm2_5 REAL,
+    pm4_0 REAL,
+    pm10_0 REAL,
+    co2 INTEGER,
+    voc_index INTEGER,
+    nox_index INTEGER,
+    luminosity REAL,
+    measurement_sequence INTEGER,
+    flags INTEGER,
+    tx_power INTEGER,
+    rssi INTEGER
+);
+CREATE INDEX air_readings_mac_address_recorded_at_idx
+    ON air_readings (mac_address, recorded_at);
+CREATE INDEX air_readings_recorded_at_idx ON air_readings (recorded_at);
+
+CREATE TABLE door_events (
+    id INTEGER PRIMARY KEY,
+    recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    mac_address TEXT NOT NULL,
+    state TEXT NOT NULL,
+    previous_state TEXT,
+    acceleration INTEGER
+);
+CREATE INDEX door_events_recorded_at_idx ON door_events (recorded_at);
+
+CREATE TABLE tag_models (
+    mac_address TEXT PRIMARY KEY,
+    model TEXT NOT NULL,
+    updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
+
+CREATE TABLE tag_quality (
+    id INTEGER PRIMARY KEY,
+    recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    mac_address TEXT NOT NULL,
+    score REAL NOT NULL,
+    sensor REAL NOT NULL,
+    delivery REAL NOT NULL,
+    readings INTEGER NOT NULL,
+    lost INTEGER NOT NULL,
+    sentinels INTEGER NOT NULL,
+    clamped INTEGER NOT NULL,
+    missing_timestamps INTEGER NOT NULL
+);
+
+CREATE TABLE receptions (
+    id INTEGER PRIMARY KEY,
+    recorded_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    mac_address TEXT NOT NULL,
+    measurement_sequence INTEGER NOT NULL,
+    listener TEXT NOT NULL,
+    rssi INTEGER,
+    is_primary INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX receptions_recorded_at_idx ON receptions (recorded_at);
@@ -0,0 +1,15 @@
+-- Listeners that have connected, and which of them heard each stored reading.
+
+CREATE TABLE listeners (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    identified INTEGER NOT NULL,
+    address TEXT NOT NULL,
+    psk TEXT NOT NULL,
+    firmware TEXT,
+    first_seen TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now')),
+    last_seen TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
+
+ALTER TABLE tag_readings ADD COLUMN listener_id INTEGER REFERENCES listeners (id);
+ALTER TABLE air_readings ADD COLUMN listener_id INTEGER REFERENCES listeners (id);
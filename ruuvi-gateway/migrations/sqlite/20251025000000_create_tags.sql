@@ -0,0 +1,10 @@
+-- Registered tags. formats is a comma separated list of the advertisement
+-- formats a tag is expected to send, empty for any.
+
+CREATE TABLE tags (
+    mac_address TEXT PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    location TEXT,
+    formats TEXT NOT NULL DEFAULT '',
+    updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
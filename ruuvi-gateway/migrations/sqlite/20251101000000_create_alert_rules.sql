@@ -0,0 +1,13 @@
+-- Alert rules managed through the API, next to the ones in the config file.
+-- Exactly one of above and below is set.
+
+CREATE TABLE alert_rules (
+    name TEXT PRIMARY KEY,
+    tag TEXT,
+    metric TEXT NOT NULL,
+    above REAL,
+    below REAL,
+    hysteresis REAL NOT NULL DEFAULT 0,
+    for_secs INTEGER NOT NULL DEFAULT 0,
+    updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'))
+);
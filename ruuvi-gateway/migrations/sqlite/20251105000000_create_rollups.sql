@@ -0,0 +1,55 @@
+-- Minimum, average and maximum of the readings per tag in 5 minute and hourly
+-- buckets, written by the rollup job. bucket is the start of the bucket.
+
+CREATE TABLE tag_readings_agg (
+    bucket TEXT NOT NULL,
+    resolution_secs INTEGER NOT NULL,
+    mac_address TEXT NOT NULL,
+    samples INTEGER NOT NULL,
+    temperature_min REAL,
+    temperature_avg REAL,
+    temperature_max REAL,
+    relative_humidity_min REAL,
+    relative_humidity_avg REAL,
+    relative_humidity_max REAL,
+    pressure_min REAL,
+    pressure_avg REAL,
+    pressure_max REAL,
+    battery_voltage_min REAL,
+    battery_voltage_avg REAL,
+    battery_voltage_max REAL,
+    PRIMARY KEY (mac_address, resolution_secs, bucket)
+);
+CREATE INDEX tag_readings_agg_resolution_secs_bucket_idx
+    ON tag_readings_agg (resolution_secs, bucket);
+
+CREATE TABLE air_readings_agg (
+    bucket TEXT NOT NULL,
+    resolution_secs INTEGER NOT NULL,
+    mac_address TEXT NOT NULL,
+    samples INTEGER NOT NULL,
+    temperature_min REAL,
+    temperature_avg REAL,
+    temperature_max REAL,
+    relative_humidity_min REAL,
+    relative_humidity_avg REAL,
+    relative_humidity_max REAL,
+    pressure_min REAL,
+    pressure_avg REAL,
+    pressure_max REAL,
+    pm2_5_min REAL,
+    pm2_5_avg REAL,
+    pm2_5_max REAL,
+    co2_min REAL,
+    co2_avg REAL,
+    co2_max REAL,
+    voc_index_min REAL,
+    voc_index_avg REAL,
+    voc_index_max REAL,
+    nox_index_min REAL,
+    nox_index_avg REAL,
+    nox_index_max REAL,
+    PRIMARY KEY (mac_address, resolution_secs, bucket)
+);
+CREATE INDEX air_readings_agg_resolution_secs_bucket_idx
+    ON air_readings_agg (resolution_secs, bucket);
@@ -0,0 +1,5 @@
+-- Manufacturer data of each reading, kept when [raw_payload] is enabled so
+-- readings can be decoded again after a decoder fix.
+
+ALTER TABLE tag_readings ADD COLUMN raw_payload BLOB;
+ALTER TABLE air_readings ADD COLUMN raw_payload BLOB;
@@ -0,0 +1,5 @@
+-- Saturation vapour pressure formula the absolute humidity and dew point of a
+-- reading were derived with, see [humidity]. NULL for readings stored before.
+
+ALTER TABLE tag_readings ADD COLUMN humidity_formula TEXT;
+ALTER TABLE air_readings ADD COLUMN humidity_formula TEXT;
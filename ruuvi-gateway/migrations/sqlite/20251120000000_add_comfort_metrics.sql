@@ -0,0 +1,8 @@
+-- Comfort metrics derived from temperature and humidity, and the European CAQI
+-- of the particulate matter. NULL for readings stored before.
+
+ALTER TABLE tag_readings ADD COLUMN heat_index REAL;
+ALTER TABLE tag_readings ADD COLUMN humidex REAL;
+ALTER TABLE tag_readings ADD COLUMN wet_bulb_temperature REAL;
+ALTER TABLE tag_readings ADD COLUMN vapour_pressure_deficit REAL;
+ALTER TABLE air_readings ADD COLUMN air_quality_index REAL;
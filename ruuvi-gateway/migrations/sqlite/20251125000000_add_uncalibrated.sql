@@ -0,0 +1,8 @@
+-- Values a calibrated tag reported before its [[calibration]] offsets were
+-- applied. NULL for tags without a calibration.
+
+ALTER TABLE tag_readings ADD COLUMN uncalibrated_temperature REAL;
+ALTER TABLE tag_readings ADD COLUMN uncalibrated_relative_humidity REAL;
+ALTER TABLE air_readings ADD COLUMN uncalibrated_temperature REAL;
+ALTER TABLE air_readings ADD COLUMN uncalibrated_relative_humidity REAL;
+ALTER TABLE air_readings ADD COLUMN uncalibrated_co2 INTEGER;
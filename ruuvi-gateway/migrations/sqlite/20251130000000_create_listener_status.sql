@@ -0,0 +1,16 @@
+-- Latest health report of each listener, replaced by every new one. Counters
+-- run since the listener booted.
+
+CREATE TABLE listener_status (
+    listener_id INTEGER PRIMARY KEY REFERENCES listeners (id) ON DELETE CASCADE,
+    reported_at TEXT NOT NULL,
+    uptime_secs INTEGER NOT NULL,
+    heap_free INTEGER NOT NULL,
+    wifi_rssi INTEGER,
+    adverts INTEGER NOT NULL,
+    parse_failures INTEGER NOT NULL,
+    dropped INTEGER NOT NULL,
+    queue_overflows INTEGER NOT NULL,
+    frames_sent INTEGER NOT NULL,
+    reconnects INTEGER NOT NULL
+);
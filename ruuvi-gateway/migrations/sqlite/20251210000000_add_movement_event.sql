@@ -0,0 +1,4 @@
+-- Readings whose movement counter changed since the tag's previous one.
+-- Only format 5 counts movements.
+
+ALTER TABLE tag_readings ADD COLUMN movement_event INTEGER NOT NULL DEFAULT 0;
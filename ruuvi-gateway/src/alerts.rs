@@ -0,0 +1,450 @@
+use crate::database::{AlertRuleRow, Storage};
+use crate::latest::LatestReading;
+use crate::mac::format_mac;
+use crate::notify::Notification;
+use crate::{AppState, Ruuvi};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Reading field a rule watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    Temp,
+    DewPointTemp,
+    RelHumidity,
+    AbsHumidity,
+    AbsPressure,
+    BatteryVoltage,
+    #[serde(rename = "pm1_0")]
+    Pm1_0,
+    #[serde(rename = "pm2_5")]
+    Pm2_5,
+    #[serde(rename = "pm4_0")]
+    Pm4_0,
+    #[serde(rename = "pm10_0")]
+    Pm10_0,
+    Co2,
+    VocIndex,
+    NoxIndex,
+    Luminosity,
+    Rssi,
+}
+
+impl Metric {
+    /// Field of the serialized reading
+    fn field(self) -> &'static str {
+        match self {
+            Self::Temp => "temp",
+            Self::DewPointTemp => "dew_point_temp",
+            Self::RelHumidity => "rel_humidity",
+            Self::AbsHumidity => "abs_humidity",
+            Self::AbsPressure => "abs_pressure",
+            Self::BatteryVoltage => "battery_voltage",
+            Self::Pm1_0 => "pm1_0",
+            Self::Pm2_5 => "pm2_5",
+            Self::Pm4_0 => "pm4_0",
+            Self::Pm10_0 => "pm10_0",
+            Self::Co2 => "co2",
+            Self::VocIndex => "voc_index",
+            Self::NoxIndex => "nox_index",
+            Self::Luminosity => "luminosity",
+            Self::Rssi => "rssi",
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            Self::Temp | Self::DewPointTemp => " °C",
+            Self::RelHumidity => " %",
+            Self::AbsHumidity => " g/m³",
+            Self::AbsPressure => " Pa",
+            Self::BatteryVoltage => " V",
+            Self::Pm1_0 | Self::Pm2_5 | Self::Pm4_0 | Self::Pm10_0 => " µg/m³",
+            Self::Co2 => " ppm",
+            Self::VocIndex | Self::NoxIndex => "",
+            Self::Luminosity => " lx",
+            Self::Rssi => " dBm",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        serde_json::from_value(Value::String(s.to_owned())).ok()
+    }
+}
+
+/// Raises an alert while a metric stays above or below a threshold. The alert
+/// clears once the metric is back past the threshold by `hysteresis`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    /// MAC or registered name, every tag when absent
+    #[serde(default)]
+    pub tag: Option<String>,
+    pub metric: Metric,
+    #[serde(default)]
+    pub above: Option<f64>,
+    #[serde(default)]
+    pub below: Option<f64>,
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// How long the threshold has to be crossed before the alert fires
+    #[serde(default)]
+    pub for_secs: u64,
+}
+
+impl AlertRule {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.name.trim().is_empty() {
+            anyhow::bail!("An alert rule needs a name");
+        }
+        if self.above.is_some() == self.below.is_some() {
+            anyhow::bail!("Alert rule {:?} needs either `above` or `below`", self.name);
+        }
+        if self.hysteresis.is_nan() || self.hysteresis < 0.0 {
+            anyhow::bail!("Alert rule {:?} has a negative hysteresis", self.name);
+        }
+        Ok(())
+    }
+
+    /// Whether `value` crosses the threshold, or with `firing` still hasn't cleared
+    fn crossed(&self, value: f64, firing: bool) -> bool {
+        let margin = if firing { self.hysteresis } else { 0.0 };
+        match (self.above, self.below) {
+            (Some(above), _) => value > above - margin,
+            (_, Some(below)) => value < below + margin,
+            (None, None) => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        let unit = self.metric.unit();
+        match (self.above, self.below) {
+            (Some(above), _) => format!("{} above {above}{unit}", self.metric.field()),
+            (_, Some(below)) => format!("{} below {below}{unit}", self.metric.field()),
+            (None, None) => self.metric.field().to_owned(),
+        }
+    }
+
+    pub fn to_row(&self) -> AlertRuleRow {
+        AlertRuleRow {
+            name: self.name.clone(),
+            tag: self.tag.clone(),
+            metric: self.metric.field().to_owned(),
+            above: self.above,
+            below: self.below,
+            hysteresis: self.hysteresis,
+            for_secs: self.for_secs as i64,
+        }
+    }
+
+    fn from_row(row: AlertRuleRow) -> Option<Self> {
+        Some(Self {
+            metric: Metric::parse(&row.metric)?,
+            name: row.name,
+            tag: row.tag,
+            above: row.above,
+            below: row.below,
+            hysteresis: row.hysteresis,
+            for_secs: row.for_secs.max(0) as u64,
+        })
+    }
+}
+
+/// Where a rule comes from, config rules can't be changed through the API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    Config,
+    Database,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rule {
+    #[serde(flatten)]
+    pub rule: AlertRule,
+    pub source: Source,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// Crossed since the timestamp, not for long enough yet
+    Pending(DateTime<Utc>),
+    Firing(DateTime<Utc>),
+}
+
+type Tracked = (Phase, f64);
+
+/// Alert of a rule for one tag
+#[derive(Debug, Clone, Serialize)]
+pub struct ActiveAlert {
+    pub rule: String,
+    pub mac: String,
+    pub since: DateTime<Utc>,
+    pub value: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transition {
+    Fired,
+    Resolved,
+}
+
+/// Evaluates the rules on the stored readings
+pub struct Alerts {
+    rules: RwLock<Vec<Rule>>,
+    /// Latest value with the phase, per rule name and tag
+    phases: Mutex<HashMap<(String, [u8; 6]), Tracked>>,
+}
+
+impl Alerts {
+    pub fn new(config: &[AlertRule], stored: Vec<AlertRuleRow>) -> Result<Self, anyhow::Error> {
+        let mut rules = Vec::new();
+        for rule in config {
+            rule.validate()?;
+            rules.push(Rule {
+                rule: rule.clone(),
+                source: Source::Config,
+            });
+        }
+        for row in stored {
+            let name = row.name.clone();
+            match AlertRule::from_row(row) {
+                Some(rule) if !rules.iter().any(|r| r.rule.name == rule.name) => rules.push(Rule {
+                    rule,
+                    source: Source::Database,
+                }),
+                Some(_) => {
+                    tracing::warn!("Alert rule {name:?} is in the config, ignoring the stored one")
+                }
+                None => {
+                    tracing::warn!("Ignoring stored alert rule {name:?} with an unknown metric")
+                }
+            }
+        }
+        Ok(Self {
+            rules: RwLock::new(rules),
+            phases: Mutex::default(),
+        })
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn source(&self, name: &str) -> Option<Source> {
+        let rules = self.rules.read().unwrap();
+        rules.iter().find(|r| r.rule.name == name).map(|r| r.source)
+    }
+
+    /// Add or replace a stored rule, failing for the name of a config rule
+    pub async fn set(&self, storage: &dyn Storage, rule: AlertRule) -> Result<(), anyhow::Error> {
+        rule.validate()?;
+        if self.source(&rule.name) == Some(Source::Config) {
+            anyhow::bail!("Alert rule {:?} is in the config file", rule.name);
+        }
+        storage.upsert_alert_rule(&rule.to_row()).await?;
+        let mut rules = self.rules.write().unwrap();
+        rules.retain(|r| r.rule.name != rule.name);
+        self.forget(&rule.name);
+        rules.push(Rule {
+            rule,
+            source: Source::Database,
+        });
+        Ok(())
+    }
+
+    /// `false` when there was no such stored rule
+    pub async fn remove(&self, storage: &dyn Storage, name: &str) -> Result<bool, anyhow::Error> {
+        if self.source(name) == Some(Source::Config) {
+            anyhow::bail!("Alert rule {name:?} is in the config file");
+        }
+        let removed = storage.delete_alert_rule(name).await?;
+        self.rules.write().unwrap().retain(|r| r.rule.name != name);
+        self.forget(name);
+        Ok(removed)
+    }
+
+    fn forget(&self, name: &str) {
+        self.phases
+            .lock()
+            .unwrap()
+            .retain(|(rule, _), _| rule != name);
+    }
+
+    pub fn active(&self) -> Vec<ActiveAlert> {
+        let mut active: Vec<_> = self
+            .phases
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|((rule, mac), (phase, value))| match phase {
+                Phase::Firing(since) => Some(ActiveAlert {
+                    rule: rule.clone(),
+                    mac: format_mac(mac),
+                    since: *since,
+                    value: *value,
+                }),
+                Phase::Pending(_) => None,
+            })
+            .collect();
+        active.sort_by(|a, b| a.rule.cmp(&b.rule).then(a.mac.cmp(&b.mac)));
+        active
+    }
+
+    /// Rules applying to the reading with the value they watch
+    fn matching(&self, state: &AppState, data: &Ruuvi) -> Vec<(AlertRule, f64)> {
+        let fields = fields(data);
+        let mac = data.mac();
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|r| &r.rule)
+            .filter(|rule| {
+                rule.tag
+                    .as_deref()
+                    .is_none_or(|tag| state.registry.resolve(tag) == Some(mac))
+            })
+            .filter_map(|rule| {
+                let value = fields.get(rule.metric.field())?.as_f64()?;
+                Some((rule.clone(), value))
+            })
+            .collect()
+    }
+
+    fn observe(
+        &self,
+        rule: &AlertRule,
+        mac: [u8; 6],
+        value: f64,
+        at: DateTime<Utc>,
+    ) -> Option<Transition> {
+        let mut phases = self.phases.lock().unwrap();
+        let key = (rule.name.clone(), mac);
+        let firing = matches!(phases.get(&key), Some((Phase::Firing(_), _)));
+        if !rule.crossed(value, firing) {
+            return phases.remove(&key).and_then(|(phase, _)| {
+                matches!(phase, Phase::Firing(_)).then_some(Transition::Resolved)
+            });
+        }
+        let hold = TimeDelta::seconds(rule.for_secs.min(i64::MAX as u64) as i64);
+        let (phase, transition) = match phases.get(&key).map(|(phase, _)| *phase) {
+            Some(Phase::Firing(since)) => (Phase::Firing(since), None),
+            Some(Phase::Pending(since)) if at - since >= hold => {
+                (Phase::Firing(since), Some(Transition::Fired))
+            }
+            Some(Phase::Pending(since)) => (Phase::Pending(since), None),
+            None if hold.is_zero() => (Phase::Firing(at), Some(Transition::Fired)),
+            None => (Phase::Pending(at), None),
+        };
+        phases.insert(key, (phase, value));
+        transition
+    }
+}
+
+/// Fields of a reading by name, as in the JSON outputs
+fn fields(data: &Ruuvi) -> serde_json::Map<String, Value> {
+    let fields = match data {
+        Ruuvi::V2(v2) => serde_json::to_value(v2),
+        Ruuvi::E1(e1) => serde_json::to_value(e1),
+        Ruuvi::V1(v1) => serde_json::to_value(v1),
+        Ruuvi::V6(v6) => serde_json::to_value(v6),
+    };
+    match fields {
+        Ok(Value::Object(fields)) => fields,
+        _ => serde_json::Map::new(),
+    }
+}
+
+/// Evaluate the rules on every stored reading and notify when an alert fires or clears
+pub async fn watch(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let mut readings = state.live.subscribe();
+    loop {
+        let reading = match readings.recv().await {
+            Ok(reading) => reading,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("Alert evaluation fell behind, {skipped} readings skipped");
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        evaluate(&state, &reading).await;
+    }
+}
+
+async fn evaluate(state: &AppState, reading: &LatestReading) {
+    let data = &reading.data;
+    let mac = data.mac();
+    for (rule, value) in state.alerts.matching(state, data) {
+        let Some(transition) = state.alerts.observe(&rule, mac, value, data.timestamp()) else {
+            continue;
+        };
+        let tag = match &reading.name {
+            Some(name) => format!("{name} ({})", format_mac(&mac)),
+            None => format_mac(&mac),
+        };
+        let unit = rule.metric.unit();
+        let notification = match transition {
+            Transition::Fired => Notification {
+                title: format!("Alert: {}", rule.name),
+                body: format!(
+                    "{tag}: {} is {value}{unit}, {}",
+                    rule.metric.field(),
+                    rule.describe()
+                ),
+            },
+            Transition::Resolved => Notification {
+                title: format!("Resolved: {}", rule.name),
+                body: format!("{tag}: {} is back to {value}{unit}", rule.metric.field()),
+            },
+        };
+        state.notifiers.notify(&notification).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AlertRule, Alerts, Metric, Transition};
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    #[test]
+    fn fires_after_the_hold_and_clears_with_hysteresis() {
+        let rule = AlertRule {
+            name: "CO2".to_owned(),
+            tag: None,
+            metric: Metric::Co2,
+            above: Some(1200.0),
+            below: None,
+            hysteresis: 100.0,
+            for_secs: 600,
+        };
+        let alerts = Alerts::new(std::slice::from_ref(&rule), Vec::new()).unwrap();
+        let mac = [1, 2, 3, 4, 5, 6];
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+        let at = |minutes| start + TimeDelta::minutes(minutes);
+        let observe = |value, minutes| alerts.observe(&rule, mac, value, at(minutes));
+
+        assert_eq!(observe(1300.0, 0), None);
+        // A dip restarts the hold
+        assert_eq!(observe(1100.0, 5), None);
+        assert_eq!(observe(1300.0, 6), None);
+        assert_eq!(observe(1250.0, 15), None);
+        assert_eq!(observe(1250.0, 16), Some(Transition::Fired));
+        // Below the threshold, but not by the hysteresis
+        assert_eq!(observe(1150.0, 17), None);
+        assert_eq!(observe(1050.0, 18), Some(Transition::Resolved));
+        assert_eq!(observe(1050.0, 19), None);
+        assert!(alerts.active().is_empty());
+
+        let both = AlertRule {
+            below: Some(5.0),
+            ..rule
+        };
+        assert!(both.validate().is_err());
+    }
+}
@@ -0,0 +1,840 @@
+use crate::AppState;
+use crate::alerts::{ActiveAlert, AlertRule, Metric, Rule, Source};
+use crate::auth::{Caller, Role, require};
+use crate::battery::forecast_tag;
+use crate::database::{HistoryBucket, HistoryCursor, HistoryRow};
+use crate::mac::format_mac;
+use crate::offline::OfflineTag;
+use crate::pagination::{MAX_LIMIT, next_cursor, page_limit};
+use crate::quality::QualitySnapshot;
+use crate::registry::{DataFormat, RegisteredTag};
+use crate::retention::RetentionReport;
+use crate::stats::ConnectionSnapshot;
+use crate::timezone::{local_midnight_days_ago, localize_timestamps};
+use crate::units::Units;
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::middleware::from_fn_with_state;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
+use chrono_tz::Tz;
+use futures_util::{Stream, stream};
+use ruuvi_schema::TagModel;
+use ruuvi_schema::protocol::{Command, LogLevel, MAX_FILTER_MACS, ScanSettings};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tower_http::compression::CompressionLayer;
+
+pub async fn serve(state: Arc<AppState>, listen: &str, dev: bool) -> Result<(), anyhow::Error> {
+    if state.config.api.tokens.is_empty() {
+        tracing::warn!("No API tokens configured, HTTP API is open to everyone");
+    }
+
+    let read = Router::new()
+        .route("/latest", get(latest))
+        .route("/latest/{mac}", get(latest_by_mac))
+        .route("/live", get(live))
+        .route("/tags", get(tag_list))
+        .route("/tags/{mac}/latest", get(latest_by_mac))
+        .route("/tags/{mac}/history", get(history_page))
+        .route("/tags/{mac}/export", get(export_csv))
+        .route("/tags/{mac}/daily", get(daily))
+        .route("/tags/{mac}/battery", get(battery))
+        .route("/tags/{mac}/quality", get(tag_quality))
+        .route("/quality", get(quality))
+        .route("/doors/events", get(door_event_list))
+        .route("/coverage", get(coverage_report))
+        .route("/offline", get(offline_tags))
+        .route("/alerts/rules", get(alert_rules))
+        .route("/alerts/active", get(active_alerts))
+        .route("/metrics", get(metrics));
+    #[cfg(feature = "graphql")]
+    let read = read.merge(crate::graphql::router(state.clone()));
+
+    let alerts = Router::new().route(
+        "/alerts/rules/{name}",
+        put(set_alert_rule).delete(delete_alert_rule),
+    );
+
+    let admin = Router::new()
+        .route("/connections", get(connections))
+        .route("/listeners/{listener}/commands", post(send_command))
+        .route("/retention", get(retention))
+        .route("/tags/{mac}", put(register_tag).delete(unregister_tag));
+    let admin = if dev {
+        tracing::warn!("Development endpoints enabled");
+        admin.merge(crate::dev::router())
+    } else {
+        admin
+    };
+
+    let app = Router::new()
+        .merge(crate::web::router())
+        .merge(scoped(read, &state, Role::Read))
+        .merge(scoped(alerts, &state, Role::Alerts))
+        .merge(scoped(admin, &state, Role::Admin))
+        // History and exports compress very well, gzip or brotli based on Accept-Encoding
+        .layer(CompressionLayer::new())
+        .with_state(state);
+
+    let listener = TcpListener::bind(listen).await?;
+    tracing::info!("HTTP API listening on {listen}");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Routes only reachable with a token granting `role`
+fn scoped(
+    router: Router<Arc<AppState>>,
+    state: &Arc<AppState>,
+    role: Role,
+) -> Router<Arc<AppState>> {
+    router.layer(from_fn_with_state((state.clone(), role), require))
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatQuery {
+    units: Option<Units>,
+    tz: Option<Tz>,
+}
+
+/// Response unit system and timezone. Resolved from the query, then the
+/// caller's token and finally the configured defaults.
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    units: Units,
+    tz: Tz,
+}
+
+impl FromRequestParts<Arc<AppState>> for Format {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Query(query) = Query::<FormatQuery>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let token_units = parts.extensions.get::<Caller>().and_then(|c| c.units);
+        Ok(Self {
+            units: query
+                .units
+                .or(token_units)
+                .unwrap_or(state.config.api.units),
+            tz: query.tz.unwrap_or(state.config.api.timezone),
+        })
+    }
+}
+
+/// Serialize a response in the requested unit system and timezone
+fn formatted<T: Serialize>(format: Format, value: &T) -> Result<Json<Value>, StatusCode> {
+    to_formatted_value(format, value).map(Json)
+}
+
+fn to_formatted_value<T: Serialize>(format: Format, value: &T) -> Result<Value, StatusCode> {
+    let mut value = serde_json::to_value(value).map_err(|e| {
+        tracing::error!("Failed to serialize response: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    format.units.apply(&mut value);
+    localize_timestamps(&mut value, format.tz);
+    Ok(value)
+}
+
+/// MAC of a MAC address or a registered tag name
+fn resolve(state: &AppState, tag: &str) -> Result<[u8; 6], StatusCode> {
+    state.registry.resolve(tag).ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Deserialize)]
+struct LatestQuery {
+    model: Option<TagModel>,
+}
+
+async fn latest(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LatestQuery>,
+    format: Format,
+) -> Result<Json<Value>, StatusCode> {
+    let mut readings = state.latest.all();
+    if let Some(model) = query.model {
+        readings.retain(|reading| reading.model == model);
+    }
+    formatted(format, &readings)
+}
+
+async fn latest_by_mac(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+    format: Format,
+) -> Result<Json<Value>, StatusCode> {
+    let mac = resolve(&state, &mac)?;
+    let reading = state.latest.get(&mac).ok_or(StatusCode::NOT_FOUND)?;
+    formatted(format, &reading)
+}
+
+#[derive(Debug, Deserialize)]
+struct LiveQuery {
+    /// Comma separated MACs, every tag when absent
+    mac: Option<String>,
+}
+
+/// Server-sent event per stored reading, in the shape of `/latest`
+async fn live(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<LiveQuery>,
+    format: Format,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let macs = query
+        .mac
+        .as_deref()
+        .map(|macs| {
+            macs.split(',')
+                .map(|mac| resolve(&state, mac))
+                .collect::<Result<HashSet<_>, _>>()
+        })
+        .transpose()?;
+
+    let stream = stream::unfold(state.live.subscribe(), move |mut receiver| {
+        let macs = macs.clone();
+        async move {
+            loop {
+                let reading = match receiver.recv().await {
+                    Ok(reading) => reading,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(
+                            "Live stream client fell behind, {skipped} readings skipped"
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                if macs
+                    .as_ref()
+                    .is_some_and(|m| !m.contains(&reading.data.mac()))
+                {
+                    continue;
+                }
+                let Ok(value) = to_formatted_value(format, &reading) else {
+                    continue;
+                };
+                let event = Event::default().event("reading").data(value.to_string());
+                return Some((Ok(event), receiver));
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Debug, Serialize)]
+struct Tag {
+    mac: String,
+    /// Registered name
+    name: Option<String>,
+    model: String,
+    /// Newest stored reading
+    last_seen: Option<DateTime<Utc>>,
+    /// Registered location, or else the zone of the latest reading since the gateway started
+    location: Option<String>,
+}
+
+async fn tag_list(
+    State(state): State<Arc<AppState>>,
+    format: Format,
+) -> Result<Json<Value>, StatusCode> {
+    let rows = state.storage.tags().await.map_err(|e| {
+        tracing::error!("Failed to query tags: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let tags: Vec<_> = rows
+        .into_iter()
+        .map(|row| {
+            let mac = row.mac_address.bytes();
+            let registered = state.registry.get(&mac);
+            let location = registered.as_ref().and_then(|tag| tag.location.clone());
+            Tag {
+                mac: format_mac(&mac),
+                name: registered.map(|tag| tag.name),
+                model: row.model,
+                last_seen: row.last_seen,
+                location: location.or_else(|| state.latest.get(&mac).and_then(|r| r.location)),
+            }
+        })
+        .collect();
+    formatted(format, &tags)
+}
+
+async fn quality(State(state): State<Arc<AppState>>) -> Json<Vec<QualitySnapshot>> {
+    Json(state.quality.all())
+}
+
+async fn tag_quality(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+) -> Result<Json<QualitySnapshot>, StatusCode> {
+    let mac = resolve(&state, &mac)?;
+    state
+        .quality
+        .get(&mac)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    /// Average the readings over buckets of this many seconds instead of paging them
+    step: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryPage {
+    readings: Vec<HistoryRow>,
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BucketPage {
+    readings: Vec<HistoryBucket>,
+}
+
+/// Fetch one page of history, the range defaults to the last 24 hours
+async fn fetch_page(
+    state: &AppState,
+    mac: &str,
+    query: &HistoryQuery,
+) -> Result<(Vec<HistoryRow>, Option<String>), StatusCode> {
+    let mac = resolve(state, mac)?;
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - TimeDelta::days(1));
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(|c| HistoryCursor::decode(c).ok_or(StatusCode::BAD_REQUEST))
+        .transpose()?;
+    let limit = page_limit(query.limit);
+
+    // Fetch one extra row to know whether there is a next page
+    let mut rows = state
+        .storage
+        .history(mac, from, to, cursor.as_ref(), limit + 1)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query history: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let next = next_cursor(&mut rows, limit);
+    Ok((rows, next))
+}
+
+/// Averaged history, limited to as many buckets as a page has rows
+async fn fetch_buckets(
+    state: &AppState,
+    mac: &str,
+    query: &HistoryQuery,
+    step: u32,
+) -> Result<Vec<HistoryBucket>, StatusCode> {
+    let mac = resolve(state, mac)?;
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - TimeDelta::days(1));
+    if step == 0 || (to - from).num_seconds() / i64::from(step) > MAX_LIMIT {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    state
+        .storage
+        .history_buckets(mac, from, to, step)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query history buckets: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+}
+
+async fn history_page(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+    Query(query): Query<HistoryQuery>,
+    format: Format,
+) -> Result<Json<Value>, StatusCode> {
+    if let Some(step) = query.step {
+        let readings = fetch_buckets(&state, &mac, &query, step).await?;
+        return formatted(format, &BucketPage { readings });
+    }
+    let (readings, next_cursor) = fetch_page(&state, &mac, &query).await?;
+    formatted(
+        format,
+        &HistoryPage {
+            readings,
+            next_cursor,
+        },
+    )
+}
+
+/// CSV page of history in metric units and UTC, the next cursor is in the `X-Next-Cursor` header
+async fn export_csv(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<(HeaderMap, String), StatusCode> {
+    let (rows, next_cursor) = fetch_page(&state, &mac, &query).await?;
+
+    let opt = |v: Option<String>| v.unwrap_or_default();
+    let mut csv = String::from(
+        "timestamp,format,temperature,dew_point_temperature,relative_humidity,absolute_humidity,\
+        pressure,acceleration_x,acceleration_y,acceleration_z,battery_voltage,movement_counter,\
+        pm1_0,pm2_5,pm4_0,pm10_0,co2,voc_index,nox_index,luminosity,measurement_sequence,\
+        tx_power,rssi\n",
+    );
+    for row in rows {
+        let fields = [
+            row.timestamp.to_rfc3339(),
+            row.format,
+            opt(row.temp.map(|v| v.to_string())),
+            opt(row.dew_point_temp.map(|v| v.to_string())),
+            opt(row.rel_humidity.map(|v| v.to_string())),
+            opt(row.abs_humidity.map(|v| v.to_string())),
+            opt(row.abs_pressure.map(|v| v.to_string())),
+            opt(row.acc_x.map(|v| v.to_string())),
+            opt(row.acc_y.map(|v| v.to_string())),
+            opt(row.acc_z.map(|v| v.to_string())),
+            opt(row.battery_voltage.map(|v| v.to_string())),
+            opt(row.movement_counter.map(|v| v.to_string())),
+            opt(row.pm1_0.map(|v| v.to_string())),
+            opt(row.pm2_5.map(|v| v.to_string())),
+            opt(row.pm4_0.map(|v| v.to_string())),
+            opt(row.pm10_0.map(|v| v.to_string())),
+            opt(row.co2.map(|v| v.to_string())),
+            opt(row.voc_index.map(|v| v.to_string())),
+            opt(row.nox_index.map(|v| v.to_string())),
+            opt(row.luminosity.map(|v| v.to_string())),
+            opt(row.measurement_seq.map(|v| v.to_string())),
+            opt(row.tx_power.map(|v| v.to_string())),
+            opt(row.rssi.map(|v| v.to_string())),
+        ];
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/csv"));
+    if let Some(cursor) = next_cursor.and_then(|c| HeaderValue::from_str(&c).ok()) {
+        headers.insert("x-next-cursor", cursor);
+    }
+    Ok((headers, csv))
+}
+
+#[derive(Debug, Deserialize)]
+struct DailyQuery {
+    days: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct DailySummary {
+    day: NaiveDate,
+    temp_min: Option<f32>,
+    temp_max: Option<f32>,
+    temp_avg: Option<f64>,
+    humidity_min: Option<f32>,
+    humidity_max: Option<f32>,
+    humidity_avg: Option<f64>,
+    samples: i64,
+}
+
+async fn daily(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+    Query(query): Query<DailyQuery>,
+    format: Format,
+) -> Result<Json<Value>, StatusCode> {
+    let mac = resolve(&state, &mac)?;
+    let tz = format.tz;
+    let since =
+        local_midnight_days_ago(tz, query.days.unwrap_or(7)).ok_or(StatusCode::BAD_REQUEST)?;
+    let rows = state
+        .storage
+        .daily_summary(mac, tz, since)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query daily summary: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let summaries: Vec<_> = rows
+        .into_iter()
+        .map(|row| DailySummary {
+            day: row.day,
+            temp_min: row.temp_min,
+            temp_max: row.temp_max,
+            temp_avg: row.temp_avg,
+            humidity_min: row.humidity_min,
+            humidity_max: row.humidity_max,
+            humidity_avg: row.humidity_avg,
+            samples: row.samples,
+        })
+        .collect();
+    formatted(format, &summaries)
+}
+
+async fn battery(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+    format: Format,
+) -> Result<Json<Value>, StatusCode> {
+    let mac = resolve(&state, &mac)?;
+    let forecast = forecast_tag(&state, mac)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to forecast battery: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    formatted(format, &forecast)
+}
+
+#[derive(Debug, Deserialize)]
+struct DoorEventQuery {
+    mac: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct DoorEvent {
+    mac: String,
+    timestamp: DateTime<Utc>,
+    state: String,
+    previous_state: Option<String>,
+}
+
+async fn door_event_list(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<DoorEventQuery>,
+    format: Format,
+) -> Result<Json<Value>, StatusCode> {
+    let mac = query
+        .mac
+        .as_deref()
+        .map(|mac| resolve(&state, mac))
+        .transpose()?;
+    let rows = state
+        .storage
+        .door_events(mac, query.limit.unwrap_or(100))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to query door events: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let events: Vec<_> = rows
+        .into_iter()
+        .map(|row| DoorEvent {
+            mac: format_mac(&row.mac_address.bytes()),
+            timestamp: row.recorded_at,
+            state: row.state,
+            previous_state: row.previous_state,
+        })
+        .collect();
+    formatted(format, &events)
+}
+
+#[derive(Debug, Deserialize)]
+struct CoverageQuery {
+    hours: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListenerCoverage {
+    listener: String,
+    tags: Vec<TagCoverage>,
+}
+
+#[derive(Debug, Serialize)]
+struct TagCoverage {
+    mac: String,
+    packets: i64,
+    avg_rssi: Option<f64>,
+    /// Share of the tag's measurements this listener heard
+    share: f64,
+    /// Share of the tag's measurements where this listener was the loudest
+    primary_share: f64,
+}
+
+async fn coverage_report(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CoverageQuery>,
+) -> Result<Json<Vec<ListenerCoverage>>, StatusCode> {
+    let hours = query.hours.unwrap_or(24);
+    if hours == 0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let since = Utc::now()
+        .checked_sub_signed(TimeDelta::hours(i64::from(hours)))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let rows = state.storage.coverage(since).await.map_err(|e| {
+        tracing::error!("Failed to query coverage: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut report: Vec<ListenerCoverage> = Vec::new();
+    for row in rows {
+        let measurements = row.measurements.max(1) as f64;
+        let tag = TagCoverage {
+            mac: format_mac(&row.mac_address.bytes()),
+            packets: row.packets,
+            avg_rssi: row.avg_rssi,
+            share: row.packets as f64 / measurements,
+            primary_share: row.primary_packets as f64 / measurements,
+        };
+        // Rows are ordered by listener
+        match report.last_mut() {
+            Some(last) if last.listener == row.listener => last.tags.push(tag),
+            _ => report.push(ListenerCoverage {
+                listener: row.listener,
+                tags: vec![tag],
+            }),
+        }
+    }
+    Ok(Json(report))
+}
+
+#[derive(Debug, Deserialize)]
+struct Registration {
+    name: String,
+    location: Option<String>,
+    /// Any format is expected when empty
+    #[serde(default)]
+    formats: Vec<DataFormat>,
+}
+
+/// Register a tag or update its registration, the path takes a MAC or the current name
+async fn register_tag(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+    Json(registration): Json<Registration>,
+) -> Result<Json<RegisteredTag>, StatusCode> {
+    let mac = resolve(&state, &mac)?;
+    let tag = RegisteredTag {
+        mac,
+        name: registration.name,
+        location: registration.location,
+        formats: registration.formats,
+    };
+    tag.validate().map_err(|_| StatusCode::BAD_REQUEST)?;
+    if state
+        .registry
+        .resolve(&tag.name)
+        .is_some_and(|other| other != mac)
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+    state
+        .registry
+        .set(state.storage.as_ref(), tag.clone())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to register tag: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(tag))
+}
+
+async fn unregister_tag(
+    State(state): State<Arc<AppState>>,
+    Path(mac): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let mac = resolve(&state, &mac)?;
+    let removed = state
+        .registry
+        .remove(state.storage.as_ref(), mac)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to unregister tag: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(if removed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Ingest statistics of the open listener connections
+/// Tags not heard for `offline.timeout_secs`
+async fn offline_tags(State(state): State<Arc<AppState>>) -> Json<Vec<OfflineTag>> {
+    let tags = state
+        .watchdog
+        .offline()
+        .into_iter()
+        .map(|(mac, last_seen)| OfflineTag {
+            mac: format_mac(&mac),
+            name: state.registry.get(&mac).map(|tag| tag.name),
+            last_seen,
+        })
+        .collect();
+    Json(tags)
+}
+
+async fn alert_rules(State(state): State<Arc<AppState>>) -> Json<Vec<Rule>> {
+    Json(state.alerts.rules())
+}
+
+async fn active_alerts(State(state): State<Arc<AppState>>) -> Json<Vec<ActiveAlert>> {
+    Json(state.alerts.active())
+}
+
+#[derive(Debug, Deserialize)]
+struct AlertRuleBody {
+    /// MAC or registered name, every tag when absent
+    tag: Option<String>,
+    metric: Metric,
+    above: Option<f64>,
+    below: Option<f64>,
+    #[serde(default)]
+    hysteresis: f64,
+    #[serde(default)]
+    for_secs: u64,
+}
+
+/// Add an alert rule or replace the stored one of the same name
+async fn set_alert_rule(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Json(body): Json<AlertRuleBody>,
+) -> Result<Json<AlertRule>, StatusCode> {
+    let rule = AlertRule {
+        name,
+        tag: body.tag,
+        metric: body.metric,
+        above: body.above,
+        below: body.below,
+        hysteresis: body.hysteresis,
+        for_secs: body.for_secs,
+    };
+    rule.validate().map_err(|_| StatusCode::BAD_REQUEST)?;
+    if state.alerts.source(&rule.name) == Some(Source::Config) {
+        return Err(StatusCode::CONFLICT);
+    }
+    state
+        .alerts
+        .set(state.storage.as_ref(), rule.clone())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to store alert rule: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(rule))
+}
+
+async fn delete_alert_rule(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    if state.alerts.source(&name) == Some(Source::Config) {
+        return Err(StatusCode::CONFLICT);
+    }
+    let removed = state
+        .alerts
+        .remove(state.storage.as_ref(), &name)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to delete alert rule: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(if removed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+async fn retention(State(state): State<Arc<AppState>>) -> Json<RetentionReport> {
+    Json(state.retention.report())
+}
+
+/// Health of the listeners in the Prometheus text format
+async fn metrics(State(state): State<Arc<AppState>>) -> Result<(HeaderMap, String), StatusCode> {
+    let rows = state.storage.listener_statuses().await.map_err(|e| {
+        tracing::error!("Failed to query listener status: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    Ok((headers, crate::metrics::render(&rows)))
+}
+
+async fn connections(State(state): State<Arc<AppState>>) -> Json<Vec<ConnectionSnapshot>> {
+    Json(state.connections.snapshot())
+}
+
+/// [`Command`] with the MACs of a tag filter as text
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CommandRequest {
+    Scan(ScanSettings),
+    Led(bool),
+    LogLevel(LogLevel),
+    TagFilter {
+        #[serde(default, deserialize_with = "crate::mac::deserialize_list")]
+        allow: Vec<[u8; 6]>,
+        #[serde(default, deserialize_with = "crate::mac::deserialize_list")]
+        deny: Vec<[u8; 6]>,
+    },
+    Telemetry,
+    Reboot,
+}
+
+impl TryFrom<CommandRequest> for Command {
+    type Error = StatusCode;
+
+    fn try_from(request: CommandRequest) -> Result<Self, StatusCode> {
+        let macs = |macs: Vec<[u8; 6]>| {
+            heapless::Vec::<_, MAX_FILTER_MACS>::from_slice(&macs)
+                .map_err(|_| StatusCode::BAD_REQUEST)
+        };
+        Ok(match request {
+            CommandRequest::Scan(settings) if !settings.is_valid() => {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            CommandRequest::Scan(settings) => Command::Scan(settings),
+            CommandRequest::Led(on) => Command::Led(on),
+            CommandRequest::LogLevel(level) => Command::LogLevel(level),
+            CommandRequest::TagFilter { allow, deny } => Command::TagFilter {
+                allow: macs(allow)?,
+                deny: macs(deny)?,
+            },
+            CommandRequest::Telemetry => Command::Telemetry,
+            CommandRequest::Reboot => Command::Reboot,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Delivery {
+    delivered: usize,
+}
+
+/// Send a command to the open connections of a listener, by its identity or address
+async fn send_command(
+    State(state): State<Arc<AppState>>,
+    Path(listener): Path<String>,
+    Json(request): Json<CommandRequest>,
+) -> Result<(StatusCode, Json<Delivery>), StatusCode> {
+    let command = Command::try_from(request)?;
+    match state.connections.command(&listener, &command) {
+        0 => Err(StatusCode::NOT_FOUND),
+        delivered => Ok((StatusCode::ACCEPTED, Json(Delivery { delivered }))),
+    }
+}
@@ -0,0 +1,83 @@
+use crate::AppState;
+use crate::units::Units;
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// API roles, each role includes the permissions of the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Readings, history and reports
+    Read,
+    /// Managing alert rules and notifiers
+    Alerts,
+    /// Downlink commands and key management
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenConfig {
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+    /// Unit system used for this token when the request doesn't specify one
+    pub units: Option<Units>,
+}
+
+/// Authenticated API client, available to handlers as a request extension
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub units: Option<Units>,
+}
+
+/// Middleware rejecting requests whose bearer token doesn't grant `role`.
+/// Without any configured tokens the API is open.
+pub async fn require(
+    State((state, role)): State<(Arc<AppState>, Role)>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let tokens = &state.config.api.tokens;
+    if tokens.is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let token = tokens
+        .iter()
+        .find(|t| constant_time_eq(t.token.as_bytes(), bearer.as_bytes()))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if token.role < role {
+        tracing::warn!(
+            "Token {} with role {:?} denied {role:?} access",
+            token.name,
+            token.role
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    tracing::debug!(
+        "{} {} by token {}",
+        request.method(),
+        request.uri(),
+        token.name
+    );
+    request
+        .extensions_mut()
+        .insert(Caller { units: token.units });
+    Ok(next.run(request).await)
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
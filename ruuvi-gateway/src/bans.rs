@@ -0,0 +1,80 @@
+use crate::config::HandshakeConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Peer {
+    /// Consecutive failed handshakes
+    failures: u32,
+    last_failure: Instant,
+}
+
+/// Refuses peers that failed the handshake `max_failures` times in a row, for
+/// `ban_secs` after their last failure
+pub struct Bans {
+    max_failures: u32,
+    duration: Duration,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+}
+
+impl Bans {
+    pub fn new(config: &HandshakeConfig) -> Self {
+        Self {
+            max_failures: config.max_failures,
+            duration: Duration::from_secs(config.ban_secs),
+            peers: Mutex::default(),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.max_failures > 0
+            && self.peers.lock().unwrap().get(&ip).is_some_and(|peer| {
+                peer.failures >= self.max_failures && peer.last_failure.elapsed() < self.duration
+            })
+    }
+
+    /// Returns true when this failure gets the peer banned
+    pub fn failure(&self, ip: IpAddr) -> bool {
+        if self.max_failures == 0 {
+            return false;
+        }
+        let mut peers = self.peers.lock().unwrap();
+        // Failures older than a ban are forgotten, keeps scanners from piling up
+        peers.retain(|_, peer| peer.last_failure.elapsed() < self.duration);
+        let peer = peers.entry(ip).or_insert(Peer {
+            failures: 0,
+            last_failure: Instant::now(),
+        });
+        peer.failures += 1;
+        peer.last_failure = Instant::now();
+        peer.failures == self.max_failures
+    }
+
+    pub fn success(&self, ip: IpAddr) {
+        self.peers.lock().unwrap().remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bans;
+    use crate::config::HandshakeConfig;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn bans_after_consecutive_failures() {
+        let bans = Bans::new(&HandshakeConfig {
+            max_failures: 2,
+            ..HandshakeConfig::default()
+        });
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        assert!(!bans.failure(ip));
+        bans.success(ip);
+        assert!(!bans.failure(ip));
+        assert!(!bans.is_banned(ip));
+        assert!(bans.failure(ip));
+        assert!(bans.is_banned(ip));
+        assert!(!bans.is_banned(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 21))));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::AppState;
+use crate::mac::format_mac;
+use crate::notify::Notification;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BatteryForecast {
+    /// Latest voltage, compensated to the reference temperature
+    pub voltage: f64,
+    pub slope_mv_per_day: f64,
+    pub temp_coefficient_mv_per_c: f64,
+    pub replacement_date: Option<DateTime<Utc>>,
+    pub samples: usize,
+}
+
+/// Fit `voltage = a + b * days + c * temperature` and extrapolate the
+/// temperature compensated trend down to `threshold_v`.
+/// Cold tags read lower, so without the temperature term winter looks like a dying battery.
+pub fn forecast(
+    samples: &[(DateTime<Utc>, f64, f64)],
+    threshold_v: f64,
+    reference_temp: f64,
+) -> Option<BatteryForecast> {
+    let (first, _, _) = samples.first()?;
+    let (last, _, _) = samples.last()?;
+    let to_days = |t: &DateTime<Utc>| (*t - *first).as_seconds_f64() / 86_400.0;
+
+    let points: Vec<_> = samples
+        .iter()
+        .map(|(t, v, temp)| (to_days(t), *temp, *v))
+        .collect();
+    let [a, b, c] = least_squares(&points)?;
+
+    let voltage = a + b * to_days(last) + c * reference_temp;
+    let replacement_date = (b < 0.0)
+        .then(|| (threshold_v - voltage) / b)
+        .map(|days| days.max(0.0))
+        .and_then(|days| TimeDelta::try_seconds((days * 86_400.0) as i64))
+        .and_then(|delta| last.checked_add_signed(delta));
+
+    Some(BatteryForecast {
+        voltage,
+        slope_mv_per_day: b * 1000.0,
+        temp_coefficient_mv_per_c: c * 1000.0,
+        replacement_date,
+        samples: samples.len(),
+    })
+}
+
+/// Ordinary least squares for `z = a + b * x + c * y` using the normal equations
+fn least_squares(points: &[(f64, f64, f64)]) -> Option<[f64; 3]> {
+    if points.len() < 3 {
+        return None;
+    }
+    let mut m = [[0f64; 3]; 3];
+    let mut r = [0f64; 3];
+    for (x, y, z) in points {
+        let row = [1.0, *x, *y];
+        for i in 0..3 {
+            for j in 0..3 {
+                m[i][j] += row[i] * row[j];
+            }
+            r[i] += row[i] * z;
+        }
+    }
+
+    let det = determinant(&m);
+    if det.abs() < 1e-9 {
+        // Constant temperature makes the system singular, fit without the temperature term
+        return least_squares_2(points);
+    }
+    let mut solution = [0f64; 3];
+    for (k, value) in solution.iter_mut().enumerate() {
+        let mut mk = m;
+        for i in 0..3 {
+            mk[i][k] = r[i];
+        }
+        *value = determinant(&mk) / det;
+    }
+    Some(solution)
+}
+
+fn least_squares_2(points: &[(f64, f64, f64)]) -> Option<[f64; 3]> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_z = points.iter().map(|p| p.2).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    if sxx.abs() < 1e-9 {
+        return None;
+    }
+    let sxz: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.2 - mean_z)).sum();
+    let b = sxz / sxx;
+    Some([mean_z - b * mean_x, b, 0.0])
+}
+
+fn determinant(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+pub async fn forecast_tag(
+    state: &AppState,
+    mac: [u8; 6],
+) -> Result<Option<BatteryForecast>, anyhow::Error> {
+    let config = &state.config.battery;
+    let since = Utc::now() - TimeDelta::days(config.history_days);
+    let samples = state.storage.battery_history(mac, since).await?;
+    Ok(forecast(
+        &samples,
+        config.threshold_v,
+        config.reference_temp,
+    ))
+}
+
+/// Periodically forecast every tag and warn once when a battery is due soon
+pub async fn watch(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let config = &state.config.battery;
+    let Some(period) = config
+        .check_interval_hours
+        .checked_mul(3600)
+        .filter(|secs| *secs > 0)
+    else {
+        anyhow::bail!("[battery] check_interval_hours has to be at least 1 and fit in seconds");
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(period));
+    let mut warned = HashSet::new();
+
+    loop {
+        interval.tick().await;
+        let since = Utc::now() - TimeDelta::days(config.history_days);
+        let macs = match state.storage.recent_tag_macs(since).await {
+            Ok(macs) => macs,
+            Err(e) => {
+                tracing::error!("Failed to list tags for battery forecast: {e}");
+                continue;
+            }
+        };
+
+        let warn_before = Utc::now() + TimeDelta::days(config.warn_days);
+        for mac in macs {
+            let forecast = match forecast_tag(&state, mac).await {
+                Ok(Some(forecast)) => forecast,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::error!("Failed to forecast battery of {}: {e}", format_mac(&mac));
+                    continue;
+                }
+            };
+            match forecast.replacement_date {
+                Some(date) if date <= warn_before => {
+                    if warned.insert(mac) {
+                        let notification = Notification {
+                            title: format!("Battery of {} running low", format_mac(&mac)),
+                            body: format!(
+                                "Estimated to reach {} V by {date}, currently {:.3} V",
+                                config.threshold_v, forecast.voltage
+                            ),
+                        };
+                        state.notifiers.notify(&notification).await;
+                    }
+                }
+                // Battery replaced or trend recovered
+                _ => {
+                    warned.remove(&mac);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::forecast;
+    use chrono::{TimeDelta, Utc};
+
+    #[test]
+    fn test_forecast_compensates_temperature() {
+        let start = Utc::now();
+        // 1 mV/day drain, 2 mV/°C temperature dependency with a daily temperature swing
+        let samples: Vec<_> = (0..100)
+            .map(|i| {
+                let days = i as f64 / 2.0;
+                let temp = if i % 2 == 0 { 20.0 } else { -5.0 };
+                let voltage = 3.0 - 0.001 * days + 0.002 * (temp - 20.0);
+                (start + TimeDelta::hours(i * 12), voltage, temp)
+            })
+            .collect();
+
+        let f = forecast(&samples, 2.5, 20.0).unwrap();
+        assert!((f.slope_mv_per_day + 1.0).abs() < 1e-6);
+        assert!((f.temp_coefficient_mv_per_c - 2.0).abs() < 1e-6);
+
+        // 3.0 - 0.0495 = 2.9505 V now, 450.5 days to 2.5 V
+        let days = (f.replacement_date.unwrap() - samples.last().unwrap().0).num_hours() / 24;
+        assert_eq!(days, 450);
+    }
+}
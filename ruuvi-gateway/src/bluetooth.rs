@@ -0,0 +1,67 @@
+//! Readings heard by the gateway's own Bluetooth adapter, for single host
+//! installs. They skip the network protocol and go straight to conversion,
+//! deduplication and storage, like a listener's.
+
+use crate::AppState;
+use std::sync::Arc;
+
+pub async fn scan(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    if !state.config.bluetooth.enabled {
+        return Ok(());
+    }
+    scan_adapter(state).await
+}
+
+#[cfg(feature = "bluetooth")]
+async fn scan_adapter(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    use crate::database::ListenerRow;
+    use crate::{Ruuvi, ingest, raw_payload};
+    use chrono::Utc;
+    use ruuvi_desktop_listener::scanner;
+    use ruuvi_schema::RuuviRaw;
+    use std::net::Ipv4Addr;
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+
+    /// Readings waiting for conversion, the newest are dropped when full
+    const QUEUE_DEPTH: usize = 256;
+
+    let config = &state.config.bluetooth;
+    let row = ListenerRow {
+        name: config.name.clone(),
+        identified: true,
+        address: Ipv4Addr::LOCALHOST.into(),
+        psk: "bluetooth".to_owned(),
+        firmware: None,
+    };
+    if let Err(e) = state.storage.upsert_listener(&row).await {
+        tracing::error!("Failed to record listener {}: {e}", config.name);
+    }
+
+    let (sender, mut readings) = mpsc::channel::<(RuuviRaw, Instant)>(QUEUE_DEPTH);
+    let receive = async {
+        while let Some((raw, heard)) = readings.recv().await {
+            let raw_payload = raw_payload(&state, &raw);
+            let dt = Utc::now() - heard.elapsed();
+            let mut data =
+                match Ruuvi::from_raw(raw, dt, &state.tag_keys, state.config.humidity.formula) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        tracing::warn!("{e}");
+                        continue;
+                    }
+                };
+            state.calibrations.apply(&mut data);
+            tracing::debug!("Data: {data:?}");
+            ingest(&state, &config.name, data, raw_payload, None, None);
+        }
+        Ok(())
+    };
+    tokio::try_join!(scanner::scan(config.adapter, sender), receive)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "bluetooth"))]
+async fn scan_adapter(_: Arc<AppState>) -> Result<(), anyhow::Error> {
+    anyhow::bail!("[bluetooth] is enabled, but the gateway was built without the bluetooth feature")
+}
@@ -0,0 +1,155 @@
+use crate::Ruuvi;
+use crate::config::CalibrationConfig;
+use ruuvi_schema::convert::humidity_derived;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Values a tag reported before its calibration was applied
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Uncalibrated {
+    pub temp: Option<f32>,
+    pub rel_humidity: Option<f32>,
+    pub co2: Option<u16>,
+}
+
+/// Per tag offsets added to the readings before they're stored
+pub struct Calibrations {
+    offsets: HashMap<[u8; 6], CalibrationConfig>,
+}
+
+impl Calibrations {
+    pub fn new(config: &[CalibrationConfig]) -> Self {
+        Self {
+            offsets: config.iter().map(|c| (c.mac, c.clone())).collect(),
+        }
+    }
+
+    /// Correct a reading of a calibrated tag. The reported values are kept in
+    /// its `uncalibrated` and the humidity values derived again.
+    pub fn apply(&self, data: &mut Ruuvi) {
+        let Some(offsets) = self.offsets.get(&data.mac()) else {
+            return;
+        };
+        match data {
+            Ruuvi::V2(v2) => {
+                v2.uncalibrated = Some(Uncalibrated {
+                    temp: v2.temp,
+                    rel_humidity: v2.rel_humidity,
+                    co2: None,
+                });
+                (v2.temp, v2.rel_humidity) = offsets.climate(v2.temp, v2.rel_humidity);
+                (v2.abs_humidity, v2.dew_point_temp) = humidity_derived(
+                    v2.humidity_formula,
+                    v2.temp,
+                    v2.rel_humidity,
+                    v2.abs_pressure,
+                );
+            }
+            Ruuvi::V1(v1) => {
+                v1.uncalibrated = Some(Uncalibrated {
+                    temp: v1.temp,
+                    rel_humidity: v1.rel_humidity,
+                    co2: None,
+                });
+                (v1.temp, v1.rel_humidity) = offsets.climate(v1.temp, v1.rel_humidity);
+                (v1.abs_humidity, v1.dew_point_temp) = humidity_derived(
+                    v1.humidity_formula,
+                    v1.temp,
+                    v1.rel_humidity,
+                    v1.abs_pressure,
+                );
+            }
+            Ruuvi::E1(e1) => {
+                e1.uncalibrated = Some(Uncalibrated {
+                    temp: e1.temp,
+                    rel_humidity: e1.rel_humidity,
+                    co2: e1.co2,
+                });
+                (e1.temp, e1.rel_humidity) = offsets.climate(e1.temp, e1.rel_humidity);
+                e1.co2 = offsets.co2(e1.co2);
+                (e1.abs_humidity, e1.dew_point_temp) = humidity_derived(
+                    e1.humidity_formula,
+                    e1.temp,
+                    e1.rel_humidity,
+                    e1.abs_pressure,
+                );
+            }
+            Ruuvi::V6(v6) => {
+                v6.uncalibrated = Some(Uncalibrated {
+                    temp: v6.temp,
+                    rel_humidity: v6.rel_humidity,
+                    co2: v6.co2,
+                });
+                (v6.temp, v6.rel_humidity) = offsets.climate(v6.temp, v6.rel_humidity);
+                v6.co2 = offsets.co2(v6.co2);
+                (v6.abs_humidity, v6.dew_point_temp) = humidity_derived(
+                    v6.humidity_formula,
+                    v6.temp,
+                    v6.rel_humidity,
+                    v6.abs_pressure,
+                );
+            }
+        }
+    }
+}
+
+impl CalibrationConfig {
+    fn climate(&self, temp: Option<f32>, rel_humidity: Option<f32>) -> (Option<f32>, Option<f32>) {
+        (
+            temp.map(|t| t + self.temp_offset),
+            rel_humidity.map(|rh| (rh + self.humidity_offset).clamp(0.0, 100.0)),
+        )
+    }
+
+    fn co2(&self, co2: Option<u16>) -> Option<u16> {
+        co2.map(|v| (i32::from(v) + self.co2_offset).clamp(0, i32::from(u16::MAX)) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Calibrations;
+    use crate::Ruuvi;
+    use crate::config::CalibrationConfig;
+    use crate::encryption::TagKeys;
+    use chrono::Utc;
+    use ruuvi_schema::RuuviRaw;
+    use ruuvi_schema::convert::HumidityFormula;
+
+    #[test]
+    fn offsets_the_calibrated_tags_only() {
+        let mac = [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F];
+        // Format 3 test vector, 26.3 °C and 20.5 %
+        let payload = [
+            0x03, 0x29, 0x1A, 0x1E, 0xCE, 0x1E, 0xFC, 0x18, 0xF9, 0x42, 0x02, 0xCA, 0x0B, 0x53,
+        ];
+        let reading = |mac| {
+            let raw = RuuviRaw::parse(&payload, mac, 0, 0).unwrap();
+            Ruuvi::from_raw(raw, Utc::now(), &TagKeys::new(&[]), HumidityFormula::Buck).unwrap()
+        };
+        let calibrations = Calibrations::new(&[CalibrationConfig {
+            mac,
+            temp_offset: -0.3,
+            humidity_offset: -25.0,
+            co2_offset: -40,
+        }]);
+
+        let mut data = reading(mac);
+        calibrations.apply(&mut data);
+        let Ruuvi::V1(v1) = data else {
+            panic!("expected a format 3 reading");
+        };
+        assert_eq!((v1.temp, v1.rel_humidity), (Some(26.0), Some(0.0)));
+        let uncalibrated = v1.uncalibrated.unwrap();
+        assert_eq!(
+            (uncalibrated.temp, uncalibrated.rel_humidity),
+            (Some(26.3), Some(20.5))
+        );
+        assert!(v1.abs_humidity.unwrap().abs() < 1e-9);
+
+        let mut other = reading([1, 2, 3, 4, 5, 6]);
+        let unchanged = other.clone();
+        calibrations.apply(&mut other);
+        assert_eq!(other, unchanged);
+    }
+}
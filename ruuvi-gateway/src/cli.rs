@@ -0,0 +1,155 @@
+use crate::mac::parse_mac;
+use crate::registry::DataFormat;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Receives Ruuvi readings from listeners and stores them"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Enable development endpoints, like injecting readings with `POST /dev/inject`
+    #[arg(long, global = true)]
+    pub dev: bool,
+    /// TOML config file, a missing file yields the default config
+    #[arg(
+        long,
+        global = true,
+        env = "RUUVI_GATEWAY_CONFIG",
+        default_value = "ruuvi-gateway.toml"
+    )]
+    pub config: PathBuf,
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+/// Overrides of the `[server]` config section
+#[derive(Debug, Args)]
+pub struct ServerArgs {
+    /// Address the listeners connect to
+    #[arg(long, global = true)]
+    pub listen: Option<String>,
+    /// Postgres connection string, or an `sqlite:` one for SQLite
+    #[arg(long, global = true, env = "DATABASE_URI", hide_env_values = true)]
+    pub database_uri: Option<String>,
+    /// Maximum number of database connections
+    #[arg(long, global = true)]
+    pub pool_size: Option<u32>,
+    /// Noise pre-shared key shared with the listeners, exactly 32 bytes
+    #[arg(long, global = true, env = "AUTH_KEY", hide_env_values = true)]
+    pub psk: Option<String>,
+    /// File holding the pre-shared key, used when no key is given directly
+    #[arg(long, global = true)]
+    pub psk_file: Option<PathBuf>,
+    /// Log filter, like `info` or `ruuvi_gateway=debug,sqlx=warn`
+    #[arg(long, global = true, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the gateway (default), creating or updating the tables first
+    Serve,
+    /// Create or update the tables and exit
+    #[command(alias = "init-db")]
+    Migrate,
+    /// Delete stored rows matching a MAC and/or date range
+    Prune(PruneArgs),
+    /// Rebuild the indexes and refresh planner statistics of the gateway tables
+    Reindex,
+    /// Check that stored derived columns match the current conversion formulas
+    Verify(VerifyArgs),
+    /// Decode stored readings again from their raw payload, or recompute their
+    /// derived columns when they have none
+    Reprocess(ReprocessArgs),
+    /// Print the Avro schema of the Kafka and NATS messages and exit
+    AvroSchema,
+    /// List, register or remove named tags
+    #[command(subcommand)]
+    Tags(TagsCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TagsCommand {
+    /// Print the registered tags
+    List,
+    /// Register a tag or update its registration
+    Set(RegisterArgs),
+    /// Remove a tag from the registry, its readings are kept
+    Remove {
+        /// MAC address or name of the tag
+        tag: String,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct RegisterArgs {
+    #[arg(value_parser = parse_mac)]
+    pub mac: [u8; 6],
+    pub name: String,
+    /// Where the tag is, like a room
+    #[arg(long)]
+    pub location: Option<String>,
+    /// Formats the tag is expected to send, any when not given
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub formats: Vec<DataFormat>,
+}
+
+#[derive(Debug, Args)]
+pub struct PruneArgs {
+    /// Only rows of this tag
+    #[arg(long, value_parser = parse_mac)]
+    pub mac: Option<[u8; 6]>,
+    /// Inclusive start, RFC 3339 timestamp or YYYY-MM-DD (UTC midnight)
+    #[arg(long, value_parser = parse_datetime)]
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive end, RFC 3339 timestamp or YYYY-MM-DD (UTC midnight)
+    #[arg(long, value_parser = parse_datetime)]
+    pub to: Option<DateTime<Utc>>,
+    /// Only count the matching rows
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Only rows of this tag
+    #[arg(long, value_parser = parse_mac)]
+    pub mac: Option<[u8; 6]>,
+    #[arg(long, value_parser = parse_datetime)]
+    pub from: Option<DateTime<Utc>>,
+    #[arg(long, value_parser = parse_datetime)]
+    pub to: Option<DateTime<Utc>>,
+    /// Maximum number of mismatching rows to print
+    #[arg(long, default_value_t = 10)]
+    pub samples: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct ReprocessArgs {
+    /// Only rows of this tag
+    #[arg(long, value_parser = parse_mac)]
+    pub mac: Option<[u8; 6]>,
+    #[arg(long, value_parser = parse_datetime)]
+    pub from: Option<DateTime<Utc>>,
+    #[arg(long, value_parser = parse_datetime)]
+    pub to: Option<DateTime<Utc>>,
+    /// Rows updated per transaction
+    #[arg(long, default_value_t = 1000)]
+    pub batch_size: u32,
+    /// Only count the rows that would be updated
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+fn parse_datetime(s: &str) -> Result<DateTime<Utc>, anyhow::Error> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")?;
+    Ok(date.and_time(NaiveTime::MIN).and_utc())
+}
@@ -0,0 +1,892 @@
+use crate::alerts::AlertRule;
+use crate::auth::TokenConfig;
+use crate::cli::ServerArgs;
+use crate::notify::NotifierConfig;
+use crate::suite::Suite;
+use crate::units::Units;
+use crate::{encryption, listener_keys, mac};
+use anyhow::Context;
+use chrono_tz::Tz;
+use ruuvi_schema::convert::HumidityFormula;
+use ruuvi_schema::protocol::ScanSettings;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub doors: Vec<DoorConfig>,
+    pub dedup: DedupConfig,
+    pub api: ApiConfig,
+    pub zones: Vec<ZoneConfig>,
+    pub location: LocationConfig,
+    pub battery: BatteryConfig,
+    pub notifiers: Vec<NotifierConfig>,
+    pub alerts: Vec<AlertRule>,
+    pub offline: OfflineConfig,
+    pub retention: RetentionConfig,
+    pub rollups: RollupConfig,
+    pub reports: ReportConfig,
+    pub stats: StatsConfig,
+    pub quarantine: QuarantineConfig,
+    pub tag_filter: TagFilterConfig,
+    pub noise: NoiseConfig,
+    pub handshake: HandshakeConfig,
+    pub tcp: TcpConfig,
+    pub ota: OtaConfig,
+    pub scan: Vec<ScanConfig>,
+    pub quality: QualityConfig,
+    pub tag_keys: Vec<TagKeyConfig>,
+    pub calibration: Vec<CalibrationConfig>,
+    pub server: ServerConfig,
+    pub timescale: TimescaleConfig,
+    pub mqtt: MqttConfig,
+    pub influx: InfluxConfig,
+    pub stdout: StdoutConfig,
+    pub outbox: OutboxConfig,
+    pub raw_payload: RawPayloadConfig,
+    pub http_ingest: HttpIngestConfig,
+    pub official_gateway: OfficialGatewayConfig,
+    pub bluetooth: BluetoothConfig,
+    pub mdns: MdnsConfig,
+    pub humidity: HumidityConfig,
+    pub kafka: KafkaConfig,
+    pub nats: NatsConfig,
+}
+
+impl Config {
+    /// Load runtime configuration from a TOML file, `None` if there is no file at `path`
+    pub fn load(path: &Path) -> Result<Option<Self>, anyhow::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => {
+                let config: Self = toml::from_str(&content)
+                    .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+                if let Some(scan) = config.scan.iter().find(|scan| !scan.settings.is_valid()) {
+                    anyhow::bail!(
+                        "Invalid scan timing {:?}, intervals and windows are 3 to 10240 ms \
+                         and a window is at most its interval",
+                        scan.settings
+                    );
+                }
+                Ok(Some(config))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Scan settings of the first `[[scan]]` listing `listener`, or listing none
+    pub fn scan_settings(&self, listener: &str) -> Option<ScanSettings> {
+        self.scan
+            .iter()
+            .find(|scan| scan.listeners.is_empty() || scan.listeners.iter().any(|l| l == listener))
+            .map(|scan| scan.settings)
+    }
+}
+
+/// Listener ingestion and database settings, each overridable on the command line
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Address the listeners connect to, `[::]` takes both IPv4 and IPv6
+    pub listen: String,
+    /// Postgres connection string, or an `sqlite:` one for SQLite
+    pub database_uri: Option<String>,
+    /// Maximum number of database connections
+    pub pool_size: u32,
+    /// File holding the Noise pre-shared key, when it isn't given with `--psk` or `AUTH_KEY`
+    pub psk_file: Option<PathBuf>,
+    /// Log filter, like `info` or `ruuvi_gateway=debug,sqlx=warn`
+    pub log_level: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: "[::]:9090".to_owned(),
+            database_uri: None,
+            pool_size: 5,
+            psk_file: None,
+            log_level: "debug".to_owned(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Command line arguments and their environment variables win over the file
+    pub fn apply(&mut self, args: &ServerArgs) {
+        if let Some(listen) = &args.listen {
+            self.listen.clone_from(listen);
+        }
+        if let Some(uri) = &args.database_uri {
+            self.database_uri = Some(uri.clone());
+        }
+        if let Some(pool_size) = args.pool_size {
+            self.pool_size = pool_size;
+        }
+        if let Some(path) = &args.psk_file {
+            self.psk_file = Some(path.clone());
+        }
+        if let Some(log_level) = &args.log_level {
+            self.log_level.clone_from(log_level);
+        }
+    }
+
+    pub fn database_uri(&self) -> Result<&str, anyhow::Error> {
+        self.database_uri
+            .as_deref()
+            .context("No database URI, set --database-uri, DATABASE_URI or [server] database_uri")
+    }
+
+    /// The key given directly takes precedence over `psk_file`. Optional when
+    /// `[[noise.psks]]` has keys.
+    pub fn psk(&self, key: Option<&str>) -> Result<Option<[u8; 32]>, anyhow::Error> {
+        let key = match (key, &self.psk_file) {
+            (Some(key), _) => key.to_owned(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?
+                .trim_end_matches(['\r', '\n'])
+                .to_owned(),
+            (None, None) => return Ok(None),
+        };
+        listener_keys::parse_psk(key).map(Some)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// How long to wait for other listeners to report the same measurement
+    pub window_ms: u64,
+    /// How long stored measurements are remembered, later copies are dropped.
+    /// Keep it below the time a tag takes to wrap its measurement sequence.
+    pub memory_secs: u64,
+    /// Record every listener that heard a measurement in `receptions`
+    pub record_receptions: bool,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: 2000,
+            memory_secs: 60,
+            record_receptions: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub listen: String,
+    /// Default unit system, clients can override it with `?units=`
+    pub units: Units,
+    /// IANA timezone for timestamps and daily buckets, clients can override it with `?tz=`
+    pub timezone: Tz,
+    /// Bearer tokens and their roles. The API is open when empty.
+    pub tokens: Vec<TokenConfig>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:8080".to_owned(),
+            units: Units::default(),
+            timezone: Tz::UTC,
+            tokens: Vec::new(),
+        }
+    }
+}
+
+/// A room or area covered by one or more listeners
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZoneConfig {
+    pub name: String,
+    pub listeners: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LocationConfig {
+    /// How many dB stronger another zone must be before a tag moves there
+    pub margin_db: i16,
+}
+
+impl Default for LocationConfig {
+    fn default() -> Self {
+        Self { margin_db: 3 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BatteryConfig {
+    /// Voltage at which the battery should be replaced
+    pub threshold_v: f64,
+    /// Temperature the forecast is compensated to
+    pub reference_temp: f64,
+    pub history_days: i64,
+    /// Warn when the estimated replacement date is closer than this
+    pub warn_days: i64,
+    pub check_interval_hours: u64,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        Self {
+            threshold_v: 2.5,
+            reference_temp: 20.0,
+            history_days: 60,
+            warn_days: 30,
+            check_interval_hours: 6,
+        }
+    }
+}
+
+/// Deletion of rows past a per-table age
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Days of rows kept per table, like `tag_readings = 365`. Tables left out are kept forever.
+    pub days: BTreeMap<String, u32>,
+    pub interval_hours: u64,
+    /// Only log how many rows would be deleted
+    pub dry_run: bool,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            days: BTreeMap::new(),
+            interval_hours: 24,
+            dry_run: false,
+        }
+    }
+}
+
+/// 5 minute and hourly min/avg/max of the readings in `tag_readings_agg` and `air_readings_agg`
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RollupConfig {
+    pub enabled: bool,
+    /// How often new buckets are written
+    pub interval_secs: u64,
+    /// Buckets this far back are recomputed, for readings arriving late from the outbox
+    pub late_secs: u64,
+}
+
+impl Default for RollupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 300,
+            late_secs: 3600,
+        }
+    }
+}
+
+/// Notifications for tags going silent
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OfflineConfig {
+    /// How long a tag has to be silent to count as offline, 0 disables the watchdog
+    pub timeout_secs: u64,
+    pub check_interval_secs: u64,
+}
+
+impl Default for OfflineConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 900,
+            check_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ReportConfig {
+    pub daily: bool,
+    /// Weekly reports are sent on Mondays
+    pub weekly: bool,
+    /// Local hour (api.timezone) when the reports are sent
+    pub hour: u32,
+    pub humidity_low: f32,
+    pub humidity_high: f32,
+    pub co2_limit: i16,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            daily: false,
+            weekly: false,
+            hour: 7,
+            humidity_low: 30.0,
+            humidity_high: 60.0,
+            co2_limit: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct StatsConfig {
+    /// How often per-connection ingest statistics are logged, 0 disables the summary
+    pub interval_secs: u64,
+}
+
+impl Default for StatsConfig {
+    fn default() -> Self {
+        Self { interval_secs: 300 }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct QuarantineConfig {
+    /// Consecutive undecryptable or undecodable frames before a connection is closed
+    pub max_failures: u32,
+    /// File the offending frame is appended to as hex
+    pub path: PathBuf,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 3,
+            path: PathBuf::from("quarantine.log"),
+        }
+    }
+}
+
+/// Tags whose readings are stored, every tag in range when left empty
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TagFilterConfig {
+    /// Only tags registered with `tags add` pass, next to the allowed ones
+    pub registered_only: bool,
+    /// Only these pass when not empty, next to the registered ones
+    #[serde(deserialize_with = "mac::deserialize_list")]
+    pub allow: Vec<[u8; 6]>,
+    /// Never pass, registered or not
+    #[serde(deserialize_with = "mac::deserialize_list")]
+    pub deny: Vec<[u8; 6]>,
+    /// Append filtered readings to the quarantine file instead of dropping them
+    pub quarantine: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct NoiseConfig {
+    /// Cipher suites listeners may choose from. Listeners without suite
+    /// selection are treated as `chachapoly`.
+    pub suites: Vec<Suite>,
+    /// Static keys of trusted listeners. When set only these are accepted and
+    /// their readings carry the listener's name instead of its address.
+    pub listeners: Vec<ListenerKeyConfig>,
+    /// Pre-shared keys accepted next to the server's, named so a listener can be
+    /// revoked without re-flashing the rest
+    pub psks: Vec<PskConfig>,
+}
+
+impl Default for NoiseConfig {
+    fn default() -> Self {
+        Self {
+            suites: vec![Suite::ChaChaPoly, Suite::AesGcm],
+            listeners: Vec::new(),
+            psks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PskConfig {
+    pub name: String,
+    /// 32 bytes, the listener's `AUTH_KEY`
+    #[serde(deserialize_with = "listener_keys::deserialize_psk")]
+    pub key: [u8; 32],
+}
+
+/// Protection against peers stalling or failing the Noise handshake
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HandshakeConfig {
+    /// Time a peer gets to complete the handshake
+    pub timeout_secs: u64,
+    /// Handshakes in progress at once, further connections are refused
+    pub max_pending: usize,
+    /// Consecutive failed handshakes before a peer is banned, 0 never bans
+    pub max_failures: u32,
+    /// How long a banned peer is refused
+    pub ban_secs: u64,
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 10,
+            max_pending: 16,
+            max_failures: 5,
+            ban_secs: 300,
+        }
+    }
+}
+
+/// Limits of the listener connections
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TcpConfig {
+    /// Open connections at once, further ones are refused
+    pub max_connections: usize,
+    /// A connection without a frame from the listener for this long is closed.
+    /// Keep it above the listener's heartbeat interval.
+    pub idle_timeout_secs: u64,
+    /// Idle time before TCP keepalive probes are sent, 0 disables keepalive
+    pub keepalive_secs: u64,
+    /// Time between keepalive probes
+    pub keepalive_interval_secs: u64,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 64,
+            idle_timeout_secs: 120,
+            keepalive_secs: 60,
+            keepalive_interval_secs: 10,
+        }
+    }
+}
+
+/// Noise static public key of a listener
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerKeyConfig {
+    pub name: String,
+    /// 64 hex characters, printed by the listener at boot
+    #[serde(deserialize_with = "listener_keys::deserialize_public_key")]
+    pub public_key: [u8; 32],
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct QualityConfig {
+    /// Readings the rolling per-tag quality scores roughly average over
+    pub window: u32,
+    /// How often the scores are stored in `tag_quality`, 0 disables storing
+    pub interval_secs: u64,
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            window: 100,
+            interval_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct OtaConfig {
+    /// Directory holding `manifest.toml`, the image and its signature.
+    /// Firmware updates are disabled when unset.
+    pub dir: Option<PathBuf>,
+    /// Listeners offered updates, all of them when empty
+    pub listeners: Vec<String>,
+}
+
+/// BLE scan parameters sent to listeners as they connect, overriding their build time ones
+#[derive(Debug, Deserialize)]
+pub struct ScanConfig {
+    /// Addresses or pinned names, all listeners when empty
+    #[serde(default)]
+    pub listeners: Vec<String>,
+    #[serde(flatten)]
+    pub settings: ScanSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// Broker address, publishing is disabled when unset
+    pub host: Option<String>,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    /// Readings go to `<topic_prefix>/ruuvi_<mac>/state`
+    pub topic_prefix: String,
+    /// Home Assistant discovery prefix, an empty prefix disables discovery
+    pub discovery_prefix: String,
+    /// Take in the readings listeners built with the `transport-mqtt` feature
+    /// publish to `<topic_prefix>/listeners/<listener>/readings`
+    pub listeners: bool,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            host: None,
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: "ruuvi-gateway".to_owned(),
+            topic_prefix: "ruuvi".to_owned(),
+            discovery_prefix: "homeassistant".to_owned(),
+            listeners: false,
+        }
+    }
+}
+
+/// Readings held while the database is unavailable
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    /// Most readings held, further ones are left to the listeners to resend.
+    /// 0 disables the outbox.
+    pub capacity: usize,
+    /// Keeps the held readings across restarts, `<spool_file>.offset` next to it
+    /// marks the ones already written
+    pub spool_file: Option<PathBuf>,
+    /// Failed writes of a held reading before it's given up
+    pub max_attempts: u32,
+    /// Given up readings are appended here as JSON lines, and lost when unset
+    pub dead_letter_file: Option<PathBuf>,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            spool_file: None,
+            max_attempts: 20,
+            dead_letter_file: None,
+        }
+    }
+}
+
+/// Message encoding of the Kafka and NATS outputs
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamEncoding {
+    #[default]
+    Json,
+    /// Single-object encoding, see `ruuvi-gateway avro-schema`
+    Avro,
+}
+
+/// Kafka output, needs the `kafka` feature
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct KafkaConfig {
+    /// Comma separated `host:port` list, producing is disabled when unset
+    pub brokers: Option<String>,
+    pub topic: String,
+    pub encoding: StreamEncoding,
+    /// Broker acknowledgements a write waits for: `all`, `1` or `0`
+    pub acks: String,
+    /// Most readings per batch
+    pub batch_size: usize,
+    /// How long readings are collected before they're sent
+    pub flush_ms: u64,
+    /// Further librdkafka producer properties, like `security.protocol` or `compression.type`
+    pub properties: BTreeMap<String, String>,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        Self {
+            brokers: None,
+            topic: "ruuvi".to_owned(),
+            encoding: StreamEncoding::Json,
+            acks: "all".to_owned(),
+            batch_size: 500,
+            flush_ms: 100,
+            properties: BTreeMap::new(),
+        }
+    }
+}
+
+/// NATS output, needs the `nats` feature
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct NatsConfig {
+    /// Like `nats://localhost:4222`, publishing is disabled when unset
+    pub url: Option<String>,
+    pub token: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Readings go to `<subject_prefix>.<mac>`, the MAC as lowercase hex
+    pub subject_prefix: String,
+    pub encoding: StreamEncoding,
+    /// Publish through JetStream and wait for the stream's acknowledgements
+    pub jetstream: bool,
+    /// Most readings per batch
+    pub batch_size: usize,
+    /// How long readings are collected before they're published
+    pub flush_ms: u64,
+}
+
+impl Default for NatsConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            token: None,
+            username: None,
+            password: None,
+            subject_prefix: "ruuvi".to_owned(),
+            encoding: StreamEncoding::Json,
+            jetstream: false,
+            batch_size: 100,
+            flush_ms: 100,
+        }
+    }
+}
+
+/// Printing the readings as JSON lines
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct StdoutConfig {
+    /// Print every stored reading to stdout, the logs go to stderr then
+    pub enabled: bool,
+}
+
+/// `POST /api/ruuvi` for listeners built with the `transport-http` feature
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HttpIngestConfig {
+    pub enabled: bool,
+    /// Address of the endpoint, apart from the API
+    pub listen: String,
+}
+
+impl Default for HttpIngestConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: "[::]:9091".to_owned(),
+        }
+    }
+}
+
+/// Readings relayed by official Ruuvi Gateway devices
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OfficialGatewayConfig {
+    /// Address receiving their HTTP posts to `/record`, disabled when unset
+    pub listen: Option<String>,
+    /// Bearer token their HTTP posts have to carry, required with `listen`
+    pub token: Option<String>,
+    /// Broker they publish to, subscribing is disabled when unset
+    pub mqtt_host: Option<String>,
+    pub mqtt_port: u16,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_client_id: String,
+    /// Their topic prefix, readings arrive at `<prefix>/<gateway MAC>/<tag MAC>`
+    pub mqtt_topic_prefix: String,
+}
+
+impl Default for OfficialGatewayConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            token: None,
+            mqtt_host: None,
+            mqtt_port: 1883,
+            mqtt_username: None,
+            mqtt_password: None,
+            mqtt_client_id: "ruuvi-gateway-ingest".to_owned(),
+            mqtt_topic_prefix: "ruuvi".to_owned(),
+        }
+    }
+}
+
+/// Scanning with the gateway's own Bluetooth adapter, needs the `bluetooth` feature
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BluetoothConfig {
+    pub enabled: bool,
+    /// Adapter by its position in the system's list
+    pub adapter: usize,
+    /// Listener the readings are attributed to
+    pub name: String,
+    /// Accept no listeners, the TCP listener isn't started and no PSK is needed
+    pub standalone: bool,
+}
+
+impl Default for BluetoothConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            adapter: 0,
+            name: "local".to_owned(),
+            standalone: false,
+        }
+    }
+}
+
+/// DNS-SD advertisement of the TCP ingestion port as `_ruuvi-gw._tcp`
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MdnsConfig {
+    pub advertise: bool,
+    /// Instance name, also the `.local` host name the address is announced under
+    pub instance: String,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            advertise: false,
+            instance: "ruuvi-gateway".to_owned(),
+        }
+    }
+}
+
+/// Keeping the manufacturer data of each reading
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RawPayloadConfig {
+    /// Store it in the `raw_payload` column of the readings tables
+    pub enabled: bool,
+}
+
+/// Deriving absolute humidity and dew point
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HumidityConfig {
+    /// Saturation vapour pressure formula, stored with each reading
+    pub formula: HumidityFormula,
+}
+
+/// TimescaleDB extension of a Postgres database
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TimescaleConfig {
+    /// Turn `tag_readings` and `air_readings` into hypertables and batch their inserts
+    pub enabled: bool,
+    /// Time range of one chunk
+    pub chunk_days: u32,
+    /// Chunks older than this are compressed, 0 leaves them uncompressed
+    pub compress_after_days: u32,
+    /// Most readings per insert
+    pub batch_size: usize,
+    /// How long readings are collected before they're inserted
+    pub batch_ms: u64,
+}
+
+impl Default for TimescaleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            chunk_days: 7,
+            compress_after_days: 14,
+            batch_size: 500,
+            batch_ms: 200,
+        }
+    }
+}
+
+/// InfluxDB 2.x output
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct InfluxConfig {
+    /// Like `http://localhost:8086`, writing is disabled when unset
+    pub url: Option<String>,
+    pub org: String,
+    pub bucket: String,
+    /// API token with write access to the bucket
+    pub token: Option<String>,
+    /// Measurements are named `<measurement_prefix>_<format>`, like `ruuvi_e1`
+    pub measurement_prefix: String,
+    /// Most lines per write request
+    pub batch_size: usize,
+    /// How long lines are collected before they're written
+    pub flush_ms: u64,
+    /// Store readings only in InfluxDB. Tag models, receptions and door events
+    /// still go to the database, its history API has no readings then.
+    pub replace_database: bool,
+}
+
+impl Default for InfluxConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            org: String::new(),
+            bucket: "ruuvi".to_owned(),
+            token: None,
+            measurement_prefix: "ruuvi".to_owned(),
+            batch_size: 500,
+            flush_ms: 1000,
+            replace_database: false,
+        }
+    }
+}
+
+/// AES-128 key of a tag sending encrypted advertisements (format 8)
+#[derive(Debug, Clone, Deserialize)]
+pub struct TagKeyConfig {
+    #[serde(deserialize_with = "mac::deserialize")]
+    pub mac: [u8; 6],
+    /// 32 hex characters
+    #[serde(deserialize_with = "encryption::deserialize_key")]
+    pub key: [u8; 16],
+}
+
+/// Offsets added to the readings of a tag, like the difference to a reference
+/// instrument
+#[derive(Debug, Clone, Deserialize)]
+pub struct CalibrationConfig {
+    #[serde(deserialize_with = "mac::deserialize")]
+    pub mac: [u8; 6],
+    /// °C
+    #[serde(default)]
+    pub temp_offset: f32,
+    /// %RH
+    #[serde(default)]
+    pub humidity_offset: f32,
+    /// ppm, corrects a drifted CO₂ baseline
+    #[serde(default)]
+    pub co2_offset: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DoorConfig {
+    #[serde(deserialize_with = "mac::deserialize")]
+    pub mac: [u8; 6],
+    /// Accelerometer axis that flips when the door opens
+    pub axis: Axis,
+    /// Whether the axis reads positive (true) or negative (false) while closed
+    #[serde(default = "default_closed_positive")]
+    pub closed_positive: bool,
+    /// Minimum absolute acceleration in mG before the axis is considered flipped.
+    /// Values inside the band keep the previous state.
+    #[serde(default = "default_threshold_mg")]
+    pub threshold_mg: i16,
+    /// Consecutive samples required before a state change is accepted
+    #[serde(default = "default_debounce")]
+    pub debounce: u8,
+}
+
+const fn default_closed_positive() -> bool {
+    true
+}
+
+const fn default_threshold_mg() -> i16 {
+    500
+}
+
+const fn default_debounce() -> u8 {
+    2
+}
@@ -1,146 +1,594 @@
-use crate::{RuuviE1, RuuviV2};
-use sqlx::types::mac_address::MacAddress;
-use sqlx::{Pool, Postgres};
-
-// ruuvi_measurements=# \d tag_readings
-//                                               Table "public.tag_readings"
-//         Column         |           Type           | Collation | Nullable |                   Default
-// -----------------------+--------------------------+-----------+----------+---------------------------------------------
-//  id                    | integer                  |           | not null | nextval('sensor_readings_id_seq'::regclass)
-//  recorded_at           | timestamp with time zone |           | not null | now()
-//  mac_address           | macaddr                  |           | not null |
-//  temperature           | real                     |           |          |
-//  relative_humidity     | real                     |           |          |
-//  pressure              | integer                  |           |          |
-//  acceleration_x        | smallint                 |           |          |
-//  acceleration_y        | smallint                 |           |          |
-//  acceleration_z        | smallint                 |           |          |
-//  battery_voltage       | real                     |           |          |
-//  tx_power              | smallint                 |           |          |
-//  movement_counter      | smallint                 |           |          |
-//  measurement_sequence  | integer                  |           |          |
-//  absolute_humidity     | real                     |           |          |
-//  dew_point_temperature | real                     |           |          |
-//  rssi                  | smallint                 |           |          |
-
-pub async fn insert_data_v2(pool: &Pool<Postgres>, data: RuuviV2) -> Result<(), anyhow::Error> {
-    return Ok(());
-    sqlx::query::<Postgres>(
-        r#"
-        INSERT INTO tag_readings (
-            recorded_at,
-            mac_address,
-            temperature,
-            relative_humidity,
-            pressure,
-            acceleration_x,
-            acceleration_y,
-            acceleration_z,
-            battery_voltage,
-            tx_power,
-            movement_counter,
-            measurement_sequence,
-            absolute_humidity,
-            dew_point_temperature,
-            rssi
-        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)
-        "#,
-    )
-    .bind(data.timestamp)
-    .bind(MacAddress::new(data.mac))
-    .bind(data.temp)
-    .bind(data.rel_humidity)
-    .bind(data.abs_pressure as i32)
-    .bind(data.acc_x)
-    .bind(data.acc_y)
-    .bind(data.acc_z)
-    .bind(data.battery_voltage)
-    .bind(data.tx_power as i16)
-    .bind(data.movement_counter as i16)
-    .bind(data.measurement_seq as i32)
-    .bind(data.abs_humidity as f32)
-    .bind(data.dew_point_temp as f32)
-    .bind(data.rssi as i16)
-    .execute(pool)
-    .await?;
-    Ok(())
-}
-
-// ruuvi_measurements=# \d air_readings
-//                                             Table "public.air_readings"
-//         Column         |           Type           | Collation | Nullable |                 Default
-// -----------------------+--------------------------+-----------+----------+------------------------------------------
-//  id                    | integer                  |           | not null | nextval('air_readings_id_seq'::regclass)
-//  recorded_at           | timestamp with time zone |           | not null | now()
-//  mac_address           | macaddr                  |           | not null |
-//  temperature           | real                     |           |          |
-//  dew_point_temperature | double precision         |           |          |
-//  relative_humidity     | real                     |           |          |
-//  absolute_humidity     | double precision         |           |          |
-//  pressure              | integer                  |           |          |
-//  pm1_0                 | real                     |           |          |
-//  pm2_5                 | real                     |           |          |
-//  pm4_0                 | real                     |           |          |
-//  pm10_0                | real                     |           |          |
-//  co2                   | smallint                 |           |          |
-//  voc_index             | smallint                 |           |          |
-//  nox_index             | smallint                 |           |          |
-//  luminosity            | real                     |           |          |
-//  measurement_sequence  | integer                  |           |          |
-//  flags                 | smallint                 |           |          |
-//  tx_power              | smallint                 |           |          |
-//  rssi                  | smallint                 |           |          |
-
-pub async fn insert_data_e1(pool: &Pool<Postgres>, data: RuuviE1) -> Result<(), anyhow::Error> {
-    return Ok(());
-    sqlx::query::<Postgres>(
-        r#"
-        INSERT INTO air_readings (
-            recorded_at,
-            mac_address,
-            temperature,
-            dew_point_temperature,
-            relative_humidity,
-            absolute_humidity,
-            pressure,
-            pm1_0,
-            pm2_5,
-            pm4_0,
-            pm10_0,
-            co2,
-            voc_index,
-            nox_index,
-            luminosity,
-            measurement_sequence,
-            flags,
-            tx_power,
-            rssi
-        ) VALUES (
-            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
-            $11, $12, $13, $14, $15, $16, $17, $18, $19
+use crate::config::Config;
+use crate::dedup::Reception;
+use crate::door::DoorTransition;
+use crate::quality::QualitySnapshot;
+use crate::{Ruuvi, RuuviE1, RuuviV1, RuuviV2, RuuviV6};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures_util::future::BoxFuture;
+use ruuvi_schema::TagModel;
+use ruuvi_schema::convert::HumidityFormula;
+use ruuvi_schema::protocol::{ResetReason, Telemetry};
+use serde::Serialize;
+use sqlx::FromRow;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+mod postgres;
+mod sqlite;
+
+pub use postgres::PostgresStorage;
+pub use sqlite::SqliteStorage;
+
+/// Tables holding per-tag rows with `recorded_at` and `mac_address` columns
+pub const TABLES: [&str; 5] = [
+    "tag_readings",
+    "air_readings",
+    "receptions",
+    "door_events",
+    "tag_quality",
+];
+
+/// Tables the retention job may expire rows of, each has `id` and `recorded_at`.
+/// The same as [`TABLES`], so pruning and expiry reach the same rows.
+pub const RETAINED_TABLES: [&str; 5] = TABLES;
+
+/// Readings table aggregated into `table`, see [`Storage::rollup`]
+pub struct Rollup {
+    pub source: &'static str,
+    pub table: &'static str,
+    /// Each has `_min`, `_avg` and `_max` columns in `table`
+    pub columns: &'static [&'static str],
+}
+
+pub const ROLLUPS: [Rollup; 2] = [
+    Rollup {
+        source: "tag_readings",
+        table: "tag_readings_agg",
+        columns: &[
+            "temperature",
+            "relative_humidity",
+            "pressure",
+            "battery_voltage",
+        ],
+    },
+    Rollup {
+        source: "air_readings",
+        table: "air_readings_agg",
+        columns: &[
+            "temperature",
+            "relative_humidity",
+            "pressure",
+            "pm2_5",
+            "co2",
+            "voc_index",
+            "nox_index",
+        ],
+    },
+];
+
+impl Rollup {
+    /// Upsert of the buckets, `bucket` being the SQL of a reading's bucket
+    /// start and `params` the placeholders of the resolution, `from` and `to`
+    fn upsert(&self, bucket: &str, params: [&str; 3], double: &str, excluded: &str) -> String {
+        let [resolution, from, to] = params;
+        let mut columns = ["bucket", "resolution_secs", "mac_address", "samples"]
+            .map(str::to_owned)
+            .to_vec();
+        let mut aggregates = Vec::new();
+        for column in self.columns {
+            for (function, suffix) in [("MIN", "min"), ("AVG", "avg"), ("MAX", "max")] {
+                columns.push(format!("{column}_{suffix}"));
+                aggregates.push(format!("{function}({column}){double}"));
+            }
+        }
+        let updates: Vec<_> = columns[3..]
+            .iter()
+            .map(|column| format!("{column} = {excluded}.{column}"))
+            .collect();
+        format!(
+            "INSERT INTO {table} ({columns}) \
+             SELECT {bucket} AS bucket, {resolution}, mac_address, COUNT(*), {aggregates} \
+             FROM {source} \
+             WHERE ({from} IS NULL OR recorded_at >= {from}) AND recorded_at < {to} \
+             GROUP BY 1, mac_address \
+             ON CONFLICT (mac_address, resolution_secs, bucket) DO UPDATE SET {updates}",
+            table = self.table,
+            source = self.source,
+            columns = columns.join(", "),
+            aggregates = aggregates.join(", "),
+            updates = updates.join(", "),
         )
-        "#,
-    )
-    .bind(data.timestamp)
-    .bind(MacAddress::new(data.mac))
-    .bind(data.temp)
-    .bind(data.dew_point_temp)
-    .bind(data.rel_humidity)
-    .bind(data.abs_humidity)
-    .bind(data.abs_pressure as i32)
-    .bind(data.pm1_0)
-    .bind(data.pm2_5)
-    .bind(data.pm4_0)
-    .bind(data.pm10_0)
-    .bind(data.co2 as i16)
-    .bind(data.voc_index as i16)
-    .bind(data.nox_index as i16)
-    .bind(data.luminosity)
-    .bind(data.measurement_seq as i32)
-    .bind(data.flags as i16)
-    .bind(data.tx_power as i16)
-    .bind(data.rssi as i16)
-    .execute(pool)
-    .await?;
-    Ok(())
+    }
+}
+
+/// Rows deleted per statement when expiring, so locks and the WAL stay small
+const EXPIRE_BATCH: i64 = 10_000;
+
+/// Opens the configured database, an `sqlite:` URI selects SQLite and anything else Postgres
+pub async fn connect(config: &Config) -> Result<Arc<dyn Storage>, anyhow::Error> {
+    let uri = config.server.database_uri()?;
+    let pool_size = config.server.pool_size;
+    if uri.starts_with("sqlite:") {
+        if config.timescale.enabled {
+            anyhow::bail!("[timescale] needs a Postgres database");
+        }
+        Ok(Arc::new(SqliteStorage::connect(uri, pool_size).await?))
+    } else {
+        Ok(Arc::new(
+            PostgresStorage::connect(uri, pool_size, &config.timescale).await?,
+        ))
+    }
+}
+
+/// MAC address column, `macaddr` in Postgres and text in SQLite
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mac(pub [u8; 6]);
+
+impl Mac {
+    pub fn bytes(self) -> [u8; 6] {
+        self.0
+    }
+}
+
+/// Rows of a tag and/or time range, `None` matches anything
+#[derive(Debug, Default)]
+pub struct RowFilter {
+    pub mac: Option<[u8; 6]>,
+    /// Inclusive
+    pub from: Option<DateTime<Utc>>,
+    /// Exclusive
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Hour with the averaged battery voltage and temperature
+pub type BatterySample = (DateTime<Utc>, f64, f64);
+
+/// Stored derived columns of a reading, see [`Storage::visit_derived`]
+pub type DerivedRow = (
+    i32,
+    DateTime<Utc>,
+    Mac,
+    Option<f32>,
+    Option<f32>,
+    Option<i32>,
+    Option<f64>,
+    Option<f64>,
+);
+
+/// A stored reading to reprocess, see [`Storage::raw_readings`]
+#[derive(Debug, FromRow)]
+pub struct RawRow {
+    pub id: i32,
+    pub recorded_at: DateTime<Utc>,
+    pub mac_address: Mac,
+    pub raw_payload: Option<Vec<u8>>,
+    pub temperature: Option<f32>,
+    pub relative_humidity: Option<f32>,
+    pub pressure: Option<i32>,
+}
+
+/// New values of a stored reading, see [`Storage::update_readings`]
+#[derive(Debug, Clone)]
+pub enum Reprocessed {
+    /// Decoded again from its raw payload, every sensor and derived column is
+    /// overwritten. The timestamp, sequence and RSSI stay.
+    Decoded(Ruuvi),
+    /// Derived again from the stored temperature and humidity
+    Derived {
+        abs_humidity: Option<f64>,
+        dew_point: Option<f64>,
+        humidity_formula: HumidityFormula,
+    },
+}
+
+/// Where the gateway stores readings and reads them back for the API, reports
+/// and maintenance commands
+pub trait Storage: Send + Sync {
+    /// Create or update the tables
+    fn migrate<'a>(&'a self) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Readings are linked to the row of `listener` in `listeners`, if it has
+    /// one. `raw_payload` is the manufacturer data the reading was decoded from.
+    fn insert_data_v2<'a>(
+        &'a self,
+        data: RuuviV2,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Format 3 lacks the power info, movement counter and sequence, they stay NULL
+    fn insert_data_v1<'a>(
+        &'a self,
+        data: RuuviV1,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    fn insert_data_e1<'a>(
+        &'a self,
+        data: RuuviE1,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Format 6 only has PM2.5 and no TX power, the other columns stay NULL
+    fn insert_data_v6<'a>(
+        &'a self,
+        data: RuuviV6,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Create or refresh the row of a listener that completed the handshake
+    fn upsert_listener<'a>(
+        &'a self,
+        listener: &'a ListenerRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Replace the stored health report of `listener`, which has to have a row
+    /// in `listeners`
+    fn upsert_listener_status<'a>(
+        &'a self,
+        listener: &'a str,
+        telemetry: Telemetry,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Latest health report of every listener that sent one, by name
+    fn listener_statuses<'a>(
+        &'a self,
+    ) -> BoxFuture<'a, Result<Vec<ListenerStatusRow>, anyhow::Error>>;
+
+    fn insert_door_event<'a>(
+        &'a self,
+        mac: [u8; 6],
+        timestamp: DateTime<Utc>,
+        transition: DoorTransition,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    fn upsert_tag_model<'a>(
+        &'a self,
+        mac: [u8; 6],
+        model: TagModel,
+        timestamp: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Every tag that has sent a reading, with the time of its newest stored reading
+    fn tags<'a>(&'a self) -> BoxFuture<'a, Result<Vec<TagRow>, anyhow::Error>>;
+
+    /// Tags given a name in the `tags` table
+    fn registered_tags<'a>(&'a self) -> BoxFuture<'a, Result<Vec<RegistryRow>, anyhow::Error>>;
+
+    fn register_tag<'a>(&'a self, tag: &'a RegistryRow)
+    -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// `false` when the tag wasn't registered
+    fn unregister_tag<'a>(&'a self, mac: [u8; 6]) -> BoxFuture<'a, Result<bool, anyhow::Error>>;
+
+    /// Alert rules added through the API
+    fn alert_rules<'a>(&'a self) -> BoxFuture<'a, Result<Vec<AlertRuleRow>, anyhow::Error>>;
+
+    fn upsert_alert_rule<'a>(
+        &'a self,
+        rule: &'a AlertRuleRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// `false` when there was no such rule
+    fn delete_alert_rule<'a>(&'a self, name: &'a str)
+    -> BoxFuture<'a, Result<bool, anyhow::Error>>;
+
+    fn insert_tag_quality<'a>(
+        &'a self,
+        snapshots: &'a [QualitySnapshot],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    fn insert_receptions<'a>(
+        &'a self,
+        mac: [u8; 6],
+        measurement_seq: u32,
+        timestamp: DateTime<Utc>,
+        primary: &'a str,
+        receptions: &'a [Reception],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Per listener and tag reception counts since `since`. `measurements` is the number of
+    /// stored measurements for the tag, every measurement has exactly one primary reception.
+    fn coverage<'a>(
+        &'a self,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<CoverageRow>, anyhow::Error>>;
+
+    /// Daily min/max/avg of a tag, days are bucketed at midnight of `tz`
+    fn daily_summary<'a>(
+        &'a self,
+        mac: [u8; 6],
+        tz: Tz,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<DailyRow>, anyhow::Error>>;
+
+    /// Hourly averaged battery voltage and temperature of a tag, oldest first
+    fn battery_history<'a>(
+        &'a self,
+        mac: [u8; 6],
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<BatterySample>, anyhow::Error>>;
+
+    fn recent_tag_macs<'a>(
+        &'a self,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<[u8; 6]>, anyhow::Error>>;
+
+    /// Latest door events, optionally only for one tag
+    fn door_events<'a>(
+        &'a self,
+        mac: Option<[u8; 6]>,
+        limit: i64,
+    ) -> BoxFuture<'a, Result<Vec<DoorEventRow>, anyhow::Error>>;
+
+    /// Per tag summary between `from` and `to`. Time above a limit is the sum of
+    /// the gaps to the next reading, capped at 5 minutes so outages aren't counted.
+    fn tag_summaries<'a>(
+        &'a self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        humidity_low: f32,
+        humidity_high: f32,
+        co2_limit: i16,
+    ) -> BoxFuture<'a, Result<Vec<TagSummaryRow>, anyhow::Error>>;
+
+    /// One page of readings of a tag ordered by time, starting after `after`
+    fn history<'a>(
+        &'a self,
+        mac: [u8; 6],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        after: Option<&'a HistoryCursor>,
+        limit: i64,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRow>, anyhow::Error>>;
+
+    /// Readings of a tag averaged over `step_secs` long buckets aligned to `from`
+    fn history_buckets<'a>(
+        &'a self,
+        mac: [u8; 6],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step_secs: u32,
+    ) -> BoxFuture<'a, Result<Vec<HistoryBucket>, anyhow::Error>>;
+
+    /// Deletes the rows of [`TABLES`] matching `filter` in one transaction, or
+    /// only counts them with `dry_run`. Returns the rows per table.
+    fn prune<'a>(
+        &'a self,
+        filter: &'a RowFilter,
+        dry_run: bool,
+    ) -> BoxFuture<'a, Result<Vec<(&'static str, u64)>, anyhow::Error>>;
+
+    /// Deletes the rows of `table`, one of [`RETAINED_TABLES`], recorded
+    /// before `before` in batches, or only counts them with `dry_run`
+    fn expire<'a>(
+        &'a self,
+        table: &'a str,
+        before: DateTime<Utc>,
+        dry_run: bool,
+    ) -> BoxFuture<'a, Result<u64, anyhow::Error>>;
+
+    /// Start of the newest bucket of `rollup` at `resolution_secs`
+    fn latest_rollup<'a>(
+        &'a self,
+        rollup: &'a Rollup,
+        resolution_secs: u32,
+    ) -> BoxFuture<'a, Result<Option<DateTime<Utc>>, anyhow::Error>>;
+
+    /// Aggregates the readings recorded from `from`, the start when `None`,
+    /// until `to` into buckets of `resolution_secs`, replacing the stored
+    /// ones. Both bounds are bucket starts. Returns the buckets written.
+    fn rollup<'a>(
+        &'a self,
+        rollup: &'a Rollup,
+        resolution_secs: u32,
+        from: Option<DateTime<Utc>>,
+        to: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<u64, anyhow::Error>>;
+
+    /// Rebuild the indexes of `table` and refresh its planner statistics
+    fn reindex<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Streams the readings of `table` matching `filter` in id order
+    fn visit_derived<'a>(
+        &'a self,
+        table: &'a str,
+        filter: &'a RowFilter,
+        visit: &'a mut (dyn FnMut(DerivedRow) + Send),
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+
+    /// Up to `limit` readings of `table` matching `filter` with an id above
+    /// `after`, in id order
+    fn raw_readings<'a>(
+        &'a self,
+        table: &'a str,
+        filter: &'a RowFilter,
+        after: i32,
+        limit: u32,
+    ) -> BoxFuture<'a, Result<Vec<RawRow>, anyhow::Error>>;
+
+    /// Overwrites readings of `table` by id in one transaction
+    fn update_readings<'a>(
+        &'a self,
+        table: &'a str,
+        updates: &'a [(i32, Reprocessed)],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+}
+
+/// A connected listener as recorded in `listeners`
+#[derive(Debug, Clone)]
+pub struct ListenerRow {
+    /// Pinned identity, or the IP address of a listener without one
+    pub name: String,
+    pub identified: bool,
+    pub address: IpAddr,
+    /// Name of the PSK it authenticated with
+    pub psk: String,
+    /// Version it reported in its hello
+    pub firmware: Option<String>,
+}
+
+/// A listener's latest [`Telemetry`] as recorded in `listener_status`
+#[derive(Debug, Clone, FromRow)]
+pub struct ListenerStatusRow {
+    pub name: String,
+    pub reported_at: DateTime<Utc>,
+    pub uptime_secs: i64,
+    pub reset_reason: Option<String>,
+    pub heap_free: i64,
+    pub wifi_rssi: Option<i16>,
+    pub adverts: i64,
+    pub parse_failures: i64,
+    pub dropped: i64,
+    pub queue_overflows: i64,
+    pub frames_sent: i64,
+    pub reconnects: i64,
+}
+
+/// `listener_status.reset_reason` of a report, `None` when the listener didn't say
+fn reset_reason(reason: ResetReason) -> Option<String> {
+    Some(match reason {
+        ResetReason::PowerOn => "power_on".to_owned(),
+        ResetReason::Software => "software".to_owned(),
+        ResetReason::Watchdog => "watchdog".to_owned(),
+        ResetReason::ScannerStalled => "scanner_stalled".to_owned(),
+        ResetReason::SenderStalled => "sender_stalled".to_owned(),
+        ResetReason::Brownout => "brownout".to_owned(),
+        ResetReason::Other(code) => format!("other_{code}"),
+        ResetReason::Panic => "panic".to_owned(),
+        ResetReason::Unknown => return None,
+    })
+}
+
+#[derive(Debug, FromRow)]
+pub struct DoorEventRow {
+    pub recorded_at: DateTime<Utc>,
+    pub mac_address: Mac,
+    pub state: String,
+    pub previous_state: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+pub struct TagRow {
+    pub mac_address: Mac,
+    pub model: String,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, FromRow)]
+pub struct RegistryRow {
+    pub mac_address: Mac,
+    pub name: String,
+    pub location: Option<String>,
+    /// Comma separated formats, empty for any
+    pub formats: String,
+}
+
+#[derive(Debug, FromRow)]
+pub struct AlertRuleRow {
+    pub name: String,
+    pub tag: Option<String>,
+    pub metric: String,
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+    pub hysteresis: f64,
+    pub for_secs: i64,
+}
+
+#[derive(Debug, FromRow)]
+pub struct CoverageRow {
+    pub listener: String,
+    pub mac_address: Mac,
+    pub packets: i64,
+    pub primary_packets: i64,
+    pub measurements: i64,
+    pub avg_rssi: Option<f64>,
+}
+
+#[derive(Debug, FromRow)]
+pub struct DailyRow {
+    pub day: NaiveDate,
+    pub temp_min: Option<f32>,
+    pub temp_max: Option<f32>,
+    pub temp_avg: Option<f64>,
+    pub humidity_min: Option<f32>,
+    pub humidity_max: Option<f32>,
+    pub humidity_avg: Option<f64>,
+    pub samples: i64,
+}
+
+#[derive(Debug, FromRow)]
+pub struct TagSummaryRow {
+    pub mac_address: Mac,
+    pub temp_min: Option<f32>,
+    pub temp_max: Option<f32>,
+    pub temp_avg: Option<f64>,
+    pub humidity_excursion_secs: Option<f64>,
+    pub has_co2: bool,
+    pub co2_above_secs: Option<f64>,
+    pub battery_voltage: Option<f32>,
+}
+
+/// Reading of any format, format specific columns are NULL for the others
+#[derive(Debug, FromRow, Serialize)]
+pub struct HistoryRow {
+    #[serde(skip)]
+    pub id: i32,
+    pub format: String,
+    pub timestamp: DateTime<Utc>,
+    pub temp: Option<f32>,
+    pub dew_point_temp: Option<f64>,
+    pub rel_humidity: Option<f32>,
+    pub abs_humidity: Option<f64>,
+    pub abs_pressure: Option<i32>,
+    pub acc_x: Option<i16>,
+    pub acc_y: Option<i16>,
+    pub acc_z: Option<i16>,
+    pub battery_voltage: Option<f32>,
+    pub movement_counter: Option<i16>,
+    pub pm1_0: Option<f32>,
+    pub pm2_5: Option<f32>,
+    pub pm4_0: Option<f32>,
+    pub pm10_0: Option<f32>,
+    pub co2: Option<i16>,
+    pub voc_index: Option<i16>,
+    pub nox_index: Option<i16>,
+    pub luminosity: Option<f32>,
+    pub measurement_seq: Option<i32>,
+    pub tx_power: Option<i16>,
+    pub rssi: Option<i16>,
+}
+
+/// Keyset position of the last row of a history page
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryCursor {
+    pub timestamp: DateTime<Utc>,
+    pub format: String,
+    pub id: i32,
+}
+
+/// Averages of the readings of a tag in one `step` long bucket
+#[derive(Debug, FromRow, Serialize)]
+pub struct HistoryBucket {
+    /// Start of the bucket
+    pub timestamp: DateTime<Utc>,
+    pub samples: i64,
+    pub temp: Option<f64>,
+    pub dew_point_temp: Option<f64>,
+    pub rel_humidity: Option<f64>,
+    pub abs_humidity: Option<f64>,
+    pub abs_pressure: Option<f64>,
+    pub battery_voltage: Option<f64>,
+    pub pm1_0: Option<f64>,
+    pub pm2_5: Option<f64>,
+    pub pm4_0: Option<f64>,
+    pub pm10_0: Option<f64>,
+    pub co2: Option<f64>,
+    pub voc_index: Option<f64>,
+    pub nox_index: Option<f64>,
+    pub luminosity: Option<f64>,
 }
@@ -0,0 +1,1532 @@
+use super::{
+    AlertRuleRow, BatterySample, CoverageRow, DailyRow, DerivedRow, DoorEventRow, EXPIRE_BATCH,
+    HistoryBucket, HistoryCursor, HistoryRow, ListenerRow, ListenerStatusRow, Mac, RETAINED_TABLES,
+    RawRow, RegistryRow, Reprocessed, Rollup, RowFilter, Storage, TABLES, TagRow, TagSummaryRow,
+    reset_reason,
+};
+use crate::config::TimescaleConfig;
+use crate::dedup::Reception;
+use crate::door::DoorTransition;
+use crate::quality::QualitySnapshot;
+use crate::sink::StoredReading;
+use crate::{Ruuvi, RuuviE1, RuuviV1, RuuviV2, RuuviV6};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use futures_util::TryStreamExt;
+use futures_util::future::BoxFuture;
+use ruuvi_schema::TagModel;
+use ruuvi_schema::protocol::Telemetry;
+use sqlx::error::BoxDynError;
+use sqlx::migrate::Migrator;
+use sqlx::postgres::{PgPoolOptions, PgTypeInfo, PgValueRef};
+use sqlx::types::mac_address::MacAddress;
+use sqlx::{Decode, Pool, Postgres, QueryBuilder, Type};
+use timescale::Batcher;
+
+mod timescale;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/postgres");
+
+impl Type<Postgres> for Mac {
+    fn type_info() -> PgTypeInfo {
+        <MacAddress as Type<Postgres>>::type_info()
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for Mac {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Self(
+            <MacAddress as Decode<Postgres>>::decode(value)?.bytes(),
+        ))
+    }
+}
+
+pub struct PostgresStorage {
+    pool: Pool<Postgres>,
+    timescale: Option<TimescaleConfig>,
+    /// Collects the readings into batched inserts, only with Timescale
+    batcher: Option<Batcher>,
+}
+
+impl PostgresStorage {
+    pub async fn connect(
+        uri: &str,
+        pool_size: u32,
+        timescale: &TimescaleConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let pool = PgPoolOptions::new()
+            .max_connections(pool_size)
+            .connect(uri)
+            .await?;
+        let timescale = timescale.enabled.then(|| timescale.clone());
+        let batcher = timescale
+            .as_ref()
+            .map(|config| Batcher::spawn(pool.clone(), config));
+        Ok(Self {
+            pool,
+            timescale,
+            batcher,
+        })
+    }
+
+    async fn insert(
+        &self,
+        data: Ruuvi,
+        listener: &str,
+        raw_payload: Option<&[u8]>,
+    ) -> Result<(), anyhow::Error> {
+        let reading = StoredReading {
+            data,
+            listener: listener.to_owned(),
+            raw_payload: raw_payload.map(<[u8]>::to_vec),
+        };
+        match &self.batcher {
+            Some(batcher) => batcher.insert(reading).await,
+            None => insert_readings(&self.pool, &[reading]).await,
+        }
+    }
+}
+
+/// Inserts the readings in one transaction, with one statement per format
+async fn insert_readings(
+    pool: &Pool<Postgres>,
+    readings: &[StoredReading],
+) -> Result<(), anyhow::Error> {
+    let mut tx = pool.begin().await?;
+
+    let v2: Vec<_> = readings
+        .iter()
+        .filter_map(|reading| match &reading.data {
+            Ruuvi::V2(v2) => Some((v2, reading)),
+            _ => None,
+        })
+        .collect();
+    if !v2.is_empty() {
+        QueryBuilder::<Postgres>::new(
+            r#"
+            INSERT INTO tag_readings (
+                recorded_at,
+                mac_address,
+                temperature,
+                relative_humidity,
+                pressure,
+                acceleration_x,
+                acceleration_y,
+                acceleration_z,
+                battery_voltage,
+                tx_power,
+                movement_counter,
+                measurement_sequence,
+                absolute_humidity,
+                dew_point_temperature,
+                rssi,
+                listener_id,
+                raw_payload,
+                humidity_formula,
+                heat_index,
+                humidex,
+                wet_bulb_temperature,
+                vapour_pressure_deficit,
+                uncalibrated_temperature,
+                uncalibrated_relative_humidity,
+                movement_event
+            )
+            "#,
+        )
+        .push_values(v2, |mut row, (data, reading)| {
+            let comfort = data.comfort();
+            row.push_bind(data.timestamp)
+                .push_bind(MacAddress::new(data.mac))
+                .push_bind(data.temp)
+                .push_bind(data.rel_humidity)
+                .push_bind(data.abs_pressure.map(|p| p as i32))
+                .push_bind(data.acc_x)
+                .push_bind(data.acc_y)
+                .push_bind(data.acc_z)
+                .push_bind(data.battery_voltage)
+                .push_bind(data.tx_power.map(i16::from))
+                .push_bind(data.movement_counter.map(i16::from))
+                .push_bind(data.measurement_seq as i32)
+                .push_bind(data.abs_humidity.map(|h| h as f32))
+                .push_bind(data.dew_point_temp.map(|t| t as f32))
+                .push_bind(data.rssi as i16)
+                .push("(SELECT id FROM listeners WHERE name = ")
+                .push_bind_unseparated(&reading.listener)
+                .push_unseparated(")")
+                .push_bind(&reading.raw_payload)
+                .push_bind(data.humidity_formula.as_str())
+                .push_bind(comfort.map(|c| c.heat_index as f32))
+                .push_bind(comfort.map(|c| c.humidex as f32))
+                .push_bind(comfort.map(|c| c.wet_bulb_temp as f32))
+                .push_bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+                .push_bind(data.uncalibrated.and_then(|u| u.temp))
+                .push_bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                .push_bind(data.movement_event);
+        })
+        .build()
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // Format 3 lacks the power info, movement counter and sequence, they stay NULL
+    let v1: Vec<_> = readings
+        .iter()
+        .filter_map(|reading| match &reading.data {
+            Ruuvi::V1(v1) => Some((v1, reading)),
+            _ => None,
+        })
+        .collect();
+    if !v1.is_empty() {
+        QueryBuilder::<Postgres>::new(
+            r#"
+            INSERT INTO tag_readings (
+                recorded_at,
+                mac_address,
+                temperature,
+                relative_humidity,
+                pressure,
+                acceleration_x,
+                acceleration_y,
+                acceleration_z,
+                battery_voltage,
+                absolute_humidity,
+                dew_point_temperature,
+                rssi,
+                listener_id,
+                raw_payload,
+                humidity_formula,
+                heat_index,
+                humidex,
+                wet_bulb_temperature,
+                vapour_pressure_deficit,
+                uncalibrated_temperature,
+                uncalibrated_relative_humidity
+            )
+            "#,
+        )
+        .push_values(v1, |mut row, (data, reading)| {
+            let comfort = data.comfort();
+            row.push_bind(data.timestamp)
+                .push_bind(MacAddress::new(data.mac))
+                .push_bind(data.temp)
+                .push_bind(data.rel_humidity)
+                .push_bind(data.abs_pressure.map(|p| p as i32))
+                .push_bind(data.acc_x)
+                .push_bind(data.acc_y)
+                .push_bind(data.acc_z)
+                .push_bind(data.battery_voltage)
+                .push_bind(data.abs_humidity.map(|h| h as f32))
+                .push_bind(data.dew_point_temp.map(|t| t as f32))
+                .push_bind(data.rssi as i16)
+                .push("(SELECT id FROM listeners WHERE name = ")
+                .push_bind_unseparated(&reading.listener)
+                .push_unseparated(")")
+                .push_bind(&reading.raw_payload)
+                .push_bind(data.humidity_formula.as_str())
+                .push_bind(comfort.map(|c| c.heat_index as f32))
+                .push_bind(comfort.map(|c| c.humidex as f32))
+                .push_bind(comfort.map(|c| c.wet_bulb_temp as f32))
+                .push_bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+                .push_bind(data.uncalibrated.and_then(|u| u.temp))
+                .push_bind(data.uncalibrated.and_then(|u| u.rel_humidity));
+        })
+        .build()
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let e1: Vec<_> = readings
+        .iter()
+        .filter_map(|reading| match &reading.data {
+            Ruuvi::E1(e1) => Some((e1, reading)),
+            _ => None,
+        })
+        .collect();
+    if !e1.is_empty() {
+        QueryBuilder::<Postgres>::new(
+            r#"
+            INSERT INTO air_readings (
+                recorded_at,
+                mac_address,
+                temperature,
+                dew_point_temperature,
+                relative_humidity,
+                absolute_humidity,
+                pressure,
+                pm1_0,
+                pm2_5,
+                pm4_0,
+                pm10_0,
+                co2,
+                voc_index,
+                nox_index,
+                luminosity,
+                measurement_sequence,
+                flags,
+                tx_power,
+                rssi,
+                listener_id,
+                raw_payload,
+                humidity_formula,
+                air_quality_index,
+                uncalibrated_temperature,
+                uncalibrated_relative_humidity,
+                uncalibrated_co2
+            )
+            "#,
+        )
+        .push_values(e1, |mut row, (data, reading)| {
+            row.push_bind(data.timestamp)
+                .push_bind(MacAddress::new(data.mac))
+                .push_bind(data.temp)
+                .push_bind(data.dew_point_temp)
+                .push_bind(data.rel_humidity)
+                .push_bind(data.abs_humidity)
+                .push_bind(data.abs_pressure.map(|p| p as i32))
+                .push_bind(data.pm1_0)
+                .push_bind(data.pm2_5)
+                .push_bind(data.pm4_0)
+                .push_bind(data.pm10_0)
+                .push_bind(data.co2.map(|v| v as i16))
+                .push_bind(data.voc_index.map(|v| v as i16))
+                .push_bind(data.nox_index.map(|v| v as i16))
+                .push_bind(data.luminosity)
+                .push_bind(data.measurement_seq as i32)
+                .push_bind(data.flags as i16)
+                .push_bind(data.tx_power as i16)
+                .push_bind(data.rssi as i16)
+                .push("(SELECT id FROM listeners WHERE name = ")
+                .push_bind_unseparated(&reading.listener)
+                .push_unseparated(")")
+                .push_bind(&reading.raw_payload)
+                .push_bind(data.humidity_formula.as_str())
+                .push_bind(data.air_quality_index().map(|v| v as f32))
+                .push_bind(data.uncalibrated.and_then(|u| u.temp))
+                .push_bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                .push_bind(data.uncalibrated.and_then(|u| u.co2).map(|v| v as i16));
+        })
+        .build()
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // Format 6 only has PM2.5 and no TX power, the other columns stay NULL
+    let v6: Vec<_> = readings
+        .iter()
+        .filter_map(|reading| match &reading.data {
+            Ruuvi::V6(v6) => Some((v6, reading)),
+            _ => None,
+        })
+        .collect();
+    if !v6.is_empty() {
+        QueryBuilder::<Postgres>::new(
+            r#"
+            INSERT INTO air_readings (
+                recorded_at,
+                mac_address,
+                temperature,
+                dew_point_temperature,
+                relative_humidity,
+                absolute_humidity,
+                pressure,
+                pm2_5,
+                co2,
+                voc_index,
+                nox_index,
+                luminosity,
+                measurement_sequence,
+                flags,
+                rssi,
+                listener_id,
+                raw_payload,
+                humidity_formula,
+                air_quality_index,
+                uncalibrated_temperature,
+                uncalibrated_relative_humidity,
+                uncalibrated_co2
+            )
+            "#,
+        )
+        .push_values(v6, |mut row, (data, reading)| {
+            row.push_bind(data.timestamp)
+                .push_bind(MacAddress::new(data.mac))
+                .push_bind(data.temp)
+                .push_bind(data.dew_point_temp)
+                .push_bind(data.rel_humidity)
+                .push_bind(data.abs_humidity)
+                .push_bind(data.abs_pressure.map(|p| p as i32))
+                .push_bind(data.pm2_5)
+                .push_bind(data.co2.map(|v| v as i16))
+                .push_bind(data.voc_index.map(|v| v as i16))
+                .push_bind(data.nox_index.map(|v| v as i16))
+                .push_bind(data.luminosity)
+                .push_bind(data.measurement_seq as i32)
+                .push_bind(data.flags as i16)
+                .push_bind(data.rssi as i16)
+                .push("(SELECT id FROM listeners WHERE name = ")
+                .push_bind_unseparated(&reading.listener)
+                .push_unseparated(")")
+                .push_bind(&reading.raw_payload)
+                .push_bind(data.humidity_formula.as_str())
+                .push_bind(data.air_quality_index().map(|v| v as f32))
+                .push_bind(data.uncalibrated.and_then(|u| u.temp))
+                .push_bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                .push_bind(data.uncalibrated.and_then(|u| u.co2).map(|v| v as i16));
+        })
+        .build()
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+impl Storage for PostgresStorage {
+    fn migrate<'a>(&'a self) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            MIGRATOR.run(&self.pool).await?;
+            if let Some(config) = &self.timescale {
+                timescale::setup(&self.pool, config).await?;
+            }
+            Ok(())
+        })
+    }
+
+    fn insert_data_v2<'a>(
+        &'a self,
+        data: RuuviV2,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(self.insert(Ruuvi::V2(data), listener, raw_payload))
+    }
+
+    fn insert_data_v1<'a>(
+        &'a self,
+        data: RuuviV1,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(self.insert(Ruuvi::V1(data), listener, raw_payload))
+    }
+
+    fn insert_data_e1<'a>(
+        &'a self,
+        data: RuuviE1,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(self.insert(Ruuvi::E1(data), listener, raw_payload))
+    }
+
+    fn insert_data_v6<'a>(
+        &'a self,
+        data: RuuviV6,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(self.insert(Ruuvi::V6(data), listener, raw_payload))
+    }
+
+    fn upsert_listener<'a>(
+        &'a self,
+        listener: &'a ListenerRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Postgres>(
+                r#"
+                INSERT INTO listeners (name, identified, address, psk, firmware, last_seen)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (name) DO UPDATE
+                SET identified = EXCLUDED.identified,
+                    address = EXCLUDED.address,
+                    psk = EXCLUDED.psk,
+                    firmware = EXCLUDED.firmware,
+                    last_seen = EXCLUDED.last_seen
+                "#,
+            )
+            .bind(&listener.name)
+            .bind(listener.identified)
+            .bind(listener.address.to_string())
+            .bind(&listener.psk)
+            .bind(&listener.firmware)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn upsert_listener_status<'a>(
+        &'a self,
+        listener: &'a str,
+        telemetry: Telemetry,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Postgres>(
+                r#"
+                INSERT INTO listener_status (
+                    listener_id,
+                    reported_at,
+                    uptime_secs,
+                    heap_free,
+                    wifi_rssi,
+                    adverts,
+                    parse_failures,
+                    dropped,
+                    queue_overflows,
+                    frames_sent,
+                    reconnects,
+                    reset_reason
+                )
+                SELECT id, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12
+                FROM listeners WHERE name = $1
+                ON CONFLICT (listener_id) DO UPDATE
+                SET reported_at = EXCLUDED.reported_at,
+                    uptime_secs = EXCLUDED.uptime_secs,
+                    heap_free = EXCLUDED.heap_free,
+                    wifi_rssi = EXCLUDED.wifi_rssi,
+                    adverts = EXCLUDED.adverts,
+                    parse_failures = EXCLUDED.parse_failures,
+                    dropped = EXCLUDED.dropped,
+                    queue_overflows = EXCLUDED.queue_overflows,
+                    frames_sent = EXCLUDED.frames_sent,
+                    reconnects = EXCLUDED.reconnects,
+                    reset_reason = EXCLUDED.reset_reason
+                "#,
+            )
+            .bind(listener)
+            .bind(Utc::now())
+            .bind(i64::try_from(telemetry.uptime_secs)?)
+            .bind(i64::from(telemetry.heap_free))
+            .bind(telemetry.wifi_rssi.map(i16::from))
+            .bind(i64::from(telemetry.adverts))
+            .bind(i64::from(telemetry.parse_failures))
+            .bind(i64::from(telemetry.dropped))
+            .bind(i64::from(telemetry.queue_overflows))
+            .bind(i64::from(telemetry.frames_sent))
+            .bind(i64::from(telemetry.reconnects))
+            .bind(reset_reason(telemetry.reset_reason))
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn listener_statuses<'a>(
+        &'a self,
+    ) -> BoxFuture<'a, Result<Vec<ListenerStatusRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, ListenerStatusRow>(
+                r#"
+                SELECT
+                    l.name,
+                    s.reported_at,
+                    s.uptime_secs,
+                    s.reset_reason,
+                    s.heap_free,
+                    s.wifi_rssi,
+                    s.adverts,
+                    s.parse_failures,
+                    s.dropped,
+                    s.queue_overflows,
+                    s.frames_sent,
+                    s.reconnects
+                FROM listener_status s
+                JOIN listeners l ON l.id = s.listener_id
+                ORDER BY l.name
+                "#,
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn insert_door_event<'a>(
+        &'a self,
+        mac: [u8; 6],
+        timestamp: DateTime<Utc>,
+        transition: DoorTransition,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Postgres>(
+                r#"
+                INSERT INTO door_events (
+                    recorded_at,
+                    mac_address,
+                    state,
+                    previous_state,
+                    acceleration
+                ) VALUES ($1, $2, $3, $4, $5)
+                "#,
+            )
+            .bind(timestamp)
+            .bind(MacAddress::new(mac))
+            .bind(transition.current.as_str())
+            .bind(transition.previous.map(|s| s.as_str()))
+            .bind(transition.acceleration)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn upsert_tag_model<'a>(
+        &'a self,
+        mac: [u8; 6],
+        model: TagModel,
+        timestamp: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Postgres>(
+                r#"
+                INSERT INTO tag_models (mac_address, model, updated_at)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (mac_address) DO UPDATE
+                SET model = EXCLUDED.model, updated_at = EXCLUDED.updated_at
+                WHERE tag_models.model <> EXCLUDED.model
+                "#,
+            )
+            .bind(MacAddress::new(mac))
+            .bind(model.as_str())
+            .bind(timestamp)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn tags<'a>(&'a self) -> BoxFuture<'a, Result<Vec<TagRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, TagRow>(
+                r#"
+                SELECT
+                    mac_address,
+                    model,
+                    GREATEST(
+                        (SELECT MAX(recorded_at) FROM tag_readings t WHERE t.mac_address = m.mac_address),
+                        (SELECT MAX(recorded_at) FROM air_readings a WHERE a.mac_address = m.mac_address)
+                    ) AS last_seen
+                FROM tag_models m
+                ORDER BY mac_address
+                "#,
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn registered_tags<'a>(&'a self) -> BoxFuture<'a, Result<Vec<RegistryRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, RegistryRow>(
+                "SELECT mac_address, name, location, formats FROM tags ORDER BY name",
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn register_tag<'a>(
+        &'a self,
+        tag: &'a RegistryRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Postgres>(
+                r#"
+                INSERT INTO tags (mac_address, name, location, formats, updated_at)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (mac_address) DO UPDATE
+                SET name = EXCLUDED.name,
+                    location = EXCLUDED.location,
+                    formats = EXCLUDED.formats,
+                    updated_at = EXCLUDED.updated_at
+                "#,
+            )
+            .bind(MacAddress::new(tag.mac_address.bytes()))
+            .bind(&tag.name)
+            .bind(&tag.location)
+            .bind(&tag.formats)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn unregister_tag<'a>(&'a self, mac: [u8; 6]) -> BoxFuture<'a, Result<bool, anyhow::Error>> {
+        Box::pin(async move {
+            let result = sqlx::query::<Postgres>("DELETE FROM tags WHERE mac_address = $1")
+                .bind(MacAddress::new(mac))
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn alert_rules<'a>(&'a self) -> BoxFuture<'a, Result<Vec<AlertRuleRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, AlertRuleRow>(
+                "SELECT name, tag, metric, above, below, hysteresis, for_secs FROM alert_rules ORDER BY name",
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn upsert_alert_rule<'a>(
+        &'a self,
+        rule: &'a AlertRuleRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Postgres>(
+                r#"
+                INSERT INTO alert_rules (name, tag, metric, above, below, hysteresis, for_secs, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT (name) DO UPDATE
+                SET tag = EXCLUDED.tag,
+                    metric = EXCLUDED.metric,
+                    above = EXCLUDED.above,
+                    below = EXCLUDED.below,
+                    hysteresis = EXCLUDED.hysteresis,
+                    for_secs = EXCLUDED.for_secs,
+                    updated_at = EXCLUDED.updated_at
+                "#,
+            )
+            .bind(&rule.name)
+            .bind(&rule.tag)
+            .bind(&rule.metric)
+            .bind(rule.above)
+            .bind(rule.below)
+            .bind(rule.hysteresis)
+            .bind(rule.for_secs)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn delete_alert_rule<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<bool, anyhow::Error>> {
+        Box::pin(async move {
+            let result = sqlx::query::<Postgres>("DELETE FROM alert_rules WHERE name = $1")
+                .bind(name)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn insert_tag_quality<'a>(
+        &'a self,
+        snapshots: &'a [QualitySnapshot],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            for snapshot in snapshots {
+                sqlx::query::<Postgres>(
+                    r#"
+                    INSERT INTO tag_quality (
+                        mac_address,
+                        score,
+                        sensor,
+                        delivery,
+                        readings,
+                        lost,
+                        sentinels,
+                        clamped,
+                        missing_timestamps
+                    ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                    "#,
+                )
+                .bind(MacAddress::new(snapshot.mac))
+                .bind(snapshot.score as f32)
+                .bind(snapshot.sensor as f32)
+                .bind(snapshot.delivery as f32)
+                .bind(snapshot.readings as i64)
+                .bind(snapshot.lost as i64)
+                .bind(snapshot.sentinels as i64)
+                .bind(snapshot.clamped as i64)
+                .bind(snapshot.missing_timestamps as i64)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn insert_receptions<'a>(
+        &'a self,
+        mac: [u8; 6],
+        measurement_seq: u32,
+        timestamp: DateTime<Utc>,
+        primary: &'a str,
+        receptions: &'a [Reception],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            for reception in receptions {
+                sqlx::query::<Postgres>(
+                    r#"
+                    INSERT INTO receptions (
+                        recorded_at,
+                        mac_address,
+                        measurement_sequence,
+                        listener,
+                        rssi,
+                        is_primary
+                    ) VALUES ($1, $2, $3, $4, $5, $6)
+                    "#,
+                )
+                .bind(timestamp)
+                .bind(MacAddress::new(mac))
+                .bind(measurement_seq as i32)
+                .bind(&reception.listener)
+                .bind(reception.rssi as i16)
+                .bind(reception.listener == primary)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn coverage<'a>(
+        &'a self,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<CoverageRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, CoverageRow>(
+                r#"
+                WITH per_tag AS (
+                    SELECT mac_address, COUNT(*) FILTER (WHERE is_primary) AS measurements
+                    FROM receptions
+                    WHERE recorded_at >= $1
+                    GROUP BY mac_address
+                )
+                SELECT
+                    r.listener,
+                    r.mac_address,
+                    COUNT(*) AS packets,
+                    COUNT(*) FILTER (WHERE r.is_primary) AS primary_packets,
+                    per_tag.measurements,
+                    AVG(r.rssi)::double precision AS avg_rssi
+                FROM receptions r
+                JOIN per_tag USING (mac_address)
+                WHERE r.recorded_at >= $1
+                GROUP BY r.listener, r.mac_address, per_tag.measurements
+                ORDER BY r.listener, r.mac_address
+                "#,
+            )
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn daily_summary<'a>(
+        &'a self,
+        mac: [u8; 6],
+        tz: Tz,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<DailyRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, DailyRow>(
+                r#"
+                SELECT
+                    (recorded_at AT TIME ZONE $2)::date AS day,
+                    MIN(temperature) AS temp_min,
+                    MAX(temperature) AS temp_max,
+                    AVG(temperature)::double precision AS temp_avg,
+                    MIN(relative_humidity) AS humidity_min,
+                    MAX(relative_humidity) AS humidity_max,
+                    AVG(relative_humidity)::double precision AS humidity_avg,
+                    COUNT(*) AS samples
+                FROM (
+                    SELECT recorded_at, temperature, relative_humidity
+                    FROM tag_readings
+                    WHERE mac_address = $1 AND recorded_at >= $3
+                    UNION ALL
+                    SELECT recorded_at, temperature, relative_humidity
+                    FROM air_readings
+                    WHERE mac_address = $1 AND recorded_at >= $3
+                ) readings
+                GROUP BY day
+                ORDER BY day
+                "#,
+            )
+            .bind(MacAddress::new(mac))
+            .bind(tz.name())
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn battery_history<'a>(
+        &'a self,
+        mac: [u8; 6],
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<BatterySample>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, BatterySample>(
+                r#"
+                SELECT
+                    date_trunc('hour', recorded_at) AS hour,
+                    AVG(battery_voltage)::double precision,
+                    AVG(temperature)::double precision
+                FROM tag_readings
+                WHERE mac_address = $1
+                    AND recorded_at >= $2
+                    AND battery_voltage IS NOT NULL
+                    AND temperature IS NOT NULL
+                GROUP BY hour
+                ORDER BY hour
+                "#,
+            )
+            .bind(MacAddress::new(mac))
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn recent_tag_macs<'a>(
+        &'a self,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<[u8; 6]>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, (MacAddress,)>(
+                "SELECT DISTINCT mac_address FROM tag_readings WHERE recorded_at >= $1",
+            )
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows.into_iter().map(|(mac,)| mac.bytes()).collect())
+        })
+    }
+
+    fn door_events<'a>(
+        &'a self,
+        mac: Option<[u8; 6]>,
+        limit: i64,
+    ) -> BoxFuture<'a, Result<Vec<DoorEventRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, DoorEventRow>(
+                r#"
+                SELECT recorded_at, mac_address, state, previous_state
+                FROM door_events
+                WHERE $1::macaddr IS NULL OR mac_address = $1
+                ORDER BY recorded_at DESC
+                LIMIT $2
+                "#,
+            )
+            .bind(mac.map(MacAddress::new))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn tag_summaries<'a>(
+        &'a self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        humidity_low: f32,
+        humidity_high: f32,
+        co2_limit: i16,
+    ) -> BoxFuture<'a, Result<Vec<TagSummaryRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, TagSummaryRow>(
+                r#"
+                WITH readings AS (
+                    SELECT recorded_at, mac_address, temperature, relative_humidity,
+                        NULL::smallint AS co2, battery_voltage
+                    FROM tag_readings
+                    WHERE recorded_at >= $1 AND recorded_at < $2
+                    UNION ALL
+                    SELECT recorded_at, mac_address, temperature, relative_humidity,
+                        co2, NULL::real AS battery_voltage
+                    FROM air_readings
+                    WHERE recorded_at >= $1 AND recorded_at < $2
+                ), spans AS (
+                    SELECT *,
+                        LEAST(
+                            EXTRACT(EPOCH FROM LEAD(recorded_at) OVER (
+                                PARTITION BY mac_address ORDER BY recorded_at
+                            ) - recorded_at),
+                            300
+                        )::double precision AS secs
+                    FROM readings
+                )
+                SELECT
+                    mac_address,
+                    MIN(temperature) AS temp_min,
+                    MAX(temperature) AS temp_max,
+                    AVG(temperature)::double precision AS temp_avg,
+                    SUM(secs) FILTER (
+                        WHERE relative_humidity < $3 OR relative_humidity > $4
+                    ) AS humidity_excursion_secs,
+                    bool_or(co2 IS NOT NULL) AS has_co2,
+                    SUM(secs) FILTER (WHERE co2 > $5) AS co2_above_secs,
+                    (array_agg(battery_voltage ORDER BY recorded_at DESC)
+                        FILTER (WHERE battery_voltage IS NOT NULL))[1] AS battery_voltage
+                FROM spans
+                GROUP BY mac_address
+                ORDER BY mac_address
+                "#,
+            )
+            .bind(from)
+            .bind(to)
+            .bind(humidity_low)
+            .bind(humidity_high)
+            .bind(co2_limit)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn history<'a>(
+        &'a self,
+        mac: [u8; 6],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        after: Option<&'a HistoryCursor>,
+        limit: i64,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, HistoryRow>(
+                r#"
+                SELECT * FROM (
+                    SELECT
+                        id,
+                        -- Only format 3 readings lack the sequence
+                        CASE WHEN measurement_sequence IS NULL THEN 'v1' ELSE 'v2' END AS format,
+                        recorded_at AS timestamp,
+                        temperature AS temp,
+                        dew_point_temperature::double precision AS dew_point_temp,
+                        relative_humidity AS rel_humidity,
+                        absolute_humidity::double precision AS abs_humidity,
+                        pressure AS abs_pressure,
+                        acceleration_x AS acc_x, acceleration_y AS acc_y, acceleration_z AS acc_z,
+                        battery_voltage, movement_counter,
+                        NULL::real AS pm1_0, NULL::real AS pm2_5, NULL::real AS pm4_0, NULL::real AS pm10_0,
+                        NULL::smallint AS co2, NULL::smallint AS voc_index, NULL::smallint AS nox_index,
+                        NULL::real AS luminosity,
+                        measurement_sequence AS measurement_seq, tx_power, rssi
+                    FROM tag_readings
+                    WHERE mac_address = $1 AND recorded_at >= $2 AND recorded_at < $3
+                    UNION ALL
+                    SELECT
+                        id,
+                        -- Only format 6 readings lack PM1.0
+                        CASE WHEN pm1_0 IS NULL THEN 'v6' ELSE 'e1' END AS format,
+                        recorded_at AS timestamp,
+                        temperature AS temp,
+                        dew_point_temperature AS dew_point_temp,
+                        relative_humidity AS rel_humidity,
+                        absolute_humidity AS abs_humidity,
+                        pressure AS abs_pressure,
+                        NULL::smallint AS acc_x, NULL::smallint AS acc_y, NULL::smallint AS acc_z,
+                        NULL::real AS battery_voltage, NULL::smallint AS movement_counter,
+                        pm1_0, pm2_5, pm4_0, pm10_0,
+                        co2, voc_index, nox_index,
+                        luminosity,
+                        measurement_sequence AS measurement_seq, tx_power, rssi
+                    FROM air_readings
+                    WHERE mac_address = $1 AND recorded_at >= $2 AND recorded_at < $3
+                ) readings
+                WHERE $4::timestamptz IS NULL OR (timestamp, format, id) > ($4, $5, $6)
+                ORDER BY timestamp, format, id
+                LIMIT $7
+                "#,
+            )
+            .bind(MacAddress::new(mac))
+            .bind(from)
+            .bind(to)
+            .bind(after.map(|c| c.timestamp))
+            .bind(after.map(|c| c.format.as_str()).unwrap_or(""))
+            .bind(after.map(|c| c.id).unwrap_or(0))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn history_buckets<'a>(
+        &'a self,
+        mac: [u8; 6],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step_secs: u32,
+    ) -> BoxFuture<'a, Result<Vec<HistoryBucket>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Postgres, HistoryBucket>(
+                r#"
+                SELECT
+                    date_bin(make_interval(secs => $4), recorded_at, $2) AS timestamp,
+                    COUNT(*) AS samples,
+                    AVG(temperature)::double precision AS temp,
+                    AVG(dew_point_temperature)::double precision AS dew_point_temp,
+                    AVG(relative_humidity)::double precision AS rel_humidity,
+                    AVG(absolute_humidity)::double precision AS abs_humidity,
+                    AVG(pressure)::double precision AS abs_pressure,
+                    AVG(battery_voltage)::double precision AS battery_voltage,
+                    AVG(pm1_0)::double precision AS pm1_0,
+                    AVG(pm2_5)::double precision AS pm2_5,
+                    AVG(pm4_0)::double precision AS pm4_0,
+                    AVG(pm10_0)::double precision AS pm10_0,
+                    AVG(co2)::double precision AS co2,
+                    AVG(voc_index)::double precision AS voc_index,
+                    AVG(nox_index)::double precision AS nox_index,
+                    AVG(luminosity)::double precision AS luminosity
+                FROM (
+                    SELECT
+                        recorded_at, temperature,
+                        dew_point_temperature::double precision AS dew_point_temperature,
+                        relative_humidity,
+                        absolute_humidity::double precision AS absolute_humidity,
+                        pressure, battery_voltage,
+                        NULL::real AS pm1_0, NULL::real AS pm2_5, NULL::real AS pm4_0, NULL::real AS pm10_0,
+                        NULL::smallint AS co2, NULL::smallint AS voc_index, NULL::smallint AS nox_index,
+                        NULL::real AS luminosity
+                    FROM tag_readings
+                    WHERE mac_address = $1 AND recorded_at >= $2 AND recorded_at < $3
+                    UNION ALL
+                    SELECT
+                        recorded_at, temperature, dew_point_temperature, relative_humidity,
+                        absolute_humidity, pressure, NULL::real AS battery_voltage,
+                        pm1_0, pm2_5, pm4_0, pm10_0, co2, voc_index, nox_index, luminosity
+                    FROM air_readings
+                    WHERE mac_address = $1 AND recorded_at >= $2 AND recorded_at < $3
+                ) readings
+                GROUP BY 1
+                ORDER BY 1
+                "#,
+            )
+            .bind(MacAddress::new(mac))
+            .bind(from)
+            .bind(to)
+            .bind(f64::from(step_secs))
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn prune<'a>(
+        &'a self,
+        filter: &'a RowFilter,
+        dry_run: bool,
+    ) -> BoxFuture<'a, Result<Vec<(&'static str, u64)>, anyhow::Error>> {
+        Box::pin(async move {
+            let condition = "WHERE ($1::macaddr IS NULL OR mac_address = $1) \
+                AND ($2::timestamptz IS NULL OR recorded_at >= $2) \
+                AND ($3::timestamptz IS NULL OR recorded_at < $3)";
+            let mut tx = self.pool.begin().await?;
+            let mut counts = Vec::new();
+            for table in TABLES {
+                let rows = if dry_run {
+                    let (count,) = sqlx::query_as::<Postgres, (i64,)>(&format!(
+                        "SELECT COUNT(*) FROM {table} {condition}"
+                    ))
+                    .bind(filter.mac.map(MacAddress::new))
+                    .bind(filter.from)
+                    .bind(filter.to)
+                    .fetch_one(&mut *tx)
+                    .await?;
+                    count as u64
+                } else {
+                    sqlx::query::<Postgres>(&format!("DELETE FROM {table} {condition}"))
+                        .bind(filter.mac.map(MacAddress::new))
+                        .bind(filter.from)
+                        .bind(filter.to)
+                        .execute(&mut *tx)
+                        .await?
+                        .rows_affected()
+                };
+                counts.push((table, rows));
+            }
+            tx.commit().await?;
+            Ok(counts)
+        })
+    }
+
+    fn expire<'a>(
+        &'a self,
+        table: &'a str,
+        before: DateTime<Utc>,
+        dry_run: bool,
+    ) -> BoxFuture<'a, Result<u64, anyhow::Error>> {
+        Box::pin(async move {
+            if !RETAINED_TABLES.contains(&table) {
+                anyhow::bail!("Can't expire rows of {table}");
+            }
+            if dry_run {
+                let (count,) = sqlx::query_as::<Postgres, (i64,)>(&format!(
+                    "SELECT COUNT(*) FROM {table} WHERE recorded_at < $1"
+                ))
+                .bind(before)
+                .fetch_one(&self.pool)
+                .await?;
+                return Ok(count as u64);
+            }
+            let mut deleted = 0;
+            loop {
+                let rows = sqlx::query::<Postgres>(&format!(
+                    "DELETE FROM {table} WHERE id IN \
+                     (SELECT id FROM {table} WHERE recorded_at < $1 LIMIT $2)"
+                ))
+                .bind(before)
+                .bind(EXPIRE_BATCH)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+                deleted += rows;
+                if rows < EXPIRE_BATCH as u64 {
+                    return Ok(deleted);
+                }
+            }
+        })
+    }
+
+    fn latest_rollup<'a>(
+        &'a self,
+        rollup: &'a Rollup,
+        resolution_secs: u32,
+    ) -> BoxFuture<'a, Result<Option<DateTime<Utc>>, anyhow::Error>> {
+        Box::pin(async move {
+            let latest = sqlx::query_scalar::<Postgres, Option<DateTime<Utc>>>(&format!(
+                "SELECT MAX(bucket) FROM {} WHERE resolution_secs = $1",
+                rollup.table
+            ))
+            .bind(resolution_secs as i32)
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(latest)
+        })
+    }
+
+    fn rollup<'a>(
+        &'a self,
+        rollup: &'a Rollup,
+        resolution_secs: u32,
+        from: Option<DateTime<Utc>>,
+        to: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<u64, anyhow::Error>> {
+        Box::pin(async move {
+            let query = rollup.upsert(
+                "date_bin(make_interval(secs => $1), recorded_at, 'epoch'::timestamptz)",
+                ["$1::integer", "$2::timestamptz", "$3"],
+                "::double precision",
+                "EXCLUDED",
+            );
+            let result = sqlx::query::<Postgres>(&query)
+                .bind(resolution_secs as i32)
+                .bind(from)
+                .bind(to)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected())
+        })
+    }
+
+    fn reindex<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Postgres>(&format!("REINDEX TABLE {table}"))
+                .execute(&self.pool)
+                .await?;
+            sqlx::query::<Postgres>(&format!("ANALYZE {table}"))
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn visit_derived<'a>(
+        &'a self,
+        table: &'a str,
+        filter: &'a RowFilter,
+        visit: &'a mut (dyn FnMut(DerivedRow) + Send),
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let query = format!(
+                "SELECT id, recorded_at, mac_address, temperature, relative_humidity, pressure, \
+                    absolute_humidity::double precision, dew_point_temperature::double precision \
+                FROM {table} \
+                WHERE ($1::macaddr IS NULL OR mac_address = $1) \
+                    AND ($2::timestamptz IS NULL OR recorded_at >= $2) \
+                    AND ($3::timestamptz IS NULL OR recorded_at < $3) \
+                ORDER BY id"
+            );
+            let mut rows = sqlx::query_as::<Postgres, DerivedRow>(&query)
+                .bind(filter.mac.map(MacAddress::new))
+                .bind(filter.from)
+                .bind(filter.to)
+                .fetch(&self.pool);
+            while let Some(row) = rows.try_next().await? {
+                visit(row);
+            }
+            Ok(())
+        })
+    }
+
+    fn raw_readings<'a>(
+        &'a self,
+        table: &'a str,
+        filter: &'a RowFilter,
+        after: i32,
+        limit: u32,
+    ) -> BoxFuture<'a, Result<Vec<RawRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let query = format!(
+                "SELECT id, recorded_at, mac_address, raw_payload, temperature, relative_humidity, \
+                    pressure \
+                FROM {table} \
+                WHERE id > $1 \
+                    AND ($2::macaddr IS NULL OR mac_address = $2) \
+                    AND ($3::timestamptz IS NULL OR recorded_at >= $3) \
+                    AND ($4::timestamptz IS NULL OR recorded_at < $4) \
+                ORDER BY id \
+                LIMIT $5"
+            );
+            let rows = sqlx::query_as::<Postgres, RawRow>(&query)
+                .bind(after)
+                .bind(filter.mac.map(MacAddress::new))
+                .bind(filter.from)
+                .bind(filter.to)
+                .bind(i64::from(limit))
+                .fetch_all(&self.pool)
+                .await?;
+            Ok(rows)
+        })
+    }
+
+    fn update_readings<'a>(
+        &'a self,
+        table: &'a str,
+        updates: &'a [(i32, Reprocessed)],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let derived = format!(
+                "UPDATE {table} SET absolute_humidity = $2, dew_point_temperature = $3, \
+                    humidity_formula = $4 WHERE id = $1"
+            );
+            let mut tx = self.pool.begin().await?;
+            for (id, reprocessed) in updates {
+                let data = match reprocessed {
+                    Reprocessed::Derived {
+                        abs_humidity,
+                        dew_point,
+                        humidity_formula,
+                    } => {
+                        sqlx::query::<Postgres>(&derived)
+                            .bind(id)
+                            .bind(abs_humidity)
+                            .bind(dew_point)
+                            .bind(humidity_formula.as_str())
+                            .execute(&mut *tx)
+                            .await?;
+                        continue;
+                    }
+                    Reprocessed::Decoded(data) => data,
+                };
+                if data.table() != table {
+                    anyhow::bail!("Reading {id} decoded into a format of {}", data.table());
+                }
+                let query = match data {
+                    Ruuvi::V2(data) => {
+                        let comfort = data.comfort();
+                        sqlx::query::<Postgres>(
+                            r#"
+                        UPDATE tag_readings SET
+                            temperature = $2,
+                            relative_humidity = $3,
+                            pressure = $4,
+                            acceleration_x = $5,
+                            acceleration_y = $6,
+                            acceleration_z = $7,
+                            battery_voltage = $8,
+                            tx_power = $9,
+                            movement_counter = $10,
+                            absolute_humidity = $11,
+                            dew_point_temperature = $12,
+                            humidity_formula = $13,
+                            heat_index = $14,
+                            humidex = $15,
+                            wet_bulb_temperature = $16,
+                            vapour_pressure_deficit = $17,
+                            uncalibrated_temperature = $18,
+                            uncalibrated_relative_humidity = $19
+                        WHERE id = $1
+                        "#,
+                        )
+                        .bind(id)
+                        .bind(data.temp)
+                        .bind(data.rel_humidity)
+                        .bind(data.abs_pressure.map(|p| p as i32))
+                        .bind(data.acc_x)
+                        .bind(data.acc_y)
+                        .bind(data.acc_z)
+                        .bind(data.battery_voltage)
+                        .bind(data.tx_power.map(i16::from))
+                        .bind(data.movement_counter.map(i16::from))
+                        .bind(data.abs_humidity.map(|h| h as f32))
+                        .bind(data.dew_point_temp.map(|t| t as f32))
+                        .bind(data.humidity_formula.as_str())
+                        .bind(comfort.map(|c| c.heat_index as f32))
+                        .bind(comfort.map(|c| c.humidex as f32))
+                        .bind(comfort.map(|c| c.wet_bulb_temp as f32))
+                        .bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+                        .bind(data.uncalibrated.and_then(|u| u.temp))
+                        .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    }
+                    Ruuvi::V1(data) => {
+                        let comfort = data.comfort();
+                        sqlx::query::<Postgres>(
+                            r#"
+                        UPDATE tag_readings SET
+                            temperature = $2,
+                            relative_humidity = $3,
+                            pressure = $4,
+                            acceleration_x = $5,
+                            acceleration_y = $6,
+                            acceleration_z = $7,
+                            battery_voltage = $8,
+                            absolute_humidity = $9,
+                            dew_point_temperature = $10,
+                            humidity_formula = $11,
+                            heat_index = $12,
+                            humidex = $13,
+                            wet_bulb_temperature = $14,
+                            vapour_pressure_deficit = $15,
+                            uncalibrated_temperature = $16,
+                            uncalibrated_relative_humidity = $17
+                        WHERE id = $1
+                        "#,
+                        )
+                        .bind(id)
+                        .bind(data.temp)
+                        .bind(data.rel_humidity)
+                        .bind(data.abs_pressure.map(|p| p as i32))
+                        .bind(data.acc_x)
+                        .bind(data.acc_y)
+                        .bind(data.acc_z)
+                        .bind(data.battery_voltage)
+                        .bind(data.abs_humidity.map(|h| h as f32))
+                        .bind(data.dew_point_temp.map(|t| t as f32))
+                        .bind(data.humidity_formula.as_str())
+                        .bind(comfort.map(|c| c.heat_index as f32))
+                        .bind(comfort.map(|c| c.humidex as f32))
+                        .bind(comfort.map(|c| c.wet_bulb_temp as f32))
+                        .bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+                        .bind(data.uncalibrated.and_then(|u| u.temp))
+                        .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    }
+                    Ruuvi::E1(data) => sqlx::query::<Postgres>(
+                        r#"
+                        UPDATE air_readings SET
+                            temperature = $2,
+                            dew_point_temperature = $3,
+                            relative_humidity = $4,
+                            absolute_humidity = $5,
+                            pressure = $6,
+                            pm1_0 = $7,
+                            pm2_5 = $8,
+                            pm4_0 = $9,
+                            pm10_0 = $10,
+                            co2 = $11,
+                            voc_index = $12,
+                            nox_index = $13,
+                            luminosity = $14,
+                            flags = $15,
+                            humidity_formula = $16,
+                            air_quality_index = $17,
+                            uncalibrated_temperature = $18,
+                            uncalibrated_relative_humidity = $19,
+                            uncalibrated_co2 = $20
+                        WHERE id = $1
+                        "#,
+                    )
+                    .bind(id)
+                    .bind(data.temp)
+                    .bind(data.dew_point_temp)
+                    .bind(data.rel_humidity)
+                    .bind(data.abs_humidity)
+                    .bind(data.abs_pressure.map(|p| p as i32))
+                    .bind(data.pm1_0)
+                    .bind(data.pm2_5)
+                    .bind(data.pm4_0)
+                    .bind(data.pm10_0)
+                    .bind(data.co2.map(|v| v as i16))
+                    .bind(data.voc_index.map(|v| v as i16))
+                    .bind(data.nox_index.map(|v| v as i16))
+                    .bind(data.luminosity)
+                    .bind(data.flags as i16)
+                    .bind(data.humidity_formula.as_str())
+                    .bind(data.air_quality_index().map(|v| v as f32))
+                    .bind(data.uncalibrated.and_then(|u| u.temp))
+                    .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    .bind(data.uncalibrated.and_then(|u| u.co2).map(|v| v as i16)),
+                    Ruuvi::V6(data) => sqlx::query::<Postgres>(
+                        r#"
+                        UPDATE air_readings SET
+                            temperature = $2,
+                            dew_point_temperature = $3,
+                            relative_humidity = $4,
+                            absolute_humidity = $5,
+                            pressure = $6,
+                            pm2_5 = $7,
+                            co2 = $8,
+                            voc_index = $9,
+                            nox_index = $10,
+                            luminosity = $11,
+                            flags = $12,
+                            humidity_formula = $13,
+                            air_quality_index = $14,
+                            uncalibrated_temperature = $15,
+                            uncalibrated_relative_humidity = $16,
+                            uncalibrated_co2 = $17
+                        WHERE id = $1
+                        "#,
+                    )
+                    .bind(id)
+                    .bind(data.temp)
+                    .bind(data.dew_point_temp)
+                    .bind(data.rel_humidity)
+                    .bind(data.abs_humidity)
+                    .bind(data.abs_pressure.map(|p| p as i32))
+                    .bind(data.pm2_5)
+                    .bind(data.co2.map(|v| v as i16))
+                    .bind(data.voc_index.map(|v| v as i16))
+                    .bind(data.nox_index.map(|v| v as i16))
+                    .bind(data.luminosity)
+                    .bind(data.flags as i16)
+                    .bind(data.humidity_formula.as_str())
+                    .bind(data.air_quality_index().map(|v| v as f32))
+                    .bind(data.uncalibrated.and_then(|u| u.temp))
+                    .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    .bind(data.uncalibrated.and_then(|u| u.co2).map(|v| v as i16)),
+                };
+                query.execute(&mut *tx).await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+}
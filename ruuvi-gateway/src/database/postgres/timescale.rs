@@ -0,0 +1,165 @@
+//! TimescaleDB on top of the Postgres storage. The readings tables become
+//! hypertables partitioned by `recorded_at`, and readings are inserted in
+//! batches ordered by time so a batch lands in as few chunks as possible.
+
+use super::insert_readings;
+use crate::config::TimescaleConfig;
+use crate::sink::StoredReading;
+use anyhow::Context;
+use sqlx::{Pool, Postgres};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+const HYPERTABLES: [&str; 2] = ["tag_readings", "air_readings"];
+/// Keeps a batch of air readings, 19 columns each, well within the 65535 bind parameters
+const MAX_BATCH: usize = 1000;
+
+/// Turn the readings tables into hypertables and apply the chunk interval and
+/// compression policy. Safe to run on every startup, the settings are
+/// reapplied so config changes take effect.
+pub async fn setup(pool: &Pool<Postgres>, config: &TimescaleConfig) -> Result<(), anyhow::Error> {
+    sqlx::query("CREATE EXTENSION IF NOT EXISTS timescaledb")
+        .execute(pool)
+        .await
+        .context("TimescaleDB isn't installed on the database server")?;
+    let chunk_days = config.chunk_days.max(1) as i32;
+
+    for table in HYPERTABLES {
+        // None while it's still a plain table
+        let compression: Option<bool> = sqlx::query_scalar(
+            r#"
+            SELECT compression_enabled
+            FROM timescaledb_information.hypertables
+            WHERE hypertable_name = $1
+            "#,
+        )
+        .bind(table)
+        .fetch_optional(pool)
+        .await?;
+
+        if compression.is_none() {
+            tracing::info!("Converting {table} into a hypertable, existing rows move into chunks");
+            let mut tx = pool.begin().await?;
+            // Unique indexes of a hypertable have to include the partitioning column
+            sqlx::query(&format!(
+                "ALTER TABLE {table} DROP CONSTRAINT {table}_pkey, ADD PRIMARY KEY (id, recorded_at)"
+            ))
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(
+                r#"
+                SELECT create_hypertable(
+                    $1::regclass,
+                    'recorded_at',
+                    chunk_time_interval => make_interval(days => $2),
+                    migrate_data => true
+                )
+                "#,
+            )
+            .bind(table)
+            .bind(chunk_days)
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        } else {
+            // Applies to chunks created from now on
+            sqlx::query("SELECT set_chunk_time_interval($1::regclass, make_interval(days => $2))")
+                .bind(table)
+                .bind(chunk_days)
+                .execute(pool)
+                .await?;
+        }
+
+        sqlx::query("SELECT remove_compression_policy($1::regclass, if_exists => true)")
+            .bind(table)
+            .execute(pool)
+            .await?;
+        if config.compress_after_days == 0 {
+            continue;
+        }
+        if compression != Some(true) {
+            // Queries are per tag and time range, so are the compressed segments
+            sqlx::query(&format!(
+                r#"
+                ALTER TABLE {table} SET (
+                    timescaledb.compress,
+                    timescaledb.compress_segmentby = 'mac_address',
+                    timescaledb.compress_orderby = 'recorded_at'
+                )
+                "#
+            ))
+            .execute(pool)
+            .await?;
+        }
+        sqlx::query("SELECT add_compression_policy($1::regclass, make_interval(days => $2))")
+            .bind(table)
+            .bind(config.compress_after_days as i32)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+type Reply = oneshot::Sender<Result<(), String>>;
+
+/// Collects concurrently stored readings into one insert, each caller waits
+/// for the outcome of its batch
+pub struct Batcher {
+    readings: mpsc::Sender<(StoredReading, Reply)>,
+}
+
+impl Batcher {
+    pub fn spawn(pool: Pool<Postgres>, config: &TimescaleConfig) -> Self {
+        let batch_size = config.batch_size.clamp(1, MAX_BATCH);
+        let (tx, rx) = mpsc::channel(batch_size);
+        tokio::spawn(run(
+            pool,
+            rx,
+            batch_size,
+            Duration::from_millis(config.batch_ms),
+        ));
+        Self { readings: tx }
+    }
+
+    pub async fn insert(&self, reading: StoredReading) -> Result<(), anyhow::Error> {
+        let (tx, rx) = oneshot::channel();
+        self.readings
+            .send((reading, tx))
+            .await
+            .map_err(|_| anyhow::anyhow!("Insert batcher stopped"))?;
+        rx.await
+            .context("Insert batcher stopped")?
+            .map_err(anyhow::Error::msg)
+    }
+}
+
+async fn run(
+    pool: Pool<Postgres>,
+    mut rx: mpsc::Receiver<(StoredReading, Reply)>,
+    batch_size: usize,
+    flush: Duration,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    while let Some(reading) = rx.recv().await {
+        batch.push(reading);
+        let deadline = Instant::now() + flush;
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(reading)) => batch.push(reading),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        batch.sort_by_key(|(reading, _)| reading.data.timestamp());
+        let (readings, replies): (Vec<_>, Vec<_>) = batch.drain(..).unzip();
+        let result = insert_readings(&pool, &readings)
+            .await
+            .map_err(|e| e.to_string());
+        if readings.len() > 1 {
+            tracing::trace!("Inserted a batch of {} readings", readings.len());
+        }
+        for reply in replies {
+            let _ = reply.send(result.clone());
+        }
+    }
+}
@@ -0,0 +1,1866 @@
+use super::{
+    AlertRuleRow, BatterySample, CoverageRow, DailyRow, DerivedRow, DoorEventRow, EXPIRE_BATCH,
+    HistoryBucket, HistoryCursor, HistoryRow, ListenerRow, ListenerStatusRow, Mac, RETAINED_TABLES,
+    RawRow, RegistryRow, Reprocessed, Rollup, RowFilter, Storage, TABLES, TagRow, TagSummaryRow,
+    reset_reason,
+};
+use crate::dedup::Reception;
+use crate::door::DoorTransition;
+use crate::mac::parse_mac;
+use crate::quality::QualitySnapshot;
+use crate::{Ruuvi, RuuviE1, RuuviV1, RuuviV2, RuuviV6};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures_util::TryStreamExt;
+use futures_util::future::BoxFuture;
+use ruuvi_schema::TagModel;
+use ruuvi_schema::protocol::Telemetry;
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteTypeInfo, SqliteValueRef,
+};
+use sqlx::{Database, Decode, Encode, Pool, Sqlite, Type};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Daily summaries are aggregated in SQL over buckets this long and merged into
+/// local days in Rust, SQLite knows no time zones. Every UTC offset is a
+/// multiple of 15 minutes, so a bucket never straddles local midnight.
+const DAILY_BUCKET_SECS: i64 = 15 * 60;
+
+impl Type<Sqlite> for Mac {
+    fn type_info() -> SqliteTypeInfo {
+        <str as Type<Sqlite>>::type_info()
+    }
+
+    fn compatible(ty: &SqliteTypeInfo) -> bool {
+        <str as Type<Sqlite>>::compatible(ty)
+    }
+}
+
+impl<'q> Encode<'q, Sqlite> for Mac {
+    fn encode_by_ref(
+        &self,
+        buf: &mut <Sqlite as Database>::ArgumentBuffer<'q>,
+    ) -> Result<IsNull, BoxDynError> {
+        let [a, b, c, d, e, f] = self.0;
+        let text = format!("{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}");
+        <String as Encode<Sqlite>>::encode(text, buf)
+    }
+}
+
+impl<'r> Decode<'r, Sqlite> for Mac {
+    fn decode(value: SqliteValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Self(parse_mac(<&str as Decode<Sqlite>>::decode(value)?)?))
+    }
+}
+
+/// SQLite database for small deployments, timestamps are stored as RFC 3339
+/// text so they sort and compare like the Postgres columns
+pub struct SqliteStorage {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteStorage {
+    pub async fn connect(uri: &str, pool_size: u32) -> Result<Self, anyhow::Error> {
+        // WAL lets the API read while a reading is written
+        let options = SqliteConnectOptions::from_str(uri)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new()
+            .max_connections(pool_size)
+            .connect_with(options)
+            .await?;
+        Ok(Self { pool })
+    }
+}
+
+/// Per bucket aggregates of [`DAILY_BUCKET_SECS`]
+type DailyBucket = (
+    i64,
+    Option<f32>,
+    Option<f32>,
+    Option<f64>,
+    i64,
+    Option<f32>,
+    Option<f32>,
+    Option<f64>,
+    i64,
+    i64,
+);
+
+impl Storage for SqliteStorage {
+    fn migrate<'a>(&'a self) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            MIGRATOR.run(&self.pool).await?;
+            Ok(())
+        })
+    }
+
+    fn insert_data_v2<'a>(
+        &'a self,
+        data: RuuviV2,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let comfort = data.comfort();
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO tag_readings (
+                    recorded_at,
+                    mac_address,
+                    temperature,
+                    relative_humidity,
+                    pressure,
+                    acceleration_x,
+                    acceleration_y,
+                    acceleration_z,
+                    battery_voltage,
+                    tx_power,
+                    movement_counter,
+                    measurement_sequence,
+                    absolute_humidity,
+                    dew_point_temperature,
+                    rssi,
+                    listener_id,
+                    raw_payload,
+                    humidity_formula,
+                    heat_index,
+                    humidex,
+                    wet_bulb_temperature,
+                    vapour_pressure_deficit,
+                    uncalibrated_temperature,
+                    uncalibrated_relative_humidity,
+                    movement_event
+                ) VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                    (SELECT id FROM listeners WHERE name = ?16),
+                    ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25
+                )
+                "#,
+            )
+            .bind(data.timestamp)
+            .bind(Mac(data.mac))
+            .bind(data.temp)
+            .bind(data.rel_humidity)
+            .bind(data.abs_pressure)
+            .bind(data.acc_x)
+            .bind(data.acc_y)
+            .bind(data.acc_z)
+            .bind(data.battery_voltage)
+            .bind(data.tx_power)
+            .bind(data.movement_counter)
+            .bind(data.measurement_seq)
+            .bind(data.abs_humidity.map(|h| h as f32))
+            .bind(data.dew_point_temp.map(|t| t as f32))
+            .bind(data.rssi)
+            .bind(listener)
+            .bind(raw_payload)
+            .bind(data.humidity_formula.as_str())
+            .bind(comfort.map(|c| c.heat_index as f32))
+            .bind(comfort.map(|c| c.humidex as f32))
+            .bind(comfort.map(|c| c.wet_bulb_temp as f32))
+            .bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+            .bind(data.uncalibrated.and_then(|u| u.temp))
+            .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+            .bind(data.movement_event)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn insert_data_v1<'a>(
+        &'a self,
+        data: RuuviV1,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let comfort = data.comfort();
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO tag_readings (
+                    recorded_at,
+                    mac_address,
+                    temperature,
+                    relative_humidity,
+                    pressure,
+                    acceleration_x,
+                    acceleration_y,
+                    acceleration_z,
+                    battery_voltage,
+                    absolute_humidity,
+                    dew_point_temperature,
+                    rssi,
+                    listener_id,
+                    raw_payload,
+                    humidity_formula,
+                    heat_index,
+                    humidex,
+                    wet_bulb_temperature,
+                    vapour_pressure_deficit,
+                    uncalibrated_temperature,
+                    uncalibrated_relative_humidity
+                ) VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12,
+                    (SELECT id FROM listeners WHERE name = ?13),
+                    ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21
+                )
+                "#,
+            )
+            .bind(data.timestamp)
+            .bind(Mac(data.mac))
+            .bind(data.temp)
+            .bind(data.rel_humidity)
+            .bind(data.abs_pressure)
+            .bind(data.acc_x)
+            .bind(data.acc_y)
+            .bind(data.acc_z)
+            .bind(data.battery_voltage)
+            .bind(data.abs_humidity.map(|h| h as f32))
+            .bind(data.dew_point_temp.map(|t| t as f32))
+            .bind(data.rssi)
+            .bind(listener)
+            .bind(raw_payload)
+            .bind(data.humidity_formula.as_str())
+            .bind(comfort.map(|c| c.heat_index as f32))
+            .bind(comfort.map(|c| c.humidex as f32))
+            .bind(comfort.map(|c| c.wet_bulb_temp as f32))
+            .bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+            .bind(data.uncalibrated.and_then(|u| u.temp))
+            .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn insert_data_e1<'a>(
+        &'a self,
+        data: RuuviE1,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO air_readings (
+                    recorded_at,
+                    mac_address,
+                    temperature,
+                    dew_point_temperature,
+                    relative_humidity,
+                    absolute_humidity,
+                    pressure,
+                    pm1_0,
+                    pm2_5,
+                    pm4_0,
+                    pm10_0,
+                    co2,
+                    voc_index,
+                    nox_index,
+                    luminosity,
+                    measurement_sequence,
+                    flags,
+                    tx_power,
+                    rssi,
+                    listener_id,
+                    raw_payload,
+                    humidity_formula,
+                    air_quality_index,
+                    uncalibrated_temperature,
+                    uncalibrated_relative_humidity,
+                    uncalibrated_co2
+                ) VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10,
+                    ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19,
+                    (SELECT id FROM listeners WHERE name = ?20),
+                    ?21, ?22, ?23, ?24, ?25, ?26
+                )
+                "#,
+            )
+            .bind(data.timestamp)
+            .bind(Mac(data.mac))
+            .bind(data.temp)
+            .bind(data.dew_point_temp)
+            .bind(data.rel_humidity)
+            .bind(data.abs_humidity)
+            .bind(data.abs_pressure)
+            .bind(data.pm1_0)
+            .bind(data.pm2_5)
+            .bind(data.pm4_0)
+            .bind(data.pm10_0)
+            .bind(data.co2)
+            .bind(data.voc_index)
+            .bind(data.nox_index)
+            .bind(data.luminosity)
+            .bind(data.measurement_seq)
+            .bind(data.flags)
+            .bind(data.tx_power)
+            .bind(data.rssi)
+            .bind(listener)
+            .bind(raw_payload)
+            .bind(data.humidity_formula.as_str())
+            .bind(data.air_quality_index().map(|v| v as f32))
+            .bind(data.uncalibrated.and_then(|u| u.temp))
+            .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+            .bind(data.uncalibrated.and_then(|u| u.co2))
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn insert_data_v6<'a>(
+        &'a self,
+        data: RuuviV6,
+        listener: &'a str,
+        raw_payload: Option<&'a [u8]>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO air_readings (
+                    recorded_at,
+                    mac_address,
+                    temperature,
+                    dew_point_temperature,
+                    relative_humidity,
+                    absolute_humidity,
+                    pressure,
+                    pm2_5,
+                    co2,
+                    voc_index,
+                    nox_index,
+                    luminosity,
+                    measurement_sequence,
+                    flags,
+                    rssi,
+                    listener_id,
+                    raw_payload,
+                    humidity_formula,
+                    air_quality_index,
+                    uncalibrated_temperature,
+                    uncalibrated_relative_humidity,
+                    uncalibrated_co2
+                ) VALUES (
+                    ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15,
+                    (SELECT id FROM listeners WHERE name = ?16),
+                    ?17, ?18, ?19, ?20, ?21, ?22
+                )
+                "#,
+            )
+            .bind(data.timestamp)
+            .bind(Mac(data.mac))
+            .bind(data.temp)
+            .bind(data.dew_point_temp)
+            .bind(data.rel_humidity)
+            .bind(data.abs_humidity)
+            .bind(data.abs_pressure)
+            .bind(data.pm2_5)
+            .bind(data.co2)
+            .bind(data.voc_index)
+            .bind(data.nox_index)
+            .bind(data.luminosity)
+            .bind(data.measurement_seq)
+            .bind(data.flags)
+            .bind(data.rssi)
+            .bind(listener)
+            .bind(raw_payload)
+            .bind(data.humidity_formula.as_str())
+            .bind(data.air_quality_index().map(|v| v as f32))
+            .bind(data.uncalibrated.and_then(|u| u.temp))
+            .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+            .bind(data.uncalibrated.and_then(|u| u.co2))
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn upsert_listener<'a>(
+        &'a self,
+        listener: &'a ListenerRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO listeners (name, identified, address, psk, firmware, last_seen)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT (name) DO UPDATE
+                SET identified = excluded.identified,
+                    address = excluded.address,
+                    psk = excluded.psk,
+                    firmware = excluded.firmware,
+                    last_seen = excluded.last_seen
+                "#,
+            )
+            .bind(&listener.name)
+            .bind(listener.identified)
+            .bind(listener.address.to_string())
+            .bind(&listener.psk)
+            .bind(&listener.firmware)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn upsert_listener_status<'a>(
+        &'a self,
+        listener: &'a str,
+        telemetry: Telemetry,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO listener_status (
+                    listener_id,
+                    reported_at,
+                    uptime_secs,
+                    heap_free,
+                    wifi_rssi,
+                    adverts,
+                    parse_failures,
+                    dropped,
+                    queue_overflows,
+                    frames_sent,
+                    reconnects,
+                    reset_reason
+                )
+                SELECT id, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12
+                FROM listeners WHERE name = ?1
+                ON CONFLICT (listener_id) DO UPDATE
+                SET reported_at = excluded.reported_at,
+                    uptime_secs = excluded.uptime_secs,
+                    heap_free = excluded.heap_free,
+                    wifi_rssi = excluded.wifi_rssi,
+                    adverts = excluded.adverts,
+                    parse_failures = excluded.parse_failures,
+                    dropped = excluded.dropped,
+                    queue_overflows = excluded.queue_overflows,
+                    frames_sent = excluded.frames_sent,
+                    reconnects = excluded.reconnects,
+                    reset_reason = excluded.reset_reason
+                "#,
+            )
+            .bind(listener)
+            .bind(Utc::now())
+            .bind(i64::try_from(telemetry.uptime_secs)?)
+            .bind(i64::from(telemetry.heap_free))
+            .bind(telemetry.wifi_rssi.map(i16::from))
+            .bind(i64::from(telemetry.adverts))
+            .bind(i64::from(telemetry.parse_failures))
+            .bind(i64::from(telemetry.dropped))
+            .bind(i64::from(telemetry.queue_overflows))
+            .bind(i64::from(telemetry.frames_sent))
+            .bind(i64::from(telemetry.reconnects))
+            .bind(reset_reason(telemetry.reset_reason))
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn listener_statuses<'a>(
+        &'a self,
+    ) -> BoxFuture<'a, Result<Vec<ListenerStatusRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, ListenerStatusRow>(
+                r#"
+                SELECT
+                    l.name,
+                    s.reported_at,
+                    s.uptime_secs,
+                    s.reset_reason,
+                    s.heap_free,
+                    s.wifi_rssi,
+                    s.adverts,
+                    s.parse_failures,
+                    s.dropped,
+                    s.queue_overflows,
+                    s.frames_sent,
+                    s.reconnects
+                FROM listener_status s
+                JOIN listeners l ON l.id = s.listener_id
+                ORDER BY l.name
+                "#,
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn insert_door_event<'a>(
+        &'a self,
+        mac: [u8; 6],
+        timestamp: DateTime<Utc>,
+        transition: DoorTransition,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO door_events (
+                    recorded_at,
+                    mac_address,
+                    state,
+                    previous_state,
+                    acceleration
+                ) VALUES (?1, ?2, ?3, ?4, ?5)
+                "#,
+            )
+            .bind(timestamp)
+            .bind(Mac(mac))
+            .bind(transition.current.as_str())
+            .bind(transition.previous.map(|s| s.as_str()))
+            .bind(transition.acceleration)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn upsert_tag_model<'a>(
+        &'a self,
+        mac: [u8; 6],
+        model: TagModel,
+        timestamp: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO tag_models (mac_address, model, updated_at)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (mac_address) DO UPDATE
+                SET model = excluded.model, updated_at = excluded.updated_at
+                WHERE tag_models.model <> excluded.model
+                "#,
+            )
+            .bind(Mac(mac))
+            .bind(model.as_str())
+            .bind(timestamp)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn tags<'a>(&'a self) -> BoxFuture<'a, Result<Vec<TagRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, TagRow>(
+                r#"
+                SELECT
+                    mac_address,
+                    model,
+                    (
+                        SELECT MAX(recorded_at) FROM (
+                            SELECT recorded_at FROM tag_readings t
+                            WHERE t.mac_address = m.mac_address
+                            UNION ALL
+                            SELECT recorded_at FROM air_readings a
+                            WHERE a.mac_address = m.mac_address
+                        )
+                    ) AS last_seen
+                FROM tag_models m
+                ORDER BY mac_address
+                "#,
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn registered_tags<'a>(&'a self) -> BoxFuture<'a, Result<Vec<RegistryRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, RegistryRow>(
+                "SELECT mac_address, name, location, formats FROM tags ORDER BY name",
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn register_tag<'a>(
+        &'a self,
+        tag: &'a RegistryRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO tags (mac_address, name, location, formats, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (mac_address) DO UPDATE
+                SET name = excluded.name,
+                    location = excluded.location,
+                    formats = excluded.formats,
+                    updated_at = excluded.updated_at
+                "#,
+            )
+            .bind(Mac(tag.mac_address.bytes()))
+            .bind(&tag.name)
+            .bind(&tag.location)
+            .bind(&tag.formats)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn unregister_tag<'a>(&'a self, mac: [u8; 6]) -> BoxFuture<'a, Result<bool, anyhow::Error>> {
+        Box::pin(async move {
+            let result = sqlx::query::<Sqlite>("DELETE FROM tags WHERE mac_address = ?1")
+                .bind(Mac(mac))
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn alert_rules<'a>(&'a self) -> BoxFuture<'a, Result<Vec<AlertRuleRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, AlertRuleRow>(
+                "SELECT name, tag, metric, above, below, hysteresis, for_secs FROM alert_rules ORDER BY name",
+            )
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn upsert_alert_rule<'a>(
+        &'a self,
+        rule: &'a AlertRuleRow,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(
+                r#"
+                INSERT INTO alert_rules (name, tag, metric, above, below, hysteresis, for_secs, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT (name) DO UPDATE
+                SET tag = excluded.tag,
+                    metric = excluded.metric,
+                    above = excluded.above,
+                    below = excluded.below,
+                    hysteresis = excluded.hysteresis,
+                    for_secs = excluded.for_secs,
+                    updated_at = excluded.updated_at
+                "#,
+            )
+            .bind(&rule.name)
+            .bind(&rule.tag)
+            .bind(&rule.metric)
+            .bind(rule.above)
+            .bind(rule.below)
+            .bind(rule.hysteresis)
+            .bind(rule.for_secs)
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn delete_alert_rule<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> BoxFuture<'a, Result<bool, anyhow::Error>> {
+        Box::pin(async move {
+            let result = sqlx::query::<Sqlite>("DELETE FROM alert_rules WHERE name = ?1")
+                .bind(name)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected() > 0)
+        })
+    }
+
+    fn insert_tag_quality<'a>(
+        &'a self,
+        snapshots: &'a [QualitySnapshot],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            for snapshot in snapshots {
+                sqlx::query::<Sqlite>(
+                    r#"
+                    INSERT INTO tag_quality (
+                        recorded_at,
+                        mac_address,
+                        score,
+                        sensor,
+                        delivery,
+                        readings,
+                        lost,
+                        sentinels,
+                        clamped,
+                        missing_timestamps
+                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                    "#,
+                )
+                .bind(Utc::now())
+                .bind(Mac(snapshot.mac))
+                .bind(snapshot.score)
+                .bind(snapshot.sensor)
+                .bind(snapshot.delivery)
+                .bind(snapshot.readings as i64)
+                .bind(snapshot.lost as i64)
+                .bind(snapshot.sentinels as i64)
+                .bind(snapshot.clamped as i64)
+                .bind(snapshot.missing_timestamps as i64)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn insert_receptions<'a>(
+        &'a self,
+        mac: [u8; 6],
+        measurement_seq: u32,
+        timestamp: DateTime<Utc>,
+        primary: &'a str,
+        receptions: &'a [Reception],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut tx = self.pool.begin().await?;
+            for reception in receptions {
+                sqlx::query::<Sqlite>(
+                    r#"
+                    INSERT INTO receptions (
+                        recorded_at,
+                        mac_address,
+                        measurement_sequence,
+                        listener,
+                        rssi,
+                        is_primary
+                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                    "#,
+                )
+                .bind(timestamp)
+                .bind(Mac(mac))
+                .bind(measurement_seq)
+                .bind(&reception.listener)
+                .bind(reception.rssi)
+                .bind(reception.listener == primary)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn coverage<'a>(
+        &'a self,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<CoverageRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, CoverageRow>(
+                r#"
+                WITH per_tag AS (
+                    SELECT mac_address, COUNT(*) FILTER (WHERE is_primary) AS measurements
+                    FROM receptions
+                    WHERE recorded_at >= ?1
+                    GROUP BY mac_address
+                )
+                SELECT
+                    r.listener,
+                    r.mac_address,
+                    COUNT(*) AS packets,
+                    COUNT(*) FILTER (WHERE r.is_primary) AS primary_packets,
+                    per_tag.measurements,
+                    AVG(r.rssi) AS avg_rssi
+                FROM receptions r
+                JOIN per_tag USING (mac_address)
+                WHERE r.recorded_at >= ?1
+                GROUP BY r.listener, r.mac_address, per_tag.measurements
+                ORDER BY r.listener, r.mac_address
+                "#,
+            )
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn daily_summary<'a>(
+        &'a self,
+        mac: [u8; 6],
+        tz: Tz,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<DailyRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let buckets = sqlx::query_as::<Sqlite, DailyBucket>(
+                r#"
+                SELECT
+                    unixepoch(recorded_at) / ?3 * ?3 AS bucket,
+                    MIN(temperature),
+                    MAX(temperature),
+                    SUM(temperature),
+                    COUNT(temperature),
+                    MIN(relative_humidity),
+                    MAX(relative_humidity),
+                    SUM(relative_humidity),
+                    COUNT(relative_humidity),
+                    COUNT(*)
+                FROM (
+                    SELECT recorded_at, temperature, relative_humidity
+                    FROM tag_readings
+                    WHERE mac_address = ?1 AND recorded_at >= ?2
+                    UNION ALL
+                    SELECT recorded_at, temperature, relative_humidity
+                    FROM air_readings
+                    WHERE mac_address = ?1 AND recorded_at >= ?2
+                ) readings
+                GROUP BY bucket
+                ORDER BY bucket
+                "#,
+            )
+            .bind(Mac(mac))
+            .bind(since)
+            .bind(DAILY_BUCKET_SECS)
+            .fetch_all(&self.pool)
+            .await?;
+
+            // Sums and counts of temperature and humidity per day, averaged once complete
+            let mut days: BTreeMap<NaiveDate, (DailyRow, f64, i64, f64, i64)> = BTreeMap::new();
+            for (
+                start,
+                temp_min,
+                temp_max,
+                temp_sum,
+                temp_count,
+                humidity_min,
+                humidity_max,
+                humidity_sum,
+                humidity_count,
+                samples,
+            ) in buckets
+            {
+                let Some(start) = DateTime::from_timestamp(start, 0) else {
+                    continue;
+                };
+                let day = start.with_timezone(&tz).date_naive();
+                let (row, temps, temp_n, humidities, humidity_n) =
+                    days.entry(day).or_insert_with(|| {
+                        let row = DailyRow {
+                            day,
+                            temp_min: None,
+                            temp_max: None,
+                            temp_avg: None,
+                            humidity_min: None,
+                            humidity_max: None,
+                            humidity_avg: None,
+                            samples: 0,
+                        };
+                        (row, 0.0, 0, 0.0, 0)
+                    });
+                row.temp_min = min(row.temp_min, temp_min);
+                row.temp_max = max(row.temp_max, temp_max);
+                row.humidity_min = min(row.humidity_min, humidity_min);
+                row.humidity_max = max(row.humidity_max, humidity_max);
+                row.samples += samples;
+                *temps += temp_sum.unwrap_or(0.0);
+                *temp_n += temp_count;
+                *humidities += humidity_sum.unwrap_or(0.0);
+                *humidity_n += humidity_count;
+            }
+            Ok(days
+                .into_values()
+                .map(|(mut row, temps, temp_n, humidities, humidity_n)| {
+                    row.temp_avg = (temp_n > 0).then(|| temps / temp_n as f64);
+                    row.humidity_avg = (humidity_n > 0).then(|| humidities / humidity_n as f64);
+                    row
+                })
+                .collect())
+        })
+    }
+
+    fn battery_history<'a>(
+        &'a self,
+        mac: [u8; 6],
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<BatterySample>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, BatterySample>(
+                r#"
+                SELECT
+                    unixepoch(recorded_at) / 3600 * 3600 AS hour,
+                    AVG(battery_voltage),
+                    AVG(temperature)
+                FROM tag_readings
+                WHERE mac_address = ?1
+                    AND recorded_at >= ?2
+                    AND battery_voltage IS NOT NULL
+                    AND temperature IS NOT NULL
+                GROUP BY hour
+                ORDER BY hour
+                "#,
+            )
+            .bind(Mac(mac))
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn recent_tag_macs<'a>(
+        &'a self,
+        since: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<Vec<[u8; 6]>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, (Mac,)>(
+                "SELECT DISTINCT mac_address FROM tag_readings WHERE recorded_at >= ?1",
+            )
+            .bind(since)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows.into_iter().map(|(mac,)| mac.bytes()).collect())
+        })
+    }
+
+    fn door_events<'a>(
+        &'a self,
+        mac: Option<[u8; 6]>,
+        limit: i64,
+    ) -> BoxFuture<'a, Result<Vec<DoorEventRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, DoorEventRow>(
+                r#"
+                SELECT recorded_at, mac_address, state, previous_state
+                FROM door_events
+                WHERE ?1 IS NULL OR mac_address = ?1
+                ORDER BY recorded_at DESC
+                LIMIT ?2
+                "#,
+            )
+            .bind(mac.map(Mac))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn tag_summaries<'a>(
+        &'a self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        humidity_low: f32,
+        humidity_high: f32,
+        co2_limit: i16,
+    ) -> BoxFuture<'a, Result<Vec<TagSummaryRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, TagSummaryRow>(
+                r#"
+                WITH readings AS (
+                    SELECT recorded_at, mac_address, temperature, relative_humidity,
+                        NULL AS co2, battery_voltage
+                    FROM tag_readings
+                    WHERE recorded_at >= ?1 AND recorded_at < ?2
+                    UNION ALL
+                    SELECT recorded_at, mac_address, temperature, relative_humidity,
+                        co2, NULL AS battery_voltage
+                    FROM air_readings
+                    WHERE recorded_at >= ?1 AND recorded_at < ?2
+                ), ordered AS (
+                    SELECT *,
+                        LEAD(recorded_at) OVER (
+                            PARTITION BY mac_address ORDER BY recorded_at
+                        ) AS next_at
+                    FROM readings
+                ), spans AS (
+                    SELECT *,
+                        MIN(
+                            COALESCE((julianday(next_at) - julianday(recorded_at)) * 86400, 300),
+                            300
+                        ) AS secs
+                    FROM ordered
+                )
+                SELECT
+                    mac_address,
+                    MIN(temperature) AS temp_min,
+                    MAX(temperature) AS temp_max,
+                    AVG(temperature) AS temp_avg,
+                    SUM(secs) FILTER (
+                        WHERE relative_humidity < ?3 OR relative_humidity > ?4
+                    ) AS humidity_excursion_secs,
+                    MAX(co2 IS NOT NULL) AS has_co2,
+                    SUM(secs) FILTER (WHERE co2 > ?5) AS co2_above_secs,
+                    (
+                        SELECT s.battery_voltage FROM spans s
+                        WHERE s.mac_address = spans.mac_address AND s.battery_voltage IS NOT NULL
+                        ORDER BY s.recorded_at DESC
+                        LIMIT 1
+                    ) AS battery_voltage
+                FROM spans
+                GROUP BY mac_address
+                ORDER BY mac_address
+                "#,
+            )
+            .bind(from)
+            .bind(to)
+            .bind(humidity_low)
+            .bind(humidity_high)
+            .bind(co2_limit)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn history<'a>(
+        &'a self,
+        mac: [u8; 6],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        after: Option<&'a HistoryCursor>,
+        limit: i64,
+    ) -> BoxFuture<'a, Result<Vec<HistoryRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, HistoryRow>(
+                r#"
+                SELECT * FROM (
+                    SELECT
+                        id,
+                        -- Only format 3 readings lack the sequence
+                        CASE WHEN measurement_sequence IS NULL THEN 'v1' ELSE 'v2' END AS format,
+                        recorded_at AS timestamp,
+                        temperature AS temp,
+                        dew_point_temperature AS dew_point_temp,
+                        relative_humidity AS rel_humidity,
+                        absolute_humidity AS abs_humidity,
+                        pressure AS abs_pressure,
+                        acceleration_x AS acc_x, acceleration_y AS acc_y, acceleration_z AS acc_z,
+                        battery_voltage, movement_counter,
+                        NULL AS pm1_0, NULL AS pm2_5, NULL AS pm4_0, NULL AS pm10_0,
+                        NULL AS co2, NULL AS voc_index, NULL AS nox_index,
+                        NULL AS luminosity,
+                        measurement_sequence AS measurement_seq, tx_power, rssi
+                    FROM tag_readings
+                    WHERE mac_address = ?1 AND recorded_at >= ?2 AND recorded_at < ?3
+                    UNION ALL
+                    SELECT
+                        id,
+                        -- Only format 6 readings lack PM1.0
+                        CASE WHEN pm1_0 IS NULL THEN 'v6' ELSE 'e1' END AS format,
+                        recorded_at AS timestamp,
+                        temperature AS temp,
+                        dew_point_temperature AS dew_point_temp,
+                        relative_humidity AS rel_humidity,
+                        absolute_humidity AS abs_humidity,
+                        pressure AS abs_pressure,
+                        NULL AS acc_x, NULL AS acc_y, NULL AS acc_z,
+                        NULL AS battery_voltage, NULL AS movement_counter,
+                        pm1_0, pm2_5, pm4_0, pm10_0,
+                        co2, voc_index, nox_index,
+                        luminosity,
+                        measurement_sequence AS measurement_seq, tx_power, rssi
+                    FROM air_readings
+                    WHERE mac_address = ?1 AND recorded_at >= ?2 AND recorded_at < ?3
+                ) readings
+                WHERE ?4 IS NULL OR (timestamp, format, id) > (?4, ?5, ?6)
+                ORDER BY timestamp, format, id
+                LIMIT ?7
+                "#,
+            )
+            .bind(Mac(mac))
+            .bind(from)
+            .bind(to)
+            .bind(after.map(|c| c.timestamp))
+            .bind(after.map(|c| c.format.as_str()).unwrap_or(""))
+            .bind(after.map(|c| c.id).unwrap_or(0))
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn history_buckets<'a>(
+        &'a self,
+        mac: [u8; 6],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step_secs: u32,
+    ) -> BoxFuture<'a, Result<Vec<HistoryBucket>, anyhow::Error>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<Sqlite, HistoryBucket>(
+                r#"
+                SELECT
+                    unixepoch(?2) + (unixepoch(recorded_at) - unixepoch(?2)) / ?4 * ?4 AS timestamp,
+                    COUNT(*) AS samples,
+                    AVG(temperature) AS temp,
+                    AVG(dew_point_temperature) AS dew_point_temp,
+                    AVG(relative_humidity) AS rel_humidity,
+                    AVG(absolute_humidity) AS abs_humidity,
+                    AVG(pressure) AS abs_pressure,
+                    AVG(battery_voltage) AS battery_voltage,
+                    AVG(pm1_0) AS pm1_0,
+                    AVG(pm2_5) AS pm2_5,
+                    AVG(pm4_0) AS pm4_0,
+                    AVG(pm10_0) AS pm10_0,
+                    AVG(co2) AS co2,
+                    AVG(voc_index) AS voc_index,
+                    AVG(nox_index) AS nox_index,
+                    AVG(luminosity) AS luminosity
+                FROM (
+                    SELECT
+                        recorded_at, temperature, dew_point_temperature, relative_humidity,
+                        absolute_humidity, pressure, battery_voltage,
+                        NULL AS pm1_0, NULL AS pm2_5, NULL AS pm4_0, NULL AS pm10_0,
+                        NULL AS co2, NULL AS voc_index, NULL AS nox_index, NULL AS luminosity
+                    FROM tag_readings
+                    WHERE mac_address = ?1 AND recorded_at >= ?2 AND recorded_at < ?3
+                    UNION ALL
+                    SELECT
+                        recorded_at, temperature, dew_point_temperature, relative_humidity,
+                        absolute_humidity, pressure, NULL AS battery_voltage,
+                        pm1_0, pm2_5, pm4_0, pm10_0, co2, voc_index, nox_index, luminosity
+                    FROM air_readings
+                    WHERE mac_address = ?1 AND recorded_at >= ?2 AND recorded_at < ?3
+                ) readings
+                GROUP BY 1
+                ORDER BY 1
+                "#,
+            )
+            .bind(Mac(mac))
+            .bind(from)
+            .bind(to)
+            .bind(step_secs)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(rows)
+        })
+    }
+
+    fn prune<'a>(
+        &'a self,
+        filter: &'a RowFilter,
+        dry_run: bool,
+    ) -> BoxFuture<'a, Result<Vec<(&'static str, u64)>, anyhow::Error>> {
+        Box::pin(async move {
+            let condition = "WHERE (?1 IS NULL OR mac_address = ?1) \
+                AND (?2 IS NULL OR recorded_at >= ?2) \
+                AND (?3 IS NULL OR recorded_at < ?3)";
+            let mut tx = self.pool.begin().await?;
+            let mut counts = Vec::new();
+            for table in TABLES {
+                let rows = if dry_run {
+                    let (count,) = sqlx::query_as::<Sqlite, (i64,)>(&format!(
+                        "SELECT COUNT(*) FROM {table} {condition}"
+                    ))
+                    .bind(filter.mac.map(Mac))
+                    .bind(filter.from)
+                    .bind(filter.to)
+                    .fetch_one(&mut *tx)
+                    .await?;
+                    count as u64
+                } else {
+                    sqlx::query::<Sqlite>(&format!("DELETE FROM {table} {condition}"))
+                        .bind(filter.mac.map(Mac))
+                        .bind(filter.from)
+                        .bind(filter.to)
+                        .execute(&mut *tx)
+                        .await?
+                        .rows_affected()
+                };
+                counts.push((table, rows));
+            }
+            tx.commit().await?;
+            Ok(counts)
+        })
+    }
+
+    fn expire<'a>(
+        &'a self,
+        table: &'a str,
+        before: DateTime<Utc>,
+        dry_run: bool,
+    ) -> BoxFuture<'a, Result<u64, anyhow::Error>> {
+        Box::pin(async move {
+            if !RETAINED_TABLES.contains(&table) {
+                anyhow::bail!("Can't expire rows of {table}");
+            }
+            if dry_run {
+                let (count,) = sqlx::query_as::<Sqlite, (i64,)>(&format!(
+                    "SELECT COUNT(*) FROM {table} WHERE recorded_at < ?1"
+                ))
+                .bind(before)
+                .fetch_one(&self.pool)
+                .await?;
+                return Ok(count as u64);
+            }
+            let mut deleted = 0;
+            loop {
+                let rows = sqlx::query::<Sqlite>(&format!(
+                    "DELETE FROM {table} WHERE id IN \
+                     (SELECT id FROM {table} WHERE recorded_at < ?1 LIMIT ?2)"
+                ))
+                .bind(before)
+                .bind(EXPIRE_BATCH)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+                deleted += rows;
+                if rows < EXPIRE_BATCH as u64 {
+                    return Ok(deleted);
+                }
+            }
+        })
+    }
+
+    fn latest_rollup<'a>(
+        &'a self,
+        rollup: &'a Rollup,
+        resolution_secs: u32,
+    ) -> BoxFuture<'a, Result<Option<DateTime<Utc>>, anyhow::Error>> {
+        Box::pin(async move {
+            let latest = sqlx::query_scalar::<Sqlite, Option<DateTime<Utc>>>(&format!(
+                "SELECT MAX(bucket) FROM {} WHERE resolution_secs = ?1",
+                rollup.table
+            ))
+            .bind(resolution_secs as i32)
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(latest)
+        })
+    }
+
+    fn rollup<'a>(
+        &'a self,
+        rollup: &'a Rollup,
+        resolution_secs: u32,
+        from: Option<DateTime<Utc>>,
+        to: DateTime<Utc>,
+    ) -> BoxFuture<'a, Result<u64, anyhow::Error>> {
+        Box::pin(async move {
+            let query = rollup.upsert(
+                "strftime('%Y-%m-%dT%H:%M:%S+00:00', unixepoch(recorded_at) / ?1 * ?1, 'unixepoch')",
+                ["?1", "?2", "?3"],
+                "",
+                "excluded",
+            );
+            let result = sqlx::query::<Sqlite>(&query)
+                .bind(resolution_secs as i32)
+                .bind(from)
+                .bind(to)
+                .execute(&self.pool)
+                .await?;
+            Ok(result.rows_affected())
+        })
+    }
+
+    fn reindex<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            sqlx::query::<Sqlite>(&format!("REINDEX {table}"))
+                .execute(&self.pool)
+                .await?;
+            sqlx::query::<Sqlite>(&format!("ANALYZE {table}"))
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn visit_derived<'a>(
+        &'a self,
+        table: &'a str,
+        filter: &'a RowFilter,
+        visit: &'a mut (dyn FnMut(DerivedRow) + Send),
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let query = format!(
+                "SELECT id, recorded_at, mac_address, temperature, relative_humidity, pressure, \
+                    absolute_humidity, dew_point_temperature \
+                FROM {table} \
+                WHERE (?1 IS NULL OR mac_address = ?1) \
+                    AND (?2 IS NULL OR recorded_at >= ?2) \
+                    AND (?3 IS NULL OR recorded_at < ?3) \
+                ORDER BY id"
+            );
+            let mut rows = sqlx::query_as::<Sqlite, DerivedRow>(&query)
+                .bind(filter.mac.map(Mac))
+                .bind(filter.from)
+                .bind(filter.to)
+                .fetch(&self.pool);
+            while let Some(row) = rows.try_next().await? {
+                visit(row);
+            }
+            Ok(())
+        })
+    }
+
+    fn raw_readings<'a>(
+        &'a self,
+        table: &'a str,
+        filter: &'a RowFilter,
+        after: i32,
+        limit: u32,
+    ) -> BoxFuture<'a, Result<Vec<RawRow>, anyhow::Error>> {
+        Box::pin(async move {
+            let query = format!(
+                "SELECT id, recorded_at, mac_address, raw_payload, temperature, relative_humidity, \
+                    pressure \
+                FROM {table} \
+                WHERE id > ?1 \
+                    AND (?2 IS NULL OR mac_address = ?2) \
+                    AND (?3 IS NULL OR recorded_at >= ?3) \
+                    AND (?4 IS NULL OR recorded_at < ?4) \
+                ORDER BY id \
+                LIMIT ?5"
+            );
+            let rows = sqlx::query_as::<Sqlite, RawRow>(&query)
+                .bind(after)
+                .bind(filter.mac.map(Mac))
+                .bind(filter.from)
+                .bind(filter.to)
+                .bind(i64::from(limit))
+                .fetch_all(&self.pool)
+                .await?;
+            Ok(rows)
+        })
+    }
+
+    fn update_readings<'a>(
+        &'a self,
+        table: &'a str,
+        updates: &'a [(i32, Reprocessed)],
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let derived = format!(
+                "UPDATE {table} SET absolute_humidity = ?2, dew_point_temperature = ?3, \
+                    humidity_formula = ?4 WHERE id = ?1"
+            );
+            let mut tx = self.pool.begin().await?;
+            for (id, reprocessed) in updates {
+                let data = match reprocessed {
+                    Reprocessed::Derived {
+                        abs_humidity,
+                        dew_point,
+                        humidity_formula,
+                    } => {
+                        sqlx::query::<Sqlite>(&derived)
+                            .bind(id)
+                            .bind(abs_humidity)
+                            .bind(dew_point)
+                            .bind(humidity_formula.as_str())
+                            .execute(&mut *tx)
+                            .await?;
+                        continue;
+                    }
+                    Reprocessed::Decoded(data) => data,
+                };
+                if data.table() != table {
+                    anyhow::bail!("Reading {id} decoded into a format of {}", data.table());
+                }
+                let query = match data {
+                    Ruuvi::V2(data) => {
+                        let comfort = data.comfort();
+                        sqlx::query::<Sqlite>(
+                            r#"
+                        UPDATE tag_readings SET
+                            temperature = ?2,
+                            relative_humidity = ?3,
+                            pressure = ?4,
+                            acceleration_x = ?5,
+                            acceleration_y = ?6,
+                            acceleration_z = ?7,
+                            battery_voltage = ?8,
+                            tx_power = ?9,
+                            movement_counter = ?10,
+                            absolute_humidity = ?11,
+                            dew_point_temperature = ?12,
+                            humidity_formula = ?13,
+                            heat_index = ?14,
+                            humidex = ?15,
+                            wet_bulb_temperature = ?16,
+                            vapour_pressure_deficit = ?17,
+                            uncalibrated_temperature = ?18,
+                            uncalibrated_relative_humidity = ?19
+                        WHERE id = ?1
+                        "#,
+                        )
+                        .bind(id)
+                        .bind(data.temp)
+                        .bind(data.rel_humidity)
+                        .bind(data.abs_pressure)
+                        .bind(data.acc_x)
+                        .bind(data.acc_y)
+                        .bind(data.acc_z)
+                        .bind(data.battery_voltage)
+                        .bind(data.tx_power)
+                        .bind(data.movement_counter)
+                        .bind(data.abs_humidity.map(|h| h as f32))
+                        .bind(data.dew_point_temp.map(|t| t as f32))
+                        .bind(data.humidity_formula.as_str())
+                        .bind(comfort.map(|c| c.heat_index as f32))
+                        .bind(comfort.map(|c| c.humidex as f32))
+                        .bind(comfort.map(|c| c.wet_bulb_temp as f32))
+                        .bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+                        .bind(data.uncalibrated.and_then(|u| u.temp))
+                        .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    }
+                    Ruuvi::V1(data) => {
+                        let comfort = data.comfort();
+                        sqlx::query::<Sqlite>(
+                            r#"
+                        UPDATE tag_readings SET
+                            temperature = ?2,
+                            relative_humidity = ?3,
+                            pressure = ?4,
+                            acceleration_x = ?5,
+                            acceleration_y = ?6,
+                            acceleration_z = ?7,
+                            battery_voltage = ?8,
+                            absolute_humidity = ?9,
+                            dew_point_temperature = ?10,
+                            humidity_formula = ?11,
+                            heat_index = ?12,
+                            humidex = ?13,
+                            wet_bulb_temperature = ?14,
+                            vapour_pressure_deficit = ?15,
+                            uncalibrated_temperature = ?16,
+                            uncalibrated_relative_humidity = ?17
+                        WHERE id = ?1
+                        "#,
+                        )
+                        .bind(id)
+                        .bind(data.temp)
+                        .bind(data.rel_humidity)
+                        .bind(data.abs_pressure)
+                        .bind(data.acc_x)
+                        .bind(data.acc_y)
+                        .bind(data.acc_z)
+                        .bind(data.battery_voltage)
+                        .bind(data.abs_humidity.map(|h| h as f32))
+                        .bind(data.dew_point_temp.map(|t| t as f32))
+                        .bind(data.humidity_formula.as_str())
+                        .bind(comfort.map(|c| c.heat_index as f32))
+                        .bind(comfort.map(|c| c.humidex as f32))
+                        .bind(comfort.map(|c| c.wet_bulb_temp as f32))
+                        .bind(comfort.map(|c| c.vapour_pressure_deficit as f32))
+                        .bind(data.uncalibrated.and_then(|u| u.temp))
+                        .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    }
+                    Ruuvi::E1(data) => sqlx::query::<Sqlite>(
+                        r#"
+                        UPDATE air_readings SET
+                            temperature = ?2,
+                            dew_point_temperature = ?3,
+                            relative_humidity = ?4,
+                            absolute_humidity = ?5,
+                            pressure = ?6,
+                            pm1_0 = ?7,
+                            pm2_5 = ?8,
+                            pm4_0 = ?9,
+                            pm10_0 = ?10,
+                            co2 = ?11,
+                            voc_index = ?12,
+                            nox_index = ?13,
+                            luminosity = ?14,
+                            flags = ?15,
+                            humidity_formula = ?16,
+                            air_quality_index = ?17,
+                            uncalibrated_temperature = ?18,
+                            uncalibrated_relative_humidity = ?19,
+                            uncalibrated_co2 = ?20
+                        WHERE id = ?1
+                        "#,
+                    )
+                    .bind(id)
+                    .bind(data.temp)
+                    .bind(data.dew_point_temp)
+                    .bind(data.rel_humidity)
+                    .bind(data.abs_humidity)
+                    .bind(data.abs_pressure)
+                    .bind(data.pm1_0)
+                    .bind(data.pm2_5)
+                    .bind(data.pm4_0)
+                    .bind(data.pm10_0)
+                    .bind(data.co2)
+                    .bind(data.voc_index)
+                    .bind(data.nox_index)
+                    .bind(data.luminosity)
+                    .bind(data.flags)
+                    .bind(data.humidity_formula.as_str())
+                    .bind(data.air_quality_index().map(|v| v as f32))
+                    .bind(data.uncalibrated.and_then(|u| u.temp))
+                    .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    .bind(data.uncalibrated.and_then(|u| u.co2)),
+                    Ruuvi::V6(data) => sqlx::query::<Sqlite>(
+                        r#"
+                        UPDATE air_readings SET
+                            temperature = ?2,
+                            dew_point_temperature = ?3,
+                            relative_humidity = ?4,
+                            absolute_humidity = ?5,
+                            pressure = ?6,
+                            pm2_5 = ?7,
+                            co2 = ?8,
+                            voc_index = ?9,
+                            nox_index = ?10,
+                            luminosity = ?11,
+                            flags = ?12,
+                            humidity_formula = ?13,
+                            air_quality_index = ?14,
+                            uncalibrated_temperature = ?15,
+                            uncalibrated_relative_humidity = ?16,
+                            uncalibrated_co2 = ?17
+                        WHERE id = ?1
+                        "#,
+                    )
+                    .bind(id)
+                    .bind(data.temp)
+                    .bind(data.dew_point_temp)
+                    .bind(data.rel_humidity)
+                    .bind(data.abs_humidity)
+                    .bind(data.abs_pressure)
+                    .bind(data.pm2_5)
+                    .bind(data.co2)
+                    .bind(data.voc_index)
+                    .bind(data.nox_index)
+                    .bind(data.luminosity)
+                    .bind(data.flags)
+                    .bind(data.humidity_formula.as_str())
+                    .bind(data.air_quality_index().map(|v| v as f32))
+                    .bind(data.uncalibrated.and_then(|u| u.temp))
+                    .bind(data.uncalibrated.and_then(|u| u.rel_humidity))
+                    .bind(data.uncalibrated.and_then(|u| u.co2)),
+                };
+                query.execute(&mut *tx).await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+}
+
+fn min(a: Option<f32>, b: Option<f32>) -> Option<f32> {
+    a.into_iter().chain(b).reduce(f32::min)
+}
+
+fn max(a: Option<f32>, b: Option<f32>) -> Option<f32> {
+    a.into_iter().chain(b).reduce(f32::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SqliteStorage;
+    use crate::database::{HistoryCursor, ListenerRow, ROLLUPS, Reprocessed, RowFilter, Storage};
+    use crate::quality::QualitySnapshot;
+    use crate::{Ruuvi, RuuviV2};
+    use chrono::{DateTime, Duration, Utc};
+    use chrono_tz::Tz;
+    use ruuvi_schema::TagModel;
+    use ruuvi_schema::convert::HumidityFormula;
+    use ruuvi_schema::protocol::{ResetReason, Telemetry};
+    use std::net::Ipv4Addr;
+
+    const MAC: [u8; 6] = [0xAA, 2, 3, 4, 5, 6];
+
+    fn reading(timestamp: DateTime<Utc>, temp: f32) -> RuuviV2 {
+        RuuviV2 {
+            temp: Some(temp),
+            ..RuuviV2::sample(MAC, 1, timestamp)
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_and_queries_readings() {
+        // Every connection gets its own in-memory database
+        let storage = SqliteStorage::connect("sqlite::memory:", 1).await.unwrap();
+        storage.migrate().await.unwrap();
+
+        let start = "2025-03-01T22:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        let hall = ListenerRow {
+            name: "hall".to_owned(),
+            identified: true,
+            address: Ipv4Addr::LOCALHOST.into(),
+            psk: "default".to_owned(),
+            firmware: None,
+        };
+        storage.upsert_listener(&hall).await.unwrap();
+        storage.upsert_listener(&hall).await.unwrap();
+        for adverts in [10, 20] {
+            let telemetry = Telemetry {
+                uptime_secs: 60,
+                reset_reason: ResetReason::Watchdog,
+                heap_free: 100_000,
+                wifi_rssi: Some(-70),
+                adverts,
+                parse_failures: 0,
+                dropped: 1,
+                queue_overflows: 1,
+                frames_sent: 9,
+                reconnects: 0,
+            };
+            storage
+                .upsert_listener_status("hall", telemetry)
+                .await
+                .unwrap();
+        }
+        let statuses = storage.listener_statuses().await.unwrap();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(
+            (
+                statuses[0].name.as_str(),
+                statuses[0].adverts,
+                statuses[0].wifi_rssi
+            ),
+            ("hall", 20, Some(-70))
+        );
+        assert_eq!(statuses[0].reset_reason.as_deref(), Some("watchdog"));
+        for (minutes, temp) in [(0, 20.0), (1, 22.0), (120, 24.0)] {
+            let timestamp = start + Duration::minutes(minutes);
+            let raw_payload = (minutes == 120).then_some(&[0x05, 0x12][..]);
+            storage
+                .insert_data_v2(reading(timestamp, temp), "hall", raw_payload)
+                .await
+                .unwrap();
+        }
+        storage
+            .upsert_tag_model(MAC, TagModel::RuuviTag, start)
+            .await
+            .unwrap();
+
+        let linked: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM tag_readings JOIN listeners ON listeners.id = listener_id",
+        )
+        .fetch_one(&storage.pool)
+        .await
+        .unwrap();
+        assert_eq!(linked, 3);
+
+        let tags = storage.tags().await.unwrap();
+        assert_eq!(tags[0].mac_address.bytes(), MAC);
+        assert_eq!(tags[0].last_seen, Some(start + Duration::minutes(120)));
+
+        let end = start + Duration::days(1);
+        let first = storage.history(MAC, start, end, None, 2).await.unwrap();
+        assert_eq!(first.len(), 2);
+        let cursor = HistoryCursor {
+            timestamp: first[1].timestamp,
+            format: first[1].format.clone(),
+            id: first[1].id,
+        };
+        let rest = storage
+            .history(MAC, start, end, Some(&cursor), 2)
+            .await
+            .unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].temp, Some(24.0));
+
+        let buckets = storage
+            .history_buckets(MAC, start, end, 3600)
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].timestamp, start);
+        assert_eq!(buckets[0].temp, Some(21.0));
+
+        // 22:30 UTC is already the next day in Helsinki
+        let helsinki: Tz = "Europe/Helsinki".parse().unwrap();
+        let days = storage.daily_summary(MAC, helsinki, start).await.unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].day.to_string(), "2025-03-02");
+        assert_eq!(days[0].temp_max, Some(24.0));
+        assert_eq!(days[0].temp_avg, Some(22.0));
+        let days = storage.daily_summary(MAC, Tz::UTC, start).await.unwrap();
+        assert_eq!(days.len(), 2);
+
+        let summaries = storage
+            .tag_summaries(start, end, 30.0, 70.0, 1000)
+            .await
+            .unwrap();
+        assert_eq!(summaries[0].battery_voltage, Some(3.0));
+        assert!(!summaries[0].has_co2);
+
+        let rollup = &ROLLUPS[0];
+        assert_eq!(storage.rollup(rollup, 3600, None, end).await.unwrap(), 2);
+        assert_eq!(
+            storage.latest_rollup(rollup, 3600).await.unwrap(),
+            Some("2025-03-02T00:00:00Z".parse().unwrap())
+        );
+        let (samples, temp_avg): (i64, f64) = sqlx::query_as(
+            "SELECT samples, temperature_avg FROM tag_readings_agg WHERE bucket = ?1",
+        )
+        .bind("2025-03-01T22:00:00+00:00")
+        .fetch_one(&storage.pool)
+        .await
+        .unwrap();
+        assert_eq!((samples, temp_avg), (2, 21.0));
+        // Recomputing replaces the buckets
+        assert_eq!(storage.rollup(rollup, 3600, None, end).await.unwrap(), 2);
+
+        let filter = RowFilter {
+            to: Some(start + Duration::minutes(60)),
+            ..RowFilter::default()
+        };
+        let pruned = storage.prune(&filter, false).await.unwrap();
+        assert_eq!(pruned[0], ("tag_readings", 2));
+        let left = storage.history(MAC, start, end, None, 10).await.unwrap();
+        assert_eq!(left.len(), 1);
+
+        let raw = storage
+            .raw_readings("tag_readings", &RowFilter::default(), 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(raw.len(), 1);
+        assert_eq!(raw[0].raw_payload.as_deref(), Some(&[0x05, 0x12][..]));
+        let decoded = Ruuvi::V2(reading(start, 25.0));
+        let updates = [(raw[0].id, Reprocessed::Decoded(decoded))];
+        storage
+            .update_readings("tag_readings", &updates)
+            .await
+            .unwrap();
+        let left = storage.history(MAC, start, end, None, 10).await.unwrap();
+        assert_eq!(left[0].temp, Some(25.0));
+        // The timestamp stays
+        assert_eq!(left[0].timestamp, start + Duration::minutes(120));
+        let heat_index: f32 =
+            sqlx::query_scalar("SELECT heat_index FROM tag_readings WHERE id = ?1")
+                .bind(raw[0].id)
+                .fetch_one(&storage.pool)
+                .await
+                .unwrap();
+        assert!((heat_index - 24.9).abs() < 0.1, "{heat_index}");
+        let derived = Reprocessed::Derived {
+            abs_humidity: Some(9.0),
+            dew_point: Some(10.0),
+            humidity_formula: HumidityFormula::HylandWexler,
+        };
+        storage
+            .update_readings("tag_readings", &[(raw[0].id, derived)])
+            .await
+            .unwrap();
+        let formula: String =
+            sqlx::query_scalar("SELECT humidity_formula FROM tag_readings WHERE id = ?1")
+                .bind(raw[0].id)
+                .fetch_one(&storage.pool)
+                .await
+                .unwrap();
+        assert_eq!(formula, "hyland_wexler");
+        assert!(
+            storage
+                .update_readings("air_readings", &updates)
+                .await
+                .is_err()
+        );
+        assert!(
+            storage
+                .raw_readings("tag_readings", &RowFilter::default(), raw[0].id, 10)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        let cutoff = start + Duration::hours(3);
+        assert_eq!(
+            storage.expire("tag_readings", cutoff, true).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            storage.expire("tag_readings", cutoff, false).await.unwrap(),
+            1
+        );
+        assert!(storage.expire("tags", cutoff, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_missing_values_empty() {
+        let storage = SqliteStorage::connect("sqlite::memory:", 1).await.unwrap();
+        storage.migrate().await.unwrap();
+        assert!(storage.tags().await.unwrap().is_empty());
+
+        let start = "2025-03-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let end = start + Duration::hours(1);
+        storage
+            .upsert_listener(&ListenerRow {
+                name: "hall".to_owned(),
+                identified: false,
+                address: Ipv4Addr::LOCALHOST.into(),
+                psk: "default".to_owned(),
+                firmware: None,
+            })
+            .await
+            .unwrap();
+        let bare = RuuviV2 {
+            temp: None,
+            rel_humidity: None,
+            dew_point_temp: None,
+            abs_humidity: None,
+            ..reading(start, 0.0)
+        };
+        storage.insert_data_v2(bare, "hall", None).await.unwrap();
+
+        let history = storage.history(MAC, start, end, None, 10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].temp, None);
+        let buckets = storage
+            .history_buckets(MAC, start, end, 3600)
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(buckets[0].temp, None);
+
+        let other = [0xBB, 2, 3, 4, 5, 6];
+        assert!(
+            storage
+                .history(other, start, end, None, 10)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn prunes_a_tags_quality_scores() {
+        let storage = SqliteStorage::connect("sqlite::memory:", 1).await.unwrap();
+        storage.migrate().await.unwrap();
+        let snapshot = |mac| QualitySnapshot {
+            mac,
+            score: 90.0,
+            sensor: 100.0,
+            delivery: 90.0,
+            readings: 10,
+            lost: 1,
+            sentinels: 0,
+            clamped: 0,
+            missing_timestamps: 0,
+        };
+        let other = [0xBB, 2, 3, 4, 5, 6];
+        storage
+            .insert_tag_quality(&[snapshot(MAC), snapshot(other)])
+            .await
+            .unwrap();
+
+        let filter = RowFilter {
+            mac: Some(MAC),
+            ..RowFilter::default()
+        };
+        let pruned = storage.prune(&filter, false).await.unwrap();
+        assert!(pruned.contains(&("tag_quality", 1)));
+        let (left,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM tag_quality")
+            .fetch_one(&storage.pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 1);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::Ruuvi;
+use chrono::{DateTime, Utc};
+use ruuvi_schema::ack::Ack;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::mem::Discriminant;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// An Air sends every measurement as both E1 and format 6 with the same
+/// sequence, the format keeps them apart
+pub type DedupKey = ([u8; 6], u32, Discriminant<Ruuvi>);
+
+#[derive(Debug, Clone)]
+pub struct Reception {
+    pub listener: String,
+    pub rssi: i8,
+}
+
+/// Acknowledges a reading to the connection it arrived over
+#[derive(Debug)]
+pub struct AckHandle {
+    pub ack: Ack,
+    pub sender: UnboundedSender<Ack>,
+}
+
+impl AckHandle {
+    pub fn send(self) {
+        // The connection may have closed in the meantime, the listener resends then
+        let _ = self.sender.send(self.ack);
+    }
+}
+
+/// A measurement collected from one or more listeners.
+/// `data` is the copy heard with the strongest RSSI.
+#[derive(Debug)]
+pub struct Pending {
+    pub data: Ruuvi,
+    pub listener: String,
+    /// Manufacturer data of `data`, when it's kept
+    pub raw_payload: Option<Vec<u8>>,
+    pub receptions: Vec<Reception>,
+    /// Sent once the measurement is stored
+    pub acks: Vec<AckHandle>,
+}
+
+/// A measurement stored recently
+#[derive(Debug, Clone)]
+struct Stored {
+    at: Instant,
+    listener: String,
+    timestamp: DateTime<Utc>,
+}
+
+/// Copy of a measurement stored before it arrived, it's already acknowledged
+#[derive(Debug)]
+pub struct Late {
+    pub mac: [u8; 6],
+    pub measurement_seq: u32,
+    /// Of the stored copy
+    pub timestamp: DateTime<Utc>,
+    /// Listener of the stored copy
+    pub primary: String,
+    pub reception: Reception,
+}
+
+#[derive(Debug)]
+pub enum Submitted {
+    /// First copy, the caller is responsible for calling `take` after the window
+    First(DedupKey),
+    /// Merged into a measurement still waiting for its window or being stored
+    Merged,
+    Late(Late),
+}
+
+/// Measurements not stored yet
+#[derive(Default)]
+struct Queue {
+    /// Waiting for their window
+    pending: HashMap<DedupKey, Pending>,
+    /// Taken and being stored, with the acknowledgements of copies arriving meanwhile
+    in_flight: HashMap<DedupKey, Vec<AckHandle>>,
+}
+
+/// Collects copies of the same (mac, seq, format) reported by several
+/// listeners during a short window, so only one canonical row gets stored.
+/// Stored measurements are remembered for a while longer, copies delayed past
+/// the window are then dropped instead of stored again.
+pub struct Deduplicator {
+    window: Duration,
+    memory: Duration,
+    /// Locked before `stored`
+    queue: Mutex<Queue>,
+    stored: Mutex<HashMap<DedupKey, Stored>>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration, memory: Duration) -> Self {
+        Self {
+            window,
+            memory,
+            queue: Mutex::new(Queue::default()),
+            stored: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Register a reception
+    pub fn submit(
+        &self,
+        listener: &str,
+        data: Ruuvi,
+        raw_payload: Option<Vec<u8>>,
+        ack: Option<AckHandle>,
+    ) -> Submitted {
+        let key = key(&data);
+        let reception = Reception {
+            listener: listener.to_owned(),
+            rssi: data.rssi(),
+        };
+
+        // Held throughout, so the measurement can't move from the queue to
+        // `stored` between the checks
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(stored) = self.recently_stored(&key) {
+            if let Some(ack) = ack {
+                ack.send();
+            }
+            return Submitted::Late(Late {
+                mac: key.0,
+                measurement_seq: key.1,
+                timestamp: stored.timestamp,
+                primary: stored.listener,
+                reception,
+            });
+        }
+
+        if let Some(acks) = queue.in_flight.get_mut(&key) {
+            acks.extend(ack);
+            return Submitted::Merged;
+        }
+        match queue.pending.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(Pending {
+                    data,
+                    listener: listener.to_owned(),
+                    raw_payload,
+                    receptions: vec![reception],
+                    acks: ack.into_iter().collect(),
+                });
+                Submitted::First(key)
+            }
+            Entry::Occupied(mut entry) => {
+                let entry = entry.get_mut();
+                entry.acks.extend(ack);
+                // Same listener repeating itself isn't another reception
+                if entry.receptions.iter().any(|r| r.listener == listener) {
+                    return Submitted::Merged;
+                }
+                if reception.rssi > entry.data.rssi() {
+                    entry.data = data;
+                    entry.listener = listener.to_owned();
+                    entry.raw_payload = raw_payload;
+                }
+                entry.receptions.push(reception);
+                Submitted::Merged
+            }
+        }
+    }
+
+    /// Take a measurement to store it. It stays in flight, merging the copies
+    /// that arrive meanwhile, until `stored` or `failed` is called.
+    pub fn take(&self, key: DedupKey) -> Option<Pending> {
+        let mut queue = self.queue.lock().unwrap();
+        let pending = queue.pending.remove(&key)?;
+        queue.in_flight.insert(key, Vec::new());
+        Some(pending)
+    }
+
+    /// Remember a measurement once it's stored. Returns the acknowledgements
+    /// of the copies that arrived while it was in flight.
+    pub fn stored(&self, data: &Ruuvi, listener: &str) -> Vec<AckHandle> {
+        let key = key(data);
+        let mut queue = self.queue.lock().unwrap();
+        let acks = queue.in_flight.remove(&key).unwrap_or_default();
+        // Format 3 has no sequence, its fingerprint repeats whenever the values do
+        if self.memory.is_zero() || matches!(data, Ruuvi::V1(_)) {
+            return acks;
+        }
+        let mut stored = self.stored.lock().unwrap();
+        let now = Instant::now();
+        stored.retain(|_, s| now.duration_since(s.at) < self.memory);
+        stored.insert(
+            key,
+            Stored {
+                at: now,
+                listener: listener.to_owned(),
+                timestamp: data.timestamp(),
+            },
+        );
+        acks
+    }
+
+    /// Forget a measurement that failed to store, so the listeners' resends get
+    /// through. The copies that arrived meanwhile stay unacknowledged.
+    pub fn failed(&self, key: DedupKey) {
+        self.queue.lock().unwrap().in_flight.remove(&key);
+    }
+
+    fn recently_stored(&self, key: &DedupKey) -> Option<Stored> {
+        let stored = self.stored.lock().unwrap();
+        stored
+            .get(key)
+            .filter(|s| s.at.elapsed() < self.memory)
+            .cloned()
+    }
+}
+
+fn key(data: &Ruuvi) -> DedupKey {
+    (
+        data.mac(),
+        data.measurement_seq(),
+        std::mem::discriminant(data),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AckHandle, Deduplicator, Submitted};
+    use crate::{Ruuvi, RuuviV2};
+    use chrono::Utc;
+    use ruuvi_schema::ack::Ack;
+    use std::time::Duration;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn reading(measurement_seq: u16, rssi: i8) -> Ruuvi {
+        Ruuvi::V2(RuuviV2 {
+            rssi,
+            ..RuuviV2::sample(
+                [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+                measurement_seq,
+                Utc::now(),
+            )
+        })
+    }
+
+    #[test]
+    fn stores_each_measurement_once() {
+        let dedup = Deduplicator::new(Duration::from_secs(2), Duration::from_secs(60));
+        let Submitted::First(key) = dedup.submit("hall", reading(1, -80), None, None) else {
+            panic!("first copy");
+        };
+        assert!(matches!(
+            dedup.submit("kitchen", reading(1, -50), None, None),
+            Submitted::Merged
+        ));
+        let pending = dedup.take(key).unwrap();
+        assert_eq!(pending.listener, "kitchen");
+        assert_eq!(pending.receptions.len(), 2);
+
+        // Being stored, a resend merges into it
+        assert!(matches!(
+            dedup.submit("hall", reading(1, -80), None, None),
+            Submitted::Merged
+        ));
+        assert!(dedup.take(key).is_none());
+        dedup.stored(&pending.data, &pending.listener);
+        match dedup.submit("garage", reading(1, -90), None, None) {
+            Submitted::Late(late) => {
+                assert_eq!(late.primary, "kitchen");
+                assert_eq!(late.reception.listener, "garage");
+            }
+            other => panic!("{other:?}"),
+        }
+        assert!(matches!(
+            dedup.submit("hall", reading(2, -80), None, None),
+            Submitted::First(_)
+        ));
+    }
+
+    #[test]
+    fn failed_store_lets_resends_through() {
+        let dedup = Deduplicator::new(Duration::from_secs(2), Duration::from_secs(60));
+        let Submitted::First(key) = dedup.submit("hall", reading(1, -80), None, None) else {
+            panic!("first copy");
+        };
+        dedup.take(key).unwrap();
+        dedup.failed(key);
+        assert!(matches!(
+            dedup.submit("hall", reading(1, -80), None, None),
+            Submitted::First(_)
+        ));
+    }
+
+    #[test]
+    fn keeps_the_strongest_copy() {
+        let dedup = Deduplicator::new(Duration::from_secs(2), Duration::ZERO);
+        let Submitted::First(key) = dedup.submit("hall", reading(1, -70), None, None) else {
+            panic!("first copy");
+        };
+        dedup.submit("kitchen", reading(1, -90), Some(vec![1]), None);
+        dedup.submit("garage", reading(1, -60), Some(vec![2]), None);
+        // A tie doesn't replace the copy heard first
+        dedup.submit("attic", reading(1, -60), Some(vec![3]), None);
+        let pending = dedup.take(key).unwrap();
+        assert_eq!(pending.listener, "garage");
+        assert_eq!(pending.data.rssi(), -60);
+        assert_eq!(pending.raw_payload, Some(vec![2]));
+        let listeners: Vec<_> = pending
+            .receptions
+            .iter()
+            .map(|r| r.listener.as_str())
+            .collect();
+        assert_eq!(listeners, ["hall", "kitchen", "garage", "attic"]);
+    }
+
+    #[test]
+    fn same_listener_repeats_are_no_reception() {
+        let dedup = Deduplicator::new(Duration::from_secs(2), Duration::ZERO);
+        let (sender, mut acks) = unbounded_channel();
+        let ack = || {
+            Some(AckHandle {
+                ack: Ack {
+                    mac: [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+                    seq: 1,
+                },
+                sender: sender.clone(),
+            })
+        };
+        let Submitted::First(key) = dedup.submit("hall", reading(1, -80), None, ack()) else {
+            panic!("first copy");
+        };
+        // A resend heard louder is still the same reception
+        assert!(matches!(
+            dedup.submit("hall", reading(1, -50), None, ack()),
+            Submitted::Merged
+        ));
+        let pending = dedup.take(key).unwrap();
+        assert_eq!(pending.receptions.len(), 1);
+        assert_eq!(pending.data.rssi(), -80);
+
+        // Both copies are acknowledged, so the listener stops resending
+        assert_eq!(pending.acks.len(), 2);
+        pending.acks.into_iter().for_each(AckHandle::send);
+        assert_eq!(acks.try_recv().unwrap().seq, 1);
+        assert_eq!(acks.try_recv().unwrap().seq, 1);
+    }
+}
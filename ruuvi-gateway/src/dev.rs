@@ -0,0 +1,42 @@
+use crate::{AppState, Ruuvi, ingest, raw_payload};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::{Json, Router};
+use chrono::Utc;
+use ruuvi_schema::RuuviRaw;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Development endpoints, only mounted with `--dev` and behind the admin role
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new().route("/dev/inject", post(inject))
+}
+
+#[derive(Debug, Deserialize)]
+struct InjectQuery {
+    /// Listener the reading is attributed to
+    listener: Option<String>,
+}
+
+/// Run a hand-crafted packet through the same conversion, deduplication and
+/// storage as listener frames. Responds with the converted reading.
+async fn inject(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<InjectQuery>,
+    Json(raw): Json<RuuviRaw>,
+) -> Result<(StatusCode, Json<Ruuvi>), (StatusCode, String)> {
+    let listener = query.listener.unwrap_or_else(|| "dev".to_owned());
+    let raw_payload = raw_payload(&state, &raw);
+    let mut data = Ruuvi::from_raw(
+        raw,
+        Utc::now(),
+        &state.tag_keys,
+        state.config.humidity.formula,
+    )
+    .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+    state.calibrations.apply(&mut data);
+    tracing::info!("Injected a reading of {:X?} from {listener}", data.mac());
+    ingest(&state, &listener, data.clone(), raw_payload, None, None);
+    Ok((StatusCode::ACCEPTED, Json(data)))
+}
@@ -0,0 +1,171 @@
+use crate::RuuviV2;
+use crate::config::{Axis, DoorConfig};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorState {
+    Open,
+    Closed,
+}
+
+impl DoorState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DoorTransition {
+    pub previous: Option<DoorState>,
+    pub current: DoorState,
+    pub acceleration: i16,
+}
+
+#[derive(Debug, Default)]
+struct Tracker {
+    state: Option<DoorState>,
+    // Candidate state and how many consecutive samples agreed on it
+    pending: Option<(DoorState, u8)>,
+}
+
+/// Infers door/window open-close state from accelerometer axis flips
+/// for the tags listed in the `[[doors]]` config.
+pub struct DoorClassifier {
+    doors: HashMap<[u8; 6], DoorConfig>,
+    trackers: Mutex<HashMap<[u8; 6], Tracker>>,
+}
+
+impl DoorClassifier {
+    pub fn new(doors: &[DoorConfig]) -> Self {
+        Self {
+            doors: doors.iter().map(|d| (d.mac, d.clone())).collect(),
+            trackers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Feed a reading, returns a transition if the tag's door state changed
+    pub fn observe(&self, data: &RuuviV2) -> Option<DoorTransition> {
+        let door = self.doors.get(&data.mac)?;
+        let acceleration = match door.axis {
+            Axis::X => data.acc_x,
+            Axis::Y => data.acc_y,
+            Axis::Z => data.acc_z,
+        }?;
+
+        // Inside the dead band the orientation is ambiguous, keep the old state
+        let candidate = classify(door, acceleration)?;
+
+        let mut trackers = self.trackers.lock().unwrap();
+        let tracker = trackers.entry(data.mac).or_default();
+        if tracker.state == Some(candidate) {
+            tracker.pending = None;
+            return None;
+        }
+
+        let count = match tracker.pending {
+            Some((state, count)) if state == candidate => count.saturating_add(1),
+            _ => 1,
+        };
+        if count < door.debounce.max(1) {
+            tracker.pending = Some((candidate, count));
+            return None;
+        }
+
+        let previous = tracker.state.replace(candidate);
+        tracker.pending = None;
+        Some(DoorTransition {
+            previous,
+            current: candidate,
+            acceleration,
+        })
+    }
+}
+
+fn classify(door: &DoorConfig, acceleration: i16) -> Option<DoorState> {
+    let threshold = door.threshold_mg.unsigned_abs();
+    if acceleration.unsigned_abs() < threshold {
+        return None;
+    }
+    let positive = acceleration > 0;
+    if positive == door.closed_positive {
+        Some(DoorState::Closed)
+    } else {
+        Some(DoorState::Open)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DoorClassifier, DoorState};
+    use crate::RuuviV2;
+    use crate::config::{Axis, DoorConfig};
+    use chrono::Utc;
+
+    const MAC: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+    fn reading(acc_z: i16) -> RuuviV2 {
+        RuuviV2 {
+            acc_z: Some(acc_z),
+            ..RuuviV2::sample(MAC, 0, Utc::now())
+        }
+    }
+
+    #[test]
+    fn test_door_flip_debounced() {
+        let classifier = DoorClassifier::new(&[DoorConfig {
+            mac: MAC,
+            axis: Axis::Z,
+            closed_positive: true,
+            threshold_mg: 500,
+            debounce: 2,
+        }]);
+
+        assert!(classifier.observe(&reading(1000)).is_none());
+        let t = classifier.observe(&reading(1000)).unwrap();
+        assert_eq!(t.current, DoorState::Closed);
+        assert_eq!(t.previous, None);
+
+        // Dead band and a single flipped sample don't change the state
+        assert!(classifier.observe(&reading(100)).is_none());
+        assert!(classifier.observe(&reading(-1000)).is_none());
+        let t = classifier.observe(&reading(-1000)).unwrap();
+        assert_eq!(t.current, DoorState::Open);
+        assert_eq!(t.previous, Some(DoorState::Closed));
+    }
+
+    #[test]
+    fn test_door_ignores_other_tags_and_missing_axis() {
+        let classifier = DoorClassifier::new(&[DoorConfig {
+            mac: MAC,
+            axis: Axis::X,
+            closed_positive: false,
+            threshold_mg: 500,
+            debounce: 0,
+        }]);
+
+        let other = RuuviV2 {
+            mac: [6, 5, 4, 3, 2, 1],
+            ..reading(0)
+        };
+        assert!(classifier.observe(&other).is_none());
+        let no_axis = RuuviV2 {
+            acc_x: None,
+            ..reading(0)
+        };
+        assert!(classifier.observe(&no_axis).is_none());
+
+        // Debounce 0 acts as 1, a negative closed side flips the states
+        let closed = RuuviV2 {
+            acc_x: Some(-800),
+            ..reading(0)
+        };
+        let t = classifier.observe(&closed).unwrap();
+        assert_eq!(t.current, DoorState::Closed);
+        assert_eq!(t.acceleration, -800);
+        assert!(classifier.observe(&closed).is_none());
+    }
+}
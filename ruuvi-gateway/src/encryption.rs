@@ -0,0 +1,95 @@
+use crate::config::TagKeyConfig;
+use crate::mac::format_mac;
+use aes::Aes128;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use anyhow::{Context, anyhow};
+use ruuvi_schema::{RuuviRawV2, RuuviRawV8};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+
+/// AES-128 keys of tags sending encrypted format 8
+pub struct TagKeys {
+    ciphers: HashMap<[u8; 6], Aes128>,
+}
+
+impl TagKeys {
+    pub fn new(keys: &[TagKeyConfig]) -> Self {
+        Self {
+            ciphers: keys
+                .iter()
+                .map(|k| (k.mac, Aes128::new(&GenericArray::from(k.key))))
+                .collect(),
+        }
+    }
+
+    /// Decrypt a format 8 packet into a format 5 reading without acceleration
+    pub fn decrypt(&self, raw: &RuuviRawV8) -> Result<RuuviRawV2, anyhow::Error> {
+        let mac = format_mac(&raw.mac);
+        let cipher = self
+            .ciphers
+            .get(&raw.mac)
+            .ok_or_else(|| anyhow!("No key for encrypted tag {mac}"))?;
+        let mut block = GenericArray::from(raw.encrypted);
+        cipher.decrypt_block(&mut block);
+        raw.decode(&block.into())
+            .map_err(|e| anyhow!("Failed to decrypt {mac}: {e}"))
+    }
+}
+
+pub fn parse_key(s: &str) -> Result<[u8; 16], anyhow::Error> {
+    if s.len() != 32 {
+        return Err(anyhow!(
+            "Tag key must be 32 hex characters, got {}",
+            s.len()
+        ));
+    }
+    let mut key = [0u8; 16];
+    for (byte, pair) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).context("Tag key isn't hex")?;
+        *byte = u8::from_str_radix(pair, 16).context("Tag key isn't hex")?;
+    }
+    Ok(key)
+}
+
+pub fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 16], D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_key(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TagKeys, parse_key};
+    use crate::config::TagKeyConfig;
+    use aes::Aes128;
+    use aes::cipher::generic_array::GenericArray;
+    use aes::cipher::{BlockEncrypt, KeyInit};
+    use ruuvi_schema::{RuuviRawV8, crc8};
+
+    #[test]
+    fn decrypts_with_the_tag_key() {
+        let mac = [1, 2, 3, 4, 5, 6];
+        let key = parse_key("000102030405060708090a0b0c0d0e0f").unwrap();
+        let mut plaintext = [0u8; 16];
+        // 21.5 °C, sequence 205
+        plaintext[..2].copy_from_slice(&4300i16.to_be_bytes());
+        plaintext[9..11].copy_from_slice(&205u16.to_be_bytes());
+        let mut block = GenericArray::from(plaintext);
+        Aes128::new(&GenericArray::from(key)).encrypt_block(&mut block);
+        let raw = RuuviRawV8 {
+            encrypted: block.into(),
+            crc: crc8(&plaintext),
+            mac,
+            timestamp: None,
+            rssi: -60,
+        };
+
+        let keys = TagKeys::new(&[TagKeyConfig { mac, key }]);
+        let v2 = keys.decrypt(&raw).unwrap();
+        assert_eq!((v2.temp, v2.measurement_seq, v2.mac), (4300, 205, mac));
+
+        let wrong = TagKeys::new(&[TagKeyConfig { mac, key: [0; 16] }]);
+        assert!(wrong.decrypt(&raw).is_err());
+        assert!(TagKeys::new(&[]).decrypt(&raw).is_err());
+    }
+}
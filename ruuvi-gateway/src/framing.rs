@@ -0,0 +1,86 @@
+use ruuvi_schema::ack::{ACK_FRAME, ACK_LEN};
+use ruuvi_schema::ota::OTA_FRAME;
+use ruuvi_schema::protocol::Message;
+use ruuvi_schema::time::TIME_FRAME;
+
+/// How a listener frames its transport messages, told apart by the first one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Postcard encoded [`Message`]s
+    Envelope,
+    /// Listeners predating the envelope. Readings are bare `RuuviRaw`s, the
+    /// rest is led by its OTA, time or ack byte.
+    Legacy,
+}
+
+impl Framing {
+    pub fn decode<'a>(self, payload: &'a [u8]) -> Result<Message<'a>, postcard::Error> {
+        match self {
+            Self::Envelope => postcard::from_bytes(payload),
+            Self::Legacy => match payload {
+                [TIME_FRAME] => Ok(Message::TimeSyncRequest),
+                [OTA_FRAME, uplink @ ..] => postcard::from_bytes(uplink).map(Message::OtaUplink),
+                _ => postcard::from_bytes(payload).map(Message::Measurement),
+            },
+        }
+    }
+
+    pub fn encode<'b>(
+        self,
+        message: &Message,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], anyhow::Error> {
+        if self == Self::Envelope {
+            return Ok(postcard::to_slice(message, buf)?);
+        }
+        let len = match message {
+            Message::TimeSyncResponse { unix_ms } => {
+                buf[0] = TIME_FRAME;
+                buf[1..9].copy_from_slice(&unix_ms.to_be_bytes());
+                9
+            }
+            Message::Ack(acks) => {
+                buf[0] = ACK_FRAME;
+                for (chunk, ack) in buf[1..].chunks_mut(ACK_LEN).zip(acks) {
+                    chunk.copy_from_slice(&ack.to_bytes());
+                }
+                1 + acks.len() * ACK_LEN
+            }
+            Message::OtaDownlink(downlink) => {
+                buf[0] = OTA_FRAME;
+                1 + postcard::to_slice(downlink, &mut buf[1..])?.len()
+            }
+            message => anyhow::bail!("{message:?} has no legacy frame"),
+        };
+        Ok(&buf[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Framing;
+    use ruuvi_schema::ack::{self, ACK_FRAME, Ack};
+    use ruuvi_schema::protocol::Message;
+    use ruuvi_schema::time::TIME_FRAME;
+
+    #[test]
+    fn legacy_frames_map_to_messages() {
+        let legacy = Framing::Legacy;
+        assert_eq!(legacy.decode(&[TIME_FRAME]), Ok(Message::TimeSyncRequest));
+
+        let mut buf = [0u8; 64];
+        let reply = legacy
+            .encode(&Message::TimeSyncResponse { unix_ms: 0x0102 }, &mut buf)
+            .unwrap();
+        assert_eq!(reply, [TIME_FRAME, 0, 0, 0, 0, 0, 0, 1, 2]);
+
+        let ack = Ack {
+            mac: [1, 2, 3, 4, 5, 6],
+            seq: 7,
+        };
+        let acks = heapless::Vec::from_slice(&[ack, ack]).unwrap();
+        let reply = legacy.encode(&Message::Ack(acks), &mut buf).unwrap();
+        assert_eq!(reply[0], ACK_FRAME);
+        assert!(ack::decode(&reply[1..]).eq([ack, ack]));
+    }
+}
@@ -0,0 +1,326 @@
+use crate::latest::LatestReading;
+use crate::mac::format_mac;
+use crate::timezone::local_midnight_days_ago;
+use crate::{AppState, Ruuvi, battery};
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Schema, SimpleObject};
+use axum::extract::State;
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use std::sync::Arc;
+
+pub type GatewaySchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+pub fn router(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(state)
+        .finish();
+    Router::new().route(
+        "/graphql",
+        get(graphiql).post(move |request| execute(schema.clone(), request)),
+    )
+}
+
+async fn execute(
+    schema: GatewaySchema,
+    Json(request): Json<async_graphql::Request>,
+) -> Json<async_graphql::Response> {
+    Json(schema.execute(request).await)
+}
+
+async fn graphiql(State(_): State<Arc<AppState>>) -> Html<String> {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+/// Flat view over tag and air readings, format specific fields are null when absent
+#[derive(SimpleObject)]
+struct Reading {
+    mac: String,
+    /// Registered name of the tag
+    name: Option<String>,
+    format: &'static str,
+    model: &'static str,
+    timestamp: DateTime<Utc>,
+    listener: String,
+    location: Option<String>,
+    temperature: Option<f32>,
+    dew_point_temperature: Option<f64>,
+    relative_humidity: Option<f32>,
+    absolute_humidity: Option<f64>,
+    pressure: Option<u32>,
+    rssi: i8,
+    tx_power: Option<i8>,
+    measurement_sequence: Option<u32>,
+    acceleration_x: Option<i16>,
+    acceleration_y: Option<i16>,
+    acceleration_z: Option<i16>,
+    battery_voltage: Option<f32>,
+    movement_counter: Option<u8>,
+    pm1_0: Option<f32>,
+    pm2_5: Option<f32>,
+    pm4_0: Option<f32>,
+    pm10_0: Option<f32>,
+    co2: Option<u16>,
+    voc_index: Option<u16>,
+    nox_index: Option<u16>,
+    luminosity: Option<f32>,
+}
+
+impl From<LatestReading> for Reading {
+    fn from(reading: LatestReading) -> Self {
+        let LatestReading {
+            name,
+            listener,
+            location,
+            model,
+            data,
+        } = reading;
+        match data {
+            Ruuvi::V2(v2) => Self {
+                mac: format_mac(&v2.mac),
+                format: "v2",
+                model: model.as_str(),
+                timestamp: v2.timestamp,
+                name,
+                listener,
+                location,
+                temperature: v2.temp,
+                dew_point_temperature: v2.dew_point_temp,
+                relative_humidity: v2.rel_humidity,
+                absolute_humidity: v2.abs_humidity,
+                pressure: v2.abs_pressure,
+                rssi: v2.rssi,
+                tx_power: v2.tx_power,
+                measurement_sequence: Some(v2.measurement_seq as u32),
+                acceleration_x: v2.acc_x,
+                acceleration_y: v2.acc_y,
+                acceleration_z: v2.acc_z,
+                battery_voltage: v2.battery_voltage,
+                movement_counter: v2.movement_counter,
+                pm1_0: None,
+                pm2_5: None,
+                pm4_0: None,
+                pm10_0: None,
+                co2: None,
+                voc_index: None,
+                nox_index: None,
+                luminosity: None,
+            },
+            Ruuvi::E1(e1) => Self {
+                mac: format_mac(&e1.mac),
+                format: "e1",
+                model: model.as_str(),
+                timestamp: e1.timestamp,
+                name,
+                listener,
+                location,
+                temperature: e1.temp,
+                dew_point_temperature: e1.dew_point_temp,
+                relative_humidity: e1.rel_humidity,
+                absolute_humidity: e1.abs_humidity,
+                pressure: e1.abs_pressure,
+                rssi: e1.rssi,
+                tx_power: Some(e1.tx_power),
+                measurement_sequence: Some(e1.measurement_seq),
+                acceleration_x: None,
+                acceleration_y: None,
+                acceleration_z: None,
+                battery_voltage: None,
+                movement_counter: None,
+                pm1_0: e1.pm1_0,
+                pm2_5: e1.pm2_5,
+                pm4_0: e1.pm4_0,
+                pm10_0: e1.pm10_0,
+                co2: e1.co2,
+                voc_index: e1.voc_index,
+                nox_index: e1.nox_index,
+                luminosity: e1.luminosity,
+            },
+            Ruuvi::V6(v6) => Self {
+                mac: format_mac(&v6.mac),
+                format: "v6",
+                model: model.as_str(),
+                timestamp: v6.timestamp,
+                name,
+                listener,
+                location,
+                temperature: v6.temp,
+                dew_point_temperature: v6.dew_point_temp,
+                relative_humidity: v6.rel_humidity,
+                absolute_humidity: v6.abs_humidity,
+                pressure: v6.abs_pressure,
+                rssi: v6.rssi,
+                tx_power: None,
+                measurement_sequence: Some(v6.measurement_seq as u32),
+                acceleration_x: None,
+                acceleration_y: None,
+                acceleration_z: None,
+                battery_voltage: None,
+                movement_counter: None,
+                pm1_0: None,
+                pm2_5: v6.pm2_5,
+                pm4_0: None,
+                pm10_0: None,
+                co2: v6.co2,
+                voc_index: v6.voc_index,
+                nox_index: v6.nox_index,
+                luminosity: v6.luminosity,
+            },
+            Ruuvi::V1(v1) => Self {
+                mac: format_mac(&v1.mac),
+                format: "v1",
+                model: model.as_str(),
+                timestamp: v1.timestamp,
+                name,
+                listener,
+                location,
+                temperature: v1.temp,
+                dew_point_temperature: v1.dew_point_temp,
+                relative_humidity: v1.rel_humidity,
+                absolute_humidity: v1.abs_humidity,
+                pressure: v1.abs_pressure,
+                rssi: v1.rssi,
+                tx_power: None,
+                measurement_sequence: None,
+                acceleration_x: v1.acc_x,
+                acceleration_y: v1.acc_y,
+                acceleration_z: v1.acc_z,
+                battery_voltage: v1.battery_voltage,
+                movement_counter: None,
+                pm1_0: None,
+                pm2_5: None,
+                pm4_0: None,
+                pm10_0: None,
+                co2: None,
+                voc_index: None,
+                nox_index: None,
+                luminosity: None,
+            },
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct DailySummary {
+    day: NaiveDate,
+    temp_min: Option<f32>,
+    temp_max: Option<f32>,
+    temp_avg: Option<f64>,
+    humidity_min: Option<f32>,
+    humidity_max: Option<f32>,
+    humidity_avg: Option<f64>,
+    samples: i64,
+}
+
+#[derive(SimpleObject)]
+struct DoorEvent {
+    mac: String,
+    timestamp: DateTime<Utc>,
+    state: String,
+    previous_state: Option<String>,
+}
+
+#[derive(SimpleObject)]
+struct BatteryForecast {
+    voltage: f64,
+    slope_mv_per_day: f64,
+    temp_coefficient_mv_per_c: f64,
+    replacement_date: Option<DateTime<Utc>>,
+    samples: usize,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Latest reading of every tag heard since startup
+    async fn tags(&self, ctx: &Context<'_>) -> Vec<Reading> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        state.latest.all().into_iter().map(Reading::from).collect()
+    }
+
+    async fn latest(
+        &self,
+        ctx: &Context<'_>,
+        mac: String,
+    ) -> async_graphql::Result<Option<Reading>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let mac = state.registry.resolve(&mac).ok_or("Unknown tag")?;
+        Ok(state.latest.get(&mac).map(Reading::from))
+    }
+
+    /// Daily aggregates bucketed at local midnight of `tz`
+    async fn daily(
+        &self,
+        ctx: &Context<'_>,
+        mac: String,
+        #[graphql(default = 7)] days: u64,
+        tz: Option<String>,
+    ) -> async_graphql::Result<Vec<DailySummary>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let mac = state.registry.resolve(&mac).ok_or("Unknown tag")?;
+        let tz = match tz {
+            Some(tz) => tz.parse::<Tz>()?,
+            None => state.config.api.timezone,
+        };
+        let since = local_midnight_days_ago(tz, days).ok_or("Invalid day range")?;
+        let rows = state.storage.daily_summary(mac, tz, since).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DailySummary {
+                day: row.day,
+                temp_min: row.temp_min,
+                temp_max: row.temp_max,
+                temp_avg: row.temp_avg,
+                humidity_min: row.humidity_min,
+                humidity_max: row.humidity_max,
+                humidity_avg: row.humidity_avg,
+                samples: row.samples,
+            })
+            .collect())
+    }
+
+    async fn battery(
+        &self,
+        ctx: &Context<'_>,
+        mac: String,
+    ) -> async_graphql::Result<Option<BatteryForecast>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let mac = state.registry.resolve(&mac).ok_or("Unknown tag")?;
+        let forecast = battery::forecast_tag(state, mac).await?;
+        Ok(forecast.map(|f| BatteryForecast {
+            voltage: f.voltage,
+            slope_mv_per_day: f.slope_mv_per_day,
+            temp_coefficient_mv_per_c: f.temp_coefficient_mv_per_c,
+            replacement_date: f.replacement_date,
+            samples: f.samples,
+        }))
+    }
+
+    /// Door open/close transitions, newest first
+    async fn door_events(
+        &self,
+        ctx: &Context<'_>,
+        mac: Option<String>,
+        #[graphql(default = 100)] limit: i64,
+    ) -> async_graphql::Result<Vec<DoorEvent>> {
+        let state = ctx.data_unchecked::<Arc<AppState>>();
+        let mac = mac
+            .as_deref()
+            .map(|mac| state.registry.resolve(mac).ok_or("Unknown tag"))
+            .transpose()?;
+        let rows = state.storage.door_events(mac, limit).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DoorEvent {
+                mac: format_mac(&row.mac_address.bytes()),
+                timestamp: row.recorded_at,
+                state: row.state,
+                previous_state: row.previous_state,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,57 @@
+use axum::body::Bytes;
+use axum::http::{Request, StatusCode, Uri, header};
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// Longest a request may take, connecting included
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POST `body` to a plain `http://` URL over a fresh connection. The gateway
+/// has no TLS stack, an HTTPS service has to be reached through a local proxy.
+/// Returns the status and the response body.
+pub async fn post(
+    url: &Uri,
+    headers: &[(header::HeaderName, &str)],
+    body: String,
+) -> Result<(StatusCode, String), anyhow::Error> {
+    tokio::time::timeout(TIMEOUT, send(url, headers, body))
+        .await
+        .map_err(|_| anyhow::anyhow!("{url} didn't answer in {}s", TIMEOUT.as_secs()))?
+}
+
+async fn send(
+    url: &Uri,
+    headers: &[(header::HeaderName, &str)],
+    body: String,
+) -> Result<(StatusCode, String), anyhow::Error> {
+    if url.scheme_str() != Some("http") {
+        anyhow::bail!("Only http:// URLs are supported, got {url}");
+    }
+    let host = url
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("No host in {url}"))?;
+    let port = url.port_u16().unwrap_or(80);
+    let stream = TcpStream::connect((host, port)).await?;
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    // Drives the connection until the response is read
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!("HTTP connection closed: {e}");
+        }
+    });
+
+    let mut request = Request::post(url.path_and_query().map_or("/", |p| p.as_str()))
+        .header(header::HOST, url.authority().map_or(host, |a| a.as_str()));
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    let response = sender
+        .send_request(request.body(Full::new(Bytes::from(body)))?)
+        .await?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
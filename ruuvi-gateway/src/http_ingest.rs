@@ -0,0 +1,195 @@
+//! `POST /api/ruuvi`, where listeners built with the `transport-http` feature
+//! send their readings as JSON. The body is signed with an HMAC-SHA256 of a
+//! pre-shared key, so curl and a shell can feed the gateway as well.
+
+use crate::database::ListenerRow;
+use crate::dedup::AckHandle;
+use crate::{AppState, Ruuvi, ingest, listen, raw_payload};
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use chrono::Utc;
+use ruuvi_schema::RuuviRaw;
+use ruuvi_schema::ack::Ack;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Longest a request waits for its reading to be stored
+const STORE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Asked of a listener whose reading wasn't stored
+const RETRY_AFTER_SECS: u64 = 5;
+
+struct HttpIngest {
+    state: Arc<AppState>,
+    /// Pre-shared key each listener was last recorded with in `listeners`
+    recorded: Mutex<HashMap<IpAddr, String>>,
+}
+
+pub async fn serve(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    if !state.config.http_ingest.enabled {
+        return Ok(());
+    }
+    let listen = state.config.http_ingest.listen.clone();
+    let app = Router::new()
+        .route("/api/ruuvi", post(receive))
+        .with_state(Arc::new(HttpIngest {
+            state,
+            recorded: Mutex::default(),
+        }));
+
+    let listener = listen::bind(&listen).await?;
+    tracing::info!("HTTP ingestion listening on {listen}");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Responds once the reading is stored, like the acknowledgements of the
+/// Noise transport. A listener resends after a 503.
+async fn receive(
+    State(http): State<Arc<HttpIngest>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let state = &http.state;
+    let ip = listen::canonical(peer).ip();
+    if state.bans.is_banned(ip) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let Some(psk) = signature(&headers).and_then(|sig| state.psks.verify(&body, &sig)) else {
+        if state.bans.failure(ip) {
+            tracing::warn!(
+                "Banning {ip} for {}s after repeated authentication failures",
+                state.config.handshake.ban_secs
+            );
+        }
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    state.bans.success(ip);
+    let listener = ip.to_string();
+    http.record(&listener, ip, psk).await;
+
+    let raw: RuuviRaw = match serde_json::from_slice(&body) {
+        Ok(raw) => raw,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let (sender, mut acks) = mpsc::unbounded_channel();
+    let ack = AckHandle {
+        ack: Ack::of(&raw),
+        sender,
+    };
+    let raw_payload = raw_payload(state, &raw);
+    // The listener has no clock over HTTP, readings are timestamped on arrival
+    let mut data = match Ruuvi::from_raw(
+        raw,
+        Utc::now(),
+        &state.tag_keys,
+        state.config.humidity.formula,
+    ) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::warn!("{e}");
+            return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response();
+        }
+    };
+    state.calibrations.apply(&mut data);
+    tracing::debug!("Data: {data:?}");
+    ingest(state, &listener, data, raw_payload, None, Some(ack));
+
+    // A reading that failed to store drops its acknowledgement unsent
+    match tokio::time::timeout(STORE_TIMEOUT, acks.recv()).await {
+        Ok(Some(_)) => StatusCode::NO_CONTENT.into_response(),
+        _ => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        )
+            .into_response(),
+    }
+}
+
+impl HttpIngest {
+    /// Create the listener's row on its first request, and again when it
+    /// switches keys
+    async fn record(&self, listener: &str, address: IpAddr, psk: &str) {
+        if let Some(recorded) = self.recorded.lock().unwrap().get(&address)
+            && recorded == psk
+        {
+            return;
+        }
+        let row = ListenerRow {
+            name: listener.to_owned(),
+            identified: false,
+            address,
+            psk: psk.to_owned(),
+            firmware: None,
+        };
+        match self.state.storage.upsert_listener(&row).await {
+            Ok(()) => {
+                self.recorded
+                    .lock()
+                    .unwrap()
+                    .insert(address, psk.to_owned());
+            }
+            Err(e) => tracing::error!("Failed to record listener {listener}: {e}"),
+        }
+    }
+}
+
+/// `Authorization: HMAC-SHA256 <64 hex characters>`
+fn signature(headers: &HeaderMap) -> Option<[u8; 32]> {
+    parse_signature(headers.get(header::AUTHORIZATION)?.to_str().ok()?)
+}
+
+/// `HMAC-SHA256 <64 hex characters>`, also signing the readings of MQTT listeners
+pub fn parse_signature(value: &str) -> Option<[u8; 32]> {
+    let hex = value.strip_prefix("HMAC-SHA256 ")?.trim();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut signature = [0u8; 32];
+    for (byte, pair) in signature.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::signature;
+    use crate::config::PskConfig;
+    use crate::listener_keys::Psks;
+    use axum::http::{HeaderMap, HeaderValue, header};
+
+    #[test]
+    fn verifies_the_signature_with_each_psk() {
+        let psks = Psks::new(
+            Some([b'a'; 32]),
+            &[PskConfig {
+                name: "sauna".to_owned(),
+                key: [b'b'; 32],
+            }],
+        )
+        .unwrap();
+        let body = br#"{"V2":{}}"#;
+        // printf '{"V2":{}}' | openssl dgst -sha256 -hmac bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb
+        let signed = "HMAC-SHA256 cfe4e1b975344a5481e04cc3de311628a300d41516e73d15d5e962fad4eae69c";
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static(signed));
+        let sig = signature(&headers).unwrap();
+        assert_eq!(psks.verify(body, &sig), Some("sauna"));
+        assert_eq!(psks.verify(b"{}", &sig), None);
+
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer x"));
+        assert_eq!(signature(&headers), None);
+    }
+}
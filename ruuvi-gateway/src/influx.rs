@@ -0,0 +1,199 @@
+use crate::Ruuvi;
+use crate::config::InfluxConfig;
+use crate::http;
+use crate::mac::format_mac;
+use crate::sink::{Buffering, Sink, StoredReading};
+use axum::http::{StatusCode, Uri, header};
+use futures_util::future::BoxFuture;
+use serde_json::Value;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Writes readings to InfluxDB 2.x in line protocol, one measurement per format
+/// tagged with the MAC and the listener that heard the reading
+pub struct InfluxSink {
+    url: Uri,
+    authorization: Option<String>,
+    measurement_prefix: String,
+}
+
+impl InfluxSink {
+    /// `None` when no InfluxDB is configured
+    pub fn new(config: &InfluxConfig) -> Result<Option<(Self, Buffering)>, anyhow::Error> {
+        let Some(url) = &config.url else {
+            return Ok(None);
+        };
+        if config.org.is_empty() {
+            anyhow::bail!("[influx] needs the org to write to");
+        }
+        let url = format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=ms",
+            url.trim_end_matches('/'),
+            encode_query(&config.org),
+            encode_query(&config.bucket)
+        )
+        .parse()?;
+        let sink = Self {
+            url,
+            authorization: config.token.as_ref().map(|token| format!("Token {token}")),
+            measurement_prefix: config.measurement_prefix.clone(),
+        };
+        let buffering = Buffering {
+            batch_size: config.batch_size,
+            flush: Duration::from_millis(config.flush_ms),
+        };
+        Ok(Some((sink, buffering)))
+    }
+}
+
+impl Sink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
+    }
+
+    fn write<'a>(&'a self, batch: &'a [StoredReading]) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let lines: Vec<_> = batch
+                .iter()
+                .filter_map(|reading| {
+                    line(&self.measurement_prefix, &reading.data, &reading.listener)
+                })
+                .collect();
+            if lines.is_empty() {
+                return Ok(());
+            }
+            let mut headers = vec![(header::CONTENT_TYPE, "text/plain; charset=utf-8")];
+            if let Some(authorization) = &self.authorization {
+                headers.push((header::AUTHORIZATION, authorization));
+            }
+            match http::post(&self.url, &headers, lines.join("\n")).await? {
+                (status, _) if status.is_success() => Ok(()),
+                // Retrying lines InfluxDB refuses won't help
+                (status, response)
+                    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    tracing::error!(
+                        "InfluxDB rejected {} lines: {status} {response}",
+                        lines.len()
+                    );
+                    Ok(())
+                }
+                (status, response) => Err(anyhow::anyhow!("{status} {response}")),
+            }
+        })
+    }
+}
+
+/// Line protocol of a reading with its millisecond timestamp. Fields are the
+/// reading's JSON fields, the ones the tag reports as not available are left out.
+fn line(prefix: &str, data: &Ruuvi, listener: &str) -> Option<String> {
+    let (format, fields) = match data {
+        Ruuvi::V2(v2) => ("v2", serde_json::to_value(v2)),
+        Ruuvi::E1(e1) => ("e1", serde_json::to_value(e1)),
+        Ruuvi::V1(v1) => ("v1", serde_json::to_value(v1)),
+        Ruuvi::V6(v6) => ("v6", serde_json::to_value(v6)),
+    };
+    let Ok(Value::Object(fields)) = fields else {
+        return None;
+    };
+
+    let mut line = format!(
+        "{prefix}_{format},mac={},listener={}",
+        format_mac(&data.mac()),
+        escape_tag(listener)
+    );
+    let mut separator = ' ';
+    for (key, value) in fields {
+        if key == "mac" || key == "timestamp" {
+            continue;
+        }
+        let value = match value {
+            Value::Number(n) if n.is_f64() => n.to_string(),
+            Value::Number(n) => format!("{n}i"),
+            Value::Bool(b) => b.to_string(),
+            _ => continue,
+        };
+        let _ = write!(line, "{separator}{key}={value}");
+        separator = ',';
+    }
+    if separator == ' ' {
+        return None;
+    }
+    let _ = write!(line, " {}", data.timestamp().timestamp_millis());
+    Some(line)
+}
+
+/// Tag values can't hold unescaped commas, equal signs or spaces
+fn escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_query, line};
+    use crate::{Ruuvi, RuuviV2};
+    use chrono::DateTime;
+
+    fn reading() -> RuuviV2 {
+        RuuviV2 {
+            temp: Some(20.5),
+            ..RuuviV2::sample(
+                [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+                7,
+                DateTime::from_timestamp_millis(1_700_000_000_123).unwrap(),
+            )
+        }
+    }
+
+    #[test]
+    fn formats_line_protocol() {
+        assert_eq!(
+            line("ruuvi", &Ruuvi::V2(reading()), "living room").unwrap(),
+            "ruuvi_v2,mac=AA:BB:CC:DD:EE:FF,listener=living\\ room \
+            abs_humidity=8.0,abs_pressure=100000i,acc_x=0i,acc_y=0i,acc_z=1000i,\
+            battery_voltage=3.0,dew_point_temp=10.0,measurement_seq=7i,movement_counter=0i,\
+            movement_event=false,rel_humidity=50.0,rssi=-60i,temp=20.5,tx_power=4i 1700000000123"
+        );
+    }
+
+    #[test]
+    fn leaves_out_unavailable_values() {
+        let data = Ruuvi::V2(RuuviV2 {
+            dew_point_temp: None,
+            rel_humidity: None,
+            abs_humidity: None,
+            acc_x: None,
+            acc_y: None,
+            acc_z: None,
+            battery_voltage: None,
+            tx_power: None,
+            movement_counter: None,
+            ..reading()
+        });
+        assert_eq!(
+            line("ruuvi", &data, "hall,2=a").unwrap(),
+            "ruuvi_v2,mac=AA:BB:CC:DD:EE:FF,listener=hall\\,2\\=a \
+            abs_pressure=100000i,measurement_seq=7i,movement_event=false,rssi=-60i,temp=20.5 1700000000123"
+        );
+        assert_eq!(encode_query("my org/ä"), "my%20org%2F%C3%A4");
+    }
+}
@@ -0,0 +1,40 @@
+use crate::Ruuvi;
+use ruuvi_schema::TagModel;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LatestReading {
+    /// Registered name of the tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub listener: String,
+    pub location: Option<String>,
+    pub model: TagModel,
+    #[serde(flatten)]
+    pub data: Ruuvi,
+}
+
+/// Latest stored reading per tag, served by the API
+#[derive(Default)]
+pub struct LatestStore {
+    readings: RwLock<HashMap<[u8; 6], LatestReading>>,
+}
+
+impl LatestStore {
+    pub fn update(&self, reading: LatestReading) {
+        let mac = reading.data.mac();
+        self.readings.write().unwrap().insert(mac, reading);
+    }
+
+    pub fn get(&self, mac: &[u8; 6]) -> Option<LatestReading> {
+        self.readings.read().unwrap().get(mac).cloned()
+    }
+
+    pub fn all(&self) -> Vec<LatestReading> {
+        let mut readings: Vec<_> = self.readings.read().unwrap().values().cloned().collect();
+        readings.sort_by_key(|r| r.data.mac());
+        readings
+    }
+}
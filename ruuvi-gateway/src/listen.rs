@@ -0,0 +1,59 @@
+//! Listening sockets of the ingestion endpoints. An IPv6 wildcard address
+//! takes IPv4 connections as well, on platforms that default to IPv6 only too.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::io;
+use tokio::net::TcpListener;
+
+/// Binds `listen`, dual stack for `[::]`. Without IPv6 on the host that falls
+/// back to `0.0.0.0` on the same port.
+pub async fn bind(listen: &str) -> io::Result<TcpListener> {
+    let Ok(addr) = listen.parse::<SocketAddr>() else {
+        return TcpListener::bind(listen).await;
+    };
+    if !(addr.is_ipv6() && addr.ip().is_unspecified()) {
+        return TcpListener::bind(addr).await;
+    }
+    match dual_stack(addr) {
+        Ok(listener) => Ok(listener),
+        Err(e) => {
+            tracing::warn!("No IPv6 for {listen}, listening on IPv4 only: {e}");
+            TcpListener::bind((Ipv4Addr::UNSPECIFIED, addr.port())).await
+        }
+    }
+}
+
+fn dual_stack(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Peer address with IPv4 ones unmapped, so a listener is known by the same
+/// address over either stack
+pub fn canonical(peer: SocketAddr) -> SocketAddr {
+    SocketAddr::new(peer.ip().to_canonical(), peer.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, canonical};
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn accepts_ipv4_on_the_ipv6_wildcard() {
+        let listener = bind("[::]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(canonical(peer), client.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config::{ListenerKeyConfig, PskConfig};
+use anyhow::{Context, anyhow};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer};
+use sha2::Sha256;
+use std::collections::HashMap;
+
+/// Name of the pre-shared key given with `--psk`, `AUTH_KEY` or `psk_file`
+pub const DEFAULT_PSK: &str = "default";
+
+/// Static keys of trusted listeners, checked against the remote static key of the handshake
+pub struct ListenerKeys {
+    names: HashMap<[u8; 32], String>,
+}
+
+impl ListenerKeys {
+    pub fn new(listeners: &[ListenerKeyConfig]) -> Self {
+        Self {
+            names: listeners
+                .iter()
+                .map(|l| (l.public_key, l.name.clone()))
+                .collect(),
+        }
+    }
+
+    /// Name of the listener holding `remote_static`. `None` when no keys are
+    /// pinned, any listener knowing the pre-shared key is accepted then.
+    pub fn identify(&self, remote_static: Option<&[u8]>) -> Result<Option<&str>, anyhow::Error> {
+        if self.names.is_empty() {
+            return Ok(None);
+        }
+        let key: [u8; 32] = remote_static
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| anyhow!("no static key"))?;
+        self.names
+            .get(&key)
+            .map(|name| Some(name.as_str()))
+            .ok_or_else(|| anyhow!("static key {} isn't pinned", hex(&key)))
+    }
+}
+
+/// Pre-shared keys listeners may authenticate with, each listener naming its
+/// key in its first handshake message. A listener is revoked by removing its
+/// key. The default has none, for a standalone gateway.
+#[derive(Default)]
+pub struct Psks {
+    keys: Vec<(String, [u8; 32])>,
+}
+
+impl Psks {
+    pub fn new(default: Option<[u8; 32]>, named: &[PskConfig]) -> Result<Self, anyhow::Error> {
+        let keys: Vec<_> = default
+            .map(|key| (DEFAULT_PSK.to_owned(), key))
+            .into_iter()
+            .chain(named.iter().map(|psk| (psk.name.clone(), psk.key)))
+            .collect();
+        if keys.is_empty() {
+            anyhow::bail!(
+                "No pre-shared key, set --psk, AUTH_KEY, --psk-file, [server] psk_file or [[noise.psks]]"
+            );
+        }
+        Ok(Self { keys })
+    }
+
+    /// Name and key of the PSK called `name`, the default one for listeners
+    /// naming none
+    pub fn get(&self, name: &str) -> Option<(&str, &[u8; 32])> {
+        let name = if name.is_empty() { DEFAULT_PSK } else { name };
+        self.keys
+            .iter()
+            .find(|(key_name, _)| key_name == name)
+            .map(|(name, key)| (name.as_str(), key))
+    }
+
+    /// Name of the key `body` was signed with, `signature` being its HMAC-SHA256.
+    /// Authenticates the readings posted to the HTTP ingestion endpoint.
+    pub fn verify(&self, body: &[u8], signature: &[u8]) -> Option<&str> {
+        self.keys.iter().find_map(|(name, key)| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key");
+            mac.update(body);
+            mac.verify_slice(signature).ok().map(|()| name.as_str())
+        })
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn parse_public_key(s: &str) -> Result<[u8; 32], anyhow::Error> {
+    if s.len() != 64 {
+        return Err(anyhow!(
+            "Listener public key must be 64 hex characters, got {}",
+            s.len()
+        ));
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(s.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).context("Listener public key isn't hex")?;
+        *byte = u8::from_str_radix(pair, 16).context("Listener public key isn't hex")?;
+    }
+    Ok(key)
+}
+
+/// 32 bytes, like the listener's `AUTH_KEY`
+pub fn parse_psk(key: String) -> Result<[u8; 32], anyhow::Error> {
+    let len = key.len();
+    key.into_bytes()
+        .try_into()
+        .map_err(|_| anyhow!("The pre-shared key must be exactly 32 bytes, got {len}"))
+}
+
+pub fn deserialize_psk<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+    parse_psk(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+pub fn deserialize_public_key<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<[u8; 32], D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_public_key(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ListenerKeys, Psks, parse_public_key};
+    use crate::config::{ListenerKeyConfig, PskConfig};
+    use crate::suite::Suite;
+    use snow::Builder;
+
+    #[test]
+    fn identifies_pinned_listeners_only() {
+        let public_key = parse_public_key(&"ab".repeat(32)).unwrap();
+        let keys = ListenerKeys::new(&[ListenerKeyConfig {
+            name: "kitchen".to_owned(),
+            public_key,
+        }]);
+        assert_eq!(keys.identify(Some(&public_key)).unwrap(), Some("kitchen"));
+        assert!(keys.identify(Some(&[0; 32])).is_err());
+        assert!(keys.identify(None).is_err());
+
+        let open = ListenerKeys::new(&[]);
+        assert_eq!(open.identify(Some(&[0; 32])).unwrap(), None);
+    }
+
+    #[test]
+    fn authenticates_with_the_named_psk() {
+        let psks = Psks::new(
+            Some([1; 32]),
+            &[PskConfig {
+                name: "kitchen".to_owned(),
+                key: [2; 32],
+            }],
+        )
+        .unwrap();
+        let handshake = |name: &str, listener_psk: &[u8; 32]| {
+            let params = Suite::ChaChaPoly.params();
+            let keys = || Builder::new(params.clone()).generate_keypair().unwrap();
+            let (listener_key, gateway_key) = (keys(), keys());
+            let mut listener = Builder::new(params.clone())
+                .local_private_key(&listener_key.private)?
+                .psk(3, listener_psk)?
+                .build_initiator()?;
+            let mut gateway = Builder::new(params.clone())
+                .local_private_key(&gateway_key.private)?
+                .build_responder()?;
+            let mut payload = [0u8; 256];
+            let mut message = [0u8; 256];
+            let len = listener.write_message(name.as_bytes(), &mut message)?;
+            let len = gateway.read_message(&message[..len], &mut payload)?;
+            let requested = std::str::from_utf8(&payload[..len]).unwrap();
+            let Some((psk, key)) = psks.get(requested) else {
+                return Ok(None);
+            };
+            gateway.set_psk(3, key)?;
+            let len = gateway.write_message(&[], &mut message)?;
+            listener.read_message(&message[..len], &mut payload)?;
+            let len = listener.write_message(b"hi", &mut message)?;
+            let len = gateway.read_message(&message[..len], &mut payload)?;
+            assert_eq!(&payload[..len], b"hi");
+            Ok::<_, snow::Error>(Some(psk.to_owned()))
+        };
+        assert_eq!(
+            handshake("kitchen", &[2; 32]).unwrap().as_deref(),
+            Some("kitchen")
+        );
+        // Listeners naming no key use the default one
+        assert_eq!(handshake("", &[1; 32]).unwrap().as_deref(), Some("default"));
+        assert_eq!(handshake("sauna", &[2; 32]).unwrap(), None);
+        assert!(handshake("kitchen", &[1; 32]).is_err());
+    }
+}
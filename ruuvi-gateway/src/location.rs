@@ -0,0 +1,57 @@
+use crate::config::ZoneConfig;
+use crate::dedup::Reception;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Estimates which zone a tag is in from the listeners that heard it.
+/// The tag is placed in the zone of the strongest listener, but it only moves
+/// once another zone beats the current one by `margin_db` to avoid flapping.
+pub struct Locator {
+    zones: HashMap<String, String>,
+    margin_db: i16,
+    current: Mutex<HashMap<[u8; 6], String>>,
+}
+
+impl Locator {
+    pub fn new(zones: &[ZoneConfig], margin_db: i16) -> Self {
+        let zones = zones
+            .iter()
+            .flat_map(|zone| {
+                zone.listeners
+                    .iter()
+                    .map(|listener| (listener.clone(), zone.name.clone()))
+            })
+            .collect();
+        Self {
+            zones,
+            margin_db,
+            current: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn locate(&self, mac: [u8; 6], receptions: &[Reception]) -> Option<String> {
+        // Strongest RSSI per zone
+        let mut zone_rssi: HashMap<&str, i8> = HashMap::new();
+        for reception in receptions {
+            if let Some(zone) = self.zones.get(&reception.listener) {
+                let rssi = zone_rssi.entry(zone).or_insert(reception.rssi);
+                *rssi = (*rssi).max(reception.rssi);
+            }
+        }
+        let (best_zone, best_rssi) = zone_rssi
+            .iter()
+            .max_by_key(|(_, rssi)| **rssi)
+            .map(|(zone, rssi)| (*zone, *rssi))?;
+
+        let mut current = self.current.lock().unwrap();
+        if let Some(current_zone) = current.get(&mac)
+            && let Some(current_rssi) = zone_rssi.get(current_zone.as_str())
+            && i16::from(best_rssi) - i16::from(*current_rssi) < self.margin_db
+        {
+            return Some(current_zone.clone());
+        }
+
+        current.insert(mac, best_zone.to_owned());
+        Some(best_zone.to_owned())
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn parse_mac(s: &str) -> Result<[u8; 6], anyhow::Error> {
+    let mut mac = [0u8; 6];
+    let mut parts = s.split([':', '-']);
+    for byte in mac.iter_mut() {
+        let part = parts
+            .next()
+            .ok_or_else(|| anyhow!("MAC address too short: {s}"))?;
+        *byte = u8::from_str_radix(part, 16)
+            .with_context(|| format!("Invalid MAC address byte {part:?} in {s}"))?;
+    }
+    if parts.next().is_some() {
+        return Err(anyhow!("MAC address too long: {s}"));
+    }
+    Ok(mac)
+}
+
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    format!(
+        "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+pub fn serialize<S: Serializer>(mac: &[u8; 6], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_mac(mac))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 6], D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_mac(&s).map_err(serde::de::Error::custom)
+}
+
+pub fn deserialize_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<[u8; 6]>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| parse_mac(s).map_err(serde::de::Error::custom))
+        .collect()
+}
@@ -1,204 +1,570 @@
+mod alerts;
+mod api;
+mod auth;
+mod bans;
+mod battery;
+mod bluetooth;
+mod calibration;
+mod cli;
+mod config;
 mod database;
+mod dedup;
+mod dev;
+mod door;
+mod encryption;
+mod framing;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod http;
+mod http_ingest;
+mod influx;
+mod latest;
+mod listen;
+mod listener_keys;
+mod location;
+mod mac;
+mod maintenance;
+mod mdns;
+mod metrics;
+mod mqtt;
+mod notify;
+mod official_gateway;
+mod offline;
+mod ota;
+mod outbox;
+mod pagination;
+mod quality;
+mod quarantine;
+mod registry;
+mod report;
+mod retention;
+mod rollup;
+mod sink;
+mod stats;
+mod stream;
+mod suite;
+mod tag_filter;
+mod timezone;
+mod units;
+mod web;
 
-use crate::database::{insert_data_e1, insert_data_v2};
+use crate::alerts::Alerts;
+use crate::bans::Bans;
+use crate::calibration::{Calibrations, Uncalibrated};
+use crate::cli::{Cli, Command};
+use crate::config::Config;
+use crate::database::{ListenerRow, Storage};
+use crate::dedup::{AckHandle, Deduplicator, Late, Pending, Submitted};
+use crate::door::DoorClassifier;
+use crate::encryption::TagKeys;
+use crate::framing::Framing;
+use crate::influx::InfluxSink;
+use crate::latest::{LatestReading, LatestStore};
+use crate::listener_keys::{ListenerKeys, Psks};
+use crate::location::Locator;
+use crate::mqtt::MqttSink;
+use crate::notify::Notifiers;
+use crate::offline::Watchdog;
+use crate::outbox::Outbox;
+use crate::quality::{Issues, QualityTracker};
+use crate::quarantine::{Failure, FailureTracker};
+use crate::registry::Registry;
+use crate::retention::Retention;
+use crate::sink::{
+    Buffering, DatabaseSink, Dispatcher, QueuedSink, Sink, StdoutSink, StoredReading,
+};
+use crate::stats::{ConnectionStats, Connections};
+use crate::suite::{Selection, Suite, read_selection};
+use crate::tag_filter::TagFilter;
 use chrono::{DateTime, Utc};
-use dotenvy_macro::dotenv;
-use ruuvi_schema::{RuuviRaw, RuuviRawE1, RuuviRawV2};
-use snow::Builder;
-use snow::params::NoiseParams;
-use sqlx::postgres::PgPoolOptions;
-use sqlx::{Pool, Postgres};
-use std::sync::LazyLock;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-
-const AUTH_KEY: &str = dotenv!("AUTH_KEY");
-const DATABASE_URI: &str = dotenv!("DATABASE_URI");
-
-static PARAMS: LazyLock<NoiseParams> =
-    LazyLock::new(|| "Noise_XXpsk3_25519_ChaChaPoly_SHA256".parse().unwrap());
-
-// Validate auth key length is 32 bytes
-const PSK_KEY: [u8; 32] = {
-    if AUTH_KEY.len() != 32 {
-        panic!("AUTH_KEY must be exactly 32 bytes");
-    }
-    const_str::to_byte_array!(AUTH_KEY)
+use clap::Parser;
+use ruuvi_schema::ack::Ack;
+use ruuvi_schema::convert::{self, AirValues, Comfort, HumidityFormula, TagValues};
+use ruuvi_schema::protocol::{
+    Hello, Message, PROTOCOL_VERSION, REKEY_PROTOCOL, RekeyPolicy, SCAN_SETTINGS_PROTOCOL,
+    Telemetry,
 };
+use ruuvi_schema::{RuuviRaw, RuuviRawE1, RuuviRawV1, RuuviRawV2, RuuviRawV6, TagModel};
+use serde::{Deserialize, Serialize};
+use snow::{Builder, TransportState};
+use socket2::{SockRef, TcpKeepalive};
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast, mpsc};
+use tokio::time::Instant;
 
-fn calculate_abs_humidity(temp: f32, rel_humidity: f32) -> f64 {
-    // https://en.wikipedia.org/wiki/Arden_Buck_equation
-    // TODO use enhancement factor
+/// How long format 6 readings of an Air are dropped after its last E1 reading
+const E1_PREFERENCE_SECS: i64 = 60;
+/// Readings buffered per `/live` client before it starts skipping
+const LIVE_CAPACITY: usize = 256;
+/// Listener protocols the gateway understands, older ones are refused
+const SUPPORTED_PROTOCOLS: std::ops::RangeInclusive<u16> = 1..=PROTOCOL_VERSION;
 
-    // Saturation vapor pressure in hPa
-    let ps_hpa = 6.1121f64
-        * ((18.678f64 - (temp as f64 / 234.5)) * (temp as f64 / (257.14 + temp as f64))).exp();
-    // In Pa
-    let ps = ps_hpa * 100.0;
-    // Actual vapor pressure
-    let pa = ps * (rel_humidity as f64 / 100.0);
-    // Absolute humidity in g/m^3
-    2.167 * pa / (temp as f64 + 273.15)
+pub struct AppState {
+    pub config: Config,
+    pub storage: Arc<dyn Storage>,
+    pub doors: DoorClassifier,
+    pub dedup: Deduplicator,
+    pub locator: Locator,
+    pub latest: LatestStore,
+    pub registry: Arc<Registry>,
+    pub notifiers: Notifiers,
+    pub alerts: Alerts,
+    pub watchdog: Watchdog,
+    pub retention: Retention,
+    pub connections: Arc<Connections>,
+    pub quality: QualityTracker,
+    pub tag_keys: TagKeys,
+    pub calibrations: Calibrations,
+    pub tag_filter: TagFilter,
+    /// Noise pre-shared keys of the listeners
+    pub psks: Psks,
+    pub listener_keys: ListenerKeys,
+    /// Peers refused after failing the handshake
+    pub bans: Bans,
+    pub sinks: Dispatcher,
+    /// Stored readings for the `/live` stream
+    pub live: broadcast::Sender<LatestReading>,
 }
 
-fn calculate_dew_pont(temp: f32, rel_humidity: f32) -> f64 {
-    // https://en.wikipedia.org/wiki/Tetens_equation
-    // https://en.wikipedia.org/wiki/Clausius%E2%80%93Clapeyron_relation#August%E2%80%93Roche%E2%80%93Magnus_approximation
-    let a = 17.625f64;
-    let b = 243.04f64;
-    let gamma = (rel_humidity as f64 / 100.0).ln() + (a * temp as f64) / (b + temp as f64);
-    (b * gamma) / (a - gamma)
+/// Model of a deserialized [`RuuviV2`] until the real one is restored
+fn placeholder_model() -> TagModel {
+    TagModel::RuuviTag
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Fields the tag reports as not available are `None`
 pub struct RuuviV2 {
+    #[serde(with = "mac")]
     pub mac: [u8; 6],
-    pub temp: f32,
-    pub dew_point_temp: f64,
-    pub rel_humidity: f32,
-    pub abs_humidity: f64,
-    pub abs_pressure: u32,
-    pub acc_x: i16,
-    pub acc_y: i16,
-    pub acc_z: i16,
-    pub battery_voltage: f32,
-    pub tx_power: i8,
-    pub movement_counter: u8,
+    pub temp: Option<f32>,
+    pub dew_point_temp: Option<f64>,
+    pub rel_humidity: Option<f32>,
+    pub abs_humidity: Option<f64>,
+    /// Formula the dew point and absolute humidity were derived with
+    #[serde(default)]
+    pub humidity_formula: HumidityFormula,
+    /// Reported values of a calibrated tag, see `[[calibration]]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uncalibrated: Option<Uncalibrated>,
+    pub abs_pressure: Option<u32>,
+    /// Not available in format C5
+    pub acc_x: Option<i16>,
+    pub acc_y: Option<i16>,
+    pub acc_z: Option<i16>,
+    pub battery_voltage: Option<f32>,
+    pub tx_power: Option<i8>,
+    pub movement_counter: Option<u8>,
+    /// The movement counter changed since the tag's previous reading
+    #[serde(default)]
+    pub movement_event: bool,
     pub measurement_seq: u16,
     pub timestamp: DateTime<Utc>,
     pub rssi: i8,
+    /// Served next to the reading, see `LatestReading`. Not serialized, whoever
+    /// reads a reading back restores it.
+    #[serde(skip, default = "placeholder_model")]
+    pub model: TagModel,
+    #[serde(skip)]
+    pub issues: Issues,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuuviV1 {
+    #[serde(with = "mac")]
+    pub mac: [u8; 6],
+    pub temp: Option<f32>,
+    pub dew_point_temp: Option<f64>,
+    pub rel_humidity: Option<f32>,
+    pub abs_humidity: Option<f64>,
+    /// Formula the dew point and absolute humidity were derived with
+    #[serde(default)]
+    pub humidity_formula: HumidityFormula,
+    /// Reported values of a calibrated tag, see `[[calibration]]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uncalibrated: Option<Uncalibrated>,
+    pub abs_pressure: Option<u32>,
+    pub acc_x: Option<i16>,
+    pub acc_y: Option<i16>,
+    pub acc_z: Option<i16>,
+    pub battery_voltage: Option<f32>,
+    pub timestamp: DateTime<Utc>,
+    pub rssi: i8,
+    /// Deduplicates in place of a measurement sequence, see `RuuviRawV1::fingerprint`
+    #[serde(skip)]
+    pub fingerprint: u16,
+    #[serde(skip)]
+    pub issues: Issues,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RuuviE1 {
+    #[serde(with = "mac")]
     pub mac: [u8; 6],
-    pub temp: f32,
-    pub dew_point_temp: f64,
-    pub rel_humidity: f32,
-    pub abs_humidity: f64,
-    pub abs_pressure: u32,
-    pub pm1_0: f32,
-    pub pm2_5: f32,
-    pub pm4_0: f32,
-    pub pm10_0: f32,
-    pub co2: u16,
-    pub voc_index: u16,
-    pub nox_index: u16,
-    pub luminosity: f32,
+    pub temp: Option<f32>,
+    pub dew_point_temp: Option<f64>,
+    pub rel_humidity: Option<f32>,
+    pub abs_humidity: Option<f64>,
+    /// Formula the dew point and absolute humidity were derived with
+    #[serde(default)]
+    pub humidity_formula: HumidityFormula,
+    /// Reported values of a calibrated tag, see `[[calibration]]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uncalibrated: Option<Uncalibrated>,
+    pub abs_pressure: Option<u32>,
+    pub pm1_0: Option<f32>,
+    pub pm2_5: Option<f32>,
+    pub pm4_0: Option<f32>,
+    pub pm10_0: Option<f32>,
+    pub co2: Option<u16>,
+    pub voc_index: Option<u16>,
+    pub nox_index: Option<u16>,
+    pub luminosity: Option<f32>,
     pub measurement_seq: u32,
     pub flags: u8,
     pub timestamp: DateTime<Utc>,
     pub tx_power: i8,
     pub rssi: i8,
+    #[serde(skip)]
+    pub issues: Issues,
+}
+
+/// Format 6 reading of a Ruuvi Air, stored next to the E1 readings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RuuviV6 {
+    #[serde(with = "mac")]
+    pub mac: [u8; 6],
+    pub temp: Option<f32>,
+    pub dew_point_temp: Option<f64>,
+    pub rel_humidity: Option<f32>,
+    pub abs_humidity: Option<f64>,
+    /// Formula the dew point and absolute humidity were derived with
+    #[serde(default)]
+    pub humidity_formula: HumidityFormula,
+    /// Reported values of a calibrated tag, see `[[calibration]]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uncalibrated: Option<Uncalibrated>,
+    pub abs_pressure: Option<u32>,
+    pub pm2_5: Option<f32>,
+    pub co2: Option<u16>,
+    pub voc_index: Option<u16>,
+    pub nox_index: Option<u16>,
+    pub luminosity: Option<f32>,
+    pub measurement_seq: u8,
+    pub flags: u8,
+    pub timestamp: DateTime<Utc>,
+    pub rssi: i8,
+    #[serde(skip)]
+    pub issues: Issues,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "format", content = "data", rename_all = "lowercase")]
 pub enum Ruuvi {
     V2(RuuviV2),
     E1(RuuviE1),
+    V1(RuuviV1),
+    V6(RuuviV6),
+}
+
+impl Ruuvi {
+    /// Convert a listener packet, `fallback_dt` is used when it has no usable timestamp.
+    /// Fails for encrypted packets without a matching key.
+    pub fn from_raw(
+        raw: RuuviRaw,
+        fallback_dt: DateTime<Utc>,
+        keys: &TagKeys,
+        formula: HumidityFormula,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(match raw {
+            RuuviRaw::E1(e1) => Self::E1(RuuviE1::from_raw(e1, fallback_dt, formula)),
+            RuuviRaw::V2(v2) => Self::V2(RuuviV2::from_raw(v2, fallback_dt, formula)),
+            RuuviRaw::V1(v1) => Self::V1(RuuviV1::from_raw(v1, fallback_dt, formula)),
+            RuuviRaw::V6(v6) => Self::V6(RuuviV6::from_raw(v6, fallback_dt, formula)),
+            RuuviRaw::V8(v8) => {
+                Self::V2(RuuviV2::from_raw(keys.decrypt(&v8)?, fallback_dt, formula))
+            }
+        })
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        match self {
+            Self::E1(e1) => e1.mac,
+            Self::V2(v2) => v2.mac,
+            Self::V1(v1) => v1.mac,
+            Self::V6(v6) => v6.mac,
+        }
+    }
+
+    pub fn measurement_seq(&self) -> u32 {
+        match self {
+            Self::E1(e1) => e1.measurement_seq,
+            Self::V2(v2) => v2.measurement_seq as u32,
+            Self::V1(v1) => v1.fingerprint as u32,
+            Self::V6(v6) => v6.measurement_seq as u32,
+        }
+    }
+
+    pub fn rssi(&self) -> i8 {
+        match self {
+            Self::E1(e1) => e1.rssi,
+            Self::V2(v2) => v2.rssi,
+            Self::V1(v1) => v1.rssi,
+            Self::V6(v6) => v6.rssi,
+        }
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Self::E1(e1) => e1.timestamp,
+            Self::V2(v2) => v2.timestamp,
+            Self::V1(v1) => v1.timestamp,
+            Self::V6(v6) => v6.timestamp,
+        }
+    }
+
+    pub fn model(&self) -> TagModel {
+        match self {
+            Self::E1(_) | Self::V6(_) => TagModel::RuuviAir,
+            Self::V2(v2) => v2.model,
+            Self::V1(_) => TagModel::RuuviTag,
+        }
+    }
+
+    /// Readings table the format is stored in
+    pub fn table(&self) -> &'static str {
+        match self {
+            Self::E1(_) | Self::V6(_) => "air_readings",
+            Self::V2(_) | Self::V1(_) => "tag_readings",
+        }
+    }
 }
 
 impl RuuviV2 {
-    fn from_raw(raw: RuuviRawV2, fallback_dt: DateTime<Utc>) -> Self {
-        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-5-rawv2
-        // Temperature in 0.005 degrees
-        let temp = raw.temp as f32 * 0.005;
-        // Humidity in 0.0025%. 0-163.83% range, though realistically 0-100%
-        let rel_humidity = f32::min(raw.humidity as f32 * 0.0025, 100f32);
-        // Pressure offset -50 000 Pa
-        let abs_pressure = raw.pressure as u32 + 50_000;
-        // First 11 bits are for battery voltage. From 1.6V to 3.646V
-        let battery_voltage = (1600 + (raw.power_info >> 5)) as f32 / 1000f32;
-        // Last 5 bits are for TX power. -40dBm - +20dBm
-        let tx_power = (raw.power_info & 0b11111) as i8 * 2 - 40;
-        // Abs humidity
-        let abs_humidity = calculate_abs_humidity(temp, rel_humidity);
-        // Dew point temp
-        let dew_point_temp = calculate_dew_pont(temp, rel_humidity);
-
-        let timestamp = DateTime::from_timestamp_millis(raw.timestamp.unwrap_or(0) as i64)
-            .unwrap_or_else(|| {
-                tracing::warn!("Failed to parse timestamp");
-                fallback_dt
-            });
+    /// Stored next to the reading, when it has both temperature and humidity
+    pub fn comfort(&self) -> Option<Comfort> {
+        comfort(self.humidity_formula, self.temp, self.rel_humidity)
+    }
+}
 
+#[cfg(test)]
+impl RuuviV2 {
+    /// A RuuviTag reading with every value present, tests override what they need
+    pub fn sample(mac: [u8; 6], measurement_seq: u16, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            mac,
+            temp: Some(20.0),
+            dew_point_temp: Some(10.0),
+            rel_humidity: Some(50.0),
+            abs_humidity: Some(8.0),
+            humidity_formula: HumidityFormula::Buck,
+            uncalibrated: None,
+            abs_pressure: Some(100_000),
+            acc_x: Some(0),
+            acc_y: Some(0),
+            acc_z: Some(1000),
+            battery_voltage: Some(3.0),
+            tx_power: Some(4),
+            movement_counter: Some(0),
+            movement_event: false,
+            measurement_seq,
+            timestamp,
+            rssi: -60,
+            model: TagModel::RuuviTag,
+            issues: Issues::default(),
+        }
+    }
+}
+
+impl RuuviV1 {
+    pub fn comfort(&self) -> Option<Comfort> {
+        comfort(self.humidity_formula, self.temp, self.rel_humidity)
+    }
+}
+
+impl RuuviE1 {
+    /// European CAQI of the particulate matter
+    pub fn air_quality_index(&self) -> Option<f64> {
+        convert::caqi(self.pm2_5, self.pm10_0)
+    }
+}
+
+impl RuuviV6 {
+    /// European CAQI of PM2.5 only, format 6 has no PM10
+    pub fn air_quality_index(&self) -> Option<f64> {
+        convert::caqi(self.pm2_5, None)
+    }
+}
+
+fn comfort(
+    formula: HumidityFormula,
+    temp: Option<f32>,
+    rel_humidity: Option<f32>,
+) -> Option<Comfort> {
+    Some(Comfort::new(formula, temp?, rel_humidity?))
+}
+
+/// Listeners without time sync send no timestamp, their readings get the arrival time
+fn parse_timestamp(timestamp: Option<u64>, fallback_dt: DateTime<Utc>) -> DateTime<Utc> {
+    let Some(timestamp) = timestamp else {
+        return fallback_dt;
+    };
+    DateTime::from_timestamp_millis(timestamp as i64).unwrap_or_else(|| {
+        tracing::warn!("Failed to parse timestamp");
+        fallback_dt
+    })
+}
+
+/// Orders readings by timestamp and measurement sequence. The floats rule out
+/// a total order, readings with the same key but different values are unordered.
+fn partial_cmp_by_key<T: PartialEq, K: Ord>(
+    a: &T,
+    b: &T,
+    key: impl Fn(&T) -> K,
+) -> Option<Ordering> {
+    match key(a).cmp(&key(b)) {
+        Ordering::Equal => (a == b).then_some(Ordering::Equal),
+        ordering => Some(ordering),
+    }
+}
+
+impl PartialOrd for Ruuvi {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        partial_cmp_by_key(self, other, |r| (r.timestamp(), r.measurement_seq()))
+    }
+}
+
+impl PartialOrd for RuuviV2 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        partial_cmp_by_key(self, other, |r| (r.timestamp, r.measurement_seq))
+    }
+}
+
+impl PartialOrd for RuuviV1 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        partial_cmp_by_key(self, other, |r| (r.timestamp, r.fingerprint))
+    }
+}
+
+impl PartialOrd for RuuviV6 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        partial_cmp_by_key(self, other, |r| (r.timestamp, r.measurement_seq))
+    }
+}
+
+impl PartialOrd for RuuviE1 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        partial_cmp_by_key(self, other, |r| (r.timestamp, r.measurement_seq))
+    }
+}
+
+impl RuuviV2 {
+    fn from_raw(raw: RuuviRawV2, fallback_dt: DateTime<Utc>, formula: HumidityFormula) -> Self {
+        let values = TagValues::from(&raw).with_formula(formula);
         Self {
             mac: raw.mac,
-            temp,
-            dew_point_temp,
-            rel_humidity,
-            abs_humidity,
-            abs_pressure,
-            acc_x: raw.acc_x,
-            acc_y: raw.acc_y,
-            acc_z: raw.acc_z,
-            battery_voltage,
-            tx_power,
-            movement_counter: raw.movement_counter,
+            temp: values.temp,
+            dew_point_temp: values.dew_point_temp,
+            rel_humidity: values.rel_humidity,
+            abs_humidity: values.abs_humidity,
+            humidity_formula: formula,
+            uncalibrated: None,
+            abs_pressure: values.abs_pressure,
+            acc_x: values.acc_x,
+            acc_y: values.acc_y,
+            acc_z: values.acc_z,
+            battery_voltage: values.battery_voltage,
+            tx_power: values.tx_power,
+            movement_counter: values.movement_counter,
+            movement_event: false,
             measurement_seq: raw.measurement_seq,
-            timestamp,
+            timestamp: parse_timestamp(raw.timestamp, fallback_dt),
             rssi: raw.rssi,
+            model: raw.model(),
+            issues: Issues::v2(&raw),
         }
     }
 }
 
-impl RuuviE1 {
-    fn from_raw(raw: RuuviRawE1, fallback_dt: DateTime<Utc>) -> Self {
-        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-e1
-        // Temperature in 0.005 degrees
-        let temp = raw.temp as f32 * 0.005;
-        // Humidity in 0.0025%. 0-163.83% range, though realistically 0-100%
-        let rel_humidity = f32::min(raw.humidity as f32 * 0.0025, 100f32);
-        // Pressure offset -50 000 Pa
-        let abs_pressure = raw.pressure as u32 + 50_000;
-
-        let dew_point_temp = calculate_dew_pont(temp, rel_humidity);
-        let abs_humidity = calculate_abs_humidity(temp, rel_humidity);
-
-        // Resolution 0.1/bit, range 0 ... 1000. 16bit unsigned
-        let pm = |v: u16| f32::min(v as f32 * 0.1, 1000f32);
-        let pm1_0 = pm(raw.pm1_0);
-        let pm2_5 = pm(raw.pm2_5);
-        let pm4_0 = pm(raw.pm4_0);
-        let pm10_0 = pm(raw.pm10_0);
-
-        // CO2 concentration, ppm. Resolution 1/bit, range 0 ... 40000. 16bit unsigned
-        let co2 = u16::min(raw.co2, 40_000);
-
-        // VOC index, unitless. Resolution 1 / bit, range 0 ... 500. 9 bit unsigned, least significant bit in Flags byte
-        let voc_index = u16::min(raw.voc_index, 500);
-        // NOX index, unitless. Resolution 1 / bit, range 0 ... 500. 9 bit unsigned, least significant bit in Flags byte
-        let nox_index = u16::min(raw.nox_index, 500);
-
-        // Luminosity
-        let luminosity = f32::min(raw.luminosity as f32 * 0.01, 144_284f32);
-
-        let timestamp = DateTime::from_timestamp_millis(raw.timestamp.unwrap_or(0) as i64)
-            .unwrap_or_else(|| {
-                tracing::warn!("Failed to parse timestamp");
-                fallback_dt
-            });
+impl RuuviV1 {
+    fn from_raw(raw: RuuviRawV1, fallback_dt: DateTime<Utc>, formula: HumidityFormula) -> Self {
+        let values = TagValues::from(&raw).with_formula(formula);
+        Self {
+            mac: raw.mac,
+            temp: values.temp,
+            dew_point_temp: values.dew_point_temp,
+            rel_humidity: values.rel_humidity,
+            abs_humidity: values.abs_humidity,
+            humidity_formula: formula,
+            uncalibrated: None,
+            abs_pressure: values.abs_pressure,
+            acc_x: values.acc_x,
+            acc_y: values.acc_y,
+            acc_z: values.acc_z,
+            battery_voltage: values.battery_voltage,
+            timestamp: parse_timestamp(raw.timestamp, fallback_dt),
+            rssi: raw.rssi,
+            fingerprint: raw.fingerprint(),
+            issues: Issues::v1(&raw),
+        }
+    }
+}
 
+impl RuuviE1 {
+    fn from_raw(raw: RuuviRawE1, fallback_dt: DateTime<Utc>, formula: HumidityFormula) -> Self {
+        let values = AirValues::from(&raw).with_formula(formula);
         Self {
             mac: raw.mac,
-            temp,
-            dew_point_temp,
-            rel_humidity,
-            abs_humidity,
-            abs_pressure,
-            pm1_0,
-            pm2_5,
-            pm4_0,
-            pm10_0,
-            co2,
-            voc_index,
-            nox_index,
-            luminosity,
+            temp: values.temp,
+            dew_point_temp: values.dew_point_temp,
+            rel_humidity: values.rel_humidity,
+            abs_humidity: values.abs_humidity,
+            humidity_formula: formula,
+            uncalibrated: None,
+            abs_pressure: values.abs_pressure,
+            pm1_0: values.pm1_0,
+            pm2_5: values.pm2_5,
+            pm4_0: values.pm4_0,
+            pm10_0: values.pm10_0,
+            co2: values.co2,
+            voc_index: values.voc_index,
+            nox_index: values.nox_index,
+            luminosity: values.luminosity,
             measurement_seq: raw.measurement_seq,
             flags: raw.flags,
-            timestamp,
+            timestamp: parse_timestamp(raw.timestamp, fallback_dt),
             tx_power: raw.tx_power,
             rssi: raw.rssi,
+            issues: Issues::e1(&raw),
+        }
+    }
+}
+
+impl RuuviV6 {
+    fn from_raw(raw: RuuviRawV6, fallback_dt: DateTime<Utc>, formula: HumidityFormula) -> Self {
+        let values = AirValues::from(&raw).with_formula(formula);
+        Self {
+            mac: raw.mac,
+            temp: values.temp,
+            dew_point_temp: values.dew_point_temp,
+            rel_humidity: values.rel_humidity,
+            abs_humidity: values.abs_humidity,
+            humidity_formula: formula,
+            uncalibrated: None,
+            abs_pressure: values.abs_pressure,
+            pm2_5: values.pm2_5,
+            co2: values.co2,
+            voc_index: values.voc_index,
+            nox_index: values.nox_index,
+            luminosity: values.luminosity,
+            measurement_seq: raw.measurement_seq,
+            flags: raw.flags,
+            timestamp: parse_timestamp(raw.timestamp, fallback_dt),
+            rssi: raw.rssi,
+            issues: Issues::v6(&raw),
         }
     }
 }
@@ -217,135 +583,889 @@ async fn send(stream: &mut TcpStream, buf: &[u8]) -> io::Result<()> {
     stream.flush().await
 }
 
+/// Encrypt and send a message, followed by a [`Message::Rekey`] when `rekey` says one is due
+async fn send_message(
+    stream: &mut TcpStream,
+    transport: &mut TransportState,
+    framing: Framing,
+    rekey: &mut Option<RekeyPolicy>,
+    message: &Message<'_>,
+    reply_buf: &mut [u8],
+    noise_buf: &mut [u8],
+) -> Result<(), anyhow::Error> {
+    let payload = framing.encode(message, reply_buf)?;
+    let len = transport.write_message(payload, noise_buf)?;
+    send(stream, &noise_buf[..len]).await?;
+
+    if let Some(rekey) = rekey
+        && rekey.due(transport.sending_nonce(), unix_millis())
+    {
+        let payload = framing.encode(&Message::Rekey, reply_buf)?;
+        let len = transport.write_message(payload, noise_buf)?;
+        send(stream, &noise_buf[..len]).await?;
+        transport.rekey_outgoing();
+        rekey.rekeyed(transport.sending_nonce(), unix_millis());
+        tracing::debug!("Rekeyed the session");
+    }
+    Ok(())
+}
+
+/// Deduplicate and store a reading. Readings that didn't arrive over a
+/// listener connection have no `stats` and nobody to acknowledge.
+fn ingest(
+    state: &Arc<AppState>,
+    listener: &str,
+    data: Ruuvi,
+    raw_payload: Option<Vec<u8>>,
+    stats: Option<&Arc<ConnectionStats>>,
+    ack: Option<AckHandle>,
+) {
+    let filter = &state.config.tag_filter;
+    if !state
+        .tag_filter
+        .passes(filter, &state.registry, data.mac(), listener)
+    {
+        // Resending won't change the verdict
+        if let Some(ack) = ack {
+            ack.send();
+        }
+        if filter.quarantine {
+            let (state, listener) = (state.clone(), listener.to_owned());
+            tokio::spawn(async move {
+                let path = &state.config.quarantine.path;
+                if let Err(e) = quarantine::record_reading(path, &listener, &data).await {
+                    tracing::error!("Failed to quarantine a reading: {e}");
+                }
+            });
+        }
+        return;
+    }
+
+    let key = match state.dedup.submit(listener, data, raw_payload, ack) {
+        Submitted::First(key) => key,
+        Submitted::Merged => return,
+        Submitted::Late(late) => {
+            record_late(state, late);
+            return;
+        }
+    };
+
+    // First copy of this measurement, wait for the other listeners and store the best one
+    let state = state.clone();
+    let stats = stats.cloned();
+    tokio::spawn(async move {
+        tokio::time::sleep(state.dedup.window()).await;
+        if let Some(mut pending) = state.dedup.take(key) {
+            let started = std::time::Instant::now();
+            let acks = std::mem::take(&mut pending.acks);
+            let (data, listener) = (pending.data.clone(), pending.listener.clone());
+            if store(&state, pending).await {
+                let merged = state.dedup.stored(&data, &listener);
+                acks.into_iter().chain(merged).for_each(AckHandle::send);
+            } else {
+                state.dedup.failed(key);
+            }
+            if let Some(stats) = stats {
+                stats.insert(started.elapsed());
+            }
+        }
+    });
+}
+
+/// A copy arriving after its measurement was stored is only another reception
+fn record_late(state: &Arc<AppState>, late: Late) {
+    let Late {
+        mac,
+        measurement_seq,
+        timestamp,
+        primary,
+        reception,
+    } = late;
+    tracing::trace!(
+        "{mac:X?} seq {measurement_seq} from {} arrived after it was stored",
+        reception.listener
+    );
+    // The primary listener resending means its acknowledgement got lost
+    if !state.config.dedup.record_receptions || reception.listener == primary {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = state
+            .storage
+            .insert_receptions(mac, measurement_seq, timestamp, &primary, &[reception])
+            .await
+        {
+            tracing::error!("Failed to insert receptions: {e}");
+        }
+    });
+}
+
+/// `false` when the reading couldn't be stored and the listener should resend it
+async fn store(state: &AppState, pending: Pending) -> bool {
+    let Pending {
+        mut data,
+        listener,
+        raw_payload,
+        receptions,
+        ..
+    } = pending;
+    let (mac, measurement_seq) = (data.mac(), data.measurement_seq());
+    // An Air advertises every measurement as both E1 and format 6, the latter
+    // only counts for listeners that can't receive E1
+    if let Ruuvi::V6(v6) = &data
+        && let Some(latest) = state.latest.get(&mac)
+        && matches!(latest.data, Ruuvi::E1(_))
+        && (v6.timestamp - latest.data.timestamp()).num_seconds() < E1_PREFERENCE_SECS
+    {
+        tracing::trace!("{mac:X?} format 6 seq {measurement_seq} skipped, E1 is received");
+        return true;
+    }
+    if receptions.len() > 1 {
+        tracing::debug!(
+            "{mac:X?} seq {measurement_seq} heard by {} listeners, primary {listener}",
+            receptions.len()
+        );
+    }
+
+    let previous = state.latest.get(&mac);
+    if let Ruuvi::V2(v2) = &mut data
+        && let Some(Ruuvi::V2(last)) = previous.as_ref().map(|reading| &reading.data)
+    {
+        v2.movement_event = v2.movement_counter.is_some()
+            && last.movement_counter.is_some()
+            && v2.movement_counter != last.movement_counter;
+    }
+
+    state.registry.check(&data);
+    let registered = state.registry.get(&mac);
+    // A registered location beats the one estimated from the listeners
+    let location = match registered.as_ref().and_then(|tag| tag.location.clone()) {
+        Some(location) => Some(location),
+        None => state.locator.locate(mac, &receptions),
+    };
+    state.quality.observe(&data);
+    let model = data.model();
+    let previous_model = previous.map(|reading| reading.model);
+    let reading = LatestReading {
+        name: registered.map(|tag| tag.name),
+        listener: listener.clone(),
+        location,
+        model,
+        data: data.clone(),
+    };
+    // Without subscribers the reading is simply dropped
+    let _ = state.live.send(reading.clone());
+    state.latest.update(reading);
+    // Models only change when a tag is swapped or loses a sensor, skip the write otherwise
+    if previous_model != Some(model) {
+        if let Some(previous) = previous_model {
+            tracing::info!("{mac:X?} is now a {model:?}, was {previous:?}");
+        }
+        if let Err(e) = state
+            .storage
+            .upsert_tag_model(mac, model, data.timestamp())
+            .await
+        {
+            tracing::error!("Failed to store tag model: {e}");
+        }
+    }
+
+    let timestamp = data.timestamp();
+    if let Ruuvi::V2(v2) = &data
+        && let Some(transition) = state.doors.observe(v2)
+    {
+        tracing::info!("Door {mac:X?} is now {}", transition.current.as_str());
+        if let Err(e) = state
+            .storage
+            .insert_door_event(mac, timestamp, transition)
+            .await
+        {
+            tracing::error!("Failed to insert door event: {e}");
+        }
+    }
+    let stored = state
+        .sinks
+        .store(StoredReading {
+            data,
+            listener: listener.clone(),
+            raw_payload,
+        })
+        .await;
+
+    if state.config.dedup.record_receptions
+        && let Err(e) = state
+            .storage
+            .insert_receptions(mac, measurement_seq, timestamp, &listener, &receptions)
+            .await
+    {
+        tracing::error!("Failed to insert receptions: {e}");
+    }
+    stored
+}
+
+/// Outcome of a completed Noise handshake
+struct Handshake<'a> {
+    transport: TransportState,
+    hello: Option<Hello>,
+    /// Name of the PSK the listener authenticated with
+    psk: &'a str,
+    /// Name of the listener's pinned static key
+    identity: Option<&'a str>,
+}
+
+/// Responder side of the Noise handshake, `listener` is the peer's address
+async fn handshake<'a>(
+    stream: &mut TcpStream,
+    state: &'a AppState,
+    listener: &str,
+    rx_buffer: &mut [u8; 4096],
+    noise_buf: &mut [u8; 4096],
+) -> Result<Handshake<'a>, anyhow::Error> {
+    // Cipher suite, then <- e
+    let (suite, legacy, read_len) = match read_selection(stream).await? {
+        Selection::Suite(suite) => (suite, false, recv(stream, rx_buffer).await?),
+        Selection::Legacy(len) => {
+            let len = usize::from(len);
+            stream.read_exact(&mut rx_buffer[..len]).await?;
+            (Suite::ChaChaPoly, true, len)
+        }
+        Selection::Unknown(id) => anyhow::bail!("{listener} requested unknown cipher suite {id}"),
+    };
+    if !state.config.noise.suites.contains(&suite) {
+        anyhow::bail!("{listener} requested disabled cipher suite {suite:?}");
+    }
+    tracing::info!("Noise handshake started with {listener} using {suite:?}");
+
+    // Initialize our responder using a builder
+    let static_key = Builder::new(suite.params()).generate_keypair()?.private;
+    let builder = Builder::new(suite.params()).local_private_key(&static_key)?;
+    // The selection byte is authenticated as the prologue, legacy listeners have none
+    let prologue = [suite.id()];
+    let builder = if legacy {
+        builder
+    } else {
+        builder.prologue(&prologue)?
+    };
+    let mut noise = builder.build_responder()?;
+    // The name of the listener's PSK as payload, authenticated with the rest of
+    // the handshake. Listeners naming none use the default one.
+    let len = noise.read_message(&rx_buffer[..read_len], noise_buf)?;
+    let requested = std::str::from_utf8(&noise_buf[..len])?;
+    let Some((psk, key)) = state.psks.get(requested) else {
+        anyhow::bail!("{listener} asked for unknown PSK {requested}");
+    };
+    noise.set_psk(3, key)?;
+
+    // -> e, ee, s, es
+    let len = noise.write_message(&[], noise_buf)?;
+    send(stream, &noise_buf[..len]).await?;
+
+    // <- s, se, with the listener's versions as payload
+    let read_len = recv(stream, rx_buffer).await?;
+    let len = noise
+        .read_message(&rx_buffer[..read_len], noise_buf)
+        .map_err(|e| anyhow::anyhow!("{listener} failed the handshake with PSK {psk}: {e}"))?;
+    tracing::info!("{listener} authenticated with PSK {psk}");
+    // Listeners predating version negotiation send no payload
+    let hello = match len {
+        0 => None,
+        len => Some(postcard::from_bytes::<Hello>(&noise_buf[..len])?),
+    };
+    let identity = match state.listener_keys.identify(noise.get_remote_static()) {
+        Ok(Some(name)) => {
+            tracing::info!("{listener} authenticated as {name}");
+            Some(name)
+        }
+        Ok(None) => None,
+        Err(e) => anyhow::bail!("{listener} rejected: {e}"),
+    };
+
+    // Transition the state machine into transport mode now that the handshake is complete.
+    let transport = noise.into_transport_mode()?;
+    tracing::info!("In transport mode");
+    Ok(Handshake {
+        transport,
+        hello,
+        psk,
+        identity,
+    })
+}
+
+/// Latest health report of a listener, kept with its connection and stored
+async fn record_telemetry(
+    state: &AppState,
+    listener: &str,
+    stats: &ConnectionStats,
+    telemetry: Telemetry,
+) {
+    tracing::debug!("Telemetry from {listener}: {telemetry:?}");
+    // A listener reports right after connecting, so the first report tells why it last started
+    if stats.telemetry(telemetry).is_none() {
+        let (reason, uptime) = (telemetry.reset_reason, telemetry.uptime_secs);
+        if reason.is_fault() {
+            tracing::warn!("{listener} restarted {uptime}s ago after {reason:?}");
+        } else {
+            tracing::info!("{listener} up for {uptime}s, started by {reason:?}");
+        }
+    }
+    if let Err(e) = state
+        .storage
+        .upsert_listener_status(listener, telemetry)
+        .await
+    {
+        tracing::error!("Failed to record the status of {listener}: {e}");
+    }
+}
+
 async fn handle_conn(
     mut stream: tokio::net::TcpStream,
-    pool: Pool<Postgres>,
+    state: Arc<AppState>,
+    pending: OwnedSemaphorePermit,
 ) -> Result<(), anyhow::Error> {
     stream.set_ttl(30)?;
+    let tcp = &state.config.tcp;
+    if tcp.keepalive_secs > 0 {
+        let keepalive = TcpKeepalive::new()
+            .with_time(Duration::from_secs(tcp.keepalive_secs))
+            .with_interval(Duration::from_secs(tcp.keepalive_interval_secs));
+        SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+    }
+    let idle_timeout = Duration::from_secs(tcp.idle_timeout_secs);
 
     let mut rx_buffer = [0u8; 4096];
     let mut noise_buf = [0u8; 4096];
 
-    // Initialize our responder using a builder.
-    let builder = Builder::new(PARAMS.clone());
-    let static_key = builder.generate_keypair()?.private;
-    let mut noise = builder
-        .local_private_key(&static_key)?
-        .psk(3, &PSK_KEY)?
-        .build_responder()?;
+    // Listeners are identified by their IP address until they authenticate
+    let peer = listen::canonical(stream.peer_addr()?);
+    let listener = peer.ip().to_string();
+
+    // A peer that never completes the handshake would hold its task and buffers forever
+    let timeout = state.config.handshake.timeout_secs;
+    let result = tokio::time::timeout(
+        Duration::from_secs(timeout),
+        handshake(
+            &mut stream,
+            &state,
+            &listener,
+            &mut rx_buffer,
+            &mut noise_buf,
+        ),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        Err(anyhow::anyhow!(
+            "{listener} didn't complete the handshake in {timeout}s"
+        ))
+    });
+    drop(pending);
+    let handshake = match result {
+        Ok(handshake) => handshake,
+        Err(e) => {
+            if state.bans.failure(peer.ip()) {
+                tracing::warn!(
+                    "Banning {listener} for {}s after repeated handshake failures",
+                    state.config.handshake.ban_secs
+                );
+            }
+            return Err(e);
+        }
+    };
+    state.bans.success(peer.ip());
 
-    tracing::info!("Noise handshake started with {:?}", stream.peer_addr());
+    let (connection, mut commands) = state.connections.register(&listener, peer);
+    let stats = &connection.stats;
+    stats.psk(handshake.psk);
+    let listener = match handshake.identity {
+        Some(name) => {
+            stats.authenticated(name);
+            name.to_owned()
+        }
+        None => listener,
+    };
+    let hello = handshake.hello;
+    let mut transport = handshake.transport;
 
-    // <- e
-    let read_len = recv(&mut stream, &mut rx_buffer).await?;
-    noise.read_message(&rx_buffer[..read_len], &mut noise_buf)?;
+    let mut reply_buf = [0u8; 1024];
+    if let Some(hello) = hello {
+        tracing::info!(
+            "{listener} runs firmware {} speaking protocol {}",
+            ota::format_version(hello.firmware),
+            hello.protocol
+        );
+        stats.hello(hello);
+        if !SUPPORTED_PROTOCOLS.contains(&hello.protocol) {
+            let message = Message::Incompatible {
+                min: *SUPPORTED_PROTOCOLS.start(),
+                max: *SUPPORTED_PROTOCOLS.end(),
+            };
+            let payload = Framing::Envelope.encode(&message, &mut reply_buf)?;
+            let len = transport.write_message(payload, &mut noise_buf)?;
+            send(&mut stream, &noise_buf[..len]).await?;
+            anyhow::bail!(
+                "{listener} speaks protocol {}, supported are {SUPPORTED_PROTOCOLS:?}",
+                hello.protocol
+            );
+        }
+    }
 
-    // -> e, ee, s, es
-    let len = noise.write_message(&[], &mut noise_buf)?;
-    send(&mut stream, &noise_buf[..len]).await?;
+    let row = ListenerRow {
+        name: listener.clone(),
+        identified: handshake.identity.is_some(),
+        address: peer.ip(),
+        psk: handshake.psk.to_owned(),
+        firmware: hello.map(|hello| ota::format_version(hello.firmware)),
+    };
+    if let Err(e) = state.storage.upsert_listener(&row).await {
+        tracing::error!("Failed to record listener {listener}: {e}");
+    }
 
-    // <- s, se
+    // The first message tells the framing apart, legacy listeners send an empty one
     let read_len = recv(&mut stream, &mut rx_buffer).await?;
-    noise.read_message(&rx_buffer[..read_len], &mut noise_buf)?;
-
-    // Transition the state machine into transport mode now that the handshake is complete.
-    let mut transport = noise.into_transport_mode()?;
-    tracing::info!("In transport mode");
+    let framing = if read_len == 0 {
+        tracing::info!("{listener} uses legacy framing");
+        Framing::Legacy
+    } else {
+        let len = transport.read_message(&rx_buffer[..read_len], &mut noise_buf)?;
+        match postcard::from_bytes::<Message>(&noise_buf[..len]) {
+            Ok(Message::TimeSyncRequest) => Framing::Envelope,
+            Ok(message) => {
+                anyhow::bail!("{listener} opened with {message:?} instead of a time sync")
+            }
+            Err(e) => anyhow::bail!("{listener} opened with an unreadable message: {e}"),
+        }
+    };
 
     // Measure network latency
-    let _ = recv(&mut stream, &mut rx_buffer).await?;
-    let time = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-    let len = transport.write_message(&time.to_be_bytes(), &mut noise_buf)?;
-    send(&mut stream, &noise_buf[..len]).await?;
+    match framing {
+        Framing::Legacy => {
+            let len = transport.write_message(&unix_millis().to_be_bytes(), &mut noise_buf)?;
+            send(&mut stream, &noise_buf[..len]).await?;
+        }
+        Framing::Envelope => {
+            let message = Message::TimeSyncResponse {
+                unix_ms: unix_millis(),
+            };
+            let payload = framing.encode(&message, &mut reply_buf)?;
+            let len = transport.write_message(payload, &mut noise_buf)?;
+            send(&mut stream, &noise_buf[..len]).await?;
+        }
+    }
+
+    // Listeners older than the rekey message keep their keys for the whole session
+    let mut rekey = (framing == Framing::Envelope
+        && hello.is_some_and(|hello| hello.protocol >= REKEY_PROTOCOL))
+    .then(|| RekeyPolicy::new(transport.sending_nonce(), unix_millis()));
+
+    if framing == Framing::Envelope
+        && hello.is_some_and(|hello| hello.protocol >= SCAN_SETTINGS_PROTOCOL)
+        && let Some(settings) = state.config.scan_settings(&listener)
+    {
+        tracing::debug!("Sending scan settings {settings:?} to {listener}");
+        send_message(
+            &mut stream,
+            &mut transport,
+            framing,
+            &mut rekey,
+            &Message::ScanSettings(settings),
+            &mut reply_buf,
+            &mut noise_buf,
+        )
+        .await?;
+    }
 
+    let quarantine = &state.config.quarantine;
+    let mut failures = FailureTracker::new(quarantine.max_failures);
+    let mut ota = ota::Session::default();
+    let (ack_sender, mut acks) = mpsc::unbounded_channel::<Ack>();
+    let mut idle_deadline = Instant::now() + idle_timeout;
     loop {
-        match recv(&mut stream, &mut rx_buffer).await {
-            Ok(len) => {
-                let fallback_dt = Utc::now();
-                // Decrypt message
-                let len = transport.read_message(&rx_buffer[..len], &mut noise_buf)?;
-
-                // Postcard deserialize
-                let data = postcard::from_bytes::<RuuviRaw>(&noise_buf[..len]);
-
-                match data {
-                    Ok(raw) => {
-                        match raw {
-                            RuuviRaw::E1(e1) => {
-                                let ruuvi_data = RuuviE1::from_raw(e1, fallback_dt);
-                                tracing::debug!("Data: {ruuvi_data:?}");
-                                if let Err(e) = insert_data_e1(&pool, ruuvi_data).await {
-                                    tracing::error!("Failed to insert E1 data: {e}");
-                                }
-                            }
-                            RuuviRaw::V2(v2) => {
-                                let ruuvi_data = RuuviV2::from_raw(v2, fallback_dt);
-                                tracing::debug!("Data: {ruuvi_data:?}");
-                                if let Err(e) = insert_data_v2(&pool, ruuvi_data).await {
-                                    tracing::error!("Failed insert V2 data: {e}");
-                                }
+        // Acknowledge stored readings while waiting for the next frame
+        let reply = tokio::select! {
+            ready = stream.readable() => {
+                ready?;
+                None
+            }
+            () = tokio::time::sleep_until(idle_deadline) => {
+                anyhow::bail!("{listener} sent nothing in {}s", idle_timeout.as_secs());
+            }
+            Some(ack) = acks.recv() => {
+                let mut batch = heapless::Vec::new();
+                let _ = batch.push(ack);
+                while !batch.is_full()
+                    && let Ok(ack) = acks.try_recv()
+                {
+                    let _ = batch.push(ack);
+                }
+                Some(Message::Ack(batch))
+            }
+            Some(command) = commands.recv(), if framing == Framing::Envelope => {
+                tracing::info!("Sending {command:?} to {listener}");
+                Some(Message::Command(command))
+            }
+        };
+        if let Some(message) = reply {
+            send_message(
+                &mut stream,
+                &mut transport,
+                framing,
+                &mut rekey,
+                &message,
+                &mut reply_buf,
+                &mut noise_buf,
+            )
+            .await?;
+            continue;
+        }
+
+        // A frame stalled half way counts as idle as well
+        let len = tokio::time::timeout_at(idle_deadline, recv(&mut stream, &mut rx_buffer))
+            .await
+            .map_err(|_| anyhow::anyhow!("{listener} stalled in the middle of a frame"))??;
+        idle_deadline = Instant::now() + idle_timeout;
+        stats.frame(len);
+        let frame = &rx_buffer[..len];
+        let fallback_dt = Utc::now();
+
+        // Decrypt message, then postcard deserialize
+        let (failure, sample) = match transport.read_message(frame, &mut noise_buf) {
+            Ok(len) => match framing.decode(&noise_buf[..len]) {
+                Ok(message) => {
+                    failures.success();
+                    let reply = match message {
+                        Message::Measurement(raw) => {
+                            receive(&state, &listener, stats, raw, fallback_dt, &ack_sender);
+                            None
+                        }
+                        Message::Batch(raws) => {
+                            for raw in raws {
+                                receive(&state, &listener, stats, raw, fallback_dt, &ack_sender);
                             }
+                            None
                         }
-
-                        continue;
+                        // Periodic clock re-sync, answered like the initial one
+                        Message::TimeSyncRequest => Some(Message::TimeSyncResponse {
+                            unix_ms: unix_millis(),
+                        }),
+                        Message::Heartbeat => {
+                            tracing::trace!("Heartbeat from {listener}");
+                            None
+                        }
+                        Message::Telemetry(telemetry) => {
+                            record_telemetry(&state, &listener, stats, telemetry).await;
+                            None
+                        }
+                        Message::LegacyTelemetry(telemetry) => {
+                            record_telemetry(&state, &listener, stats, telemetry.into()).await;
+                            None
+                        }
+                        Message::Rekey => {
+                            tracing::debug!("{listener} rekeyed its session");
+                            transport.rekey_incoming();
+                            None
+                        }
+                        Message::OtaUplink(uplink) => ota
+                            .handle(&state.config.ota, &listener, uplink)
+                            .await
+                            .map(Message::OtaDownlink),
+                        message => {
+                            tracing::warn!("Ignoring {message:?} from {listener}");
+                            None
+                        }
+                    };
+                    if let Some(message) = reply {
+                        send_message(
+                            &mut stream,
+                            &mut transport,
+                            framing,
+                            &mut rekey,
+                            &message,
+                            &mut reply_buf,
+                            &mut noise_buf,
+                        )
+                        .await?;
                     }
-                    Err(err) => tracing::error!("Failed to parse ruuvidata: {err}"),
+                    continue;
                 }
-            }
+                Err(err) => {
+                    stats.decode_failure();
+                    tracing::error!("Failed to parse a message from {listener}: {err}");
+                    (Failure::Decode, &noise_buf[..len])
+                }
+            },
             Err(e) => {
-                return Err(e.into());
+                stats.decrypt_failure();
+                tracing::error!("Failed to decrypt a frame from {listener}: {e}");
+                (Failure::Decrypt, frame)
+            }
+        };
+
+        // The stream is most likely desynced, give up instead of looping over garbage
+        if failures.failure() {
+            if let Err(e) = quarantine::record(&quarantine.path, &listener, failure, sample).await {
+                tracing::error!("Failed to quarantine a frame: {e}");
             }
+            anyhow::bail!(
+                "{} consecutive bad frames from {listener}, closing the connection",
+                quarantine.max_failures
+            );
         }
     }
 }
 
-async fn tcp_server(pool: sqlx::Pool<sqlx::Postgres>) -> Result<(), anyhow::Error> {
-    let listener: TcpListener = TcpListener::bind("0.0.0.0:9090").await?;
-    tracing::info!("TCP ingestion listening on :9090");
+/// Convert and ingest a reading from a listener connection, it is
+/// acknowledged once stored
+fn receive(
+    state: &Arc<AppState>,
+    listener: &str,
+    stats: &Arc<ConnectionStats>,
+    raw: RuuviRaw,
+    fallback_dt: DateTime<Utc>,
+    acks: &mpsc::UnboundedSender<Ack>,
+) {
+    let ack = AckHandle {
+        ack: Ack::of(&raw),
+        sender: acks.clone(),
+    };
+    let raw_payload = raw_payload(state, &raw);
+    // A missing tag key is a config problem, not a broken stream.
+    // Resending won't help either, so the reading is acknowledged.
+    let mut ruuvi_data = match Ruuvi::from_raw(
+        raw,
+        fallback_dt,
+        &state.tag_keys,
+        state.config.humidity.formula,
+    ) {
+        Ok(ruuvi_data) => ruuvi_data,
+        Err(e) => {
+            tracing::warn!("{e}");
+            ack.send();
+            return;
+        }
+    };
+    state.calibrations.apply(&mut ruuvi_data);
+    tracing::debug!("Data: {ruuvi_data:?}");
+    ingest(
+        state,
+        listener,
+        ruuvi_data,
+        raw_payload,
+        Some(stats),
+        Some(ack),
+    );
+}
+
+/// The packet encoded back into manufacturer data, when `[raw_payload]` is
+/// enabled. Encrypted packets keep their ciphertext.
+fn raw_payload(state: &AppState, raw: &RuuviRaw) -> Option<Vec<u8>> {
+    state
+        .config
+        .raw_payload
+        .enabled
+        .then(|| raw.to_bytes().to_vec())
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+async fn tcp_server(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    if state.config.bluetooth.standalone {
+        return Ok(());
+    }
+    let listen = &state.config.server.listen;
+    let listener = listen::bind(listen).await?;
+    tracing::info!("TCP ingestion listening on {listen}");
+    let max_pending = state.config.handshake.max_pending.max(1);
+    let pending = Arc::new(Semaphore::new(max_pending));
+    let max_connections = state.config.tcp.max_connections.max(1);
+    let connections = Arc::new(Semaphore::new(max_connections));
     loop {
         let (sock, addr) = listener.accept().await?;
-        let pool = pool.clone();
+        let addr = listen::canonical(addr);
+        // Before taking a slot, so banned peers reconnecting can't crowd out listeners
+        if state.bans.is_banned(addr.ip()) {
+            tracing::debug!("Refused banned {addr}");
+            continue;
+        }
+        // Held until the connection closes
+        let Ok(slot) = connections.clone().try_acquire_owned() else {
+            tracing::warn!("Refused {addr}, {max_connections} connections already open");
+            continue;
+        };
+        let Ok(permit) = pending.clone().try_acquire_owned() else {
+            tracing::warn!("Refused {addr}, {max_pending} handshakes already pending");
+            continue;
+        };
+        let state = state.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_conn(sock, pool).await {
+            if let Err(e) = handle_conn(sock, state, permit).await {
                 tracing::error!("Conn {addr} error: {e}");
             }
+            drop(slot);
         });
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    tracing_subscriber::fmt()
-        .with_env_filter("debug")
-        .compact()
-        .init();
+    let cli = Cli::parse();
+
+    let file = Config::load(&cli.config)?;
+    let found = file.is_some();
+    let mut config = file.unwrap_or_default();
+    config.server.apply(&cli.server);
+
+    if let Some(Command::AvroSchema) = cli.command {
+        println!("{:#}", stream::avro::schema());
+        return Ok(());
+    }
+
+    let logs = tracing_subscriber::fmt()
+        .with_env_filter(config.server.log_level.as_str())
+        .compact();
+    // Stdout carries the readings then
+    if config.stdout.enabled {
+        logs.with_writer(std::io::stderr).init();
+    } else {
+        logs.init();
+    }
+    if !found {
+        tracing::info!("No config file at {}, using defaults", cli.config.display());
+    }
 
     tracing::info!("Connecting to the database...");
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(DATABASE_URI)
-        .await?;
+    let storage = database::connect(&config).await?;
     tracing::info!("Database connection created!");
 
-    tcp_server(pool).await
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            storage.migrate().await?;
+            let psk = config.server.psk(cli.server.psk.as_deref())?;
+            // A standalone gateway accepts no listeners
+            let psks =
+                if config.bluetooth.standalone && psk.is_none() && config.noise.psks.is_empty() {
+                    Psks::default()
+                } else {
+                    Psks::new(psk, &config.noise.psks)?
+                };
+            serve(config, storage, psks, cli.dev).await
+        }
+        Command::Migrate => {
+            storage.migrate().await?;
+            tracing::info!("Database is up to date");
+            Ok(())
+        }
+        Command::Prune(args) => maintenance::prune(storage.as_ref(), args).await,
+        Command::Reindex => maintenance::reindex(storage.as_ref()).await,
+        Command::Verify(args) => {
+            maintenance::verify(storage.as_ref(), config.humidity.formula, args).await
+        }
+        Command::Reprocess(args) => {
+            let keys = TagKeys::new(&config.tag_keys);
+            let calibrations = Calibrations::new(&config.calibration);
+            let formula = config.humidity.formula;
+            maintenance::reprocess(storage.as_ref(), &keys, &calibrations, formula, args).await
+        }
+        Command::Tags(command) => maintenance::tags(storage.as_ref(), command).await,
+        Command::AvroSchema => unreachable!("printed before setting up the logs"),
+    }
 }
 
-#[cfg(test)]
+async fn serve(
+    config: Config,
+    storage: Arc<dyn Storage>,
+    psks: Psks,
+    dev: bool,
+) -> Result<(), anyhow::Error> {
+    let registry = Arc::new(Registry::load(storage.as_ref()).await?);
+    let alerts = Alerts::new(&config.alerts, storage.alert_rules().await?)?;
+    let mut queued: Vec<QueuedSink> = Vec::new();
+    let (mqtt, mqtt_connection) = MqttSink::new(&config.mqtt, registry.clone()).unzip();
+    if let Some(mqtt) = mqtt {
+        queued.push((Arc::new(mqtt), Buffering::NONE));
+    }
+    let influx = InfluxSink::new(&config.influx)?;
+    let replace_database = influx.is_some() && config.influx.replace_database;
+    if let Some((influx, buffering)) = influx {
+        queued.push((Arc::new(influx), buffering));
+    }
+    queued.extend(stream::kafka(&config.kafka)?);
+    queued.extend(stream::nats(&config.nats).await?);
+    if config.stdout.enabled {
+        queued.push((Arc::new(StdoutSink), Buffering::NONE));
+    }
+    // Readings are acknowledged once they're in the database, or right away
+    // when InfluxDB replaces it
+    let primary: Option<Arc<dyn Sink>> =
+        (!replace_database).then(|| Arc::new(DatabaseSink(storage.clone())) as _);
+    let (sinks, sink_workers) = Dispatcher::new(primary, Outbox::new(&config.outbox)?, queued);
+    tracing::info!("Writing readings to {}", sinks.names().join(", "));
+    let state = Arc::new(AppState {
+        storage,
+        doors: DoorClassifier::new(&config.doors),
+        dedup: Deduplicator::new(
+            Duration::from_millis(config.dedup.window_ms),
+            Duration::from_secs(config.dedup.memory_secs),
+        ),
+        locator: Locator::new(&config.zones, config.location.margin_db),
+        latest: LatestStore::default(),
+        registry,
+        notifiers: Notifiers::from_config(&config.notifiers)?,
+        alerts,
+        watchdog: Watchdog::new(),
+        retention: Retention::new(&config.retention)?,
+        connections: Arc::default(),
+        quality: QualityTracker::new(config.quality.window),
+        tag_keys: TagKeys::new(&config.tag_keys),
+        calibrations: Calibrations::new(&config.calibration),
+        tag_filter: TagFilter::new(),
+        psks,
+        listener_keys: ListenerKeys::new(&config.noise.listeners),
+        bans: Bans::new(&config.handshake),
+        sinks,
+        live: broadcast::channel(LIVE_CAPACITY).0,
+        config,
+    });
+
+    tokio::try_join!(
+        tcp_server(state.clone()),
+        battery::watch(state.clone()),
+        report::schedule(state.clone()),
+        stats::summarize(state.clone()),
+        quality::record(state.clone()),
+        registry::reload(state.clone()),
+        alerts::watch(state.clone()),
+        offline::watch(state.clone()),
+        retention::run(state.clone()),
+        rollup::run(state.clone()),
+        mqtt::run(state.clone(), mqtt_connection),
+        sink::run(sink_workers),
+        http_ingest::serve(state.clone()),
+        official_gateway::serve(state.clone()),
+        bluetooth::scan(state.clone()),
+        mdns::advertise(state.clone()),
+        api::serve(state.clone(), &state.config.api.listen, dev)
+    )?;
+    Ok(())
+}
 
+#[cfg(test)]
 mod tests {
-    use super::RuuviV2;
+    use super::parse_timestamp;
+    use chrono::DateTime;
 
     #[test]
-    fn test_abs_humidity() {
-        let res = RuuviV2::calculate_abs_humidity(22.2f32, 52.4125f32);
-        assert_eq!(res, 10.29308183848681);
-    }
-
-    fn test_dew_point() {
-        let res = RuuviV2::calculate_dew_pont(22.22f32, 52.234f32);
-        assert_eq!(res, 12.0);
+    fn missing_timestamp_is_the_arrival_time() {
+        let arrival = DateTime::from_timestamp_millis(1_700_000_000_000).unwrap();
+        assert_eq!(parse_timestamp(None, arrival), arrival);
+        assert_eq!(parse_timestamp(Some(i64::MAX as u64), arrival), arrival);
+        assert_eq!(
+            parse_timestamp(Some(1_600_000_000_000), arrival),
+            DateTime::from_timestamp_millis(1_600_000_000_000).unwrap()
+        );
     }
 }
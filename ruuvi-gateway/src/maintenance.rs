@@ -0,0 +1,278 @@
+use crate::Ruuvi;
+use crate::calibration::Calibrations;
+use crate::cli::{PruneArgs, ReprocessArgs, TagsCommand, VerifyArgs};
+use crate::database::{DerivedRow, RawRow, Reprocessed, RowFilter, Storage, TABLES};
+use crate::encryption::TagKeys;
+use crate::mac::format_mac;
+use crate::registry::{RegisteredTag, Registry};
+use ruuvi_schema::RuuviRaw;
+use ruuvi_schema::convert::{self, HumidityFormula};
+
+/// Most rows failing to reprocess that are printed per table
+const REPROCESS_SAMPLES: u64 = 10;
+
+pub async fn prune(storage: &dyn Storage, args: PruneArgs) -> Result<(), anyhow::Error> {
+    if args.mac.is_none() && args.from.is_none() && args.to.is_none() {
+        anyhow::bail!("Refusing to prune everything, give at least one of --mac, --from or --to");
+    }
+
+    let filter = RowFilter {
+        mac: args.mac,
+        from: args.from,
+        to: args.to,
+    };
+    let verb = if args.dry_run {
+        "Would delete"
+    } else {
+        "Deleted"
+    };
+    for (table, rows) in storage.prune(&filter, args.dry_run).await? {
+        println!("{verb} {rows} rows from {table}");
+    }
+    Ok(())
+}
+
+pub async fn reindex(storage: &dyn Storage) -> Result<(), anyhow::Error> {
+    for table in TABLES {
+        println!("Reindexing {table}...");
+        storage.reindex(table).await?;
+    }
+    println!("Done");
+    Ok(())
+}
+
+pub async fn verify(
+    storage: &dyn Storage,
+    formula: HumidityFormula,
+    args: VerifyArgs,
+) -> Result<(), anyhow::Error> {
+    let filter = RowFilter {
+        mac: args.mac,
+        from: args.from,
+        to: args.to,
+    };
+    let mut printed = 0;
+    for table in ["tag_readings", "air_readings"] {
+        let mut checked = 0u64;
+        let mut table_mismatches = 0u64;
+        let mut check = |row: DerivedRow| {
+            let (id, recorded_at, mac, temp, rel_humidity, pressure, abs_humidity, dew_point) = row;
+            checked += 1;
+            let (Some(temp), Some(rel_humidity)) = (temp, rel_humidity) else {
+                return;
+            };
+            let pressure = pressure.map(|p| p as u32);
+            let expected_abs = convert::abs_humidity(formula, temp, rel_humidity, pressure);
+            let expected_dew = convert::dew_point(formula, temp, rel_humidity);
+            // Columns are stored as real in tag_readings, allow for the lost precision
+            let differs = |stored: Option<f64>, expected: f64| {
+                stored.is_some_and(|v| (v - expected).abs() > 0.01)
+            };
+            if differs(abs_humidity, expected_abs) || differs(dew_point, expected_dew) {
+                table_mismatches += 1;
+                if printed < args.samples {
+                    printed += 1;
+                    println!(
+                        "{table} id {id} {} at {recorded_at}: abs humidity {abs_humidity:?} (expected {expected_abs:.3}), dew point {dew_point:?} (expected {expected_dew:.3})",
+                        format_mac(&mac.bytes()),
+                    );
+                }
+            }
+        };
+        storage.visit_derived(table, &filter, &mut check).await?;
+        println!("{table}: checked {checked} rows, {table_mismatches} mismatching");
+    }
+    Ok(())
+}
+
+pub async fn reprocess(
+    storage: &dyn Storage,
+    keys: &TagKeys,
+    calibrations: &Calibrations,
+    formula: HumidityFormula,
+    args: ReprocessArgs,
+) -> Result<(), anyhow::Error> {
+    let filter = RowFilter {
+        mac: args.mac,
+        from: args.from,
+        to: args.to,
+    };
+    let verb = if args.dry_run {
+        "Would update"
+    } else {
+        "Updated"
+    };
+    for table in ["tag_readings", "air_readings"] {
+        let (mut decoded, mut derived, mut failed) = (0u64, 0u64, 0u64);
+        let mut after = 0;
+        loop {
+            let rows = storage
+                .raw_readings(table, &filter, after, args.batch_size.max(1))
+                .await?;
+            let Some(last) = rows.last() else {
+                break;
+            };
+            after = last.id;
+            let mut updates = Vec::with_capacity(rows.len());
+            for row in &rows {
+                match reprocess_row(row, keys, calibrations, formula) {
+                    Ok(Some(reprocessed)) => {
+                        match reprocessed {
+                            Reprocessed::Decoded(_) => decoded += 1,
+                            Reprocessed::Derived { .. } => derived += 1,
+                        }
+                        updates.push((row.id, reprocessed));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        failed += 1;
+                        if failed <= REPROCESS_SAMPLES {
+                            println!(
+                                "{table} id {} {} at {}: {e}",
+                                row.id,
+                                format_mac(&row.mac_address.bytes()),
+                                row.recorded_at
+                            );
+                        }
+                    }
+                }
+            }
+            if !args.dry_run && !updates.is_empty() {
+                storage.update_readings(table, &updates).await?;
+            }
+        }
+        println!(
+            "{table}: {verb} {decoded} rows from their raw payload and {derived} from the stored columns, {failed} failed"
+        );
+    }
+    Ok(())
+}
+
+/// `None` for a row without a payload, temperature or humidity to go on
+fn reprocess_row(
+    row: &RawRow,
+    keys: &TagKeys,
+    calibrations: &Calibrations,
+    formula: HumidityFormula,
+) -> Result<Option<Reprocessed>, anyhow::Error> {
+    let Some(payload) = &row.raw_payload else {
+        let (Some(temp), Some(rel_humidity)) = (row.temperature, row.relative_humidity) else {
+            return Ok(None);
+        };
+        let pressure = row.pressure.map(|p| p as u32);
+        return Ok(Some(Reprocessed::Derived {
+            abs_humidity: Some(convert::abs_humidity(formula, temp, rel_humidity, pressure)),
+            dew_point: Some(convert::dew_point(formula, temp, rel_humidity)),
+            humidity_formula: formula,
+        }));
+    };
+    // Neither the RSSI nor the advertised TX power is in the payload, their columns stay
+    let raw = RuuviRaw::parse(payload, row.mac_address.bytes(), 0, 0)
+        .map_err(|e| anyhow::anyhow!("{e}"))?;
+    let mut data = Ruuvi::from_raw(raw, row.recorded_at, keys, formula)?;
+    calibrations.apply(&mut data);
+    Ok(Some(Reprocessed::Decoded(data)))
+}
+
+pub async fn tags(storage: &dyn Storage, command: TagsCommand) -> Result<(), anyhow::Error> {
+    let registry = Registry::load(storage).await?;
+    match command {
+        TagsCommand::List => {
+            for tag in registry.all() {
+                let formats: Vec<_> = tag.formats.iter().map(|f| f.as_str()).collect();
+                println!(
+                    "{}  {}  {}  {}",
+                    format_mac(&tag.mac),
+                    tag.name,
+                    tag.location.as_deref().unwrap_or("-"),
+                    if formats.is_empty() {
+                        "any".to_owned()
+                    } else {
+                        formats.join(",")
+                    }
+                );
+            }
+        }
+        TagsCommand::Set(args) => {
+            let tag = RegisteredTag {
+                mac: args.mac,
+                name: args.name,
+                location: args.location,
+                formats: args.formats,
+            };
+            registry.set(storage, tag).await?;
+            println!("Registered {}", format_mac(&args.mac));
+        }
+        TagsCommand::Remove { tag } => {
+            let mac = registry
+                .resolve(&tag)
+                .ok_or_else(|| anyhow::anyhow!("No tag called {tag:?}"))?;
+            if !registry.remove(storage, mac).await? {
+                anyhow::bail!("{} isn't registered", format_mac(&mac));
+            }
+            println!("Removed {}", format_mac(&mac));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reprocess_row;
+    use crate::Ruuvi;
+    use crate::calibration::Calibrations;
+    use crate::database::{Mac, RawRow, Reprocessed};
+    use crate::encryption::TagKeys;
+    use chrono::Utc;
+    use ruuvi_schema::convert::HumidityFormula;
+
+    #[test]
+    fn decodes_payloads_or_derives_from_columns() {
+        let mac = [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F];
+        let keys = TagKeys::new(&[]);
+        let calibrations = Calibrations::new(&[]);
+        let mut row = RawRow {
+            id: 1,
+            recorded_at: Utc::now(),
+            mac_address: Mac(mac),
+            // Format 3 test vector
+            raw_payload: Some(vec![
+                0x03, 0x29, 0x1A, 0x1E, 0xCE, 0x1E, 0xFC, 0x18, 0xF9, 0x42, 0x02, 0xCA, 0x0B, 0x53,
+            ]),
+            temperature: Some(20.0),
+            relative_humidity: Some(50.0),
+            pressure: Some(100_000),
+        };
+        let Some(Reprocessed::Decoded(Ruuvi::V1(v1))) =
+            reprocess_row(&row, &keys, &calibrations, HumidityFormula::Magnus).unwrap()
+        else {
+            panic!("expected a decoded format 3 reading");
+        };
+        assert_eq!(
+            (v1.mac, v1.temp, v1.timestamp, v1.humidity_formula),
+            (mac, Some(26.3), row.recorded_at, HumidityFormula::Magnus)
+        );
+
+        row.raw_payload = Some(vec![0x08]);
+        assert!(reprocess_row(&row, &keys, &calibrations, HumidityFormula::Magnus).is_err());
+
+        row.raw_payload = None;
+        let Some(Reprocessed::Derived {
+            dew_point,
+            humidity_formula,
+            ..
+        }) = reprocess_row(&row, &keys, &calibrations, HumidityFormula::Magnus).unwrap()
+        else {
+            panic!("expected derived columns");
+        };
+        assert!((dew_point.unwrap() - 9.3).abs() < 0.1);
+        assert_eq!(humidity_formula, HumidityFormula::Magnus);
+
+        row.relative_humidity = None;
+        assert!(
+            reprocess_row(&row, &keys, &calibrations, HumidityFormula::Magnus)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
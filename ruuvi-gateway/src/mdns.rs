@@ -0,0 +1,50 @@
+//! DNS-SD advertisement of the TCP ingestion port, so listeners find the
+//! gateway after DHCP hands it a new address.
+
+use crate::AppState;
+use anyhow::Context;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::sync::Arc;
+
+/// Service type the listeners query, `<instance>._ruuvi-gw._tcp.local.`
+pub const SERVICE_TYPE: &str = "_ruuvi-gw._tcp.local.";
+
+pub async fn advertise(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let config = &state.config.mdns;
+    if !config.advertise || state.config.bluetooth.standalone {
+        return Ok(());
+    }
+    let listen = &state.config.server.listen;
+    let port = port(listen).with_context(|| format!("No port in listen address {listen}"))?;
+
+    let daemon = ServiceDaemon::new()?;
+    let host = format!("{}.local.", config.instance);
+    // Addresses follow the interfaces, so a new DHCP lease is announced as well
+    let service =
+        ServiceInfo::new(SERVICE_TYPE, &config.instance, &host, (), port, None)?.enable_addr_auto();
+    daemon.register(service)?;
+    tracing::info!(
+        "Advertising {}.{SERVICE_TYPE} on port {port}",
+        config.instance
+    );
+
+    // The daemon answers queries on its own thread for as long as it lives
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
+fn port(listen: &str) -> Option<u16> {
+    listen.rsplit_once(':')?.1.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::port;
+
+    #[test]
+    fn takes_the_port_of_the_listen_address() {
+        assert_eq!(port("0.0.0.0:9090"), Some(9090));
+        assert_eq!(port("[::]:9091"), Some(9091));
+        assert_eq!(port("localhost"), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Listener health in the Prometheus text format, from the latest report each
+//! listener stored in `listener_status`. Names match the listener's own metrics
+//! endpoint, with the listener as a label.
+
+use crate::database::ListenerStatusRow;
+use std::fmt::Write as _;
+
+type Value = fn(&ListenerStatusRow) -> Option<i64>;
+
+const GAUGES: [(&str, &str, Value); 4] = [
+    (
+        "ruuvi_listener_status_timestamp_seconds",
+        "Time of the latest health report",
+        |row| Some(row.reported_at.timestamp()),
+    ),
+    ("ruuvi_listener_uptime_seconds", "Time since boot", |row| {
+        Some(row.uptime_secs)
+    }),
+    ("ruuvi_listener_heap_free_bytes", "Free heap", |row| {
+        Some(row.heap_free)
+    }),
+    (
+        "ruuvi_listener_wifi_rssi_dbm",
+        "Signal of the Wi-Fi access point",
+        |row| row.wifi_rssi.map(i64::from),
+    ),
+];
+
+const COUNTERS: [(&str, &str, Value); 6] = [
+    (
+        "ruuvi_listener_adverts_total",
+        "Ruuvi advertisements received",
+        |row| Some(row.adverts),
+    ),
+    (
+        "ruuvi_listener_parse_failures_total",
+        "Advertisements that failed to parse",
+        |row| Some(row.parse_failures),
+    ),
+    (
+        "ruuvi_listener_dropped_total",
+        "Readings lost to a full queue",
+        |row| Some(row.dropped),
+    ),
+    (
+        "ruuvi_listener_queue_overflows_total",
+        "Times the packet queue ran full",
+        |row| Some(row.queue_overflows),
+    ),
+    (
+        "ruuvi_listener_frames_sent_total",
+        "Readings sent to the gateway",
+        |row| Some(row.frames_sent),
+    ),
+    (
+        "ruuvi_listener_reconnects_total",
+        "Reconnects to the gateway",
+        |row| Some(row.reconnects),
+    ),
+];
+
+pub fn render(rows: &[ListenerStatusRow]) -> String {
+    let mut body = String::new();
+    let families = GAUGES
+        .iter()
+        .map(|family| (family, "gauge"))
+        .chain(COUNTERS.iter().map(|family| (family, "counter")));
+    for ((name, help, value), kind) in families {
+        let _ = writeln!(body, "# HELP {name} {help}");
+        let _ = writeln!(body, "# TYPE {name} {kind}");
+        for row in rows {
+            if let Some(value) = value(row) {
+                let _ = writeln!(body, "{name}{{listener=\"{}\"}} {value}", label(&row.name));
+            }
+        }
+    }
+
+    let name = "ruuvi_listener_reset_info";
+    let _ = writeln!(body, "# HELP {name} Why the listener last started");
+    let _ = writeln!(body, "# TYPE {name} gauge");
+    for row in rows {
+        if let Some(reason) = &row.reset_reason {
+            let _ = writeln!(
+                body,
+                "{name}{{listener=\"{}\",reason=\"{}\"}} 1",
+                label(&row.name),
+                label(reason)
+            );
+        }
+    }
+    body
+}
+
+fn label(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::database::ListenerStatusRow;
+
+    #[test]
+    fn labels_each_listener() {
+        let row = ListenerStatusRow {
+            name: "hall \"north\"".to_owned(),
+            reported_at: "2025-11-30T12:00:00Z".parse().unwrap(),
+            uptime_secs: 3600,
+            reset_reason: Some("sender_stalled".to_owned()),
+            heap_free: 120_000,
+            wifi_rssi: None,
+            adverts: 500,
+            parse_failures: 0,
+            dropped: 2,
+            queue_overflows: 1,
+            frames_sent: 480,
+            reconnects: 1,
+        };
+        let body = render(&[row]);
+        assert!(
+            body.contains("ruuvi_listener_uptime_seconds{listener=\"hall \\\"north\\\"\"} 3600\n")
+        );
+        assert!(body.contains(
+            "ruuvi_listener_status_timestamp_seconds{listener=\"hall \\\"north\\\"\"} 1764504000\n"
+        ));
+        assert!(body.contains("# TYPE ruuvi_listener_dropped_total counter\n"));
+        assert!(body.contains(
+            "ruuvi_listener_reset_info{listener=\"hall \\\"north\\\"\",reason=\"sender_stalled\"} 1\n"
+        ));
+        assert!(!body.contains("ruuvi_listener_wifi_rssi_dbm{"));
+    }
+}
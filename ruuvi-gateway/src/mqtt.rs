@@ -0,0 +1,383 @@
+use crate::config::MqttConfig;
+use crate::http_ingest::parse_signature;
+use crate::listener_keys::Psks;
+use crate::mac::format_mac;
+use crate::registry::{RegisteredTag, Registry};
+use crate::sink::{Sink, StoredReading};
+use crate::{AppState, Ruuvi, ingest, raw_payload};
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, QoS};
+use ruuvi_schema::{RuuviRaw, TagModel};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Publishes waiting for the event loop, the sink's own queue holds the rest
+const QUEUE_CAPACITY: usize = 256;
+
+/// A Home Assistant sensor entity of a tag
+struct Entity {
+    key: &'static str,
+    name: &'static str,
+    /// Field of the published state
+    field: &'static str,
+    unit: &'static str,
+    device_class: &'static str,
+}
+
+const fn entity(
+    key: &'static str,
+    name: &'static str,
+    field: &'static str,
+    unit: &'static str,
+    device_class: &'static str,
+) -> Entity {
+    Entity {
+        key,
+        name,
+        field,
+        unit,
+        device_class,
+    }
+}
+
+const ENVIRONMENT: [Entity; 3] = [
+    entity("temperature", "Temperature", "temp", "°C", "temperature"),
+    entity("humidity", "Humidity", "rel_humidity", "%", "humidity"),
+    entity("pressure", "Pressure", "abs_pressure", "Pa", "pressure"),
+];
+
+const TAG: [Entity; 1] = [entity(
+    "battery",
+    "Battery voltage",
+    "battery_voltage",
+    "V",
+    "voltage",
+)];
+
+const AIR: [Entity; 2] = [
+    entity("co2", "CO2", "co2", "ppm", "carbon_dioxide"),
+    entity("pm2_5", "PM2.5", "pm2_5", "µg/m³", "pm25"),
+];
+
+/// The broker connection, driven by [`run`]
+pub struct Connection {
+    client: AsyncClient,
+    eventloop: EventLoop,
+}
+
+/// Publishes readings to an MQTT broker, announcing each tag to Home Assistant
+/// with its first reading and again when its registration changes
+pub struct MqttSink {
+    client: AsyncClient,
+    topic_prefix: String,
+    discovery_prefix: Option<String>,
+    registry: Arc<Registry>,
+    /// Registration each tag was announced with
+    announced: Mutex<HashMap<[u8; 6], Option<RegisteredTag>>>,
+}
+
+impl MqttSink {
+    /// `None` when no broker is configured. The connection has to be driven with [`run`].
+    pub fn new(config: &MqttConfig, registry: Arc<Registry>) -> Option<(Self, Connection)> {
+        let host = config.host.as_ref()?;
+        let mut options = MqttOptions::new(&config.client_id, host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        let sink = Self {
+            client: client.clone(),
+            topic_prefix: config.topic_prefix.clone(),
+            discovery_prefix: Some(config.discovery_prefix.clone()).filter(|p| !p.is_empty()),
+            registry,
+            announced: Mutex::default(),
+        };
+        Some((sink, Connection { client, eventloop }))
+    }
+
+    /// Publish a reading to `<topic_prefix>/<mac>/state`, announcing the tag first
+    async fn publish(&self, data: &Ruuvi) -> Result<(), anyhow::Error> {
+        let mac = data.mac();
+        let id = object_id(&mac);
+        let state_topic = format!("{}/{id}/state", self.topic_prefix);
+
+        let registered = self.registry.get(&mac);
+        if let Some(discovery_prefix) = &self.discovery_prefix
+            && self.announced.lock().unwrap().get(&mac) != Some(&registered)
+        {
+            for (entity, config) in discovery(data, registered.as_ref(), &state_topic) {
+                let topic = format!("{discovery_prefix}/sensor/{id}/{}/config", entity.key);
+                self.client
+                    .publish(topic, QoS::AtLeastOnce, true, config.to_string())
+                    .await?;
+            }
+            self.announced.lock().unwrap().insert(mac, registered);
+        }
+
+        let payload = match data {
+            Ruuvi::V2(v2) => serde_json::to_string(v2),
+            Ruuvi::E1(e1) => serde_json::to_string(e1),
+            Ruuvi::V1(v1) => serde_json::to_string(v1),
+            Ruuvi::V6(v6) => serde_json::to_string(v6),
+        };
+        match payload {
+            Ok(payload) => {
+                self.client
+                    .publish(state_topic, QoS::AtLeastOnce, false, payload)
+                    .await?
+            }
+            Err(e) => tracing::error!("Failed to serialize a reading for MQTT: {e}"),
+        }
+        Ok(())
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    fn write<'a>(&'a self, batch: &'a [StoredReading]) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            for reading in batch {
+                self.publish(&reading.data).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Keep the broker connection up, reconnecting after errors. With
+/// `[mqtt] listeners` the readings of MQTT listeners are taken in as well.
+pub async fn run(
+    state: Arc<AppState>,
+    connection: Option<Connection>,
+) -> Result<(), anyhow::Error> {
+    let Some(Connection {
+        client,
+        mut eventloop,
+    }) = connection
+    else {
+        return Ok(());
+    };
+    let config = &state.config.mqtt;
+    let listeners = format!("{}/listeners/+/readings", config.topic_prefix);
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("MQTT broker connected");
+                // Sessions are clean, so every connection subscribes again. Waiting
+                // for room in the request queue would block the loop draining it.
+                if config.listeners
+                    && let Err(e) = client.try_subscribe(&listeners, QoS::AtLeastOnce)
+                {
+                    tracing::error!("Failed to subscribe to {listeners}: {e}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                receive(&state, &publish.topic, &publish.payload)
+            }
+            Ok(_) => (),
+            Err(e) => {
+                tracing::warn!("MQTT connection error: {e}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// A reading published to `<topic_prefix>/listeners/<listener>/readings`: a
+/// `HMAC-SHA256 <hex>` line signing the JSON of `POST /api/ruuvi` after it
+fn receive(state: &Arc<AppState>, topic: &str, payload: &[u8]) {
+    let Some((_, listener)) = topic
+        .strip_suffix("/readings")
+        .and_then(|topic| topic.rsplit_once('/'))
+    else {
+        return;
+    };
+    let Some(body) = verified(&state.psks, payload) else {
+        tracing::warn!("Dropped a reading of {listener} without a valid signature");
+        return;
+    };
+
+    let raw: RuuviRaw = match serde_json::from_slice(body) {
+        Ok(raw) => raw,
+        Err(e) => {
+            tracing::warn!("Failed to parse a reading of {listener}: {e}");
+            return;
+        }
+    };
+    let raw_payload = raw_payload(state, &raw);
+    // The listener has no clock over MQTT, readings are timestamped on arrival
+    let mut data = match Ruuvi::from_raw(
+        raw,
+        Utc::now(),
+        &state.tag_keys,
+        state.config.humidity.formula,
+    ) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::warn!("{e}");
+            return;
+        }
+    };
+    state.calibrations.apply(&mut data);
+    tracing::debug!("Data: {data:?}");
+    // QoS 1 has the broker redeliver what wasn't acknowledged, not what wasn't stored
+    ingest(state, listener, data, raw_payload, None, None);
+}
+
+/// The JSON of `payload`, when its first line signs it with one of `psks`
+fn verified<'a>(psks: &Psks, payload: &'a [u8]) -> Option<&'a [u8]> {
+    let end = payload.iter().position(|&byte| byte == b'\n')?;
+    let signature = parse_signature(std::str::from_utf8(&payload[..end]).ok()?)?;
+    let body = &payload[end + 1..];
+    psks.verify(body, &signature).map(|_| body)
+}
+
+fn object_id(mac: &[u8; 6]) -> String {
+    let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+    format!("ruuvi_{hex}")
+}
+
+/// Home Assistant MQTT discovery configs of the sensors a reading carries.
+/// A registered tag is named after its registration and placed in its location.
+fn discovery<'a>(
+    data: &Ruuvi,
+    registered: Option<&RegisteredTag>,
+    state_topic: &str,
+) -> Vec<(&'a Entity, Value)> {
+    let mac = data.mac();
+    let id = object_id(&mac);
+    let model = data.model();
+    let extra: &[Entity] = match model {
+        TagModel::RuuviAir => &AIR,
+        _ => &TAG,
+    };
+    let model_name = match model {
+        TagModel::RuuviAir => "Ruuvi Air",
+        _ => "RuuviTag",
+    };
+    let formatted = format_mac(&mac);
+    let mut device = json!({
+        "identifiers": [id],
+        "connections": [["mac", formatted]],
+        "name": format!("{model_name} {}", &formatted[12..]),
+        "manufacturer": "Ruuvi Innovations",
+        "model": model_name,
+    });
+    if let Some(registered) = registered {
+        device["name"] = registered.name.clone().into();
+        if let Some(location) = &registered.location {
+            device["suggested_area"] = location.clone().into();
+        }
+    }
+
+    ENVIRONMENT
+        .iter()
+        .chain(extra)
+        .map(|entity| {
+            let config = json!({
+                "name": entity.name,
+                "unique_id": format!("{id}_{}", entity.key),
+                "state_topic": state_topic,
+                "value_template": format!("{{{{ value_json.{} }}}}", entity.field),
+                "unit_of_measurement": entity.unit,
+                "device_class": entity.device_class,
+                "state_class": "measurement",
+                "device": device,
+            });
+            (entity, config)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discovery, verified};
+    use crate::config::PskConfig;
+    use crate::listener_keys::Psks;
+    use crate::registry::RegisteredTag;
+    use crate::{Ruuvi, RuuviV2};
+    use chrono::Utc;
+    use ruuvi_schema::TagModel;
+
+    #[test]
+    fn announces_tag_sensors() {
+        let data = Ruuvi::V2(RuuviV2::sample(
+            [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+            1,
+            Utc::now(),
+        ));
+        let configs = discovery(&data, None, "ruuvi/ruuvi_aabbccddeeff/state");
+        let keys: Vec<_> = configs.iter().map(|(entity, _)| entity.key).collect();
+        assert_eq!(keys, ["temperature", "humidity", "pressure", "battery"]);
+
+        let (_, temperature) = &configs[0];
+        assert_eq!(temperature["unique_id"], "ruuvi_aabbccddeeff_temperature");
+        assert_eq!(temperature["value_template"], "{{ value_json.temp }}");
+        assert_eq!(temperature["device"]["name"], "RuuviTag EE:FF");
+
+        let registered = RegisteredTag {
+            mac: data.mac(),
+            name: "Sauna".to_owned(),
+            location: Some("Basement".to_owned()),
+            formats: Vec::new(),
+        };
+        let configs = discovery(&data, Some(&registered), "ruuvi/ruuvi_aabbccddeeff/state");
+        assert_eq!(configs[0].1["device"]["name"], "Sauna");
+        assert_eq!(configs[0].1["device"]["suggested_area"], "Basement");
+    }
+
+    #[test]
+    fn names_other_tag_models_as_ruuvitag() {
+        let data = Ruuvi::V2(RuuviV2 {
+            model: TagModel::RuuviTagPro,
+            ..RuuviV2::sample([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x0F], 1, Utc::now())
+        });
+        let configs = discovery(&data, None, "state");
+        assert_eq!(configs.len(), 4);
+        assert_eq!(configs[3].1["unique_id"], "ruuvi_aabbccddee0f_battery");
+        assert_eq!(configs[3].1["device"]["model"], "RuuviTag");
+        assert_eq!(configs[3].1["device"]["name"], "RuuviTag EE:0F");
+
+        // Without a location there's no area to suggest
+        let registered = RegisteredTag {
+            mac: data.mac(),
+            name: "Porch".to_owned(),
+            location: None,
+            formats: Vec::new(),
+        };
+        let configs = discovery(&data, Some(&registered), "state");
+        assert_eq!(configs[0].1["device"]["name"], "Porch");
+        assert!(configs[0].1["device"].get("suggested_area").is_none());
+    }
+
+    #[test]
+    fn takes_the_reading_after_a_valid_signature() {
+        let psks = Psks::new(
+            None,
+            &[PskConfig {
+                name: "sauna".to_owned(),
+                key: [b'b'; 32],
+            }],
+        )
+        .unwrap();
+        // printf '{"V2":{}}' | openssl dgst -sha256 -hmac bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb
+        let signature =
+            "HMAC-SHA256 cfe4e1b975344a5481e04cc3de311628a300d41516e73d15d5e962fad4eae69c";
+        let payload = format!("{signature}\n{{\"V2\":{{}}}}");
+        assert_eq!(
+            verified(&psks, payload.as_bytes()),
+            Some(br#"{"V2":{}}"#.as_slice())
+        );
+
+        let tampered = format!("{signature}\n{{\"V2\":{{ }}}}");
+        assert_eq!(verified(&psks, tampered.as_bytes()), None);
+        assert_eq!(verified(&psks, br#"{"V2":{}}"#), None);
+    }
+}
@@ -0,0 +1,275 @@
+use crate::http;
+use axum::http::{Uri, header};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+mod email;
+
+/// Window of `max_per_hour`
+const RATE_WINDOW: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+}
+
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &str;
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotifierConfig {
+    #[serde(flatten)]
+    pub kind: NotifierKind,
+    /// Most notifications sent per hour, the rest are dropped. 0 for no limit.
+    #[serde(default)]
+    pub max_per_hour: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NotifierKind {
+    /// Write notifications to the gateway log
+    Log,
+    /// POST every notification as JSON to `url`
+    Webhook { url: String },
+    /// Message a chat through a Telegram bot
+    Telegram {
+        bot_token: String,
+        /// Chat ID, or `@name` of a channel
+        chat_id: String,
+        #[serde(default = "default_telegram_api")]
+        api_url: String,
+    },
+    /// Mail through an SMTP relay
+    Email {
+        #[serde(default = "default_smtp_server")]
+        server: String,
+        from: String,
+        to: Vec<String>,
+    },
+}
+
+/// A local Bot API server, as the gateway can't reach api.telegram.org without TLS
+fn default_telegram_api() -> String {
+    "http://localhost:8081".to_owned()
+}
+
+fn default_smtp_server() -> String {
+    "localhost:25".to_owned()
+}
+
+/// Writes notifications to the gateway log
+pub struct LogNotifier;
+
+impl Notifier for LogNotifier {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            tracing::warn!("{}\n{}", notification.title, notification.body);
+            Ok(())
+        })
+    }
+}
+
+/// POSTs `{"title": .., "body": ..}` to a URL
+pub struct WebhookNotifier {
+    url: Uri,
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let body = serde_json::to_string(notification)?;
+            let headers = [(header::CONTENT_TYPE, "application/json")];
+            match http::post(&self.url, &headers, body).await? {
+                (status, _) if status.is_success() => Ok(()),
+                (status, response) => anyhow::bail!("{} answered {status} {response}", self.url),
+            }
+        })
+    }
+}
+
+/// Sends notifications with the Bot API `sendMessage` method
+pub struct TelegramNotifier {
+    url: Uri,
+    chat_id: String,
+}
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &str {
+        "telegram"
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let body = serde_json::json!({
+                "chat_id": self.chat_id,
+                "text": format!("{}\n{}", notification.title, notification.body),
+            });
+            let headers = [(header::CONTENT_TYPE, "application/json")];
+            match http::post(&self.url, &headers, body.to_string()).await? {
+                (status, _) if status.is_success() => Ok(()),
+                (status, response) => anyhow::bail!("Telegram answered {status} {response}"),
+            }
+        })
+    }
+}
+
+/// A notifier dropping what exceeds its hourly limit
+struct Limited {
+    notifier: Box<dyn Notifier>,
+    max_per_hour: u32,
+    /// When the notifications of the past hour were sent
+    sent: Mutex<VecDeque<Instant>>,
+    /// Dropped since the last one sent
+    dropped: AtomicU32,
+}
+
+impl Limited {
+    fn new(notifier: Box<dyn Notifier>, max_per_hour: u32) -> Self {
+        Self {
+            notifier,
+            max_per_hour,
+            sent: Mutex::default(),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Counts a notification sent at `now`, `false` when it's over the limit
+    fn allow(&self, now: Instant) -> bool {
+        if self.max_per_hour == 0 {
+            return true;
+        }
+        let mut sent = self.sent.lock().unwrap();
+        while sent
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= RATE_WINDOW)
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= self.max_per_hour as usize {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+/// Fans notifications out to every configured backend
+pub struct Notifiers {
+    notifiers: Vec<Limited>,
+}
+
+impl Notifiers {
+    pub fn from_config(configs: &[NotifierConfig]) -> Result<Self, anyhow::Error> {
+        let mut notifiers = Vec::new();
+        for config in configs {
+            let notifier: Box<dyn Notifier> = match &config.kind {
+                NotifierKind::Log => Box::new(LogNotifier),
+                NotifierKind::Webhook { url } => Box::new(WebhookNotifier { url: url.parse()? }),
+                NotifierKind::Telegram {
+                    bot_token,
+                    chat_id,
+                    api_url,
+                } => Box::new(TelegramNotifier {
+                    url: format!(
+                        "{}/bot{bot_token}/sendMessage",
+                        api_url.trim_end_matches('/')
+                    )
+                    .parse()?,
+                    chat_id: chat_id.clone(),
+                }),
+                NotifierKind::Email { server, from, to } => {
+                    Box::new(email::EmailNotifier::new(server, from, to)?)
+                }
+            };
+            notifiers.push(Limited::new(notifier, config.max_per_hour));
+        }
+        // Without configuration, notifications still end up in the log
+        if notifiers.is_empty() {
+            notifiers.push(Limited::new(Box::new(LogNotifier), 0));
+        }
+        Ok(Self { notifiers })
+    }
+
+    pub async fn notify(&self, notification: &Notification) {
+        for limited in &self.notifiers {
+            let notifier = &limited.notifier;
+            if !limited.allow(Instant::now()) {
+                if limited.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                    tracing::warn!(
+                        "Notifier {} reached {} notifications per hour, dropping the rest",
+                        notifier.name(),
+                        limited.max_per_hour
+                    );
+                }
+                continue;
+            }
+            let dropped = limited.dropped.swap(0, Ordering::Relaxed);
+            let result = if dropped > 0 {
+                let notification = Notification {
+                    title: notification.title.clone(),
+                    body: format!(
+                        "{}\n\n{dropped} earlier notifications were dropped by the rate limit",
+                        notification.body
+                    ),
+                };
+                notifier.send(&notification).await
+            } else {
+                notifier.send(notification).await
+            };
+            if let Err(e) = result {
+                tracing::error!("Notifier {} failed: {e}", notifier.name());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limited, LogNotifier, RATE_WINDOW};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn limits_notifications_per_hour() {
+        let limited = Limited::new(Box::new(LogNotifier), 2);
+        let start = Instant::now();
+        assert!(limited.allow(start));
+        assert!(limited.allow(start + Duration::from_secs(60)));
+        assert!(!limited.allow(start + Duration::from_secs(120)));
+        // The first one has left the window
+        assert!(limited.allow(start + RATE_WINDOW));
+        assert!(!limited.allow(start + RATE_WINDOW + Duration::from_secs(1)));
+
+        let unlimited = Limited::new(Box::new(LogNotifier), 0);
+        assert!((0..100).all(|_| unlimited.allow(start)));
+    }
+}
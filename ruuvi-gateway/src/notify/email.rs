@@ -0,0 +1,230 @@
+//! Plain SMTP client for the email notifier. Like the HTTP client it has no
+//! TLS, mail goes through a relay on the same host or network, like Postfix,
+//! which takes care of delivering it.
+
+use super::{Notification, Notifier};
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use std::fmt::Write as _;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Longest a delivery may take, connecting included
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct EmailNotifier {
+    server: String,
+    from: String,
+    to: Vec<String>,
+}
+
+impl EmailNotifier {
+    pub fn new(server: &str, from: &str, to: &[String]) -> Result<Self, anyhow::Error> {
+        if to.is_empty() {
+            anyhow::bail!("The email notifier needs at least one recipient");
+        }
+        // Addresses end up in SMTP commands as they are
+        for address in std::iter::once(from).chain(to.iter().map(String::as_str)) {
+            if !address.contains('@') || address.contains(['<', '>', '\r', '\n']) {
+                anyhow::bail!("{address:?} isn't an email address");
+            }
+        }
+        Ok(Self {
+            server: server.to_owned(),
+            from: from.to_owned(),
+            to: to.to_vec(),
+        })
+    }
+
+    fn message(&self, notification: &Notification) -> String {
+        let mut message = String::new();
+        let _ = write!(
+            message,
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from,
+            self.to.join(", "),
+            encode_header(&notification.title),
+            Utc::now().to_rfc2822()
+        );
+        for line in notification.body.lines() {
+            // A lone dot would end the message
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message
+    }
+
+    async fn deliver(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        notification: &Notification,
+    ) -> Result<(), anyhow::Error> {
+        let mut session = BufReader::new(stream);
+        reply(&mut session, 220).await?;
+        command(&mut session, "EHLO ruuvi-gateway", 250).await?;
+        command(&mut session, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        for to in &self.to {
+            command(&mut session, &format!("RCPT TO:<{to}>"), 250).await?;
+        }
+        command(&mut session, "DATA", 354).await?;
+        let message = self.message(notification);
+        command(&mut session, &format!("{message}."), 250).await?;
+        // The mail is accepted, a failing goodbye doesn't matter
+        let _ = command(&mut session, "QUIT", 221).await;
+        Ok(())
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn send<'a>(
+        &'a self,
+        notification: &'a Notification,
+    ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let stream = tokio::time::timeout(TIMEOUT, TcpStream::connect(&self.server))
+                .await
+                .map_err(|_| anyhow::anyhow!("{} didn't answer", self.server))??;
+            tokio::time::timeout(TIMEOUT, self.deliver(stream, notification))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("{} didn't finish in {}s", self.server, TIMEOUT.as_secs())
+                })?
+        })
+    }
+}
+
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut BufReader<S>,
+    line: &str,
+    expected: u16,
+) -> Result<(), anyhow::Error> {
+    let stream = session.get_mut();
+    stream.write_all(line.as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+    stream.flush().await?;
+    reply(session, expected).await
+}
+
+/// Reads a reply, multiline ones included, and checks it's of the class of `expected`
+async fn reply<S: AsyncRead + Unpin>(
+    session: &mut BufReader<S>,
+    expected: u16,
+) -> Result<(), anyhow::Error> {
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        if session.read_line(&mut line).await? == 0 {
+            anyhow::bail!("SMTP server closed the connection");
+        }
+        text.push_str(line.trim_end());
+        // `250-` continues the reply, `250 ` ends it
+        if line.as_bytes().get(3) != Some(&b'-') {
+            break;
+        }
+        text.push(' ');
+    }
+    let code: u16 = text
+        .get(..3)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Not an SMTP reply: {text}"))?;
+    if code / 100 != expected / 100 {
+        anyhow::bail!("SMTP server answered {text}");
+    }
+    Ok(())
+}
+
+/// RFC 2047 Q-encoding of a header with anything besides printable ASCII
+fn encode_header(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+        return value.to_owned();
+    }
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in value.chars() {
+        let mut bytes = [0; 4];
+        let encoded: String = match c {
+            ' ' => "_".to_owned(),
+            c if c.is_ascii_alphanumeric() => c.to_string(),
+            c => c
+                .encode_utf8(&mut bytes)
+                .bytes()
+                .map(|b| format!("={b:02X}"))
+                .collect(),
+        };
+        // Encoded words are at most 75 characters, characters aren't split
+        if word.len() + encoded.len() > 75 - "=?UTF-8?Q??=".len() {
+            words.push(std::mem::take(&mut word));
+        }
+        word.push_str(&encoded);
+    }
+    words.push(word);
+    words
+        .iter()
+        .map(|word| format!("=?UTF-8?Q?{word}?="))
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailNotifier, encode_header};
+    use crate::notify::Notification;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    #[tokio::test]
+    async fn talks_smtp() {
+        assert_eq!(
+            encode_header("Alert: CO₂"),
+            "=?UTF-8?Q?Alert=3A_CO=E2=82=82?="
+        );
+        assert_eq!(encode_header("Alert: Sauna"), "Alert: Sauna");
+
+        let notifier = EmailNotifier::new(
+            "localhost:25",
+            "gateway@example.com",
+            &["me@example.com".to_owned()],
+        )
+        .unwrap();
+        let (client, server) = tokio::io::duplex(4096);
+        let relay = tokio::spawn(async move {
+            let mut server = BufReader::new(server);
+            let mut received = Vec::new();
+            server.get_mut().write_all(b"220 relay\r\n").await.unwrap();
+            let mut line = String::new();
+            while server.read_line(&mut line).await.unwrap() > 0 {
+                let reply: &[u8] = match line.trim_end() {
+                    "EHLO ruuvi-gateway" => b"250-relay\r\n250 8BITMIME\r\n",
+                    "DATA" => b"354 go ahead\r\n",
+                    "." => b"250 queued\r\n",
+                    "QUIT" => b"221 bye\r\n",
+                    command if command.starts_with("MAIL") || command.starts_with("RCPT") => {
+                        b"250 ok\r\n"
+                    }
+                    _ => b"",
+                };
+                server.get_mut().write_all(reply).await.unwrap();
+                received.push(std::mem::take(&mut line));
+            }
+            received.concat()
+        });
+
+        let notification = Notification {
+            title: "Alert: Sauna".to_owned(),
+            body: "Too hot\n.5 °C over".to_owned(),
+        };
+        notifier.deliver(client, &notification).await.unwrap();
+        let received = relay.await.unwrap();
+        assert!(received.contains("RCPT TO:<me@example.com>\r\n"));
+        assert!(received.contains("Subject: Alert: Sauna\r\n"));
+        assert!(received.contains("\r\n\r\nToo hot\r\n..5 °C over\r\n.\r\n"));
+    }
+}
@@ -0,0 +1,310 @@
+//! Readings of official Ruuvi Gateway devices, posted to `/record` or published
+//! to an MQTT broker. Their `data` is the whole advertisement in hex, decoded with
+//! the parser of the listeners and stored like a listener's reading, so copies
+//! heard by both are deduplicated.
+
+use crate::auth::constant_time_eq;
+use crate::database::ListenerRow;
+use crate::mac::parse_mac;
+use crate::{AppState, Ruuvi, ingest, raw_payload};
+use anyhow::anyhow;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::routing::post;
+use chrono::{DateTime, Utc};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use ruuvi_schema::{ParseError, RuuviRaw};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// Messages waiting for the event loop, only subscriptions are sent
+const QUEUE_CAPACITY: usize = 16;
+
+/// Body of a `/record` post
+#[derive(Debug, Deserialize)]
+struct Record {
+    data: RecordData,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordData {
+    gw_mac: String,
+    /// Latest advertisement of each tag, keyed by its MAC
+    tags: HashMap<String, RecordTag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordTag {
+    rssi: i8,
+    #[serde(default, deserialize_with = "timestamp")]
+    timestamp: Option<i64>,
+    data: String,
+}
+
+/// Message published to `<prefix>/<gateway MAC>/<tag MAC>`
+#[derive(Debug, Deserialize)]
+struct MqttMessage {
+    gw_mac: String,
+    rssi: i8,
+    #[serde(default, deserialize_with = "timestamp")]
+    ts: Option<i64>,
+    data: String,
+}
+
+struct OfficialGateways {
+    state: Arc<AppState>,
+    /// Address each gateway was last recorded with in `listeners`
+    recorded: Mutex<HashMap<String, IpAddr>>,
+}
+
+pub async fn serve(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let gateways = Arc::new(OfficialGateways {
+        state,
+        recorded: Mutex::default(),
+    });
+    tokio::try_join!(serve_http(gateways.clone()), subscribe(gateways))?;
+    Ok(())
+}
+
+async fn serve_http(gateways: Arc<OfficialGateways>) -> Result<(), anyhow::Error> {
+    let config = &gateways.state.config.official_gateway;
+    let Some(listen) = config.listen.clone() else {
+        return Ok(());
+    };
+    if config.token.as_ref().is_none_or(String::is_empty) {
+        anyhow::bail!(
+            "[official_gateway] listen needs a token, anyone reaching it could post readings"
+        );
+    }
+    let app = Router::new()
+        .route("/record", post(record))
+        .with_state(gateways);
+
+    let listener = TcpListener::bind(&listen).await?;
+    tracing::info!("Ruuvi Gateway ingestion listening on {listen}");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Subscribe to the gateways' topics, resubscribing after reconnects
+async fn subscribe(gateways: Arc<OfficialGateways>) -> Result<(), anyhow::Error> {
+    let config = &gateways.state.config.official_gateway;
+    let Some(host) = &config.mqtt_host else {
+        return Ok(());
+    };
+    let mut options = MqttOptions::new(&config.mqtt_client_id, host, config.mqtt_port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &config.mqtt_username {
+        options.set_credentials(
+            username,
+            config.mqtt_password.as_deref().unwrap_or_default(),
+        );
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+    let topic = format!("{}/#", config.mqtt_topic_prefix);
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Subscribing to Ruuvi Gateway readings on {topic}");
+                client.try_subscribe(&topic, QoS::AtMostOnce)?;
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                gateways.message(&publish.topic, &publish.payload).await;
+            }
+            Ok(_) => (),
+            Err(e) => {
+                tracing::warn!("Ruuvi Gateway MQTT connection error: {e}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// `POST /record`, the gateway's "Custom HTTP server". It doesn't resend, so
+/// readings that fail to store are lost like a listener's without the outbox.
+async fn record(
+    State(gateways): State<Arc<OfficialGateways>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let state = &gateways.state;
+    let ip = peer.ip();
+    if state.bans.is_banned(ip) {
+        return Err((StatusCode::FORBIDDEN, String::new()));
+    }
+    // `serve_http` doesn't start without a token
+    let authorized = state
+        .config
+        .official_gateway
+        .token
+        .as_ref()
+        .is_some_and(|token| {
+            headers
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .is_some_and(|bearer| constant_time_eq(token.as_bytes(), bearer.as_bytes()))
+        });
+    if !authorized {
+        state.bans.failure(ip);
+        return Err((StatusCode::UNAUTHORIZED, String::new()));
+    }
+    state.bans.success(ip);
+
+    let record: Record =
+        serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let gateway = &record.data.gw_mac;
+    gateways.record_listener(gateway, ip, "http").await;
+    for (tag, t) in &record.data.tags {
+        if let Err(e) = gateways.relay(gateway, tag, t.rssi, t.timestamp, &t.data) {
+            tracing::warn!("Reading of {tag} from Ruuvi Gateway {gateway}: {e}");
+        }
+    }
+    Ok(StatusCode::OK)
+}
+
+impl OfficialGateways {
+    async fn message(&self, topic: &str, payload: &[u8]) {
+        // Topics not ending in a tag MAC, like `gw_status`, carry no readings
+        let Some(tag) = topic.rsplit('/').next().filter(|t| parse_mac(t).is_ok()) else {
+            return;
+        };
+        let message: MqttMessage = match serde_json::from_slice(payload) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!("Invalid Ruuvi Gateway message on {topic}: {e}");
+                return;
+            }
+        };
+        let gateway = &message.gw_mac;
+        // The broker hides the gateway's address
+        self.record_listener(gateway, Ipv4Addr::UNSPECIFIED.into(), "mqtt")
+            .await;
+        if let Err(e) = self.relay(gateway, tag, message.rssi, message.ts, &message.data) {
+            tracing::warn!("Reading of {tag} from Ruuvi Gateway {gateway}: {e}");
+        }
+    }
+
+    /// Create the gateway's row in `listeners`, named by its MAC, on its first
+    /// reading and again when its address changes. `transport` stands in for
+    /// the PSK.
+    async fn record_listener(&self, gateway: &str, address: IpAddr, transport: &str) {
+        if self.recorded.lock().unwrap().get(gateway) == Some(&address) {
+            return;
+        }
+        let row = ListenerRow {
+            name: gateway.to_owned(),
+            identified: false,
+            address,
+            psk: transport.to_owned(),
+            firmware: None,
+        };
+        match self.state.storage.upsert_listener(&row).await {
+            Ok(()) => {
+                self.recorded
+                    .lock()
+                    .unwrap()
+                    .insert(gateway.to_owned(), address);
+            }
+            Err(e) => tracing::error!("Failed to record Ruuvi Gateway {gateway}: {e}"),
+        }
+    }
+
+    /// Decode a relayed advertisement and pass it on like a listener's reading.
+    /// Advertisements of other devices, relayed with the gateway's Ruuvi filter
+    /// off, are skipped.
+    fn relay(
+        &self,
+        gateway: &str,
+        tag: &str,
+        rssi: i8,
+        timestamp: Option<i64>,
+        data: &str,
+    ) -> Result<(), anyhow::Error> {
+        let state = &self.state;
+        let advertisement = decode_hex(data).ok_or_else(|| anyhow!("data isn't hex: {data}"))?;
+        let raw = match RuuviRaw::from_advertisement(&advertisement, parse_mac(tag)?, rssi) {
+            Ok(raw) => raw,
+            Err(ParseError::NotRuuvi) => return Ok(()),
+            Err(e) => return Err(anyhow!("{e}")),
+        };
+        let raw_payload = raw_payload(state, &raw);
+        // A gateway without time sync sends zeros
+        let dt = timestamp
+            .filter(|&ts| ts > 0)
+            .and_then(|ts| DateTime::from_timestamp(ts, 0))
+            .unwrap_or_else(Utc::now);
+        let mut data = Ruuvi::from_raw(raw, dt, &state.tag_keys, state.config.humidity.formula)?;
+        state.calibrations.apply(&mut data);
+        tracing::debug!("Data: {data:?}");
+        ingest(state, gateway, data, raw_payload, None, None);
+        Ok(())
+    }
+}
+
+/// The timestamps are strings or numbers depending on the firmware version
+fn timestamp<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Number(i64),
+        Text(String),
+    }
+    Ok(match Option::<Timestamp>::deserialize(deserializer)? {
+        Some(Timestamp::Number(ts)) => Some(ts),
+        Some(Timestamp::Text(ts)) => ts.parse().ok(),
+        None => None,
+    })
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MqttMessage, Record, decode_hex};
+    use ruuvi_schema::RuuviRaw;
+
+    #[test]
+    fn reads_both_message_formats() {
+        // Format 5 test vector as relayed by a gateway, from its documentation
+        let data = "0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F";
+        let body = format!(
+            r#"{{"data": {{"coordinates": "", "timestamp": "1659633400", "gw_mac": "C8:25:2D:8E:9C:2C",
+                "tags": {{"CB:B8:33:4C:88:4F": {{"rssi": -53, "timestamp": "1659633398", "data": "{data}"}}}}}}}}"#
+        );
+        let record: Record = serde_json::from_str(&body).unwrap();
+        let tag = &record.data.tags["CB:B8:33:4C:88:4F"];
+        assert_eq!((tag.rssi, tag.timestamp), (-53, Some(1659633398)));
+
+        let message = format!(
+            r#"{{"gw_mac": "C8:25:2D:8E:9C:2C", "rssi": -62, "aoa": [], "gwts": 1659633400,
+                "ts": 1659633398, "data": "{data}", "coords": ""}}"#
+        );
+        let message: MqttMessage = serde_json::from_str(&message).unwrap();
+        assert_eq!(message.ts, Some(1659633398));
+
+        let advertisement = decode_hex(&message.data).unwrap();
+        let raw = RuuviRaw::from_advertisement(&advertisement, [0; 6], message.rssi).unwrap();
+        assert_eq!(raw.mac(), [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F]);
+        assert_eq!(decode_hex("0G"), None);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::AppState;
+use crate::mac::format_mac;
+use crate::notify::Notification;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A tag that hasn't been heard for the configured timeout
+#[derive(Debug, Clone, Serialize)]
+pub struct OfflineTag {
+    pub mac: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// `None` for a registered tag not heard since the gateway started
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Change {
+    Offline(Option<DateTime<Utc>>),
+    /// Back after being silent since the time
+    Online(Option<DateTime<Utc>>),
+}
+
+/// Tracks which tags went silent. Tags heard since the gateway started and
+/// registered tags are watched, so long retired tags don't raise alarms on
+/// every restart.
+pub struct Watchdog {
+    started: DateTime<Utc>,
+    /// Offline tags with when they were last heard
+    offline: Mutex<HashMap<[u8; 6], Option<DateTime<Utc>>>>,
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Self {
+            started: Utc::now(),
+            offline: Mutex::default(),
+        }
+    }
+
+    pub fn offline(&self) -> Vec<([u8; 6], Option<DateTime<Utc>>)> {
+        let mut offline: Vec<_> = self
+            .offline
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(mac, last_seen)| (*mac, *last_seen))
+            .collect();
+        offline.sort();
+        offline
+    }
+
+    /// Compare the last time each tag was heard against `timeout`
+    fn check(
+        &self,
+        now: DateTime<Utc>,
+        timeout: TimeDelta,
+        last_seen: &HashMap<[u8; 6], DateTime<Utc>>,
+        registered: &[[u8; 6]],
+    ) -> Vec<([u8; 6], Change)> {
+        let mut offline = self.offline.lock().unwrap();
+        let mut changes = Vec::new();
+        let watched = last_seen
+            .keys()
+            .chain(registered)
+            .copied()
+            .collect::<HashSet<_>>();
+        for &mac in &watched {
+            let seen = last_seen.get(&mac).copied();
+            let silent = now - seen.unwrap_or(self.started) >= timeout;
+            match (silent, offline.get(&mac).copied()) {
+                (true, None) => {
+                    offline.insert(mac, seen);
+                    changes.push((mac, Change::Offline(seen)));
+                }
+                (false, Some(since)) => {
+                    offline.remove(&mac);
+                    changes.push((mac, Change::Online(since)));
+                }
+                _ => {}
+            }
+        }
+        // Unregistered while offline
+        offline.retain(|mac, _| watched.contains(mac));
+        changes.sort_by_key(|(mac, _)| *mac);
+        changes
+    }
+}
+
+/// Check the tags every `check_interval_secs` and notify when one goes silent or comes back
+pub async fn watch(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let config = &state.config.offline;
+    if config.timeout_secs == 0 {
+        return Ok(());
+    }
+    let timeout = TimeDelta::seconds(config.timeout_secs as i64);
+    let mut interval =
+        tokio::time::interval(Duration::from_secs(config.check_interval_secs.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let last_seen = state
+            .latest
+            .all()
+            .into_iter()
+            .map(|reading| (reading.data.mac(), reading.data.timestamp()))
+            .collect();
+        let registered: Vec<_> = state.registry.all().iter().map(|tag| tag.mac).collect();
+        let now = Utc::now();
+        for (mac, change) in state.watchdog.check(now, timeout, &last_seen, &registered) {
+            let tag = match state.registry.get(&mac) {
+                Some(registered) => format!("{} ({})", registered.name, format_mac(&mac)),
+                None => format_mac(&mac),
+            };
+            let notification = match change {
+                Change::Offline(Some(seen)) => Notification {
+                    title: format!("{tag} is offline"),
+                    body: format!("Last heard {}, {} ago", seen, silence(now - seen)),
+                },
+                Change::Offline(None) => Notification {
+                    title: format!("{tag} is offline"),
+                    body: format!(
+                        "Not heard since the gateway started at {}",
+                        state.watchdog.started
+                    ),
+                },
+                Change::Online(since) => Notification {
+                    title: format!("{tag} is back online"),
+                    body: match since {
+                        Some(since) => format!("Silent since {since}"),
+                        None => "First heard since the gateway started".to_owned(),
+                    },
+                },
+            };
+            tracing::info!("{}", notification.title);
+            state.notifiers.notify(&notification).await;
+        }
+    }
+}
+
+/// Like `2h 5m`
+fn silence(duration: TimeDelta) -> String {
+    let minutes = duration.num_minutes();
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => format!("{minutes}m"),
+        (hours, minutes) => format!("{hours}h {minutes}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, Watchdog};
+    use chrono::TimeDelta;
+    use std::collections::HashMap;
+
+    #[test]
+    fn reports_silent_tags_once() {
+        let watchdog = Watchdog::new();
+        let start = watchdog.started;
+        let timeout = TimeDelta::minutes(15);
+        let heard = [1, 1, 1, 1, 1, 1];
+        let registered = [2, 2, 2, 2, 2, 2];
+        let mut last_seen = HashMap::from([(heard, start)]);
+        let at = |minutes| start + TimeDelta::minutes(minutes);
+
+        assert!(
+            watchdog
+                .check(at(10), timeout, &last_seen, &[registered])
+                .is_empty()
+        );
+        assert_eq!(
+            watchdog.check(at(20), timeout, &last_seen, &[registered]),
+            [
+                (heard, Change::Offline(Some(start))),
+                (registered, Change::Offline(None))
+            ]
+        );
+        assert!(
+            watchdog
+                .check(at(30), timeout, &last_seen, &[registered])
+                .is_empty()
+        );
+        assert_eq!(watchdog.offline().len(), 2);
+
+        last_seen.insert(heard, at(31));
+        assert_eq!(
+            watchdog.check(at(32), timeout, &last_seen, &[]),
+            [(heard, Change::Online(Some(start)))]
+        );
+        // Forgotten once unregistered
+        assert!(watchdog.offline().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::OtaConfig;
+use anyhow::Context;
+use ruuvi_schema::ota::{CHUNK_SIZE, Downlink, FirmwareInfo, Uplink};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.toml";
+
+/// `manifest.toml` in the firmware directory, paths are relative to it
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    version: String,
+    image: PathBuf,
+    /// Raw 64 byte Ed25519 signature of the image's SHA-256 digest
+    signature: PathBuf,
+}
+
+/// A signed listener image
+pub struct Firmware {
+    pub info: FirmwareInfo,
+    image: Vec<u8>,
+}
+
+impl Firmware {
+    /// Load the image described by the manifest, `None` when there is no manifest
+    pub async fn load(dir: &Path) -> Result<Option<Self>, anyhow::Error> {
+        let path = dir.join(MANIFEST);
+        let manifest = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => toml::from_str::<Manifest>(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        let image = tokio::fs::read(dir.join(&manifest.image))
+            .await
+            .with_context(|| format!("Failed to read image {}", manifest.image.display()))?;
+        let signature = tokio::fs::read(dir.join(&manifest.signature))
+            .await
+            .with_context(|| format!("Failed to read {}", manifest.signature.display()))?;
+        let signature: [u8; 64] = signature
+            .try_into()
+            .map_err(|s: Vec<u8>| anyhow::anyhow!("Signature is {} bytes, expected 64", s.len()))?;
+
+        let mut halves = [[0u8; 32]; 2];
+        halves[0].copy_from_slice(&signature[..32]);
+        halves[1].copy_from_slice(&signature[32..]);
+        let info = FirmwareInfo {
+            version: parse_version(&manifest.version)?,
+            size: u32::try_from(image.len()).context("Image too large")?,
+            sha256: Sha256::digest(&image).into(),
+            signature: halves,
+        };
+        Ok(Some(Self { info, image }))
+    }
+
+    fn chunk(&self, offset: u32) -> Option<Downlink<'_>> {
+        let start = usize::try_from(offset).ok()?;
+        let end = (start + CHUNK_SIZE).min(self.image.len());
+        let data = self.image.get(start..end)?;
+        Some(Downlink::Chunk {
+            version: self.info.version,
+            offset,
+            data,
+        })
+    }
+}
+
+/// Parse `major.minor.patch` into the wire format
+fn parse_version(version: &str) -> Result<u32, anyhow::Error> {
+    let parts = version
+        .split('.')
+        .map(str::parse::<u8>)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid firmware version {version}"))?;
+    let [major, minor, patch] = parts[..] else {
+        anyhow::bail!("Firmware version {version} is not major.minor.patch");
+    };
+    Ok(ruuvi_schema::ota::version(major, minor, patch))
+}
+
+pub fn format_version(version: u32) -> String {
+    let [_, major, minor, patch] = version.to_be_bytes();
+    format!("{major}.{minor}.{patch}")
+}
+
+/// Update state of one listener connection
+#[derive(Default)]
+pub struct Session {
+    firmware: Option<Firmware>,
+    downloading: bool,
+}
+
+impl Session {
+    /// Answer a listener's OTA message, if it needs an answer
+    pub async fn handle(
+        &mut self,
+        config: &OtaConfig,
+        listener: &str,
+        uplink: Uplink,
+    ) -> Option<Downlink<'_>> {
+        match uplink {
+            Uplink::Hello { version, confirmed } => {
+                if confirmed {
+                    tracing::info!("{listener} confirmed firmware {}", format_version(version));
+                }
+                let dir = config.dir.as_ref()?;
+                if !config.listeners.is_empty() && !config.listeners.iter().any(|l| l == listener) {
+                    return None;
+                }
+                match Firmware::load(dir).await {
+                    Ok(Some(firmware)) if firmware.info.version > version => {
+                        tracing::info!(
+                            "Offering firmware {} to {listener} running {}",
+                            format_version(firmware.info.version),
+                            format_version(version)
+                        );
+                        let info = firmware.info.clone();
+                        self.firmware = Some(firmware);
+                        Some(Downlink::Offer(info))
+                    }
+                    Ok(_) => None,
+                    Err(e) => {
+                        tracing::error!("Failed to load firmware: {e:#}");
+                        None
+                    }
+                }
+            }
+            Uplink::Request { version, offset } => {
+                let firmware = self
+                    .firmware
+                    .as_ref()
+                    .filter(|firmware| firmware.info.version == version)?;
+                if !self.downloading {
+                    self.downloading = true;
+                    tracing::info!(
+                        "{listener} downloading firmware {} from offset {offset}/{}",
+                        format_version(version),
+                        firmware.info.size
+                    );
+                }
+                firmware.chunk(offset)
+            }
+            Uplink::Complete { version } => {
+                tracing::info!(
+                    "{listener} installed firmware {}, rebooting",
+                    format_version(version)
+                );
+                None
+            }
+            Uplink::Failed { version, reason } => {
+                tracing::warn!(
+                    "{listener} rejected firmware {}: {reason:?}",
+                    format_version(version)
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_version, parse_version};
+
+    #[test]
+    fn version_roundtrip() {
+        let version = parse_version("1.12.3").unwrap();
+        assert!(version > parse_version("1.9.200").unwrap());
+        assert_eq!(format_version(version), "1.12.3");
+        assert!(parse_version("1.2").is_err());
+    }
+}
@@ -0,0 +1,602 @@
+use crate::Ruuvi;
+use crate::config::OutboxConfig;
+use crate::quality::Issues;
+use crate::sink::{Sink, StoredReading};
+use anyhow::Context;
+use ruuvi_schema::TagModel;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// First and longest wait before retrying the primary sink
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+struct Entry {
+    reading: StoredReading,
+    /// Failed writes so far
+    attempts: u32,
+}
+
+/// Line of the spool and dead-letter files
+#[derive(Serialize, Deserialize)]
+struct Spooled {
+    listener: String,
+    model: TagModel,
+    reading: Ruuvi,
+    /// Absent in lines spooled before it was kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw_payload: Option<Vec<u8>>,
+    /// Not serialized with the reading, absent in lines spooled before they were kept
+    #[serde(default)]
+    issues: Issues,
+    /// Of a format 3 reading
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<u16>,
+}
+
+impl Spooled {
+    fn new(reading: &StoredReading) -> Self {
+        let (issues, fingerprint) = match &reading.data {
+            Ruuvi::V2(v2) => (v2.issues, None),
+            Ruuvi::E1(e1) => (e1.issues, None),
+            Ruuvi::V1(v1) => (v1.issues, Some(v1.fingerprint)),
+            Ruuvi::V6(v6) => (v6.issues, None),
+        };
+        Self {
+            listener: reading.listener.clone(),
+            model: reading.data.model(),
+            reading: reading.data.clone(),
+            raw_payload: reading.raw_payload.clone(),
+            issues,
+            fingerprint,
+        }
+    }
+
+    fn into_reading(self) -> StoredReading {
+        let mut data = self.reading;
+        match &mut data {
+            Ruuvi::V2(v2) => {
+                v2.model = self.model;
+                v2.issues = self.issues;
+            }
+            Ruuvi::E1(e1) => e1.issues = self.issues,
+            Ruuvi::V1(v1) => {
+                v1.issues = self.issues;
+                v1.fingerprint = self.fingerprint.unwrap_or_default();
+            }
+            Ruuvi::V6(v6) => v6.issues = self.issues,
+        }
+        StoredReading {
+            data,
+            listener: self.listener,
+            raw_payload: self.raw_payload,
+        }
+    }
+}
+
+/// Change of the held readings for the spool writer, in the order they were held
+enum SpoolOp {
+    Append(Box<StoredReading>),
+    /// The oldest reading was written or given up
+    Remove,
+    /// Nothing is held anymore
+    Clear,
+}
+
+/// Holds the readings the primary sink couldn't take, in arrival order, and
+/// retries them until it's back. Readings failing `max_attempts` times go to
+/// the dead-letter file.
+pub struct Outbox {
+    entries: Mutex<VecDeque<Entry>>,
+    /// Wakes [`drain`] when the first reading is held
+    held: Notify,
+    /// Set while the outbox is full, so it's logged once
+    full: AtomicBool,
+    capacity: usize,
+    max_attempts: u32,
+    /// Sent to while `entries` is locked, so the spool follows its order.
+    /// The writer thread does the file I/O.
+    spool: Option<(mpsc::Sender<SpoolOp>, JoinHandle<()>)>,
+    dead_letter: Option<PathBuf>,
+}
+
+impl Outbox {
+    /// `None` when disabled with a zero capacity. Readings spooled before a
+    /// restart are held again, except the ones already written.
+    pub fn new(config: &OutboxConfig) -> Result<Option<Self>, anyhow::Error> {
+        if config.capacity == 0 {
+            return Ok(None);
+        }
+        let (entries, spool) = match &config.spool_file {
+            Some(path) => {
+                let (entries, writer) = SpoolWriter::load(path)?;
+                let (ops, rx) = mpsc::channel();
+                let thread = std::thread::Builder::new()
+                    .name("outbox-spool".to_owned())
+                    .spawn(move || writer.run(rx))?;
+                (entries, Some((ops, thread)))
+            }
+            None => (VecDeque::new(), None),
+        };
+        if !entries.is_empty() {
+            tracing::info!(
+                "{} spooled readings waiting for the database",
+                entries.len()
+            );
+        }
+        Ok(Some(Self {
+            entries: Mutex::new(entries),
+            held: Notify::new(),
+            full: AtomicBool::new(false),
+            capacity: config.capacity,
+            max_attempts: config.max_attempts.max(1),
+            spool,
+            dead_letter: config.dead_letter_file.clone(),
+        }))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_empty()
+    }
+
+    /// `false` when the outbox is full and the reading wasn't taken
+    pub fn push(&self, reading: StoredReading) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            if !self.full.swap(true, Ordering::Relaxed) {
+                tracing::error!("Outbox is full, readings are refused until it drains");
+            }
+            return false;
+        }
+        if entries.is_empty() {
+            tracing::warn!("Holding readings in the outbox until the database is back");
+        }
+        self.spool(SpoolOp::Append(Box::new(reading.clone())));
+        entries.push_back(Entry {
+            reading,
+            attempts: 0,
+        });
+        self.held.notify_one();
+        true
+    }
+
+    fn spool(&self, op: SpoolOp) {
+        if let Some((ops, _)) = &self.spool {
+            // The writer only stops when the outbox is dropped
+            let _ = ops.send(op);
+        }
+    }
+}
+
+impl Drop for Outbox {
+    /// Lets the writer finish the queued changes
+    fn drop(&mut self) {
+        if let Some((ops, thread)) = self.spool.take() {
+            drop(ops);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Write the held readings to `primary`, oldest first, retrying with a growing
+/// delay while it fails
+pub async fn drain(outbox: &Outbox, primary: &dyn Sink) {
+    let mut delay = RETRY_DELAY;
+    loop {
+        let head = outbox
+            .entries
+            .lock()
+            .unwrap()
+            .front()
+            .map(|entry| entry.reading.clone());
+        let Some(reading) = head else {
+            outbox.held.notified().await;
+            continue;
+        };
+
+        let result = primary.write(std::slice::from_ref(&reading)).await;
+        if let Err(e) = &result {
+            tracing::warn!("Failed to write a held reading to {}: {e}", primary.name());
+        }
+        if outbox.settle(result.is_ok()) {
+            delay = RETRY_DELAY;
+        } else {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+impl Outbox {
+    /// Removes the head after it was written, or counts the failed attempt and
+    /// gives up on it after `max_attempts`. Returns `written`.
+    fn settle(&self, written: bool) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let mut given_up = None;
+        if written {
+            entries.pop_front();
+            self.spool(SpoolOp::Remove);
+        } else if let Some(entry) = entries.front_mut() {
+            entry.attempts += 1;
+            if entry.attempts >= self.max_attempts {
+                given_up = entries.pop_front();
+                self.spool(SpoolOp::Remove);
+            }
+        }
+        if entries.is_empty() {
+            tracing::info!("Outbox drained");
+            self.full.store(false, Ordering::Relaxed);
+            self.spool(SpoolOp::Clear);
+        }
+        drop(entries);
+        if let Some(entry) = given_up {
+            dead_letter(self.dead_letter.as_deref(), &entry.reading);
+        }
+        written
+    }
+}
+
+/// Keeps the spool file in step with the held readings. Readings are appended
+/// as lines, the ones taken off the front are skipped by the byte offset kept
+/// next to it, until nothing is held and the file is emptied.
+struct SpoolWriter {
+    path: PathBuf,
+    /// `<spool>.offset`
+    offset_path: PathBuf,
+    /// Where the line of each held reading ends
+    ends: VecDeque<u64>,
+    /// End of the last line
+    len: u64,
+}
+
+impl SpoolWriter {
+    /// The readings held in the spool, past its offset. A line cut short by a
+    /// crash is all that's lost.
+    fn load(path: &Path) -> Result<(VecDeque<Entry>, Self), anyhow::Error> {
+        let mut offset_path = path.as_os_str().to_owned();
+        offset_path.push(".offset");
+        let mut writer = Self {
+            path: path.to_owned(),
+            offset_path: offset_path.into(),
+            ends: VecDeque::new(),
+            len: 0,
+        };
+        let mut entries = VecDeque::new();
+        // Read and written, a line cut short at the end is removed
+        let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((entries, writer)),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        writer.len = file.metadata()?.len();
+        let offset = match std::fs::read_to_string(&writer.offset_path) {
+            Ok(offset) => offset.trim().parse().unwrap_or(0),
+            Err(_) => 0,
+        };
+        // Past the end when the spool was emptied before its offset was reset
+        let mut end = if offset > writer.len { 0 } else { offset };
+        file.seek(SeekFrom::Start(end))?;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            if !line.ends_with('\n') {
+                tracing::warn!("Skipping a cut spooled reading in {}", path.display());
+                drop(reader);
+                file.set_len(end)?;
+                writer.len = end;
+                break;
+            }
+            end += read as u64;
+            match serde_json::from_str::<Spooled>(&line) {
+                Ok(spooled) => {
+                    entries.push_back(Entry {
+                        reading: spooled.into_reading(),
+                        attempts: 0,
+                    });
+                    writer.ends.push_back(end);
+                }
+                Err(e) => tracing::warn!("Skipping a spooled reading in {}: {e}", path.display()),
+            }
+        }
+        Ok((entries, writer))
+    }
+
+    fn run(mut self, ops: mpsc::Receiver<SpoolOp>) {
+        for op in ops {
+            let result = match op {
+                SpoolOp::Append(reading) => self.append(&reading),
+                SpoolOp::Remove => match self.ends.pop_front() {
+                    Some(end) => self.set_offset(end),
+                    None => Ok(()),
+                },
+                SpoolOp::Clear => self.clear(),
+            };
+            if let Err(e) = result {
+                tracing::warn!("Failed to update the spool {}: {e}", self.path.display());
+            }
+        }
+    }
+
+    fn append(&mut self, reading: &StoredReading) -> Result<(), anyhow::Error> {
+        // A failed append leaves its reading without a line, taking it off moves nothing
+        self.ends.push_back(self.len);
+        append(&self.path, reading)?;
+        self.len = std::fs::metadata(&self.path)?.len();
+        if let Some(end) = self.ends.back_mut() {
+            *end = self.len;
+        }
+        Ok(())
+    }
+
+    /// Written next to the spool and renamed over the old one, so it's never partial
+    fn set_offset(&self, offset: u64) -> Result<(), anyhow::Error> {
+        let mut tmp = self.offset_path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, offset.to_string())?;
+        std::fs::rename(&tmp, &self.offset_path)?;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), anyhow::Error> {
+        self.ends.clear();
+        self.len = 0;
+        // Emptied first, an offset left past the end is ignored on load
+        File::create(&self.path)?;
+        self.set_offset(0)
+    }
+}
+
+fn dead_letter(path: Option<&Path>, reading: &StoredReading) {
+    let mac = reading.data.mac();
+    match path {
+        Some(path) => match append(path, reading) {
+            Ok(()) => tracing::error!("Gave up on a reading of {mac:X?}, see {}", path.display()),
+            Err(e) => tracing::error!(
+                "Gave up on a reading of {mac:X?}, writing it to {} failed: {e}",
+                path.display()
+            ),
+        },
+        None => tracing::error!("Gave up on a reading of {mac:X?}, it's lost"),
+    }
+}
+
+fn append(path: &Path, reading: &StoredReading) -> Result<(), anyhow::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_vec(&Spooled::new(reading))?;
+    line.push(b'\n');
+    // One write, so a failure can't leave half a line for the next one to join
+    file.write_all(&line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outbox;
+    use crate::config::OutboxConfig;
+    use crate::quality::Issues;
+    use crate::sink::StoredReading;
+    use crate::{Ruuvi, RuuviV1, RuuviV2};
+    use chrono::DateTime;
+    use ruuvi_schema::TagModel;
+    use ruuvi_schema::convert::HumidityFormula;
+    use std::io::Write;
+
+    fn reading(measurement_seq: u16) -> StoredReading {
+        StoredReading {
+            data: Ruuvi::V2(RuuviV2 {
+                temp: Some(20.5),
+                dew_point_temp: Some(9.5),
+                rel_humidity: Some(49.0),
+                abs_humidity: None,
+                acc_x: Some(-4),
+                acc_y: None,
+                movement_counter: Some(7),
+                model: TagModel::RuuviTagPro,
+                ..RuuviV2::sample(
+                    [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+                    measurement_seq,
+                    DateTime::from_timestamp_millis(1_700_000_000_123).unwrap(),
+                )
+            }),
+            listener: "hall".to_owned(),
+            raw_payload: Some(vec![0x05, 0x12, 0xFC]),
+        }
+    }
+
+    fn held(outbox: &Outbox) -> Vec<StoredReading> {
+        outbox
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.reading.clone())
+            .collect()
+    }
+
+    #[test]
+    fn spools_and_gives_up() {
+        let dir = std::env::temp_dir().join(format!("ruuvi-outbox-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = OutboxConfig {
+            capacity: 2,
+            spool_file: Some(dir.join("spool.jsonl")),
+            max_attempts: 1,
+            dead_letter_file: Some(dir.join("dead.jsonl")),
+        };
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        assert!(outbox.push(reading(1)));
+        assert!(outbox.push(reading(2)));
+        assert!(!outbox.push(reading(3)));
+        drop(outbox);
+
+        // A restart holds the spooled readings again, models included
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        let held = held(&outbox);
+        assert_eq!(held.len(), 2);
+        assert_eq!(held[0].data, reading(1).data);
+        assert_eq!(held[0].listener, "hall");
+        assert_eq!(held[0].raw_payload, reading(1).raw_payload);
+
+        assert!(!outbox.settle(false));
+        let dead = std::fs::read_to_string(dir.join("dead.jsonl")).unwrap();
+        assert_eq!(dead.lines().count(), 1);
+        assert!(outbox.settle(true));
+        assert!(outbox.is_empty());
+        drop(outbox);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("spool.jsonl")).unwrap(),
+            ""
+        );
+        assert!(Outbox::new(&config).unwrap().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn restart_after_a_partial_drain_doesnt_replay_written_readings() {
+        let dir = std::env::temp_dir().join(format!("ruuvi-outbox-drain-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = OutboxConfig {
+            capacity: 10,
+            spool_file: Some(dir.join("spool.jsonl")),
+            max_attempts: 5,
+            dead_letter_file: None,
+        };
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        for seq in 1..=3 {
+            assert!(outbox.push(reading(seq)));
+        }
+        assert!(outbox.settle(true));
+        drop(outbox);
+
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        let seqs: Vec<_> = held(&outbox)
+            .iter()
+            .map(|reading| reading.data.measurement_seq())
+            .collect();
+        assert_eq!(seqs, [2, 3]);
+        // Held again after the restart, then written
+        assert!(outbox.push(reading(4)));
+        assert!(outbox.settle(true));
+        drop(outbox);
+
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        let seqs: Vec<_> = held(&outbox)
+            .iter()
+            .map(|reading| reading.data.measurement_seq())
+            .collect();
+        assert_eq!(seqs, [3, 4]);
+        drop(outbox);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_format_3_fingerprint_and_issues() {
+        let dir = std::env::temp_dir().join(format!("ruuvi-outbox-v1-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = OutboxConfig {
+            capacity: 10,
+            spool_file: Some(dir.join("spool.jsonl")),
+            max_attempts: 5,
+            dead_letter_file: None,
+        };
+        let v1 = StoredReading {
+            data: Ruuvi::V1(RuuviV1 {
+                mac: [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+                temp: Some(26.3),
+                dew_point_temp: None,
+                rel_humidity: Some(20.5),
+                abs_humidity: None,
+                humidity_formula: HumidityFormula::Magnus,
+                uncalibrated: None,
+                abs_pressure: Some(102_766),
+                acc_x: Some(-1000),
+                acc_y: Some(-1726),
+                acc_z: Some(714),
+                battery_voltage: Some(2.899),
+                timestamp: DateTime::from_timestamp_millis(1_700_000_000_123).unwrap(),
+                rssi: -70,
+                fingerprint: 0xBEEF,
+                issues: Issues {
+                    sentinels: 0,
+                    clamped: 1,
+                    missing_timestamp: true,
+                },
+            }),
+            listener: "hall".to_owned(),
+            raw_payload: None,
+        };
+        let mut v2 = reading(1);
+        if let Ruuvi::V2(data) = &mut v2.data {
+            data.issues.sentinels = 2;
+        }
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        assert!(outbox.push(v1.clone()));
+        assert!(outbox.push(v2.clone()));
+        drop(outbox);
+
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        let held = held(&outbox);
+        assert_eq!(held[0].data, v1.data);
+        assert_eq!(held[1].data, v2.data);
+        drop(outbox);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn skips_cut_lines_and_disables_at_zero_capacity() {
+        let config = OutboxConfig {
+            capacity: 0,
+            spool_file: None,
+            max_attempts: 0,
+            dead_letter_file: None,
+        };
+        assert!(Outbox::new(&config).unwrap().is_none());
+
+        let dir = std::env::temp_dir().join(format!("ruuvi-outbox-cut-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spool = dir.join("spool.jsonl");
+        let config = OutboxConfig {
+            capacity: 10,
+            spool_file: Some(spool.clone()),
+            ..config
+        };
+        // A missing spool is an empty one
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        assert!(outbox.push(reading(1)));
+        drop(outbox);
+        let mut line = std::fs::read_to_string(&spool).unwrap();
+        line.truncate(line.len() / 2);
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&spool)
+            .unwrap()
+            .write_all(line.as_bytes())
+            .unwrap();
+
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        assert_eq!(held(&outbox).len(), 1);
+        // The cut line is gone, a new reading doesn't join it
+        assert!(outbox.push(reading(2)));
+        drop(outbox);
+        let outbox = Outbox::new(&config).unwrap().unwrap();
+        assert_eq!(held(&outbox).len(), 2);
+        // Zero attempts acts as one, without a dead-letter file the reading is lost
+        assert!(!outbox.settle(false));
+        assert!(!outbox.settle(false));
+        assert!(outbox.is_empty());
+        drop(outbox);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
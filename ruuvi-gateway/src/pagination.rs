@@ -0,0 +1,67 @@
+use crate::database::{HistoryCursor, HistoryRow};
+use chrono::DateTime;
+
+pub const DEFAULT_LIMIT: i64 = 1000;
+pub const MAX_LIMIT: i64 = 10_000;
+
+impl HistoryCursor {
+    /// Opaque cursor string handed to clients
+    pub fn encode(&self) -> String {
+        format!(
+            "{}.{}.{}",
+            self.timestamp.timestamp_micros(),
+            self.format,
+            self.id
+        )
+    }
+
+    pub fn decode(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(3, '.');
+        let micros = parts.next()?.parse().ok()?;
+        let format = parts.next()?.to_owned();
+        let id = parts.next()?.parse().ok()?;
+        Some(Self {
+            timestamp: DateTime::from_timestamp_micros(micros)?,
+            format,
+            id,
+        })
+    }
+}
+
+/// Clamp a requested page size into the allowed range
+pub fn page_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+}
+
+/// Split one extra fetched row off the page, returning the cursor for the next page if there is one
+pub fn next_cursor(rows: &mut Vec<HistoryRow>, limit: i64) -> Option<String> {
+    if rows.len() as i64 <= limit {
+        return None;
+    }
+    rows.truncate(limit as usize);
+    rows.last().map(|row| {
+        HistoryCursor {
+            timestamp: row.timestamp,
+            format: row.format.clone(),
+            id: row.id,
+        }
+        .encode()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::HistoryCursor;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = HistoryCursor {
+            timestamp: Utc.with_ymd_and_hms(2025, 1, 2, 3, 4, 5).unwrap(),
+            format: "e1".to_owned(),
+            id: 42,
+        };
+        assert_eq!(HistoryCursor::decode(&cursor.encode()), Some(cursor));
+        assert_eq!(HistoryCursor::decode("garbage"), None);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::mac;
+use crate::{AppState, Ruuvi};
+use ruuvi_schema::{RuuviRawE1, RuuviRawV1, RuuviRawV2, RuuviRawV6, TagModel};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Larger jumps in the measurement sequence are a tag reboot, not lost packets
+const MAX_SEQ_GAP: u32 = 1000;
+
+/// Problems found in a single reading, counted before the values are clamped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Issues {
+    /// Fields reporting the format's "not available" value
+    pub sentinels: u8,
+    /// Fields outside the documented range, clamped on conversion
+    pub clamped: u8,
+    /// The listener couldn't timestamp the reading, the arrival time is used
+    pub missing_timestamp: bool,
+}
+
+impl Issues {
+    pub fn v2(raw: &RuuviRawV2) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-5-rawv2
+        // C5 has no acceleration at all, single missing axes are sensor faults
+        let has_acceleration = !raw.lacks_acceleration();
+        let mut sentinels = [
+            raw.valid_temp().is_none(),
+            raw.valid_battery_mv().is_none(),
+            raw.valid_tx_power_dbm().is_none(),
+            raw.valid_movement_counter().is_none(),
+            raw.valid_measurement_seq().is_none(),
+        ]
+        .into_iter()
+        .chain(raw.valid_acc().map(|a| has_acceleration && a.is_none()))
+        .filter(|&s| s)
+        .count() as u8;
+        // A Pro lacks these sensors by design
+        if raw.model() == TagModel::RuuviTag {
+            sentinels +=
+                raw.valid_humidity().is_none() as u8 + raw.valid_pressure().is_none() as u8;
+        }
+        let humidity_clamped = raw.valid_humidity().is_some_and(|h| h > 40_000);
+        Self {
+            sentinels,
+            clamped: humidity_clamped as u8,
+            missing_timestamp: raw.timestamp.is_none(),
+        }
+    }
+
+    /// Format 3 has no "not available" values
+    pub fn v1(raw: &RuuviRawV1) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-3-rawv1
+        Self {
+            sentinels: 0,
+            clamped: (raw.humidity > 200) as u8,
+            missing_timestamp: raw.timestamp.is_none(),
+        }
+    }
+
+    pub fn e1(raw: &RuuviRawE1) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-e1
+        let pm = raw.valid_pm();
+        let sentinels = [
+            raw.valid_temp().is_none(),
+            raw.valid_humidity().is_none(),
+            raw.valid_pressure().is_none(),
+            raw.valid_co2().is_none(),
+            raw.valid_voc_index().is_none(),
+            raw.valid_nox_index().is_none(),
+            raw.valid_luminosity().is_none(),
+            raw.valid_measurement_seq().is_none(),
+        ]
+        .into_iter()
+        .chain(pm.iter().map(Option::is_none))
+        .filter(|&s| s)
+        .count() as u8;
+        let clamped = [
+            raw.valid_humidity().is_some_and(|v| v > 40_000),
+            raw.valid_co2().is_some_and(|v| v > 40_000),
+            raw.valid_voc_index().is_some_and(|v| v > 500),
+            raw.valid_nox_index().is_some_and(|v| v > 500),
+            raw.valid_luminosity().is_some_and(|v| v > 14_428_400),
+        ]
+        .into_iter()
+        .chain(pm.iter().map(|v| v.is_some_and(|v| v > 10_000)))
+        .filter(|&c| c)
+        .count() as u8;
+        Self {
+            sentinels,
+            clamped,
+            missing_timestamp: raw.timestamp.is_none(),
+        }
+    }
+
+    pub fn v6(raw: &RuuviRawV6) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-6
+        let sentinels = [
+            raw.valid_temp().is_none(),
+            raw.valid_humidity().is_none(),
+            raw.valid_pressure().is_none(),
+            raw.valid_pm2_5().is_none(),
+            raw.valid_co2().is_none(),
+            raw.valid_voc_index().is_none(),
+            raw.valid_nox_index().is_none(),
+            raw.valid_luminosity().is_none(),
+        ]
+        .into_iter()
+        .filter(|&s| s)
+        .count() as u8;
+        let clamped = [
+            raw.valid_humidity().is_some_and(|v| v > 40_000),
+            raw.valid_pm2_5().is_some_and(|v| v > 10_000),
+            raw.valid_co2().is_some_and(|v| v > 40_000),
+            raw.valid_voc_index().is_some_and(|v| v > 500),
+            raw.valid_nox_index().is_some_and(|v| v > 500),
+        ]
+        .into_iter()
+        .filter(|&c| c)
+        .count() as u8;
+        Self {
+            sentinels,
+            clamped,
+            missing_timestamp: raw.timestamp.is_none(),
+        }
+    }
+
+    fn is_clean(&self) -> bool {
+        self.sentinels == 0 && self.clamped == 0 && !self.missing_timestamp
+    }
+}
+
+/// Rolling quality of one tag. `sensor` tracks readings without issues and
+/// `delivery` the share of measurements that reached the gateway, so a
+/// failing sensor and a poor radio link are told apart.
+#[derive(Debug)]
+struct TagQuality {
+    sensor: f64,
+    delivery: f64,
+    last_seq: Option<u32>,
+    readings: u64,
+    lost: u64,
+    sentinels: u64,
+    clamped: u64,
+    missing_timestamps: u64,
+}
+
+impl TagQuality {
+    fn new() -> Self {
+        Self {
+            sensor: 1.0,
+            delivery: 1.0,
+            last_seq: None,
+            readings: 0,
+            lost: 0,
+            sentinels: 0,
+            clamped: 0,
+            missing_timestamps: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QualitySnapshot {
+    #[serde(serialize_with = "mac::serialize")]
+    pub mac: [u8; 6],
+    /// 0-100, the product of the sensor and delivery scores
+    pub score: f64,
+    pub sensor: f64,
+    pub delivery: f64,
+    pub readings: u64,
+    pub lost: u64,
+    pub sentinels: u64,
+    pub clamped: u64,
+    pub missing_timestamps: u64,
+}
+
+/// Per tag quality scores, averaged over roughly the last `window` readings
+pub struct QualityTracker {
+    alpha: f64,
+    tags: Mutex<HashMap<[u8; 6], TagQuality>>,
+}
+
+impl QualityTracker {
+    pub fn new(window: u32) -> Self {
+        Self {
+            alpha: 1.0 / f64::from(window.max(1)),
+            tags: Mutex::default(),
+        }
+    }
+
+    /// Account a stored reading
+    pub fn observe(&self, data: &Ruuvi) {
+        let (issues, seq_range) = match data {
+            Ruuvi::V2(v2) => (v2.issues, Some(1 << 16)),
+            Ruuvi::E1(e1) => (e1.issues, Some(1 << 24)),
+            Ruuvi::V6(v6) => (v6.issues, Some(1 << 8)),
+            // Without a sequence losses can't be counted, delivery stays untracked
+            Ruuvi::V1(v1) => (v1.issues, None),
+        };
+        let seq = data.measurement_seq();
+        let mut tags = self.tags.lock().unwrap();
+        let tag = tags.entry(data.mac()).or_insert_with(TagQuality::new);
+
+        if let Some(seq_range) = seq_range {
+            // Format 6 wraps every 256 readings, half of that separates lost from late
+            let max_gap = MAX_SEQ_GAP.min(seq_range / 2);
+            // Sequences wrap around, late readings from a slow listener are skipped
+            let gap = tag
+                .last_seq
+                .map(|last| (seq + seq_range - last) % seq_range)
+                .unwrap_or(1);
+            if gap == 0 || (gap > max_gap && seq_range - gap <= max_gap) {
+                return;
+            }
+            tag.last_seq = Some(seq);
+            if gap <= max_gap {
+                for _ in 1..gap {
+                    tag.delivery -= self.alpha * tag.delivery;
+                }
+                tag.lost += u64::from(gap - 1);
+            }
+            tag.delivery += self.alpha * (1.0 - tag.delivery);
+        }
+
+        let clean = if issues.is_clean() { 1.0 } else { 0.0 };
+        tag.sensor += self.alpha * (clean - tag.sensor);
+        tag.readings += 1;
+        tag.sentinels += u64::from(issues.sentinels);
+        tag.clamped += u64::from(issues.clamped);
+        tag.missing_timestamps += u64::from(issues.missing_timestamp);
+    }
+
+    pub fn get(&self, mac: &[u8; 6]) -> Option<QualitySnapshot> {
+        self.tags
+            .lock()
+            .unwrap()
+            .get(mac)
+            .map(|tag| snapshot(*mac, tag))
+    }
+
+    pub fn all(&self) -> Vec<QualitySnapshot> {
+        let mut snapshots: Vec<_> = self
+            .tags
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(mac, tag)| snapshot(*mac, tag))
+            .collect();
+        snapshots.sort_by_key(|s| s.mac);
+        snapshots
+    }
+}
+
+fn snapshot(mac: [u8; 6], tag: &TagQuality) -> QualitySnapshot {
+    let round = |v: f64| (v * 1000.0).round() / 10.0;
+    QualitySnapshot {
+        mac,
+        score: round(tag.sensor * tag.delivery),
+        sensor: round(tag.sensor),
+        delivery: round(tag.delivery),
+        readings: tag.readings,
+        lost: tag.lost,
+        sentinels: tag.sentinels,
+        clamped: tag.clamped,
+        missing_timestamps: tag.missing_timestamps,
+    }
+}
+
+/// Store the scores of every tag each `quality.interval_secs`
+pub async fn record(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let secs = state.config.quality.interval_secs;
+    if secs == 0 {
+        return Ok(());
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let snapshots = state.quality.all();
+        if let Err(e) = state.storage.insert_tag_quality(&snapshots).await {
+            tracing::error!("Failed to store tag quality: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Issues, QualityTracker};
+    use crate::{Ruuvi, RuuviV2};
+    use chrono::Utc;
+
+    fn reading(measurement_seq: u16) -> Ruuvi {
+        Ruuvi::V2(RuuviV2::sample(
+            [1, 2, 3, 4, 5, 6],
+            measurement_seq,
+            Utc::now(),
+        ))
+    }
+
+    #[test]
+    fn counts_sequence_gaps_across_wraparound() {
+        let tracker = QualityTracker::new(10);
+        for seq in [65_533, 65_534, 1, 2, 1, 3] {
+            tracker.observe(&reading(seq));
+        }
+        let quality = tracker.get(&[1, 2, 3, 4, 5, 6]).unwrap();
+        // 65535 and 0 were lost, the late duplicate of 1 is ignored
+        assert_eq!(quality.lost, 2);
+        assert_eq!(quality.readings, 5);
+        assert!(quality.delivery < 100.0);
+        assert_eq!(quality.sensor, 100.0);
+    }
+
+    #[test]
+    fn treats_large_jumps_as_reboots() {
+        let tracker = QualityTracker::new(10);
+        for seq in [10, 11, 5_000, 5_001] {
+            tracker.observe(&reading(seq));
+        }
+        let quality = tracker.get(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(quality.lost, 0);
+        assert_eq!(quality.readings, 4);
+        assert_eq!(quality.delivery, 100.0);
+    }
+
+    #[test]
+    fn counts_reading_issues() {
+        let tracker = QualityTracker::new(1);
+        let Ruuvi::V2(mut v2) = reading(1) else {
+            unreachable!();
+        };
+        v2.issues = Issues {
+            sentinels: 2,
+            clamped: 1,
+            missing_timestamp: true,
+        };
+        tracker.observe(&Ruuvi::V2(v2));
+        let quality = tracker.get(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(
+            (
+                quality.sentinels,
+                quality.clamped,
+                quality.missing_timestamps
+            ),
+            (2, 1, 1)
+        );
+        assert_eq!(quality.sensor, 0.0);
+        assert_eq!(quality.score, 0.0);
+
+        tracker.observe(&reading(2));
+        assert_eq!(tracker.get(&[1, 2, 3, 4, 5, 6]).unwrap().sensor, 100.0);
+        assert!(tracker.get(&[0; 6]).is_none());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::Ruuvi;
+use anyhow::Context;
+use chrono::Utc;
+use std::fmt::Write as _;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+// Longer frames are truncated, the head is enough to tell what's going on
+const SAMPLE_BYTES: usize = 256;
+
+/// Which stage rejected a frame
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    Decrypt,
+    Decode,
+}
+
+impl Failure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Decrypt => "decrypt",
+            Self::Decode => "decode",
+        }
+    }
+}
+
+/// Counts consecutive bad frames on a connection
+pub struct FailureTracker {
+    limit: u32,
+    consecutive: u32,
+}
+
+impl FailureTracker {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit: limit.max(1),
+            consecutive: 0,
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// Returns true once the connection should be given up
+    pub fn failure(&mut self) -> bool {
+        self.consecutive += 1;
+        self.consecutive >= self.limit
+    }
+}
+
+/// Append a reading of a filtered tag to the quarantine file, as JSON
+pub async fn record_reading(
+    path: &Path,
+    listener: &str,
+    data: &Ruuvi,
+) -> Result<(), anyhow::Error> {
+    let line = format!(
+        "{} {listener} filtered {}\n",
+        Utc::now().to_rfc3339(),
+        serde_json::to_string(data)?
+    );
+    append(path, &line).await
+}
+
+/// Append a hex sample of an offending frame to the quarantine file
+pub async fn record(
+    path: &Path,
+    listener: &str,
+    failure: Failure,
+    frame: &[u8],
+) -> Result<(), anyhow::Error> {
+    let sample = &frame[..frame.len().min(SAMPLE_BYTES)];
+    let mut line = format!(
+        "{} {listener} {} {} bytes ",
+        Utc::now().to_rfc3339(),
+        failure.as_str(),
+        frame.len()
+    );
+    for byte in sample {
+        let _ = write!(line, "{byte:02x}");
+    }
+    line.push('\n');
+    append(path, &line).await
+}
+
+async fn append(path: &Path, line: &str) -> Result<(), anyhow::Error> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,240 @@
+use crate::database::{RegistryRow, Storage};
+use crate::mac::{self, format_mac, parse_mac};
+use crate::{AppState, Ruuvi};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Picks up tags registered with the CLI while the gateway runs
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Advertisement format of a reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    V1,
+    V2,
+    E1,
+    V6,
+}
+
+impl DataFormat {
+    pub fn of(data: &Ruuvi) -> Self {
+        match data {
+            Ruuvi::V1(_) => Self::V1,
+            Ruuvi::V2(_) => Self::V2,
+            Ruuvi::E1(_) => Self::E1,
+            Ruuvi::V6(_) => Self::V6,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::V1 => "v1",
+            Self::V2 => "v2",
+            Self::E1 => "e1",
+            Self::V6 => "v6",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        [Self::V1, Self::V2, Self::E1, Self::V6]
+            .into_iter()
+            .find(|format| format.as_str() == s)
+    }
+}
+
+/// A tag given a name, and optionally where it is and which formats it sends
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegisteredTag {
+    #[serde(with = "mac")]
+    pub mac: [u8; 6],
+    pub name: String,
+    #[serde(default)]
+    pub location: Option<String>,
+    /// Any format is expected when empty
+    #[serde(default)]
+    pub formats: Vec<DataFormat>,
+}
+
+impl RegisteredTag {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.name.trim().is_empty() {
+            anyhow::bail!("A tag needs a name");
+        }
+        // Names stand in for MACs in the API paths
+        if parse_mac(&self.name).is_ok() {
+            anyhow::bail!(
+                "{:?} looks like a MAC address, pick another name",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    pub fn to_row(&self) -> RegistryRow {
+        RegistryRow {
+            mac_address: crate::database::Mac(self.mac),
+            name: self.name.clone(),
+            location: self.location.clone(),
+            formats: self
+                .formats
+                .iter()
+                .map(|format| format.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+
+    pub fn from_row(row: RegistryRow) -> Self {
+        Self {
+            mac: row.mac_address.bytes(),
+            name: row.name,
+            location: row.location,
+            formats: row
+                .formats
+                .split(',')
+                .filter_map(DataFormat::parse)
+                .collect(),
+        }
+    }
+}
+
+/// Registered tags, kept in memory for naming readings as they arrive
+#[derive(Default)]
+pub struct Registry {
+    tags: RwLock<HashMap<[u8; 6], RegisteredTag>>,
+    /// Unregistered tags and unexpected formats already warned about
+    warned: Mutex<HashSet<([u8; 6], Option<DataFormat>)>>,
+}
+
+impl Registry {
+    pub async fn load(storage: &dyn Storage) -> Result<Self, anyhow::Error> {
+        let registry = Self::default();
+        registry.replace(storage.registered_tags().await?);
+        Ok(registry)
+    }
+
+    pub fn replace(&self, rows: Vec<RegistryRow>) {
+        let tags = rows
+            .into_iter()
+            .map(RegisteredTag::from_row)
+            .map(|tag| (tag.mac, tag))
+            .collect();
+        *self.tags.write().unwrap() = tags;
+    }
+
+    pub fn get(&self, mac: &[u8; 6]) -> Option<RegisteredTag> {
+        self.tags.read().unwrap().get(mac).cloned()
+    }
+
+    pub fn all(&self) -> Vec<RegisteredTag> {
+        let mut tags: Vec<_> = self.tags.read().unwrap().values().cloned().collect();
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
+        tags
+    }
+
+    /// MAC of a MAC address or a registered name
+    pub fn resolve(&self, tag: &str) -> Option<[u8; 6]> {
+        parse_mac(tag).ok().or_else(|| {
+            self.tags
+                .read()
+                .unwrap()
+                .values()
+                .find(|registered| registered.name.eq_ignore_ascii_case(tag))
+                .map(|registered| registered.mac)
+        })
+    }
+
+    /// Register or update a tag, failing when another tag has its name
+    pub async fn set(
+        &self,
+        storage: &dyn Storage,
+        tag: RegisteredTag,
+    ) -> Result<(), anyhow::Error> {
+        tag.validate()?;
+        if let Some(other) = self.resolve(&tag.name).filter(|mac| *mac != tag.mac) {
+            anyhow::bail!("{} is already called {:?}", format_mac(&other), tag.name);
+        }
+        storage.register_tag(&tag.to_row()).await?;
+        self.tags.write().unwrap().insert(tag.mac, tag);
+        Ok(())
+    }
+
+    /// `false` when the tag wasn't registered
+    pub async fn remove(&self, storage: &dyn Storage, mac: [u8; 6]) -> Result<bool, anyhow::Error> {
+        let removed = storage.unregister_tag(mac).await?;
+        self.tags.write().unwrap().remove(&mac);
+        Ok(removed)
+    }
+
+    /// Warn once about a tag that isn't registered or sends a format it isn't expected to
+    pub fn check(&self, data: &Ruuvi) {
+        let mac = data.mac();
+        let format = DataFormat::of(data);
+        let problem = match self.tags.read().unwrap().get(&mac) {
+            None => None,
+            Some(tag) if tag.formats.is_empty() || tag.formats.contains(&format) => return,
+            Some(_) => Some(format),
+        };
+        if !self.warned.lock().unwrap().insert((mac, problem)) {
+            return;
+        }
+        match problem {
+            None => tracing::warn!("Unregistered tag {} appeared", format_mac(&mac)),
+            Some(format) => {
+                tracing::warn!(
+                    "{} sent unexpected format {}",
+                    format_mac(&mac),
+                    format.as_str()
+                )
+            }
+        }
+    }
+}
+
+/// Reload the registry every minute
+pub async fn reload(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match state.storage.registered_tags().await {
+            Ok(rows) => state.registry.replace(rows),
+            Err(e) => tracing::warn!("Failed to reload the tag registry: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DataFormat, RegisteredTag, Registry};
+
+    #[test]
+    fn resolves_names_and_keeps_formats() {
+        let tag = RegisteredTag {
+            mac: [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+            name: "Sauna".to_owned(),
+            location: Some("Basement".to_owned()),
+            formats: vec![DataFormat::V2, DataFormat::E1],
+        };
+        assert_eq!(tag.to_row().formats, "v2,e1");
+        assert_eq!(RegisteredTag::from_row(tag.to_row()), tag);
+
+        let registry = Registry::default();
+        registry.replace(vec![tag.to_row()]);
+        assert_eq!(registry.resolve("sauna"), Some(tag.mac));
+        assert_eq!(registry.resolve("aa:bb:cc:dd:ee:ff"), Some(tag.mac));
+        assert_eq!(registry.resolve("attic"), None);
+
+        let nameless = RegisteredTag {
+            name: "11:22:33:44:55:66".to_owned(),
+            ..tag
+        };
+        assert!(nameless.validate().is_err());
+    }
+}
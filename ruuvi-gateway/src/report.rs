@@ -0,0 +1,134 @@
+use crate::AppState;
+use crate::database::TagSummaryRow;
+use crate::mac::format_mac;
+use crate::notify::Notification;
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// Send daily and weekly (on Mondays) summaries at `reports.hour` local time
+pub async fn schedule(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let config = &state.config.reports;
+    if !config.daily && !config.weekly {
+        return Ok(());
+    }
+    let tz = state.config.api.timezone;
+
+    loop {
+        let Some(next) = next_run(Utc::now(), tz, config.hour) else {
+            anyhow::bail!("Invalid report hour {}", config.hour);
+        };
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tracing::info!("Next summary report at {}", next.with_timezone(&tz));
+        tokio::time::sleep(wait).await;
+
+        let today = next.with_timezone(&tz).date_naive();
+        if config.daily {
+            send_report(&state, tz, "Daily", today, 1).await;
+        }
+        if config.weekly && today.weekday() == Weekday::Mon {
+            send_report(&state, tz, "Weekly", today, 7).await;
+        }
+    }
+}
+
+fn next_run(now: DateTime<Utc>, tz: Tz, hour: u32) -> Option<DateTime<Utc>> {
+    let time = NaiveTime::from_hms_opt(hour, 0, 0)?;
+    let today = now.with_timezone(&tz).date_naive();
+    [today, today.checked_add_days(Days::new(1))?]
+        .into_iter()
+        .filter_map(|day| local_to_utc(tz, day, time))
+        .find(|t| *t > now)
+}
+
+fn local_to_utc(tz: Tz, day: NaiveDate, time: NaiveTime) -> Option<DateTime<Utc>> {
+    tz.from_local_datetime(&day.and_time(time))
+        .earliest()
+        .map(|dt| dt.with_timezone(&Utc))
+}
+
+async fn send_report(state: &AppState, tz: Tz, kind: &str, today: NaiveDate, days: u64) {
+    let (Some(from_day), Some(from), Some(to)) = (
+        today.checked_sub_days(Days::new(days)),
+        today
+            .checked_sub_days(Days::new(days))
+            .and_then(|d| local_to_utc(tz, d, NaiveTime::MIN)),
+        local_to_utc(tz, today, NaiveTime::MIN),
+    ) else {
+        return;
+    };
+
+    let config = &state.config.reports;
+    let rows = match state
+        .storage
+        .tag_summaries(
+            from,
+            to,
+            config.humidity_low,
+            config.humidity_high,
+            config.co2_limit,
+        )
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            tracing::error!("Failed to query {kind} summary: {e}");
+            return;
+        }
+    };
+    if rows.is_empty() {
+        return;
+    }
+
+    let period = if days == 1 {
+        from_day.to_string()
+    } else {
+        format!("{from_day} - {}", today.pred_opt().unwrap_or(today))
+    };
+    let notification = Notification {
+        title: format!("{kind} summary {period}"),
+        body: format_summary(
+            &rows,
+            config.humidity_low,
+            config.humidity_high,
+            config.co2_limit,
+        ),
+    };
+    state.notifiers.notify(&notification).await;
+}
+
+fn format_summary(
+    rows: &[TagSummaryRow],
+    humidity_low: f32,
+    humidity_high: f32,
+    co2_limit: i16,
+) -> String {
+    let minutes = |secs: Option<f64>| secs.unwrap_or(0.0) / 60.0;
+    let mut body = String::new();
+    for row in rows {
+        let _ = write!(body, "{}:", format_mac(&row.mac_address.bytes()));
+        if let (Some(min), Some(max), Some(avg)) = (row.temp_min, row.temp_max, row.temp_avg) {
+            let _ = write!(body, " temp {min:.1}/{avg:.1}/{max:.1} °C (min/avg/max)");
+        }
+        let excursion = minutes(row.humidity_excursion_secs);
+        if excursion > 0.0 {
+            let _ = write!(
+                body,
+                ", humidity outside {humidity_low}-{humidity_high}% for {excursion:.0} min"
+            );
+        }
+        if row.has_co2 {
+            let _ = write!(
+                body,
+                ", CO2 above {co2_limit} ppm for {:.0} min",
+                minutes(row.co2_above_secs)
+            );
+        }
+        if let Some(voltage) = row.battery_voltage {
+            let _ = write!(body, ", battery {voltage:.2} V");
+        }
+        body.push('\n');
+    }
+    body
+}
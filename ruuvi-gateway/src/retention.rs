@@ -0,0 +1,116 @@
+use crate::AppState;
+use crate::config::RetentionConfig;
+use crate::database::RETAINED_TABLES;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Rows expired from one table
+#[derive(Debug, Clone, Serialize)]
+pub struct Expired {
+    /// Rows recorded before this were deleted
+    pub cutoff: DateTime<Utc>,
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionRun {
+    pub started: DateTime<Utc>,
+    /// Rows were only counted
+    pub dry_run: bool,
+    pub tables: BTreeMap<&'static str, Expired>,
+}
+
+/// Served at `/retention`
+#[derive(Debug, Clone, Serialize)]
+pub struct RetentionReport {
+    pub last_run: Option<RetentionRun>,
+    /// Rows deleted per table since the gateway started
+    pub deleted: BTreeMap<&'static str, u64>,
+}
+
+/// Deletes rows past their table's age on a schedule
+pub struct Retention {
+    /// How long rows of each table are kept
+    policies: Vec<(&'static str, TimeDelta)>,
+    report: Mutex<RetentionReport>,
+}
+
+impl Retention {
+    pub fn new(config: &RetentionConfig) -> Result<Self, anyhow::Error> {
+        if config.interval_hours == 0 {
+            anyhow::bail!("[retention] interval_hours has to be at least 1");
+        }
+        let mut policies = Vec::new();
+        for (table, days) in &config.days {
+            let Some(table) = RETAINED_TABLES.iter().find(|t| **t == table) else {
+                anyhow::bail!(
+                    "[retention] can't expire {table}, only {}",
+                    RETAINED_TABLES.join(", ")
+                );
+            };
+            if *days == 0 {
+                anyhow::bail!("[retention] keeps {table} for 0 days, leave it out to keep it");
+            }
+            policies.push((*table, TimeDelta::days(i64::from(*days))));
+        }
+        Ok(Self {
+            policies,
+            report: Mutex::new(RetentionReport {
+                last_run: None,
+                deleted: BTreeMap::new(),
+            }),
+        })
+    }
+
+    pub fn report(&self) -> RetentionReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn record(&self, run: RetentionRun) {
+        let mut report = self.report.lock().unwrap();
+        if !run.dry_run {
+            for (table, expired) in &run.tables {
+                *report.deleted.entry(table).or_default() += expired.rows;
+            }
+        }
+        report.last_run = Some(run);
+    }
+}
+
+/// Expire old rows every `interval_hours`, starting right away
+pub async fn run(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let config = &state.config.retention;
+    let retention = &state.retention;
+    if retention.policies.is_empty() {
+        return Ok(());
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_hours * 3600));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let started = Utc::now();
+        let mut tables = BTreeMap::new();
+        for &(table, age) in &retention.policies {
+            let cutoff = started - age;
+            match state.storage.expire(table, cutoff, config.dry_run).await {
+                Ok(rows) => {
+                    if config.dry_run {
+                        tracing::info!("Retention would delete {rows} rows from {table}");
+                    } else if rows > 0 {
+                        tracing::info!("Retention deleted {rows} rows from {table}");
+                    }
+                    tables.insert(table, Expired { cutoff, rows });
+                }
+                Err(e) => tracing::error!("Failed to expire rows of {table}: {e}"),
+            }
+        }
+        retention.record(RetentionRun {
+            started,
+            dry_run: config.dry_run,
+            tables,
+        });
+    }
+}
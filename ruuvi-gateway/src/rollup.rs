@@ -0,0 +1,128 @@
+use crate::AppState;
+use crate::database::{ROLLUPS, Rollup};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Bucket lengths of the rollup tables, 5 minutes and an hour
+pub const RESOLUTIONS: [u32; 2] = [300, 3600];
+
+/// Start of the bucket `at` falls in, buckets are aligned to the Unix epoch
+fn bucket_start(at: DateTime<Utc>, resolution_secs: u32) -> DateTime<Utc> {
+    let secs = at.timestamp();
+    let start = secs - secs.rem_euclid(i64::from(resolution_secs));
+    DateTime::from_timestamp(start, 0).unwrap_or(at)
+}
+
+/// Range of buckets to (re)compute: from `late` before the newest stored
+/// bucket, or everything on the first run, until the current bucket, which
+/// isn't complete yet. `None` when there's nothing to do.
+fn pending(
+    latest: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    late: TimeDelta,
+    resolution_secs: u32,
+) -> Option<(Option<DateTime<Utc>>, DateTime<Utc>)> {
+    let to = bucket_start(now, resolution_secs);
+    let from = latest.map(|latest| bucket_start(latest - late, resolution_secs));
+    match from {
+        Some(from) if from >= to => None,
+        from => Some((from, to)),
+    }
+}
+
+/// Aggregate the readings into the rollup tables every `interval_secs`
+pub async fn run(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let config = &state.config.rollups;
+    if !config.enabled {
+        return Ok(());
+    }
+    let late = TimeDelta::seconds(config.late_secs as i64);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        for rollup in &ROLLUPS {
+            for resolution_secs in RESOLUTIONS {
+                if let Err(e) = update(&state, rollup, resolution_secs, late).await {
+                    tracing::error!(
+                        "Failed to roll up {} into {resolution_secs}s buckets: {e}",
+                        rollup.source
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn update(
+    state: &AppState,
+    rollup: &Rollup,
+    resolution_secs: u32,
+    late: TimeDelta,
+) -> Result<(), anyhow::Error> {
+    let latest = state.storage.latest_rollup(rollup, resolution_secs).await?;
+    let Some((from, to)) = pending(latest, Utc::now(), late, resolution_secs) else {
+        return Ok(());
+    };
+    if from.is_none() {
+        tracing::info!(
+            "Rolling up all of {} into {resolution_secs}s buckets",
+            rollup.source
+        );
+    }
+    let buckets = state
+        .storage
+        .rollup(rollup, resolution_secs, from, to)
+        .await?;
+    tracing::debug!(
+        "Wrote {buckets} {resolution_secs}s buckets of {}",
+        rollup.source
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pending;
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    #[test]
+    fn recomputes_complete_buckets_only() {
+        let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+        let now = at("2025-03-01T12:34:56Z");
+        let late = TimeDelta::minutes(30);
+
+        assert_eq!(
+            pending(None, now, late, 300),
+            Some((None, at("2025-03-01T12:30:00Z")))
+        );
+        assert_eq!(
+            pending(Some(at("2025-03-01T12:25:00Z")), now, late, 300),
+            Some((Some(at("2025-03-01T11:55:00Z")), at("2025-03-01T12:30:00Z")))
+        );
+        assert_eq!(
+            pending(Some(at("2025-03-01T11:00:00Z")), now, late, 3600),
+            Some((Some(at("2025-03-01T10:00:00Z")), at("2025-03-01T12:00:00Z")))
+        );
+        // The newest complete bucket is recomputed until the next one completes
+        assert_eq!(
+            pending(
+                Some(at("2025-03-01T11:00:00Z")),
+                now,
+                TimeDelta::zero(),
+                3600
+            ),
+            Some((Some(at("2025-03-01T11:00:00Z")), at("2025-03-01T12:00:00Z")))
+        );
+        assert_eq!(
+            pending(
+                Some(at("2025-03-01T12:00:00Z")),
+                now,
+                TimeDelta::zero(),
+                3600
+            ),
+            None
+        );
+    }
+}
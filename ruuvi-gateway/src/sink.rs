@@ -0,0 +1,393 @@
+//! Outputs of the stored readings. The primary sink, normally the database,
+//! is written before a reading is acknowledged to its listener. Every other
+//! sink gets its own queue and worker, batching the readings and retrying
+//! failed writes, so a slow or unreachable output holds back nothing else.
+
+use crate::Ruuvi;
+use crate::database::Storage;
+use crate::outbox::{self, Outbox};
+use futures_util::future::BoxFuture;
+use serde_json::Value;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Readings are dropped instead of blocking ingestion once this many are queued for a sink
+const QUEUE_CAPACITY: usize = 10_000;
+/// First and longest wait before retrying a failed write
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// A stored reading with the listener that heard it best
+#[derive(Debug, Clone)]
+pub struct StoredReading {
+    pub data: Ruuvi,
+    pub listener: String,
+    /// Manufacturer data the reading was decoded from, when `[raw_payload]` is enabled
+    pub raw_payload: Option<Vec<u8>>,
+}
+
+/// Output for the stored readings
+pub trait Sink: Send + Sync {
+    /// Used in logs
+    fn name(&self) -> &'static str;
+
+    /// Write a batch of readings, oldest first. A failed batch is retried as a
+    /// whole, readings the sink can never write are logged and skipped instead.
+    fn write<'a>(&'a self, batch: &'a [StoredReading]) -> BoxFuture<'a, Result<(), anyhow::Error>>;
+}
+
+/// A sink written from its own queue
+pub type QueuedSink = (Arc<dyn Sink>, Buffering);
+
+/// How a queued sink collects readings into batches
+#[derive(Debug, Clone, Copy)]
+pub struct Buffering {
+    /// Most readings per write
+    pub batch_size: usize,
+    /// How long readings are collected after the first of a batch
+    pub flush: Duration,
+}
+
+impl Buffering {
+    /// Every reading is written on its own as soon as it's queued
+    pub const NONE: Self = Self {
+        batch_size: 1,
+        flush: Duration::ZERO,
+    };
+}
+
+struct Queue {
+    name: &'static str,
+    readings: mpsc::Sender<StoredReading>,
+    /// Set while the queue is full, so falling behind is logged once
+    dropping: AtomicBool,
+}
+
+/// Fans the stored readings out to the sinks
+pub struct Dispatcher {
+    /// `None` acknowledges readings without writing them anywhere first
+    primary: Option<Arc<dyn Sink>>,
+    /// Holds the readings while the primary sink is failing
+    outbox: Option<Arc<Outbox>>,
+    queues: Vec<Queue>,
+}
+
+/// Drive with [`run`]
+pub struct Workers {
+    queues: Vec<Worker>,
+    outbox: Option<(Arc<Outbox>, Arc<dyn Sink>)>,
+}
+
+/// Drains the queue of one sink
+struct Worker {
+    sink: Arc<dyn Sink>,
+    buffering: Buffering,
+    readings: mpsc::Receiver<StoredReading>,
+}
+
+impl Dispatcher {
+    /// The outbox and the queued sinks have to be driven with [`run`]
+    pub fn new(
+        primary: Option<Arc<dyn Sink>>,
+        outbox: Option<Outbox>,
+        queued: Vec<QueuedSink>,
+    ) -> (Self, Workers) {
+        let (queues, workers) = queued
+            .into_iter()
+            .map(|(sink, buffering)| {
+                let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+                let queue = Queue {
+                    name: sink.name(),
+                    readings: tx,
+                    dropping: AtomicBool::new(false),
+                };
+                let worker = Worker {
+                    sink,
+                    buffering,
+                    readings: rx,
+                };
+                (queue, worker)
+            })
+            .unzip();
+        // Without a primary sink there's nothing to hold readings for
+        let outbox = primary.clone().zip(outbox.map(Arc::new));
+        let workers = Workers {
+            queues: workers,
+            outbox: outbox.clone().map(|(primary, outbox)| (outbox, primary)),
+        };
+        let dispatcher = Self {
+            primary,
+            outbox: outbox.map(|(_, outbox)| outbox),
+            queues,
+        };
+        (dispatcher, workers)
+    }
+
+    /// Names of the sinks, the primary one first
+    pub fn names(&self) -> Vec<&'static str> {
+        self.primary
+            .iter()
+            .map(|sink| sink.name())
+            .chain(self.queues.iter().map(|queue| queue.name))
+            .collect()
+    }
+
+    /// Writes the reading to the primary sink, or holds it in the outbox when
+    /// that fails, and queues it for the others. `false` when the reading was
+    /// neither written nor held and the listener should resend it.
+    pub async fn store(&self, reading: StoredReading) -> bool {
+        let taken = match (&self.primary, &self.outbox) {
+            (None, _) => true,
+            // Keeps the order and spares the primary sink until it caught up
+            (Some(_), Some(outbox)) if !outbox.is_empty() => outbox.push(reading.clone()),
+            (Some(primary), outbox) => match primary.write(std::slice::from_ref(&reading)).await {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::error!("Failed to write a reading to {}: {e}", primary.name());
+                    outbox
+                        .as_ref()
+                        .is_some_and(|outbox| outbox.push(reading.clone()))
+                }
+            },
+        };
+        if !taken {
+            return false;
+        }
+        for queue in &self.queues {
+            match queue.readings.try_send(reading.clone()) {
+                Ok(()) => {
+                    if queue.dropping.swap(false, Ordering::Relaxed) {
+                        tracing::info!("{} caught up", queue.name);
+                    }
+                }
+                Err(e) => {
+                    if !queue.dropping.swap(true, Ordering::Relaxed) {
+                        tracing::warn!("{} is falling behind, dropping readings: {e}", queue.name);
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+/// Write the queued readings of every sink until the dispatcher is dropped,
+/// and the ones held in the outbox
+pub async fn run(workers: Workers) -> Result<(), anyhow::Error> {
+    let queues = futures_util::future::join_all(workers.queues.into_iter().map(Worker::run));
+    match workers.outbox {
+        Some((outbox, primary)) => {
+            tokio::join!(queues, outbox::drain(&outbox, primary.as_ref()));
+        }
+        None => {
+            queues.await;
+        }
+    }
+    Ok(())
+}
+
+impl Worker {
+    async fn run(mut self) {
+        let batch_size = self.buffering.batch_size.max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+        while let Some(reading) = self.readings.recv().await {
+            batch.push(reading);
+            let deadline = Instant::now() + self.buffering.flush;
+            while batch.len() < batch_size {
+                match tokio::time::timeout_at(deadline, self.readings.recv()).await {
+                    Ok(Some(reading)) => batch.push(reading),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            self.write(&batch).await;
+            batch.clear();
+        }
+    }
+
+    /// Retries with a growing delay until the sink takes the batch, new
+    /// readings queue up meanwhile
+    async fn write(&self, batch: &[StoredReading]) {
+        let mut delay = RETRY_DELAY;
+        while let Err(e) = self.sink.write(batch).await {
+            tracing::warn!(
+                "Failed to write {} readings to {}, retrying in {}s: {e}",
+                batch.len(),
+                self.sink.name(),
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+/// The readings tables of the database
+pub struct DatabaseSink(pub Arc<dyn Storage>);
+
+impl Sink for DatabaseSink {
+    fn name(&self) -> &'static str {
+        "database"
+    }
+
+    fn write<'a>(&'a self, batch: &'a [StoredReading]) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            for reading in batch {
+                let (listener, raw_payload) = (&reading.listener, reading.raw_payload.as_deref());
+                match reading.data.clone() {
+                    Ruuvi::V2(v2) => self.0.insert_data_v2(v2, listener, raw_payload).await?,
+                    Ruuvi::E1(e1) => self.0.insert_data_e1(e1, listener, raw_payload).await?,
+                    Ruuvi::V1(v1) => self.0.insert_data_v1(v1, listener, raw_payload).await?,
+                    Ruuvi::V6(v6) => self.0.insert_data_v6(v6, listener, raw_payload).await?,
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Prints every reading as a line of JSON, for piping into other tools
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn write<'a>(&'a self, batch: &'a [StoredReading]) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut out = std::io::stdout().lock();
+            for reading in batch {
+                writeln!(out, "{}", to_json(reading))?;
+            }
+            out.flush()?;
+            Ok(())
+        })
+    }
+}
+
+/// The reading's fields with its format and listener
+pub fn to_json(reading: &StoredReading) -> Value {
+    let (format, fields) = match &reading.data {
+        Ruuvi::V2(v2) => ("v2", serde_json::to_value(v2)),
+        Ruuvi::E1(e1) => ("e1", serde_json::to_value(e1)),
+        Ruuvi::V1(v1) => ("v1", serde_json::to_value(v1)),
+        Ruuvi::V6(v6) => ("v6", serde_json::to_value(v6)),
+    };
+    let mut line = match fields {
+        Ok(Value::Object(fields)) => fields,
+        _ => serde_json::Map::new(),
+    };
+    line.insert("format".to_owned(), format.into());
+    line.insert("listener".to_owned(), reading.listener.clone().into());
+    Value::Object(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buffering, Dispatcher, Sink, StoredReading, to_json};
+    use crate::config::OutboxConfig;
+    use crate::outbox::Outbox;
+    use crate::{Ruuvi, RuuviV2};
+    use chrono::Utc;
+    use futures_util::future::BoxFuture;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        failing: AtomicBool,
+        written: Mutex<Vec<u32>>,
+    }
+
+    impl Sink for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn write<'a>(
+            &'a self,
+            batch: &'a [StoredReading],
+        ) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+            Box::pin(async move {
+                if self.failing.load(Ordering::Relaxed) {
+                    anyhow::bail!("unavailable");
+                }
+                let mut written = self.written.lock().unwrap();
+                written.extend(batch.iter().map(|reading| reading.data.measurement_seq()));
+                Ok(())
+            })
+        }
+    }
+
+    fn reading(measurement_seq: u16) -> StoredReading {
+        StoredReading {
+            data: Ruuvi::V2(RuuviV2::sample(
+                [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+                measurement_seq,
+                Utc::now(),
+            )),
+            listener: "hall".to_owned(),
+            raw_payload: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn fans_out_only_what_the_primary_stored() {
+        let primary = Arc::new(Recorder::default());
+        let queued = Arc::new(Recorder::default());
+        let (dispatcher, workers) = Dispatcher::new(
+            Some(primary.clone()),
+            None,
+            vec![(queued.clone(), Buffering::NONE)],
+        );
+
+        primary.failing.store(true, Ordering::Relaxed);
+        assert!(!dispatcher.store(reading(1)).await);
+        primary.failing.store(false, Ordering::Relaxed);
+        assert!(dispatcher.store(reading(2)).await);
+        assert!(dispatcher.store(reading(3)).await);
+
+        // Dropping the dispatcher closes the queues, the workers finish what's queued
+        drop(dispatcher);
+        super::run(workers).await.unwrap();
+        assert_eq!(*primary.written.lock().unwrap(), [2, 3]);
+        assert_eq!(*queued.written.lock().unwrap(), [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn holds_readings_in_order_while_the_primary_fails() {
+        let primary = Arc::new(Recorder::default());
+        let outbox = Outbox::new(&OutboxConfig {
+            capacity: 10,
+            spool_file: None,
+            max_attempts: 5,
+            dead_letter_file: None,
+        })
+        .unwrap();
+        let (dispatcher, _workers) = Dispatcher::new(Some(primary.clone()), outbox, Vec::new());
+
+        primary.failing.store(true, Ordering::Relaxed);
+        assert!(dispatcher.store(reading(1)).await);
+        // The primary is back, but the held reading has to go first
+        primary.failing.store(false, Ordering::Relaxed);
+        assert!(dispatcher.store(reading(2)).await);
+        assert!(primary.written.lock().unwrap().is_empty());
+        assert!(!dispatcher.outbox.as_ref().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn acknowledges_without_a_primary() {
+        let (dispatcher, _workers) = Dispatcher::new(None, None, Vec::new());
+        assert!(dispatcher.store(reading(1)).await);
+        assert!(dispatcher.names().is_empty());
+
+        let json = to_json(&reading(1));
+        assert_eq!(json["format"], "v2");
+        assert_eq!(json["listener"], "hall");
+        assert_eq!(json["measurement_seq"], 1);
+    }
+}
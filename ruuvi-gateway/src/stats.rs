@@ -0,0 +1,305 @@
+use crate::AppState;
+use crate::ota::format_version;
+use chrono::{DateTime, Utc};
+use ruuvi_schema::protocol::{COMMAND_PROTOCOL, Command, Hello, Telemetry};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Ingest counters of a single listener connection
+#[derive(Debug)]
+pub struct ConnectionStats {
+    listener: String,
+    peer: SocketAddr,
+    connected_at: DateTime<Utc>,
+    bytes: AtomicU64,
+    frames: AtomicU64,
+    decrypt_failures: AtomicU64,
+    decode_failures: AtomicU64,
+    inserts: AtomicU64,
+    insert_micros_total: AtomicU64,
+    insert_micros_max: AtomicU64,
+    telemetry: Mutex<Option<Telemetry>>,
+    hello: Mutex<Option<Hello>>,
+    /// Name of the pre-shared key the listener authenticated with
+    psk: Mutex<Option<String>>,
+    /// Name of the pinned static key the listener authenticated with
+    identity: Mutex<Option<String>>,
+}
+
+impl ConnectionStats {
+    fn new(listener: &str, peer: SocketAddr) -> Self {
+        Self {
+            listener: listener.to_owned(),
+            peer,
+            connected_at: Utc::now(),
+            bytes: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            decrypt_failures: AtomicU64::new(0),
+            decode_failures: AtomicU64::new(0),
+            inserts: AtomicU64::new(0),
+            insert_micros_total: AtomicU64::new(0),
+            insert_micros_max: AtomicU64::new(0),
+            telemetry: Mutex::default(),
+            hello: Mutex::default(),
+            psk: Mutex::default(),
+            identity: Mutex::default(),
+        }
+    }
+
+    /// A length-prefixed frame read from the socket
+    pub fn frame(&self, len: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64 + 2, Ordering::Relaxed);
+    }
+
+    /// Versions the listener announced in the handshake
+    pub fn hello(&self, hello: Hello) {
+        *self.hello.lock().unwrap() = Some(hello);
+    }
+
+    pub fn psk(&self, name: &str) {
+        *self.psk.lock().unwrap() = Some(name.to_owned());
+    }
+
+    /// The listener proved holding the pinned static key of `name`
+    pub fn authenticated(&self, name: &str) {
+        *self.identity.lock().unwrap() = Some(name.to_owned());
+    }
+
+    /// Latest health report of the listener, returns the one before it
+    pub fn telemetry(&self, telemetry: Telemetry) -> Option<Telemetry> {
+        self.telemetry.lock().unwrap().replace(telemetry)
+    }
+
+    pub fn decrypt_failure(&self) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decode_failure(&self) {
+        self.decode_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Time spent storing one measurement received on this connection
+    pub fn insert(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.inserts.fetch_add(1, Ordering::Relaxed);
+        self.insert_micros_total
+            .fetch_add(micros, Ordering::Relaxed);
+        self.insert_micros_max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Pinned name of the listener, its address until authenticated
+    fn name(&self) -> String {
+        self.identity
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| self.listener.clone())
+    }
+
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        let hello = *self.hello.lock().unwrap();
+        let inserts = self.inserts.load(Ordering::Relaxed);
+        let total = self.insert_micros_total.load(Ordering::Relaxed);
+        ConnectionSnapshot {
+            listener: self.name(),
+            peer: self.peer.to_string(),
+            connected_at: self.connected_at,
+            bytes: self.bytes.load(Ordering::Relaxed),
+            frames: self.frames.load(Ordering::Relaxed),
+            decrypt_failures: self.decrypt_failures.load(Ordering::Relaxed),
+            decode_failures: self.decode_failures.load(Ordering::Relaxed),
+            inserts,
+            insert_avg_ms: if inserts == 0 {
+                0.0
+            } else {
+                total as f64 / inserts as f64 / 1000.0
+            },
+            insert_max_ms: self.insert_micros_max.load(Ordering::Relaxed) as f64 / 1000.0,
+            telemetry: *self.telemetry.lock().unwrap(),
+            protocol: hello.map(|hello| hello.protocol),
+            firmware: hello.map(|hello| format_version(hello.firmware)),
+            psk: self.psk.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionSnapshot {
+    pub listener: String,
+    pub peer: String,
+    pub connected_at: DateTime<Utc>,
+    pub bytes: u64,
+    pub frames: u64,
+    pub decrypt_failures: u64,
+    pub decode_failures: u64,
+    pub inserts: u64,
+    pub insert_avg_ms: f64,
+    pub insert_max_ms: f64,
+    /// Listeners predating the message envelope don't report it
+    pub telemetry: Option<Telemetry>,
+    /// Unknown for listeners predating version negotiation
+    pub protocol: Option<u16>,
+    pub firmware: Option<String>,
+    /// Name of the pre-shared key, `None` until the handshake completes
+    pub psk: Option<String>,
+}
+
+impl ConnectionSnapshot {
+    fn log(&self, prefix: &str) {
+        tracing::info!(
+            "{prefix} {} ({}): {} frames, {} bytes, {} decrypt / {} decode failures, \
+            {} inserts avg {:.1} ms max {:.1} ms",
+            self.listener,
+            self.peer,
+            self.frames,
+            self.bytes,
+            self.decrypt_failures,
+            self.decode_failures,
+            self.inserts,
+            self.insert_avg_ms,
+            self.insert_max_ms
+        );
+    }
+}
+
+struct OpenConnection {
+    stats: Arc<ConnectionStats>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+/// Statistics of the currently open listener connections
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, OpenConnection>>,
+}
+
+impl Connections {
+    /// Track a new connection until the returned guard is dropped. Commands for the
+    /// listener arrive on the returned receiver.
+    pub fn register(
+        self: &Arc<Self>,
+        listener: &str,
+        peer: SocketAddr,
+    ) -> (ConnectionGuard, mpsc::UnboundedReceiver<Command>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(ConnectionStats::new(listener, peer));
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let open = OpenConnection {
+            stats: stats.clone(),
+            commands,
+        };
+        self.open.lock().unwrap().insert(id, open);
+        let guard = ConnectionGuard {
+            id,
+            stats,
+            connections: self.clone(),
+        };
+        (guard, receiver)
+    }
+
+    /// Hands `command` to every connection of `listener` speaking at least
+    /// [`COMMAND_PROTOCOL`], returns how many there were
+    pub fn command(&self, listener: &str, command: &Command) -> usize {
+        self.open
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|open| open.stats.name() == listener)
+            .filter(|open| {
+                open.stats
+                    .hello
+                    .lock()
+                    .unwrap()
+                    .is_some_and(|hello| hello.protocol >= COMMAND_PROTOCOL)
+            })
+            .filter(|open| open.commands.send(command.clone()).is_ok())
+            .count()
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        let mut snapshots: Vec<_> = self
+            .open
+            .lock()
+            .unwrap()
+            .values()
+            .map(|open| open.stats.snapshot())
+            .collect();
+        snapshots.sort_by(|a, b| a.listener.cmp(&b.listener).then(a.peer.cmp(&b.peer)));
+        snapshots
+    }
+}
+
+pub struct ConnectionGuard {
+    id: u64,
+    pub stats: Arc<ConnectionStats>,
+    connections: Arc<Connections>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.open.lock().unwrap().remove(&self.id);
+        self.stats.snapshot().log("Closed");
+    }
+}
+
+/// Log a summary line per open connection every `stats.interval_secs`
+pub async fn summarize(state: Arc<AppState>) -> Result<(), anyhow::Error> {
+    let secs = state.config.stats.interval_secs;
+    if secs == 0 {
+        return Ok(());
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for snapshot in state.connections.snapshot() {
+            snapshot.log("Connection");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connections;
+    use ruuvi_schema::protocol::{COMMAND_PROTOCOL, Command, Hello};
+    use std::sync::Arc;
+
+    #[test]
+    fn commands_listeners_that_speak_them() {
+        let connections = Arc::new(Connections::default());
+        let peer = "192.168.1.20:50000".parse().unwrap();
+        let (kitchen, mut commands) = connections.register("192.168.1.20", peer);
+        kitchen.stats.authenticated("kitchen");
+        let (old, mut old_commands) = connections.register("192.168.1.21", peer);
+
+        // Unknown protocol until the hello
+        assert_eq!(connections.command("kitchen", &Command::Reboot), 0);
+        kitchen.stats.hello(Hello {
+            protocol: COMMAND_PROTOCOL,
+            firmware: 1,
+        });
+        assert_eq!(connections.command("kitchen", &Command::Telemetry), 1);
+        assert_eq!(commands.try_recv().unwrap(), Command::Telemetry);
+        assert_eq!(connections.command("192.168.1.20", &Command::Reboot), 0);
+
+        old.stats.hello(Hello {
+            protocol: COMMAND_PROTOCOL - 1,
+            firmware: 1,
+        });
+        assert_eq!(connections.command("192.168.1.21", &Command::Reboot), 0);
+        assert!(old_commands.try_recv().is_err());
+
+        drop(kitchen);
+        assert_eq!(connections.command("kitchen", &Command::Telemetry), 0);
+    }
+}
@@ -0,0 +1,62 @@
+//! Kafka and NATS outputs for data platforms. Every reading is one message
+//! keyed by its tag, encoded as JSON or Avro. Both clients are optional
+//! features, a configured stream fails the startup of a gateway built without.
+
+use crate::config::{KafkaConfig, NatsConfig, StreamEncoding};
+use crate::sink::{QueuedSink, StoredReading, to_json};
+#[cfg(any(feature = "kafka", feature = "nats"))]
+use std::sync::Arc;
+
+// The encoders are only used by the optional clients, their tests run regardless
+#[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(dead_code))]
+pub mod avro;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "nats")]
+mod nats;
+
+#[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(dead_code))]
+pub fn encode(reading: &StoredReading, encoding: StreamEncoding) -> Vec<u8> {
+    match encoding {
+        StreamEncoding::Json => to_json(reading).to_string().into_bytes(),
+        StreamEncoding::Avro => avro::encode(reading),
+    }
+}
+
+/// `None` when no brokers are configured
+pub fn kafka(config: &KafkaConfig) -> Result<Option<QueuedSink>, anyhow::Error> {
+    if config.brokers.is_none() {
+        return Ok(None);
+    }
+    kafka_sink(config).map(Some)
+}
+
+#[cfg(feature = "kafka")]
+fn kafka_sink(config: &KafkaConfig) -> Result<QueuedSink, anyhow::Error> {
+    let (sink, buffering) = kafka::KafkaSink::new(config)?;
+    Ok((Arc::new(sink), buffering))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn kafka_sink(_: &KafkaConfig) -> Result<QueuedSink, anyhow::Error> {
+    anyhow::bail!("[kafka] is configured, but the gateway was built without the kafka feature")
+}
+
+/// `None` when no server is configured
+pub async fn nats(config: &NatsConfig) -> Result<Option<QueuedSink>, anyhow::Error> {
+    if config.url.is_none() {
+        return Ok(None);
+    }
+    nats_sink(config).await.map(Some)
+}
+
+#[cfg(feature = "nats")]
+async fn nats_sink(config: &NatsConfig) -> Result<QueuedSink, anyhow::Error> {
+    let (sink, buffering) = nats::NatsSink::connect(config).await?;
+    Ok((Arc::new(sink), buffering))
+}
+
+#[cfg(not(feature = "nats"))]
+async fn nats_sink(_: &NatsConfig) -> Result<QueuedSink, anyhow::Error> {
+    anyhow::bail!("[nats] is configured, but the gateway was built without the nats feature")
+}
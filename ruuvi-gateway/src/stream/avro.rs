@@ -0,0 +1,182 @@
+//! Avro single-object encoding of readings. Every format shares one record
+//! schema, fields a format lacks are null. Each message starts with the
+//! schema's CRC-64-AVRO fingerprint so consumers can find the schema, which
+//! `ruuvi-gateway avro-schema` prints.
+
+use crate::sink::{StoredReading, to_json};
+use serde_json::{Value, json};
+
+#[derive(Clone, Copy)]
+enum Kind {
+    String,
+    /// Milliseconds since the Unix epoch
+    Timestamp,
+    Long,
+    OptionalLong,
+    OptionalDouble,
+}
+
+const FIELDS: [(&str, Kind); 26] = [
+    ("mac", Kind::String),
+    ("format", Kind::String),
+    ("listener", Kind::String),
+    ("timestamp", Kind::Timestamp),
+    ("rssi", Kind::Long),
+    ("measurement_seq", Kind::OptionalLong),
+    ("temp", Kind::OptionalDouble),
+    ("dew_point_temp", Kind::OptionalDouble),
+    ("rel_humidity", Kind::OptionalDouble),
+    ("abs_humidity", Kind::OptionalDouble),
+    ("abs_pressure", Kind::OptionalLong),
+    ("acc_x", Kind::OptionalLong),
+    ("acc_y", Kind::OptionalLong),
+    ("acc_z", Kind::OptionalLong),
+    ("battery_voltage", Kind::OptionalDouble),
+    ("tx_power", Kind::OptionalLong),
+    ("movement_counter", Kind::OptionalLong),
+    ("pm1_0", Kind::OptionalDouble),
+    ("pm2_5", Kind::OptionalDouble),
+    ("pm4_0", Kind::OptionalDouble),
+    ("pm10_0", Kind::OptionalDouble),
+    ("co2", Kind::OptionalLong),
+    ("voc_index", Kind::OptionalLong),
+    ("nox_index", Kind::OptionalLong),
+    ("luminosity", Kind::OptionalDouble),
+    ("flags", Kind::OptionalLong),
+];
+
+const NAMESPACE: &str = "ruuvi";
+const NAME: &str = "Reading";
+
+/// The schema as consumers register it
+pub fn schema() -> Value {
+    let fields: Vec<_> = FIELDS
+        .iter()
+        .map(|&(name, kind)| match kind {
+            Kind::String => json!({ "name": name, "type": "string" }),
+            Kind::Timestamp => json!({
+                "name": name,
+                "type": { "type": "long", "logicalType": "timestamp-millis" },
+            }),
+            Kind::Long => json!({ "name": name, "type": "long" }),
+            Kind::OptionalLong => {
+                json!({ "name": name, "type": ["null", "long"], "default": null })
+            }
+            Kind::OptionalDouble => {
+                json!({ "name": name, "type": ["null", "double"], "default": null })
+            }
+        })
+        .collect();
+    json!({
+        "type": "record",
+        "name": NAME,
+        "namespace": NAMESPACE,
+        "fields": fields,
+    })
+}
+
+/// Parsing Canonical Form of [`schema`], the input of its fingerprint
+fn canonical_schema() -> String {
+    let fields: Vec<_> = FIELDS
+        .iter()
+        .map(|&(name, kind)| {
+            let kind = match kind {
+                Kind::String => r#""string""#,
+                Kind::Timestamp | Kind::Long => r#""long""#,
+                Kind::OptionalLong => r#"["null","long"]"#,
+                Kind::OptionalDouble => r#"["null","double"]"#,
+            };
+            format!(r#"{{"name":"{name}","type":{kind}}}"#)
+        })
+        .collect();
+    format!(
+        r#"{{"name":"{NAMESPACE}.{NAME}","type":"record","fields":[{}]}}"#,
+        fields.join(",")
+    )
+}
+
+const EMPTY: u64 = 0xc15d_213a_a4d7_a795;
+
+const FINGERPRINT_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut fp = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            fp = (fp >> 1) ^ (EMPTY & (fp & 1).wrapping_neg());
+            bit += 1;
+        }
+        table[i] = fp;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-64-AVRO, the Rabin fingerprint of the Avro specification
+fn fingerprint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(EMPTY, |fp, &b| {
+        (fp >> 8) ^ FINGERPRINT_TABLE[((fp ^ u64::from(b)) & 0xff) as usize]
+    })
+}
+
+pub fn encode(reading: &StoredReading) -> Vec<u8> {
+    let json = to_json(reading);
+    let mut out = vec![0xC3, 0x01];
+    out.extend_from_slice(&fingerprint(canonical_schema().as_bytes()).to_le_bytes());
+    for (name, kind) in FIELDS {
+        let value = &json[name];
+        match kind {
+            Kind::String => write_string(&mut out, value.as_str().unwrap_or_default()),
+            Kind::Timestamp => write_long(&mut out, reading.data.timestamp().timestamp_millis()),
+            Kind::Long => write_long(&mut out, value.as_i64().unwrap_or_default()),
+            Kind::OptionalLong => match value.as_i64() {
+                Some(v) => {
+                    write_long(&mut out, 1);
+                    write_long(&mut out, v);
+                }
+                None => write_long(&mut out, 0),
+            },
+            Kind::OptionalDouble => match value.as_f64() {
+                Some(v) => {
+                    write_long(&mut out, 1);
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+                None => write_long(&mut out, 0),
+            },
+        }
+    }
+    out
+}
+
+/// Zigzag varint
+fn write_long(out: &mut Vec<u8>, value: i64) {
+    let mut n = ((value << 1) ^ (value >> 63)) as u64;
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_long(out, value.len() as i64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, write_long};
+
+    #[test]
+    fn encodes_like_the_specification() {
+        // Test vector of the Avro schema fingerprinting tests
+        assert_eq!(fingerprint(br#""null""#), 7195948357588979594);
+
+        let mut out = Vec::new();
+        for value in [0, -1, 1, -64, 64] {
+            write_long(&mut out, value);
+        }
+        assert_eq!(out, [0x00, 0x01, 0x02, 0x7F, 0x80, 0x01]);
+    }
+}
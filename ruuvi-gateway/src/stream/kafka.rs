@@ -0,0 +1,68 @@
+use super::encode;
+use crate::config::{KafkaConfig, StreamEncoding};
+use crate::mac::format_mac;
+use crate::sink::{Buffering, Sink, StoredReading};
+use futures_util::future::BoxFuture;
+use rdkafka::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use std::time::Duration;
+
+/// Longest a message waits for room in the producer's queue
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Produces readings to a Kafka topic, keyed by the tag's MAC so a tag's
+/// readings stay in order within one partition
+pub struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+    encoding: StreamEncoding,
+}
+
+impl KafkaSink {
+    pub fn new(config: &KafkaConfig) -> Result<(Self, Buffering), anyhow::Error> {
+        let mut client = ClientConfig::new();
+        client
+            .set(
+                "bootstrap.servers",
+                config.brokers.as_deref().unwrap_or_default(),
+            )
+            .set("acks", &config.acks);
+        for (key, value) in &config.properties {
+            client.set(key, value);
+        }
+        let sink = Self {
+            producer: client.create()?,
+            topic: config.topic.clone(),
+            encoding: config.encoding,
+        };
+        let buffering = Buffering {
+            batch_size: config.batch_size,
+            flush: Duration::from_millis(config.flush_ms),
+        };
+        Ok((sink, buffering))
+    }
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "Kafka"
+    }
+
+    fn write<'a>(&'a self, batch: &'a [StoredReading]) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            // Queued together, the producer sends them in as few requests as it can
+            let deliveries = batch.iter().map(|reading| {
+                let key = format_mac(&reading.data.mac());
+                let payload = encode(reading, self.encoding);
+                async move {
+                    let record = FutureRecord::to(&self.topic).key(&key).payload(&payload);
+                    self.producer.send(record, QUEUE_TIMEOUT).await
+                }
+            });
+            for delivery in futures_util::future::join_all(deliveries).await {
+                delivery.map_err(|(e, _)| e)?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,80 @@
+use super::encode;
+use crate::config::{NatsConfig, StreamEncoding};
+use crate::sink::{Buffering, Sink, StoredReading};
+use async_nats::jetstream;
+use futures_util::future::BoxFuture;
+use std::time::Duration;
+
+/// Publishes readings to `<subject_prefix>.<mac>`, optionally waiting for the
+/// acknowledgement of the JetStream stream covering the subjects
+pub struct NatsSink {
+    client: async_nats::Client,
+    jetstream: Option<jetstream::Context>,
+    subject_prefix: String,
+    encoding: StreamEncoding,
+}
+
+impl NatsSink {
+    pub async fn connect(config: &NatsConfig) -> Result<(Self, Buffering), anyhow::Error> {
+        // The client keeps reconnecting in the background, also when the server
+        // is down at startup
+        let mut options = async_nats::ConnectOptions::new().retry_on_initial_connect();
+        if let Some(token) = &config.token {
+            options = options.token(token.clone());
+        }
+        if let Some(username) = &config.username {
+            options = options.user_and_password(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            );
+        }
+        let client = options
+            .connect(config.url.as_deref().unwrap_or_default())
+            .await?;
+        let sink = Self {
+            jetstream: config.jetstream.then(|| jetstream::new(client.clone())),
+            client,
+            subject_prefix: config.subject_prefix.clone(),
+            encoding: config.encoding,
+        };
+        let buffering = Buffering {
+            batch_size: config.batch_size,
+            flush: Duration::from_millis(config.flush_ms),
+        };
+        Ok((sink, buffering))
+    }
+}
+
+impl Sink for NatsSink {
+    fn name(&self) -> &'static str {
+        "NATS"
+    }
+
+    fn write<'a>(&'a self, batch: &'a [StoredReading]) -> BoxFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let mut acks = Vec::new();
+            for reading in batch {
+                let mac: String = reading
+                    .data
+                    .mac()
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect();
+                let subject = format!("{}.{mac}", self.subject_prefix);
+                let payload = encode(reading, self.encoding).into();
+                match &self.jetstream {
+                    Some(jetstream) => acks.push(jetstream.publish(subject, payload).await?),
+                    None => self.client.publish(subject, payload).await?,
+                }
+            }
+            if self.jetstream.is_none() {
+                self.client.flush().await?;
+            }
+            // Published back to back, the stream acknowledges them in order
+            for ack in acks {
+                ack.await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,59 @@
+use serde::Deserialize;
+use snow::params::NoiseParams;
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::TcpStream;
+
+/// Noise cipher suites a listener can pick with the first byte of the connection.
+/// The byte is also the handshake prologue, so a tampered choice fails the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Suite {
+    /// Fastest without AES hardware, used by the ESP32 listeners
+    ChaChaPoly,
+    /// For hosts with AES-NI
+    AesGcm,
+}
+
+impl Suite {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::ChaChaPoly => 1,
+            Self::AesGcm => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::ChaChaPoly),
+            2 => Some(Self::AesGcm),
+            _ => None,
+        }
+    }
+
+    pub fn params(&self) -> NoiseParams {
+        let params = match self {
+            Self::ChaChaPoly => "Noise_XXpsk3_25519_ChaChaPoly_SHA256",
+            Self::AesGcm => "Noise_XXpsk3_25519_AESGCM_SHA256",
+        };
+        params.parse().expect("Valid noise params")
+    }
+}
+
+/// Cipher suite requested by a connecting listener
+#[derive(Debug, Clone, Copy)]
+pub enum Selection {
+    Suite(Suite),
+    /// Listeners predating suite selection start directly with the length prefix
+    /// of the first handshake message, whose high byte is always zero.
+    /// Carries the low byte that was already consumed.
+    Legacy(u8),
+    Unknown(u8),
+}
+
+pub async fn read_selection(stream: &mut TcpStream) -> io::Result<Selection> {
+    let id = stream.read_u8().await?;
+    if id == 0 {
+        return Ok(Selection::Legacy(stream.read_u8().await?));
+    }
+    Ok(Suite::from_id(id).map_or(Selection::Unknown(id), Selection::Suite))
+}
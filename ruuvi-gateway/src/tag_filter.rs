@@ -0,0 +1,87 @@
+//! Readings of unwanted tags, like the neighbours', are dropped or quarantined
+//! before deduplication, whichever listener heard them.
+
+use crate::config::TagFilterConfig;
+use crate::mac::format_mac;
+use crate::registry::Registry;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+pub struct TagFilter {
+    /// Filtered tags already logged
+    seen: Mutex<HashSet<[u8; 6]>>,
+}
+
+impl TagFilter {
+    pub fn new() -> Self {
+        Self {
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Whether the readings of `mac` are stored. A filtered tag is logged the
+    /// first time it's heard.
+    pub fn passes(
+        &self,
+        config: &TagFilterConfig,
+        registry: &Registry,
+        mac: [u8; 6],
+        listener: &str,
+    ) -> bool {
+        let restricted = config.registered_only || !config.allow.is_empty();
+        let passes = !config.deny.contains(&mac)
+            && (!restricted
+                || config.allow.contains(&mac)
+                || (config.registered_only && registry.get(&mac).is_some()));
+        if !passes && self.seen.lock().unwrap().insert(mac) {
+            let action = if config.quarantine {
+                "Quarantining"
+            } else {
+                "Dropping"
+            };
+            tracing::info!(
+                "{action} the readings of filtered tag {}, first heard by {listener}",
+                format_mac(&mac)
+            );
+        }
+        passes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TagFilter;
+    use crate::config::TagFilterConfig;
+    use crate::registry::{RegisteredTag, Registry};
+
+    #[test]
+    fn allows_registered_and_listed_tags() {
+        let (own, listed, neighbour) = ([1; 6], [2; 6], [3; 6]);
+        let registry = Registry::default();
+        registry.replace(vec![
+            RegisteredTag {
+                mac: own,
+                name: "sauna".to_owned(),
+                location: None,
+                formats: Vec::new(),
+            }
+            .to_row(),
+        ]);
+        let filter = TagFilter::new();
+        let passes = |config: &TagFilterConfig, mac| filter.passes(config, &registry, mac, "hall");
+
+        let mut config = TagFilterConfig::default();
+        assert!(passes(&config, neighbour));
+
+        config.registered_only = true;
+        assert!(passes(&config, own));
+        assert!(!passes(&config, listed));
+
+        config.allow = vec![listed];
+        assert!(passes(&config, listed));
+        assert!(!passes(&config, neighbour));
+
+        config.deny = vec![own];
+        assert!(!passes(&config, own));
+    }
+}
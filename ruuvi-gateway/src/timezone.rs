@@ -0,0 +1,33 @@
+use chrono::{DateTime, Days, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde_json::Value;
+
+/// Render `timestamp` fields of a serialized response in the given timezone
+pub fn localize_timestamps(value: &mut Value, tz: Tz) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                match field {
+                    Value::String(s) if key == "timestamp" => {
+                        if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+                            *s = dt.with_timezone(&tz).to_rfc3339();
+                        }
+                    }
+                    _ => localize_timestamps(field, tz),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| localize_timestamps(v, tz)),
+        _ => (),
+    }
+}
+
+/// Local midnight `days` days before today in `tz`, as UTC
+pub fn local_midnight_days_ago(tz: Tz, days: u64) -> Option<DateTime<Utc>> {
+    let today = Utc::now().with_timezone(&tz).date_naive();
+    let day = today.checked_sub_days(Days::new(days))?;
+    // DST transitions can make midnight ambiguous or skipped, take the earliest valid instant
+    tz.from_local_datetime(&day.and_time(NaiveTime::MIN))
+        .earliest()
+        .map(|dt| dt.with_timezone(&Utc))
+}
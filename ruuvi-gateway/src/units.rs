@@ -0,0 +1,50 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Unit system used in API responses. Readings are always stored in metric.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+impl Units {
+    /// Convert known metric fields of a serialized reading in place
+    pub fn apply(self, value: &mut Value) {
+        if self == Self::Metric {
+            return;
+        }
+        match value {
+            Value::Object(map) => {
+                for (key, field) in map.iter_mut() {
+                    match (key.as_str(), field.as_f64()) {
+                        (key, Some(v)) => {
+                            if let Some(converted) = to_imperial(key, v) {
+                                *field = Value::from(converted);
+                            }
+                        }
+                        (_, None) => self.apply(field),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|v| self.apply(v)),
+            _ => (),
+        }
+    }
+}
+
+fn to_imperial(key: &str, v: f64) -> Option<f64> {
+    match key {
+        // °C -> °F
+        "temp" | "dew_point_temp" | "temp_min" | "temp_max" | "temp_avg" => {
+            Some(v * 9.0 / 5.0 + 32.0)
+        }
+        // Pa -> inHg
+        "abs_pressure" => Some(v / 3386.389),
+        // g/m³ -> gr/ft³
+        "abs_humidity" => Some(v * 0.436996),
+        _ => None,
+    }
+}
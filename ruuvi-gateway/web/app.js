@@ -0,0 +1,121 @@
+"use strict";
+
+// Latest values refresh often, the sparkline history only every few minutes
+const LATEST_INTERVAL_MS = 10_000;
+const HISTORY_INTERVAL_MS = 300_000;
+const STALE_SECS = 300;
+const MODELS = {
+  ruuvi_tag: "RuuviTag",
+  ruuvi_tag_pro: "RuuviTag Pro",
+  ruuvi_air: "Ruuvi Air",
+};
+
+const sparklines = new Map();
+
+function token() {
+  return localStorage.getItem("ruuvi-token");
+}
+
+function askToken() {
+  const value = prompt("API token (leave empty for an open API)", token() ?? "");
+  if (value === null) return;
+  if (value) localStorage.setItem("ruuvi-token", value);
+  else localStorage.removeItem("ruuvi-token");
+  refresh(true);
+}
+
+async function api(path) {
+  const headers = token() ? { Authorization: `Bearer ${token()}` } : {};
+  const sep = path.includes("?") ? "&" : "?";
+  const response = await fetch(`${path}${sep}units=metric`, { headers });
+  if (response.status === 401 || response.status === 403) {
+    throw new Error("API token required");
+  }
+  if (!response.ok) throw new Error(`${path}: ${response.status}`);
+  return response.json();
+}
+
+function fixed(value, digits, unit) {
+  return value === undefined || value === null ? "" : `${value.toFixed(digits)} ${unit}`;
+}
+
+function age(timestamp) {
+  return Math.max(0, Math.round((Date.now() - Date.parse(timestamp)) / 1000));
+}
+
+function sparkline(values) {
+  if (values.length < 2) return "";
+  const width = 120;
+  const height = 24;
+  const min = Math.min(...values);
+  const max = Math.max(...values);
+  const span = max - min || 1;
+  const points = values
+    .map((v, i) => {
+      const x = (i / (values.length - 1)) * width;
+      const y = height - ((v - min) / span) * height;
+      return `${x.toFixed(1)},${y.toFixed(1)}`;
+    })
+    .join(" ");
+  return `<svg class="spark" width="${width}" height="${height}" viewBox="0 0 ${width} ${height}">` +
+    `<title>${min.toFixed(1)} – ${max.toFixed(1)} °C</title><polyline points="${points}"/></svg>`;
+}
+
+async function loadSparkline(mac) {
+  const page = await api(`/tags/${mac}/history`);
+  const temps = page.readings.map((r) => r.temp).filter((t) => t !== null && t !== undefined);
+  sparklines.set(mac, { html: sparkline(temps), loaded: Date.now() });
+}
+
+function row(reading) {
+  const data = reading.data;
+  const seen = age(data.timestamp);
+  const spark = sparklines.get(data.mac);
+  const cells = [
+    [data.mac, "mac"],
+    [MODELS[reading.model] ?? reading.model],
+    [reading.location ?? ""],
+    [fixed(data.temp, 1, "°C")],
+    [fixed(data.rel_humidity, 1, "%")],
+    [fixed(data.abs_pressure / 100, 1, "hPa")],
+    [data.co2 !== undefined ? `${data.co2} ppm` : ""],
+    [fixed(data.battery_voltage, 2, "V")],
+    [`${data.rssi} dBm`],
+    [`${seen} s ago`, seen > STALE_SECS ? "stale" : ""],
+  ];
+  const tr = document.createElement("tr");
+  for (const [text, cls] of cells) {
+    const td = document.createElement("td");
+    td.textContent = text;
+    if (cls) td.className = cls;
+    tr.appendChild(td);
+  }
+  const td = document.createElement("td");
+  td.innerHTML = spark ? spark.html : "";
+  tr.appendChild(td);
+  return tr;
+}
+
+async function refresh(reloadHistory = false) {
+  const status = document.getElementById("status");
+  try {
+    const latest = await api("/latest");
+    await Promise.all(
+      latest
+        .map((r) => r.data.mac)
+        .filter((mac) => {
+          const spark = sparklines.get(mac);
+          return reloadHistory || !spark || Date.now() - spark.loaded > HISTORY_INTERVAL_MS;
+        })
+        .map((mac) => loadSparkline(mac).catch(() => {})),
+    );
+    document.getElementById("tags").replaceChildren(...latest.map(row));
+    status.textContent = `${latest.length} tags, updated ${new Date().toLocaleTimeString()}`;
+  } catch (e) {
+    status.textContent = e.message;
+  }
+}
+
+document.getElementById("token").addEventListener("click", askToken);
+refresh();
+setInterval(refresh, LATEST_INTERVAL_MS);
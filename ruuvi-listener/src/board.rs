@@ -1,17 +1,54 @@
 use crate::config::BoardConfig;
+use anyhow::anyhow;
 use bt_hci::controller::ExternalController;
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use esp_bootloader_esp_idf::partitions::{self, PARTITION_TABLE_MAX_LEN};
 use esp_hal::clock::CpuClock;
+use esp_hal::gpio::{Input, InputConfig, Pull};
 use esp_hal::peripherals;
 use esp_hal::peripherals::Peripherals;
 use esp_hal::rmt::{PulseCode, Rmt};
+use esp_hal::rtc_cntl::{Rtc, Rwdt};
 use esp_hal::time::Rate;
 use esp_hal::timer::systimer::SystemTimer;
 use esp_hal_smartled::{SmartLedsAdapterAsync, buffer_size_async};
 use esp_radio::ble::controller::BleConnector;
+use esp_storage::FlashStorage;
 use static_cell::StaticCell;
 
 static RMT_BUF: StaticCell<[PulseCode; buffer_size_async(1)]> = StaticCell::new();
 static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
+static FLASH: StaticCell<Flash> = StaticCell::new();
+
+/// Flash shared by the tasks keeping state across reboots
+pub struct Flash(Mutex<NoopRawMutex, RefCell<FlashStorage<'static>>>);
+
+impl Flash {
+    /// Operations block, so they never interleave. Don't nest them.
+    pub fn with<T>(&self, op: impl FnOnce(&mut FlashStorage<'static>) -> T) -> T {
+        self.0.lock(|flash| op(&mut flash.borrow_mut()))
+    }
+}
+
+/// Runs `op` on the data partition labeled `label`, see `partitions.csv`
+pub fn with_partition(
+    flash: &mut FlashStorage<'static>,
+    label: &str,
+    op: impl FnOnce(
+        &mut partitions::FlashRegion<'_, FlashStorage<'static>>,
+    ) -> Result<(), partitions::Error>,
+) -> Result<(), anyhow::Error> {
+    let mut buffer = [0u8; PARTITION_TABLE_MAX_LEN];
+    let table = partitions::read_partition_table(flash, &mut buffer)
+        .map_err(|e| anyhow!("Failed to read the partition table: {e:?}"))?;
+    let entry = table
+        .iter()
+        .find(|entry| entry.label_as_str() == label)
+        .ok_or_else(|| anyhow!("No {label} partition"))?;
+    op(&mut entry.as_embedded_storage(flash)).map_err(|e| anyhow!("{label} partition: {e:?}"))
+}
 
 pub fn init_peripherals() -> Peripherals {
     // find more examples https://github.com/embassy-rs/trouble/tree/main/examples/esp32
@@ -58,6 +95,9 @@ pub fn init(peripherals: Peripherals) -> BoardConfig {
         ble_controller,
         peripherals.RMT,
         peripherals.GPIO48,
+        peripherals.GPIO0,
+        peripherals.FLASH,
+        peripherals.LPWR,
     )
 }
 
@@ -81,3 +121,17 @@ pub fn init_led(
     log::info!("Smart LED adapter initialized!");
     led
 }
+
+pub fn init_button(gpio0: peripherals::GPIO0<'static>) -> Input<'static> {
+    // BOOT button, pulls the pin low while pressed
+    Input::new(gpio0, InputConfig::default().with_pull(Pull::Up))
+}
+
+pub fn init_flash(flash: peripherals::FLASH<'static>) -> &'static Flash {
+    FLASH.init(Flash(Mutex::new(RefCell::new(FlashStorage::new(flash)))))
+}
+
+pub fn init_watchdog(lpwr: peripherals::LPWR<'static>) -> Rwdt {
+    // Stage 0 resets the system, the other stages are off
+    Rtc::new(lpwr).rwdt
+}
@@ -0,0 +1,61 @@
+//! Wi-Fi and BLE share the radio, and a busy scanner can starve the sends to
+//! the gateway. Each send that fails or crawls halves the scan window, up to
+//! [`COEX_MAX_BACKOFF`] times, and it grows back a step for every
+//! [`COEX_RECOVER_SECS`] without trouble.
+
+use crate::config::{COEX_MAX_BACKOFF, COEX_RECOVER_SECS, COEX_SLOW_SEND_MS};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+use embassy_time::{Duration, Instant};
+
+/// Shortest window a controller takes
+const MIN_WINDOW: Duration = Duration::from_millis(3);
+
+/// Times the scan window is currently halved
+static BACKOFF: AtomicU8 = AtomicU8::new(0);
+/// Seconds since boot of the last trouble, or of the last step back up
+static LAST_CHANGE: AtomicU32 = AtomicU32::new(0);
+
+/// A send to the gateway went through after `elapsed`
+pub fn sent(elapsed: Duration) {
+    if elapsed >= Duration::from_millis(COEX_SLOW_SEND_MS) {
+        log::warn!("Sending took {}ms", elapsed.as_millis());
+        trouble();
+    }
+}
+
+/// A send to the gateway failed
+pub fn send_failed() {
+    trouble();
+}
+
+fn trouble() {
+    LAST_CHANGE.store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+    let backoff = BACKOFF.load(Ordering::Relaxed);
+    if backoff < COEX_MAX_BACKOFF {
+        BACKOFF.store(backoff + 1, Ordering::Relaxed);
+        log::info!(
+            "Wi-Fi under pressure, scan window backed off {}x",
+            backoff + 1
+        );
+    }
+}
+
+/// Times the scan window is currently halved, for the metrics
+#[cfg(feature = "metrics")]
+pub fn backoff() -> u8 {
+    BACKOFF.load(Ordering::Relaxed)
+}
+
+/// `window` under the current pressure, taking a step back up once it has eased
+pub fn window(window: Duration) -> Duration {
+    let now = Instant::now().as_secs() as u32;
+    let mut backoff = BACKOFF.load(Ordering::Relaxed);
+    let calm = now.saturating_sub(LAST_CHANGE.load(Ordering::Relaxed));
+    if backoff > 0 && u64::from(calm) >= COEX_RECOVER_SECS {
+        backoff -= 1;
+        BACKOFF.store(backoff, Ordering::Relaxed);
+        LAST_CHANGE.store(now, Ordering::Relaxed);
+        log::info!("Wi-Fi pressure eased, scan window backed off {backoff}x");
+    }
+    (window / (1u32 << backoff)).max(MIN_WINDOW)
+}
@@ -1,16 +1,53 @@
 use bt_hci::controller::ExternalController;
-use core::net::Ipv4Addr;
 use dotenvy_macro::dotenv;
 use esp_hal::peripherals;
 use esp_hal::rng::Rng;
 use esp_radio::ble::controller::BleConnector;
 use esp_radio::wifi::{Interfaces, WifiController};
+use ruuvi_schema::protocol::ScanPhys;
 
 pub const SSID: &str = dotenv!("SSID");
 pub const PASSWORD: &str = dotenv!("PASSWORD");
+/// User name of a WPA2-Enterprise network, empty for a WPA2-Personal or open one
+pub const EAP_USERNAME: &str = dotenv!("EAP_USERNAME");
+/// Outer identity sent in the clear, like `anonymous@example.com`. Empty sends the user name.
+pub const EAP_IDENTITY: &str = dotenv!("EAP_IDENTITY");
+/// Hex encoded DER certificate of the CA that signed the RADIUS server's certificate.
+/// Empty accepts any server.
+pub const EAP_CA_CERT_HEX: &str = dotenv!("EAP_CA_CERT");
 pub const GATEWAY_IP: &str = dotenv!("GATEWAY_IP");
 pub const GATEWAY_PORT: &str = dotenv!("GATEWAY_PORT");
 pub const AUTH_KEY: &str = dotenv!("AUTH_KEY");
+/// Name of `AUTH_KEY` in the gateway's `[[noise.psks]]`, empty for its default key
+pub const PSK_NAME: &str = dotenv!("PSK_NAME");
+/// Broker credentials of the MQTT transport, empty for an anonymous broker
+#[cfg(feature = "transport-mqtt")]
+pub const MQTT_USERNAME: &str = dotenv!("MQTT_USERNAME");
+#[cfg(feature = "transport-mqtt")]
+pub const MQTT_PASSWORD: &str = dotenv!("MQTT_PASSWORD");
+/// The MQTT transport publishes to `<MQTT_TOPIC_PREFIX>/listeners/<address>/readings`,
+/// matching the gateway's `[mqtt] topic_prefix`
+#[cfg(feature = "transport-mqtt")]
+pub const MQTT_TOPIC_PREFIX: &str = "ruuvi";
+/// Static address with its prefix length, like `192.168.1.50/24`. Empty uses DHCP.
+pub const STATIC_IP: &str = dotenv!("STATIC_IP");
+/// Router of the static address, empty for none
+pub const STATIC_GATEWAY: &str = dotenv!("STATIC_GATEWAY");
+/// Up to 3 DNS servers of the static address, separated by commas
+pub const STATIC_DNS: &str = dotenv!("STATIC_DNS");
+/// Comma separated tag MACs like `C8:25:2D:8E:9C:2C`, only these are forwarded when set
+pub const TAG_ALLOWLIST: &str = dotenv!("TAG_ALLOWLIST");
+/// Comma separated tag MACs never forwarded
+pub const TAG_DENYLIST: &str = dotenv!("TAG_DENYLIST");
+/// Hex encoded Ed25519 key firmware images pushed by the gateway must be signed with
+pub const OTA_PUBLIC_KEY_HEX: &str = dotenv!("OTA_PUBLIC_KEY");
+/// Pairing code written to the provisioning GATT service before anything else, empty
+/// disables provisioning over BLE
+pub const PROVISIONING_CODE: &str = dotenv!("PROVISIONING_CODE");
+/// Network name of the diagnostics access point
+pub const DIAG_SSID: &str = "ruuvi-listener-diag";
+/// Network name of the setup portal's access point
+pub const PORTAL_SSID: &str = "ruuvi-listener-setup";
 
 // Validate auth key length is 32 bytes
 const _: () = {
@@ -19,35 +56,190 @@ const _: () = {
     }
 };
 
+const _: () = {
+    let timing = ruuvi_schema::protocol::SCAN_TIMING_MS;
+    if SCAN_INTERVAL_MS < *timing.start() || SCAN_INTERVAL_MS > *timing.end() {
+        panic!("SCAN_INTERVAL_MS must be within 3..=10240");
+    }
+    if SCAN_WINDOW_MS < *timing.start() || SCAN_WINDOW_MS > SCAN_INTERVAL_MS {
+        panic!("SCAN_WINDOW_MS must be at least 3 and at most SCAN_INTERVAL_MS");
+    }
+};
+
+const EAP_CA_CERT: &[u8] = &const_str::hex!(EAP_CA_CERT_HEX);
+
+pub const OTA_PUBLIC_KEY: [u8; 32] = {
+    if OTA_PUBLIC_KEY_HEX.len() != 64 {
+        panic!("OTA_PUBLIC_KEY must be exactly 64 hex characters");
+    }
+    const_str::hex!(OTA_PUBLIC_KEY_HEX)
+};
+
+/// Readings buffered between the BLE scanner and the sender.
+/// Raise for deployments with many tags so Wi-Fi hiccups don't drop packets.
+pub const PACKET_QUEUE_DEPTH: usize = 16;
+/// What goes when a reading arrives to a full packet queue, counted as dropped in telemetry
+pub const QUEUE_OVERFLOW: OverflowPolicy = OverflowPolicy::DropDuplicates;
+/// LED events buffered for the blinker task
+pub const LED_QUEUE_DEPTH: usize = 16;
+/// A BLE scan starts this often and lasts for the window. A window below the interval
+/// idles the radio in between, saving power at the cost of missing advertisements.
+/// The gateway can override these, see `[[scan]]` in its configuration.
+pub const SCAN_INTERVAL_MS: u16 = 1000;
+pub const SCAN_WINDOW_MS: u16 = 1000;
+/// PHYs scanned. `M1Coded` also picks up tags advertising on the long range Coded PHY, like
+/// a Ruuvi Air at the edge of range. The controller then splits its time between the two.
+pub const SCAN_PHYS: ScanPhys = ScanPhys::M1;
+/// Ask for scan responses, Ruuvi tags put all their data in the advertisement
+pub const SCAN_ACTIVE: bool = false;
+/// A send to the gateway taking this long counts as Wi-Fi starved by the BLE scanner
+pub const COEX_SLOW_SEND_MS: u64 = 500;
+/// The scan window is halved on every slow or failed send, at most this many times. 0 keeps
+/// the window as configured.
+pub const COEX_MAX_BACKOFF: u8 = 3;
+/// The scan window doubles back once sends have gone through for this long
+pub const COEX_RECOVER_SECS: u64 = 30;
+/// The provisioning GATT service is advertised this long after boot
+pub const PROVISIONING_WINDOW_SECS: u64 = 5 * 60;
+/// Tags tracked for duplicate detection, must be a power of two. Also the most MACs
+/// `TAG_ALLOWLIST` and `TAG_DENYLIST` take each.
+pub const MAX_TAGS: usize = 16;
+/// At most one reading per tag is forwarded this often, 0 forwards them all. A tag that
+/// moved is forwarded right away. Keep it below the gateway's `[offline] timeout_secs`.
+pub const TAG_MIN_INTERVAL_SECS: u64 = 0;
+/// Readings are sent in batches once per window, at a phase derived from the device MAC so
+/// listeners don't all flush at once. 0 sends readings as they arrive. Keep the window and
+/// jitter below the gateway's dedup window when several listeners hear the same tags.
+pub const BATCH_WINDOW_MS: u64 = 1000;
+/// Random delay added to every batch on top of the phase
+pub const BATCH_JITTER_MS: u64 = 250;
+/// The clock is re-synced with the gateway this often while connected, correcting
+/// the crystal's drift in between
+pub const TIME_SYNC_INTERVAL_SECS: u64 = 15 * 60;
+/// Telemetry is reported to the gateway this often
+pub const TELEMETRY_INTERVAL_SECS: u64 = 5 * 60;
+/// Without anything else to send a heartbeat goes out this often, so a dead connection
+/// fails a write instead of going unnoticed
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 30;
+/// Sent readings kept until the gateway acknowledges storing them, the oldest is dropped
+/// when full. Resent after a reconnect.
+pub const RETRY_BUFFER_DEPTH: usize = 64;
+/// Before connecting the listener asks for the gateway over mDNS and waits this long for an
+/// answer, falling back to `GATEWAY_IP` and `GATEWAY_PORT`. 0 always uses those.
+pub const MDNS_TIMEOUT_MS: u64 = 1000;
+/// Configure an IPv6 address from router advertisements next to the IPv4 one
+pub const IPV6_SLAAC: bool = true;
+/// Failed connects or handshakes in a row before the listener moves on to the next gateway
+/// in `GATEWAY_IP`
+pub const FAILOVER_AFTER: u32 = 3;
+/// A session with a backup gateway is closed this often to try the primary again
+pub const PRIMARY_RETRY_SECS: u64 = 10 * 60;
+/// A scanner or sender that hasn't checked in for this long is considered wedged and the
+/// listener resets. Keep it above the longest reconnect, backoff included.
+pub const TASK_STALL_SECS: u64 = 5 * 60;
+/// The hardware watchdog resets the listener once the firmware stops running for this long
+pub const WATCHDOG_TIMEOUT_SECS: u64 = 30;
+/// A connection without acknowledgements for this long is considered dead. Keep it above
+/// the gateway's dedup window.
+pub const ACK_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// The reading queued the longest
+    DropOldest,
+    /// The reading that arrived, the queued ones stay
+    DropNewest,
+    /// The oldest reading of a tag that has a newer one queued or arriving, the oldest
+    /// reading when every tag has just the one
+    DropDuplicates,
+}
+
 pub struct WifiConfig {
     pub ssid: &'static str,
+    /// Pre-shared key, or the user's password on an enterprise network
     pub password: &'static str,
+    pub enterprise: Option<EnterpriseConfig>,
+}
+
+/// 802.1X credentials for EAP-PEAP or EAP-TTLS, the server picks the method.
+/// The inner authentication is MSCHAPv2 either way.
+pub struct EnterpriseConfig {
+    pub identity: &'static str,
+    pub username: &'static str,
+    pub ca_cert: Option<&'static [u8]>,
 }
 
 impl WifiConfig {
     pub const fn new() -> Self {
+        let enterprise = if EAP_USERNAME.is_empty() {
+            None
+        } else {
+            Some(EnterpriseConfig {
+                identity: if EAP_IDENTITY.is_empty() {
+                    EAP_USERNAME
+                } else {
+                    EAP_IDENTITY
+                },
+                username: EAP_USERNAME,
+                ca_cert: if EAP_CA_CERT.is_empty() {
+                    None
+                } else {
+                    Some(EAP_CA_CERT)
+                },
+            })
+        };
         Self {
             ssid: SSID,
             password: PASSWORD,
+            enterprise,
         }
     }
 }
 
+/// `GATEWAY_IP` split at commas
+const GATEWAY_HOSTS: &[&str] = &const_str::split!(GATEWAY_IP, ",");
+
+#[derive(Clone, Copy)]
 pub struct GatewayConfig {
-    pub ip: Ipv4Addr,
+    /// IP addresses or host names, each with an optional `:port`, resolved again on every
+    /// reconnect. The first is the primary, the others are backups in order.
+    pub hosts: &'static [&'static str],
+    /// Port of the hosts without one
     pub port: u16,
     pub auth: [u8; 32],
+    /// Sent in the first handshake message so the gateway knows which PSK to expect
+    pub psk_name: &'static str,
 }
 
 impl GatewayConfig {
     pub const fn new() -> Self {
-        let ip = const_str::ip_addr!(v4, GATEWAY_IP);
+        if GATEWAY_IP.is_empty() {
+            panic!("GATEWAY_IP must be an IP address or a host name");
+        }
         let port = const_str::parse!(GATEWAY_PORT, u16);
         let auth_key = const_str::to_byte_array!(AUTH_KEY);
         Self {
-            ip,
+            hosts: GATEWAY_HOSTS,
             port,
             auth: auth_key,
+            psk_name: PSK_NAME,
+        }
+    }
+
+    /// Host and port of gateway `index`. IPv6 addresses with a port go in
+    /// brackets, like `[fd00::10]:9090`.
+    pub fn endpoint(&self, index: usize) -> (&'static str, u16) {
+        let host = self.hosts[index].trim();
+        if let Some(bracketed) = host.strip_prefix('[') {
+            return match bracketed.split_once("]:") {
+                Some((address, port)) => (address, port.parse().unwrap_or(self.port)),
+                None => (bracketed.trim_end_matches(']'), self.port),
+            };
+        }
+        match host.split_once(':') {
+            // A bare IPv6 address has several colons
+            Some((name, port)) if !port.contains(':') => (name, port.parse().unwrap_or(self.port)),
+            _ => (host, self.port),
         }
     }
 }
@@ -59,6 +251,9 @@ pub struct BoardConfig {
     pub ble_controller: Option<ExternalController<BleConnector<'static>, 20>>,
     pub rmt: Option<peripherals::RMT<'static>>,
     pub gpio48: Option<peripherals::GPIO48<'static>>,
+    pub gpio0: Option<peripherals::GPIO0<'static>>,
+    pub flash: Option<peripherals::FLASH<'static>>,
+    pub lpwr: Option<peripherals::LPWR<'static>>,
 }
 
 impl BoardConfig {
@@ -69,6 +264,9 @@ impl BoardConfig {
         ble_controller: ExternalController<BleConnector<'static>, 20>,
         rmt: peripherals::RMT<'static>,
         gpio48: peripherals::GPIO48<'static>,
+        gpio0: peripherals::GPIO0<'static>,
+        flash: peripherals::FLASH<'static>,
+        lpwr: peripherals::LPWR<'static>,
     ) -> Self {
         Self {
             rng,
@@ -77,6 +275,9 @@ impl BoardConfig {
             ble_controller: Some(ble_controller),
             rmt: Some(rmt),
             gpio48: Some(gpio48),
+            gpio0: Some(gpio0),
+            flash: Some(flash),
+            lpwr: Some(lpwr),
         }
     }
 }
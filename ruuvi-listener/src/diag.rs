@@ -0,0 +1,372 @@
+use crate::config::{DIAG_SSID, GatewayConfig, WifiConfig};
+use alloc::string::String as AllocString;
+use core::cell::RefCell;
+use core::fmt::Write as _;
+use core::net::{Ipv4Addr, SocketAddr};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use esp_hal::gpio::Input;
+use esp_radio::wifi::{AccessPointConfig, AuthMethod, WifiDevice};
+use heapless::{Deque, String, Vec};
+use log::{LevelFilter, Log, Metadata, Record};
+use ruuvi_schema::protocol::LogLevel;
+use static_cell::StaticCell;
+
+/// Consecutive Wi-Fi or gateway failures before the access point is raised
+const FAILURE_THRESHOLD: u32 = 10;
+pub const BUTTON_HOLD_SECS: u64 = 3;
+pub const AP_ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+const SCAN_ENTRIES: usize = 8;
+const LOG_LINES: usize = 32;
+const LOG_LINE_LEN: usize = 128;
+
+static STACK_RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+static STATUS: Mutex<CriticalSectionRawMutex, RefCell<Status>> =
+    Mutex::new(RefCell::new(Status::new()));
+static LOGS: Mutex<CriticalSectionRawMutex, RefCell<Deque<String<LOG_LINE_LEN>, LOG_LINES>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+// One signal per waiter, the connection task raises the AP and the server starts serving
+static AP_REQUEST: Signal<CriticalSectionRawMutex, Reason> = Signal::new();
+static SERVER_REQUEST: Signal<CriticalSectionRawMutex, Reason> = Signal::new();
+static LOGGER: DiagLogger = DiagLogger;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reason {
+    Button,
+    Failures,
+}
+
+struct ScanEntry {
+    mac: [u8; 6],
+    format: u8,
+    rssi: i8,
+    seen: Instant,
+}
+
+struct Status {
+    reason: Option<Reason>,
+    wifi_failures: u32,
+    gateway_failures: u32,
+    last_sent: Option<Instant>,
+    /// Gateway in use, by its position in `GATEWAY_IP`
+    endpoint: usize,
+    /// Address it was last found at, over mDNS or DNS
+    resolved: Option<SocketAddr>,
+    scans: Vec<ScanEntry, SCAN_ENTRIES>,
+    /// Noise static public key, for pinning the listener on the gateway
+    public_key: Option<[u8; 32]>,
+}
+
+impl Status {
+    const fn new() -> Self {
+        Self {
+            reason: None,
+            wifi_failures: 0,
+            gateway_failures: 0,
+            last_sent: None,
+            endpoint: 0,
+            resolved: None,
+            scans: Vec::new(),
+            public_key: None,
+        }
+    }
+}
+
+/// Raise the diagnostics access point, only the first trigger has an effect
+pub fn trigger(reason: Reason) {
+    let first = STATUS.lock(|status| {
+        let mut status = status.borrow_mut();
+        let first = status.reason.is_none();
+        status.reason.get_or_insert(reason);
+        first
+    });
+    if first {
+        log::warn!("Diagnostics mode requested: {reason:?}");
+        AP_REQUEST.signal(reason);
+        SERVER_REQUEST.signal(reason);
+    }
+}
+
+pub fn requested() -> Option<Reason> {
+    STATUS.lock(|status| status.borrow().reason)
+}
+
+pub async fn wait_requested() -> Reason {
+    AP_REQUEST.wait().await
+}
+
+pub fn wifi_ok() {
+    STATUS.lock(|status| status.borrow_mut().wifi_failures = 0);
+}
+
+pub fn wifi_failed() {
+    let failures = STATUS.lock(|status| {
+        let mut status = status.borrow_mut();
+        status.wifi_failures += 1;
+        status.wifi_failures
+    });
+    if failures >= FAILURE_THRESHOLD {
+        trigger(Reason::Failures);
+    }
+}
+
+pub fn gateway_ok() {
+    STATUS.lock(|status| {
+        let mut status = status.borrow_mut();
+        status.gateway_failures = 0;
+        status.last_sent = Some(Instant::now());
+    });
+}
+
+pub fn gateway_failed() {
+    let failures = STATUS.lock(|status| {
+        let mut status = status.borrow_mut();
+        status.gateway_failures += 1;
+        status.gateway_failures
+    });
+    if failures >= FAILURE_THRESHOLD {
+        trigger(Reason::Failures);
+    }
+}
+
+pub fn gateway_resolved(endpoint: usize, address: Option<SocketAddr>) {
+    STATUS.lock(|status| {
+        let mut status = status.borrow_mut();
+        status.endpoint = endpoint;
+        status.resolved = address;
+    });
+}
+
+pub fn identity(public_key: [u8; 32]) {
+    STATUS.lock(|status| status.borrow_mut().public_key = Some(public_key));
+}
+
+/// Remember the latest advertisement per tag, the oldest tag is forgotten when full
+pub fn record_scan(mac: [u8; 6], format: u8, rssi: i8) {
+    STATUS.lock(|status| {
+        let mut status = status.borrow_mut();
+        let scans = &mut status.scans;
+        let entry = ScanEntry {
+            mac,
+            format,
+            rssi,
+            seen: Instant::now(),
+        };
+        if let Some(existing) = scans.iter_mut().find(|s| s.mac == mac) {
+            *existing = entry;
+        } else if let Err(entry) = scans.push(entry)
+            && let Some(oldest) = scans.iter_mut().min_by_key(|s| s.seen)
+        {
+            *oldest = entry;
+        }
+    });
+}
+
+/// Prints like the esp-println logger and keeps the latest lines for the status page
+struct DiagLogger;
+
+impl Log for DiagLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        esp_println::println!("{} - {}", record.level(), record.args());
+
+        // Lines longer than the buffer are truncated
+        let mut line: String<LOG_LINE_LEN> = String::new();
+        let _ = write!(line, "{} - {}", record.level(), record.args());
+        LOGS.lock(|logs| {
+            let mut logs = logs.borrow_mut();
+            if logs.is_full() {
+                logs.pop_front();
+            }
+            let _ = logs.push_back(line);
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, the level is read from `ESP_LOG` at build time
+pub fn init_logger() {
+    let level = option_env!("ESP_LOG")
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    // Only fails when a logger is already installed
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// Level from the gateway, overrides `ESP_LOG` until reboot
+pub fn set_log_level(level: LogLevel) {
+    log::set_max_level(match level {
+        LogLevel::Off => LevelFilter::Off,
+        LogLevel::Error => LevelFilter::Error,
+        LogLevel::Warn => LevelFilter::Warn,
+        LogLevel::Info => LevelFilter::Info,
+        LogLevel::Debug => LevelFilter::Debug,
+        LogLevel::Trace => LevelFilter::Trace,
+    });
+}
+
+/// Access point raised next to the station, protected with the Wi-Fi password.
+/// `None` when the password is too short for WPA2, the page isn't served openly.
+pub fn ap_config(config: &WifiConfig) -> Option<AccessPointConfig> {
+    (config.password.len() >= 8).then(|| {
+        AccessPointConfig::default()
+            .with_ssid(DIAG_SSID.into())
+            .with_auth_method(AuthMethod::Wpa2Personal)
+            .with_password(config.password.into())
+    })
+}
+
+/// Network stack of the access point interface. There is no DHCP server outside
+/// the setup portal, clients need a static address in 192.168.4.0/24.
+pub fn init_stack(
+    ap_device: WifiDevice<'static>,
+    seed: u64,
+) -> (Stack<'static>, Runner<'static, WifiDevice<'static>>) {
+    let config = embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(AP_ADDRESS, 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    });
+    let stack_resources = STACK_RESOURCES.init(StackResources::new());
+    embassy_net::new(ap_device, config, stack_resources, seed)
+}
+
+/// Long press of the BOOT button raises the access point
+#[embassy_executor::task]
+pub async fn button(mut input: Input<'static>) {
+    loop {
+        input.wait_for_low().await;
+        // A short press or a bump doesn't count
+        if input
+            .wait_for_high()
+            .with_timeout(Duration::from_secs(BUTTON_HOLD_SECS))
+            .await
+            .is_err()
+        {
+            trigger(Reason::Button);
+            return;
+        }
+    }
+}
+
+fn render(
+    reason: Reason,
+    sta_stack: Stack<'static>,
+    gateway_config: &GatewayConfig,
+) -> AllocString {
+    let now = Instant::now();
+    let mut page = AllocString::new();
+    let _ = writeln!(page, "Ruuvi listener diagnostics");
+    let _ = writeln!(page, "Reason: {reason:?}");
+    let _ = writeln!(page, "Uptime: {} s\n", now.as_secs());
+
+    let address = sta_stack.config_v4().map(|config| config.address);
+    STATUS.lock(|status| {
+        let status = status.borrow();
+        let _ = writeln!(
+            page,
+            "Wi-Fi: {:?}, address {address:?}, {} failed connects",
+            esp_radio::wifi::sta_state(),
+            status.wifi_failures
+        );
+        let (host, port) = gateway_config.endpoint(status.endpoint);
+        let _ = write!(
+            page,
+            "Gateway {} of {}: {host}:{port}",
+            status.endpoint + 1,
+            gateway_config.hosts.len()
+        );
+        let _ = match status.resolved {
+            Some(address) => write!(page, " at {address}, "),
+            None => write!(page, " unresolved, "),
+        };
+        let _ = write!(page, "{} consecutive failures, ", status.gateway_failures);
+        let _ = match status.last_sent {
+            Some(sent) => writeln!(page, "last send {} s ago", (now - sent).as_secs()),
+            None => writeln!(page, "nothing sent yet"),
+        };
+        if let Some(public_key) = status.public_key {
+            let _ = write!(page, "Public key: ");
+            for byte in public_key {
+                let _ = write!(page, "{byte:02x}");
+            }
+            let _ = writeln!(page);
+        }
+        let _ = writeln!(page);
+
+        let _ = writeln!(page, "Last scans:");
+        for scan in &status.scans {
+            let _ = writeln!(
+                page,
+                "  {:02X?} format {:#04X} rssi {} dBm, {} s ago",
+                scan.mac,
+                scan.format,
+                scan.rssi,
+                (now - scan.seen).as_secs()
+            );
+        }
+    });
+
+    let _ = writeln!(page, "\nLogs:");
+    LOGS.lock(|logs| {
+        for line in logs.borrow().iter() {
+            let _ = writeln!(page, "  {line}");
+        }
+    });
+    page
+}
+
+/// Serves the status page on the access point once diagnostics are requested
+#[embassy_executor::task]
+pub async fn serve(
+    ap_stack: Stack<'static>,
+    sta_stack: Stack<'static>,
+    gateway_config: GatewayConfig,
+) {
+    let reason = SERVER_REQUEST.wait().await;
+    log::warn!("Diagnostics page at http://{AP_ADDRESS}/ on the {DIAG_SSID} network");
+
+    let mut socket_rx_buffer = [0u8; 512];
+    let mut socket_tx_buffer = [0u8; 2048];
+    let mut request = [0u8; 512];
+    loop {
+        let mut socket = TcpSocket::new(ap_stack, &mut socket_rx_buffer, &mut socket_tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(80).await {
+            log::warn!("Diagnostics accept error: {e:?}");
+            Timer::after(Duration::from_millis(500)).await;
+            continue;
+        }
+
+        // Every path serves the same page, the request itself doesn't matter
+        let _ = socket.read(&mut request).await;
+        let page = render(reason, sta_stack, &gateway_config);
+        let head = b"HTTP/1.0 200 OK\r\n\
+            Content-Type: text/plain; charset=utf-8\r\n\
+            Connection: close\r\n\r\n";
+        if let Err(e) = socket.write_all(head).await {
+            log::warn!("Diagnostics write error: {e:?}");
+            continue;
+        }
+        if let Err(e) = socket.write_all(page.as_bytes()).await {
+            log::warn!("Diagnostics write error: {e:?}");
+            continue;
+        }
+        // Let the page drain before the socket is dropped
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
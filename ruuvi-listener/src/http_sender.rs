@@ -0,0 +1,299 @@
+use crate::coex;
+use crate::config::{GatewayConfig, LED_QUEUE_DEPTH, PACKET_QUEUE_DEPTH};
+use crate::diag;
+use crate::led::LedEvent;
+use crate::metrics;
+use crate::net;
+use crate::schedule::Schedule;
+use crate::signature::sign;
+use crate::watchdog::{self, Task};
+use anyhow::anyhow;
+use core::fmt::Write as _;
+use core::net::SocketAddr;
+use embassy_net::Stack;
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use esp_hal::rng::Rng;
+use ruuvi_schema::RuuviRaw;
+
+const PATH: &str = "/api/ruuvi";
+const BASE_BACKOFF_MS: u64 = 500;
+const TIMEOUT_SECS: u64 = 20;
+const MAX_BACKOFF_SECS: u64 = 30;
+/// Checks in with the watchdog this often while no readings arrive
+const IDLE_BEAT: Duration = Duration::from_secs(30);
+
+struct Response {
+    status: u16,
+    retry_after_secs: Option<u64>,
+    // False when the gateway closes the connection or the body framing isn't supported
+    reusable: bool,
+}
+
+async fn post(
+    socket: &mut TcpSocket<'_>,
+    gateway_config: &GatewayConfig,
+    server: SocketAddr,
+    body: &[u8],
+) -> Result<(), anyhow::Error> {
+    let signature = sign(&gateway_config.auth, body);
+    let signature =
+        core::str::from_utf8(&signature).map_err(|e| anyhow!("Invalid signature: {e}"))?;
+
+    let mut head: heapless::String<256> = heapless::String::new();
+    write!(
+        head,
+        "POST {PATH} HTTP/1.1\r\n\
+        Host: {server}\r\n\
+        Authorization: HMAC-SHA256 {signature}\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {}\r\n\
+        Connection: keep-alive\r\n\r\n",
+        body.len()
+    )
+    .map_err(|_| anyhow!("Request head too large"))?;
+
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| anyhow!("Failed to write request head: {e:?}"))?;
+    socket
+        .write_all(body)
+        .await
+        .map_err(|e| anyhow!("Failed to write request body: {e:?}"))?;
+    socket
+        .flush()
+        .await
+        .map_err(|e| anyhow!("Failed to flush the socket: {e:?}"))
+}
+
+async fn read_response(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut [u8; 1024],
+) -> Result<Response, anyhow::Error> {
+    // Read until the end of the headers
+    let mut filled = 0;
+    let header_end = loop {
+        if filled == buf.len() {
+            return Err(anyhow!("Response headers too large"));
+        }
+        let n = socket
+            .read(&mut buf[filled..])
+            .await
+            .map_err(|e| anyhow!("Failed to read response: {e:?}"))?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed by the gateway"));
+        }
+        filled += n;
+        if let Some(pos) = buf[..filled].windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = core::str::from_utf8(&buf[..header_end])
+        .map_err(|e| anyhow!("Invalid response head: {e}"))?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("Invalid status line"))?;
+
+    let mut content_length = 0usize;
+    let mut retry_after_secs = None;
+    let mut reusable = true;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().unwrap_or(0);
+        } else if name.eq_ignore_ascii_case("retry-after") {
+            // Only the delay-seconds form, HTTP dates would need a clock
+            retry_after_secs = value.parse().ok();
+        } else if name.eq_ignore_ascii_case("connection") && value.eq_ignore_ascii_case("close") {
+            reusable = false;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // Chunked bodies aren't parsed, reconnect instead of reading a desynced stream
+            reusable = false;
+        }
+    }
+
+    // Drain the body so the next response starts at a clean boundary
+    let mut remaining = content_length.saturating_sub(filled - header_end);
+    while reusable && remaining > 0 {
+        let len = remaining.min(buf.len());
+        let n = socket
+            .read(&mut buf[..len])
+            .await
+            .map_err(|e| anyhow!("Failed to drain response body: {e:?}"))?;
+        if n == 0 {
+            reusable = false;
+        }
+        remaining -= n;
+    }
+
+    Ok(Response {
+        status,
+        retry_after_secs,
+        reusable,
+    })
+}
+
+/// Sends readings as JSON to the gateway's `POST /api/ruuvi` endpoint.
+/// The body is authenticated with an HMAC-SHA256 of the auth key, it isn't encrypted.
+#[embassy_executor::task]
+pub async fn run(
+    stack: Stack<'static>,
+    receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    gateway_config: GatewayConfig,
+    rng: Rng,
+    led_sender: Sender<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
+) {
+    // Buffers
+    let mut socket_rx_buffer = [0u8; 2048];
+    let mut socket_tx_buffer = [0u8; 2048];
+    let mut rx_buffer = [0u8; 1024];
+    let mut json_buf = [0u8; 768];
+
+    let mut backoff_ms = BASE_BACKOFF_MS;
+    let mut failover = net::Failover::new(&gateway_config);
+    // Packet to retry after the gateway asked us to slow down
+    let mut pending: Option<RuuviRaw> = None;
+    let mut schedule = Schedule::new(rng);
+
+    loop {
+        watchdog::beat(Task::Sender);
+        // The advertised port is the Noise one, only the address is taken
+        let endpoint = failover.current();
+        let server = match net::resolve_gateway(stack, &gateway_config, endpoint).await {
+            Ok(server) => SocketAddr::new(server.ip(), gateway_config.endpoint(endpoint).1),
+            Err(e) => {
+                log::warn!("{e}; backoff {backoff_ms}ms");
+                diag::gateway_failed();
+                failover.failed();
+                Timer::after(Duration::from_millis(backoff_ms)).await;
+                backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+                continue;
+            }
+        };
+
+        // Create TCP socket
+        let mut socket = TcpSocket::new(stack, &mut socket_rx_buffer, &mut socket_tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+
+        // Connect
+        log::info!("Trying to connect to: {server}");
+        match socket.connect((server.ip(), server.port())).await {
+            Ok(_) => {
+                log::info!("TCP connected");
+                failover.connected();
+            }
+            Err(e) => {
+                log::warn!("Connect error: {e:?}; backoff {backoff_ms}ms");
+                diag::gateway_failed();
+                failover.failed();
+                Timer::after(Duration::from_millis(backoff_ms)).await;
+                backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+                continue;
+            }
+        }
+
+        'sending: loop {
+            // Waiting for readings is progress as well, with no tags in range
+            while pending.is_none() && receiver.is_empty() {
+                watchdog::beat(Task::Sender);
+                let _ = receiver.ready_to_receive().with_timeout(IDLE_BEAT).await;
+            }
+            watchdog::beat(Task::Sender);
+            let pkt = match pending.take() {
+                Some(pkt) => pkt,
+                None => schedule.next(&receiver).await.0,
+            };
+
+            // No time sync over HTTP, the gateway timestamps readings on arrival
+            let len = match serde_json_core::to_slice(&pkt, &mut json_buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    log::error!("Failed to serialize RuuviRaw to JSON: {e}");
+                    continue;
+                }
+            };
+
+            let started = Instant::now();
+            if let Err(e) = post(&mut socket, &gateway_config, server, &json_buf[..len]).await {
+                coex::send_failed();
+                log::error!("Failed to send the request: {e}");
+                pending = Some(pkt);
+                break 'sending;
+            }
+            coex::sent(started.elapsed());
+            let response = match read_response(&mut socket, &mut rx_buffer).await {
+                Ok(response) => response,
+                Err(e) => {
+                    log::error!("Failed to read the response: {e}");
+                    diag::gateway_failed();
+                    // The gateway may not have received it, resend on the next connection
+                    pending = Some(pkt);
+                    break 'sending;
+                }
+            };
+
+            match response.status {
+                200..=299 => {
+                    if let Err(err) = led_sender.try_send(LedEvent::TcpOk) {
+                        log::error!("Failed to send LedEvent to the channel! {err:?}");
+                    }
+                    // After successful send, reset
+                    backoff_ms = BASE_BACKOFF_MS;
+                    diag::gateway_ok();
+                    metrics::frame_sent();
+                }
+                401 | 403 => {
+                    // The key won't fix itself, stop hammering the gateway
+                    log::error!(
+                        "Gateway rejected the auth key ({}), pausing for {MAX_BACKOFF_SECS}s",
+                        response.status
+                    );
+                    backoff_ms = MAX_BACKOFF_SECS * 1000;
+                    break 'sending;
+                }
+                429 | 503 => {
+                    let wait_ms = response
+                        .retry_after_secs
+                        .map(|secs| secs.min(MAX_BACKOFF_SECS) * 1000)
+                        .unwrap_or(backoff_ms);
+                    log::warn!(
+                        "Gateway busy ({}), retrying in {wait_ms}ms",
+                        response.status
+                    );
+                    pending = Some(pkt);
+                    Timer::after(Duration::from_millis(wait_ms)).await;
+                    backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+                }
+                status => log::warn!("Gateway responded {status}, dropping the reading"),
+            }
+
+            if !response.reusable {
+                break 'sending;
+            }
+            if failover
+                .retry_primary_at()
+                .is_some_and(|at| Instant::now() >= at)
+            {
+                failover.retry_primary();
+                break 'sending;
+            }
+        }
+
+        socket.close();
+        log::info!("Reconnecting after backoff {backoff_ms}ms");
+        metrics::reconnect();
+        Timer::after(Duration::from_millis(backoff_ms)).await;
+        backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::board::with_partition;
+use anyhow::anyhow;
+use core::fmt;
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+use snow::Keypair;
+
+/// Label of the data partition holding the static key, see `partitions.csv`
+const PARTITION_LABEL: &str = "identity";
+/// Leads a stored key, erased flash reads as 0xFF
+const MAGIC: [u8; 4] = *b"RNK1";
+const RECORD_LEN: usize = MAGIC.len() + 64;
+
+/// Noise static key of the listener, kept across reboots so the gateway can pin it
+pub struct StaticKey {
+    pub private: [u8; 32],
+    pub public: [u8; 32],
+}
+
+/// Hex encodes bytes for the logs
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+/// Static key stored in the identity partition. On first boot one is made with
+/// `generate` and stored, its public key logged for provisioning the gateway.
+/// Without a usable partition the generated key only lasts until the next reboot.
+pub fn load(
+    flash: &mut FlashStorage<'static>,
+    generate: impl FnOnce() -> Result<Keypair, snow::Error>,
+) -> Result<StaticKey, anyhow::Error> {
+    match read(flash) {
+        Ok(Some(key)) => {
+            log::info!("Listener public key {}", Hex(&key.public));
+            return Ok(key);
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("Failed to read the static key: {e}"),
+    }
+
+    let keypair = generate().map_err(|e| anyhow!("Failed to generate keypair: {e}"))?;
+    let key = StaticKey {
+        private: to_key(&keypair.private)?,
+        public: to_key(&keypair.public)?,
+    };
+    match write(flash, &key) {
+        Ok(()) => log::warn!(
+            "Generated a new static key, pin it in the gateway's [[noise.listeners]] as \
+            public_key = \"{}\"",
+            Hex(&key.public)
+        ),
+        Err(e) => log::error!(
+            "Failed to store the static key, it changes on every boot: {e}. Public key {}",
+            Hex(&key.public)
+        ),
+    }
+    Ok(key)
+}
+
+fn read(flash: &mut FlashStorage<'static>) -> Result<Option<StaticKey>, anyhow::Error> {
+    let mut record = [0u8; RECORD_LEN];
+    with_partition(flash, PARTITION_LABEL, |region| region.read(0, &mut record))?;
+    if record[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    let (private, public) = record[MAGIC.len()..].split_at(32);
+    Ok(Some(StaticKey {
+        private: to_key(private)?,
+        public: to_key(public)?,
+    }))
+}
+
+fn to_key(bytes: &[u8]) -> Result<[u8; 32], anyhow::Error> {
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("Expected a 32 byte key, got {}", bytes.len()))
+}
+
+fn write(flash: &mut FlashStorage<'static>, key: &StaticKey) -> Result<(), anyhow::Error> {
+    let mut record = [0u8; RECORD_LEN];
+    record[..MAGIC.len()].copy_from_slice(&MAGIC);
+    record[MAGIC.len()..][..32].copy_from_slice(&key.private);
+    record[MAGIC.len() + 32..].copy_from_slice(&key.public);
+    with_partition(flash, PARTITION_LABEL, |region| region.write(0, &record))
+}
@@ -1,3 +1,5 @@
+use crate::config::LED_QUEUE_DEPTH;
+use core::sync::atomic::{AtomicBool, Ordering};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::channel::Receiver;
 use embassy_time::{Duration, WithTimeout};
@@ -5,6 +7,8 @@ use esp_hal_smartled::SmartLedsAdapterAsync;
 use smart_leds::colors::{BLACK, BLUE, GREEN, RED};
 use smart_leds::{SmartLedsWriteAsync, brightness};
 
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
 #[derive(Debug)]
 pub enum LedEvent {
     BleOk,
@@ -12,10 +16,15 @@ pub enum LedEvent {
     TcpOk,
 }
 
+/// Events still arrive while disabled, they just don't light the led
+pub fn enable(on: bool) {
+    ENABLED.store(on, Ordering::Relaxed);
+}
+
 #[embassy_executor::task]
 pub async fn task(
     mut led: SmartLedsAdapterAsync<'static, 25>,
-    receiver: Receiver<'static, NoopRawMutex, LedEvent, 16>,
+    receiver: Receiver<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
 ) {
     let level = 1;
     let mut event = None;
@@ -30,6 +39,10 @@ pub async fn task(
             event = Some(v);
         }
         log::debug!("Received event: {event:?}");
+        if !ENABLED.load(Ordering::Relaxed) {
+            event = None;
+            continue;
+        }
 
         // Match event variant to a correct color
         let data: smart_leds::RGB<u8> = match event {
@@ -7,15 +7,52 @@
 )]
 
 mod board;
+mod coex;
 mod config;
+mod diag;
 mod led;
+mod metrics;
 mod net;
+mod portal;
+mod provision;
 mod scanner;
-mod schema;
+mod schedule;
+mod settings;
+mod slaac;
+mod watchdog;
+
+#[cfg(any(
+    all(feature = "transport-noise", feature = "transport-http"),
+    all(feature = "transport-noise", feature = "transport-mqtt"),
+    all(feature = "transport-http", feature = "transport-mqtt"),
+))]
+compile_error!(
+    "Enable only one of the `transport-noise`, `transport-http` and `transport-mqtt` features"
+);
+#[cfg(not(any(
+    feature = "transport-noise",
+    feature = "transport-http",
+    feature = "transport-mqtt"
+)))]
+compile_error!(
+    "Enable one of the `transport-noise`, `transport-http` or `transport-mqtt` features"
+);
+
+#[cfg(feature = "transport-http")]
+mod http_sender;
+#[cfg(feature = "transport-noise")]
+mod identity;
+#[cfg(feature = "transport-mqtt")]
+mod mqtt_sender;
+#[cfg(feature = "transport-noise")]
+mod ota;
+#[cfg(feature = "transport-noise")]
 mod sender;
+#[cfg(any(feature = "transport-http", feature = "transport-mqtt"))]
+mod signature;
 
 extern crate alloc;
-use crate::config::{BoardConfig, GatewayConfig, WifiConfig};
+use crate::config::{BoardConfig, GatewayConfig, LED_QUEUE_DEPTH, PACKET_QUEUE_DEPTH, WifiConfig};
 use crate::led::LedEvent;
 use crate::net::acquire_address;
 use embassy_executor::Spawner;
@@ -30,9 +67,11 @@ use static_cell::StaticCell;
 // For more information see: <https://docs.espressif.com/projects/esp-idf/en/stable/esp32/api-reference/system/app_image_format.html#application-description>
 esp_bootloader_esp_idf::esp_app_desc!();
 
-static CHANNEL: StaticCell<Channel<NoopRawMutex, (RuuviRaw, Instant), 16>> = StaticCell::new();
+static CHANNEL: StaticCell<Channel<NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>> =
+    StaticCell::new();
 static BOARD_CONFIG: StaticCell<BoardConfig> = StaticCell::new();
-static LED_CHANNEL: StaticCell<Channel<NoopRawMutex, LedEvent, 16>> = StaticCell::new();
+static LED_CHANNEL: StaticCell<Channel<NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>> =
+    StaticCell::new();
 
 // Constant configs
 const WIFI_CONFIG: WifiConfig = WifiConfig::new();
@@ -40,27 +79,63 @@ const GATEWAY_CONFIG: GatewayConfig = GatewayConfig::new();
 
 #[esp_rtos::main]
 async fn main(spawner: Spawner) {
-    esp_println::logger::init_logger_from_env();
+    diag::init_logger();
+    log::info!("Reset reason: {:?}", watchdog::last_reset());
 
     let peripherals = board::init_peripherals();
     let board_config = BOARD_CONFIG.init(board::init(peripherals));
+    let flash = board::init_flash(board_config.flash.take().unwrap());
+    // Provisioned settings replace the build time ones
+    let stored = settings::load(flash);
+    let gateway_config = stored.gateway(GATEWAY_CONFIG);
+
+    let wifi_config = stored.wifi(WIFI_CONFIG);
+    let mut button = board::init_button(board_config.gpio0.take().unwrap());
+    let wifi_controller = board_config
+        .wifi_controller
+        .take()
+        .expect("Wifi controller taken already");
 
-    let (net_stack, runner) = net::init_network_stack(board_config);
+    // The access point serves diagnostics on request, or the setup portal instead of
+    // everything else
+    let (net_stack, runner, ap_device) = net::init_network_stack(board_config);
+    let seed = (board_config.rng.random() as u64) << 32 | board_config.rng.random() as u64;
+    let (ap_stack, ap_runner) = diag::init_stack(ap_device, seed);
     spawner
-        .spawn(net::connection(
-            board_config
-                .wifi_controller
-                .take()
-                .expect("Wifi controller taken already"),
-            WIFI_CONFIG,
-        ))
+        .spawn(net::run_stack(ap_runner))
+        .expect("Failed to spawn access point network runner task!");
+    if portal::wanted(&mut button, &wifi_config).await {
+        spawner
+            .spawn(portal::run(wifi_controller, ap_stack, flash, stored))
+            .expect("Failed to spawn setup portal task!");
+        return;
+    }
+
+    spawner
+        .spawn(net::connection(wifi_controller, wifi_config))
         .expect("Failed to spawn network connection task!");
     spawner
         .spawn(net::run_stack(runner))
         .expect("Failed to spawn network runner task!");
+    spawner
+        .spawn(diag::serve(ap_stack, net_stack, gateway_config))
+        .expect("Failed to spawn diagnostics server task!");
+    spawner
+        .spawn(diag::button(button))
+        .expect("Failed to spawn diagnostics button task!");
 
+    if config::IPV6_SLAAC {
+        spawner
+            .spawn(slaac::run(net_stack))
+            .expect("Failed to spawn SLAAC task!");
+    }
     acquire_address(net_stack).await;
 
+    #[cfg(feature = "metrics")]
+    spawner
+        .spawn(metrics::serve(net_stack))
+        .expect("Failed to spawn metrics server task!");
+
     // Initialize a bounded channel of LED events
     let led_channel = &*LED_CHANNEL.init(Channel::new());
     let led_sender = led_channel.sender();
@@ -88,18 +163,45 @@ async fn main(spawner: Spawner) {
                 .take()
                 .expect("BLE controller taken already"),
             sender,
+            receiver,
             led_sender,
+            flash,
+            stored,
         ))
         .expect("Failed to spawn BLE scanner!");
 
-    // Run TCP packet sender task
+    // Run packet sender task of the selected transport, firmware updates need the Noise session
+    #[cfg(feature = "transport-noise")]
+    let sender_task = sender::run(
+        net_stack,
+        receiver,
+        gateway_config,
+        board_config.rng,
+        led_sender2,
+        flash,
+    );
+    #[cfg(feature = "transport-http")]
+    let sender_task = http_sender::run(
+        net_stack,
+        receiver,
+        gateway_config,
+        board_config.rng,
+        led_sender2,
+    );
+    #[cfg(feature = "transport-mqtt")]
+    let sender_task = mqtt_sender::run(
+        net_stack,
+        receiver,
+        gateway_config,
+        board_config.rng,
+        led_sender2,
+    );
     spawner
-        .spawn(sender::run(
-            net_stack,
-            receiver,
-            GATEWAY_CONFIG,
-            board_config.rng,
-            led_sender2,
-        ))
-        .expect("Failed to HTTP sender logger!");
+        .spawn(sender_task)
+        .expect("Failed to spawn packet sender!");
+
+    let rwdt = board::init_watchdog(board_config.lpwr.take().unwrap());
+    spawner
+        .spawn(watchdog::supervise(rwdt))
+        .expect("Failed to spawn watchdog task!");
 }
@@ -0,0 +1,192 @@
+#[cfg(feature = "metrics")]
+use crate::coex;
+use crate::watchdog;
+use core::sync::atomic::{AtomicI8, AtomicU32, Ordering};
+use ruuvi_schema::protocol::Telemetry;
+#[cfg(feature = "metrics")]
+use {
+    alloc::string::String,
+    core::fmt::Write as _,
+    embassy_net::Stack,
+    embassy_net::tcp::TcpSocket,
+    embassy_time::{Duration, Instant, Timer},
+    embedded_io_async::{Read, Write},
+};
+
+/// Port of the Prometheus endpoint
+#[cfg(feature = "metrics")]
+const PORT: u16 = 9100;
+
+static ADVERTS: AtomicU32 = AtomicU32::new(0);
+static PARSE_FAILURES: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static QUEUE_OVERFLOWS: AtomicU32 = AtomicU32::new(0);
+static FRAMES_SENT: AtomicU32 = AtomicU32::new(0);
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+/// Latest signal of the access point, [`NO_RSSI`] while disconnected
+static WIFI_RSSI: AtomicI8 = AtomicI8::new(NO_RSSI);
+const NO_RSSI: i8 = i8::MIN;
+
+/// A Ruuvi advertisement was received
+pub fn advert_seen() {
+    ADVERTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn parse_failed() {
+    PARSE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The packet queue ran full and its `dropped` readings were discarded
+pub fn queue_overflow(dropped: usize) {
+    QUEUE_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+    DROPPED.fetch_add(dropped as u32, Ordering::Relaxed);
+}
+
+/// An unacknowledged reading was pushed out of the retry buffer
+pub fn reading_dropped() {
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Signal of the access point in dBm, `None` once disconnected
+pub fn wifi_rssi(rssi: Option<i8>) {
+    WIFI_RSSI.store(rssi.unwrap_or(NO_RSSI), Ordering::Relaxed);
+}
+
+fn current_rssi() -> Option<i8> {
+    Some(WIFI_RSSI.load(Ordering::Relaxed)).filter(|&rssi| rssi != NO_RSSI)
+}
+
+/// A reading was handed to the gateway
+pub fn frame_sent() {
+    FRAMES_SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn reconnect() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counters reported to the gateway
+pub fn telemetry() -> Telemetry {
+    Telemetry {
+        uptime_secs: embassy_time::Instant::now().as_secs(),
+        reset_reason: watchdog::last_reset(),
+        heap_free: esp_alloc::HEAP.free() as u32,
+        wifi_rssi: current_rssi(),
+        adverts: ADVERTS.load(Ordering::Relaxed),
+        parse_failures: PARSE_FAILURES.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        queue_overflows: QUEUE_OVERFLOWS.load(Ordering::Relaxed),
+        frames_sent: FRAMES_SENT.load(Ordering::Relaxed),
+        reconnects: RECONNECTS.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn render() -> String {
+    let counters = [
+        (
+            "ruuvi_listener_adverts_total",
+            "Ruuvi advertisements received",
+            &ADVERTS,
+        ),
+        (
+            "ruuvi_listener_parse_failures_total",
+            "Advertisements that failed to parse",
+            &PARSE_FAILURES,
+        ),
+        (
+            "ruuvi_listener_dropped_total",
+            "Readings lost to a full queue",
+            &DROPPED,
+        ),
+        (
+            "ruuvi_listener_queue_overflows_total",
+            "Times the packet queue ran full",
+            &QUEUE_OVERFLOWS,
+        ),
+        (
+            "ruuvi_listener_frames_sent_total",
+            "Readings sent to the gateway",
+            &FRAMES_SENT,
+        ),
+        (
+            "ruuvi_listener_reconnects_total",
+            "Reconnects to the gateway",
+            &RECONNECTS,
+        ),
+    ];
+    let gauges = [
+        (
+            "ruuvi_listener_heap_free_bytes",
+            "Free heap",
+            esp_alloc::HEAP.free() as u64,
+        ),
+        (
+            "ruuvi_listener_uptime_seconds",
+            "Time since boot",
+            Instant::now().as_secs(),
+        ),
+        (
+            "ruuvi_listener_scan_backoff",
+            "Times the scan window is halved for Wi-Fi",
+            u64::from(coex::backoff()),
+        ),
+    ];
+
+    let mut body = String::new();
+    if let Some(rssi) = current_rssi() {
+        let name = "ruuvi_listener_wifi_rssi_dbm";
+        let _ = writeln!(body, "# HELP {name} Signal of the Wi-Fi access point");
+        let _ = writeln!(body, "# TYPE {name} gauge");
+        let _ = writeln!(body, "{name} {rssi}");
+    }
+    for (name, help, counter) in counters {
+        let _ = writeln!(body, "# HELP {name} {help}");
+        let _ = writeln!(body, "# TYPE {name} counter");
+        let _ = writeln!(body, "{name} {}", counter.load(Ordering::Relaxed));
+    }
+    for (name, help, value) in gauges {
+        let _ = writeln!(body, "# HELP {name} {help}");
+        let _ = writeln!(body, "# TYPE {name} gauge");
+        let _ = writeln!(body, "{name} {value}");
+    }
+    body
+}
+
+/// Serves the counters in the Prometheus text format on the station network
+#[cfg(feature = "metrics")]
+#[embassy_executor::task]
+pub async fn serve(stack: Stack<'static>) {
+    log::info!("Metrics served on port {PORT}");
+
+    let mut socket_rx_buffer = [0u8; 512];
+    let mut socket_tx_buffer = [0u8; 1024];
+    let mut request = [0u8; 512];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut socket_rx_buffer, &mut socket_tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(PORT).await {
+            log::warn!("Metrics accept error: {e:?}");
+            Timer::after(Duration::from_millis(500)).await;
+            continue;
+        }
+
+        // Every path serves the metrics, the request itself doesn't matter
+        let _ = socket.read(&mut request).await;
+        let body = render();
+        let head = b"HTTP/1.0 200 OK\r\n\
+            Content-Type: text/plain; version=0.0.4\r\n\
+            Connection: close\r\n\r\n";
+        if let Err(e) = socket.write_all(head).await {
+            log::warn!("Metrics write error: {e:?}");
+            continue;
+        }
+        if let Err(e) = socket.write_all(body.as_bytes()).await {
+            log::warn!("Metrics write error: {e:?}");
+            continue;
+        }
+        // Let the response drain before the socket is dropped
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
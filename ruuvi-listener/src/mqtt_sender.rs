@@ -0,0 +1,323 @@
+//! MQTT 3.1.1 client of the `transport-mqtt` feature, just what publishing
+//! needs. Readings go out with QoS 1 as the JSON of the HTTP transport, after
+//! a `HMAC-SHA256 <hex>` line signing it, to
+//! `<MQTT_TOPIC_PREFIX>/listeners/<address>/readings` where the gateway
+//! subscribes.
+
+use crate::coex;
+use crate::config::{
+    GatewayConfig, LED_QUEUE_DEPTH, MQTT_PASSWORD, MQTT_TOPIC_PREFIX, MQTT_USERNAME,
+    PACKET_QUEUE_DEPTH,
+};
+use crate::diag;
+use crate::led::LedEvent;
+use crate::metrics;
+use crate::net;
+use crate::schedule::Schedule;
+use crate::signature::sign;
+use crate::watchdog::{self, Task};
+use anyhow::anyhow;
+use core::fmt::Write as _;
+use embassy_net::Stack;
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use esp_hal::rng::Rng;
+use ruuvi_schema::RuuviRaw;
+
+const BASE_BACKOFF_MS: u64 = 500;
+const TIMEOUT_SECS: u64 = 20;
+const MAX_BACKOFF_SECS: u64 = 30;
+/// Checks in with the watchdog this often while no readings arrive
+const IDLE_BEAT: Duration = Duration::from_secs(30);
+/// Longest silence the broker is asked to accept, a ping goes out after half of it
+const KEEP_ALIVE_SECS: u16 = 60;
+
+// Fixed header bytes of the packets used
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH_QOS1: u8 = 0x32;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+
+type Packet = heapless::Vec<u8, 1024>;
+
+fn put(packet: &mut Packet, bytes: &[u8]) -> Result<(), anyhow::Error> {
+    packet
+        .extend_from_slice(bytes)
+        .map_err(|_| anyhow!("MQTT packet too large"))
+}
+
+/// A string or binary field, prefixed with its length
+fn put_field(packet: &mut Packet, bytes: &[u8]) -> Result<(), anyhow::Error> {
+    put(packet, &(bytes.len() as u16).to_be_bytes())?;
+    put(packet, bytes)
+}
+
+async fn send(socket: &mut TcpSocket<'_>, kind: u8, body: &[u8]) -> Result<(), anyhow::Error> {
+    // The remaining length takes 7 bits per byte, the high bit marks a following one
+    let mut header: heapless::Vec<u8, 5> = heapless::Vec::new();
+    let _ = header.push(kind);
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        let _ = header.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+
+    socket
+        .write_all(&header)
+        .await
+        .map_err(|e| anyhow!("Failed to write packet header: {e:?}"))?;
+    socket
+        .write_all(body)
+        .await
+        .map_err(|e| anyhow!("Failed to write packet: {e:?}"))?;
+    socket
+        .flush()
+        .await
+        .map_err(|e| anyhow!("Failed to flush the socket: {e:?}"))
+}
+
+async fn receive<const N: usize>(socket: &mut TcpSocket<'_>) -> Result<[u8; N], anyhow::Error> {
+    let mut packet = [0u8; N];
+    socket
+        .read_exact(&mut packet)
+        .await
+        .map_err(|e| anyhow!("Failed to read from the broker: {e:?}"))?;
+    Ok(packet)
+}
+
+/// Opens a clean session, the broker assigning the client identifier.
+/// Returns the CONNACK return code, 0 being accepted.
+async fn connect(socket: &mut TcpSocket<'_>) -> Result<u8, anyhow::Error> {
+    let mut flags = 0x02;
+    if !MQTT_USERNAME.is_empty() {
+        flags |= 0x80;
+        if !MQTT_PASSWORD.is_empty() {
+            flags |= 0x40;
+        }
+    }
+    let mut packet = Packet::new();
+    put_field(&mut packet, b"MQTT")?;
+    // Protocol level 4 is MQTT 3.1.1
+    put(&mut packet, &[4, flags])?;
+    put(&mut packet, &KEEP_ALIVE_SECS.to_be_bytes())?;
+    put_field(&mut packet, b"")?;
+    if flags & 0x80 != 0 {
+        put_field(&mut packet, MQTT_USERNAME.as_bytes())?;
+    }
+    if flags & 0x40 != 0 {
+        put_field(&mut packet, MQTT_PASSWORD.as_bytes())?;
+    }
+    send(socket, CONNECT, &packet).await?;
+
+    match receive::<4>(socket).await? {
+        [CONNACK, 2, _, code] => Ok(code),
+        [kind, ..] => Err(anyhow!("Expected CONNACK, got packet {kind:#04x}")),
+    }
+}
+
+/// Publishes `body` with QoS 1 and waits for the broker to acknowledge it
+async fn publish(
+    socket: &mut TcpSocket<'_>,
+    topic: &str,
+    packet_id: u16,
+    key: &[u8; 32],
+    body: &[u8],
+) -> Result<(), anyhow::Error> {
+    let mut packet = Packet::new();
+    put_field(&mut packet, topic.as_bytes())?;
+    put(&mut packet, &packet_id.to_be_bytes())?;
+    put(&mut packet, b"HMAC-SHA256 ")?;
+    put(&mut packet, &sign(key, body))?;
+    put(&mut packet, b"\n")?;
+    put(&mut packet, body)?;
+    send(socket, PUBLISH_QOS1, &packet).await?;
+
+    match receive::<4>(socket).await? {
+        [PUBACK, 2, high, low] if u16::from_be_bytes([high, low]) == packet_id => Ok(()),
+        [PUBACK, ..] => Err(anyhow!("PUBACK of another packet")),
+        [kind, ..] => Err(anyhow!("Expected PUBACK, got packet {kind:#04x}")),
+    }
+}
+
+async fn ping(socket: &mut TcpSocket<'_>) -> Result<(), anyhow::Error> {
+    send(socket, PINGREQ, &[]).await?;
+    match receive::<2>(socket).await? {
+        [PINGRESP, 0] => Ok(()),
+        [kind, _] => Err(anyhow!("Expected PINGRESP, got packet {kind:#04x}")),
+    }
+}
+
+/// Publishes readings to the MQTT broker at the gateway addresses.
+/// Each is authenticated with an HMAC-SHA256 of the auth key, it isn't encrypted.
+#[embassy_executor::task]
+pub async fn run(
+    stack: Stack<'static>,
+    receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    gateway_config: GatewayConfig,
+    rng: Rng,
+    led_sender: Sender<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
+) {
+    // Buffers
+    let mut socket_rx_buffer = [0u8; 2048];
+    let mut socket_tx_buffer = [0u8; 2048];
+    let mut json_buf = [0u8; 768];
+
+    let mut backoff_ms = BASE_BACKOFF_MS;
+    let mut failover = net::Failover::new(&gateway_config);
+    // Packet the broker didn't acknowledge, resent on the next connection
+    let mut pending: Option<RuuviRaw> = None;
+    let mut schedule = Schedule::new(rng);
+    let mut packet_id: u16 = 0;
+
+    loop {
+        watchdog::beat(Task::Sender);
+        let endpoint = failover.current();
+        let server = match net::resolve_broker(stack, &gateway_config, endpoint).await {
+            Ok(server) => server,
+            Err(e) => {
+                log::warn!("{e}; backoff {backoff_ms}ms");
+                diag::gateway_failed();
+                failover.failed();
+                Timer::after(Duration::from_millis(backoff_ms)).await;
+                backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+                continue;
+            }
+        };
+
+        // Create TCP socket
+        let mut socket = TcpSocket::new(stack, &mut socket_rx_buffer, &mut socket_tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+
+        // Connect
+        log::info!("Trying to connect to: {server}");
+        if let Err(e) = socket.connect((server.ip(), server.port())).await {
+            log::warn!("Connect error: {e:?}; backoff {backoff_ms}ms");
+            diag::gateway_failed();
+            failover.failed();
+            Timer::after(Duration::from_millis(backoff_ms)).await;
+            backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+            continue;
+        }
+
+        // Listeners are named after their address, like over the other transports
+        let mut topic: heapless::String<96> = heapless::String::new();
+        let named = match socket.local_endpoint() {
+            Some(local) => write!(
+                topic,
+                "{MQTT_TOPIC_PREFIX}/listeners/{}/readings",
+                local.addr
+            )
+            .is_ok(),
+            None => false,
+        };
+
+        let connected = named
+            && match connect(&mut socket).await {
+                Ok(0) => {
+                    log::info!("MQTT broker connected, publishing to {topic}");
+                    failover.connected();
+                    true
+                }
+                Ok(code) => {
+                    // Credentials or protocol won't fix themselves, stop hammering the broker
+                    log::error!(
+                        "Broker refused the connection ({code}), pausing for {MAX_BACKOFF_SECS}s"
+                    );
+                    backoff_ms = MAX_BACKOFF_SECS * 1000;
+                    false
+                }
+                Err(e) => {
+                    log::warn!("MQTT connect failed: {e}");
+                    diag::gateway_failed();
+                    failover.failed();
+                    false
+                }
+            };
+
+        let mut last_sent = Instant::now();
+        'sending: while connected {
+            // Waiting for readings is progress as well, with no tags in range
+            while pending.is_none() && receiver.is_empty() {
+                watchdog::beat(Task::Sender);
+                let _ = receiver.ready_to_receive().with_timeout(IDLE_BEAT).await;
+                if receiver.is_empty()
+                    && last_sent.elapsed() >= Duration::from_secs(KEEP_ALIVE_SECS as u64 / 2)
+                {
+                    if let Err(e) = ping(&mut socket).await {
+                        log::error!("Broker didn't answer the ping: {e}");
+                        diag::gateway_failed();
+                        break 'sending;
+                    }
+                    last_sent = Instant::now();
+                }
+            }
+            watchdog::beat(Task::Sender);
+            let pkt = match pending.take() {
+                Some(pkt) => pkt,
+                None => schedule.next(&receiver).await.0,
+            };
+
+            // No time sync over MQTT, the gateway timestamps readings on arrival
+            let len = match serde_json_core::to_slice(&pkt, &mut json_buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    log::error!("Failed to serialize RuuviRaw to JSON: {e}");
+                    continue;
+                }
+            };
+
+            // 0 isn't a valid packet identifier
+            packet_id = packet_id.wrapping_add(1).max(1);
+            let started = Instant::now();
+            let published = publish(
+                &mut socket,
+                &topic,
+                packet_id,
+                &gateway_config.auth,
+                &json_buf[..len],
+            )
+            .await;
+            if let Err(e) = published {
+                coex::send_failed();
+                log::error!("Failed to publish: {e}");
+                diag::gateway_failed();
+                // The broker may not have received it, resend on the next connection
+                pending = Some(pkt);
+                break 'sending;
+            }
+            coex::sent(started.elapsed());
+            last_sent = Instant::now();
+
+            if let Err(err) = led_sender.try_send(LedEvent::TcpOk) {
+                log::error!("Failed to send LedEvent to the channel! {err:?}");
+            }
+            // After successful send, reset
+            backoff_ms = BASE_BACKOFF_MS;
+            diag::gateway_ok();
+            metrics::frame_sent();
+
+            if failover
+                .retry_primary_at()
+                .is_some_and(|at| Instant::now() >= at)
+            {
+                failover.retry_primary();
+                break 'sending;
+            }
+        }
+
+        socket.close();
+        log::info!("Reconnecting after backoff {backoff_ms}ms");
+        metrics::reconnect();
+        Timer::after(Duration::from_millis(backoff_ms)).await;
+        backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+    }
+}
@@ -1,45 +1,147 @@
-use crate::config::{BoardConfig, WifiConfig};
-use embassy_net::{Runner, Stack, StackResources};
-use embassy_time::{Duration, Timer};
+use crate::config::{
+    BoardConfig, FAILOVER_AFTER, GatewayConfig, MDNS_TIMEOUT_MS, PRIMARY_RETRY_SECS, STATIC_DNS,
+    STATIC_GATEWAY, STATIC_IP, WifiConfig,
+};
+use crate::{diag, metrics};
+use anyhow::anyhow;
+use core::net::{IpAddr, Ipv4Addr, SocketAddr};
+use embassy_futures::select::{Either3, select3};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Cidr, Runner, Stack, StackResources, StaticConfigV4};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
 use esp_backtrace as _;
 use esp_radio::wifi::{
-    ClientConfig, ModeConfig, ScanConfig, WifiController, WifiDevice, WifiEvent, WifiStaState,
+    ClientConfig, EapClientConfig, ModeConfig, ScanConfig, TtlsPhase2Method, WifiController,
+    WifiDevice, WifiEvent, WifiStaState,
 };
 use static_cell::StaticCell;
 
-static STACK_RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+/// DNS-SD service the gateway advertises itself as
+pub const GATEWAY_SERVICE: &str = "_ruuvi-gw._tcp.local";
+/// mDNS queries and answers both go to this group
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+pub const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+pub const CLASS_IN: u16 = 1;
+
+/// The access point's signal is sampled this often while connected
+const RSSI_SAMPLE_SECS: u64 = 30;
+
+/// DHCP, DNS, the sender, the metrics endpoint, mDNS discovery and SLAAC
+static STACK_RESOURCES: StaticCell<StackResources<6>> = StaticCell::new();
 
+/// Returns the station stack and the access point device for diagnostics
 pub fn init_network_stack(
     board_config: &mut BoardConfig,
-) -> (Stack<'static>, Runner<'static, WifiDevice<'static>>) {
+) -> (
+    Stack<'static>,
+    Runner<'static, WifiDevice<'static>>,
+    WifiDevice<'static>,
+) {
     log::info!("Starting to initialize network stack.");
-    let wifi_interface = board_config.interfaces.take().expect("No interface!").sta;
-    let config = embassy_net::Config::dhcpv4(Default::default());
+    let interfaces = board_config.interfaces.take().expect("No interface!");
+    let wifi_interface = interfaces.sta;
+    let config = ip_config();
     let seed = (board_config.rng.random() as u64) << 32 | board_config.rng.random() as u64;
     let stack_resources = STACK_RESOURCES.init(StackResources::new());
-    let stack_n_runner = embassy_net::new(wifi_interface, config, stack_resources, seed);
+    let (stack, runner) = embassy_net::new(wifi_interface, config, stack_resources, seed);
     log::info!("Network stack initialized!");
-    stack_n_runner
+    (stack, runner, interfaces.ap)
+}
+
+/// DHCP, or the static address of `STATIC_IP` with its router and DNS servers
+fn ip_config() -> embassy_net::Config {
+    if STATIC_IP.is_empty() {
+        return embassy_net::Config::dhcpv4(Default::default());
+    }
+    let (address, prefix) = STATIC_IP
+        .split_once('/')
+        .expect("STATIC_IP needs a prefix length, like 192.168.1.50/24");
+    let address = address.parse().expect("STATIC_IP isn't an IPv4 address");
+    let prefix = prefix
+        .parse()
+        .expect("STATIC_IP has an invalid prefix length");
+    let mut config = StaticConfigV4 {
+        address: Ipv4Cidr::new(address, prefix),
+        gateway: None,
+        dns_servers: Default::default(),
+    };
+    if !STATIC_GATEWAY.is_empty() {
+        let gateway = STATIC_GATEWAY.parse();
+        config.gateway = Some(gateway.expect("STATIC_GATEWAY isn't an IPv4 address"));
+    }
+    for server in STATIC_DNS
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+    {
+        let server = server.parse().expect("STATIC_DNS has an invalid address");
+        config
+            .dns_servers
+            .push(server)
+            .expect("STATIC_DNS takes up to 3 servers");
+    }
+    log::info!("Using the static address {STATIC_IP}");
+    embassy_net::Config::ipv4_static(config)
 }
 
 #[embassy_executor::task]
 pub async fn connection(mut controller: WifiController<'static>, config: WifiConfig) {
     log::info!("Start connection task");
     log::info!("Device capabilities: {:?}", controller.capabilities());
+    let mut ap_enabled = false;
     loop {
         if esp_radio::wifi::sta_state() == WifiStaState::Connected {
-            // Wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            Timer::after(Duration::from_millis(5000)).await
+            metrics::wifi_rssi(controller.rssi().ok().map(|rssi| rssi as i8));
+            // Wait until we're no longer connected or diagnostics are requested,
+            // sampling the signal for telemetry meanwhile
+            let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+            let sample = Timer::after(Duration::from_secs(RSSI_SAMPLE_SECS));
+            match select3(disconnected, diag::wait_requested(), sample).await {
+                Either3::First(_) => {
+                    metrics::wifi_rssi(None);
+                    Timer::after(Duration::from_millis(5000)).await
+                }
+                Either3::Second(_) => (),
+                Either3::Third(_) => continue,
+            }
+        }
+        if !ap_enabled && diag::requested().is_some() {
+            // Restart the radio with the diagnostics access point next to the station
+            if matches!(controller.is_started(), Ok(true))
+                && let Err(e) = controller.stop_async().await
+            {
+                log::error!("Failed to stop wifi: {e:?}");
+            }
+            ap_enabled = true;
         }
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = ModeConfig::Client(
-                ClientConfig::default()
+            let mode_config = if let Some(eap_config) = eap_config(&config) {
+                // The radio can't raise an access point next to an enterprise station
+                if ap_enabled {
+                    log::warn!("No diagnostics access point on an enterprise network");
+                }
+                ModeConfig::EapClient(eap_config)
+            } else {
+                let client_config = ClientConfig::default()
                     .with_ssid(config.ssid.into())
-                    .with_password(config.password.into()),
-            );
+                    .with_password(config.password.into());
+                match ap_enabled.then(|| diag::ap_config(&config)) {
+                    Some(Some(ap_config)) => ModeConfig::ApSta(client_config, ap_config),
+                    Some(None) => {
+                        log::warn!(
+                            "No diagnostics access point, the Wi-Fi password is under 8 characters"
+                        );
+                        ModeConfig::Client(client_config)
+                    }
+                    None => ModeConfig::Client(client_config),
+                }
+            };
 
-            controller.set_config(&client_config).unwrap();
+            controller.set_config(&mode_config).unwrap();
             log::info!("Starting wifi");
             controller.start_async().await.unwrap();
             log::info!("Wifi started!");
@@ -54,18 +156,45 @@ pub async fn connection(mut controller: WifiController<'static>, config: WifiCon
                 log::info!("{ap:?}");
             }
         }
+        if esp_radio::wifi::sta_state() == WifiStaState::Connected {
+            continue;
+        }
         log::info!("About to connect...");
         match controller.connect_async().await {
-            Ok(_) => log::info!("Wifi connected!"),
+            Ok(_) => {
+                log::info!("Wifi connected!");
+                diag::wifi_ok();
+            }
             Err(e) => {
                 log::info!("Failed to connect to wifi: {e:?}");
+                diag::wifi_failed();
                 Timer::after(Duration::from_millis(5000)).await
             }
         }
     }
 }
 
-#[embassy_executor::task]
+/// WPA2-Enterprise station, when the network has one. The server certificate's
+/// validity period isn't checked, the clock is only set once the gateway is reached.
+fn eap_config(config: &WifiConfig) -> Option<EapClientConfig> {
+    let enterprise = config.enterprise.as_ref()?;
+    let eap_config = EapClientConfig::default()
+        .with_ssid(config.ssid.into())
+        .with_identity(enterprise.identity.into())
+        .with_username(enterprise.username.into())
+        .with_password(config.password.into())
+        .with_ttls_phase2_method(TtlsPhase2Method::Mschapv2);
+    Some(match enterprise.ca_cert {
+        Some(ca_cert) => eap_config.with_ca_cert(ca_cert),
+        None => {
+            log::warn!("No EAP_CA_CERT, the RADIUS server isn't verified");
+            eap_config
+        }
+    })
+}
+
+// Station and diagnostics access point stacks
+#[embassy_executor::task(pool_size = 2)]
 pub async fn run_stack(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await
 }
@@ -88,3 +217,305 @@ pub async fn acquire_address(stack: Stack<'static>) {
         Timer::after(Duration::from_millis(500)).await;
     }
 }
+
+/// Address of gateway `endpoint`, looked up again on every call to follow it
+/// around. For the primary the one advertised over mDNS wins. Hosts are
+/// resolved over DNS unless they're addresses already.
+pub async fn resolve_gateway(
+    stack: Stack<'static>,
+    config: &GatewayConfig,
+    endpoint: usize,
+) -> Result<SocketAddr, anyhow::Error> {
+    let (host, port) = config.endpoint(endpoint);
+    let discovered = match endpoint {
+        0 => discover_gateway(stack).await,
+        _ => None,
+    };
+    let resolved = match discovered {
+        Some((address, port)) => Ok(SocketAddr::new(address.into(), port)),
+        None => resolve(stack, host, port).await,
+    };
+    diag::gateway_resolved(endpoint, resolved.as_ref().ok().copied());
+    resolved
+}
+
+/// Address of MQTT broker `endpoint`, the gateway's hosts being brokers with
+/// the MQTT transport. Brokers aren't advertised over mDNS.
+#[cfg(feature = "transport-mqtt")]
+pub async fn resolve_broker(
+    stack: Stack<'static>,
+    config: &GatewayConfig,
+    endpoint: usize,
+) -> Result<SocketAddr, anyhow::Error> {
+    let (host, port) = config.endpoint(endpoint);
+    let resolved = resolve(stack, host, port).await;
+    diag::gateway_resolved(endpoint, resolved.as_ref().ok().copied());
+    resolved
+}
+
+async fn resolve(
+    stack: Stack<'static>,
+    host: &str,
+    port: u16,
+) -> Result<SocketAddr, anyhow::Error> {
+    match host.parse::<IpAddr>() {
+        Ok(address) => Ok(SocketAddr::new(address, port)),
+        Err(_) => resolve_host(stack, host)
+            .await
+            .map(|address| SocketAddr::new(address, port)),
+    }
+}
+
+/// Which of the configured gateways to connect to. Moves on to the next after
+/// [`FAILOVER_AFTER`] failed attempts in a row, and back to the primary once
+/// a session with a backup has lasted [`PRIMARY_RETRY_SECS`].
+pub struct Failover {
+    endpoints: usize,
+    current: usize,
+    failures: u32,
+    connected: Instant,
+}
+
+impl Failover {
+    pub fn new(config: &GatewayConfig) -> Self {
+        Self {
+            endpoints: config.hosts.len(),
+            current: 0,
+            failures: 0,
+            connected: Instant::now(),
+        }
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// A failed connect or handshake
+    pub fn failed(&mut self) {
+        self.failures += 1;
+        if self.failures >= FAILOVER_AFTER && self.endpoints > 1 {
+            self.current = (self.current + 1) % self.endpoints;
+            self.failures = 0;
+            log::warn!("Failing over to gateway {}", self.current + 1);
+        }
+    }
+
+    pub fn connected(&mut self) {
+        self.failures = 0;
+        self.connected = Instant::now();
+    }
+
+    /// When the session with a backup should be closed, never for the primary
+    pub fn retry_primary_at(&self) -> Option<Instant> {
+        (self.current != 0).then(|| self.connected + Duration::from_secs(PRIMARY_RETRY_SECS))
+    }
+
+    /// Completes at [`Self::retry_primary_at`]
+    pub async fn retry_primary_due(&self) {
+        match self.retry_primary_at() {
+            Some(at) => Timer::at(at).await,
+            None => core::future::pending().await,
+        }
+    }
+
+    /// Tries the primary next, a single failure moves on again
+    pub fn retry_primary(&mut self) {
+        log::info!("Trying the primary gateway again");
+        self.current = 0;
+        self.failures = FAILOVER_AFTER - 1;
+    }
+}
+
+/// Queries the configured DNS servers, an IPv6 address is only taken when
+/// there's no IPv4 one
+async fn resolve_host(stack: Stack<'static>, host: &str) -> Result<IpAddr, anyhow::Error> {
+    let mut error = None;
+    for kind in [DnsQueryType::A, DnsQueryType::Aaaa] {
+        match stack.dns_query(host, kind).await {
+            Ok(addresses) => {
+                if let Some(address) = addresses.first() {
+                    let address = IpAddr::from(*address);
+                    log::info!("Resolved {host} to {address}");
+                    return Ok(address);
+                }
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(match error {
+        Some(e) => anyhow!("Failed to resolve {host}: {e:?}"),
+        None => anyhow!("{host} has no address"),
+    })
+}
+
+/// Gateway advertised over mDNS, `None` when none answers in time
+async fn discover_gateway(stack: Stack<'static>) -> Option<(Ipv4Addr, u16)> {
+    if MDNS_TIMEOUT_MS == 0 {
+        return None;
+    }
+    match query_gateway(stack)
+        .with_timeout(Duration::from_millis(MDNS_TIMEOUT_MS))
+        .await
+    {
+        Ok(Ok(gateway)) => {
+            log::info!("Found {GATEWAY_SERVICE} at {}:{}", gateway.0, gateway.1);
+            Some(gateway)
+        }
+        Ok(Err(e)) => {
+            log::warn!("mDNS discovery failed: {e}");
+            None
+        }
+        Err(_) => {
+            log::info!("No answer for {GATEWAY_SERVICE}, using the configured gateway");
+            None
+        }
+    }
+}
+
+/// Asks the group for the gateway service and waits for an answer carrying
+/// its SRV and A records, other mDNS traffic is skipped
+async fn query_gateway(stack: Stack<'static>) -> Result<(Ipv4Addr, u16), anyhow::Error> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 64];
+    let mut packet = [0u8; 512];
+
+    // Joining again is a no-op, answers are multicast like the queries
+    stack
+        .join_multicast_group(MDNS_GROUP)
+        .map_err(|e| anyhow!("Failed to join the mDNS group: {e:?}"))?;
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket
+        .bind(MDNS_PORT)
+        .map_err(|e| anyhow!("Failed to bind the mDNS port: {e:?}"))?;
+
+    let len = write_query(&mut packet);
+    socket
+        .send_to(&packet[..len], (MDNS_GROUP, MDNS_PORT))
+        .await
+        .map_err(|e| anyhow!("Failed to send the mDNS query: {e:?}"))?;
+    loop {
+        // Packets larger than the buffer are truncated, none of them is ours
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        if let Some(gateway) = parse_answer(&packet[..len]) {
+            return Ok(gateway);
+        }
+    }
+}
+
+/// A PTR query for [`GATEWAY_SERVICE`], returns its length
+fn write_query(buf: &mut [u8]) -> usize {
+    // ID 0 and no flags, a single question
+    buf[..12].copy_from_slice(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    let mut len = 12;
+    for label in GATEWAY_SERVICE.split('.') {
+        buf[len] = label.len() as u8;
+        buf[len + 1..len + 1 + label.len()].copy_from_slice(label.as_bytes());
+        len += 1 + label.len();
+    }
+    buf[len] = 0;
+    buf[len + 1..len + 3].copy_from_slice(&TYPE_PTR.to_be_bytes());
+    buf[len + 3..len + 5].copy_from_slice(&CLASS_IN.to_be_bytes());
+    len + 5
+}
+
+/// Lowercase, dot separated domain name
+pub type Name = heapless::Vec<u8, 255>;
+
+/// Address and port of the first gateway instance in an mDNS response. The
+/// responder sends the SRV record of the instance and the A record of its
+/// host along with the PTR answer.
+fn parse_answer(packet: &[u8]) -> Option<(Ipv4Addr, u16)> {
+    let flags = be16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        // A query, maybe our own
+        return None;
+    }
+    let questions = be16(packet, 4)?;
+    let records = (6..12)
+        .step_by(2)
+        .map(|offset| be16(packet, offset).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset, &mut Name::new())? + 4;
+    }
+
+    let mut target: Option<(Name, u16)> = None;
+    let mut addresses = heapless::Vec::<(Name, Ipv4Addr), 4>::new();
+    for _ in 0..records {
+        let mut name = Name::new();
+        offset = read_name(packet, offset, &mut name)?;
+        let kind = be16(packet, offset)?;
+        let data = offset + 10;
+        let length = usize::from(be16(packet, offset + 8)?);
+        let rdata = packet.get(data..data + length)?;
+        match kind {
+            TYPE_SRV if target.is_none() && is_gateway_instance(&name) => {
+                let mut host = Name::new();
+                read_name(packet, data + 6, &mut host)?;
+                target = Some((host, be16(packet, data + 4)?));
+            }
+            TYPE_A if length == 4 => {
+                let address = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                let _ = addresses.push((name, address));
+            }
+            _ => (),
+        }
+        offset = data + length;
+    }
+    let (host, port) = target?;
+    addresses
+        .iter()
+        .find(|(name, _)| *name == host)
+        .map(|(_, address)| (*address, port))
+}
+
+/// `<instance>.<service>`, the owner of the service's SRV records
+fn is_gateway_instance(name: &[u8]) -> bool {
+    name.strip_suffix(GATEWAY_SERVICE.as_bytes())
+        .is_some_and(|instance| instance.len() > 1 && instance.ends_with(b"."))
+}
+
+/// Reads the possibly compressed name at `offset` into `name`, returns the
+/// offset following it
+pub fn read_name(packet: &[u8], mut offset: usize, name: &mut Name) -> Option<usize> {
+    let mut end = None;
+    // Limits pointer loops in malformed packets
+    for _ in 0..64 {
+        let len = *packet.get(offset)?;
+        match len {
+            0 => return Some(end.unwrap_or(offset + 1)),
+            len if len & 0xC0 == 0xC0 => {
+                end.get_or_insert(offset + 2);
+                offset = usize::from(be16(packet, offset)? & 0x3FFF);
+            }
+            len if len & 0xC0 == 0 => {
+                let label = packet.get(offset + 1..offset + 1 + usize::from(len))?;
+                if !name.is_empty() {
+                    name.push(b'.').ok()?;
+                }
+                for byte in label {
+                    name.push(byte.to_ascii_lowercase()).ok()?;
+                }
+                offset += 1 + usize::from(len);
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+pub fn be16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
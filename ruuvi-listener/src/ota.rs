@@ -0,0 +1,247 @@
+use crate::board::Flash;
+use crate::config::OTA_PUBLIC_KEY;
+use ed25519_dalek::{Signature, VerifyingKey};
+use embedded_storage::Storage;
+use esp_bootloader_esp_idf::ota::OtaImageState;
+use esp_bootloader_esp_idf::ota_updater::OtaUpdater;
+use esp_bootloader_esp_idf::partitions::{Error, PARTITION_TABLE_MAX_LEN};
+use esp_storage::FlashStorage;
+use ruuvi_schema::ota::{Downlink, FailReason, FirmwareInfo, Uplink, version};
+use sha2::{Digest, Sha256};
+
+/// Version of the running image, offers of this or older versions are ignored
+pub const FIRMWARE_VERSION: u32 = version(
+    const_str::parse!(env!("CARGO_PKG_VERSION_MAJOR"), u8),
+    const_str::parse!(env!("CARGO_PKG_VERSION_MINOR"), u8),
+    const_str::parse!(env!("CARGO_PKG_VERSION_PATCH"), u8),
+);
+
+struct Download {
+    info: FirmwareInfo,
+    offset: u32,
+    hasher: Sha256,
+}
+
+/// Writes images offered by the gateway to the inactive OTA partition.
+/// Survives reconnects, so an interrupted download resumes where it stopped.
+pub struct Ota {
+    flash: &'static Flash,
+    buffer: [u8; PARTITION_TABLE_MAX_LEN],
+    download: Option<Download>,
+    rejected: Option<u32>,
+    checked: bool,
+}
+
+impl Ota {
+    pub fn new(flash: &'static Flash) -> Self {
+        Self {
+            flash,
+            buffer: [0; PARTITION_TABLE_MAX_LEN],
+            download: None,
+            rejected: None,
+            checked: false,
+        }
+    }
+
+    /// First message of every session
+    pub fn hello(&mut self) -> Uplink {
+        Uplink::Hello {
+            version: FIRMWARE_VERSION,
+            confirmed: self.confirm(),
+        }
+    }
+
+    /// Mark a freshly installed image valid once it has reached the gateway,
+    /// otherwise the bootloader rolls it back on the next reset
+    fn confirm(&mut self) -> bool {
+        if self.checked {
+            return false;
+        }
+        self.checked = true;
+
+        let buffer = &mut self.buffer;
+        self.flash.with(|flash| {
+            let mut updater = match OtaUpdater::new(flash, buffer) {
+                Ok(updater) => updater,
+                Err(e) => {
+                    log::warn!("No OTA partitions, firmware updates unavailable: {e:?}");
+                    return false;
+                }
+            };
+            match updater.current_ota_state() {
+                Ok(OtaImageState::New | OtaImageState::PendingVerify) => {
+                    match updater.set_current_ota_state(OtaImageState::Valid) {
+                        Ok(()) => {
+                            log::info!("Firmware {FIRMWARE_VERSION:#08x} confirmed");
+                            true
+                        }
+                        Err(e) => {
+                            log::error!("Failed to confirm the firmware: {e:?}");
+                            false
+                        }
+                    }
+                }
+                Ok(_) => false,
+                Err(e) => {
+                    log::error!("Failed to read the OTA state: {e:?}");
+                    false
+                }
+            }
+        })
+    }
+
+    /// Handle a gateway message, returns the answer if there is one
+    pub fn handle(&mut self, downlink: Downlink) -> Option<Uplink> {
+        match downlink {
+            Downlink::Offer(info) => self.offer(info),
+            Downlink::Chunk {
+                version,
+                offset,
+                data,
+            } => self.chunk(version, offset, data),
+        }
+    }
+
+    fn offer(&mut self, info: FirmwareInfo) -> Option<Uplink> {
+        if info.version <= FIRMWARE_VERSION || self.rejected == Some(info.version) {
+            return None;
+        }
+
+        // Same image as before the connection dropped
+        if let Some(download) = &self.download
+            && download.info == info
+        {
+            log::info!("Resuming firmware download at {}", download.offset);
+            return Some(Uplink::Request {
+                version: info.version,
+                offset: download.offset,
+            });
+        }
+
+        match self.capacity() {
+            Ok(capacity) if info.size <= capacity => {}
+            Ok(capacity) => {
+                log::error!("Firmware of {} bytes doesn't fit {capacity}", info.size);
+                return self.fail(info.version, FailReason::TooLarge);
+            }
+            Err(e) => {
+                log::error!("Failed to find the OTA partition: {e:?}");
+                return self.fail(info.version, FailReason::Flash);
+            }
+        }
+
+        log::info!(
+            "Downloading firmware {:#08x}, {} bytes",
+            info.version,
+            info.size
+        );
+        let request = Uplink::Request {
+            version: info.version,
+            offset: 0,
+        };
+        self.download = Some(Download {
+            info,
+            offset: 0,
+            hasher: Sha256::new(),
+        });
+        Some(request)
+    }
+
+    fn chunk(&mut self, version: u32, offset: u32, data: &[u8]) -> Option<Uplink> {
+        let download = self
+            .download
+            .as_mut()
+            .filter(|download| download.info.version == version)?;
+        // Stale or duplicate chunk, ask again from where the image ends
+        if offset != download.offset || data.is_empty() {
+            return Some(Uplink::Request {
+                version,
+                offset: download.offset,
+            });
+        }
+
+        let buffer = &mut self.buffer;
+        if let Err(e) = self.flash.with(|flash| write(flash, buffer, offset, data)) {
+            log::error!("Failed to write firmware at {offset}: {e:?}");
+            return self.fail(version, FailReason::Flash);
+        }
+        download.hasher.update(data);
+        download.offset += data.len() as u32;
+
+        if download.offset < download.info.size {
+            Some(Uplink::Request {
+                version,
+                offset: download.offset,
+            })
+        } else {
+            self.finish()
+        }
+    }
+
+    fn finish(&mut self) -> Option<Uplink> {
+        let download = self.download.take()?;
+        let version = download.info.version;
+
+        let digest: [u8; 32] = download.hasher.finalize().into();
+        if digest != download.info.sha256 {
+            log::error!("Firmware checksum mismatch");
+            return self.fail(version, FailReason::Checksum);
+        }
+        if !verify(&download.info) {
+            log::error!("Firmware signature doesn't match OTA_PUBLIC_KEY");
+            return self.fail(version, FailReason::Signature);
+        }
+
+        let buffer = &mut self.buffer;
+        let activated = self.flash.with(|flash| {
+            OtaUpdater::new(flash, buffer).and_then(|mut updater| {
+                updater.activate_next_partition()?;
+                updater.set_current_ota_state(OtaImageState::New)
+            })
+        });
+        if let Err(e) = activated {
+            log::error!("Failed to activate the new firmware: {e:?}");
+            return self.fail(version, FailReason::Flash);
+        }
+        log::info!("Firmware {version:#08x} installed");
+        Some(Uplink::Complete { version })
+    }
+
+    fn fail(&mut self, version: u32, reason: FailReason) -> Option<Uplink> {
+        self.download = None;
+        self.rejected = Some(version);
+        Some(Uplink::Failed { version, reason })
+    }
+
+    fn capacity(&mut self) -> Result<u32, Error> {
+        let buffer = &mut self.buffer;
+        self.flash.with(|flash| {
+            let mut updater = OtaUpdater::new(flash, buffer)?;
+            let (partition, _) = updater.next_partition()?;
+            Ok(partition.partition_size() as u32)
+        })
+    }
+}
+
+fn write(
+    flash: &mut FlashStorage<'static>,
+    buffer: &mut [u8; PARTITION_TABLE_MAX_LEN],
+    offset: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    let mut updater = OtaUpdater::new(flash, buffer)?;
+    let (mut partition, _) = updater.next_partition()?;
+    partition.write(offset, data)
+}
+
+/// The image is signed by its digest
+fn verify(info: &FirmwareInfo) -> bool {
+    let Ok(key) = VerifyingKey::from_bytes(&OTA_PUBLIC_KEY) else {
+        return false;
+    };
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&info.signature[0]);
+    signature[32..].copy_from_slice(&info.signature[1]);
+    key.verify_strict(&info.sha256, &Signature::from_bytes(&signature))
+        .is_ok()
+}
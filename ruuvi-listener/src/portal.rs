@@ -0,0 +1,512 @@
+//! Setup portal for onboarding. Without a Wi-Fi network, or with the BOOT button
+//! held right after boot, the listener raises the [`PORTAL_SSID`] access point
+//! instead of scanning. It hands out addresses, answers every DNS query with itself
+//! so phones open the form as a captive portal, and stores the submitted network
+//! and gateway before rebooting into normal operation. The form takes
+//! [`PROVISIONING_CODE`] along, the portal isn't raised without one.
+
+use crate::board::Flash;
+use crate::config::{GATEWAY_IP, PORTAL_SSID, PROVISIONING_CODE, WifiConfig};
+use crate::diag::{AP_ADDRESS, BUTTON_HOLD_SECS};
+use crate::net::{self, CLASS_IN, Name, TYPE_A};
+use crate::provision;
+use crate::settings::{self, Settings};
+use alloc::string::String as AllocString;
+use core::fmt::Write as _;
+use core::net::Ipv4Addr;
+use embassy_futures::join::join3;
+use embassy_net::Stack;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_time::{Duration, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use esp_hal::gpio::Input;
+use esp_radio::wifi::{AccessPointConfig, AuthMethod, ModeConfig, WifiController};
+use heapless::{String, Vec};
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+/// Fixed part of a DHCP message, the options follow
+const DHCP_HEADER_LEN: usize = 240;
+/// Messages are padded to the minimum BOOTP length
+const DHCP_MIN_LEN: usize = 300;
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+const LEASE_SECS: u32 = 60 * 60;
+/// Clients given an address, from 192.168.4.2 on. Past that the oldest lease is reused.
+const CLIENTS: usize = 4;
+const DNS_PORT: u16 = 53;
+const DNS_TTL_SECS: u32 = 60;
+/// Lets the confirmation page reach the browser before the reboot
+const REBOOT_DELAY: Duration = Duration::from_secs(2);
+/// Wrong codes after which submissions are refused until the next boot
+const MAX_CODE_FAILURES: u32 = 5;
+
+const FORM_HEAD: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width\"><title>Ruuvi listener setup</title>\
+</head><body><h1>Ruuvi listener setup</h1>";
+const FORM_TAIL: &str = "</body></html>";
+
+/// Whether to onboard instead of running. Waits while the BOOT button is held.
+/// Never without a valid `PROVISIONING_CODE`.
+pub async fn wanted(button: &mut Input<'static>, wifi: &WifiConfig) -> bool {
+    if provision::passkey().is_none() {
+        if wifi.ssid.is_empty() {
+            log::error!(
+                "No Wi-Fi network configured and no PROVISIONING_CODE for the setup portal"
+            );
+        }
+        return false;
+    }
+    if wifi.ssid.is_empty() {
+        log::warn!("No Wi-Fi network configured");
+        return true;
+    }
+    // Only once the firmware runs, held through a reset the chip stays in the ROM loader
+    button.is_low()
+        && button
+            .wait_for_high()
+            .with_timeout(Duration::from_secs(BUTTON_HOLD_SECS))
+            .await
+            .is_err()
+}
+
+/// Raises the access point and serves the form until settings are submitted
+#[embassy_executor::task]
+pub async fn run(
+    mut controller: WifiController<'static>,
+    stack: Stack<'static>,
+    flash: &'static Flash,
+    stored: &'static Settings,
+) {
+    // Open for phones to join, the form asks for the code instead
+    let ap_config = AccessPointConfig::default()
+        .with_ssid(PORTAL_SSID.into())
+        .with_auth_method(AuthMethod::None);
+    if let Err(e) = controller.set_config(&ModeConfig::AccessPoint(ap_config)) {
+        log::error!("Failed to configure the setup access point: {e:?}");
+        return;
+    }
+    if let Err(e) = controller.start_async().await {
+        log::error!("Failed to start the setup access point: {e:?}");
+        return;
+    }
+    log::warn!("Setup portal at http://{AP_ADDRESS}/ on the {PORTAL_SSID} network");
+
+    join3(
+        serve_form(stack, flash, stored),
+        serve_dhcp(stack),
+        serve_dns(stack),
+    )
+    .await;
+}
+
+async fn serve_form(stack: Stack<'static>, flash: &Flash, stored: &Settings) {
+    let mut socket_rx_buffer = [0u8; 1024];
+    let mut socket_tx_buffer = [0u8; 2048];
+    let mut request = [0u8; 1024];
+    let mut failures = 0;
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut socket_rx_buffer, &mut socket_tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if let Err(e) = socket.accept(80).await {
+            log::warn!("Setup portal accept error: {e:?}");
+            Timer::after(Duration::from_millis(500)).await;
+            continue;
+        }
+
+        // Every other request gets the form, which makes it the captive portal
+        let len = read_request(&mut socket, &mut request).await;
+        let (error, saved) = match submission(&request[..len]) {
+            Some(_) if failures >= MAX_CODE_FAILURES => (
+                Some("Too many wrong codes, reset the listener to try again."),
+                false,
+            ),
+            Some(form) if !has_code(form) => {
+                failures += 1;
+                log::warn!("Wrong setup code, {failures} of {MAX_CODE_FAILURES}");
+                (Some("Wrong setup code."), false)
+            }
+            Some(form) => match submit(form, stored) {
+                Ok(submitted) => match settings::store(flash, &submitted) {
+                    Ok(()) => (None, true),
+                    Err(e) => {
+                        log::error!("Failed to store the submitted settings: {e}");
+                        (Some("Saving failed, try again."), false)
+                    }
+                },
+                Err(error) => (Some(error), false),
+            },
+            None => (None, false),
+        };
+
+        let page = render(error, saved);
+        let head = b"HTTP/1.0 200 OK\r\n\
+            Content-Type: text/html; charset=utf-8\r\n\
+            Cache-Control: no-store\r\n\
+            Connection: close\r\n\r\n";
+        if let Err(e) = socket.write_all(head).await {
+            log::warn!("Setup portal write error: {e:?}");
+            continue;
+        }
+        if let Err(e) = socket.write_all(page.as_bytes()).await {
+            log::warn!("Setup portal write error: {e:?}");
+            continue;
+        }
+        // Let the page drain before the socket is dropped
+        socket.close();
+        let _ = socket.flush().await;
+
+        if saved {
+            log::info!("Settings submitted, rebooting");
+            Timer::after(REBOOT_DELAY).await;
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+/// Reads until the head and the body of its `Content-Length` are in, or the
+/// buffer is full. Returns the length read.
+async fn read_request(socket: &mut TcpSocket<'_>, buf: &mut [u8]) -> usize {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match socket.read(&mut buf[filled..]).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => filled += n,
+        }
+        let Some(header_end) = buf[..filled].windows(4).position(|w| w == b"\r\n\r\n") else {
+            continue;
+        };
+        let content_length = core::str::from_utf8(&buf[..header_end])
+            .ok()
+            .and_then(|head| {
+                head.split("\r\n").find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().ok())?
+                })
+            })
+            .unwrap_or(0);
+        if filled >= header_end + 4 + content_length {
+            break;
+        }
+    }
+    filled
+}
+
+/// Form body of a `POST`, `None` for any other request
+fn submission(request: &[u8]) -> Option<&str> {
+    let header_end = request.windows(4).position(|w| w == b"\r\n\r\n")?;
+    if !request.starts_with(b"POST ") {
+        return None;
+    }
+    core::str::from_utf8(&request[header_end + 4..]).ok()
+}
+
+/// Whether the form carries `PROVISIONING_CODE`
+fn has_code(form: &str) -> bool {
+    form.split('&').any(|pair| {
+        pair.split_once('=').is_some_and(|(name, value)| {
+            name == "code"
+                && decode::<6>(value)
+                    .flatten()
+                    .is_some_and(|code| code == PROVISIONING_CODE)
+        })
+    })
+}
+
+/// The stored settings with the submitted network and gateway
+fn submit(form: &str, stored: &Settings) -> Result<Settings, &'static str> {
+    let mut settings = stored.clone();
+    settings.ssid = None;
+    settings.password = None;
+    settings.gateway = None;
+    for pair in form.split('&') {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "ssid" => settings.ssid = decode(value).ok_or("The network name is too long.")?,
+            "password" => settings.password = decode(value).ok_or("The password is too long.")?,
+            "gateway" => {
+                settings.gateway = decode(value).ok_or("The gateway address is too long.")?
+            }
+            _ => (),
+        }
+    }
+    if settings.ssid.is_none() {
+        return Err("Enter the name of the Wi-Fi network.");
+    }
+    if settings
+        .password
+        .as_ref()
+        .is_some_and(|password| password.len() < 8)
+    {
+        return Err("A WPA2 password has at least 8 characters.");
+    }
+    Ok(settings)
+}
+
+/// URL encoded form value, `Some(None)` when empty and `None` when invalid or over `N` bytes
+fn decode<const N: usize>(value: &str) -> Option<Option<String<N>>> {
+    let mut bytes = Vec::<u8, N>::new();
+    let mut rest = value.bytes();
+    while let Some(byte) = rest.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [rest.next()?, rest.next()?];
+                u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        };
+        bytes.push(byte).ok()?;
+    }
+    if bytes.is_empty() {
+        return Some(None);
+    }
+    String::from_utf8(bytes).ok().map(Some)
+}
+
+fn render(error: Option<&str>, saved: bool) -> AllocString {
+    let mut page = AllocString::from(FORM_HEAD);
+    if saved {
+        page.push_str("<p>Saved. The listener reboots and joins the network.</p>");
+    } else {
+        if let Some(error) = error {
+            let _ = write!(page, "<p><strong>{error}</strong></p>");
+        }
+        let _ = write!(
+            page,
+            "<form method=\"post\" action=\"/\" accept-charset=\"utf-8\">\
+            <p><label>Setup code<br><input name=\"code\" inputmode=\"numeric\" maxlength=\"6\" \
+            required></label></p>\
+            <p><label>Wi-Fi network<br><input name=\"ssid\" maxlength=\"32\" required></label></p>\
+            <p><label>Password, empty for an open network<br>\
+            <input name=\"password\" type=\"password\" maxlength=\"64\"></label></p>\
+            <p><label>Gateway, empty for {GATEWAY_IP}<br>\
+            <input name=\"gateway\" maxlength=\"96\"></label></p>\
+            <p><button>Save and reboot</button></p></form>"
+        );
+    }
+    page.push_str(FORM_TAIL);
+    page
+}
+
+async fn serve_dhcp(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0u8; 1024];
+    let mut packet = [0u8; 576];
+    let mut reply = [0u8; DHCP_MIN_LEN];
+    let mut leases = Leases::new();
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(DHCP_SERVER_PORT) {
+        log::error!("Failed to bind the DHCP port: {e:?}");
+        return;
+    }
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let Some(len) = dhcp_reply(&packet[..len], &mut leases, &mut reply) else {
+            continue;
+        };
+        // The client has no address yet
+        if let Err(e) = socket
+            .send_to(&reply[..len], (Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT))
+            .await
+        {
+            log::warn!("Failed to send a DHCP reply: {e:?}");
+        }
+    }
+}
+
+/// Client MACs by address, an all zero one is free
+struct Leases {
+    macs: [[u8; 6]; CLIENTS],
+    next: usize,
+}
+
+impl Leases {
+    const fn new() -> Self {
+        Self {
+            macs: [[0; 6]; CLIENTS],
+            next: 0,
+        }
+    }
+
+    /// The address leased to `mac`, leased now if it had none
+    fn address(&mut self, mac: [u8; 6]) -> Ipv4Addr {
+        let index = match self.macs.iter().position(|leased| *leased == mac) {
+            Some(index) => index,
+            None => {
+                let index = self.next;
+                self.macs[index] = mac;
+                self.next = (index + 1) % CLIENTS;
+                index
+            }
+        };
+        let [a, b, c, _] = AP_ADDRESS.octets();
+        Ipv4Addr::new(a, b, c, 2 + index as u8)
+    }
+}
+
+/// An offer for a discover and an acknowledgement for a request, the client's
+/// choice of address isn't checked. Returns the length of the reply.
+fn dhcp_reply(request: &[u8], leases: &mut Leases, reply: &mut [u8]) -> Option<usize> {
+    if request.len() < DHCP_HEADER_LEN
+        || request[0] != 1
+        || request[236..DHCP_HEADER_LEN] != DHCP_MAGIC
+    {
+        return None;
+    }
+    let kind = match dhcp_option(&request[DHCP_HEADER_LEN..], OPTION_MESSAGE_TYPE)? {
+        [DHCP_DISCOVER] => DHCP_OFFER,
+        [DHCP_REQUEST] => DHCP_ACK,
+        _ => return None,
+    };
+    let mac = request[28..34].try_into().ok()?;
+    let address = leases.address(mac);
+
+    reply.fill(0);
+    // A reply over Ethernet with the transaction and flags of the request
+    reply[..3].copy_from_slice(&[2, 1, 6]);
+    reply[4..8].copy_from_slice(&request[4..8]);
+    reply[10..12].copy_from_slice(&request[10..12]);
+    reply[16..20].copy_from_slice(&address.octets());
+    reply[20..24].copy_from_slice(&AP_ADDRESS.octets());
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[236..DHCP_HEADER_LEN].copy_from_slice(&DHCP_MAGIC);
+
+    let server = AP_ADDRESS.octets();
+    let options: [(u8, &[u8]); 6] = [
+        (OPTION_MESSAGE_TYPE, &[kind]),
+        (OPTION_SERVER_ID, &server),
+        (OPTION_LEASE_TIME, &LEASE_SECS.to_be_bytes()),
+        (OPTION_SUBNET_MASK, &[255, 255, 255, 0]),
+        (OPTION_ROUTER, &server),
+        (OPTION_DNS, &server),
+    ];
+    let mut len = DHCP_HEADER_LEN;
+    for (code, value) in options {
+        reply[len] = code;
+        reply[len + 1] = value.len() as u8;
+        reply[len + 2..len + 2 + value.len()].copy_from_slice(value);
+        len += 2 + value.len();
+    }
+    reply[len] = OPTION_END;
+    Some(DHCP_MIN_LEN.max(len + 1))
+}
+
+/// Value of the option `code`
+fn dhcp_option(mut options: &[u8], code: u8) -> Option<&[u8]> {
+    loop {
+        match *options.first()? {
+            OPTION_END => return None,
+            // Padding
+            0 => options = &options[1..],
+            found => {
+                let len = usize::from(*options.get(1)?);
+                let value = options.get(2..2 + len)?;
+                if found == code {
+                    return Some(value);
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+}
+
+async fn serve_dns(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+    let mut packet = [0u8; 512];
+    let mut reply = [0u8; 512];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(DNS_PORT) {
+        log::error!("Failed to bind the DNS port: {e:?}");
+        return;
+    }
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let Some(len) = dns_reply(&packet[..len], &mut reply) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&reply[..len], meta.endpoint).await {
+            log::warn!("Failed to send a DNS reply: {e:?}");
+        }
+    }
+}
+
+/// Answers a query for any A record with the access point, other types get an
+/// empty answer. Returns the length of the reply.
+fn dns_reply(query: &[u8], reply: &mut [u8]) -> Option<usize> {
+    let flags = net::be16(query, 2)?;
+    if flags & 0x8000 != 0 || net::be16(query, 4)? != 1 {
+        return None;
+    }
+    let end = net::read_name(query, 12, &mut Name::new())? + 4;
+    let question = query.get(12..end)?;
+    let kind = net::be16(query, end - 4)?;
+    let class = net::be16(query, end - 2)?;
+    let answers = u16::from(kind == TYPE_A && class == CLASS_IN);
+
+    // A response with authority and recursion desired copied from the query
+    let flags = 0x8400 | (flags & 0x0100);
+    reply.get_mut(..12)?.copy_from_slice(&[
+        query[0],
+        query[1],
+        (flags >> 8) as u8,
+        flags as u8,
+        0,
+        1,
+        0,
+        answers as u8,
+        0,
+        0,
+        0,
+        0,
+    ]);
+    let mut len = 12 + question.len();
+    reply.get_mut(12..len)?.copy_from_slice(question);
+    if answers == 1 {
+        // Pointer to the question's name
+        let record = reply.get_mut(len..len + 16)?;
+        record[..2].copy_from_slice(&[0xC0, 12]);
+        record[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        record[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        record[6..10].copy_from_slice(&DNS_TTL_SECS.to_be_bytes());
+        record[10..12].copy_from_slice(&4u16.to_be_bytes());
+        record[12..].copy_from_slice(&AP_ADDRESS.octets());
+        len += 16;
+    }
+    Some(len)
+}
@@ -0,0 +1,305 @@
+//! Runtime configuration over BLE. For [`PROVISIONING_WINDOW_SECS`] after boot the
+//! listener advertises a GATT service taking Wi-Fi credentials, gateway addresses and
+//! scan settings. Clients pair with LE Secure Connections, entering [`PROVISIONING_CODE`]
+//! as the passkey, and writes are refused on a link that isn't encrypted and
+//! authenticated that way. Writing 1 to `apply` stores the settings and reboots into them.
+
+use crate::board::Flash;
+use crate::config::{PROVISIONING_CODE, PROVISIONING_WINDOW_SECS};
+use crate::settings::{self, Settings};
+use embassy_time::{Duration, Instant, Timer};
+use esp_radio::ble::controller::BleConnector;
+use heapless::String;
+use ruuvi_schema::protocol::{ScanPhys, ScanSettings};
+use trouble_host::prelude::*;
+
+const NAME: &str = "ruuvi-listener";
+/// Advertised in the scan response, least significant byte first
+const SERVICE_UUID: u128 = 0x6e0b0000_4f3c_4d7e_9a51_2c8d5b1e7a00;
+/// Lets the reply to `apply` reach the client before the reboot
+const APPLY_DELAY: Duration = Duration::from_secs(1);
+/// Marks an unset field of the `scan` characteristic
+const UNSET: u8 = 0xFF;
+/// Failed pairings after which the service closes until the next boot
+const MAX_PAIRING_FAILURES: u32 = 5;
+
+type Controller = ExternalController<BleConnector<'static>, 20>;
+
+#[gatt_server]
+struct Server {
+    provisioning: ProvisioningService,
+}
+
+/// Write only, nothing provisioned is read back
+#[gatt_service(uuid = "6e0b0000-4f3c-4d7e-9a51-2c8d5b1e7a00")]
+struct ProvisioningService {
+    /// Network name, empty goes back to `SSID`
+    #[characteristic(uuid = "6e0b0002-4f3c-4d7e-9a51-2c8d5b1e7a00", write)]
+    ssid: String<32>,
+    /// WPA2-Personal key, empty for an open network
+    #[characteristic(uuid = "6e0b0003-4f3c-4d7e-9a51-2c8d5b1e7a00", write)]
+    password: String<64>,
+    /// Like `GATEWAY_IP`, empty goes back to it
+    #[characteristic(uuid = "6e0b0004-4f3c-4d7e-9a51-2c8d5b1e7a00", write)]
+    gateway: String<96>,
+    /// Interval and window in milliseconds as u16 LE, phys and active, see [`scan_settings`]
+    #[characteristic(uuid = "6e0b0005-4f3c-4d7e-9a51-2c8d5b1e7a00", write)]
+    scan: [u8; 6],
+    /// 1 stores the settings and reboots
+    #[characteristic(uuid = "6e0b0006-4f3c-4d7e-9a51-2c8d5b1e7a00", write)]
+    apply: u8,
+}
+
+/// `PROVISIONING_CODE` as a passkey, `None` unless it's 6 digits
+pub fn passkey() -> Option<u32> {
+    if PROVISIONING_CODE.len() != 6 || !PROVISIONING_CODE.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    PROVISIONING_CODE.parse().ok()
+}
+
+/// Serves the provisioning service until the window after boot closes, or too many
+/// pairings failed. Returns at once without a valid `PROVISIONING_CODE`.
+pub async fn run(
+    mut peripheral: Peripheral<'_, Controller, DefaultPacketPool>,
+    flash: &Flash,
+    stored: &Settings,
+) {
+    let Some(passkey) = passkey() else {
+        if !PROVISIONING_CODE.is_empty() {
+            log::error!("PROVISIONING_CODE isn't 6 digits, BLE provisioning is disabled");
+        }
+        return;
+    };
+    let server = match Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: NAME,
+        appearance: &appearance::sensor::GENERIC_SENSOR,
+    })) {
+        Ok(server) => server,
+        Err(e) => {
+            log::error!("Failed to set up the provisioning service: {e}");
+            return;
+        }
+    };
+
+    let mut adv_data = [0u8; 31];
+    let mut scan_data = [0u8; 31];
+    let (Ok(adv_len), Ok(scan_len)) = (
+        AdStructure::encode_slice(
+            &[
+                AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+                AdStructure::CompleteLocalName(NAME.as_bytes()),
+            ],
+            &mut adv_data,
+        ),
+        AdStructure::encode_slice(
+            &[AdStructure::ServiceUuids128(&[SERVICE_UUID.to_le_bytes()])],
+            &mut scan_data,
+        ),
+    ) else {
+        log::error!("Failed to encode the provisioning advertisement");
+        return;
+    };
+
+    let closes = Instant::from_secs(PROVISIONING_WINDOW_SECS);
+    let mut failures = 0;
+    log::info!("Provisioning over BLE for {PROVISIONING_WINDOW_SECS}s after boot");
+    while failures < MAX_PAIRING_FAILURES
+        && let Some(remaining) = closes.checked_duration_since(Instant::now())
+    {
+        let sets = [AdvertisementSet {
+            params: AdvertisementParameters {
+                timeout: Some(remaining),
+                ..Default::default()
+            },
+            data: Advertisement::ConnectableScannableUndirected {
+                adv_data: &adv_data[..adv_len],
+                scan_data: &scan_data[..scan_len],
+            },
+        }];
+        let mut handles = AdvertisementSet::handles(&sets);
+        let conn = match peripheral.advertise_ext(&sets, &mut handles).await {
+            Ok(advertiser) => match advertiser.accept().await {
+                Ok(conn) => conn,
+                Err(Error::Timeout) => break,
+                Err(e) => {
+                    log::warn!("Provisioning connection failed: {e:?}");
+                    continue;
+                }
+            },
+            Err(e) => {
+                log::error!("Failed to advertise the provisioning service: {e:?}");
+                break;
+            }
+        };
+        match conn.with_attribute_server(&server) {
+            Ok(conn) => {
+                serve(
+                    &conn,
+                    &server,
+                    flash,
+                    stored.clone(),
+                    passkey,
+                    &mut failures,
+                )
+                .await
+            }
+            Err(e) => log::warn!("Failed to serve the provisioning connection: {e:?}"),
+        }
+    }
+    if failures >= MAX_PAIRING_FAILURES {
+        log::warn!("Provisioning closed after {failures} failed pairings");
+    } else {
+        log::info!("Provisioning window closed");
+    }
+}
+
+/// Takes writes on top of `settings` until the client disconnects, or is disconnected
+/// for a failed pairing, which counts in `failures`. Whatever wasn't applied is forgotten.
+async fn serve(
+    conn: &GattConnection<'_, '_, DefaultPacketPool>,
+    server: &Server<'_>,
+    flash: &Flash,
+    mut settings: Settings,
+    passkey: u32,
+    failures: &mut u32,
+) {
+    log::info!("Provisioning client connected");
+    let link = conn.raw();
+    // Nothing is kept of the pairing, every connection pairs anew
+    if let Err(e) = link
+        .set_bondable(false)
+        .and_then(|()| link.request_security())
+    {
+        log::warn!("Failed to ask the provisioning client to pair: {e:?}");
+        return;
+    }
+    let service = &server.provisioning;
+    loop {
+        let event = match conn.next().await {
+            GattConnectionEvent::Disconnected { reason } => {
+                log::info!("Provisioning client disconnected: {reason:?}");
+                return;
+            }
+            GattConnectionEvent::PassKeyInput => {
+                if let Err(e) = link.pass_key_input(passkey) {
+                    log::warn!("Failed to enter the provisioning passkey: {e:?}");
+                }
+                continue;
+            }
+            // Only a passkey the client enters proves it knows the code
+            GattConnectionEvent::PassKeyDisplay(_) | GattConnectionEvent::PassKeyConfirm(_) => {
+                let _ = link.pass_key_cancel();
+                continue;
+            }
+            GattConnectionEvent::PairingComplete { security_level, .. } => {
+                log::info!("Provisioning client paired, {security_level:?}");
+                continue;
+            }
+            GattConnectionEvent::PairingFailed(e) => {
+                *failures += 1;
+                log::warn!(
+                    "Provisioning pairing failed, {failures} of {MAX_PAIRING_FAILURES}: {e:?}"
+                );
+                link.disconnect();
+                continue;
+            }
+            GattConnectionEvent::Gatt {
+                event: GattEvent::Write(event),
+            } => event,
+            GattConnectionEvent::Gatt { event } => {
+                if let Ok(reply) = event.accept() {
+                    reply.send().await;
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        let handle = event.handle();
+        let mut apply = false;
+        let secured = match link.security_level() {
+            Ok(SecurityLevel::EncryptedAuthenticated) => Ok(()),
+            Ok(SecurityLevel::Encrypted) => Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION),
+            _ => Err(AttErrorCode::INSUFFICIENT_ENCRYPTION),
+        };
+        let outcome = if let Err(code) = secured {
+            Err(code)
+        } else if handle == service.ssid.handle {
+            text(event.data()).map(|ssid| settings.ssid = ssid)
+        } else if handle == service.password.handle {
+            text(event.data()).map(|password| settings.password = password)
+        } else if handle == service.gateway.handle {
+            text(event.data()).map(|gateway| settings.gateway = gateway)
+        } else if handle == service.scan.handle {
+            scan_settings(event.data()).map(|scan| settings.scan = scan)
+        } else if handle == service.apply.handle {
+            if event.data() != [1] {
+                Err(AttErrorCode::VALUE_NOT_ALLOWED)
+            } else if let Err(e) = settings::store(flash, &settings) {
+                log::error!("Failed to store the provisioned settings: {e}");
+                Err(AttErrorCode::UNLIKELY_ERROR)
+            } else {
+                apply = true;
+                Ok(())
+            }
+        } else {
+            Err(AttErrorCode::WRITE_NOT_PERMITTED)
+        };
+
+        let reply = match outcome {
+            Ok(()) => event.accept(),
+            Err(code) => event.reject(code),
+        };
+        if let Ok(reply) = reply {
+            reply.send().await;
+        }
+        if apply {
+            log::info!("Provisioned settings stored, rebooting");
+            Timer::after(APPLY_DELAY).await;
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+/// UTF-8 of at most `N` bytes, empty unsets the value
+fn text<const N: usize>(data: &[u8]) -> Result<Option<String<N>>, AttErrorCode> {
+    let text = core::str::from_utf8(data).map_err(|_| AttErrorCode::VALUE_NOT_ALLOWED)?;
+    if text.is_empty() {
+        return Ok(None);
+    }
+    String::try_from(text)
+        .map(Some)
+        .map_err(|_| AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH)
+}
+
+/// Interval and window in milliseconds as u16 LE, 0 keeps the build time value. Then
+/// the phys, 0 for 1M, 1 for Coded and 2 for both, and active scanning as 0 or 1,
+/// each 0xFF to keep the build time value. All unset goes back to the build time scan.
+fn scan_settings(data: &[u8]) -> Result<Option<ScanSettings>, AttErrorCode> {
+    let &[i0, i1, w0, w1, phys, active] = data else {
+        return Err(AttErrorCode::INVALID_ATTRIBUTE_VALUE_LENGTH);
+    };
+    let millis = |bytes| Some(u16::from_le_bytes(bytes)).filter(|&ms| ms != 0);
+    let scan = ScanSettings {
+        interval_ms: millis([i0, i1]),
+        window_ms: millis([w0, w1]),
+        phys: match phys {
+            0 => Some(ScanPhys::M1),
+            1 => Some(ScanPhys::Coded),
+            2 => Some(ScanPhys::M1Coded),
+            UNSET => None,
+            _ => return Err(AttErrorCode::VALUE_NOT_ALLOWED),
+        },
+        active: match active {
+            0 => Some(false),
+            1 => Some(true),
+            UNSET => None,
+            _ => return Err(AttErrorCode::VALUE_NOT_ALLOWED),
+        },
+    };
+    if !scan.is_valid() {
+        return Err(AttErrorCode::VALUE_NOT_ALLOWED);
+    }
+    Ok((scan != ScanSettings::default()).then_some(scan))
+}
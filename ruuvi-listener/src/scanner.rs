@@ -1,111 +1,329 @@
+use crate::board::Flash;
+use crate::coex;
+use crate::config::{
+    LED_QUEUE_DEPTH, MAX_TAGS, OverflowPolicy, PACKET_QUEUE_DEPTH, QUEUE_OVERFLOW, SCAN_ACTIVE,
+    SCAN_INTERVAL_MS, SCAN_PHYS, SCAN_WINDOW_MS, TAG_ALLOWLIST, TAG_DENYLIST,
+    TAG_MIN_INTERVAL_SECS,
+};
+use crate::diag;
 use crate::led::LedEvent;
-use crate::schema::parse_ruuvi_raw;
-use bt_hci::param::LeExtAdvReport;
+use crate::metrics;
+use crate::provision;
+use crate::schedule;
+use crate::settings::Settings;
+use crate::watchdog::{self, Task};
+use bt_hci::param::{LeExtAdvDataStatus, LeExtAdvReport};
 use core::cell::RefCell;
-use embassy_futures::join::join;
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::channel::Sender;
-use embassy_time::{Duration, Instant, Timer};
+use embassy_futures::join::join3;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::channel::{Receiver, Sender};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, WithTimeout};
+use esp_hal::rng::Trng;
 use esp_radio::ble::controller::BleConnector;
 use heapless::index_map::FnvIndexMap;
 use ruuvi_schema::RuuviRaw;
+use ruuvi_schema::protocol::{ScanPhys, ScanSettings};
+use ruuvi_schema::seq::SeqWindow;
 use trouble_host::prelude::*;
 
+/// The provisioning client
 const CONNECTIONS_MAX: usize = 1;
-const L2CAP_CHANNELS_MAX: usize = 1;
+/// Signaling and ATT of the provisioning connection
+const L2CAP_CHANNELS_MAX: usize = 2;
 const RUUVI_MAN_ID: [u8; 2] = [0x99, 0x04];
+const AD_MANUFACTURER_DATA: u8 = 0xFF;
+/// Scans are restarted at least this often
+const RESTART_INTERVAL: Duration = Duration::from_secs(1);
+
+static SETTINGS: Signal<CriticalSectionRawMutex, ScanSettings> = Signal::new();
+/// Allow and deny lists from the gateway
+static FILTER: Signal<CriticalSectionRawMutex, (Macs, Macs)> = Signal::new();
 
 type DataFormat = u8;
 type DataIndex = usize;
+type Macs = heapless::Vec<[u8; 6], MAX_TAGS>;
 
 #[embassy_executor::task]
 pub async fn run(
     controller: ExternalController<BleConnector<'static>, 20>,
-    sender: Sender<'static, NoopRawMutex, (RuuviRaw, Instant), 16>,
-    led_sender: Sender<'static, NoopRawMutex, LedEvent, 16>,
+    sender: Sender<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    led_sender: Sender<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
+    flash: &'static Flash,
+    stored: &'static Settings,
 ) {
     let address: Address = Address::random([0xB0, 0x0B, 0xCA, 0xFE, 0xB0, 0x0B]);
     log::info!("MAC address: {address:?}");
 
     let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> =
         HostResources::new();
-    let stack = trouble_host::new(controller, &mut resources).set_random_address(address);
+    // The radio is up, so the RNG draws from true entropy
+    let mut trng = Trng::try_new().expect("Radio enabled, TRNG available");
+    // Provisioning clients pair with the passkey the listener enters, see `provision`
+    let stack = trouble_host::new(controller, &mut resources)
+        .set_random_address(address)
+        .set_random_generator_seed(&mut trng)
+        .set_io_capabilities(IoCapabilities::KeyboardOnly);
     let Host {
         central,
+        peripheral,
         mut runner,
-        ..
     } = stack.build();
     log::info!("BLE stack initialized!");
 
-    let handler = Handler::new(sender, led_sender);
+    let handler = Handler::new(sender, receiver, led_sender);
     let mut scanner = Scanner::new(central);
     log::info!("Start scanning BLE ruuvi packets");
-    let _ = join(runner.run_with_handler(&handler), async {
-        let config = ScanConfig {
-            active: false, // No need for scan responses, data is all in advertisement payload
-            phys: PhySet::M1,
-            interval: Duration::from_millis(1000),
-            window: Duration::from_millis(1000),
-            ..Default::default()
-        };
+    let scanning = async {
+        // Provisioned settings stand in for the build time ones, the gateway's go on top
+        let provisioned = stored.scan.unwrap_or_default();
+        let mut settings = provisioned;
 
-        // Scan forever
+        // Scan forever, a controller that stops answering never returns a session
         loop {
+            watchdog::beat(Task::Scanner);
+            // Settings are checked as they arrive
+            let mut config = scan_config(settings).unwrap();
+            config.window = coex::window(config.window);
             let scan_session = scanner.scan_ext(&config).await;
-            if let Err(e) = scan_session {
+            if let Err(e) = &scan_session {
                 log::error!("Error during scanning: {e:?}");
             }
-            Timer::after(Duration::from_secs(1)).await;
+            // Restarted right away with new settings, otherwise once per scan interval
+            // to follow the Wi-Fi pressure
+            let restart = config.interval.max(RESTART_INTERVAL);
+            if let Ok(new) = SETTINGS.wait().with_timeout(restart).await {
+                let new = new.or(provisioned);
+                if scan_config(new).is_some() {
+                    log::info!("Scan settings from the gateway: {new:?}");
+                    settings = new;
+                } else {
+                    log::warn!("Ignoring invalid scan settings {new:?}");
+                }
+            }
         }
-    })
+    };
+    let _ = join3(
+        runner.run_with_handler(&handler),
+        scanning,
+        provision::run(peripheral, flash, stored),
+    )
     .await;
 }
 
+/// Scan parameters from the gateway, taken over on the next scan
+pub fn configure(settings: ScanSettings) {
+    SETTINGS.signal(settings);
+}
+
+/// Tag filter from the gateway, replaces `TAG_ALLOWLIST` and `TAG_DENYLIST` until reboot
+pub fn filter(allow: &[[u8; 6]], deny: &[[u8; 6]]) {
+    if allow.len() > MAX_TAGS || deny.len() > MAX_TAGS {
+        log::warn!("Tag filter cut to {MAX_TAGS} MACs per list");
+    }
+    let macs = |list: &[[u8; 6]]| list.iter().copied().take(MAX_TAGS).collect();
+    FILTER.signal((macs(allow), macs(deny)));
+}
+
+/// `settings` on top of the build time configuration, `None` if the timing is invalid
+fn scan_config(settings: ScanSettings) -> Option<ScanConfig<'static>> {
+    let interval = settings.interval_ms.unwrap_or(SCAN_INTERVAL_MS);
+    let window = settings.window_ms.unwrap_or(SCAN_WINDOW_MS);
+    if !settings.is_valid() || window > interval {
+        return None;
+    }
+    Some(ScanConfig {
+        active: settings.active.unwrap_or(SCAN_ACTIVE),
+        phys: match settings.phys.unwrap_or(SCAN_PHYS) {
+            ScanPhys::M1 => PhySet::M1,
+            ScanPhys::Coded => PhySet::Coded,
+            ScanPhys::M1Coded => PhySet::M1Coded,
+        },
+        interval: Duration::from_millis(interval.into()),
+        window: Duration::from_millis(window.into()),
+        ..Default::default()
+    })
+}
+
+/// MACs of a comma separated list like `TAG_ALLOWLIST`
+fn parse_macs(list: &str, name: &str) -> Macs {
+    let mut macs = Macs::new();
+    for mac in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let mut bytes = [0u8; 6];
+        let mut parts = mac.split(':');
+        for byte in &mut bytes {
+            *byte = parts
+                .next()
+                .and_then(|part| u8::from_str_radix(part, 16).ok())
+                .unwrap_or_else(|| panic!("{name} has an invalid MAC {mac}"));
+        }
+        if parts.next().is_some() {
+            panic!("{name} has an invalid MAC {mac}");
+        }
+        macs.push(bytes)
+            .unwrap_or_else(|_| panic!("{name} takes up to {MAX_TAGS} MACs"));
+    }
+    macs
+}
+
 struct Handler {
-    sender: Sender<'static, NoopRawMutex, (RuuviRaw, Instant), 16>,
-    led_sender: Sender<'static, NoopRawMutex, LedEvent, 16>,
+    sender: Sender<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    /// Takes readings back out of a full queue
+    receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    led_sender: Sender<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
+    /// Only these tags are forwarded when not empty
+    allowed: RefCell<Macs>,
+    denied: RefCell<Macs>,
     // Use interior mutability since, handler cannot access its mutable self
-    sequence_numbers: RefCell<FnvIndexMap<[u8; 6], u32, 16>>,
+    sequence_numbers: RefCell<FnvIndexMap<[u8; 6], SeqWindow, MAX_TAGS>>,
+    /// When each tag was last forwarded
+    forwarded: RefCell<FnvIndexMap<[u8; 6], Instant, MAX_TAGS>>,
+    /// Latest movement counter of each tag
+    movements: RefCell<FnvIndexMap<[u8; 6], u8, MAX_TAGS>>,
 }
 
 impl Handler {
     fn new(
-        sender: Sender<'static, NoopRawMutex, (RuuviRaw, Instant), 16>,
-        led_sender: Sender<'static, NoopRawMutex, LedEvent, 16>,
+        sender: Sender<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+        receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+        led_sender: Sender<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
     ) -> Self {
+        let allowed = parse_macs(TAG_ALLOWLIST, "TAG_ALLOWLIST");
+        let denied = parse_macs(TAG_DENYLIST, "TAG_DENYLIST");
+        if !allowed.is_empty() {
+            log::info!("Forwarding only {} allowed tags", allowed.len());
+        }
         Handler {
             sender,
+            receiver,
             led_sender,
+            allowed: RefCell::new(allowed),
+            denied: RefCell::new(denied),
             sequence_numbers: RefCell::new(FnvIndexMap::new()),
+            forwarded: RefCell::new(FnvIndexMap::new()),
+            movements: RefCell::new(FnvIndexMap::new()),
         }
     }
 
-    fn is_new_seq(&self, mac: [u8; 6], seq: u32) -> bool {
-        let map = self.sequence_numbers.borrow();
-        map.get(&mac).is_none_or(|prev_seq| *prev_seq != seq)
+    fn is_wanted(&self, mac: &[u8; 6]) -> bool {
+        if let Some((allowed, denied)) = FILTER.try_take() {
+            log::info!(
+                "Tag filter updated, {} allowed and {} denied tags",
+                allowed.len(),
+                denied.len()
+            );
+            *self.allowed.borrow_mut() = allowed;
+            *self.denied.borrow_mut() = denied;
+        }
+        let allowed = self.allowed.borrow();
+        (allowed.is_empty() || allowed.contains(mac)) && !self.denied.borrow().contains(mac)
     }
 
-    fn upsert_seq(&self, mac: [u8; 6], seq: u32) {
-        let mut map = self.sequence_numbers.borrow_mut();
-        _ = map.insert(mac, seq).map_err(|(mac, seq_key)| {
-            log::error!("Failed to insert key {mac:?}, value: {seq_key}")
+    /// Whether the tag's movement counter changed since its previous reading
+    fn has_moved(&self, mac: [u8; 6], movement: Option<u8>) -> bool {
+        let Some(movement) = movement else {
+            return false;
+        };
+        let mut map = self.movements.borrow_mut();
+        match map.insert(mac, movement) {
+            Ok(previous) => previous.is_some_and(|previous| previous != movement),
+            Err((mac, _)) => {
+                log::error!("Failed to insert key {mac:?} into the movement counters");
+                false
+            }
+        }
+    }
+
+    /// Whether a reading is held back by [`TAG_MIN_INTERVAL_SECS`], records it otherwise.
+    /// A tag that moved is never held back.
+    fn is_throttled(&self, mac: [u8; 6], moved: bool, at: Instant) -> bool {
+        if TAG_MIN_INTERVAL_SECS == 0 {
+            return false;
+        }
+        let mut map = self.forwarded.borrow_mut();
+        if !moved
+            && let Some(last) = map.get(&mac)
+            && at.duration_since(*last) < Duration::from_secs(TAG_MIN_INTERVAL_SECS)
+        {
+            return true;
+        }
+        _ = map.insert(mac, at).map_err(|(mac, _)| {
+            log::error!("Failed to insert key {mac:?} into the throttle");
         });
+        false
     }
 
-    fn extract_ruuvi_format(report: LeExtAdvReport<'_>) -> Option<(DataFormat, DataIndex)> {
-        // Ruuvi tag & air address kinds are random
-        // Ruuvi manufacturer's ID:
-        // Tag - format 5 - 5..7
-        // Air - format E1 - 2..4
-        // Air - format 6 - 9..11, skipping format 6, since we are using E1
-        if report.addr_kind == AddrKind::RANDOM && report.data.len() >= 7 {
-            if report.data[5..7] == RUUVI_MAN_ID {
-                return Some((report.data[7], 7));
+    /// Whether the sequence wasn't seen yet, records it
+    fn is_new_seq(&self, mac: [u8; 6], seq: u32, bits: Option<u32>) -> bool {
+        let mut map = self.sequence_numbers.borrow_mut();
+        if let Some(window) = map.get_mut(&mac) {
+            return window.accept(seq, bits);
+        }
+        _ = map
+            .insert(mac, SeqWindow::new(seq))
+            .map_err(|(mac, _)| log::error!("Failed to insert key {mac:?}, value: {seq}"));
+        true
+    }
+
+    /// Frees a queue slot for a reading of `mac` as [`QUEUE_OVERFLOW`] says, `false` when
+    /// the reading is to be dropped instead
+    fn make_room(&self, mac: [u8; 6]) -> bool {
+        if !self.sender.is_full() {
+            return true;
+        }
+        metrics::queue_overflow(1);
+        match QUEUE_OVERFLOW {
+            OverflowPolicy::DropNewest => {
+                log::warn!("Channel full. Dropping new data of mac: {mac:?}");
+                return false;
             }
+            OverflowPolicy::DropOldest => {
+                _ = self.receiver.try_receive();
+            }
+            OverflowPolicy::DropDuplicates => {
+                let mut queued = heapless::Vec::<_, PACKET_QUEUE_DEPTH>::new();
+                while let Ok(reading) = self.receiver.try_receive() {
+                    _ = queued.push(reading);
+                }
+                let stale = (0..queued.len())
+                    .find(|&i| {
+                        let tag = queued[i].0.mac();
+                        tag == mac || queued[i + 1..].iter().any(|(next, _)| next.mac() == tag)
+                    })
+                    .unwrap_or(0);
+                if stale < queued.len() {
+                    queued.remove(stale);
+                }
+                for reading in queued {
+                    _ = self.sender.try_send(reading);
+                }
+            }
+        }
+        log::warn!("Channel full. Dropped a queued reading for new data!");
+        true
+    }
 
-            if report.data[2..4] == RUUVI_MAN_ID {
-                return Some((report.data[4], 4));
+    fn extract_ruuvi_format(report: LeExtAdvReport<'_>) -> Option<(DataFormat, DataIndex)> {
+        // Ruuvi tag & air address kinds are random
+        if report.addr_kind != AddrKind::RANDOM {
+            return None;
+        }
+        // The manufacturer data follows the flags on the tags, comes first in the Air's
+        // extended advertisements and after the UUIDs in its legacy format 6 ones. Walk
+        // the AD structures instead, extended ones on the Coded PHY can carry more.
+        let mut index = 0;
+        while let Some(&len) = report.data.get(index) {
+            let len = usize::from(len);
+            let structure = report.data.get(index + 1..index + 1 + len)?;
+            match structure {
+                [] => return None,
+                [AD_MANUFACTURER_DATA, id0, id1, format, ..] if [*id0, *id1] == RUUVI_MAN_ID => {
+                    return Some((*format, index + 4));
+                }
+                _ => (),
             }
+            index += 1 + len;
         }
         None
     }
@@ -113,30 +331,52 @@ impl Handler {
 
 impl EventHandler for Handler {
     fn on_ext_adv_reports(&self, mut reports: LeExtAdvReportsIter) {
+        watchdog::beat(Task::Scanner);
         while let Some(Ok(report)) = reports.next() {
+            // Fragments of a long extended advertisement, Ruuvi payloads fit in one report
+            if !matches!(
+                report.event_kind.data_status(),
+                LeExtAdvDataStatus::Complete
+            ) {
+                log::debug!(
+                    "Skipping an incomplete advertisement from {:?}",
+                    report.addr
+                );
+                continue;
+            }
             if let Some((data_format, index)) = Self::extract_ruuvi_format(report) {
+                // Format 3 has no MAC in the payload, the address is little endian
+                let mut mac = [0u8; 6];
+                mac.copy_from_slice(report.addr.raw());
+                mac.reverse();
+                // Neighbours' tags are dropped before they cost a parse or a queue slot
+                if !self.is_wanted(&mac) {
+                    log::debug!("Skipping tag {mac:02X?}");
+                    continue;
+                }
+
+                metrics::advert_seen();
                 let rssi = report.rssi;
                 let tx_power = report.tx_power;
 
-                log::info!("Data format: {data_format:X?}",);
+                log::info!(
+                    "Data format: {data_format:X?} on {:?}",
+                    report.primary_adv_phy
+                );
                 log::info!("Data start at: {index}");
                 log::info!("Data len: {}", report.data[index..].len());
 
                 let t = Instant::now();
-                match parse_ruuvi_raw(data_format, &report.data[index..], rssi, tx_power) {
+
+                match RuuviRaw::parse(&report.data[index..], mac, rssi, tx_power) {
                     Ok(parsed) => {
-                        // If channel is full, empty it
-                        if self.sender.is_full() {
-                            self.sender.clear();
-                            log::warn!("Channel full. Clearing channel for new data!");
-                        }
+                        diag::record_scan(parsed.mac(), data_format, rssi);
 
                         let mac = parsed.mac();
                         let measurement_seq = parsed.measurement_seq();
 
                         // Verify the sequence number of the packet
-                        let is_new = self.is_new_seq(mac, measurement_seq);
-                        self.upsert_seq(mac, measurement_seq);
+                        let is_new = self.is_new_seq(mac, measurement_seq, parsed.seq_bits());
 
                         // If it's not new, skip the loop
                         if !is_new {
@@ -149,15 +389,31 @@ impl EventHandler for Handler {
                             continue;
                         }
 
+                        let moved = self.has_moved(mac, parsed.movement_counter());
+                        if self.is_throttled(mac, moved, t) {
+                            log::debug!("Throttled mac: {mac:?}, seq: {measurement_seq}");
+                            continue;
+                        }
+
+                        if !self.make_room(mac) {
+                            continue;
+                        }
+
                         // Send data to the channel
                         if let Err(err) = self.sender.try_send((parsed, t)) {
                             log::error!("Failed to send RuuviRawV2 to the channel! {err:?}");
+                        } else if moved {
+                            log::info!("Tag moved, sending at once. mac: {mac:?}");
+                            schedule::hurry();
                         }
                         if let Err(err) = self.led_sender.try_send(LedEvent::BleOk) {
                             log::error!("Failed to send LedEvent to the channel! {err:?}");
                         }
                     }
-                    Err(e) => log::error!("Payload error! {e:?}!"),
+                    Err(e) => {
+                        metrics::parse_failed();
+                        log::error!("Payload error! {e}!");
+                    }
                 }
             }
         }
@@ -0,0 +1,86 @@
+use crate::config::{BATCH_JITTER_MS, BATCH_WINDOW_MS, PACKET_QUEUE_DEPTH};
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::channel::Receiver;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::efuse::Efuse;
+use esp_hal::rng::Rng;
+use ruuvi_schema::RuuviRaw;
+use ruuvi_schema::time::ClockSync;
+
+/// A reading of a tag that moved is queued
+static URGENT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Sends the queued readings without waiting for the slot, for a tag that moved
+pub fn hurry() {
+    URGENT.signal(());
+}
+
+/// Holds readings until this listener's slot in the batch window, so a fleet of
+/// listeners doesn't hit the gateway and the database at the same moment
+pub struct Schedule {
+    phase_ms: u64,
+    rng: Rng,
+    // Gateway time at a local instant, slots line up across listeners once synced
+    clock: ClockSync,
+}
+
+impl Schedule {
+    /// The phase is derived from the device MAC, so it survives reboots and
+    /// listeners powered up together still spread out
+    pub fn new(rng: Rng) -> Self {
+        let mac = Efuse::mac_address();
+        // FNV-1a, neighbouring MACs land far apart
+        let hash = mac.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        let phase_ms = hash.checked_rem(BATCH_WINDOW_MS).unwrap_or(0);
+        if BATCH_WINDOW_MS > 0 {
+            log::info!("Sending batches every {BATCH_WINDOW_MS}ms at phase {phase_ms}ms");
+        }
+        Self {
+            phase_ms,
+            rng,
+            clock: ClockSync::default(),
+        }
+    }
+
+    /// Align slots to the gateway's clock
+    pub fn sync(&mut self, clock: ClockSync) {
+        self.clock = clock;
+    }
+
+    /// Next reading to send. Queued readings go out back to back, once the queue
+    /// runs dry the next batch waits for the listener's slot, or for [`hurry`].
+    pub async fn next(
+        &mut self,
+        receiver: &Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    ) -> (RuuviRaw, Instant) {
+        if let Ok(received) = receiver.try_receive() {
+            return received;
+        }
+        // Moved readings already went out, they don't hurry the next batch
+        URGENT.reset();
+        let received = receiver.receive().await;
+        self.wait_slot().await;
+        received
+    }
+
+    async fn wait_slot(&mut self) {
+        if BATCH_WINDOW_MS == 0 {
+            return;
+        }
+        let now = Instant::now().as_millis();
+        let now_ms = self.clock.gateway_time(now).unwrap_or(now);
+
+        // Slots are at phase + k * window, take the first one after now
+        let shifted = now_ms + BATCH_WINDOW_MS - self.phase_ms;
+        let slot = (shifted / BATCH_WINDOW_MS) * BATCH_WINDOW_MS + self.phase_ms;
+        let jitter = u64::from(self.rng.random())
+            .checked_rem(BATCH_JITTER_MS)
+            .unwrap_or(0);
+        let slot = Timer::after(Duration::from_millis(slot + jitter - now_ms));
+        select(slot, URGENT.wait()).await;
+    }
+}
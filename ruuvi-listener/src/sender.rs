@@ -1,24 +1,50 @@
-use crate::config::GatewayConfig;
-use crate::led::LedEvent;
+use crate::board::Flash;
+use crate::coex;
+use crate::config::{
+    ACK_TIMEOUT_SECS, GatewayConfig, HEARTBEAT_INTERVAL_SECS, LED_QUEUE_DEPTH, PACKET_QUEUE_DEPTH,
+    RETRY_BUFFER_DEPTH, TELEMETRY_INTERVAL_SECS, TIME_SYNC_INTERVAL_SECS,
+};
+use crate::diag;
+use crate::identity;
+use crate::led::{self, LedEvent};
+use crate::metrics;
+use crate::net;
+use crate::ota::{FIRMWARE_VERSION, Ota};
+use crate::scanner;
+use crate::schedule::Schedule;
+use crate::watchdog::{self, Task};
 use alloc::boxed::Box;
 use anyhow::anyhow;
+use core::cell::{Cell, RefCell};
+use embassy_futures::select::{Either, Either3, Either4, select, select3, select4};
 use embassy_net::Stack;
-use embassy_net::tcp::TcpSocket;
+use embassy_net::tcp::{TcpReader, TcpSocket, TcpWriter};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::channel::{Receiver, Sender};
+use embassy_sync::channel::{Channel, Receiver, Sender};
+use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
 use esp_hal::rng::Rng;
 use ruuvi_schema::RuuviRaw;
+use ruuvi_schema::ack::Ack;
+use ruuvi_schema::ota::Uplink;
+use ruuvi_schema::protocol::{Command, Hello, MAX_BATCH, Message, PROTOCOL_VERSION, RekeyPolicy};
+use ruuvi_schema::time::ClockSync;
 use snow::params::{CipherChoice, DHChoice, HashChoice};
 use snow::resolvers::{CryptoResolver, DefaultResolver};
 use snow::types::{Cipher, Dh, Hash, Random};
 use snow::{Builder, HandshakeState, TransportState};
 
 const PARAMS: &str = "Noise_XXpsk3_25519_ChaChaPoly_SHA256";
+// Gateway's id for the ChaChaPoly suite, sent before the handshake and used as the prologue
+const SUITE_ID: u8 = 1;
 const BASE_BACKOFF_MS: u64 = 500;
 const TIMEOUT_SECS: u64 = 20;
 const MAX_BACKOFF_SECS: u64 = 30;
+// Messages waiting for the socket
+const MESSAGE_QUEUE_DEPTH: usize = 4;
+// Time for the last messages to leave before rebooting
+const REBOOT_DELAY_SECS: u64 = 2;
 
 macro_rules! try_continue {
     ($expr:expr, $error_msg:literal) => {
@@ -41,10 +67,7 @@ macro_rules! try_continue {
     };
 }
 
-async fn recv(
-    socket: &mut TcpSocket<'_>,
-    rx_buffer: &mut [u8; 1024],
-) -> Result<usize, anyhow::Error> {
+async fn recv(socket: &mut impl Read, rx_buffer: &mut [u8; 1024]) -> Result<usize, anyhow::Error> {
     let mut msg_len_buf = [0u8; 2];
     socket
         .read_exact(&mut msg_len_buf)
@@ -58,7 +81,7 @@ async fn recv(
     Ok(msg_len)
 }
 
-async fn send(socket: &mut TcpSocket<'_>, tx_buffer: &[u8]) -> Result<(), anyhow::Error> {
+async fn send(socket: &mut impl Write, tx_buffer: &[u8]) -> Result<(), anyhow::Error> {
     let msg_len = u16::try_from(tx_buffer.len())?;
     socket
         .write_all(&msg_len.to_be_bytes())
@@ -129,14 +152,21 @@ impl<R: CryptoResolver> CryptoResolver for MyResolver<R> {
 async fn noise_handshake(
     socket: &mut TcpSocket<'_>,
     mut noise: HandshakeState,
+    psk_name: &str,
     tx_buffer: &mut [u8; 1024],
     rx_buffer: &mut [u8; 1024],
     noise_buffer: &mut [u8; 1024],
 ) -> Result<TransportState, anyhow::Error> {
+    // Select the cipher suite
+    socket
+        .write_all(&[SUITE_ID])
+        .await
+        .map_err(|e| anyhow!("Failed to write cipher suite: {e:?}"))?;
+
     // https://noiseprotocol.org/noise.html
-    // -> e
+    // -> e, naming our PSK for the gateway
     let len = noise
-        .write_message(&[], tx_buffer)
+        .write_message(psk_name.as_bytes(), tx_buffer)
         .map_err(|e| anyhow!("Failed to write e message: {e}"))?;
 
     send(socket, &tx_buffer[..len]).await?;
@@ -147,9 +177,16 @@ async fn noise_handshake(
         .read_message(&noise_buffer[..len], rx_buffer)
         .map_err(|e| anyhow!("Failed to read e, ee, s, es messages: {e}"))?;
 
-    // -> s, se
+    // -> s, se, with our versions as payload
+    let hello = Hello {
+        protocol: PROTOCOL_VERSION,
+        firmware: FIRMWARE_VERSION,
+    };
+    let mut payload = [0u8; 16];
+    let payload = postcard::to_slice(&hello, &mut payload)
+        .map_err(|e| anyhow!("Failed to postcard serialize hello: {e}"))?;
     let len = noise
-        .write_message(&[], tx_buffer)
+        .write_message(payload, tx_buffer)
         .map_err(|e| anyhow!("Failed to write s, se messages: {e}"))?;
     send(socket, &tx_buffer[..len]).await?;
 
@@ -163,38 +200,360 @@ async fn sync_time(
     socket: &mut TcpSocket<'_>,
     tp: &mut TransportState,
     noise_buffer: &mut [u8; 1024],
-    time_reference: &mut Option<(Instant, u64)>,
+    clock: &mut ClockSync,
 ) -> Result<(), anyhow::Error> {
-    // Gateway sends u64 unix timestamp as be bytes
-    let mut buf = [0u8; 8];
+    let mut buf = [0u8; 64];
+    let request = postcard::to_slice(&Message::TimeSyncRequest, &mut buf)
+        .map_err(|e| anyhow!("Failed to postcard serialize the time request: {e}"))?;
+    let len = tp
+        .write_message(request, noise_buffer)
+        .map_err(|e| anyhow!("Failed to noise encrypt the time request: {e}"))?;
     // Request time
     let t1 = Instant::now();
-    send(socket, &[]).await?;
+    send(socket, &noise_buffer[..len]).await?;
 
     let len = recv(socket, noise_buffer).await?;
     let elapsed = t1.elapsed();
-    tp.read_message(&noise_buffer[..len], &mut buf)
-        .map_err(|e| anyhow!("Failed to read unix timestamp: {e}"))?;
+    let len = tp
+        .read_message(&noise_buffer[..len], &mut buf)
+        .map_err(|e| anyhow!("Failed to decrypt the time response: {e}"))?;
+    let timestamp = match postcard::from_bytes(&buf[..len]) {
+        Ok(Message::TimeSyncResponse { unix_ms }) => unix_ms,
+        Ok(Message::Incompatible { min, max }) => {
+            return Err(anyhow!(
+                "Gateway supports protocols {min}-{max}, this firmware speaks {PROTOCOL_VERSION}"
+            ));
+        }
+        _ => return Err(anyhow!("Expected a time response from the gateway")),
+    };
 
-    let timestamp = u64::from_be_bytes(buf);
     let delay = elapsed / 2;
     let ref_t = t1 + delay;
     let adjusted_timestamp = timestamp + delay.as_millis();
 
-    // Store the reference point
-    *time_reference = Some((ref_t, adjusted_timestamp));
+    // Store the reference point, kept across reconnects so the drift estimate carries over
+    clock.update(ref_t.as_millis(), adjusted_timestamp);
     log::info!("Network delay: {} ms", delay.as_millis());
     log::info!("Time synced! {adjusted_timestamp}");
     Ok(())
 }
 
+/// Readings sent but not yet acknowledged by the gateway, with the time they were last sent
+struct RetryBuffer {
+    entries: heapless::Vec<(RuuviRaw, Instant), RETRY_BUFFER_DEPTH>,
+}
+
+impl RetryBuffer {
+    const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    /// Track a sent reading, a resent one only gets its send time refreshed
+    fn sent(&mut self, pkt: &RuuviRaw) {
+        let now = Instant::now();
+        if let Some(entry) = self.entries.iter_mut().find(|(p, _)| p == pkt) {
+            entry.1 = now;
+            return;
+        }
+        if self.entries.is_full() {
+            let (dropped, _) = self.entries.remove(0);
+            metrics::reading_dropped();
+            log::warn!(
+                "Retry buffer full, dropping an unacknowledged reading of {:02X?}",
+                dropped.mac()
+            );
+        }
+        // Room was made above
+        let _ = self.entries.push((pkt.clone(), now));
+    }
+
+    fn acknowledge(&mut self, ack: Ack) {
+        self.entries.retain(|(pkt, _)| Ack::of(pkt) != ack);
+    }
+
+    /// When the longest waiting reading was sent
+    fn oldest(&self) -> Option<Instant> {
+        self.entries.iter().map(|(_, sent)| *sent).min()
+    }
+
+    fn unacknowledged(&self) -> heapless::Vec<RuuviRaw, RETRY_BUFFER_DEPTH> {
+        self.entries.iter().map(|(pkt, _)| pkt.clone()).collect()
+    }
+}
+
+/// Serializes and encrypts a message into `tx_buffer`, returns the frame's length.
+/// Errors are logged.
+fn encode(
+    message: &Message,
+    tp: &RefCell<TransportState>,
+    postcard_buf: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Option<usize> {
+    // Serialize it with postcard
+    let payload = try_continue!(
+        postcard::to_slice(message, postcard_buf),
+        "Failed to postcard serialize a message",
+        return None
+    );
+
+    // Encrypt serialized data
+    let len = try_continue!(
+        tp.borrow_mut().write_message(payload, tx_buffer),
+        "Failed to noise encrypt the message",
+        return None
+    );
+    Some(len)
+}
+
+/// Queues up to [`MAX_BATCH`] readings in one message and tracks them until acknowledged
+async fn send_readings(
+    readings: &[RuuviRaw],
+    retry: &RefCell<RetryBuffer>,
+    messages: Sender<'_, NoopRawMutex, Message<'static>, MESSAGE_QUEUE_DEPTH>,
+) {
+    let message = match readings {
+        [pkt] => Message::Measurement(pkt.clone()),
+        // Callers never pass more than a batch
+        _ => Message::Batch(heapless::Vec::from_slice(readings).unwrap()),
+    };
+    let mut tracked = retry.borrow_mut();
+    readings.iter().for_each(|pkt| tracked.sent(pkt));
+    drop(tracked);
+    messages.send(message).await;
+}
+
+/// Timestamps packets and batches them into messages for the write stage.
+/// Readings the previous connection didn't get acknowledged go first.
+async fn batch_stage(
+    receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
+    schedule: &mut Schedule,
+    clock: &Cell<ClockSync>,
+    retry: &RefCell<RetryBuffer>,
+    messages: Sender<'_, NoopRawMutex, Message<'static>, MESSAGE_QUEUE_DEPTH>,
+) {
+    let unacknowledged = retry.borrow().unacknowledged();
+    if !unacknowledged.is_empty() {
+        log::info!("Resending {} unacknowledged readings", unacknowledged.len());
+    }
+    for batch in unacknowledged.chunks(MAX_BATCH) {
+        send_readings(batch, retry, messages).await;
+    }
+
+    // Compute timestamp based on the latest sync, corrected for drift
+    let timestamped = |(mut pkt, t): (RuuviRaw, Instant)| {
+        pkt.set_timestamp(clock.get().gateway_time(t.as_millis()));
+        pkt
+    };
+    loop {
+        // Receive RuuviRawV2 from the channel, batched into this listener's slot
+        schedule.sync(clock.get());
+        let mut batch = heapless::Vec::<RuuviRaw, MAX_BATCH>::new();
+        let _ = batch.push(timestamped(schedule.next(&receiver).await));
+        // Readings queued meanwhile share the message
+        while !batch.is_full()
+            && let Ok(received) = receiver.try_receive()
+        {
+            let _ = batch.push(timestamped(received));
+        }
+
+        send_readings(&batch, retry, messages).await;
+    }
+}
+
+/// Encrypts the queued messages and writes them to the socket, returns only when the
+/// connection fails. The only stage encrypting, so frames leave in nonce order. Rekeys
+/// the sending direction between two frames when the [`RekeyPolicy`] says so.
+async fn write_stage(
+    socket: &mut TcpWriter<'_>,
+    tp: &RefCell<TransportState>,
+    requested: &Cell<Option<Instant>>,
+    messages: Receiver<'_, NoopRawMutex, Message<'static>, MESSAGE_QUEUE_DEPTH>,
+    led_sender: Sender<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
+    backoff_ms: &mut u64,
+    postcard_buf: &mut [u8; 512],
+    tx_buffer: &mut [u8; 1024],
+) -> Result<(), anyhow::Error> {
+    let mut rekey = RekeyPolicy::new(tp.borrow().sending_nonce(), Instant::now().as_millis());
+    loop {
+        let nonce = tp.borrow().sending_nonce();
+        let message = if rekey.due(nonce, Instant::now().as_millis()) {
+            log::info!("Rekeying the session at message {nonce}");
+            Message::Rekey
+        } else {
+            messages.receive().await
+        };
+        let Some(len) = encode(&message, tp, postcard_buf, tx_buffer) else {
+            continue;
+        };
+        match message {
+            // Frames encrypted from here on use the new key, the gateway follows
+            // once it reads this one
+            Message::Rekey => {
+                let mut tp = tp.borrow_mut();
+                tp.rekey_outgoing();
+                rekey.rekeyed(tp.sending_nonce(), Instant::now().as_millis());
+            }
+            Message::TimeSyncRequest => requested.set(Some(Instant::now())),
+            _ => {}
+        }
+
+        // Send the encrypted data
+        let started = Instant::now();
+        if let Err(e) = send(socket, &tx_buffer[..len]).await {
+            coex::send_failed();
+            return Err(e);
+        }
+        coex::sent(started.elapsed());
+
+        if let Err(err) = led_sender.try_send(LedEvent::TcpOk) {
+            log::error!("Failed to send LedEvent to the channel! {err:?}");
+        }
+
+        // After successful send, reset
+        *backoff_ms = BASE_BACKOFF_MS;
+        diag::gateway_ok();
+        metrics::frame_sent();
+    }
+}
+
+/// Sends a heartbeat every [`HEARTBEAT_INTERVAL_SECS`], or telemetry and time
+/// re-sync requests when due. Telemetry is also sent once `telemetry` is signaled.
+/// The time reply is handled by the downlink stage.
+async fn control_stage(
+    telemetry: &Signal<NoopRawMutex, ()>,
+    messages: Sender<'_, NoopRawMutex, Message<'static>, MESSAGE_QUEUE_DEPTH>,
+) {
+    let mut next_sync = Instant::now() + Duration::from_secs(TIME_SYNC_INTERVAL_SECS);
+    // Reported right away, so the gateway learns why the listener last reset
+    let mut next_telemetry = Instant::now();
+    loop {
+        // Wakes at least once per heartbeat for as long as the session lives
+        watchdog::beat(Task::Sender);
+        let next_heartbeat = Instant::now() + Duration::from_secs(HEARTBEAT_INTERVAL_SECS);
+        let due = next_heartbeat.min(next_sync).min(next_telemetry);
+        if let Either::Second(()) = select(Timer::at(due), telemetry.wait()).await {
+            next_telemetry = Instant::now();
+        }
+
+        let now = Instant::now();
+        let message = if now >= next_sync {
+            next_sync = now + Duration::from_secs(TIME_SYNC_INTERVAL_SECS);
+            Message::TimeSyncRequest
+        } else if now >= next_telemetry {
+            next_telemetry = now + Duration::from_secs(TELEMETRY_INTERVAL_SECS);
+            Message::Telemetry(metrics::telemetry())
+        } else {
+            Message::Heartbeat
+        };
+        messages.send(message).await;
+    }
+}
+
+/// Fails once a reading has waited [`ACK_TIMEOUT_SECS`] for its acknowledgement,
+/// the socket is most likely dead without having noticed
+async fn ack_watchdog(retry: &RefCell<RetryBuffer>) -> Result<(), anyhow::Error> {
+    let timeout = Duration::from_secs(ACK_TIMEOUT_SECS);
+    loop {
+        Timer::after(Duration::from_secs(1)).await;
+        if retry
+            .borrow()
+            .oldest()
+            .is_some_and(|sent| sent.elapsed() > timeout)
+        {
+            return Err(anyhow!("No acknowledgement in {ACK_TIMEOUT_SECS}s"));
+        }
+    }
+}
+
+/// Carries out a command from the gateway, its changes last until reboot
+async fn apply(command: Command, telemetry: &Signal<NoopRawMutex, ()>) {
+    log::info!("Gateway command {command:?}");
+    match command {
+        Command::Scan(settings) => scanner::configure(settings),
+        Command::Led(on) => led::enable(on),
+        Command::LogLevel(level) => diag::set_log_level(level),
+        Command::TagFilter { allow, deny } => scanner::filter(&allow, &deny),
+        Command::Telemetry => telemetry.signal(()),
+        Command::Reboot => {
+            Timer::after(Duration::from_secs(REBOOT_DELAY_SECS)).await;
+            esp_hal::system::software_reset();
+        }
+    }
+}
+
+/// Answers firmware offers and chunks from the gateway, applies time re-syncs and
+/// commands and clears acknowledged readings, returns only when the connection fails
+async fn downlink_stage(
+    socket: &mut TcpReader<'_>,
+    tp: &RefCell<TransportState>,
+    clock: &Cell<ClockSync>,
+    requested: &Cell<Option<Instant>>,
+    telemetry: &Signal<NoopRawMutex, ()>,
+    retry: &RefCell<RetryBuffer>,
+    ota: &mut Ota,
+    rx_buffer: &mut [u8; 1024],
+    noise_buffer: &mut [u8; 1024],
+    messages: Sender<'_, NoopRawMutex, Message<'static>, MESSAGE_QUEUE_DEPTH>,
+) -> Result<(), anyhow::Error> {
+    let mut uplink = Some(ota.hello());
+    loop {
+        if let Some(uplink) = uplink.take() {
+            let complete = matches!(uplink, Uplink::Complete { .. });
+            messages.send(Message::OtaUplink(uplink)).await;
+
+            if complete {
+                log::info!("Rebooting into the new firmware");
+                Timer::after(Duration::from_secs(REBOOT_DELAY_SECS)).await;
+                esp_hal::system::software_reset();
+            }
+        }
+
+        let len = recv(socket, noise_buffer).await?;
+        let len = tp
+            .borrow_mut()
+            .read_message(&noise_buffer[..len], rx_buffer)
+            .map_err(|e| anyhow!("Failed to decrypt a gateway message: {e}"))?;
+        match postcard::from_bytes::<Message>(&rx_buffer[..len]) {
+            Ok(Message::Ack(acks)) => {
+                let mut retry = retry.borrow_mut();
+                acks.into_iter().for_each(|ack| retry.acknowledge(ack));
+            }
+            Ok(Message::OtaDownlink(downlink)) => uplink = ota.handle(downlink),
+            Ok(Message::Rekey) => tp.borrow_mut().rekey_incoming(),
+            Ok(Message::ScanSettings(settings)) => scanner::configure(settings),
+            Ok(Message::Command(command)) => apply(command, telemetry).await,
+            Ok(Message::TimeSyncResponse { unix_ms }) => {
+                let Some(t1) = requested.take() else {
+                    log::warn!("Ignoring an unrequested time response");
+                    continue;
+                };
+                // Same half round trip estimate as the initial sync
+                let delay = t1.elapsed() / 2;
+                let mut synced = clock.get();
+                synced.update((t1 + delay).as_millis(), unix_ms + delay.as_millis());
+                clock.set(synced);
+                log::info!(
+                    "Time re-synced, network delay {} ms, drift {:.1} ppm",
+                    delay.as_millis(),
+                    synced.drift_ppm()
+                );
+            }
+            Ok(message) => log::warn!("Ignoring an unexpected gateway message {message:?}"),
+            Err(e) => log::warn!("Failed to parse a gateway message: {e}"),
+        }
+    }
+}
+
 #[embassy_executor::task]
 pub async fn run(
     stack: Stack<'static>,
-    receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), 16>,
+    receiver: Receiver<'static, NoopRawMutex, (RuuviRaw, Instant), PACKET_QUEUE_DEPTH>,
     gateway_config: GatewayConfig,
     rng: Rng,
-    led_sender: Sender<'static, NoopRawMutex, LedEvent, 16>,
+    led_sender: Sender<'static, NoopRawMutex, LedEvent, LED_QUEUE_DEPTH>,
+    flash: &'static Flash,
 ) {
     // Buffers
     let mut socket_rx_buffer = [0u8; 2048];
@@ -205,10 +564,31 @@ pub async fn run(
     let mut postcard_buf = [0u8; 512];
 
     let mut backoff_ms = BASE_BACKOFF_MS;
-    let server = (gateway_config.ip, gateway_config.port);
-    let mut time_reference: Option<(Instant, u64)> = None;
+    let mut failover = net::Failover::new(&gateway_config);
+    let mut clock = ClockSync::default();
+    let retry = RefCell::new(RetryBuffer::new());
+
+    // Stable across reboots, so the gateway can pin it
+    let static_key = loop {
+        let generate = || {
+            let params = PARAMS.parse()?;
+            let resolver = MyResolver::new(DefaultResolver, rng);
+            Builder::with_resolver(params, Box::new(resolver)).generate_keypair()
+        };
+        match flash.with(|flash| identity::load(flash, generate)) {
+            Ok(key) => break key,
+            Err(e) => {
+                log::error!("Failed to load the static key: {e}");
+                Timer::after(Duration::from_secs(1)).await;
+            }
+        }
+    };
+    diag::identity(static_key.public);
+    let mut ota = Ota::new(flash);
+    let mut schedule = Schedule::new(rng);
 
     loop {
+        watchdog::beat(Task::Sender);
         // Parse noise params
         let params = try_continue!(PARAMS.parse(), "Failed to parse noise params");
 
@@ -219,31 +599,44 @@ pub async fn run(
         // Create builder with custom resolver
         let builder = Builder::with_resolver(params, Box::new(custom_resolver));
 
-        // Generate local static key
-        let static_key =
-            try_continue!(builder.generate_keypair(), "Failed to generate keypair").private;
-
         // Build noise handshaker
         let builder = try_continue!(
-            builder.local_private_key(&static_key),
+            builder.local_private_key(&static_key.private),
             "Failed to add private key"
         );
         let builder = try_continue!(
             builder.psk(3, &gateway_config.auth),
             "Failed to specify PSK"
         );
+        let builder = try_continue!(builder.prologue(&[SUITE_ID]), "Failed to set prologue");
         let noise = try_continue!(builder.build_initiator(), "Failed to build initiator");
 
+        // Looked up on every connect, the gateway may have moved since
+        let endpoint = failover.current();
+        let server = match net::resolve_gateway(stack, &gateway_config, endpoint).await {
+            Ok(server) => server,
+            Err(e) => {
+                log::warn!("{e}; backoff {backoff_ms}ms");
+                diag::gateway_failed();
+                failover.failed();
+                Timer::after(Duration::from_millis(backoff_ms)).await;
+                backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+                continue;
+            }
+        };
+
         // Create TCP socket
         let mut socket = TcpSocket::new(stack, &mut socket_rx_buffer, &mut socket_tx_buffer);
         socket.set_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
 
         // Connect
-        log::info!("Trying to connect to: {}:{}", server.0, server.1);
-        match socket.connect(server).await {
+        log::info!("Trying to connect to: {server}");
+        match socket.connect((server.ip(), server.port())).await {
             Ok(_) => log::info!("TCP connected"),
             Err(e) => {
                 log::warn!("Connect error: {e:?}; backoff {backoff_ms}ms");
+                diag::gateway_failed();
+                failover.failed();
                 Timer::after(Duration::from_millis(backoff_ms)).await;
                 backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
                 continue;
@@ -254,6 +647,7 @@ pub async fn run(
         let mut tp = match noise_handshake(
             &mut socket,
             noise,
+            gateway_config.psk_name,
             &mut tx_buffer,
             &mut rx_buffer,
             &mut noise_buf,
@@ -266,61 +660,97 @@ pub async fn run(
             }
             Err(e) => {
                 log::warn!("Noise handshake error: {e}");
+                diag::gateway_failed();
+                failover.failed();
                 Timer::after(Duration::from_millis(backoff_ms)).await;
                 backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
                 continue;
             }
         };
 
-        try_continue!(
-            sync_time(&mut socket, &mut tp, &mut noise_buf, &mut time_reference).await,
-            "Failed to synchronize time"
+        // Also where an incompatible gateway refuses us, back off instead of hammering it
+        if let Err(e) = sync_time(&mut socket, &mut tp, &mut noise_buf, &mut clock).await {
+            log::warn!("Failed to synchronize time: {e}; backoff {backoff_ms}ms");
+            diag::gateway_failed();
+            failover.failed();
+            Timer::after(Duration::from_millis(backoff_ms)).await;
+            backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
+            continue;
+        }
+        failover.connected();
+
+        // Encode and encrypt the next packets while the previous frame is still being written,
+        // the gateway's OTA messages and time replies are read alongside
+        let tp = RefCell::new(tp);
+        let shared_clock = Cell::new(clock);
+        let requested = Cell::new(None);
+        let telemetry = Signal::new();
+        let (mut reader, mut writer) = socket.split();
+        let messages: Channel<NoopRawMutex, Message<'static>, MESSAGE_QUEUE_DEPTH> = Channel::new();
+        let batcher = batch_stage(
+            receiver,
+            &mut schedule,
+            &shared_clock,
+            &retry,
+            messages.sender(),
         );
-
-        'sending: loop {
-            // Receive RuuviRawV2 from the channel
-            receiver.ready_to_receive().await;
-            let (mut pkt, t) = receiver.receive().await;
-
-            // Compute timestamp based on the reference T
-            if let Some((ref_t, ref_ts)) = time_reference {
-                if t >= ref_t {
-                    let elapsed = t.saturating_duration_since(ref_t);
-                    pkt.set_timestamp(Some(ref_ts + elapsed.as_millis()));
-                } else {
-                    let elapsed = ref_t.saturating_duration_since(t);
-                    pkt.set_timestamp(Some(ref_ts - elapsed.as_millis()));
-                }
+        let writer = write_stage(
+            &mut writer,
+            &tp,
+            &requested,
+            messages.receiver(),
+            led_sender,
+            &mut backoff_ms,
+            &mut postcard_buf,
+            &mut tx_buffer,
+        );
+        let control = control_stage(&telemetry, messages.sender());
+        let watchdog = ack_watchdog(&retry);
+        let downlink = downlink_stage(
+            &mut reader,
+            &tp,
+            &shared_clock,
+            &requested,
+            &telemetry,
+            &retry,
+            &mut ota,
+            &mut rx_buffer,
+            &mut noise_buf,
+            messages.sender(),
+        );
+        let retry_primary = failover.retry_primary_due();
+        let ended = select4(
+            batcher,
+            writer,
+            downlink,
+            select3(control, watchdog, retry_primary),
+        )
+        .await;
+        match ended {
+            Either4::Second(Err(e)) => {
+                log::error!("Failed to send the encrypted message: {e}");
+                diag::gateway_failed();
             }
-
-            // Serialize it with postcard
-            let payload = try_continue!(
-                postcard::to_slice(&pkt, &mut postcard_buf),
-                "Failed to postcard serialize RuuviRawV2"
-            );
-
-            // Encrypt serialized data
-            let len = try_continue!(
-                tp.write_message(payload, &mut tx_buffer),
-                "Failed to noise encrypt the message"
-            );
-
-            // Send the encrypted data
-            try_continue!(
-                send(&mut socket, &tx_buffer[..len]).await,
-                "Failed to send the encrypted message",
-                break 'sending
-            );
-
-            if let Err(err) = led_sender.try_send(LedEvent::TcpOk) {
-                log::error!("Failed to send LedEvent to the channel! {err:?}");
+            Either4::Third(Err(e)) => {
+                log::error!("Failed to receive from the gateway: {e}");
+                diag::gateway_failed();
             }
-
-            // After successful send, reset
-            backoff_ms = BASE_BACKOFF_MS;
+            Either4::Fourth(Either3::Second(Err(e))) => {
+                log::error!("Gateway connection stalled: {e}");
+                diag::gateway_failed();
+            }
+            Either4::Fourth(Either3::Third(())) => failover.retry_primary(),
+            _ => {}
+        }
+        clock = shared_clock.get();
+        // Messages still queued belong to this session, their readings are resent
+        // from the retry buffer
+        if !messages.is_empty() {
+            log::warn!("Dropping {} queued messages", messages.len());
         }
 
         log::info!("Reconnecting after backoff {backoff_ms}ms");
+        metrics::reconnect();
         Timer::after(Duration::from_millis(backoff_ms)).await;
         backoff_ms = (backoff_ms * 2).min(MAX_BACKOFF_SECS * 1000);
     }
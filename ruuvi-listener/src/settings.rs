@@ -0,0 +1,126 @@
+//! Settings provisioned at runtime on top of the build time configuration.
+//! They're kept in the `settings` partition and read once at boot, so a
+//! change applies after a reboot.
+
+use crate::board::{Flash, with_partition};
+use crate::config::{GatewayConfig, WifiConfig};
+use anyhow::anyhow;
+use embassy_sync::once_lock::OnceLock;
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+use heapless::{String, Vec};
+use ruuvi_schema::protocol::ScanSettings;
+use serde::{Deserialize, Serialize};
+
+/// Label of the data partition holding the settings, see `partitions.csv`
+const PARTITION_LABEL: &str = "settings";
+/// Leads stored settings, erased flash reads as 0xFF
+const MAGIC: [u8; 4] = *b"RNS1";
+/// Magic and the length of the encoded settings
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const MAX_LEN: usize = 256;
+/// Gateways taken from a provisioned address list
+const MAX_GATEWAYS: usize = 4;
+
+static STORED: OnceLock<Settings> = OnceLock::new();
+static GATEWAY_HOSTS: OnceLock<Vec<&'static str, MAX_GATEWAYS>> = OnceLock::new();
+
+/// The unset ones keep the build time configuration
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub ssid: Option<String<32>>,
+    /// Pre-shared key of `ssid`, which is WPA2-Personal or open
+    pub password: Option<String<64>>,
+    /// Like `GATEWAY_IP`, the port of the hosts without one is `GATEWAY_PORT`
+    pub gateway: Option<String<96>>,
+    pub scan: Option<ScanSettings>,
+}
+
+impl Settings {
+    /// A provisioned network replaces the build time one
+    pub fn wifi(&'static self, config: WifiConfig) -> WifiConfig {
+        match &self.ssid {
+            Some(ssid) => WifiConfig {
+                ssid: ssid.as_str(),
+                password: self.password.as_deref().unwrap_or(""),
+                enterprise: None,
+            },
+            None => config,
+        }
+    }
+
+    /// Provisioned gateways replace the build time ones
+    pub fn gateway(&'static self, config: GatewayConfig) -> GatewayConfig {
+        let Some(gateway) = &self.gateway else {
+            return config;
+        };
+        let hosts = GATEWAY_HOSTS.get_or_init(|| {
+            gateway
+                .split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .take(MAX_GATEWAYS)
+                .collect()
+        });
+        if hosts.is_empty() {
+            return config;
+        }
+        GatewayConfig {
+            hosts: hosts.as_slice(),
+            ..config
+        }
+    }
+}
+
+/// Settings stored in the settings partition, none without a usable one
+pub fn load(flash: &Flash) -> &'static Settings {
+    STORED.get_or_init(|| match flash.with(read) {
+        Ok(Some(settings)) => {
+            log::info!(
+                "Using provisioned settings, network {:?}, gateway {:?}, scan {:?}",
+                settings.ssid,
+                settings.gateway,
+                settings.scan
+            );
+            settings
+        }
+        Ok(None) => Settings::default(),
+        Err(e) => {
+            log::error!("Failed to read the provisioned settings: {e}");
+            Settings::default()
+        }
+    })
+}
+
+/// Replaces the stored settings, they apply after a reboot
+pub fn store(flash: &Flash, settings: &Settings) -> Result<(), anyhow::Error> {
+    let mut record = [0u8; HEADER_LEN + MAX_LEN];
+    let len = postcard::to_slice(settings, &mut record[HEADER_LEN..])
+        .map_err(|e| anyhow!("Failed to encode the settings: {e}"))?
+        .len();
+    record[..MAGIC.len()].copy_from_slice(&MAGIC);
+    record[MAGIC.len()..HEADER_LEN].copy_from_slice(&(len as u16).to_le_bytes());
+    flash.with(|flash| {
+        with_partition(flash, PARTITION_LABEL, |region| {
+            region.write(0, &record[..HEADER_LEN + len])
+        })
+    })
+}
+
+fn read(flash: &mut FlashStorage<'static>) -> Result<Option<Settings>, anyhow::Error> {
+    let mut record = [0u8; HEADER_LEN + MAX_LEN];
+    with_partition(flash, PARTITION_LABEL, |region| region.read(0, &mut record))?;
+    if record[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    let len = usize::from(u16::from_le_bytes([
+        record[MAGIC.len()],
+        record[MAGIC.len() + 1],
+    ]));
+    let encoded = record[HEADER_LEN..]
+        .get(..len)
+        .ok_or_else(|| anyhow!("Stored settings of {len} bytes"))?;
+    postcard::from_bytes(encoded)
+        .map(Some)
+        .map_err(|e| anyhow!("Failed to decode the settings: {e}"))
+}
@@ -0,0 +1,21 @@
+//! Readings sent without Noise are authenticated with an HMAC-SHA256 of the
+//! auth key, which the gateway checks against its pre-shared keys.
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+/// Hex encoded HMAC-SHA256 of `body`
+pub fn sign(key: &[u8; 32], body: &[u8]) -> [u8; 64] {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC key");
+    mac.update(body);
+    let tag = mac.finalize().into_bytes();
+
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = [0u8; 64];
+    for (i, byte) in tag.iter().enumerate() {
+        hex[i * 2] = HEX[(byte >> 4) as usize];
+        hex[i * 2 + 1] = HEX[(byte & 0x0F) as usize];
+    }
+    hex
+}
@@ -0,0 +1,188 @@
+//! IPv6 address by stateless autoconfiguration. smoltcp ignores router
+//! advertisements, so they're read from a raw ICMPv6 socket: the first /64
+//! prefix open to autonomous configuration gets an address with the EUI-64
+//! interface identifier, the router becomes the default gateway and the RDNSS
+//! servers are used for DNS next to the IPv4 ones.
+
+use core::net::Ipv6Addr;
+use embassy_net::raw::{IpProtocol, IpVersion, PacketMetadata, RawSocket};
+use embassy_net::{ConfigV6, Ipv6Cidr, Stack, StaticConfigV6};
+use embassy_time::{Duration, Instant, WithTimeout};
+
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+const IPV6_HEADER_LEN: usize = 40;
+const NEXT_HEADER_ICMPV6: u8 = 58;
+/// Neighbour discovery messages only count with the hop limit untouched
+const HOP_LIMIT: u8 = 255;
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const OPTION_PREFIX: u8 = 3;
+const OPTION_RDNSS: u8 = 25;
+/// Autonomous address configuration flag of a prefix option
+const FLAG_AUTONOMOUS: u8 = 0x40;
+/// Solicitations sent without an answer, then the periodic advertisements are awaited
+const SOLICITATIONS: u32 = 3;
+const SOLICITATION_INTERVAL: Duration = Duration::from_secs(4);
+
+#[embassy_executor::task]
+pub async fn run(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0u8; 64];
+    let mut packet = [0u8; 512];
+
+    let Ok(mac) = <[u8; 6]>::try_from(stack.hardware_address().as_bytes()) else {
+        log::error!("No MAC address to derive an IPv6 address from");
+        return;
+    };
+    let socket = RawSocket::new(
+        stack,
+        IpVersion::Ipv6,
+        IpProtocol::Icmpv6,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    stack.wait_link_up().await;
+    let mut solicitations = 0;
+    let mut expires: Option<Instant> = None;
+    loop {
+        if expires.is_none() && solicitations < SOLICITATIONS {
+            socket.send(&router_solicitation()).await;
+            solicitations += 1;
+        }
+        match socket
+            .recv(&mut packet)
+            .with_timeout(SOLICITATION_INTERVAL)
+            .await
+        {
+            Ok(Ok(len)) => {
+                if let Some((config, valid)) = parse_advertisement(&packet[..len], mac) {
+                    let current = stack.config_v6();
+                    if current.as_ref().map(|c| (c.address, c.gateway))
+                        != Some((config.address, config.gateway))
+                    {
+                        log::info!("Got IPv6: {}", config.address);
+                        stack.set_config_v6(ConfigV6::Static(config));
+                    }
+                    expires = Some(Instant::now() + valid);
+                }
+            }
+            Ok(Err(e)) => log::debug!("Failed to receive ICMPv6: {e:?}"),
+            Err(_) => (),
+        }
+        if expires.is_some_and(|at| Instant::now() >= at) {
+            log::warn!("IPv6 prefix expired without a new router advertisement");
+            stack.set_config_v6(ConfigV6::None);
+            expires = None;
+            solicitations = 0;
+        }
+    }
+}
+
+/// Router solicitation from the unspecified address, which carries no options
+fn router_solicitation() -> [u8; IPV6_HEADER_LEN + 8] {
+    let mut packet = [0u8; IPV6_HEADER_LEN + 8];
+    packet[0] = 0x60;
+    packet[4..6].copy_from_slice(&8u16.to_be_bytes());
+    packet[6] = NEXT_HEADER_ICMPV6;
+    packet[7] = HOP_LIMIT;
+    packet[24..40].copy_from_slice(&ALL_ROUTERS.octets());
+    packet[IPV6_HEADER_LEN] = ROUTER_SOLICITATION;
+    let checksum = checksum(
+        Ipv6Addr::UNSPECIFIED,
+        ALL_ROUTERS,
+        &packet[IPV6_HEADER_LEN..],
+    );
+    packet[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// ICMPv6 checksum, over the pseudo header and the message
+fn checksum(source: Ipv6Addr, destination: Ipv6Addr, message: &[u8]) -> u16 {
+    let length = (message.len() as u32).to_be_bytes();
+    let next_header = [0, 0, 0, NEXT_HEADER_ICMPV6];
+    let mut sum = [
+        &source.octets()[..],
+        &destination.octets(),
+        &length,
+        &next_header,
+        message,
+    ]
+    .iter()
+    .flat_map(|part| part.chunks(2))
+    .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+    .sum::<u32>();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Address, router and DNS servers from a router advertisement, with how
+/// long the prefix stays valid
+fn parse_advertisement(packet: &[u8], mac: [u8; 6]) -> Option<(StaticConfigV6, Duration)> {
+    let header = packet.get(..IPV6_HEADER_LEN)?;
+    let message = &packet[IPV6_HEADER_LEN..];
+    let router = Ipv6Addr::from(<[u8; 16]>::try_from(&header[8..24]).ok()?);
+    if header[6] != NEXT_HEADER_ICMPV6
+        || header[7] != HOP_LIMIT
+        || *message.first()? != ROUTER_ADVERTISEMENT
+        || !router.is_unicast_link_local()
+    {
+        return None;
+    }
+    let router_lifetime = u16::from_be_bytes(message.get(6..8)?.try_into().ok()?);
+
+    let mut config = StaticConfigV6 {
+        address: Ipv6Cidr::new(Ipv6Addr::UNSPECIFIED, 64),
+        gateway: (router_lifetime > 0).then_some(router),
+        dns_servers: Default::default(),
+    };
+    let mut valid: Option<Duration> = None;
+    let mut options = message.get(16..)?;
+    while let [kind, length, ..] = *options {
+        let option = options.get(..usize::from(length) * 8)?;
+        if option.is_empty() {
+            return None;
+        }
+        match kind {
+            OPTION_PREFIX if option.len() == 32 && valid.is_none() => {
+                let lifetime = u32::from_be_bytes(option[4..8].try_into().ok()?);
+                if option[2] == 64 && option[3] & FLAG_AUTONOMOUS != 0 && lifetime > 0 {
+                    let mut address = [0u8; 16];
+                    address[..8].copy_from_slice(&option[16..24]);
+                    address[8..].copy_from_slice(&interface_id(mac));
+                    config.address = Ipv6Cidr::new(Ipv6Addr::from(address), 64);
+                    valid = Some(Duration::from_secs(u64::from(lifetime)));
+                }
+            }
+            OPTION_RDNSS => {
+                for server in option.get(8..)?.chunks_exact(16) {
+                    let server = <[u8; 16]>::try_from(server).ok()?;
+                    let _ = config.dns_servers.push(Ipv6Addr::from(server));
+                }
+            }
+            _ => (),
+        }
+        options = &options[option.len()..];
+    }
+    Some((config, valid?))
+}
+
+/// Modified EUI-64 of the MAC address
+fn interface_id(mac: [u8; 6]) -> [u8; 8] {
+    [
+        mac[0] ^ 0x02,
+        mac[1],
+        mac[2],
+        0xff,
+        0xfe,
+        mac[3],
+        mac[4],
+        mac[5],
+    ]
+}
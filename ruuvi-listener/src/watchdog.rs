@@ -0,0 +1,115 @@
+//! Supervision of the scanner and the sender. Each checks in with [`beat`], and
+//! one that stays silent for [`TASK_STALL_SECS`], like on a hung BLE controller,
+//! gets the listener reset on purpose. The hardware watchdog covers the rest, it
+//! fires once the supervisor itself stops running. A panic resets the listener
+//! as well, once the backtrace is printed.
+
+use crate::config::{TASK_STALL_SECS, WATCHDOG_TIMEOUT_SECS};
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_sync::once_lock::OnceLock;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rtc_cntl::{Rwdt, RwdtStage, SocResetReason};
+use ruuvi_schema::protocol::ResetReason;
+
+/// The hardware watchdog is fed this often while every task checks in
+const FEED_INTERVAL: Duration = Duration::from_secs(5);
+/// Marks a cause in [`CAUSE`], anything else there is left over from power on
+const CAUSE_MAGIC: u32 = 0x5354_4C44;
+const CAUSE_PANIC: u32 = 0xFF;
+
+/// Doubles as the [`CAUSE`] of the reset a stall leads to
+#[derive(Debug, Clone, Copy)]
+pub enum Task {
+    Scanner,
+    Sender,
+}
+
+impl Task {
+    const ALL: [Task; 2] = [Task::Scanner, Task::Sender];
+}
+
+/// Seconds since boot of each task's last check in
+static BEATS: [AtomicU32; Task::ALL.len()] = [const { AtomicU32::new(0) }; Task::ALL.len()];
+static LAST_RESET: OnceLock<ResetReason> = OnceLock::new();
+
+/// Magic and why the firmware reset itself, kept across the reset
+#[esp_hal::ram(unstable(rtc_fast, persistent))]
+static mut CAUSE: [u32; 2] = [0; 2];
+
+/// The task is making progress
+pub fn beat(task: Task) {
+    BEATS[task as usize].store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+}
+
+/// Why the listener last started
+pub fn last_reset() -> ResetReason {
+    *LAST_RESET.get_or_init(|| {
+        // Read once and cleared, a later reset for a firmware update isn't a stall
+        let [magic, cause] = unsafe { (&raw const CAUSE).read_volatile() };
+        unsafe { (&raw mut CAUSE).write_volatile([0; 2]) };
+        let cause = (magic == CAUSE_MAGIC).then_some(cause);
+
+        match esp_hal::system::reset_reason() {
+            Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+            Some(SocResetReason::CoreSw | SocResetReason::CpuSw) => match cause {
+                Some(CAUSE_PANIC) => ResetReason::Panic,
+                Some(task) if task == Task::Scanner as u32 => ResetReason::ScannerStalled,
+                Some(task) if task == Task::Sender as u32 => ResetReason::SenderStalled,
+                _ => ResetReason::Software,
+            },
+            Some(
+                SocResetReason::CoreMwdt0
+                | SocResetReason::CoreMwdt1
+                | SocResetReason::CoreRtcWdt
+                | SocResetReason::CpuMwdt0
+                | SocResetReason::CpuMwdt1
+                | SocResetReason::CpuRtcWdt
+                | SocResetReason::SysRtcWdt
+                | SocResetReason::SysSuperWdt,
+            ) => ResetReason::Watchdog,
+            Some(SocResetReason::SysBrownOut) => ResetReason::Brownout,
+            Some(reason) => ResetReason::Other(reason as u8),
+            None => ResetReason::Unknown,
+        }
+    })
+}
+
+/// Feeds the hardware watchdog for as long as every task checks in within
+/// [`TASK_STALL_SECS`], resets the listener otherwise
+#[embassy_executor::task]
+pub async fn supervise(mut rwdt: Rwdt) {
+    let now = Instant::now().as_secs() as u32;
+    for beat in &BEATS {
+        beat.store(now, Ordering::Relaxed);
+    }
+    rwdt.set_timeout(
+        RwdtStage::Stage0,
+        esp_hal::time::Duration::from_secs(WATCHDOG_TIMEOUT_SECS),
+    );
+    rwdt.enable();
+    log::info!("Watchdog armed, tasks stall after {TASK_STALL_SECS}s");
+
+    loop {
+        Timer::after(FEED_INTERVAL).await;
+        let now = Instant::now().as_secs() as u32;
+        for task in Task::ALL {
+            let silent = now.saturating_sub(BEATS[task as usize].load(Ordering::Relaxed));
+            if u64::from(silent) >= TASK_STALL_SECS {
+                log::error!("{task:?} hasn't checked in for {silent}s, resetting");
+                reset(task as u32);
+            }
+        }
+        rwdt.feed();
+    }
+}
+
+fn reset(cause: u32) -> ! {
+    unsafe { (&raw mut CAUSE).write_volatile([CAUSE_MAGIC, cause]) };
+    esp_hal::system::software_reset()
+}
+
+/// Called by esp-backtrace once the panic is printed, instead of halting
+#[unsafe(no_mangle)]
+fn custom_halt() -> ! {
+    reset(CAUSE_PANIC)
+}
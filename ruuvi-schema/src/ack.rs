@@ -0,0 +1,81 @@
+//! Acknowledgements of stored readings.
+//!
+//! Once readings are stored the gateway answers with up to [`MAX_ACKS`]
+//! entries, each the MAC and the measurement sequence as given by
+//! [`RuuviRaw::measurement_seq`]. The listener keeps readings until they are
+//! acknowledged and resends the rest after a reconnect.
+//!
+//! Sessions without the [`Message`](crate::protocol::Message) envelope get
+//! an [`ACK_FRAME`] followed by entries of [`ACK_LEN`] bytes, the sequence
+//! big endian.
+
+use crate::RuuviRaw;
+use serde::{Deserialize, Serialize};
+
+/// Leading byte of every acknowledgement frame
+pub const ACK_FRAME: u8 = 0xF2;
+pub const ACK_LEN: usize = 10;
+/// Entries per frame, keeps frames within the listener's 1024 byte buffer
+pub const MAX_ACKS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Ack {
+    pub mac: [u8; 6],
+    pub seq: u32,
+}
+
+impl Ack {
+    pub fn of(raw: &RuuviRaw) -> Self {
+        Self {
+            mac: raw.mac(),
+            seq: raw.measurement_seq(),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; ACK_LEN] {
+        let mut bytes = [0u8; ACK_LEN];
+        bytes[..6].copy_from_slice(&self.mac);
+        bytes[6..].copy_from_slice(&self.seq.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; ACK_LEN]) -> Self {
+        let [m0, m1, m2, m3, m4, m5, s0, s1, s2, s3] = *bytes;
+        Self {
+            mac: [m0, m1, m2, m3, m4, m5],
+            seq: u32::from_be_bytes([s0, s1, s2, s3]),
+        }
+    }
+}
+
+/// Entries of a frame payload after [`ACK_FRAME`], a trailing partial entry is ignored
+pub fn decode(payload: &[u8]) -> impl Iterator<Item = Ack> + '_ {
+    payload
+        .chunks_exact(ACK_LEN)
+        .map(|chunk| Ack::from_bytes(chunk.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ACK_LEN, Ack, decode};
+
+    #[test]
+    fn decodes_encoded_entries() {
+        let acks = [
+            Ack {
+                mac: [1, 2, 3, 4, 5, 6],
+                seq: 65_535,
+            },
+            Ack {
+                mac: [0xAA; 6],
+                seq: 0x0102_0304,
+            },
+        ];
+        let mut payload = [0u8; 2 * ACK_LEN + 3];
+        for (chunk, ack) in payload.chunks_mut(ACK_LEN).zip(acks) {
+            chunk.copy_from_slice(&ack.to_bytes());
+        }
+        assert_eq!(payload[16..20], [1, 2, 3, 4]);
+        assert!(decode(&payload).eq(acks));
+    }
+}
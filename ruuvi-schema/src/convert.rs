@@ -0,0 +1,514 @@
+//! Raw readings in engineering units. Needs `std` or `libm` for the
+//! humidity math.
+
+use crate::{RuuviRawE1, RuuviRawV1, RuuviRawV2, RuuviRawV6};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+fn exp(x: f64) -> f64 {
+    x.exp()
+}
+
+#[cfg(feature = "std")]
+fn ln(x: f64) -> f64 {
+    x.ln()
+}
+
+#[cfg(feature = "std")]
+fn sqrt(x: f64) -> f64 {
+    x.sqrt()
+}
+
+#[cfg(feature = "std")]
+fn atan(x: f64) -> f64 {
+    x.atan()
+}
+
+#[cfg(not(feature = "std"))]
+use libm::{atan, exp, log as ln, sqrt};
+
+/// Saturation vapour pressure formula behind the derived humidity values
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HumidityFormula {
+    /// Arden Buck (1996), the default
+    #[default]
+    Buck,
+    /// August-Roche-Magnus with the Alduchov and Eskridge (1996) coefficients
+    Magnus,
+    /// Hyland and Wexler (1983), as used by ASHRAE
+    HylandWexler,
+}
+
+impl HumidityFormula {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Buck => "buck",
+            Self::Magnus => "magnus",
+            Self::HylandWexler => "hyland_wexler",
+        }
+    }
+
+    /// Saturation vapour pressure over water in Pa
+    pub fn saturation_pressure(self, temp: f64) -> f64 {
+        match self {
+            // https://en.wikipedia.org/wiki/Arden_Buck_equation
+            Self::Buck => 611.21 * exp((18.678 - (temp / 234.5)) * (temp / (257.14 + temp))),
+            // https://en.wikipedia.org/wiki/Clausius%E2%80%93Clapeyron_relation#August%E2%80%93Roche%E2%80%93Magnus_approximation
+            Self::Magnus => 610.94 * exp(MAGNUS_A * temp / (MAGNUS_B + temp)),
+            // ASHRAE Handbook Fundamentals, saturation over liquid water
+            Self::HylandWexler => {
+                let t = temp + 273.15;
+                exp(
+                    -5.800_220_6e3 / t + 1.391_499_3 - 4.864_023_9e-2 * t + 4.176_476_8e-5 * t * t
+                        - 1.445_209_3e-8 * t * t * t
+                        + 6.545_967_3 * ln(t),
+                )
+            }
+        }
+    }
+}
+
+/// Coefficients of the Magnus formula
+const MAGNUS_A: f64 = 17.625;
+const MAGNUS_B: f64 = 243.04;
+
+/// Used for the enhancement factor when a reading has no pressure
+const STANDARD_PRESSURE: f64 = 101_325.0;
+
+/// Moist air holds slightly more water vapour than the saturation pressure
+/// over pure water suggests, by 0.4 % or so at sea level. Buck (1996), for
+/// pressure in Pa.
+pub fn enhancement_factor(temp: f64, pressure: f64) -> f64 {
+    let hpa = pressure / 100.0;
+    1.0 + 1e-4 * (7.2 + hpa * (0.0320 + 5.9e-6 * temp * temp))
+}
+
+/// Absolute humidity in g/m³. `pressure` in Pa goes into the enhancement
+/// factor, standard pressure is assumed without one.
+pub fn abs_humidity(
+    formula: HumidityFormula,
+    temp: f32,
+    rel_humidity: f32,
+    pressure: Option<u32>,
+) -> f64 {
+    let temp = f64::from(temp);
+    let pressure = pressure.map_or(STANDARD_PRESSURE, f64::from);
+    // Actual vapour pressure in Pa
+    let pa = enhancement_factor(temp, pressure)
+        * formula.saturation_pressure(temp)
+        * (f64::from(rel_humidity) / 100.0);
+    2.167 * pa / (temp + 273.15)
+}
+
+/// Dew point in °C, the temperature the actual vapour pressure saturates at.
+/// The enhancement factor is the same on both sides and cancels out.
+pub fn dew_point(formula: HumidityFormula, temp: f32, rel_humidity: f32) -> f64 {
+    // https://en.wikipedia.org/wiki/Tetens_equation
+    let temp = f64::from(temp);
+    let gamma = ln(f64::from(rel_humidity) / 100.0) + (MAGNUS_A * temp) / (MAGNUS_B + temp);
+    let magnus = (MAGNUS_B * gamma) / (MAGNUS_A - gamma);
+    if formula == HumidityFormula::Magnus {
+        return magnus;
+    }
+    // The others have no closed form inverse, refine the Magnus estimate with
+    // Newton's method on the logarithm of the saturation pressure
+    let target = ln(formula.saturation_pressure(temp) * f64::from(rel_humidity) / 100.0);
+    let ln_es = |t: f64| ln(formula.saturation_pressure(t));
+    let mut dew_point = magnus;
+    for _ in 0..4 {
+        let slope = (ln_es(dew_point + 0.01) - ln_es(dew_point - 0.01)) / 0.02;
+        dew_point -= (ln_es(dew_point) - target) / slope;
+    }
+    dew_point
+}
+
+/// Absolute humidity and dew point, when both inputs are available
+pub fn humidity_derived(
+    formula: HumidityFormula,
+    temp: Option<f32>,
+    rel_humidity: Option<f32>,
+    pressure: Option<u32>,
+) -> (Option<f64>, Option<f64>) {
+    let (Some(temp), Some(rel_humidity)) = (temp, rel_humidity) else {
+        return (None, None);
+    };
+    (
+        Some(abs_humidity(formula, temp, rel_humidity, pressure)),
+        Some(dew_point(formula, temp, rel_humidity)),
+    )
+}
+
+/// How warm or muggy a temperature and relative humidity feel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comfort {
+    /// °C, apparent temperature of the US National Weather Service
+    pub heat_index: f64,
+    /// °C, Canadian humidex
+    pub humidex: f64,
+    /// °C, psychrometric wet-bulb temperature at sea level
+    pub wet_bulb_temp: f64,
+    /// kPa, how much more water vapour the air could hold
+    pub vapour_pressure_deficit: f64,
+}
+
+impl Comfort {
+    pub fn new(formula: HumidityFormula, temp: f32, rel_humidity: f32) -> Self {
+        let (temp, rel_humidity) = (f64::from(temp), f64::from(rel_humidity));
+        let saturation = formula.saturation_pressure(temp);
+        let vapour = saturation * rel_humidity / 100.0;
+        Self {
+            heat_index: heat_index(temp, rel_humidity),
+            // Environment Canada defines it with the vapour pressure in hPa
+            humidex: temp + 0.5555 * (vapour / 100.0 - 10.0),
+            wet_bulb_temp: wet_bulb_temp(temp, rel_humidity),
+            vapour_pressure_deficit: (saturation - vapour) / 1000.0,
+        }
+    }
+}
+
+/// https://www.wpc.ncep.noaa.gov/html/heatindex_equation.shtml, in °C
+fn heat_index(temp: f64, rel_humidity: f64) -> f64 {
+    let t = temp * 1.8 + 32.0;
+    let rh = rel_humidity;
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        // Rothfusz regression
+        let mut hi = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+            - 0.224_755_41 * t * rh
+            - 6.837_83e-3 * t * t
+            - 5.481_717e-2 * rh * rh
+            + 1.228_74e-3 * t * t * rh
+            + 8.528_2e-4 * t * rh * rh
+            - 1.99e-6 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            hi -= (13.0 - rh) / 4.0 * sqrt((17.0 - (t - 95.0).abs()) / 17.0);
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            hi += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+        hi
+    };
+    (fahrenheit - 32.0) / 1.8
+}
+
+/// Stull (2011), within 0.3 °C for 5 to 99 %RH and -20 to 50 °C
+fn wet_bulb_temp(temp: f64, rel_humidity: f64) -> f64 {
+    let rh = rel_humidity;
+    temp * atan(0.151_977 * sqrt(rh + 8.313_659)) + atan(temp + rh) - atan(rh - 1.676_331)
+        + 0.003_918_38 * rh * sqrt(rh) * atan(0.023_101 * rh)
+        - 4.686_035
+}
+
+/// Breakpoints of the hourly background CAQI, concentrations in µg/m³ at
+/// index 0, 25, 50, 75 and 100
+const CAQI_PM2_5: [f64; 5] = [0.0, 15.0, 30.0, 55.0, 110.0];
+const CAQI_PM10: [f64; 5] = [0.0, 25.0, 50.0, 90.0, 180.0];
+
+/// European Common Air Quality Index of the particulate matter, the worse
+/// of the PM2.5 and PM10 sub-indices. Above 100 is very high pollution.
+pub fn caqi(pm2_5: Option<f32>, pm10_0: Option<f32>) -> Option<f64> {
+    let sub_index = |value: f32, breakpoints: &[f64; 5]| {
+        let value = f64::from(value).max(0.0);
+        // Past the last breakpoint the top band is extrapolated
+        let band = breakpoints[1..4]
+            .iter()
+            .position(|&b| value < b)
+            .unwrap_or(3);
+        let (low, high) = (breakpoints[band], breakpoints[band + 1]);
+        25.0 * (band as f64 + (value - low) / (high - low))
+    };
+    let pm2_5 = pm2_5.map(|v| sub_index(v, &CAQI_PM2_5));
+    let pm10_0 = pm10_0.map(|v| sub_index(v, &CAQI_PM10));
+    match (pm2_5, pm10_0) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+// Temperature in 0.005 degrees
+fn temp(raw: Option<i16>) -> Option<f32> {
+    raw.map(|t| t as f32 * 0.005)
+}
+
+// Humidity in 0.0025%. 0-163.83% range, though realistically 0-100%
+fn rel_humidity(raw: Option<u16>) -> Option<f32> {
+    raw.map(|h| f32::min(h as f32 * 0.0025, 100.0))
+}
+
+// Pressure offset -50 000 Pa
+fn abs_pressure(raw: Option<u16>) -> Option<u32> {
+    raw.map(|p| p as u32 + 50_000)
+}
+
+// Resolution 0.1/bit, range 0 ... 1000. 16bit unsigned
+fn pm(raw: Option<u16>) -> Option<f32> {
+    raw.map(|v| f32::min(v as f32 * 0.1, 1000.0))
+}
+
+// CO2 concentration, ppm. Resolution 1/bit, range 0 ... 40000. 16bit unsigned
+fn co2(raw: Option<u16>) -> Option<u16> {
+    raw.map(|v| u16::min(v, 40_000))
+}
+
+// VOC and NOX index, unitless. Resolution 1 / bit, range 0 ... 500. 9 bit unsigned
+fn index(raw: Option<u16>) -> Option<u16> {
+    raw.map(|v| u16::min(v, 500))
+}
+
+/// Values of a RuuviTag. Fields the tag reports as not available, or its
+/// format doesn't carry, are `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagValues {
+    /// °C
+    pub temp: Option<f32>,
+    pub dew_point_temp: Option<f64>,
+    /// %RH
+    pub rel_humidity: Option<f32>,
+    /// g/m³
+    pub abs_humidity: Option<f64>,
+    /// Pa
+    pub abs_pressure: Option<u32>,
+    /// mG
+    pub acc_x: Option<i16>,
+    pub acc_y: Option<i16>,
+    pub acc_z: Option<i16>,
+    /// V
+    pub battery_voltage: Option<f32>,
+    /// dBm
+    pub tx_power: Option<i8>,
+    pub movement_counter: Option<u8>,
+}
+
+impl TagValues {
+    /// The humidity values derived with `formula` instead of [`HumidityFormula::Buck`]
+    pub fn with_formula(self, formula: HumidityFormula) -> Self {
+        let (abs_humidity, dew_point_temp) =
+            humidity_derived(formula, self.temp, self.rel_humidity, self.abs_pressure);
+        Self {
+            abs_humidity,
+            dew_point_temp,
+            ..self
+        }
+    }
+}
+
+impl From<&RuuviRawV2> for TagValues {
+    fn from(raw: &RuuviRawV2) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-5-rawv2
+        let temp = temp(raw.valid_temp());
+        let rel_humidity = rel_humidity(raw.valid_humidity());
+        let abs_pressure = abs_pressure(raw.valid_pressure());
+        let (abs_humidity, dew_point_temp) =
+            humidity_derived(HumidityFormula::Buck, temp, rel_humidity, abs_pressure);
+        let [acc_x, acc_y, acc_z] = raw.valid_acc();
+        Self {
+            temp,
+            dew_point_temp,
+            rel_humidity,
+            abs_humidity,
+            abs_pressure,
+            acc_x,
+            acc_y,
+            acc_z,
+            battery_voltage: raw.valid_battery_mv().map(|mv| mv as f32 / 1000.0),
+            tx_power: raw.valid_tx_power_dbm(),
+            movement_counter: raw.valid_movement_counter(),
+        }
+    }
+}
+
+impl From<&RuuviRawV1> for TagValues {
+    fn from(raw: &RuuviRawV1) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-3-rawv1
+        // Temperature in 0.01 degrees
+        let temp = raw.temp_centi() as f32 * 0.01;
+        // Humidity in 0.5%. 0-127.5% range, though realistically 0-100%
+        let rel_humidity = f32::min(raw.humidity as f32 * 0.5, 100.0);
+        let abs_pressure = abs_pressure(Some(raw.pressure));
+        let (abs_humidity, dew_point_temp) = humidity_derived(
+            HumidityFormula::Buck,
+            Some(temp),
+            Some(rel_humidity),
+            abs_pressure,
+        );
+        Self {
+            temp: Some(temp),
+            dew_point_temp,
+            rel_humidity: Some(rel_humidity),
+            abs_humidity,
+            abs_pressure,
+            acc_x: Some(raw.acc_x),
+            acc_y: Some(raw.acc_y),
+            acc_z: Some(raw.acc_z),
+            battery_voltage: Some(raw.battery_mv as f32 / 1000.0),
+            tx_power: None,
+            movement_counter: None,
+        }
+    }
+}
+
+/// Values of a Ruuvi Air. Fields the device reports as not available, or its
+/// format doesn't carry, are `None`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AirValues {
+    /// °C
+    pub temp: Option<f32>,
+    pub dew_point_temp: Option<f64>,
+    /// %RH
+    pub rel_humidity: Option<f32>,
+    /// g/m³
+    pub abs_humidity: Option<f64>,
+    /// Pa
+    pub abs_pressure: Option<u32>,
+    /// µg/m³
+    pub pm1_0: Option<f32>,
+    pub pm2_5: Option<f32>,
+    pub pm4_0: Option<f32>,
+    pub pm10_0: Option<f32>,
+    /// ppm
+    pub co2: Option<u16>,
+    pub voc_index: Option<u16>,
+    pub nox_index: Option<u16>,
+    /// lux
+    pub luminosity: Option<f32>,
+}
+
+impl AirValues {
+    /// The humidity values derived with `formula` instead of [`HumidityFormula::Buck`]
+    pub fn with_formula(self, formula: HumidityFormula) -> Self {
+        let (abs_humidity, dew_point_temp) =
+            humidity_derived(formula, self.temp, self.rel_humidity, self.abs_pressure);
+        Self {
+            abs_humidity,
+            dew_point_temp,
+            ..self
+        }
+    }
+}
+
+impl From<&RuuviRawE1> for AirValues {
+    fn from(raw: &RuuviRawE1) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-e1
+        let temp = temp(raw.valid_temp());
+        let rel_humidity = rel_humidity(raw.valid_humidity());
+        let abs_pressure = abs_pressure(raw.valid_pressure());
+        let (abs_humidity, dew_point_temp) =
+            humidity_derived(HumidityFormula::Buck, temp, rel_humidity, abs_pressure);
+        let [pm1_0, pm2_5, pm4_0, pm10_0] = raw.valid_pm().map(pm);
+        Self {
+            temp,
+            dew_point_temp,
+            rel_humidity,
+            abs_humidity,
+            abs_pressure,
+            pm1_0,
+            pm2_5,
+            pm4_0,
+            pm10_0,
+            co2: co2(raw.valid_co2()),
+            voc_index: index(raw.valid_voc_index()),
+            nox_index: index(raw.valid_nox_index()),
+            // 0.01 lux, range 0 ... 144284
+            luminosity: raw
+                .valid_luminosity()
+                .map(|v| f32::min(v as f32 * 0.01, 144_284.0)),
+        }
+    }
+}
+
+impl From<&RuuviRawV6> for AirValues {
+    fn from(raw: &RuuviRawV6) -> Self {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-6
+        // Same scales as E1
+        let temp = temp(raw.valid_temp());
+        let rel_humidity = rel_humidity(raw.valid_humidity());
+        let abs_pressure = abs_pressure(raw.valid_pressure());
+        let (abs_humidity, dew_point_temp) =
+            humidity_derived(HumidityFormula::Buck, temp, rel_humidity, abs_pressure);
+        Self {
+            temp,
+            dew_point_temp,
+            rel_humidity,
+            abs_humidity,
+            abs_pressure,
+            pm1_0: None,
+            pm2_5: pm(raw.valid_pm2_5()),
+            pm4_0: None,
+            pm10_0: None,
+            co2: co2(raw.valid_co2()),
+            voc_index: index(raw.valid_voc_index()),
+            nox_index: index(raw.valid_nox_index()),
+            // Logarithmic, 0 ... 254 maps to 0 ... 65535 lux
+            luminosity: raw
+                .valid_luminosity()
+                .map(|v| (exp(f64::from(v) * (ln(65536.0) / 254.0)) - 1.0) as f32),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Comfort, HumidityFormula, abs_humidity, caqi, dew_point, enhancement_factor};
+
+    const FORMULAS: [HumidityFormula; 3] = [
+        HumidityFormula::Buck,
+        HumidityFormula::Magnus,
+        HumidityFormula::HylandWexler,
+    ];
+
+    #[test]
+    fn test_abs_humidity() {
+        for formula in FORMULAS {
+            let res = abs_humidity(formula, 22.2f32, 52.4125f32, Some(101_325));
+            assert!((res - 10.33).abs() < 0.02, "{formula:?} {res}");
+        }
+        // Thinner air at altitude holds a little less
+        let low = abs_humidity(HumidityFormula::Buck, 22.2, 52.4125, Some(80_000));
+        assert!(low < abs_humidity(HumidityFormula::Buck, 22.2, 52.4125, None));
+        let f = enhancement_factor(20.0, 101_325.0);
+        assert!((f - 1.0042).abs() < 0.0001, "{f}");
+    }
+
+    #[test]
+    fn test_dew_point() {
+        for formula in FORMULAS {
+            let res = dew_point(formula, 22.22f32, 52.234f32);
+            assert!((res - 12.0).abs() < 0.1, "{formula:?} {res}");
+            // Saturated air is at its dew point
+            let res = dew_point(formula, -5.0, 100.0);
+            assert!((res + 5.0).abs() < 0.001, "{formula:?} {res}");
+        }
+    }
+
+    #[test]
+    fn test_comfort() {
+        // NWS heat index chart, 90 °F and 60 % feel like 100 °F
+        let hot = Comfort::new(HumidityFormula::Buck, 32.22, 60.0);
+        assert!((hot.heat_index - 37.8).abs() < 0.3, "{hot:?}");
+        // 30 °C and a dew point of 15 °C make a humidex of 34
+        let rh = 100.0 * HumidityFormula::Buck.saturation_pressure(15.0)
+            / HumidityFormula::Buck.saturation_pressure(30.0);
+        let muggy = Comfort::new(HumidityFormula::Buck, 30.0, rh as f32);
+        assert!((muggy.humidex - 34.0).abs() < 0.5, "{muggy:?}");
+        // Stull's own example
+        let stull = Comfort::new(HumidityFormula::Buck, 20.0, 50.0);
+        assert!((stull.wet_bulb_temp - 13.7).abs() < 0.1, "{stull:?}");
+        assert!(
+            (stull.vapour_pressure_deficit - 1.17).abs() < 0.01,
+            "{stull:?}"
+        );
+        // Dry mild air feels a little cooler
+        let mild = Comfort::new(HumidityFormula::Buck, 20.0, 40.0);
+        assert!((mild.heat_index - 19.1).abs() < 0.1, "{mild:?}");
+    }
+
+    #[test]
+    fn test_caqi() {
+        assert_eq!(caqi(None, None), None);
+        assert_eq!(caqi(Some(15.0), None), Some(25.0));
+        assert_eq!(caqi(Some(10.0), Some(70.0)), Some(62.5));
+        assert_eq!(caqi(Some(165.0), Some(0.0)), Some(125.0));
+    }
+}
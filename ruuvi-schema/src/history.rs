@@ -0,0 +1,193 @@
+//! Records of the tag's internal measurement log, read over the Nordic UART
+//! service of a GATT connection.
+//!
+//! The log is requested with [`read_command`]. The tag answers with one
+//! 11 byte record per logged value followed by an end marker:
+//!
+//! | Byte | Content                                                  |
+//! |------|----------------------------------------------------------|
+//! | 0    | `0x3A`, environmental endpoint                           |
+//! | 1    | `0x30` temperature, `0x31` humidity, `0x32` pressure     |
+//! | 2    | `0x10`, log value write                                  |
+//! | 3-6  | Unix timestamp in seconds, big endian                    |
+//! | 7-10 | Value, big endian `i32`                                  |
+//!
+//! The end marker has `0x3A` in byte 1 and all ones in bytes 3-10.
+
+use serde::{Deserialize, Serialize};
+
+pub const RECORD_LEN: usize = 11;
+
+const ENVIRONMENTAL: u8 = 0x3A;
+const TEMPERATURE: u8 = 0x30;
+const HUMIDITY: u8 = 0x31;
+const PRESSURE: u8 = 0x32;
+const LOG_VALUE_WRITE: u8 = 0x10;
+const LOG_VALUE_READ: u8 = 0x11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LogKind {
+    /// 0.01 °C
+    Temperature,
+    /// 0.01 %RH
+    Humidity,
+    /// Pa
+    Pressure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub kind: LogKind,
+    /// Unix time in seconds, by the tag's clock
+    pub timestamp: u32,
+    pub value: i32,
+}
+
+impl LogRecord {
+    /// Value in °C, %RH or Pa
+    pub fn scaled(&self) -> f32 {
+        match self.kind {
+            LogKind::Temperature | LogKind::Humidity => self.value as f32 / 100.0,
+            LogKind::Pressure => self.value as f32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogEntry {
+    Record(LogRecord),
+    /// No more records follow
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogError {
+    /// Records are exactly [`RECORD_LEN`] bytes
+    Length(usize),
+    /// Not a log value write of the environmental endpoint
+    Header([u8; 3]),
+    UnknownKind(u8),
+}
+
+impl core::fmt::Display for LogError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Length(len) => write!(f, "log record of {len} bytes, expected {RECORD_LEN}"),
+            Self::Header(header) => write!(f, "unexpected log record header {header:02X?}"),
+            Self::UnknownKind(kind) => write!(f, "unknown log value type {kind:#04X}"),
+        }
+    }
+}
+
+/// Request the records logged since `start`. `now` sets the tag's clock, which
+/// timestamps the records.
+pub fn read_command(now: u32, start: u32) -> [u8; RECORD_LEN] {
+    let mut command = [0u8; RECORD_LEN];
+    command[..3].copy_from_slice(&[ENVIRONMENTAL, ENVIRONMENTAL, LOG_VALUE_READ]);
+    command[3..7].copy_from_slice(&now.to_be_bytes());
+    command[7..].copy_from_slice(&start.to_be_bytes());
+    command
+}
+
+/// Decode one notification of a log read
+pub fn decode(bytes: &[u8]) -> Result<LogEntry, LogError> {
+    let bytes: &[u8; RECORD_LEN] = bytes
+        .try_into()
+        .map_err(|_| LogError::Length(bytes.len()))?;
+    let [destination, source, op, ..] = *bytes;
+    if destination != ENVIRONMENTAL || op != LOG_VALUE_WRITE {
+        return Err(LogError::Header([destination, source, op]));
+    }
+    if source == ENVIRONMENTAL && bytes[3..].iter().all(|&b| b == 0xFF) {
+        return Ok(LogEntry::End);
+    }
+
+    let kind = match source {
+        TEMPERATURE => LogKind::Temperature,
+        HUMIDITY => LogKind::Humidity,
+        PRESSURE => LogKind::Pressure,
+        kind => return Err(LogError::UnknownKind(kind)),
+    };
+    let [_, _, _, t0, t1, t2, t3, v0, v1, v2, v3] = *bytes;
+    Ok(LogEntry::Record(LogRecord {
+        kind,
+        timestamp: u32::from_be_bytes([t0, t1, t2, t3]),
+        value: i32::from_be_bytes([v0, v1, v2, v3]),
+    }))
+}
+
+/// The values logged at one moment. The tag sends each value as its own
+/// record, consecutive records with the same timestamp are merged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LogSample {
+    pub timestamp: u32,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub pressure: Option<f32>,
+}
+
+impl LogSample {
+    /// Add a record, `false` if it belongs to another sample
+    pub fn merge(&mut self, record: &LogRecord) -> bool {
+        let empty =
+            self.temperature.is_none() && self.humidity.is_none() && self.pressure.is_none();
+        let slot = match record.kind {
+            LogKind::Temperature => &mut self.temperature,
+            LogKind::Humidity => &mut self.humidity,
+            LogKind::Pressure => &mut self.pressure,
+        };
+        if !empty && (record.timestamp != self.timestamp || slot.is_some()) {
+            return false;
+        }
+        self.timestamp = record.timestamp;
+        *slot = Some(record.scaled());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogEntry, LogError, LogKind, LogRecord, LogSample, decode, read_command};
+
+    #[test]
+    fn decodes_records_and_end_marker() {
+        let temperature = [
+            0x3A, 0x30, 0x10, 0x65, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0x06,
+        ];
+        let expected = LogRecord {
+            kind: LogKind::Temperature,
+            timestamp: 0x6500_0000,
+            value: -250,
+        };
+        assert_eq!(decode(&temperature), Ok(LogEntry::Record(expected)));
+        assert_eq!(expected.scaled(), -2.5);
+
+        let end = [
+            0x3A, 0x3A, 0x10, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        assert_eq!(decode(&end), Ok(LogEntry::End));
+        assert_eq!(decode(&end[..10]), Err(LogError::Length(10)));
+        assert_eq!(
+            decode(&read_command(1, 0)),
+            Err(LogError::Header([0x3A, 0x3A, 0x11]))
+        );
+    }
+
+    #[test]
+    fn merges_records_of_one_moment() {
+        let record = |kind, timestamp, value| LogRecord {
+            kind,
+            timestamp,
+            value,
+        };
+        let mut sample = LogSample::default();
+        assert!(sample.merge(&record(LogKind::Temperature, 10, 2150)));
+        assert!(sample.merge(&record(LogKind::Humidity, 10, 4520)));
+        assert!(sample.merge(&record(LogKind::Pressure, 10, 101_325)));
+        assert!(!sample.merge(&record(LogKind::Temperature, 20, 2200)));
+        assert_eq!(sample.timestamp, 10);
+        assert_eq!(sample.temperature, Some(21.5));
+        assert_eq!(sample.humidity, Some(45.2));
+        assert_eq!(sample.pressure, Some(101_325.0));
+    }
+}
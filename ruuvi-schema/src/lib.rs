@@ -1,8 +1,88 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod ack;
+#[cfg(any(feature = "std", feature = "libm"))]
+pub mod convert;
+pub mod history;
+pub mod ota;
+pub mod protocol;
+pub mod seq;
+pub mod time;
+
+use core::cmp::Ordering;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Why a manufacturer data payload couldn't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The payload is shorter than the format requires
+    TooShort {
+        format: u8,
+        len: usize,
+    },
+    UnknownFormat(u8),
+    /// The payload is empty
+    Empty,
+    /// A decrypted payload fails its CRC, most likely the key is wrong
+    Checksum,
+    /// An advertisement without Ruuvi manufacturer data
+    NotRuuvi,
+}
+
+impl core::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::TooShort { format, len } => {
+                write!(f, "format {format:#04X} payload too short: {len} bytes")
+            }
+            Self::UnknownFormat(format) => write!(f, "unknown data format {format:#04X}"),
+            Self::Empty => write!(f, "empty payload"),
+            Self::Checksum => write!(f, "checksum mismatch, wrong key?"),
+            Self::NotRuuvi => write!(f, "no Ruuvi manufacturer data in the advertisement"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseError {}
+
+// The formats mark a missing sensor or reading with the extreme value of the field
+
+const fn valid_i16(v: i16) -> Option<i16> {
+    if v == i16::MIN { None } else { Some(v) }
+}
+
+const fn valid_u16(v: u16) -> Option<u16> {
+    if v == u16::MAX { None } else { Some(v) }
+}
+
+/// For the 9 and 24 bit fields of E1 and format 6
+const fn valid_bits(v: u32, bits: u32) -> Option<u32> {
+    if v == (1 << bits) - 1 { None } else { Some(v) }
+}
+
+/// Hardware family of a tag, inferred from the data format and which fields it fills in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[serde(rename_all = "snake_case")]
+pub enum TagModel {
+    RuuviTag,
+    RuuviTagPro,
+    RuuviAir,
+}
+
+impl TagModel {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::RuuviTag => "ruuvi_tag",
+            Self::RuuviTagPro => "ruuvi_tag_pro",
+            Self::RuuviAir => "ruuvi_air",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct RuuviRawV2 {
     pub temp: i16,            // 1-2
     pub humidity: u16,        // 3-4
@@ -20,6 +100,7 @@ pub struct RuuviRawV2 {
 }
 
 impl RuuviRawV2 {
+    #[allow(clippy::too_many_arguments)]
     pub const fn new(
         temp: i16,
         humidity: u16,
@@ -51,7 +132,328 @@ impl RuuviRawV2 {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl RuuviRawV2 {
+    pub const FORMAT: u8 = 0x05;
+    pub const LEN: usize = 24;
+
+    /// Decode a format 5 payload, starting at the data format byte
+    pub fn from_bytes(data: &[u8], rssi: i8) -> Result<Self, ParseError> {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-5-rawv2
+        let Some(data) = data.first_chunk::<{ Self::LEN }>() else {
+            return Err(ParseError::TooShort {
+                format: Self::FORMAT,
+                len: data.len(),
+            });
+        };
+        Ok(Self::new(
+            i16::from_be_bytes([data[1], data[2]]),
+            u16::from_be_bytes([data[3], data[4]]),
+            u16::from_be_bytes([data[5], data[6]]),
+            i16::from_be_bytes([data[7], data[8]]),
+            i16::from_be_bytes([data[9], data[10]]),
+            i16::from_be_bytes([data[11], data[12]]),
+            u16::from_be_bytes([data[13], data[14]]),
+            data[15],
+            u16::from_be_bytes([data[16], data[17]]),
+            [data[18], data[19], data[20], data[21], data[22], data[23]],
+            None,
+            rssi,
+        ))
+    }
+
+    pub const FORMAT_C5: u8 = 0xC5;
+    pub const LEN_C5: usize = 18;
+    /// Acceleration of formats without an accelerometer reading
+    pub const ACC_NOT_AVAILABLE: i16 = i16::MIN;
+
+    /// Decode a format C5 payload, starting at the data format byte. Long-life
+    /// tags drop the acceleration from format 5, it's set to not available.
+    pub fn from_c5_bytes(data: &[u8], rssi: i8) -> Result<Self, ParseError> {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-c5-cut-rawv2
+        let Some(data) = data.first_chunk::<{ Self::LEN_C5 }>() else {
+            return Err(ParseError::TooShort {
+                format: Self::FORMAT_C5,
+                len: data.len(),
+            });
+        };
+        Ok(Self::new(
+            i16::from_be_bytes([data[1], data[2]]),
+            u16::from_be_bytes([data[3], data[4]]),
+            u16::from_be_bytes([data[5], data[6]]),
+            Self::ACC_NOT_AVAILABLE,
+            Self::ACC_NOT_AVAILABLE,
+            Self::ACC_NOT_AVAILABLE,
+            u16::from_be_bytes([data[7], data[8]]),
+            data[9],
+            u16::from_be_bytes([data[10], data[11]]),
+            [data[12], data[13], data[14], data[15], data[16], data[17]],
+            None,
+            rssi,
+        ))
+    }
+
+    /// Encode as a format 5 payload, the inverse of [`from_bytes`](Self::from_bytes)
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[0] = Self::FORMAT;
+        data[1..3].copy_from_slice(&self.temp.to_be_bytes());
+        data[3..5].copy_from_slice(&self.humidity.to_be_bytes());
+        data[5..7].copy_from_slice(&self.pressure.to_be_bytes());
+        data[7..9].copy_from_slice(&self.acc_x.to_be_bytes());
+        data[9..11].copy_from_slice(&self.acc_y.to_be_bytes());
+        data[11..13].copy_from_slice(&self.acc_z.to_be_bytes());
+        data[13..15].copy_from_slice(&self.power_info.to_be_bytes());
+        data[15] = self.movement_counter;
+        data[16..18].copy_from_slice(&self.measurement_seq.to_be_bytes());
+        data[18..].copy_from_slice(&self.mac);
+        data
+    }
+
+    /// Encode as a format C5 payload, the acceleration is left out
+    pub fn to_c5_bytes(&self) -> [u8; Self::LEN_C5] {
+        let format_5 = self.to_bytes();
+        let mut data = [0u8; Self::LEN_C5];
+        data[0] = Self::FORMAT_C5;
+        data[1..7].copy_from_slice(&format_5[1..7]);
+        data[7..].copy_from_slice(&format_5[13..]);
+        data
+    }
+
+    /// None of the axes are available, the tag sent C5 or has no accelerometer
+    pub const fn lacks_acceleration(&self) -> bool {
+        self.acc_x == Self::ACC_NOT_AVAILABLE
+            && self.acc_y == Self::ACC_NOT_AVAILABLE
+            && self.acc_z == Self::ACC_NOT_AVAILABLE
+    }
+
+    /// Battery voltage in millivolts, the first 11 bits of power info. From 1600 to 3646 mV
+    pub const fn battery_mv(&self) -> u16 {
+        1600 + (self.power_info >> 5)
+    }
+
+    /// TX power in dBm, the last 5 bits of power info. From -40 to +20 dBm in 2 dBm steps
+    pub const fn tx_power_dbm(&self) -> i8 {
+        (self.power_info & 0b11111) as i8 * 2 - 40
+    }
+
+    pub const fn valid_temp(&self) -> Option<i16> {
+        valid_i16(self.temp)
+    }
+
+    pub const fn valid_humidity(&self) -> Option<u16> {
+        valid_u16(self.humidity)
+    }
+
+    pub const fn valid_pressure(&self) -> Option<u16> {
+        valid_u16(self.pressure)
+    }
+
+    /// X, Y and Z
+    pub const fn valid_acc(&self) -> [Option<i16>; 3] {
+        [
+            valid_i16(self.acc_x),
+            valid_i16(self.acc_y),
+            valid_i16(self.acc_z),
+        ]
+    }
+
+    pub const fn valid_battery_mv(&self) -> Option<u16> {
+        match valid_bits((self.power_info >> 5) as u32, 11) {
+            Some(_) => Some(self.battery_mv()),
+            None => None,
+        }
+    }
+
+    pub const fn valid_tx_power_dbm(&self) -> Option<i8> {
+        match valid_bits((self.power_info & 0b11111) as u32, 5) {
+            Some(_) => Some(self.tx_power_dbm()),
+            None => None,
+        }
+    }
+
+    pub const fn valid_movement_counter(&self) -> Option<u8> {
+        if self.movement_counter == u8::MAX {
+            None
+        } else {
+            Some(self.movement_counter)
+        }
+    }
+
+    pub const fn valid_measurement_seq(&self) -> Option<u16> {
+        valid_u16(self.measurement_seq)
+    }
+
+    /// No field holds its "not available" value. Pros and C5 tags never are.
+    pub const fn is_valid(&self) -> bool {
+        let [x, y, z] = self.valid_acc();
+        self.valid_temp().is_some()
+            && self.valid_humidity().is_some()
+            && self.valid_pressure().is_some()
+            && x.is_some()
+            && y.is_some()
+            && z.is_some()
+            && self.valid_battery_mv().is_some()
+            && self.valid_tx_power_dbm().is_some()
+            && self.valid_movement_counter().is_some()
+            && self.valid_measurement_seq().is_some()
+    }
+
+    /// The Pro 2in1 and 3in1 lack the humidity or pressure sensor and advertise the
+    /// field as not available. A Pro 4in1 can't be told apart from a RuuviTag.
+    pub const fn model(&self) -> TagModel {
+        if self.valid_humidity().is_none() || self.valid_pressure().is_none() {
+            TagModel::RuuviTagPro
+        } else {
+            TagModel::RuuviTag
+        }
+    }
+}
+
+impl Ord for RuuviRawV2 {
+    /// Orders by timestamp and measurement sequence, the rest of the fields only break ties
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = |p: &Self| {
+            (
+                p.timestamp,
+                p.measurement_seq,
+                p.mac,
+                p.temp,
+                p.humidity,
+                p.pressure,
+                p.acc_x,
+                p.acc_y,
+                p.acc_z,
+                p.power_info,
+                p.movement_counter,
+                p.rssi,
+            )
+        };
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for RuuviRawV2 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Format 3, sent by RuuviTags on old firmware. It has no MAC address or
+/// measurement sequence in the payload.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct RuuviRawV1 {
+    pub humidity: u8,    // 1 raw, 0.5 % units
+    pub temp: u16,       // 2-3 sign bit, integer and hundredths
+    pub pressure: u16,   // 4-5 raw, Pa with -50000 offset
+    pub acc_x: i16,      // 6-7 mG
+    pub acc_y: i16,      // 8-9 mG
+    pub acc_z: i16,      // 10-11 mG
+    pub battery_mv: u16, // 12-13 mV
+    // Added fields
+    pub mac: [u8; 6],
+    pub timestamp: Option<u64>,
+    pub rssi: i8,
+}
+
+impl RuuviRawV1 {
+    pub const FORMAT: u8 = 0x03;
+    pub const LEN: usize = 14;
+
+    /// Decode a format 3 payload, starting at the data format byte. `mac` is
+    /// the advertiser address.
+    pub fn from_bytes(data: &[u8], mac: [u8; 6], rssi: i8) -> Result<Self, ParseError> {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-3-rawv1
+        let Some(data) = data.first_chunk::<{ Self::LEN }>() else {
+            return Err(ParseError::TooShort {
+                format: Self::FORMAT,
+                len: data.len(),
+            });
+        };
+        Ok(Self {
+            humidity: data[1],
+            temp: u16::from_be_bytes([data[2], data[3]]),
+            pressure: u16::from_be_bytes([data[4], data[5]]),
+            acc_x: i16::from_be_bytes([data[6], data[7]]),
+            acc_y: i16::from_be_bytes([data[8], data[9]]),
+            acc_z: i16::from_be_bytes([data[10], data[11]]),
+            battery_mv: u16::from_be_bytes([data[12], data[13]]),
+            mac,
+            timestamp: None,
+            rssi,
+        })
+    }
+
+    /// Encode as a format 3 payload, the inverse of [`from_bytes`](Self::from_bytes)
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[0] = Self::FORMAT;
+        data[1] = self.humidity;
+        data[2..4].copy_from_slice(&self.temp.to_be_bytes());
+        data[4..6].copy_from_slice(&self.pressure.to_be_bytes());
+        data[6..8].copy_from_slice(&self.acc_x.to_be_bytes());
+        data[8..10].copy_from_slice(&self.acc_y.to_be_bytes());
+        data[10..12].copy_from_slice(&self.acc_z.to_be_bytes());
+        data[12..14].copy_from_slice(&self.battery_mv.to_be_bytes());
+        data
+    }
+
+    /// Temperature in 0.01 °C. The first byte is the integer part with the
+    /// sign in the highest bit, the second byte the hundredths.
+    pub const fn temp_centi(&self) -> i16 {
+        let [int, frac] = self.temp.to_be_bytes();
+        let magnitude = (int & 0x7F) as i16 * 100 + frac as i16;
+        if int & 0x80 == 0 {
+            magnitude
+        } else {
+            -magnitude
+        }
+    }
+
+    /// Stands in for the missing measurement sequence. The tag repeats each
+    /// measurement in several advertisements, which share the fingerprint.
+    pub fn fingerprint(&self) -> u16 {
+        // FNV-1a folded to 16 bits
+        let hash = [
+            [self.humidity, 0],
+            self.temp.to_be_bytes(),
+            self.pressure.to_be_bytes(),
+            self.acc_x.to_be_bytes(),
+            self.acc_y.to_be_bytes(),
+            self.acc_z.to_be_bytes(),
+            self.battery_mv.to_be_bytes(),
+        ]
+        .iter()
+        .flatten()
+        .fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+        });
+        ((hash >> 16) ^ (hash & 0xFFFF)) as u16
+    }
+}
+
+impl Ord for RuuviRawV1 {
+    /// Orders by timestamp, the rest of the fields only break ties
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = |p: &Self| {
+            (
+                (p.timestamp, p.mac),
+                (p.temp, p.humidity, p.pressure),
+                (p.acc_x, p.acc_y, p.acc_z, p.battery_mv, p.rssi),
+            )
+        };
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for RuuviRawV1 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct RuuviRawE1 {
     pub temp: i16,            // 1-2 raw, 0.005 °C units
     pub humidity: u16,        // 3-4 raw, 0.0025 % units
@@ -74,6 +476,7 @@ pub struct RuuviRawE1 {
 }
 
 impl RuuviRawE1 {
+    #[allow(clippy::too_many_arguments)]
     pub const fn new(
         temp: i16,
         humidity: u16,
@@ -115,17 +518,523 @@ impl RuuviRawE1 {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl RuuviRawE1 {
+    pub const FORMAT: u8 = 0xE1;
+    pub const LEN: usize = 40;
+
+    /// Decode a format E1 payload, starting at the data format byte
+    pub fn from_bytes(data: &[u8], rssi: i8, tx_power: i8) -> Result<Self, ParseError> {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-e1
+        let Some(data) = data.first_chunk::<{ Self::LEN }>() else {
+            return Err(ParseError::TooShort {
+                format: Self::FORMAT,
+                len: data.len(),
+            });
+        };
+        let flags = data[28];
+        // The lowest bits of the 9-bit indices are in the flags
+        let voc_index = ((data[17] as u16) << 1) | ((flags >> 6) & 0x01) as u16;
+        let nox_index = ((data[18] as u16) << 1) | ((flags >> 7) & 0x01) as u16;
+        let u24 = |i: usize| u32::from_be_bytes([0, data[i], data[i + 1], data[i + 2]]);
+        Ok(Self::new(
+            i16::from_be_bytes([data[1], data[2]]),
+            u16::from_be_bytes([data[3], data[4]]),
+            u16::from_be_bytes([data[5], data[6]]),
+            u16::from_be_bytes([data[7], data[8]]),
+            u16::from_be_bytes([data[9], data[10]]),
+            u16::from_be_bytes([data[11], data[12]]),
+            u16::from_be_bytes([data[13], data[14]]),
+            u16::from_be_bytes([data[15], data[16]]),
+            voc_index,
+            nox_index,
+            u24(19),
+            u24(25),
+            flags,
+            [data[34], data[35], data[36], data[37], data[38], data[39]],
+            None,
+            rssi,
+            tx_power,
+        ))
+    }
+
+    /// Encode as a format E1 payload, the inverse of [`from_bytes`](Self::from_bytes).
+    /// The reserved bytes are zero, the lowest bits of the indices stay in the flags.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[0] = Self::FORMAT;
+        data[1..3].copy_from_slice(&self.temp.to_be_bytes());
+        data[3..5].copy_from_slice(&self.humidity.to_be_bytes());
+        data[5..7].copy_from_slice(&self.pressure.to_be_bytes());
+        data[7..9].copy_from_slice(&self.pm1_0.to_be_bytes());
+        data[9..11].copy_from_slice(&self.pm2_5.to_be_bytes());
+        data[11..13].copy_from_slice(&self.pm4_0.to_be_bytes());
+        data[13..15].copy_from_slice(&self.pm10_0.to_be_bytes());
+        data[15..17].copy_from_slice(&self.co2.to_be_bytes());
+        data[17] = (self.voc_index >> 1) as u8;
+        data[18] = (self.nox_index >> 1) as u8;
+        data[19..22].copy_from_slice(&self.luminosity.to_be_bytes()[1..]);
+        data[25..28].copy_from_slice(&self.measurement_seq.to_be_bytes()[1..]);
+        data[28] = self.flags;
+        data[34..].copy_from_slice(&self.mac);
+        data
+    }
+
+    pub const fn valid_temp(&self) -> Option<i16> {
+        valid_i16(self.temp)
+    }
+
+    pub const fn valid_humidity(&self) -> Option<u16> {
+        valid_u16(self.humidity)
+    }
+
+    pub const fn valid_pressure(&self) -> Option<u16> {
+        valid_u16(self.pressure)
+    }
+
+    /// PM1.0, PM2.5, PM4.0 and PM10.0
+    pub const fn valid_pm(&self) -> [Option<u16>; 4] {
+        [
+            valid_u16(self.pm1_0),
+            valid_u16(self.pm2_5),
+            valid_u16(self.pm4_0),
+            valid_u16(self.pm10_0),
+        ]
+    }
+
+    pub const fn valid_co2(&self) -> Option<u16> {
+        valid_u16(self.co2)
+    }
+
+    pub const fn valid_voc_index(&self) -> Option<u16> {
+        if valid_bits(self.voc_index as u32, 9).is_some() {
+            Some(self.voc_index)
+        } else {
+            None
+        }
+    }
+
+    pub const fn valid_nox_index(&self) -> Option<u16> {
+        if valid_bits(self.nox_index as u32, 9).is_some() {
+            Some(self.nox_index)
+        } else {
+            None
+        }
+    }
+
+    pub const fn valid_luminosity(&self) -> Option<u32> {
+        valid_bits(self.luminosity, 24)
+    }
+
+    pub const fn valid_measurement_seq(&self) -> Option<u32> {
+        valid_bits(self.measurement_seq, 24)
+    }
+
+    /// No field holds its "not available" value
+    pub const fn is_valid(&self) -> bool {
+        let [pm1_0, pm2_5, pm4_0, pm10_0] = self.valid_pm();
+        self.valid_temp().is_some()
+            && self.valid_humidity().is_some()
+            && self.valid_pressure().is_some()
+            && pm1_0.is_some()
+            && pm2_5.is_some()
+            && pm4_0.is_some()
+            && pm10_0.is_some()
+            && self.valid_co2().is_some()
+            && self.valid_voc_index().is_some()
+            && self.valid_nox_index().is_some()
+            && self.valid_luminosity().is_some()
+            && self.valid_measurement_seq().is_some()
+    }
+}
+
+impl Ord for RuuviRawE1 {
+    /// Orders by timestamp and measurement sequence, the rest of the fields only break ties
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = |p: &Self| {
+            (
+                (p.timestamp, p.measurement_seq, p.mac),
+                (p.temp, p.humidity, p.pressure),
+                (p.pm1_0, p.pm2_5, p.pm4_0, p.pm10_0),
+                (p.co2, p.voc_index, p.nox_index, p.luminosity, p.flags),
+                (p.rssi, p.tx_power),
+            )
+        };
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for RuuviRawE1 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Format 6, the Ruuvi Air's legacy advertisement for BLE 4 receivers. Only the
+/// low half of the MAC address and of the measurement sequence fit.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct RuuviRawV6 {
+    pub temp: i16,           // 1-2 raw, 0.005 °C units
+    pub humidity: u16,       // 3-4 raw, 0.0025 % units
+    pub pressure: u16,       // 5-6 raw, Pa with -50000 offset
+    pub pm2_5: u16,          // 7-8 raw, 0.1 µg/m³
+    pub co2: u16,            // 9-10 raw, ppm
+    pub voc_index: u16,      // 9-bit (byte11 << 1 | flags bit6)
+    pub nox_index: u16,      // 9-bit (byte12 << 1 | flags bit7)
+    pub luminosity: u8,      // 13 logarithmic code, see the conversion
+    pub measurement_seq: u8, // 15 lowest 8 bits of the counter
+    pub flags: u8,           // 16
+    // Added fields
+    pub mac: [u8; 6],
+    pub timestamp: Option<u64>,
+    pub rssi: i8,
+}
+
+impl RuuviRawV6 {
+    pub const FORMAT: u8 = 0x06;
+    pub const LEN: usize = 20;
+
+    /// Decode a format 6 payload, starting at the data format byte. `mac` is
+    /// the advertiser address, the payload only has its last three bytes.
+    pub fn from_bytes(data: &[u8], mac: [u8; 6], rssi: i8) -> Result<Self, ParseError> {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-6
+        let Some(data) = data.first_chunk::<{ Self::LEN }>() else {
+            return Err(ParseError::TooShort {
+                format: Self::FORMAT,
+                len: data.len(),
+            });
+        };
+        let flags = data[16];
+        Ok(Self {
+            temp: i16::from_be_bytes([data[1], data[2]]),
+            humidity: u16::from_be_bytes([data[3], data[4]]),
+            pressure: u16::from_be_bytes([data[5], data[6]]),
+            pm2_5: u16::from_be_bytes([data[7], data[8]]),
+            co2: u16::from_be_bytes([data[9], data[10]]),
+            voc_index: ((data[11] as u16) << 1) | ((flags >> 6) & 0x01) as u16,
+            nox_index: ((data[12] as u16) << 1) | ((flags >> 7) & 0x01) as u16,
+            luminosity: data[13],
+            measurement_seq: data[15],
+            flags,
+            mac,
+            timestamp: None,
+            rssi,
+        })
+    }
+
+    /// Encode as a format 6 payload, the inverse of [`from_bytes`](Self::from_bytes).
+    /// The reserved byte is 0xFF.
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[0] = Self::FORMAT;
+        data[1..3].copy_from_slice(&self.temp.to_be_bytes());
+        data[3..5].copy_from_slice(&self.humidity.to_be_bytes());
+        data[5..7].copy_from_slice(&self.pressure.to_be_bytes());
+        data[7..9].copy_from_slice(&self.pm2_5.to_be_bytes());
+        data[9..11].copy_from_slice(&self.co2.to_be_bytes());
+        data[11] = (self.voc_index >> 1) as u8;
+        data[12] = (self.nox_index >> 1) as u8;
+        data[13] = self.luminosity;
+        data[14] = 0xFF;
+        data[15] = self.measurement_seq;
+        data[16] = self.flags;
+        data[17..].copy_from_slice(&self.mac[3..]);
+        data
+    }
+
+    pub const fn valid_temp(&self) -> Option<i16> {
+        valid_i16(self.temp)
+    }
+
+    pub const fn valid_humidity(&self) -> Option<u16> {
+        valid_u16(self.humidity)
+    }
+
+    pub const fn valid_pressure(&self) -> Option<u16> {
+        valid_u16(self.pressure)
+    }
+
+    pub const fn valid_pm2_5(&self) -> Option<u16> {
+        valid_u16(self.pm2_5)
+    }
+
+    pub const fn valid_co2(&self) -> Option<u16> {
+        valid_u16(self.co2)
+    }
+
+    pub const fn valid_voc_index(&self) -> Option<u16> {
+        if valid_bits(self.voc_index as u32, 9).is_some() {
+            Some(self.voc_index)
+        } else {
+            None
+        }
+    }
+
+    pub const fn valid_nox_index(&self) -> Option<u16> {
+        if valid_bits(self.nox_index as u32, 9).is_some() {
+            Some(self.nox_index)
+        } else {
+            None
+        }
+    }
+
+    pub const fn valid_luminosity(&self) -> Option<u8> {
+        if self.luminosity == u8::MAX {
+            None
+        } else {
+            Some(self.luminosity)
+        }
+    }
+
+    /// No field holds its "not available" value
+    pub const fn is_valid(&self) -> bool {
+        self.valid_temp().is_some()
+            && self.valid_humidity().is_some()
+            && self.valid_pressure().is_some()
+            && self.valid_pm2_5().is_some()
+            && self.valid_co2().is_some()
+            && self.valid_voc_index().is_some()
+            && self.valid_nox_index().is_some()
+            && self.valid_luminosity().is_some()
+    }
+}
+
+impl Ord for RuuviRawV6 {
+    /// Orders by timestamp and measurement sequence, the rest of the fields only break ties
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = |p: &Self| {
+            (
+                (p.timestamp, p.measurement_seq, p.mac),
+                (p.temp, p.humidity, p.pressure, p.pm2_5),
+                (p.co2, p.voc_index, p.nox_index, p.luminosity, p.flags),
+                p.rssi,
+            )
+        };
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for RuuviRawV6 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Format 8, encrypted with a per-tag AES-128 key. The receiver holding the
+/// key turns it into a format 5 reading without acceleration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct RuuviRawV8 {
+    pub encrypted: [u8; 16], // 1-16 AES-128-ECB
+    pub crc: u8,             // 17 CRC-8 of the plaintext
+    pub mac: [u8; 6],        // 18-23
+    // Added fields
+    pub timestamp: Option<u64>,
+    pub rssi: i8,
+}
+
+impl RuuviRawV8 {
+    pub const FORMAT: u8 = 0x08;
+    pub const LEN: usize = 24;
+
+    /// Decode a format 8 payload, starting at the data format byte
+    pub fn from_bytes(data: &[u8], rssi: i8) -> Result<Self, ParseError> {
+        // https://docs.ruuvi.com/communication/bluetooth-advertisements/data-format-8-encrypted-environmental
+        let Some(data) = data.first_chunk::<{ Self::LEN }>() else {
+            return Err(ParseError::TooShort {
+                format: Self::FORMAT,
+                len: data.len(),
+            });
+        };
+        let mut encrypted = [0u8; 16];
+        encrypted.copy_from_slice(&data[1..17]);
+        Ok(Self {
+            encrypted,
+            crc: data[17],
+            mac: [data[18], data[19], data[20], data[21], data[22], data[23]],
+            timestamp: None,
+            rssi,
+        })
+    }
+
+    /// Encode as a format 8 payload, the inverse of [`from_bytes`](Self::from_bytes)
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[0] = Self::FORMAT;
+        data[1..17].copy_from_slice(&self.encrypted);
+        data[17] = self.crc;
+        data[18..].copy_from_slice(&self.mac);
+        data
+    }
+
+    /// Decode the decrypted block. It holds the format 5 temperature,
+    /// humidity, pressure, power info, movement counter and sequence in that
+    /// order, followed by padding.
+    pub fn decode(&self, plaintext: &[u8; 16]) -> Result<RuuviRawV2, ParseError> {
+        if crc8(plaintext) != self.crc {
+            return Err(ParseError::Checksum);
+        }
+        let p = plaintext;
+        Ok(RuuviRawV2::new(
+            i16::from_be_bytes([p[0], p[1]]),
+            u16::from_be_bytes([p[2], p[3]]),
+            u16::from_be_bytes([p[4], p[5]]),
+            RuuviRawV2::ACC_NOT_AVAILABLE,
+            RuuviRawV2::ACC_NOT_AVAILABLE,
+            RuuviRawV2::ACC_NOT_AVAILABLE,
+            u16::from_be_bytes([p[6], p[7]]),
+            p[8],
+            u16::from_be_bytes([p[9], p[10]]),
+            self.mac,
+            self.timestamp,
+            self.rssi,
+        ))
+    }
+}
+
+/// CRC-8 with polynomial 0x07 and no reflection, as used by the tag firmware
+pub const fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    let mut i = 0;
+    while i < data.len() {
+        crc ^= data[i];
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        i += 1;
+    }
+    crc
+}
+
+impl Ord for RuuviRawV8 {
+    /// Orders by timestamp, the rest of the fields only break ties
+    fn cmp(&self, other: &Self) -> Ordering {
+        let key = |p: &Self| (p.timestamp, p.mac, p.encrypted, p.crc, p.rssi);
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for RuuviRawV8 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum RuuviRaw {
     V2(RuuviRawV2),
     E1(RuuviRawE1),
+    // New variants go last, postcard encodes the variant index
+    V1(RuuviRawV1),
+    V6(RuuviRawV6),
+    /// Forwarded as is, receivers without the key can't read it
+    V8(RuuviRawV8),
 }
 
 impl RuuviRaw {
+    /// Decode Ruuvi manufacturer data, starting at the data format byte.
+    /// `mac` is the advertiser address, formats 3 and 6 lack a full one in the payload.
+    /// `tx_power` is the advertised power, format 5 carries its own.
+    pub fn parse(data: &[u8], mac: [u8; 6], rssi: i8, tx_power: i8) -> Result<Self, ParseError> {
+        match data.first() {
+            Some(&RuuviRawV1::FORMAT) => RuuviRawV1::from_bytes(data, mac, rssi).map(Self::V1),
+            Some(&RuuviRawV6::FORMAT) => RuuviRawV6::from_bytes(data, mac, rssi).map(Self::V6),
+            Some(&RuuviRawV8::FORMAT) => RuuviRawV8::from_bytes(data, rssi).map(Self::V8),
+            Some(&RuuviRawV2::FORMAT) => RuuviRawV2::from_bytes(data, rssi).map(Self::V2),
+            Some(&RuuviRawV2::FORMAT_C5) => RuuviRawV2::from_c5_bytes(data, rssi).map(Self::V2),
+            Some(&RuuviRawE1::FORMAT) => RuuviRawE1::from_bytes(data, rssi, tx_power).map(Self::E1),
+            Some(&format) => Err(ParseError::UnknownFormat(format)),
+            None => Err(ParseError::Empty),
+        }
+    }
+
+    /// Decode a whole advertisement, like the official Ruuvi Gateway relays them.
+    /// The AD structures are searched for Ruuvi's manufacturer data and the
+    /// advertised TX power.
+    pub fn from_advertisement(adv: &[u8], mac: [u8; 6], rssi: i8) -> Result<Self, ParseError> {
+        let mut payload = None;
+        // "Not available", as a controller reports a missing TX power
+        let mut tx_power = 127;
+        let mut rest = adv;
+        while let [len, tail @ ..] = rest {
+            let len = usize::from(*len);
+            // A zero length ends the significant part
+            if len == 0 || len > tail.len() {
+                break;
+            }
+            let (structure, next) = tail.split_at(len);
+            match structure {
+                // Manufacturer specific data of company 0x0499, little endian
+                [0xFF, 0x99, 0x04, data @ ..] => payload = Some(data),
+                [0x0A, power] => tx_power = *power as i8,
+                _ => (),
+            }
+            rest = next;
+        }
+        Self::parse(payload.ok_or(ParseError::NotRuuvi)?, mac, rssi, tx_power)
+    }
+
+    /// Encode back into manufacturer data that [`parse`](Self::parse)s into
+    /// the same packet. Readings without acceleration become format C5.
+    pub fn to_bytes(&self) -> heapless::Vec<u8, { RuuviRawE1::LEN }> {
+        let bytes: &[u8] = match self {
+            Self::V2(v2) if v2.lacks_acceleration() => &v2.to_c5_bytes(),
+            Self::V2(v2) => &v2.to_bytes(),
+            Self::E1(e1) => &e1.to_bytes(),
+            Self::V1(v1) => &v1.to_bytes(),
+            Self::V6(v6) => &v6.to_bytes(),
+            Self::V8(v8) => &v8.to_bytes(),
+        };
+        // The longest format fits
+        heapless::Vec::from_slice(bytes).unwrap()
+    }
+
+    /// The data format byte
+    pub const fn format(&self) -> u8 {
+        match self {
+            Self::E1(_) => RuuviRawE1::FORMAT,
+            Self::V2(_) => RuuviRawV2::FORMAT,
+            Self::V1(_) => RuuviRawV1::FORMAT,
+            Self::V6(_) => RuuviRawV6::FORMAT,
+            Self::V8(_) => RuuviRawV8::FORMAT,
+        }
+    }
+
+    /// Format 3 has no sequence, its fingerprint is used instead. The sequence
+    /// of format 8 is encrypted, the checksum changes with every measurement.
     pub fn measurement_seq(&self) -> u32 {
         match self {
             Self::E1(e1) => e1.measurement_seq,
             Self::V2(v2) => v2.measurement_seq as u32,
+            Self::V1(v1) => v1.fingerprint() as u32,
+            Self::V6(v6) => v6.measurement_seq as u32,
+            Self::V8(v8) => v8.crc as u32,
+        }
+    }
+
+    /// Width of the counter [`measurement_seq`](Self::measurement_seq) comes
+    /// from, `None` for the formats without one
+    pub const fn seq_bits(&self) -> Option<u32> {
+        match self {
+            Self::E1(_) => Some(24),
+            Self::V2(_) => Some(16),
+            Self::V6(_) => Some(8),
+            Self::V1(_) | Self::V8(_) => None,
+        }
+    }
+
+    /// Only format 5 counts movements
+    pub fn movement_counter(&self) -> Option<u8> {
+        match self {
+            Self::V2(v2) => v2.valid_movement_counter(),
+            _ => None,
         }
     }
 
@@ -133,6 +1042,19 @@ impl RuuviRaw {
         match self {
             Self::E1(e1) => e1.mac,
             Self::V2(v2) => v2.mac,
+            Self::V1(v1) => v1.mac,
+            Self::V6(v6) => v6.mac,
+            Self::V8(v8) => v8.mac,
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        match self {
+            Self::E1(e1) => e1.timestamp,
+            Self::V2(v2) => v2.timestamp,
+            Self::V1(v1) => v1.timestamp,
+            Self::V6(v6) => v6.timestamp,
+            Self::V8(v8) => v8.timestamp,
         }
     }
 
@@ -140,6 +1062,316 @@ impl RuuviRaw {
         match self {
             Self::E1(e1) => e1.timestamp = timestamp,
             Self::V2(v2) => v2.timestamp = timestamp,
+            Self::V1(v1) => v1.timestamp = timestamp,
+            Self::V6(v6) => v6.timestamp = timestamp,
+            Self::V8(v8) => v8.timestamp = timestamp,
+        }
+    }
+
+    /// Only the Ruuvi Air sends formats E1 and 6, format 3 predates the Pro
+    pub fn model(&self) -> TagModel {
+        match self {
+            Self::E1(_) | Self::V6(_) => TagModel::RuuviAir,
+            Self::V2(v2) => v2.model(),
+            Self::V1(_) | Self::V8(_) => TagModel::RuuviTag,
+        }
+    }
+}
+
+impl Ord for RuuviRaw {
+    /// Orders packets of both formats by timestamp and measurement sequence.
+    /// Packets without a timestamp sort first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp(), self.measurement_seq(), self.mac())
+            .cmp(&(other.timestamp(), other.measurement_seq(), other.mac()))
+            .then_with(|| match (self, other) {
+                (Self::V2(a), Self::V2(b)) => a.cmp(b),
+                (Self::E1(a), Self::E1(b)) => a.cmp(b),
+                (Self::V1(a), Self::V1(b)) => a.cmp(b),
+                (Self::V6(a), Self::V6(b)) => a.cmp(b),
+                (Self::V8(a), Self::V8(b)) => a.cmp(b),
+                (a, b) => a.format().cmp(&b.format()),
+            })
+    }
+}
+
+impl PartialOrd for RuuviRaw {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ParseError, RuuviRaw, RuuviRawE1, RuuviRawV1, RuuviRawV2, RuuviRawV6, RuuviRawV8, TagModel,
+        crc8,
+    };
+
+    const MAC: [u8; 6] = [0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F];
+
+    fn hex(s: &str) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        for (i, pair) in s.as_bytes().chunks(2).enumerate() {
+            let pair = core::str::from_utf8(pair).unwrap();
+            bytes[i] = u8::from_str_radix(pair, 16).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_format_5_test_vector() {
+        // Valid data from the format 5 documentation
+        let data = hex("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F");
+        let RuuviRaw::V2(v2) = RuuviRaw::parse(&data[..RuuviRawV2::LEN], [0; 6], -60, 0).unwrap()
+        else {
+            panic!("expected format 5");
+        };
+        assert_eq!(v2.temp, 4860);
+        assert_eq!(v2.humidity, 21396);
+        assert_eq!(v2.pressure, 50044);
+        assert_eq!((v2.acc_x, v2.acc_y, v2.acc_z), (4, -4, 1036));
+        assert_eq!(v2.battery_mv(), 2977);
+        assert_eq!(v2.tx_power_dbm(), 4);
+        assert_eq!(v2.movement_counter, 66);
+        assert_eq!(v2.measurement_seq, 205);
+        assert_eq!(v2.mac, MAC);
+        assert_eq!(v2.rssi, -60);
+        assert!(v2.is_valid());
+
+        assert_eq!(
+            RuuviRaw::parse(&data[..10], MAC, 0, 0),
+            Err(ParseError::TooShort { format: 5, len: 10 })
+        );
+    }
+
+    #[test]
+    fn finds_the_payload_in_an_advertisement() {
+        // Flags, the format 5 test vector and a TX power of -4 dBm
+        let adv = hex("0201061BFF99040512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F020AFC");
+        let RuuviRaw::V2(v2) = RuuviRaw::from_advertisement(&adv[..34], MAC, -60).unwrap() else {
+            panic!("expected format 5");
+        };
+        assert_eq!((v2.temp, v2.mac), (4860, MAC));
+
+        // Another company's manufacturer data, and a truncated structure
+        let adv = hex("0201060BFF4C0005120000000000");
+        assert_eq!(
+            RuuviRaw::from_advertisement(&adv[..14], MAC, -60),
+            Err(ParseError::NotRuuvi)
+        );
+        assert_eq!(
+            RuuviRaw::from_advertisement(&adv[..8], MAC, -60),
+            Err(ParseError::NotRuuvi)
+        );
+    }
+
+    #[test]
+    fn parses_format_e1_test_vector() {
+        // Valid data from the format E1 documentation
+        let data =
+            hex("E1170C5668C79E0065007004BD11CA00C90A0213E0AC000000DECDEE100000000000CBB8334C884F");
+        let RuuviRaw::E1(e1) = RuuviRaw::parse(&data[..RuuviRawE1::LEN], [0; 6], -70, 8).unwrap()
+        else {
+            panic!("expected format E1");
+        };
+        assert_eq!(e1.temp, 5900);
+        assert_eq!(e1.humidity, 22120);
+        assert_eq!(e1.pressure, 51102);
+        assert_eq!(
+            (e1.pm1_0, e1.pm2_5, e1.pm4_0, e1.pm10_0),
+            (101, 112, 1213, 4554)
+        );
+        assert_eq!(e1.co2, 201);
+        assert_eq!((e1.voc_index, e1.nox_index), (20, 4));
+        assert_eq!(e1.luminosity, 1_302_700);
+        assert_eq!(e1.measurement_seq, 0xDECDEE);
+        assert_eq!(e1.mac, MAC);
+        assert_eq!((e1.rssi, e1.tx_power), (-70, 8));
+
+        assert_eq!(
+            RuuviRaw::parse(&[0x04, 0x00], MAC, 0, 0),
+            Err(ParseError::UnknownFormat(4))
+        );
+        assert_eq!(RuuviRaw::parse(&[], MAC, 0, 0), Err(ParseError::Empty));
+    }
+
+    #[test]
+    fn parses_format_3_test_vector() {
+        // Valid data from the format 3 documentation
+        let data = hex("03291A1ECE1EFC18F94202CA0B53");
+        let RuuviRaw::V1(v1) = RuuviRaw::parse(&data[..RuuviRawV1::LEN], MAC, -50, 0).unwrap()
+        else {
+            panic!("expected format 3");
+        };
+        assert_eq!(v1.humidity, 41);
+        assert_eq!(v1.temp_centi(), 2630);
+        assert_eq!(v1.pressure, 52766);
+        assert_eq!((v1.acc_x, v1.acc_y, v1.acc_z), (-1000, -1726, 714));
+        assert_eq!(v1.battery_mv, 2899);
+        assert_eq!(v1.mac, MAC);
+
+        // Sign and magnitude, not two's complement
+        let negative = RuuviRawV1 {
+            temp: 0x8145,
+            ..v1.clone()
+        };
+        assert_eq!(negative.temp_centi(), -169);
+        assert_ne!(negative.fingerprint(), v1.fingerprint());
+    }
+
+    #[test]
+    fn parses_format_6_test_vector() {
+        // Valid data from the format 6 documentation
+        let data = hex("06170C5668C79E007000C90501D9FFCD004C884F");
+        let RuuviRaw::V6(v6) = RuuviRaw::parse(&data[..RuuviRawV6::LEN], MAC, -80, 0).unwrap()
+        else {
+            panic!("expected format 6");
+        };
+        assert_eq!((v6.temp, v6.humidity, v6.pressure), (5900, 22120, 51102));
+        assert_eq!((v6.pm2_5, v6.co2), (112, 201));
+        assert_eq!((v6.voc_index, v6.nox_index), (10, 2));
+        assert_eq!(v6.luminosity, 0xD9);
+        assert_eq!(v6.measurement_seq, 0xCD);
+        assert_eq!(v6.mac, MAC);
+    }
+
+    #[test]
+    fn parses_format_c5_test_vector() {
+        // Valid data from the format C5 documentation, format 5 without acceleration
+        let data = hex("C512FC5394C37CAC364200CDCBB8334C884F");
+        let RuuviRaw::V2(c5) =
+            RuuviRaw::parse(&data[..RuuviRawV2::LEN_C5], [0; 6], -60, 0).unwrap()
+        else {
+            panic!("expected format 5");
+        };
+        let data = hex("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F");
+        let v2 = RuuviRawV2::from_bytes(&data, -60).unwrap();
+        assert!(c5.lacks_acceleration());
+        assert_eq!(
+            c5,
+            RuuviRawV2 {
+                acc_x: i16::MIN,
+                acc_y: i16::MIN,
+                acc_z: i16::MIN,
+                ..v2
+            }
+        );
+    }
+
+    #[test]
+    fn checks_format_8_plaintext() {
+        // CRC-8/SMBUS check value
+        assert_eq!(crc8(b"123456789"), 0xF4);
+
+        let mut plaintext = [0u8; 16];
+        plaintext[..11].copy_from_slice(&hex("12FC5394C37CAC364200CD")[..11]);
+        let mut data = [0u8; RuuviRawV8::LEN];
+        data[0] = RuuviRawV8::FORMAT;
+        data[17] = crc8(&plaintext);
+        data[18..].copy_from_slice(&MAC);
+        let RuuviRaw::V8(v8) = RuuviRaw::parse(&data, [0; 6], -60, 0).unwrap() else {
+            panic!("expected format 8");
+        };
+
+        // The block is left as is, a real receiver decrypts it first
+        let v2 = v8.decode(&plaintext).unwrap();
+        let data = hex("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F");
+        let expected = RuuviRawV2::from_bytes(&data, -60).unwrap();
+        assert!(v2.lacks_acceleration());
+        assert_eq!(
+            (v2.temp, v2.power_info),
+            (expected.temp, expected.power_info)
+        );
+        assert_eq!(v2.measurement_seq, expected.measurement_seq);
+        assert_eq!(v2.mac, MAC);
+
+        plaintext[0] ^= 1;
+        assert_eq!(v8.decode(&plaintext), Err(ParseError::Checksum));
+    }
+
+    #[test]
+    fn encodes_test_vectors_back() {
+        for vector in [
+            "0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F",
+            "C512FC5394C37CAC364200CDCBB8334C884F",
+            "E1170C5668C79E0065007004BD11CA00C90A0213E0AC000000DECDEE100000000000CBB8334C884F",
+            "03291A1ECE1EFC18F94202CA0B53",
+            "06170C5668C79E007000C90501D9FFCD004C884F",
+        ] {
+            let data = &hex(vector)[..vector.len() / 2];
+            let raw = RuuviRaw::parse(data, MAC, -60, 4).unwrap();
+            assert_eq!(raw.to_bytes().as_slice(), data, "{vector}");
         }
+
+        // The lowest bits of the VOC and NOX indices survive
+        let data =
+            hex("E1170C5668C79E0065007004BD11CA00C90A0213E0AC000000DECDEED00000000000CBB8334C884F");
+        let e1 = RuuviRawE1::from_bytes(&data, 0, 0).unwrap();
+        assert_eq!((e1.voc_index, e1.nox_index), (21, 5));
+        assert_eq!(e1.to_bytes(), data[..RuuviRawE1::LEN]);
+    }
+
+    #[test]
+    fn infers_the_tag_model() {
+        let model = |vector: &str| {
+            let data = &hex(vector)[..vector.len() / 2];
+            RuuviRaw::parse(data, MAC, -60, 0).unwrap().model()
+        };
+        assert_eq!(
+            model("0512FC5394C37C0004FFFC040CAC364200CDCBB8334C884F"),
+            TagModel::RuuviTag
+        );
+        assert_eq!(
+            model("C512FC5394C37CAC364200CDCBB8334C884F"),
+            TagModel::RuuviTag
+        );
+        assert_eq!(model("03291A1ECE1EFC18F94202CA0B53"), TagModel::RuuviTag);
+        // A Pro without the humidity or the pressure sensor
+        assert_eq!(
+            model("0512FCFFFFC37C0004FFFC040CAC364200CDCBB8334C884F"),
+            TagModel::RuuviTagPro
+        );
+        assert_eq!(
+            model("0512FC5394FFFF0004FFFC040CAC364200CDCBB8334C884F"),
+            TagModel::RuuviTagPro
+        );
+        // Formats 6 and E1 only come from the Air
+        assert_eq!(
+            model("06170C5668C79E007000C90501D9FFCD004C884F"),
+            TagModel::RuuviAir
+        );
+        assert_eq!(
+            model(
+                "E1170C5668C79E0065007004BD11CA00C90A0213E0AC000000DECDEE100000000000CBB8334C884F"
+            ),
+            TagModel::RuuviAir
+        );
+    }
+
+    #[test]
+    fn reports_not_available_values() {
+        // Invalid data from the format 5 documentation
+        let data = hex("058000FFFFFFFF800080008000FFFFFFFFFFFFFFFFFFFF");
+        let v2 = RuuviRawV2::from_bytes(&data, 0).unwrap();
+        assert!(!v2.is_valid());
+        assert_eq!(v2.valid_temp(), None);
+        assert_eq!(v2.valid_humidity(), None);
+        assert_eq!(v2.valid_pressure(), None);
+        assert_eq!(v2.valid_acc(), [None; 3]);
+        assert_eq!(v2.valid_battery_mv(), None);
+        assert_eq!(v2.valid_tx_power_dbm(), None);
+        assert_eq!(v2.valid_movement_counter(), None);
+        assert_eq!(v2.valid_measurement_seq(), None);
+
+        // Invalid data from the format E1 documentation
+        let data = hex(
+            "E18000FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+        );
+        let e1 = RuuviRawE1::from_bytes(&data, 0, 0).unwrap();
+        assert!(!e1.is_valid());
+        assert_eq!(e1.valid_pm(), [None; 4]);
+        assert_eq!((e1.valid_voc_index(), e1.valid_nox_index()), (None, None));
+        assert_eq!(e1.valid_luminosity(), None);
     }
 }
@@ -0,0 +1,63 @@
+//! Firmware updates pushed by the gateway over an established Noise session.
+//!
+//! [`Uplink`] and [`Downlink`] travel in the
+//! [`Message`](crate::protocol::Message) envelope. Sessions without it use
+//! OTA frames: [`OTA_FRAME`] followed by the postcard encoded message, while
+//! reading frames start with their `RuuviRaw` variant index.
+
+use serde::{Deserialize, Serialize};
+
+/// Leading byte of every OTA frame
+pub const OTA_FRAME: u8 = 0xF0;
+/// Image bytes per chunk, sized so an encrypted chunk fits the listener's receive buffer
+pub const CHUNK_SIZE: usize = 512;
+
+/// Pack a semantic version into the `u32` used on the wire, `0x00MMmmpp`
+pub const fn version(major: u8, minor: u8, patch: u8) -> u32 {
+    (major as u32) << 16 | (minor as u32) << 8 | patch as u32
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareInfo {
+    pub version: u32,
+    pub size: u32,
+    pub sha256: [u8; 32],
+    /// Ed25519 signature of `sha256`, split in halves since serde only
+    /// derives arrays up to 32 elements
+    pub signature: [[u8; 32]; 2],
+}
+
+/// Gateway to listener
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Downlink<'a> {
+    /// A newer image is available
+    Offer(FirmwareInfo),
+    /// Image bytes starting at `offset`, answers a [`Uplink::Request`]
+    Chunk {
+        version: u32,
+        offset: u32,
+        data: &'a [u8],
+    },
+}
+
+/// Listener to gateway
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Uplink {
+    /// Sent after the time sync. `confirmed` is set once per update, when the
+    /// freshly booted image is marked valid and won't be rolled back.
+    Hello { version: u32, confirmed: bool },
+    /// Ask for the chunk at `offset`, a reconnecting listener resumes where it left off
+    Request { version: u32, offset: u32 },
+    /// Image written, verified and activated, the listener reboots next
+    Complete { version: u32 },
+    /// Image rejected, the listener won't ask for this version again until rebooted
+    Failed { version: u32, reason: FailReason },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailReason {
+    TooLarge,
+    Flash,
+    Checksum,
+    Signature,
+}
@@ -0,0 +1,393 @@
+//! Messages exchanged over an established Noise session.
+//!
+//! The listener sends its [`Hello`] as the payload of its last handshake
+//! message. Every transport message is then one postcard encoded [`Message`],
+//! opened by the listener's [`Message::TimeSyncRequest`]. A gateway that
+//! doesn't speak the listener's protocol answers it with
+//! [`Message::Incompatible`] and closes the connection.
+//!
+//! Either side rekeys its sending direction following [`RekeyPolicy`],
+//! announced with a [`Message::Rekey`] so the peer rekeys its receiving one.
+//!
+//! Listeners predating the envelope open with an empty, unencrypted frame
+//! instead and keep using the frames of [`ota`](crate::ota),
+//! [`time`](crate::time) and [`ack`](crate::ack) for the rest of the session.
+
+use crate::RuuviRaw;
+use crate::ack::{Ack, MAX_ACKS};
+use crate::ota::{Downlink, Uplink};
+use core::ops::RangeInclusive;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever [`Message`] changes in a way older peers can't read
+pub const PROTOCOL_VERSION: u16 = 5;
+/// First protocol with [`Message::Rekey`], older listeners are never sent one
+pub const REKEY_PROTOCOL: u16 = 2;
+/// First protocol reporting [`Message::Telemetry`] instead of [`Message::LegacyTelemetry`]
+pub const TELEMETRY_PROTOCOL: u16 = 3;
+/// First protocol taking [`Message::ScanSettings`]
+pub const SCAN_SETTINGS_PROTOCOL: u16 = 4;
+/// First protocol taking [`Message::Command`]
+pub const COMMAND_PROTOCOL: u16 = 5;
+
+/// Messages sent with one key before rekeying
+pub const REKEY_AFTER_MESSAGES: u64 = 10_000;
+/// Longest a key is used, idle sessions are rekeyed as well
+pub const REKEY_AFTER_SECS: u64 = 30 * 60;
+
+/// Scan intervals and windows a BLE controller takes, in milliseconds
+pub const SCAN_TIMING_MS: RangeInclusive<u16> = 3..=10_240;
+
+/// Readings per [`Message::Batch`], keeps a batch within the listener's frame buffer
+pub const MAX_BATCH: usize = 4;
+/// MACs in each list of a [`Command::TagFilter`], as many as the listener tracks
+pub const MAX_FILTER_MACS: usize = 16;
+
+// Boxing the acknowledgements would need an allocator, messages are short lived anyway
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message<'a> {
+    // New variants go last, postcard encodes the variant index
+    /// Listener to gateway
+    Measurement(RuuviRaw),
+    /// Readings that were queued back to back, listener to gateway
+    Batch(heapless::Vec<RuuviRaw, MAX_BATCH>),
+    /// Answered with a [`Message::TimeSyncResponse`]
+    TimeSyncRequest,
+    /// Gateway's Unix time in milliseconds
+    TimeSyncResponse {
+        unix_ms: u64,
+    },
+    /// Keeps an idle connection in use, so a dead one gets noticed
+    Heartbeat,
+    /// Readings the gateway stored, gateway to listener
+    Ack(heapless::Vec<Ack, MAX_ACKS>),
+    /// Listener health of protocol 2 and older, listener to gateway
+    LegacyTelemetry(LegacyTelemetry),
+    OtaUplink(Uplink),
+    #[serde(borrow)]
+    OtaDownlink(Downlink<'a>),
+    /// The listener's protocol is outside the gateway's supported range
+    Incompatible {
+        min: u16,
+        max: u16,
+    },
+    /// The last message with the current key, the receiver rekeys its incoming
+    /// direction before reading the next one
+    Rekey,
+    /// Listener health, listener to gateway
+    Telemetry(Telemetry),
+    /// Scan parameters, gateway to listener
+    ScanSettings(ScanSettings),
+    /// Operator's command, gateway to listener
+    Command(Command),
+}
+
+/// When the sending direction of a session is due for a [`Message::Rekey`],
+/// after [`REKEY_AFTER_MESSAGES`] or [`REKEY_AFTER_SECS`], whichever comes first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyPolicy {
+    /// Sending nonce and the caller's milliseconds at the last rekey
+    nonce: u64,
+    at_ms: u64,
+}
+
+impl RekeyPolicy {
+    pub fn new(nonce: u64, now_ms: u64) -> Self {
+        Self {
+            nonce,
+            at_ms: now_ms,
+        }
+    }
+
+    pub fn due(&self, nonce: u64, now_ms: u64) -> bool {
+        nonce.saturating_sub(self.nonce) >= REKEY_AFTER_MESSAGES
+            || now_ms.saturating_sub(self.at_ms) >= REKEY_AFTER_SECS * 1000
+    }
+
+    pub fn rekeyed(&mut self, nonce: u64, now_ms: u64) {
+        *self = Self::new(nonce, now_ms);
+    }
+}
+
+/// Versions of a listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol: u16,
+    /// See [`version`](crate::ota::version)
+    pub firmware: u32,
+}
+
+/// Counters since the listener booted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Telemetry {
+    pub uptime_secs: u64,
+    /// Why the listener last started
+    pub reset_reason: ResetReason,
+    pub heap_free: u32,
+    /// Signal of the Wi-Fi access point in dBm, `None` while disconnected
+    pub wifi_rssi: Option<i8>,
+    /// Ruuvi advertisements received
+    pub adverts: u32,
+    pub parse_failures: u32,
+    /// Readings lost to a full queue on their way to the gateway
+    pub dropped: u32,
+    /// Times the queue between the scanner and the sender ran full
+    pub queue_overflows: u32,
+    pub frames_sent: u32,
+    pub reconnects: u32,
+}
+
+/// Cause of the listener's last reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResetReason {
+    PowerOn,
+    /// Restarted on purpose, like into new firmware
+    Software,
+    /// The hardware watchdog fired, the firmware stopped running altogether
+    Watchdog,
+    /// The BLE scanner stopped checking in and the listener reset itself
+    ScannerStalled,
+    /// The sender stopped checking in and the listener reset itself
+    SenderStalled,
+    Brownout,
+    /// Any other reset, with the chip's reason code
+    Other(u8),
+    Unknown,
+    /// The firmware panicked and restarted
+    Panic,
+}
+
+impl ResetReason {
+    /// Crashes, hangs and power problems, as opposed to power cycles and updates
+    pub fn is_fault(self) -> bool {
+        matches!(
+            self,
+            Self::Watchdog
+                | Self::ScannerStalled
+                | Self::SenderStalled
+                | Self::Brownout
+                | Self::Panic
+        )
+    }
+}
+
+/// BLE scan parameters, the unset ones keep the listener's build time configuration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanSettings {
+    /// How often the controller starts scanning, see [`SCAN_TIMING_MS`]
+    pub interval_ms: Option<u16>,
+    /// How long each scan lasts, at most the interval
+    pub window_ms: Option<u16>,
+    pub phys: Option<ScanPhys>,
+    /// Request scan responses, Ruuvi tags need none
+    pub active: Option<bool>,
+}
+
+impl ScanSettings {
+    /// The set values are within [`SCAN_TIMING_MS`] and the window fits the interval
+    pub fn is_valid(&self) -> bool {
+        let in_range = |ms: Option<u16>| ms.is_none_or(|ms| SCAN_TIMING_MS.contains(&ms));
+        in_range(self.interval_ms)
+            && in_range(self.window_ms)
+            && match (self.window_ms, self.interval_ms) {
+                (Some(window), Some(interval)) => window <= interval,
+                _ => true,
+            }
+    }
+
+    /// These settings with the unset ones taken from `base`
+    pub fn or(self, base: ScanSettings) -> ScanSettings {
+        ScanSettings {
+            interval_ms: self.interval_ms.or(base.interval_ms),
+            window_ms: self.window_ms.or(base.window_ms),
+            phys: self.phys.or(base.phys),
+            active: self.active.or(base.active),
+        }
+    }
+}
+
+/// What an operator has a listener do. The changes last until it reboots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    // New variants go last, postcard encodes the variant index
+    /// Like [`Message::ScanSettings`], on demand
+    Scan(ScanSettings),
+    /// Turns the status LED on or off
+    Led(bool),
+    LogLevel(LogLevel),
+    /// Replaces the listener's tag filter. An empty `allow` forwards every tag
+    /// that isn't in `deny`.
+    TagFilter {
+        allow: heapless::Vec<[u8; 6], MAX_FILTER_MACS>,
+        deny: heapless::Vec<[u8; 6], MAX_FILTER_MACS>,
+    },
+    /// Report [`Message::Telemetry`] now instead of when due
+    Telemetry,
+    Reboot,
+}
+
+/// Most detailed log records a listener prints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// PHYs scanned for advertisements, advertising only starts on these
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanPhys {
+    /// 1 Mbps, what the tags advertise on
+    M1,
+    /// Long range, for tags configured to advertise on it
+    Coded,
+    M1Coded,
+}
+
+/// [`Telemetry`] as protocol 2 listeners report it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegacyTelemetry {
+    pub uptime_secs: u64,
+    pub heap_free: u32,
+    pub adverts: u32,
+    pub parse_failures: u32,
+    pub frames_sent: u32,
+    pub reconnects: u32,
+}
+
+impl From<LegacyTelemetry> for Telemetry {
+    /// These listeners didn't count lost readings, they're reported as none
+    fn from(legacy: LegacyTelemetry) -> Self {
+        Self {
+            uptime_secs: legacy.uptime_secs,
+            reset_reason: ResetReason::Unknown,
+            heap_free: legacy.heap_free,
+            wifi_rssi: None,
+            adverts: legacy.adverts,
+            parse_failures: legacy.parse_failures,
+            dropped: 0,
+            queue_overflows: 0,
+            frames_sent: legacy.frames_sent,
+            reconnects: legacy.reconnects,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Command, LogLevel, Message, REKEY_AFTER_MESSAGES, REKEY_AFTER_SECS, RekeyPolicy,
+        ResetReason, ScanPhys, ScanSettings, Telemetry,
+    };
+    use crate::ack::Ack;
+    use crate::ota::Downlink;
+
+    #[test]
+    fn round_trips_through_postcard() {
+        let mut acks = heapless::Vec::new();
+        acks.push(Ack {
+            mac: [1, 2, 3, 4, 5, 6],
+            seq: 70_000,
+        })
+        .unwrap();
+        let data = [0xAB; 16];
+        let messages = [
+            Message::TimeSyncResponse {
+                unix_ms: 1_700_000_000_000,
+            },
+            Message::Ack(acks),
+            Message::Telemetry(Telemetry {
+                uptime_secs: 86_400,
+                reset_reason: ResetReason::SenderStalled,
+                heap_free: 120_000,
+                wifi_rssi: Some(-67),
+                adverts: 5_000,
+                parse_failures: 1,
+                dropped: 3,
+                queue_overflows: 1,
+                frames_sent: 4_990,
+                reconnects: 2,
+            }),
+            Message::ScanSettings(ScanSettings {
+                interval_ms: Some(500),
+                window_ms: None,
+                phys: Some(ScanPhys::M1Coded),
+                active: Some(false),
+            }),
+            Message::OtaDownlink(Downlink::Chunk {
+                version: 1,
+                offset: 512,
+                data: &data,
+            }),
+            Message::Command(Command::LogLevel(LogLevel::Debug)),
+            Message::Command(Command::TagFilter {
+                allow: heapless::Vec::from_slice(&[[0xC8, 0x25, 0x2D, 0x8E, 0x9C, 0x2C]; 16])
+                    .unwrap(),
+                deny: heapless::Vec::new(),
+            }),
+            Message::Command(Command::Reboot),
+        ];
+        let mut buf = [0u8; 128];
+        for message in messages {
+            let bytes = postcard::to_slice(&message, &mut buf).unwrap();
+            assert_eq!(postcard::from_bytes::<Message>(bytes).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn rekeys_by_messages_or_time() {
+        let mut policy = RekeyPolicy::new(5, 1_000);
+        assert!(!policy.due(5 + REKEY_AFTER_MESSAGES - 1, 1_000));
+        assert!(policy.due(5 + REKEY_AFTER_MESSAGES, 1_000));
+        assert!(policy.due(6, 1_000 + REKEY_AFTER_SECS * 1000));
+
+        policy.rekeyed(5 + REKEY_AFTER_MESSAGES, 2_000);
+        assert!(!policy.due(6 + REKEY_AFTER_MESSAGES, 2_000));
+    }
+
+    #[test]
+    fn checks_scan_timing() {
+        let settings = |interval_ms, window_ms| ScanSettings {
+            interval_ms,
+            window_ms,
+            ..Default::default()
+        };
+        assert!(settings(None, None).is_valid());
+        assert!(settings(Some(100), Some(100)).is_valid());
+        assert!(settings(None, Some(10_240)).is_valid());
+        assert!(!settings(Some(100), Some(200)).is_valid());
+        assert!(!settings(Some(2), None).is_valid());
+        assert!(!settings(None, Some(20_000)).is_valid());
+    }
+
+    #[test]
+    fn layers_scan_settings() {
+        let gateway = ScanSettings {
+            window_ms: Some(50),
+            phys: Some(ScanPhys::Coded),
+            ..Default::default()
+        };
+        let stored = ScanSettings {
+            interval_ms: Some(100),
+            window_ms: Some(100),
+            active: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            gateway.or(stored),
+            ScanSettings {
+                interval_ms: Some(100),
+                window_ms: Some(50),
+                phys: Some(ScanPhys::Coded),
+                active: Some(true),
+            }
+        );
+    }
+}
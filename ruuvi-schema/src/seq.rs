@@ -0,0 +1,112 @@
+//! Duplicate detection of advertisements by measurement sequence.
+//!
+//! A tag repeats each measurement over several adverts, and now and then they
+//! arrive out of order. [`SeqWindow`] remembers the latest sequence of a tag
+//! and which of the [`WINDOW`] before it were seen. Sequences are compared in
+//! the width of the format's counter, see [`RuuviRaw::seq_bits`], so a
+//! rollover to 0 counts as the next measurement.
+//!
+//! [`RuuviRaw::seq_bits`]: crate::RuuviRaw::seq_bits
+
+/// Sequences behind the latest one still told apart from duplicates. Ones
+/// further behind are a tag that restarted its counter.
+pub const WINDOW: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqWindow {
+    latest: u32,
+    /// Bit `n` is set once `latest - 1 - n` was seen
+    seen: u32,
+}
+
+impl SeqWindow {
+    pub const fn new(seq: u32) -> Self {
+        Self {
+            latest: seq,
+            seen: 0,
+        }
+    }
+
+    /// Whether `seq` wasn't seen yet, and records it. `bits` is the width of
+    /// the counter, without one only a repeat of the latest is a duplicate.
+    pub fn accept(&mut self, seq: u32, bits: Option<u32>) -> bool {
+        let Some(bits) = bits else {
+            let new = seq != self.latest;
+            *self = Self::new(seq);
+            return new;
+        };
+        let mask = u32::MAX >> 32u32.saturating_sub(bits);
+        let ahead = seq.wrapping_sub(self.latest) & mask;
+        if ahead == 0 {
+            return false;
+        }
+        // Up to half the counter ahead is newer, the rest is behind
+        if ahead <= mask / 2 + 1 {
+            self.seen = self.seen.checked_shl(ahead).unwrap_or(0)
+                | 1u32.checked_shl(ahead - 1).unwrap_or(0);
+            self.latest = seq;
+            return true;
+        }
+        let behind = self.latest.wrapping_sub(seq) & mask;
+        if behind > WINDOW {
+            *self = Self::new(seq);
+            return true;
+        }
+        let bit = 1 << (behind - 1);
+        let new = self.seen & bit == 0;
+        self.seen |= bit;
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SeqWindow, WINDOW};
+
+    #[test]
+    fn accepts_reordered_sequences_once() {
+        let mut window = SeqWindow::new(100);
+        assert!(!window.accept(100, Some(16)));
+        assert!(window.accept(103, Some(16)));
+        assert!(window.accept(101, Some(16)));
+        assert!(!window.accept(101, Some(16)));
+        assert!(window.accept(102, Some(16)));
+        assert!(!window.accept(100, Some(16)));
+        assert!(!window.accept(103, Some(16)));
+        assert!(window.accept(104, Some(16)));
+    }
+
+    #[test]
+    fn rolls_over_in_the_width_of_the_counter() {
+        let mut window = SeqWindow::new(0xFFFE);
+        assert!(window.accept(0xFFFF, Some(16)));
+        assert!(window.accept(1, Some(16)));
+        assert!(window.accept(0, Some(16)));
+        assert!(!window.accept(0xFFFF, Some(16)));
+
+        let mut window = SeqWindow::new(0xFF_FFFF);
+        assert!(window.accept(0, Some(24)));
+        assert!(!window.accept(0xFF_FFFF, Some(24)));
+
+        let mut window = SeqWindow::new(250);
+        assert!(window.accept(4, Some(8)));
+        assert!(window.accept(252, Some(8)));
+    }
+
+    #[test]
+    fn starts_over_after_a_restarted_counter() {
+        let mut window = SeqWindow::new(5000);
+        assert!(window.accept(3, Some(16)));
+        assert!(window.accept(4, Some(16)));
+        assert!(!window.accept(3, Some(16)));
+        assert!(window.accept(0xFFFF - WINDOW, Some(16)));
+    }
+
+    #[test]
+    fn only_repeats_are_duplicates_without_a_counter() {
+        let mut window = SeqWindow::new(0xAB12);
+        assert!(!window.accept(0xAB12, None));
+        assert!(window.accept(0x0F00, None));
+        assert!(window.accept(0xAB12, None));
+    }
+}
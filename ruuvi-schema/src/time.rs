@@ -0,0 +1,89 @@
+//! Clock synchronization over an established Noise session.
+//!
+//! The listener opens the session with a time sync request and repeats it
+//! periodically, see [`Message`](crate::protocol::Message). Listeners
+//! without the envelope open with an empty frame, answered with the gateway's
+//! Unix time in milliseconds as a big endian `u64`. Their later re-syncs send
+//! a lone [`TIME_FRAME`], answered with [`TIME_FRAME`] and the timestamp.
+
+/// Leading byte of every re-sync frame
+pub const TIME_FRAME: u8 = 0xF1;
+
+/// Syncs closer together than this don't update the drift, network jitter
+/// would dominate the estimate
+const MIN_DRIFT_INTERVAL_MS: u64 = 60_000;
+/// Larger corrections are a changed gateway clock, not drift
+const MAX_DRIFT_ERROR_MS: i64 = 10_000;
+/// Weight of a new drift measurement
+const DRIFT_SMOOTHING: f32 = 0.25;
+/// Far beyond any crystal, guards against a burst of bad samples
+const MAX_DRIFT_PPM: f32 = 5_000.0;
+
+/// Maps the listener's monotonic clock to gateway time. Between syncs the
+/// local clock is corrected by its drift, estimated over consecutive syncs.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClockSync {
+    /// Local milliseconds and the gateway time at that moment
+    reference: Option<(u64, u64)>,
+    /// How much faster the gateway clock runs, in parts per million
+    drift_ppm: f32,
+}
+
+impl ClockSync {
+    /// Record a sync, `gateway_ms` is the gateway time at `local_ms`
+    pub fn update(&mut self, local_ms: u64, gateway_ms: u64) {
+        if let Some((ref_local, _)) = self.reference
+            && let Some(predicted) = self.gateway_time(local_ms)
+        {
+            let elapsed = local_ms.saturating_sub(ref_local);
+            let error = gateway_ms as i64 - predicted as i64;
+            if elapsed >= MIN_DRIFT_INTERVAL_MS && error.abs() < MAX_DRIFT_ERROR_MS {
+                // The error piled up over `elapsed` on top of the current estimate
+                let measured = self.drift_ppm + error as f32 * 1e6 / elapsed as f32;
+                self.drift_ppm += DRIFT_SMOOTHING * (measured - self.drift_ppm);
+                self.drift_ppm = self.drift_ppm.clamp(-MAX_DRIFT_PPM, MAX_DRIFT_PPM);
+            }
+        }
+        self.reference = Some((local_ms, gateway_ms));
+    }
+
+    /// Gateway time at `local_ms`, `None` before the first sync
+    pub fn gateway_time(&self, local_ms: u64) -> Option<u64> {
+        let (ref_local, ref_gateway) = self.reference?;
+        let elapsed = local_ms as i64 - ref_local as i64;
+        let correction = (elapsed as f32 * self.drift_ppm / 1e6) as i64;
+        Some(ref_gateway.saturating_add_signed(elapsed + correction))
+    }
+
+    pub fn drift_ppm(&self) -> f32 {
+        self.drift_ppm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClockSync;
+
+    #[test]
+    fn learns_drift_between_syncs() {
+        // The gateway clock runs 1000 ppm faster than the listener's
+        let gateway = |local_ms: u64| 1_700_000_000_000 + local_ms + local_ms / 1000;
+        let mut clock = ClockSync::default();
+        assert_eq!(clock.gateway_time(0), None);
+
+        for sync in 0..20 {
+            let local_ms = sync * 900_000;
+            clock.update(local_ms, gateway(local_ms));
+        }
+        assert!(
+            (clock.drift_ppm() - 1000.0).abs() < 10.0,
+            "{}",
+            clock.drift_ppm()
+        );
+
+        // Half way to the next sync
+        let local_ms = 19 * 900_000 + 450_000;
+        let error = clock.gateway_time(local_ms).unwrap() as i64 - gateway(local_ms) as i64;
+        assert!(error.abs() <= 5, "{error}");
+    }
+}